@@ -2,7 +2,7 @@ use std::process::exit;
 
 use edgesearch_client::http::Client;
 use edgesearch_client::query::{QueryBuilder, QueryExpr};
-use edgesearch_client::Result;
+use edgesearch_client::{Result, SearchOptions};
 
 fn main() -> Result<()> {
     // Expect the base URL to be passed as the first argument
@@ -43,7 +43,7 @@ fn main() -> Result<()> {
         None,
         None,
     )?;
-    println!("Added document 1: {}", doc1.uuid);
+    println!("Added document 1: {}", doc1.id);
 
     let doc2 = client.add_document(
         "my-index",
@@ -52,7 +52,7 @@ fn main() -> Result<()> {
         None,
     )?;
 
-    println!("Added document 2: {}", doc2.uuid);
+    println!("Added document 2: {}", doc2.id);
 
     let doc3 = client.add_document(
         "my-index",
@@ -60,14 +60,30 @@ fn main() -> Result<()> {
         None,
         None,
     )?;
-    println!("Added document 3: {}", doc3.uuid);
+    println!(
+        "Added document 3: {} ({} keywords, {} docs in the index)",
+        doc3.id,
+        doc3.keywords_total,
+        doc3.index_docs_count.unwrap_or_default()
+    );
 
     // Basic search for documents
-    let results = client.search("my-index", "\"programming\"", Some(true))?;
+    let results = client.search(
+        "my-index",
+        "\"programming\"",
+        Some(SearchOptions::new().full()),
+    )?;
     println!("\nBasic search found {} documents", results.document_count);
+    for warning in &results.warnings {
+        println!("  Warning ({:?}): {}", warning.code, warning.message);
+    }
 
     for result in &results.matches {
-        println!("- Document {}: score={:.2}", result.doc_id, result.score);
+        println!(
+            "- Document {}: score={:.2}",
+            result.doc_id,
+            result.score.unwrap_or_default()
+        );
         if let Some(body) = &result.body {
             println!("  Body: {}", body);
         }
@@ -79,7 +95,8 @@ fn main() -> Result<()> {
         .and(QueryExpr::word("hello").not());
 
     println!("\nQuery expression: {}", query_expr);
-    let expr_results = client.search_expr("my-index", &query_expr, Some(true))?;
+    let expr_results =
+        client.search_expr("my-index", &query_expr, Some(SearchOptions::new().full()))?;
     println!(
         "Expression search found {} documents",
         expr_results.document_count
@@ -92,7 +109,8 @@ fn main() -> Result<()> {
 
     if let Some(query) = builder.to_query_string() {
         println!("\nBuilt query: {}", query);
-        let builder_results = client.search("my-index", &query, Some(true))?;
+        let builder_results =
+            client.search("my-index", &query, Some(SearchOptions::new().full()))?;
         println!(
             "Builder search found {} documents",
             builder_results.document_count
@@ -106,7 +124,7 @@ fn main() -> Result<()> {
 
     if let Some(built_query) = complex_query.to_query_string() {
         println!("\nComplex query: {}", built_query);
-        let complex_results = client.search("my-index", &built_query, Some(false))?;
+        let complex_results = client.search("my-index", &built_query, None)?;
         println!(
             "Complex search found {} documents",
             complex_results.document_count
@@ -115,19 +133,25 @@ fn main() -> Result<()> {
 
     // Update the document
     let update_response =
-        client.update_document("my-index", &doc1.uuid, "Updated content".to_string())?;
-    println!("\nDocument updated: revision={}", update_response.revision);
+        client.update_document("my-index", &doc1.id, "Updated content".to_string())?;
+    println!(
+        "\nDocument updated: revision={}, {} keywords added, {} removed",
+        update_response.revision, update_response.keywords_added, update_response.keywords_removed
+    );
 
     // Get a specific document
-    let retrieved_doc = client.get_document("my-index", &doc1.uuid)?;
+    let retrieved_doc = client.get_document("my-index", &doc1.id)?;
     println!("Retrieved document body: {:?}", retrieved_doc.document_body);
 
     // Search for a keyword
-    let keyword_response = client.get_keyword("my-index", "programming")?;
+    let keyword_response = client.get_keyword("my-index", "programming", Some(10), None)?;
     println!(
         "Keyword '{}' found in {} documents",
         keyword_response.keyword, keyword_response.document_count
     );
+    if let Some(best) = keyword_response.scores.first() {
+        println!("Best match: {} (score {:.2})", best.doc_id, best.score);
+    }
 
     // List all indexes
     let indexes = client.list_indexes()?;
@@ -141,9 +165,9 @@ fn main() -> Result<()> {
     );
 
     // Delete the documents
-    client.delete_document("my-index", &doc1.uuid)?;
-    client.delete_document("my-index", &doc2.uuid)?;
-    client.delete_document("my-index", &doc3.uuid)?;
+    client.delete_document("my-index", &doc1.id)?;
+    client.delete_document("my-index", &doc2.id)?;
+    client.delete_document("my-index", &doc3.id)?;
     println!("Documents deleted");
 
     // Delete the index
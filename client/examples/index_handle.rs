@@ -0,0 +1,96 @@
+use std::process::exit;
+
+use edgesearch_client::http::Client;
+use edgesearch_client::query::{QueryBuilder, QueryExpr};
+use edgesearch_client::{Result, SearchOptions};
+
+// The same walkthrough as `basic_usage`, through an index handle instead of passing
+// the index name to every call
+fn main() -> Result<()> {
+    // Expect the base URL to be passed as the first argument
+    // and the API key after
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() < 3 {
+        eprintln!("Usage: {} <base_url> <api_key>", args[0]);
+        exit(1);
+    }
+
+    let client = Client::new(args[1].clone()).with_api_key(args[2].clone());
+    let index = client.index("my-index");
+
+    // Create the index, or reuse it when it's already there
+    let info = index.ensure_exists()?;
+    println!("Using index: {} with {} docs", info.index, info.docs_count);
+
+    // Add some documents
+    let doc1 = index.add_document(
+        "Hello world content about programming".to_string(),
+        None,
+        None,
+    )?;
+    let doc2 = index.add_document("World peace and harmony".to_string(), None, None)?;
+    let doc3 = index.add_document("Programming tutorials and guides".to_string(), None, None)?;
+    println!("Added documents: {}, {}, {}", doc1.id, doc2.id, doc3.id);
+
+    // Basic search for documents
+    let results = index.search("\"programming\"", Some(SearchOptions::new().full()))?;
+    println!("\nBasic search found {} documents", results.document_count);
+    for result in &results.matches {
+        println!(
+            "- Document {}: score={:.2}",
+            result.doc_id,
+            result.score.unwrap_or_default()
+        );
+    }
+
+    // Using QueryExpr directly
+    let query_expr = QueryExpr::word("programming")
+        .or(QueryExpr::word("world"))
+        .and(QueryExpr::word("hello").not());
+    let expr_results = index.search_expr(&query_expr, None)?;
+    println!(
+        "\nExpression search for {} found {} documents",
+        query_expr, expr_results.document_count
+    );
+
+    // Using QueryBuilder fluently
+    let builder = QueryBuilder::word("programming")
+        .and("tutorials")
+        .or_expr(QueryExpr::word("world").and(QueryExpr::word("peace")));
+    let builder_results = index.search_builder(builder, None)?;
+    println!(
+        "Builder search found {} documents",
+        builder_results.document_count
+    );
+
+    // Update and read back a document
+    let update_response = index.update_document(&doc1.id, "Updated content".to_string())?;
+    println!(
+        "\nDocument updated: revision={}, {} keywords added, {} removed",
+        update_response.revision, update_response.keywords_added, update_response.keywords_removed
+    );
+    let retrieved_doc = index.get_document(&doc1.id)?;
+    println!("Retrieved document body: {:?}", retrieved_doc.document_body);
+
+    // Look up a keyword
+    let keyword_response = index.keyword("programming", Some(10), None)?;
+    println!(
+        "Keyword '{}' found in {} documents",
+        keyword_response.keyword, keyword_response.document_count
+    );
+
+    let stats = index.stats()?;
+    println!(
+        "Index info: {} docs, version {}",
+        stats.docs_count, stats.version
+    );
+
+    // Clean up
+    for doc in [&doc1, &doc2, &doc3] {
+        index.delete_document(&doc.id)?;
+    }
+    let deleted = index.delete()?;
+    println!("Index deleted: {}", deleted.deleted);
+
+    Ok(())
+}
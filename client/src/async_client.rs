@@ -0,0 +1,818 @@
+//! A non-blocking client with the same endpoints as the blocking
+//! [`Client`](crate::http::Client), for use from async code. Its default transport is
+//! an async reqwest client, which must be driven by a tokio runtime.
+
+use std::{collections::HashMap, sync::OnceLock};
+
+use futures::future::BoxFuture;
+use serde::Deserialize;
+
+use crate::{
+    endpoints::{self, Call},
+    http::{
+        build_request, handle_exists, handle_export, handle_response, AutoCreate, ContentType,
+        HttpClient, HttpMethod, HttpRequest, HttpResponse,
+    },
+    index::IndexHandle,
+    query::{QueryBuilder, QueryExpr},
+    ActivityEvent, AddDocumentResponse, ClientError, DeleteDocumentResponse, DeletedResponse,
+    Document, DocumentKeywords, DocumentPage, FsckReport, GetKeywordResponse, IndexDocument,
+    IndexListing, IndexMetadata, IndexSettings, IndexTemplate, KeywordExportPage, KeywordScores,
+    KeywordTrend, RelatedKeyword, ReshardReport, RestoreReport, Result, SavedQuery, SearchMode,
+    SearchOptions, SearchResponse, SnapshotListing, SnapshotReport, StatusResponse, StopList,
+    TopBy, TopReport, UpgradeReport, UsageDay, WarmReport, CAPABILITY_QUERY_AST,
+};
+
+pub struct AsyncClient {
+    base_url: String,
+    api_key: Option<String>,
+    transport: Box<dyn HttpClient>,
+    /// Whether the server takes query ASTs, once [`Self::status`] was asked
+    query_ast: OnceLock<bool>,
+    /// Whether document writes create their missing index, see
+    /// [`Self::with_auto_create_indexes`]
+    auto_create_indexes: bool,
+    auto_create_settings: Option<IndexSettings>,
+}
+
+/// The default transport of [`AsyncClient`], sending requests with an async reqwest
+/// client
+#[derive(Default)]
+pub struct AsyncReqwestTransport {
+    client: reqwest::Client,
+}
+
+impl AsyncReqwestTransport {
+    async fn send(&self, request: HttpRequest) -> Result<HttpResponse> {
+        let builder = match request.method {
+            HttpMethod::GET => self.client.get(&request.url),
+            HttpMethod::POST => self.client.post(&request.url),
+            HttpMethod::PUT => self.client.put(&request.url),
+            HttpMethod::PATCH => self.client.patch(&request.url),
+            HttpMethod::DELETE => self.client.delete(&request.url),
+            HttpMethod::HEAD => self.client.head(&request.url),
+        };
+        let builder = request
+            .headers
+            .iter()
+            .fold(builder, |builder, (name, value)| {
+                builder.header(name, value)
+            });
+        let builder = match request.body {
+            Some(body) => builder.body(body),
+            None => builder,
+        };
+
+        let response = builder.send().await.map_err(ClientError::Reqwest)?;
+        let status = response.status().as_u16();
+        let body = response.text().await.map_err(ClientError::Reqwest)?;
+        Ok(HttpResponse { status, body })
+    }
+}
+
+impl HttpClient for AsyncReqwestTransport {
+    fn request(&self, request: HttpRequest) -> BoxFuture<'_, Result<HttpResponse>> {
+        Box::pin(self.send(request))
+    }
+}
+
+impl AsyncClient {
+    pub fn new(base_url: String) -> Self {
+        Self {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            api_key: None,
+            query_ast: OnceLock::new(),
+            transport: Box::new(AsyncReqwestTransport::default()),
+            auto_create_indexes: false,
+            auto_create_settings: None,
+        }
+    }
+
+    pub fn with_api_key(mut self, api_key: String) -> Self {
+        self.api_key = Some(api_key);
+        self
+    }
+
+    /// Send requests through `transport` instead of reqwest, e.g. to test code that
+    /// uses the client against a [`crate::mock::MockTransport`]
+    pub fn with_transport<T: HttpClient + 'static>(mut self, transport: T) -> Self {
+        self.transport = Box::new(transport);
+        self
+    }
+
+    /// Create the index [`Self::add_document`] or [`Self::add_document_id`] writes to
+    /// when it is missing, then send the write once more. Searches and every other
+    /// call still fail on a missing index.
+    pub fn with_auto_create_indexes(mut self, enabled: bool) -> Self {
+        self.auto_create_indexes = enabled;
+        self
+    }
+
+    /// The settings indexes made by [`Self::with_auto_create_indexes`] are created with
+    pub fn with_auto_create_settings(mut self, settings: IndexSettings) -> Self {
+        self.auto_create_settings = Some(settings);
+        self
+    }
+
+    /// A handle on one index, whose calls don't take the index name
+    pub fn index(&self, name: &str) -> IndexHandle<'_, AsyncClient> {
+        IndexHandle::new(self, name)
+    }
+
+    // Status endpoint
+    pub async fn status(&self) -> Result<StatusResponse> {
+        self.call(endpoints::status()).await
+    }
+
+    // Index management endpoints
+    pub async fn list_indexes(&self) -> Result<Vec<String>> {
+        self.call(endpoints::list_indexes()).await
+    }
+
+    /// List every index with its index document, in one request
+    pub async fn list_indexes_detailed(&self) -> Result<IndexListing> {
+        self.call(endpoints::list_indexes_detailed()).await
+    }
+
+    /// List the indexes labelled `name: value` with their index documents
+    pub async fn list_indexes_with_label(&self, name: &str, value: &str) -> Result<IndexListing> {
+        self.call(endpoints::list_indexes_with_label(name, value))
+            .await
+    }
+
+    pub async fn get_index(&self, index: &str) -> Result<IndexDocument> {
+        self.call(endpoints::get_index(index)).await
+    }
+
+    /// Whether the index exists, checked without fetching it
+    pub async fn index_exists(&self, index: &str) -> Result<bool> {
+        self.exists(endpoints::index_exists(index)).await
+    }
+
+    pub async fn create_index(&self, index: &str) -> Result<IndexDocument> {
+        self.call(endpoints::create_index(index, None)).await
+    }
+
+    /// Create an index whose documents fall back to `lang` when their language can't
+    /// be detected confidently
+    pub async fn create_index_with_lang(&self, index: &str, lang: &str) -> Result<IndexDocument> {
+        self.call(endpoints::create_index(index, Some(lang))).await
+    }
+
+    /// Replace the search defaults of an index, creating it if it doesn't exist
+    pub async fn set_index_settings(
+        &self,
+        index: &str,
+        settings: &IndexSettings,
+    ) -> Result<IndexDocument> {
+        self.call(endpoints::set_index_settings(index, settings)?)
+            .await
+    }
+
+    /// Create an index with a description and labels, or apply them to the existing
+    /// index, leaving its settings alone
+    pub async fn create_index_with_metadata(
+        &self,
+        index: &str,
+        metadata: &IndexMetadata,
+    ) -> Result<IndexDocument> {
+        self.call(endpoints::create_index_with_metadata(index, metadata)?)
+            .await
+    }
+
+    /// Change the description and labels of an existing index, leaving its settings
+    /// alone
+    pub async fn update_index_metadata(
+        &self,
+        index: &str,
+        metadata: &IndexMetadata,
+    ) -> Result<IndexDocument> {
+        self.call(endpoints::update_index_metadata(index, metadata)?)
+            .await
+    }
+
+    pub async fn delete_index(&self, index: &str) -> Result<DeletedResponse> {
+        self.call(endpoints::delete_index(index)).await
+    }
+
+    /// Reject document writes to an index until [`Self::unfreeze_index`]. Needs the
+    /// API key itself, even when the server runs with `AUTH_DISABLED=true`.
+    pub async fn freeze_index(&self, index: &str) -> Result<IndexDocument> {
+        self.call(endpoints::freeze_index(index)).await
+    }
+
+    pub async fn unfreeze_index(&self, index: &str) -> Result<IndexDocument> {
+        self.call(endpoints::unfreeze_index(index)).await
+    }
+
+    // Document endpoints
+    pub async fn get_document(&self, index: &str, doc_id: &str) -> Result<Document> {
+        self.call(endpoints::get_document(index, doc_id)).await
+    }
+
+    /// The next page of the index's documents, without their bodies, keeping those
+    /// updated at or after `updated_since` milliseconds since the epoch. Pass back
+    /// the returned cursor until it is `None` to list every document.
+    pub async fn list_documents(
+        &self,
+        index: &str,
+        cursor: Option<&str>,
+        updated_since: Option<u64>,
+    ) -> Result<DocumentPage> {
+        self.call(endpoints::list_documents(index, cursor, updated_since))
+            .await
+    }
+
+    /// Whether the document exists, checked without fetching it. A missing index
+    /// also reads as `false`.
+    pub async fn document_exists(&self, index: &str, doc_id: &str) -> Result<bool> {
+        self.exists(endpoints::document_exists(index, doc_id)).await
+    }
+
+    /// Fetch a document, or `None` when `etag` (see [`Document::etag`]) is still
+    /// its current revision
+    pub async fn get_document_if_modified(
+        &self,
+        index: &str,
+        doc_id: &str,
+        etag: &str,
+    ) -> Result<Option<Document>> {
+        self.call(endpoints::get_document_if_modified(index, doc_id, etag))
+            .await
+    }
+
+    /// Fetch a document's keywords and the shards their postings live in. With
+    /// `verify`, the server also checks each shard actually holds the posting.
+    pub async fn document_keywords(
+        &self,
+        index: &str,
+        doc_id: &str,
+        verify: bool,
+    ) -> Result<DocumentKeywords> {
+        self.call(endpoints::document_keywords(index, doc_id, verify))
+            .await
+    }
+
+    pub async fn add_document_id(
+        &self,
+        index: &str,
+        doc_id: &str,
+        body: String,
+        lang: Option<&str>,
+        content_type: Option<ContentType>,
+    ) -> Result<AddDocumentResponse> {
+        let call = endpoints::add_document(index, Some(doc_id), body, lang, content_type);
+        self.write_document(index, call).await
+    }
+
+    pub async fn add_document(
+        &self,
+        index: &str,
+        body: String,
+        lang: Option<&str>,
+        content_type: Option<ContentType>,
+    ) -> Result<AddDocumentResponse> {
+        let call = endpoints::add_document(index, None, body, lang, content_type);
+        self.write_document(index, call).await
+    }
+
+    pub async fn update_document(
+        &self,
+        index: &str,
+        doc_id: &str,
+        body: String,
+    ) -> Result<AddDocumentResponse> {
+        self.call(endpoints::update_document(index, doc_id, body))
+            .await
+    }
+
+    pub async fn delete_document(
+        &self,
+        index: &str,
+        doc_id: &str,
+    ) -> Result<DeleteDocumentResponse> {
+        self.call(endpoints::delete_document(index, doc_id)).await
+    }
+
+    // Search endpoint
+    /// Search with `options`, or the server's defaults when `None`
+    pub async fn search(
+        &self,
+        index: &str,
+        query: &str,
+        options: Option<SearchOptions>,
+    ) -> Result<SearchResponse> {
+        self.search_with_options(index, query, &options.unwrap_or_default())
+            .await
+    }
+
+    #[deprecated(note = "use `search` with `SearchOptions::new().full()`")]
+    pub async fn search_full(
+        &self,
+        index: &str,
+        query: &str,
+        full: Option<bool>,
+    ) -> Result<SearchResponse> {
+        self.search(
+            index,
+            query,
+            Some(SearchOptions {
+                full,
+                ..SearchOptions::default()
+            }),
+        )
+        .await
+    }
+
+    /// Search with explicit options, e.g. to trim the fields returned per match
+    pub async fn search_with_options(
+        &self,
+        index: &str,
+        query: &str,
+        options: &SearchOptions,
+    ) -> Result<SearchResponse> {
+        self.call(endpoints::search(index, query, options)).await
+    }
+
+    /// Search using a QueryExpr, sent as its AST to servers that take one and as a
+    /// query string to older ones
+    pub async fn search_expr(
+        &self,
+        index: &str,
+        expr: &QueryExpr,
+        options: Option<SearchOptions>,
+    ) -> Result<SearchResponse> {
+        let options = options.unwrap_or_default();
+        if self.supports_query_ast().await {
+            self.call(endpoints::search_ast(index, expr, &options))
+                .await
+        } else {
+            self.search_with_options(index, &expr.to_query_string(), &options)
+                .await
+        }
+    }
+
+    #[deprecated(note = "use `search_expr` with `SearchOptions::new().full()`")]
+    pub async fn search_expr_full(
+        &self,
+        index: &str,
+        expr: &QueryExpr,
+        full: Option<bool>,
+    ) -> Result<SearchResponse> {
+        self.search_expr(
+            index,
+            expr,
+            Some(SearchOptions {
+                full,
+                ..SearchOptions::default()
+            }),
+        )
+        .await
+    }
+
+    /// Whether the server takes a search's query as its AST, which servers older
+    /// than the capability don't. A failed check falls back to the query string.
+    async fn supports_query_ast(&self) -> bool {
+        if let Some(supported) = self.query_ast.get() {
+            return *supported;
+        }
+        match self.status().await {
+            Ok(status) => *self
+                .query_ast
+                .get_or_init(|| status.supports(CAPABILITY_QUERY_AST)),
+            Err(_) => false,
+        }
+    }
+
+    /// The IDs of the documents matching `query`, best first, without their rows
+    pub async fn search_ids(&self, index: &str, query: &str) -> Result<Vec<String>> {
+        self.call(endpoints::search_ids(index, query))
+            .await
+            .map(|response| response.ids)
+    }
+
+    /// Search a search box's free text, whose words the server combines by `mode`,
+    /// also trying adjacent pairs of them as one keyword
+    pub async fn search_simple(
+        &self,
+        index: &str,
+        text: &str,
+        mode: SearchMode,
+    ) -> Result<SearchResponse> {
+        self.call(endpoints::search_text(index, text, mode)).await
+    }
+
+    /// Search using a QueryBuilder
+    pub async fn search_builder(
+        &self,
+        index: &str,
+        builder: QueryBuilder,
+        options: Option<SearchOptions>,
+    ) -> Result<SearchResponse> {
+        match builder.to_query_string() {
+            Some(query) => self.search(index, &query, options).await,
+            None => Err(ClientError::EmptyQuery),
+        }
+    }
+
+    #[deprecated(note = "use `search_builder` with `SearchOptions::new().full()`")]
+    pub async fn search_builder_full(
+        &self,
+        index: &str,
+        builder: QueryBuilder,
+        full: Option<bool>,
+    ) -> Result<SearchResponse> {
+        self.search_builder(
+            index,
+            builder,
+            Some(SearchOptions {
+                full,
+                ..SearchOptions::default()
+            }),
+        )
+        .await
+    }
+
+    // Keyword endpoint
+    /// Fetch the best scored postings of `keyword`, skipping the first `offset` and
+    /// returning at most `limit` (all of them when `None`). Small pages may be
+    /// gathered from some of the keyword's shards; see [`GetKeywordResponse::exact`].
+    pub async fn get_keyword(
+        &self,
+        index: &str,
+        keyword: &str,
+        limit: Option<usize>,
+        offset: Option<usize>,
+    ) -> Result<GetKeywordResponse> {
+        self.call(endpoints::get_keyword(index, keyword, limit, offset))
+            .await
+    }
+
+    /// Fetch the keywords that most often appear alongside `keyword`, at most `limit`
+    /// of them (10 when `None`)
+    pub async fn related_keywords(
+        &self,
+        index: &str,
+        keyword: &str,
+        limit: Option<usize>,
+    ) -> Result<Vec<RelatedKeyword>> {
+        self.call(endpoints::related_keywords(index, keyword, limit))
+            .await
+    }
+
+    /// Fetch how many documents gained or lost `keyword` on each of the last `days`
+    /// days (30 when `None`)
+    pub async fn keyword_trend(
+        &self,
+        index: &str,
+        keyword: &str,
+        days: Option<u32>,
+    ) -> Result<KeywordTrend> {
+        self.call(endpoints::keyword_trend(index, keyword, days))
+            .await
+    }
+
+    /// Fetch the merged scores of many keywords in a single request
+    pub async fn get_keywords(
+        &self,
+        index: &str,
+        keywords: Vec<&str>,
+    ) -> Result<HashMap<String, KeywordScores>> {
+        self.call(endpoints::get_keywords(index, keywords)?).await
+    }
+
+    // Stop-list endpoints
+    pub async fn get_stoplist(&self, index: &str) -> Result<StopList> {
+        self.call(endpoints::get_stoplist(index)).await
+    }
+
+    /// Replace the stop-list of an index, returning it as the server normalized it
+    pub async fn set_stoplist(&self, index: &str, keywords: &[&str]) -> Result<StopList> {
+        self.call(endpoints::set_stoplist(index, keywords)?).await
+    }
+
+    // Index template endpoints
+    pub async fn list_templates(&self) -> Result<Vec<IndexTemplate>> {
+        self.call(endpoints::list_templates()).await
+    }
+
+    pub async fn get_template(&self, name: &str) -> Result<IndexTemplate> {
+        self.call(endpoints::get_template(name)).await
+    }
+
+    /// Create or replace the template `name`, applied to indexes created without
+    /// settings afterwards, returning it as the server normalized it
+    pub async fn put_template(
+        &self,
+        name: &str,
+        template: &IndexTemplate,
+    ) -> Result<IndexTemplate> {
+        self.call(endpoints::put_template(name, template)?).await
+    }
+
+    pub async fn delete_template(&self, name: &str) -> Result<DeletedResponse> {
+        self.call(endpoints::delete_template(name)).await
+    }
+
+    // Saved query endpoints
+    pub async fn list_queries(&self, index: &str) -> Result<Vec<SavedQuery>> {
+        self.call(endpoints::list_queries(index)).await
+    }
+
+    pub async fn get_query(&self, index: &str, name: &str) -> Result<SavedQuery> {
+        self.call(endpoints::get_query(index, name)).await
+    }
+
+    /// Create or replace the saved query `name`, which the server refuses unless
+    /// its query parses
+    pub async fn save_query(
+        &self,
+        index: &str,
+        name: &str,
+        query: &SavedQuery,
+    ) -> Result<SavedQuery> {
+        self.call(endpoints::save_query(index, name, query)?).await
+    }
+
+    pub async fn delete_query(&self, index: &str, name: &str) -> Result<DeletedResponse> {
+        self.call(endpoints::delete_query(index, name)).await
+    }
+
+    /// Search with the saved query `name`, each of its placeholders replaced by the
+    /// value given for it in `values` as a quoted keyword. Options set in `options`
+    /// replace the saved ones of the same name.
+    pub async fn run_query(
+        &self,
+        index: &str,
+        name: &str,
+        values: &[(&str, &str)],
+        options: Option<SearchOptions>,
+    ) -> Result<SearchResponse> {
+        self.call(endpoints::run_query(
+            index,
+            name,
+            values,
+            &options.unwrap_or_default(),
+        )?)
+        .await
+    }
+
+    /// Check the next batch of an index's documents and keyword shards for
+    /// inconsistent postings, fixing them with `repair`. Pass back the returned
+    /// cursor until it is `None` to check the whole index.
+    pub async fn fsck(
+        &self,
+        index: &str,
+        cursor: Option<&str>,
+        repair: bool,
+    ) -> Result<FsckReport> {
+        self.call(endpoints::fsck(index, cursor, repair)).await
+    }
+
+    /// Run the next batch of moving a frozen index's keyword shards to
+    /// `target_shards` shards. Pass back the returned cursor until it is `None`,
+    /// then unfreeze the index.
+    pub async fn reshard(
+        &self,
+        index: &str,
+        target_shards: u32,
+        cursor: Option<&str>,
+    ) -> Result<ReshardReport> {
+        self.call(endpoints::reshard(index, target_shards, cursor))
+            .await
+    }
+
+    /// Run the next batch of upgrading a frozen index to the latest version. Pass
+    /// back the returned cursor until it is `None`, then unfreeze the index.
+    pub async fn upgrade(&self, index: &str, cursor: Option<&str>) -> Result<UpgradeReport> {
+        self.call(endpoints::upgrade(index, cursor)).await
+    }
+
+    /// The index's daily usage over the last `days` days, 7 when `None`, oldest first
+    pub async fn usage(&self, index: &str, days: Option<u32>) -> Result<Vec<UsageDay>> {
+        Ok(self.call(endpoints::usage(index, days)).await?.days)
+    }
+
+    /// The index's recent document changes, newest first, only those made at or
+    /// after `since` (milliseconds since the epoch) when given
+    pub async fn activity(&self, index: &str, since: Option<u64>) -> Result<Vec<ActivityEvent>> {
+        Ok(self.call(endpoints::activity(index, since)).await?.events)
+    }
+
+    /// Rank the next batch of an index's documents by stored size or keywords by
+    /// postings, the best `limit` of them. `sample` reads only that share of the
+    /// documents. Needs the API key itself; pass back the returned cursor until it
+    /// is `None`, keeping the best entries of every batch.
+    pub async fn top(
+        &self,
+        index: &str,
+        by: TopBy,
+        limit: Option<u32>,
+        sample: Option<f64>,
+        cursor: Option<&str>,
+    ) -> Result<TopReport> {
+        self.call(endpoints::top(index, by, limit, sample, cursor))
+            .await
+    }
+
+    /// Merge `keywords` ahead of the searches for them, keeping the postings of those
+    /// stored in every shard. Only indexes without a recorded shard count can be
+    /// warmed.
+    pub async fn warm(&self, index: &str, keywords: &[&str]) -> Result<WarmReport> {
+        self.call(endpoints::warm(index, keywords)?).await
+    }
+
+    /// [`Self::warm`] the `top` keywords with the most postings in the next batch of
+    /// a `keyword_postings` walk. Needs the API key itself; pass back the returned
+    /// cursor until it is `None` to warm the whole index.
+    pub async fn warm_top(
+        &self,
+        index: &str,
+        top: u32,
+        cursor: Option<&str>,
+    ) -> Result<WarmReport> {
+        self.call(endpoints::warm_top(index, top, cursor)).await
+    }
+
+    /// The next `limit` keywords of an index with their shard and document counts,
+    /// resuming from `cursor`, or after the keyword `after`. Needs the API key
+    /// itself; pass back the returned cursor until it is `None`.
+    pub async fn export_keywords_page(
+        &self,
+        index: &str,
+        limit: Option<u32>,
+        cursor: Option<&str>,
+        after: Option<&str>,
+    ) -> Result<KeywordExportPage> {
+        let call = endpoints::export_keywords(index, limit, cursor, after);
+        let request = build_request(&self.base_url, self.api_key.as_deref(), call);
+        handle_export(self.transport.request(request).await?)
+    }
+
+    /// Write the next batch of a snapshot of a frozen index to R2, starting one
+    /// when none is in progress. Call again until the report is `complete`.
+    pub async fn snapshot(&self, index: &str) -> Result<SnapshotReport> {
+        self.call(endpoints::snapshot(index)).await
+    }
+
+    /// The index's complete snapshots, oldest first
+    pub async fn list_snapshots(&self, index: &str) -> Result<Vec<SnapshotListing>> {
+        Ok(self.call(endpoints::list_snapshots(index)).await?.snapshots)
+    }
+
+    /// Run the next batch of replacing a frozen index's contents with `snapshot`.
+    /// Call again until the report is `complete`, then unfreeze the index.
+    pub async fn restore(&self, index: &str, snapshot: u64) -> Result<RestoreReport> {
+        self.call(endpoints::restore(index, snapshot)).await
+    }
+
+    async fn call<T>(&self, call: Call<T>) -> Result<T>
+    where
+        T: for<'de> Deserialize<'de>,
+    {
+        let request = build_request(&self.base_url, self.api_key.as_deref(), call);
+        let response = self.transport.request(request).await?;
+        handle_response(response)
+    }
+
+    async fn exists(&self, call: Call<bool>) -> Result<bool> {
+        let request = build_request(&self.base_url, self.api_key.as_deref(), call);
+        let response = self.transport.request(request).await?;
+        handle_exists(response)
+    }
+
+    /// Send a document write, creating its index first if it's missing and
+    /// [`Self::with_auto_create_indexes`] is on
+    async fn write_document(
+        &self,
+        index: &str,
+        call: Call<AddDocumentResponse>,
+    ) -> Result<AddDocumentResponse> {
+        let request = build_request(&self.base_url, self.api_key.as_deref(), call);
+        let mut state = AutoCreate::start(self.auto_create_indexes);
+        loop {
+            let response = self.transport.request(request.clone()).await?;
+            let written = handle_response(response);
+            if !state.should_create(&written) {
+                return written;
+            }
+            let create = endpoints::auto_create_index(index, self.auto_create_settings.as_ref())?;
+            self.call(create).await?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+
+    use super::*;
+    use crate::{mock::MockTransport, ErrorCode};
+
+    fn client(transport: &MockTransport) -> AsyncClient {
+        AsyncClient::new("https://search.example/".into())
+            .with_api_key("secret".into())
+            .with_transport(transport.clone())
+    }
+
+    #[test]
+    fn test_search() {
+        let transport = MockTransport::new();
+        transport.respond(200, r#"{"document_count":0,"matches":[]}"#);
+
+        let options = SearchOptions::new().limit(5);
+        let response = block_on(client(&transport).search("idx", "ocean", Some(options))).unwrap();
+        assert_eq!(response.document_count, 0);
+
+        let request = transport.last_request().unwrap();
+        assert_eq!(request.method, HttpMethod::POST);
+        assert_eq!(
+            request.url,
+            "https://search.example/idx/search?query=ocean&limit=5"
+        );
+        assert_eq!(request.headers["X-API-Key"], "secret");
+    }
+
+    #[test]
+    fn test_set_stoplist() {
+        let transport = MockTransport::new();
+        transport.respond(200, r#"{"keywords":["the"]}"#);
+
+        block_on(client(&transport).set_stoplist("idx", &["The"])).unwrap();
+        let request = transport.last_request().unwrap();
+        assert_eq!(request.method, HttpMethod::PUT);
+        assert_eq!(request.url, "https://search.example/idx/stoplist");
+        assert_eq!(request.body.as_deref(), Some(r#"["The"]"#));
+    }
+
+    #[test]
+    fn test_put_template() {
+        let transport = MockTransport::new();
+        transport.respond(
+            200,
+            r#"{"name":"tenants","pattern":"tenant-*","stoplist":["acme"]}"#,
+        );
+
+        let template = IndexTemplate {
+            pattern: "tenant-*".into(),
+            stoplist: vec!["Acme".into()],
+            ..IndexTemplate::default()
+        };
+        let saved = block_on(client(&transport).put_template("tenants", &template)).unwrap();
+        assert_eq!(
+            (saved.name.as_str(), saved.stoplist),
+            ("tenants", vec!["acme".into()])
+        );
+        let request = transport.last_request().unwrap();
+        assert_eq!(request.method, HttpMethod::PUT);
+        assert_eq!(request.url, "https://search.example/_templates/tenants");
+        assert_eq!(
+            request.body.as_deref(),
+            Some(r#"{"pattern":"tenant-*","settings":{},"stoplist":["Acme"]}"#)
+        );
+    }
+
+    #[test]
+    fn test_run_query() {
+        let transport = MockTransport::new();
+        transport.respond(200, r#"{"document_count":0,"matches":[]}"#);
+
+        block_on(client(&transport).run_query("idx", "recipes", &[], None)).unwrap();
+        let request = transport.last_request().unwrap();
+        assert_eq!(request.method, HttpMethod::POST);
+        assert_eq!(
+            request.url,
+            "https://search.example/idx/queries/recipes/run"
+        );
+        assert_eq!(request.body.as_deref(), Some("{}"));
+    }
+
+    #[test]
+    fn test_document_exists() {
+        let transport = MockTransport::new();
+        transport.respond(200, "").respond(404, "");
+
+        let client = client(&transport);
+        assert!(block_on(client.document_exists("idx", "doc1")).unwrap());
+        assert!(!block_on(client.document_exists("idx", "doc2")).unwrap());
+        let request = transport.last_request().unwrap();
+        assert_eq!(request.method, HttpMethod::HEAD);
+        assert_eq!(request.url, "https://search.example/idx/doc/doc2");
+        assert_eq!(request.body, None);
+    }
+
+    #[test]
+    fn test_error_mapping() {
+        let transport = MockTransport::new();
+        transport.respond(
+            404,
+            r#"{"error":"Document 'doc1' not found","code":"document_not_found"}"#,
+        );
+
+        match block_on(client(&transport).delete_document("idx", "doc1")) {
+            Err(ClientError::NotFound(api)) => assert_eq!(api.code, ErrorCode::DocumentNotFound),
+            other => panic!("expected a not found error, got {:?}", other),
+        }
+        assert_eq!(transport.requests()[0].body, None);
+    }
+}
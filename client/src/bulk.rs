@@ -0,0 +1,498 @@
+//! Uploading many documents at once through the per-document endpoint, with retries
+//! and progress reporting.
+//!
+//! Documents are taken from the iterator in batches of [`UploadOptions::batch_size`].
+//! The blocking [`Client`] sends each batch one document at a time, while the
+//! [`AsyncClient`] keeps up to [`UploadOptions::concurrency`] requests in flight. The
+//! progress callback runs after every batch.
+
+#[cfg(feature = "async")]
+use crate::async_client::AsyncClient;
+#[cfg(feature = "blocking")]
+use crate::http::Client;
+use crate::{http::ContentType, index::IndexHandle, AddDocumentResponse, ClientError, Result};
+
+/// A document to upload, with the same options as a single add-document request
+#[derive(Debug, Clone)]
+pub struct NewDocument {
+    /// The ID to store the document under, or `None` to have the server assign one
+    pub id: Option<String>,
+    pub body: String,
+    pub lang: Option<String>,
+    pub content_type: Option<ContentType>,
+}
+
+impl NewDocument {
+    pub fn new(body: impl Into<String>) -> NewDocument {
+        NewDocument {
+            id: None,
+            body: body.into(),
+            lang: None,
+            content_type: None,
+        }
+    }
+
+    pub fn with_id(mut self, id: impl Into<String>) -> NewDocument {
+        self.id = Some(id.into());
+        self
+    }
+
+    pub fn with_lang(mut self, lang: impl Into<String>) -> NewDocument {
+        self.lang = Some(lang.into());
+        self
+    }
+
+    pub fn with_content_type(mut self, content_type: ContentType) -> NewDocument {
+        self.content_type = Some(content_type);
+        self
+    }
+}
+
+/// How far an upload has got, passed to [`UploadOptions::on_progress`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UploadProgress {
+    /// Documents taken from the iterator and sent at least once
+    pub sent: usize,
+    pub succeeded: usize,
+    /// Documents that still failed after their last retry
+    pub failed: usize,
+}
+
+pub struct UploadOptions {
+    /// Documents taken from the iterator between progress reports
+    pub batch_size: usize,
+    /// The most requests the async client has in flight at once. The blocking client
+    /// always sends one at a time.
+    pub concurrency: usize,
+    /// How many times a document is resent after a retryable failure: a transport
+    /// error, a 429 or a 5xx response. Retries are sent straight away.
+    pub max_retries: u32,
+    pub progress: Option<Box<dyn FnMut(UploadProgress) + Send>>,
+}
+
+impl Default for UploadOptions {
+    fn default() -> Self {
+        UploadOptions {
+            batch_size: 100,
+            concurrency: 4,
+            max_retries: 2,
+            progress: None,
+        }
+    }
+}
+
+impl UploadOptions {
+    /// Call `progress` after every batch
+    pub fn on_progress(mut self, progress: impl FnMut(UploadProgress) + Send + 'static) -> Self {
+        self.progress = Some(Box::new(progress));
+        self
+    }
+}
+
+/// What happened to one uploaded document
+#[derive(Debug)]
+pub struct UploadOutcome {
+    /// Where the document came in the uploaded iterator
+    pub position: usize,
+    /// The document's ID, as given or as assigned by the server. `None` when the
+    /// server never accepted a document without a given ID.
+    pub id: Option<String>,
+    /// Requests sent for the document, including retries
+    pub attempts: u32,
+    pub result: Result<AddDocumentResponse>,
+}
+
+/// The outcome of every uploaded document, in the order they were uploaded
+#[derive(Debug, Default)]
+pub struct UploadReport {
+    pub outcomes: Vec<UploadOutcome>,
+}
+
+impl UploadReport {
+    pub fn succeeded(&self) -> impl Iterator<Item = &UploadOutcome> {
+        self.outcomes
+            .iter()
+            .filter(|outcome| outcome.result.is_ok())
+    }
+
+    pub fn failed(&self) -> impl Iterator<Item = &UploadOutcome> {
+        self.outcomes
+            .iter()
+            .filter(|outcome| outcome.result.is_err())
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.failed().next().is_none()
+    }
+}
+
+/// Whether sending the same document again might succeed
+fn is_retryable(err: &ClientError) -> bool {
+    match err {
+        ClientError::Http(_) | ClientError::Reqwest(_) => true,
+        ClientError::Api(api) => api.is_retryable(),
+        _ => false,
+    }
+}
+
+/// Collects outcomes into the report, and the progress handed to the callback
+struct Tally {
+    options: UploadOptions,
+    progress: UploadProgress,
+    report: UploadReport,
+}
+
+impl Tally {
+    fn new(options: UploadOptions) -> Tally {
+        Tally {
+            options,
+            progress: UploadProgress::default(),
+            report: UploadReport::default(),
+        }
+    }
+
+    /// The next batch of documents, numbered by their position in the upload
+    fn next_batch(
+        &self,
+        documents: &mut impl Iterator<Item = NewDocument>,
+    ) -> Vec<(usize, NewDocument)> {
+        let start = self.progress.sent;
+        documents
+            .take(self.options.batch_size.max(1))
+            .enumerate()
+            .map(|(offset, document)| (start + offset, document))
+            .collect()
+    }
+
+    fn record(
+        &mut self,
+        position: usize,
+        document: NewDocument,
+        attempts: u32,
+        result: Result<AddDocumentResponse>,
+    ) {
+        let id = match &result {
+            Ok(response) => Some(response.id.clone()),
+            Err(_) => document.id,
+        };
+        match result {
+            Ok(_) => self.progress.succeeded += 1,
+            Err(_) => self.progress.failed += 1,
+        }
+        self.progress.sent += 1;
+        self.report.outcomes.push(UploadOutcome {
+            position,
+            id,
+            attempts,
+            result,
+        });
+    }
+
+    fn report_progress(&mut self) {
+        if let Some(progress) = self.options.progress.as_mut() {
+            progress(self.progress);
+        }
+    }
+}
+
+#[cfg(feature = "blocking")]
+impl Client {
+    /// Add every document to `index`, sending them one at a time and retrying
+    /// failures as `options` allows. Failed documents don't stop the upload; they are
+    /// listed in the returned report.
+    pub fn upload_documents(
+        &self,
+        index: &str,
+        documents: impl IntoIterator<Item = NewDocument>,
+        options: UploadOptions,
+    ) -> UploadReport {
+        let mut documents = documents.into_iter();
+        let mut tally = Tally::new(options);
+        loop {
+            let batch = tally.next_batch(&mut documents);
+            if batch.is_empty() {
+                return tally.report;
+            }
+            for (position, document) in batch {
+                let (attempts, result) =
+                    self.upload_document(index, &document, tally.options.max_retries);
+                tally.record(position, document, attempts, result);
+            }
+            tally.report_progress();
+        }
+    }
+
+    fn upload_document(
+        &self,
+        index: &str,
+        document: &NewDocument,
+        max_retries: u32,
+    ) -> (u32, Result<AddDocumentResponse>) {
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            let body = document.body.clone();
+            let lang = document.lang.as_deref();
+            let result = match &document.id {
+                Some(id) => self.add_document_id(index, id, body, lang, document.content_type),
+                None => self.add_document(index, body, lang, document.content_type),
+            };
+            match result {
+                Err(err) if is_retryable(&err) && attempts <= max_retries => continue,
+                result => return (attempts, result),
+            }
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl AsyncClient {
+    /// Add every document to `index`, with up to `options.concurrency` requests in
+    /// flight, retrying failures as `options` allows. Failed documents don't stop the
+    /// upload; they are listed in the returned report.
+    pub async fn upload_documents(
+        &self,
+        index: &str,
+        documents: impl IntoIterator<Item = NewDocument>,
+        options: UploadOptions,
+    ) -> UploadReport {
+        use futures::stream::{self, StreamExt};
+
+        let mut documents = documents.into_iter();
+        let mut tally = Tally::new(options);
+        loop {
+            let batch = tally.next_batch(&mut documents);
+            if batch.is_empty() {
+                return tally.report;
+            }
+            let max_retries = tally.options.max_retries;
+            let uploads = batch.into_iter().map(|(position, document)| async move {
+                let (attempts, result) = self.upload_document(index, &document, max_retries).await;
+                (position, document, attempts, result)
+            });
+            let mut uploaded: Vec<_> = stream::iter(uploads)
+                .buffer_unordered(tally.options.concurrency.max(1))
+                .collect()
+                .await;
+            uploaded.sort_by_key(|(position, ..)| *position);
+            for (position, document, attempts, result) in uploaded {
+                tally.record(position, document, attempts, result);
+            }
+            tally.report_progress();
+        }
+    }
+
+    async fn upload_document(
+        &self,
+        index: &str,
+        document: &NewDocument,
+        max_retries: u32,
+    ) -> (u32, Result<AddDocumentResponse>) {
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            let body = document.body.clone();
+            let lang = document.lang.as_deref();
+            let result = match &document.id {
+                Some(id) => {
+                    self.add_document_id(index, id, body, lang, document.content_type)
+                        .await
+                }
+                None => {
+                    self.add_document(index, body, lang, document.content_type)
+                        .await
+                }
+            };
+            match result {
+                Err(err) if is_retryable(&err) && attempts <= max_retries => continue,
+                result => return (attempts, result),
+            }
+        }
+    }
+}
+
+#[cfg(feature = "blocking")]
+impl IndexHandle<'_, Client> {
+    pub fn upload_documents(
+        &self,
+        documents: impl IntoIterator<Item = NewDocument>,
+        options: UploadOptions,
+    ) -> UploadReport {
+        self.client()
+            .upload_documents(self.name(), documents, options)
+    }
+}
+
+#[cfg(feature = "async")]
+impl IndexHandle<'_, AsyncClient> {
+    pub async fn upload_documents(
+        &self,
+        documents: impl IntoIterator<Item = NewDocument>,
+        options: UploadOptions,
+    ) -> UploadReport {
+        self.client()
+            .upload_documents(self.name(), documents, options)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "blocking")]
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::mock::MockTransport;
+    #[cfg(feature = "blocking")]
+    use crate::ErrorCode;
+
+    fn added(id: &str) -> String {
+        format!(
+            r#"{{"id":"{}","revision":1,"lang":"EN","keywords_added":1,"keywords_removed":0,
+                "keywords_total":1,"index_docs_count":1}}"#,
+            id
+        )
+    }
+
+    #[cfg(feature = "blocking")]
+    const UNAVAILABLE: &str = r#"{"error":"KV store error","code":"internal_error"}"#;
+    #[cfg(feature = "blocking")]
+    const INVALID: &str = r#"{"error":"Empty body","code":"invalid_request"}"#;
+
+    fn documents() -> Vec<NewDocument> {
+        vec![
+            NewDocument::new("Ocean tides").with_id("ocean"),
+            NewDocument::new("Storm fronts"),
+            NewDocument::new("").with_id("empty"),
+        ]
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn test_upload_retries_and_reports() {
+        let transport = MockTransport::new();
+        transport
+            .respond(503, UNAVAILABLE)
+            .respond(201, &added("ocean"))
+            .respond(201, &added("assigned"))
+            .respond(400, INVALID);
+        let client = Client::new("https://search.example".into()).with_transport(transport.clone());
+
+        let seen = Arc::new(Mutex::new(vec![]));
+        let options = UploadOptions {
+            batch_size: 2,
+            ..Default::default()
+        };
+        let options = options.on_progress({
+            let seen = seen.clone();
+            move |progress| seen.lock().unwrap().push(progress)
+        });
+        let report = client.upload_documents("idx", documents(), options);
+
+        let outcomes: Vec<_> = report
+            .outcomes
+            .iter()
+            .map(|outcome| {
+                (
+                    outcome.position,
+                    outcome.id.as_deref(),
+                    outcome.attempts,
+                    outcome.result.is_ok(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            outcomes,
+            vec![
+                (0, Some("ocean"), 2, true),
+                (1, Some("assigned"), 1, true),
+                (2, Some("empty"), 1, false),
+            ]
+        );
+        assert!(!report.is_complete());
+        match &report.failed().next().unwrap().result {
+            Err(ClientError::Api(api)) => assert_eq!(api.code, ErrorCode::InvalidRequest),
+            other => panic!("expected an API error, got {:?}", other),
+        }
+
+        // One report per batch of two
+        assert_eq!(
+            *seen.lock().unwrap(),
+            vec![
+                UploadProgress {
+                    sent: 2,
+                    succeeded: 2,
+                    failed: 0
+                },
+                UploadProgress {
+                    sent: 3,
+                    succeeded: 2,
+                    failed: 1
+                },
+            ]
+        );
+        let urls: Vec<_> = transport
+            .requests()
+            .into_iter()
+            .map(|request| request.url)
+            .collect();
+        assert_eq!(
+            urls,
+            vec![
+                "https://search.example/idx/doc/ocean",
+                "https://search.example/idx/doc/ocean",
+                "https://search.example/idx/doc",
+                "https://search.example/idx/doc/empty",
+            ]
+        );
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn test_upload_gives_up_after_max_retries() {
+        let transport = MockTransport::new();
+        transport
+            .respond(500, UNAVAILABLE)
+            .respond(502, "<html>Bad Gateway</html>");
+        let client = Client::new("https://search.example".into()).with_transport(transport.clone());
+
+        let options = UploadOptions {
+            max_retries: 1,
+            ..Default::default()
+        };
+        let report = client
+            .index("idx")
+            .upload_documents(vec![NewDocument::new("Ocean")], options);
+        let outcome = &report.outcomes[0];
+        assert_eq!((outcome.id.as_deref(), outcome.attempts), (None, 2));
+        assert!(matches!(outcome.result, Err(ClientError::Http(_))));
+        assert_eq!(transport.requests().len(), 2);
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_async_upload() {
+        let transport = MockTransport::new();
+        transport
+            .respond(201, &added("ocean"))
+            .respond(201, &added("assigned"))
+            .respond(201, &added("empty"));
+        let client =
+            AsyncClient::new("https://search.example".into()).with_transport(transport.clone());
+
+        let options = UploadOptions {
+            concurrency: 2,
+            ..Default::default()
+        };
+        let report =
+            futures::executor::block_on(client.upload_documents("idx", documents(), options));
+        assert!(report.is_complete());
+        let positions: Vec<_> = report
+            .outcomes
+            .iter()
+            .map(|outcome| outcome.position)
+            .collect();
+        assert_eq!(positions, vec![0, 1, 2]);
+        assert_eq!(transport.requests().len(), 3);
+    }
+}
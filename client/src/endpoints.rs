@@ -0,0 +1,507 @@
+//! The method, path and body of every API call, shared by the blocking
+//! [`Client`](crate::http::Client) and the
+//! [`AsyncClient`](crate::async_client::AsyncClient) so both build identical requests.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    marker::PhantomData,
+};
+
+use crate::{
+    http::{ContentType, HttpMethod},
+    query::QueryExpr,
+    ActivityResponse, AddDocumentResponse, DeleteDocumentResponse, DeletedResponse, Document,
+    DocumentKeywords, DocumentPage, FsckReport, GetKeywordResponse, IndexDocument, IndexListing,
+    IndexMetadata, IndexSettings, IndexTemplate, KeywordExportPage, KeywordScores, KeywordTrend,
+    RelatedKeyword, ReshardReport, RestoreReport, Result, SavedQuery, SearchIdsResponse,
+    SearchMode, SearchOptions, SearchResponse, SnapshotList, SnapshotReport, StatusResponse,
+    StopList, TopBy, TopReport, UpgradeReport, UsageSeries, WarmReport,
+};
+
+/// A request to the API, relative to the client's base URL, whose response body
+/// deserializes into `T`
+pub(crate) struct Call<T> {
+    pub method: HttpMethod,
+    pub path: String,
+    pub body: Option<String>,
+    pub headers: Vec<(&'static str, String)>,
+    response: PhantomData<fn() -> T>,
+}
+
+impl<T> Call<T> {
+    fn new(method: HttpMethod, path: String) -> Self {
+        Call {
+            method,
+            path,
+            body: None,
+            headers: vec![],
+            response: PhantomData,
+        }
+    }
+
+    fn with_body(mut self, body: String) -> Self {
+        self.body = Some(body);
+        self
+    }
+
+    fn with_header(mut self, name: &'static str, value: &str) -> Self {
+        self.headers.push((name, value.to_string()));
+        self
+    }
+}
+
+// Status endpoint
+pub(crate) fn status() -> Call<StatusResponse> {
+    Call::new(HttpMethod::GET, "/".to_string())
+}
+
+// Index management endpoints
+pub(crate) fn list_indexes() -> Call<Vec<String>> {
+    Call::new(HttpMethod::GET, "/indexes".to_string())
+}
+
+pub(crate) fn list_indexes_detailed() -> Call<IndexListing> {
+    Call::new(HttpMethod::GET, "/indexes?detail=true".to_string())
+}
+
+pub(crate) fn list_indexes_with_label(name: &str, value: &str) -> Call<IndexListing> {
+    let label = format!("{}:{}", name, value);
+    let path = format!("/indexes?detail=true&label={}", urlencoding::encode(&label));
+    Call::new(HttpMethod::GET, path)
+}
+
+pub(crate) fn get_index(index: &str) -> Call<IndexDocument> {
+    Call::new(HttpMethod::GET, format!("/{}", index))
+}
+
+pub(crate) fn index_exists(index: &str) -> Call<bool> {
+    Call::new(HttpMethod::HEAD, format!("/{}", index))
+}
+
+pub(crate) fn create_index(index: &str, lang: Option<&str>) -> Call<IndexDocument> {
+    let path = match lang {
+        Some(lang) => format!("/{}?lang={}", index, urlencoding::encode(lang)),
+        None => format!("/{}", index),
+    };
+    Call::new(HttpMethod::PUT, path)
+}
+
+pub(crate) fn set_index_settings(
+    index: &str,
+    settings: &IndexSettings,
+) -> Result<Call<IndexDocument>> {
+    let body = serde_json::to_string(settings)?;
+    Ok(Call::new(HttpMethod::PUT, format!("/{}", index)).with_body(body))
+}
+
+/// The call creating an index a document write found missing, with `settings`
+/// when the client has some
+pub(crate) fn auto_create_index(
+    index: &str,
+    settings: Option<&IndexSettings>,
+) -> Result<Call<IndexDocument>> {
+    match settings {
+        Some(settings) => set_index_settings(index, settings),
+        None => Ok(create_index(index, None)),
+    }
+}
+
+pub(crate) fn create_index_with_metadata(
+    index: &str,
+    metadata: &IndexMetadata,
+) -> Result<Call<IndexDocument>> {
+    let body = serde_json::to_string(metadata)?;
+    Ok(Call::new(HttpMethod::PUT, format!("/{}", index)).with_body(body))
+}
+
+pub(crate) fn update_index_metadata(
+    index: &str,
+    metadata: &IndexMetadata,
+) -> Result<Call<IndexDocument>> {
+    let body = serde_json::to_string(metadata)?;
+    Ok(Call::new(HttpMethod::PATCH, format!("/{}", index)).with_body(body))
+}
+
+pub(crate) fn delete_index(index: &str) -> Call<DeletedResponse> {
+    Call::new(HttpMethod::DELETE, format!("/{}", index))
+}
+
+pub(crate) fn freeze_index(index: &str) -> Call<IndexDocument> {
+    Call::new(HttpMethod::POST, format!("/{}/freeze", index))
+}
+
+pub(crate) fn unfreeze_index(index: &str) -> Call<IndexDocument> {
+    Call::new(HttpMethod::POST, format!("/{}/unfreeze", index))
+}
+
+// Document endpoints
+pub(crate) fn get_document(index: &str, doc_id: &str) -> Call<Document> {
+    Call::new(HttpMethod::GET, format!("/{}/doc/{}", index, doc_id))
+}
+
+pub(crate) fn list_documents(
+    index: &str,
+    cursor: Option<&str>,
+    updated_since: Option<u64>,
+) -> Call<DocumentPage> {
+    let mut params = vec![];
+    if let Some(cursor) = cursor {
+        params.push(format!("cursor={}", urlencoding::encode(cursor)));
+    }
+    if let Some(updated_since) = updated_since {
+        params.push(format!("updated_since={}", updated_since));
+    }
+    let path = match params.is_empty() {
+        true => format!("/{}/docs", index),
+        false => format!("/{}/docs?{}", index, params.join("&")),
+    };
+    Call::new(HttpMethod::GET, path)
+}
+
+pub(crate) fn document_exists(index: &str, doc_id: &str) -> Call<bool> {
+    Call::new(HttpMethod::HEAD, format!("/{}/doc/{}", index, doc_id))
+}
+
+/// Fetch a document unless `etag` is still its current revision, which the server
+/// answers with a 304 that reads as `None`
+pub(crate) fn get_document_if_modified(
+    index: &str,
+    doc_id: &str,
+    etag: &str,
+) -> Call<Option<Document>> {
+    Call::new(HttpMethod::GET, format!("/{}/doc/{}", index, doc_id))
+        .with_header("If-None-Match", etag)
+}
+
+pub(crate) fn document_keywords(index: &str, doc_id: &str, verify: bool) -> Call<DocumentKeywords> {
+    let path = format!("/{}/doc/{}/keywords?verify={}", index, doc_id, verify);
+    Call::new(HttpMethod::GET, path)
+}
+
+pub(crate) fn add_document(
+    index: &str,
+    doc_id: Option<&str>,
+    body: String,
+    lang: Option<&str>,
+    content_type: Option<ContentType>,
+) -> Call<AddDocumentResponse> {
+    let path = match doc_id {
+        Some(doc_id) => format!("/{}/doc/{}", index, doc_id),
+        None => format!("/{}/doc", index),
+    };
+    let path = path + &add_document_query(lang, content_type);
+    Call::new(HttpMethod::POST, path).with_body(body)
+}
+
+pub(crate) fn update_document(
+    index: &str,
+    doc_id: &str,
+    body: String,
+) -> Call<AddDocumentResponse> {
+    Call::new(HttpMethod::PATCH, format!("/{}/doc/{}", index, doc_id)).with_body(body)
+}
+
+pub(crate) fn delete_document(index: &str, doc_id: &str) -> Call<DeleteDocumentResponse> {
+    Call::new(HttpMethod::DELETE, format!("/{}/doc/{}", index, doc_id))
+}
+
+// Search endpoint
+pub(crate) fn search(index: &str, query: &str, options: &SearchOptions) -> Call<SearchResponse> {
+    let mut path = format!("/{}/search?query={}", index, urlencoding::encode(query));
+    path.push_str(&options.to_query_params());
+    Call::new(HttpMethod::POST, path)
+}
+
+pub(crate) fn search_ids(index: &str, query: &str) -> Call<SearchIdsResponse> {
+    let path = format!(
+        "/{}/search?query={}&ids_only=true",
+        index,
+        urlencoding::encode(query)
+    );
+    Call::new(HttpMethod::POST, path)
+}
+
+/// A search of a search box's free text, which the server rewrites into a query
+pub(crate) fn search_text(index: &str, text: &str, mode: SearchMode) -> Call<SearchResponse> {
+    let path = format!(
+        "/{}/search?text={}&mode={}",
+        index,
+        urlencoding::encode(text),
+        mode.as_str()
+    );
+    Call::new(HttpMethod::POST, path)
+}
+
+/// A search sending the query's AST as its body, for servers with
+/// [`crate::CAPABILITY_QUERY_AST`]
+pub(crate) fn search_ast(
+    index: &str,
+    expr: &QueryExpr,
+    options: &SearchOptions,
+) -> Call<SearchResponse> {
+    let mut path = format!("/{}/search", index);
+    if let Some(params) = options.to_query_params().strip_prefix('&') {
+        path.push('?');
+        path.push_str(params);
+    }
+    Call::new(HttpMethod::POST, path)
+        .with_body(expr.to_ast_json())
+        .with_header("Content-Type", "application/json")
+}
+
+// Keyword endpoints
+pub(crate) fn get_keyword(
+    index: &str,
+    keyword: &str,
+    limit: Option<usize>,
+    offset: Option<usize>,
+) -> Call<GetKeywordResponse> {
+    let mut path = format!("/{}/keyword/{}", index, urlencoding::encode(keyword));
+    let mut params = vec![];
+    if let Some(offset) = offset {
+        params.push(format!("offset={}", offset));
+    }
+    if let Some(limit) = limit {
+        params.push(format!("limit={}", limit));
+    }
+    if !params.is_empty() {
+        path.push('?');
+        path.push_str(&params.join("&"));
+    }
+    Call::new(HttpMethod::GET, path)
+}
+
+pub(crate) fn related_keywords(
+    index: &str,
+    keyword: &str,
+    limit: Option<usize>,
+) -> Call<Vec<RelatedKeyword>> {
+    let mut path = format!(
+        "/{}/keyword/{}/related",
+        index,
+        urlencoding::encode(keyword)
+    );
+    if let Some(limit) = limit {
+        path.push_str(&format!("?limit={}", limit));
+    }
+    Call::new(HttpMethod::GET, path)
+}
+
+pub(crate) fn keyword_trend(index: &str, keyword: &str, days: Option<u32>) -> Call<KeywordTrend> {
+    let mut path = format!("/{}/keyword/{}/trend", index, urlencoding::encode(keyword));
+    if let Some(days) = days {
+        path.push_str(&format!("?days={}", days));
+    }
+    Call::new(HttpMethod::GET, path)
+}
+
+pub(crate) fn get_keywords(
+    index: &str,
+    keywords: Vec<&str>,
+) -> Result<Call<HashMap<String, KeywordScores>>> {
+    let body = serde_json::to_string(&keywords)?;
+    Ok(Call::new(HttpMethod::POST, format!("/{}/keywords:batch", index)).with_body(body))
+}
+
+// Stop-list endpoints
+pub(crate) fn get_stoplist(index: &str) -> Call<StopList> {
+    Call::new(HttpMethod::GET, format!("/{}/stoplist", index))
+}
+
+pub(crate) fn set_stoplist(index: &str, keywords: &[&str]) -> Result<Call<StopList>> {
+    let body = serde_json::to_string(keywords)?;
+    Ok(Call::new(HttpMethod::PUT, format!("/{}/stoplist", index)).with_body(body))
+}
+
+// Index template endpoints
+pub(crate) fn list_templates() -> Call<Vec<IndexTemplate>> {
+    Call::new(HttpMethod::GET, "/_templates".into())
+}
+
+pub(crate) fn get_template(name: &str) -> Call<IndexTemplate> {
+    Call::new(HttpMethod::GET, format!("/_templates/{}", name))
+}
+
+pub(crate) fn put_template(name: &str, template: &IndexTemplate) -> Result<Call<IndexTemplate>> {
+    let body = serde_json::to_string(template)?;
+    Ok(Call::new(HttpMethod::PUT, format!("/_templates/{}", name)).with_body(body))
+}
+
+pub(crate) fn delete_template(name: &str) -> Call<DeletedResponse> {
+    Call::new(HttpMethod::DELETE, format!("/_templates/{}", name))
+}
+
+// Saved query endpoints
+pub(crate) fn list_queries(index: &str) -> Call<Vec<SavedQuery>> {
+    Call::new(HttpMethod::GET, format!("/{}/queries", index))
+}
+
+pub(crate) fn get_query(index: &str, name: &str) -> Call<SavedQuery> {
+    Call::new(HttpMethod::GET, format!("/{}/queries/{}", index, name))
+}
+
+pub(crate) fn save_query(index: &str, name: &str, query: &SavedQuery) -> Result<Call<SavedQuery>> {
+    let body = serde_json::to_string(query)?;
+    Ok(Call::new(HttpMethod::PUT, format!("/{}/queries/{}", index, name)).with_body(body))
+}
+
+pub(crate) fn delete_query(index: &str, name: &str) -> Call<DeletedResponse> {
+    Call::new(HttpMethod::DELETE, format!("/{}/queries/{}", index, name))
+}
+
+/// A search with the saved query `name`, its placeholders filled in from `values`
+/// and its saved options replaced by those set in `options`
+pub(crate) fn run_query(
+    index: &str,
+    name: &str,
+    values: &[(&str, &str)],
+    options: &SearchOptions,
+) -> Result<Call<SearchResponse>> {
+    let mut path = format!("/{}/queries/{}/run", index, name);
+    if let Some(params) = options.to_query_params().strip_prefix('&') {
+        path.push('?');
+        path.push_str(params);
+    }
+    let values: BTreeMap<&str, &str> = values.iter().copied().collect();
+    let body = serde_json::to_string(&values)?;
+    Ok(Call::new(HttpMethod::POST, path)
+        .with_body(body)
+        .with_header("Content-Type", "application/json"))
+}
+
+pub(crate) fn fsck(index: &str, cursor: Option<&str>, repair: bool) -> Call<FsckReport> {
+    let mut path = format!("/{}/fsck?repair={}", index, repair);
+    if let Some(cursor) = cursor {
+        path.push_str(&format!("&cursor={}", urlencoding::encode(cursor)));
+    }
+    Call::new(HttpMethod::POST, path)
+}
+
+pub(crate) fn reshard(
+    index: &str,
+    target_shards: u32,
+    cursor: Option<&str>,
+) -> Call<ReshardReport> {
+    let mut path = format!("/{}/reshard?target_shards={}", index, target_shards);
+    if let Some(cursor) = cursor {
+        path.push_str(&format!("&cursor={}", urlencoding::encode(cursor)));
+    }
+    Call::new(HttpMethod::POST, path)
+}
+
+pub(crate) fn upgrade(index: &str, cursor: Option<&str>) -> Call<UpgradeReport> {
+    let mut path = format!("/{}/upgrade", index);
+    if let Some(cursor) = cursor {
+        path.push_str(&format!("?cursor={}", urlencoding::encode(cursor)));
+    }
+    Call::new(HttpMethod::POST, path)
+}
+
+pub(crate) fn usage(index: &str, days: Option<u32>) -> Call<UsageSeries> {
+    let mut path = format!("/{}/usage", index);
+    if let Some(days) = days {
+        path.push_str(&format!("?days={}", days));
+    }
+    Call::new(HttpMethod::GET, path)
+}
+
+pub(crate) fn activity(index: &str, since: Option<u64>) -> Call<ActivityResponse> {
+    let mut path = format!("/{}/activity", index);
+    if let Some(since) = since {
+        path.push_str(&format!("?since={}", since));
+    }
+    Call::new(HttpMethod::GET, path)
+}
+
+pub(crate) fn top(
+    index: &str,
+    by: TopBy,
+    limit: Option<u32>,
+    sample: Option<f64>,
+    cursor: Option<&str>,
+) -> Call<TopReport> {
+    let mut path = format!("/{}/top?by={}", index, by.name());
+    if let Some(limit) = limit {
+        path.push_str(&format!("&limit={}", limit));
+    }
+    if let Some(sample) = sample {
+        path.push_str(&format!("&sample={}", sample));
+    }
+    if let Some(cursor) = cursor {
+        path.push_str(&format!("&cursor={}", urlencoding::encode(cursor)));
+    }
+    Call::new(HttpMethod::GET, path)
+}
+
+pub(crate) fn warm(index: &str, keywords: &[&str]) -> Result<Call<WarmReport>> {
+    let body = serde_json::to_string(keywords)?;
+    Ok(Call::new(HttpMethod::POST, format!("/{}/warm", index)).with_body(body))
+}
+
+pub(crate) fn warm_top(index: &str, top: u32, cursor: Option<&str>) -> Call<WarmReport> {
+    let mut path = format!("/{}/warm?top={}", index, top);
+    if let Some(cursor) = cursor {
+        path.push_str(&format!("&cursor={}", urlencoding::encode(cursor)));
+    }
+    Call::new(HttpMethod::POST, path)
+}
+
+pub(crate) fn export_keywords(
+    index: &str,
+    limit: Option<u32>,
+    cursor: Option<&str>,
+    after: Option<&str>,
+) -> Call<KeywordExportPage> {
+    let mut query = url::form_urlencoded::Serializer::new(String::new());
+    if let Some(limit) = limit {
+        query.append_pair("limit", &limit.to_string());
+    }
+    if let Some(cursor) = cursor {
+        query.append_pair("cursor", cursor);
+    }
+    if let Some(after) = after {
+        query.append_pair("after", after);
+    }
+    let query = query.finish();
+    let path = match query.is_empty() {
+        true => format!("/{}/keywords/export", index),
+        false => format!("/{}/keywords/export?{}", index, query),
+    };
+    Call::new(HttpMethod::GET, path)
+}
+
+pub(crate) fn snapshot(index: &str) -> Call<SnapshotReport> {
+    Call::new(HttpMethod::POST, format!("/{}/snapshot", index))
+}
+
+pub(crate) fn list_snapshots(index: &str) -> Call<SnapshotList> {
+    Call::new(HttpMethod::GET, format!("/{}/snapshots", index))
+}
+
+pub(crate) fn restore(index: &str, snapshot: u64) -> Call<RestoreReport> {
+    Call::new(
+        HttpMethod::POST,
+        format!("/{}/restore?snapshot={}", index, snapshot),
+    )
+}
+
+/// The `?lang=&format=` query string of an add-document request, if either is set
+fn add_document_query(lang: Option<&str>, content_type: Option<ContentType>) -> String {
+    let mut query = url::form_urlencoded::Serializer::new(String::new());
+    if let Some(lang) = lang {
+        query.append_pair("lang", lang);
+    }
+    if let Some(kind) = content_type {
+        let format = match kind {
+            ContentType::Json => "json",
+            ContentType::Text => "text",
+            ContentType::Binary => "binary",
+            ContentType::Envelope => "envelope",
+        };
+        query.append_pair("format", format);
+    }
+    match query.finish() {
+        query if query.is_empty() => query,
+        query => format!("?{}", query),
+    }
+}
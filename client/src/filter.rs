@@ -0,0 +1,115 @@
+use std::fmt::Display;
+
+/// A condition on a metadata field, the top-level fields of documents stored as
+/// JSON objects. A search with filters only returns matches passing all of them.
+///
+/// ```
+/// use edgesearch_client::filter::Filter;
+///
+/// assert_eq!(Filter::lt("price", 100).to_string(), "price:<100");
+/// assert_eq!(Filter::range("year", 2019, 2023).to_string(), "year:2019..2023");
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub enum Filter {
+    /// The field is this string, or a number or boolean written this way
+    Eq(String, String),
+    Lt(String, f64),
+    Le(String, f64),
+    Gt(String, f64),
+    Ge(String, f64),
+    /// The field is a number between the two, inclusive
+    Range(String, f64, f64),
+}
+
+impl Filter {
+    pub fn eq<S: Into<String>, V: Display>(field: S, value: V) -> Self {
+        Filter::Eq(field.into(), value.to_string())
+    }
+
+    pub fn lt<S: Into<String>>(field: S, value: impl Into<f64>) -> Self {
+        Filter::Lt(field.into(), value.into())
+    }
+
+    pub fn le<S: Into<String>>(field: S, value: impl Into<f64>) -> Self {
+        Filter::Le(field.into(), value.into())
+    }
+
+    pub fn gt<S: Into<String>>(field: S, value: impl Into<f64>) -> Self {
+        Filter::Gt(field.into(), value.into())
+    }
+
+    pub fn ge<S: Into<String>>(field: S, value: impl Into<f64>) -> Self {
+        Filter::Ge(field.into(), value.into())
+    }
+
+    pub fn range<S: Into<String>>(field: S, low: impl Into<f64>, high: impl Into<f64>) -> Self {
+        Filter::Range(field.into(), low.into(), high.into())
+    }
+
+    /// Convert the filter to the `field:condition` syntax of the `filter=` parameter
+    pub fn to_filter_string(&self) -> String {
+        match self {
+            Filter::Eq(field, value) => format!("{}:{}", field, value),
+            Filter::Lt(field, value) => format!("{}:<{}", field, value),
+            Filter::Le(field, value) => format!("{}:<={}", field, value),
+            Filter::Gt(field, value) => format!("{}:>{}", field, value),
+            Filter::Ge(field, value) => format!("{}:>={}", field, value),
+            Filter::Range(field, low, high) => format!("{}:{}..{}", field, low, high),
+        }
+    }
+
+    /// The filter as an `&`-prefixed, percent-encoded `filter=` query parameter
+    pub fn to_query_param(&self) -> String {
+        format!("&filter={}", percent_encode(&self.to_filter_string()))
+    }
+}
+
+/// Percent-encode everything but unreserved characters, which the query types need
+/// without the HTTP features' `urlencoding` dependency
+pub(crate) fn percent_encode(value: &str) -> String {
+    let mut encoded = String::new();
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+impl Display for Filter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.to_filter_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter_strings() {
+        assert_eq!(Filter::le("price", 9.5).to_string(), "price:<=9.5");
+        assert_eq!(Filter::gt("rating", 4).to_string(), "rating:>4");
+        assert_eq!(Filter::ge("temp", -3).to_string(), "temp:>=-3");
+        assert_eq!(Filter::eq("published", true).to_string(), "published:true");
+        assert_eq!(
+            Filter::eq("category", "books").to_string(),
+            "category:books"
+        );
+    }
+
+    #[test]
+    fn test_query_param_encoding() {
+        assert_eq!(
+            Filter::lt("price", 100).to_query_param(),
+            "&filter=price%3A%3C100"
+        );
+        assert_eq!(
+            Filter::eq("author", "Ann & Bo").to_query_param(),
+            "&filter=author%3AAnn%20%26%20Bo"
+        );
+    }
+}
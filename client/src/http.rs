@@ -1,63 +1,173 @@
+//! The transport behind both clients, and the blocking [`Client`]
+#[cfg(feature = "blocking")]
 use crate::{
+    endpoints::{self, Call},
+    index::IndexHandle,
     query::{QueryBuilder, QueryExpr},
-    ClientError, DeleteDocumentResponse, DeletedResponse, Document, ErrorResponse,
-    GetKeywordResponse, IndexDocument, Result, SearchResponse, StatusResponse,
-    UpdateDocumentResponse,
+    ActivityEvent, DeleteDocumentResponse, DeletedResponse, Document, DocumentKeywords,
+    DocumentPage, FsckReport, GetKeywordResponse, IndexDocument, IndexListing, IndexMetadata,
+    IndexSettings, IndexTemplate, KeywordScores, KeywordTrend, RelatedKeyword, ReshardReport,
+    RestoreReport, SavedQuery, SearchMode, SearchOptions, SearchResponse, SnapshotListing,
+    SnapshotReport, StatusResponse, StopList, TopBy, TopReport, UpgradeReport, UsageDay,
+    WarmReport, CAPABILITY_QUERY_AST,
 };
-use std::{collections::HashMap, str::FromStr};
+use crate::{
+    AddDocumentResponse, ApiError, ClientError, ErrorCode, ErrorResponse, ExportedKeyword,
+    KeywordExportPage, Result,
+};
+use std::collections::HashMap;
+#[cfg(feature = "blocking")]
+use std::sync::OnceLock;
 
-use futures::future::Future;
-use reqwest::header::{HeaderName, HeaderValue};
+use futures::future::BoxFuture;
 use serde::Deserialize;
-use url::Url;
 
+#[cfg(feature = "blocking")]
 pub struct Client {
     base_url: String,
     api_key: Option<String>,
+    transport: Box<dyn HttpClient>,
+    /// Whether the server takes query ASTs, once [`Self::status`] was asked
+    query_ast: OnceLock<bool>,
+    /// Whether document writes create their missing index, see
+    /// [`Self::with_auto_create_indexes`]
+    auto_create_indexes: bool,
+    auto_create_settings: Option<IndexSettings>,
+}
+
+/// Where one document write of a client with `with_auto_create_indexes` is. Each
+/// write starts its own, so the index is created and the write sent again at most
+/// once per call.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum AutoCreate {
+    /// Missing indexes are reported as they are
+    Off,
+    /// The write was sent once, and a missing index is created before sending it again
+    FirstAttempt,
+    /// The index was created and the write sent again, so its answer is final
+    Retried,
+}
+
+impl AutoCreate {
+    pub(crate) fn start(enabled: bool) -> Self {
+        match enabled {
+            true => AutoCreate::FirstAttempt,
+            false => AutoCreate::Off,
+        }
+    }
+
+    /// Whether `result` reports a missing index to create before sending the write
+    /// again, moving on to [`Self::Retried`] when it does
+    pub(crate) fn should_create<T>(&mut self, result: &Result<T>) -> bool {
+        let missing = matches!(
+            result,
+            Err(ClientError::NotFound(api)) if api.code == ErrorCode::IndexNotFound
+        );
+        if missing && *self == AutoCreate::FirstAttempt {
+            *self = AutoCreate::Retried;
+            return true;
+        }
+        false
+    }
 }
 
+/// Sends the requests a client makes. The reqwest transport of the enabled feature is
+/// used unless another is given with `with_transport`, such as a
+/// [`crate::mock::MockTransport`].
 pub trait HttpClient: Send + Sync {
-    fn request(
-        &self,
-        request: HttpRequest,
-    ) -> Box<dyn Future<Output = Result<HttpResponse>> + Send + '_>;
+    fn request(&self, request: HttpRequest) -> BoxFuture<'_, Result<HttpResponse>>;
 }
 
 #[derive(Debug, Clone)]
 pub struct HttpRequest {
-    pub method: String,
+    pub method: HttpMethod,
+    /// The full URL, including the client's base URL and any query string
     pub url: String,
     pub headers: HashMap<String, String>,
     pub body: Option<String>,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum HttpMethod {
     GET,
     POST,
     PUT,
     PATCH,
     DELETE,
+    HEAD,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ContentType {
     Json,
     Text,
     Binary,
+    /// A JSON object of a text `body` and a `title`, whose keywords the server boosts
+    /// by the index's [`crate::IndexSettings::title_boost`]
+    Envelope,
 }
 
-static HEADER_API_KEY: &'static str = "X-API-Key";
+static HEADER_API_KEY: &str = "X-API-Key";
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct HttpResponse {
     pub status: u16,
     pub body: String,
 }
 
+/// The default transport of [`Client`], sending requests with a blocking reqwest client
+#[cfg(feature = "blocking")]
+#[derive(Default)]
+pub struct ReqwestTransport {
+    client: reqwest::blocking::Client,
+}
+
+#[cfg(feature = "blocking")]
+impl ReqwestTransport {
+    fn send(&self, request: HttpRequest) -> Result<HttpResponse> {
+        let builder = match request.method {
+            HttpMethod::GET => self.client.get(&request.url),
+            HttpMethod::POST => self.client.post(&request.url),
+            HttpMethod::PUT => self.client.put(&request.url),
+            HttpMethod::PATCH => self.client.patch(&request.url),
+            HttpMethod::DELETE => self.client.delete(&request.url),
+            HttpMethod::HEAD => self.client.head(&request.url),
+        };
+        let builder = request
+            .headers
+            .iter()
+            .fold(builder, |builder, (name, value)| {
+                builder.header(name, value)
+            });
+        let builder = match request.body {
+            Some(body) => builder.body(body),
+            None => builder,
+        };
+
+        let response = builder.send().map_err(ClientError::Reqwest)?;
+        let status = response.status().as_u16();
+        let body = response.text().map_err(ClientError::Reqwest)?;
+        Ok(HttpResponse { status, body })
+    }
+}
+
+#[cfg(feature = "blocking")]
+impl HttpClient for ReqwestTransport {
+    fn request(&self, request: HttpRequest) -> BoxFuture<'_, Result<HttpResponse>> {
+        Box::pin(futures::future::ready(self.send(request)))
+    }
+}
+
+#[cfg(feature = "blocking")]
 impl Client {
     pub fn new(base_url: String) -> Self {
         Self {
             base_url: base_url.trim_end_matches('/').to_string(),
             api_key: None,
+            query_ast: OnceLock::new(),
+            transport: Box::new(ReqwestTransport::default()),
+            auto_create_indexes: false,
+            auto_create_settings: None,
         }
     }
 
@@ -66,35 +176,157 @@ impl Client {
         self
     }
 
+    /// Send requests through `transport` instead of reqwest, e.g. to test code that
+    /// uses the client against a [`crate::mock::MockTransport`]
+    pub fn with_transport<T: HttpClient + 'static>(mut self, transport: T) -> Self {
+        self.transport = Box::new(transport);
+        self
+    }
+
+    /// Create the index [`Self::add_document`] or [`Self::add_document_id`] writes to
+    /// when it is missing, then send the write once more. Searches and every other
+    /// call still fail on a missing index.
+    pub fn with_auto_create_indexes(mut self, enabled: bool) -> Self {
+        self.auto_create_indexes = enabled;
+        self
+    }
+
+    /// The settings indexes made by [`Self::with_auto_create_indexes`] are created with
+    pub fn with_auto_create_settings(mut self, settings: IndexSettings) -> Self {
+        self.auto_create_settings = Some(settings);
+        self
+    }
+
+    /// A handle on one index, whose calls don't take the index name
+    pub fn index(&self, name: &str) -> IndexHandle<'_, Client> {
+        IndexHandle::new(self, name)
+    }
+
     // Status endpoint
     pub fn status(&self) -> Result<StatusResponse> {
-        self.request::<StatusResponse>(HttpMethod::GET, "/", None, None)
+        self.call(endpoints::status())
     }
 
     // Index management endpoints
     pub fn list_indexes(&self) -> Result<Vec<String>> {
-        self.request::<Vec<String>>(HttpMethod::GET, "/indexes", None, None)
+        self.call(endpoints::list_indexes())
+    }
+
+    /// List every index with its index document, in one request
+    pub fn list_indexes_detailed(&self) -> Result<IndexListing> {
+        self.call(endpoints::list_indexes_detailed())
+    }
+
+    /// List the indexes labelled `name: value` with their index documents
+    pub fn list_indexes_with_label(&self, name: &str, value: &str) -> Result<IndexListing> {
+        self.call(endpoints::list_indexes_with_label(name, value))
     }
 
     pub fn get_index(&self, index: &str) -> Result<IndexDocument> {
-        let url = format!("/{}", index);
-        self.request::<IndexDocument>(HttpMethod::GET, &url, None, None)
+        self.call(endpoints::get_index(index))
+    }
+
+    /// Whether the index exists, checked without fetching it
+    pub fn index_exists(&self, index: &str) -> Result<bool> {
+        self.exists(endpoints::index_exists(index))
     }
 
     pub fn create_index(&self, index: &str) -> Result<IndexDocument> {
-        let url = format!("/{}", index);
-        self.request::<IndexDocument>(HttpMethod::PUT, &url, None, None)
+        self.call(endpoints::create_index(index, None))
+    }
+
+    /// Create an index whose documents fall back to `lang` when their language can't
+    /// be detected confidently
+    pub fn create_index_with_lang(&self, index: &str, lang: &str) -> Result<IndexDocument> {
+        self.call(endpoints::create_index(index, Some(lang)))
+    }
+
+    /// Replace the search defaults of an index, creating it if it doesn't exist
+    pub fn set_index_settings(
+        &self,
+        index: &str,
+        settings: &IndexSettings,
+    ) -> Result<IndexDocument> {
+        self.call(endpoints::set_index_settings(index, settings)?)
+    }
+
+    /// Create an index with a description and labels, or apply them to the existing
+    /// index, leaving its settings alone
+    pub fn create_index_with_metadata(
+        &self,
+        index: &str,
+        metadata: &IndexMetadata,
+    ) -> Result<IndexDocument> {
+        self.call(endpoints::create_index_with_metadata(index, metadata)?)
+    }
+
+    /// Change the description and labels of an existing index, leaving its settings
+    /// alone
+    pub fn update_index_metadata(
+        &self,
+        index: &str,
+        metadata: &IndexMetadata,
+    ) -> Result<IndexDocument> {
+        self.call(endpoints::update_index_metadata(index, metadata)?)
     }
 
     pub fn delete_index(&self, index: &str) -> Result<DeletedResponse> {
-        let url = format!("/{}", index);
-        self.request::<DeletedResponse>(HttpMethod::DELETE, &url, None, None)
+        self.call(endpoints::delete_index(index))
+    }
+
+    /// Reject document writes to an index until [`Self::unfreeze_index`]. Needs the
+    /// API key itself, even when the server runs with `AUTH_DISABLED=true`.
+    pub fn freeze_index(&self, index: &str) -> Result<IndexDocument> {
+        self.call(endpoints::freeze_index(index))
+    }
+
+    pub fn unfreeze_index(&self, index: &str) -> Result<IndexDocument> {
+        self.call(endpoints::unfreeze_index(index))
     }
 
     // Document endpoints
     pub fn get_document(&self, index: &str, doc_id: &str) -> Result<Document> {
-        let url = format!("/{}/doc/{}", index, doc_id);
-        self.request::<Document>(HttpMethod::GET, &url, None, None)
+        self.call(endpoints::get_document(index, doc_id))
+    }
+
+    /// The next page of the index's documents, without their bodies, keeping those
+    /// updated at or after `updated_since` milliseconds since the epoch. Pass back
+    /// the returned cursor until it is `None` to list every document.
+    pub fn list_documents(
+        &self,
+        index: &str,
+        cursor: Option<&str>,
+        updated_since: Option<u64>,
+    ) -> Result<DocumentPage> {
+        self.call(endpoints::list_documents(index, cursor, updated_since))
+    }
+
+    /// Whether the document exists, checked without fetching it. A missing index
+    /// also reads as `false`.
+    pub fn document_exists(&self, index: &str, doc_id: &str) -> Result<bool> {
+        self.exists(endpoints::document_exists(index, doc_id))
+    }
+
+    /// Fetch a document, or `None` when `etag` (see [`Document::etag`]) is still
+    /// its current revision
+    pub fn get_document_if_modified(
+        &self,
+        index: &str,
+        doc_id: &str,
+        etag: &str,
+    ) -> Result<Option<Document>> {
+        self.call(endpoints::get_document_if_modified(index, doc_id, etag))
+    }
+
+    /// Fetch a document's keywords and the shards their postings live in. With
+    /// `verify`, the server also checks each shard actually holds the posting.
+    pub fn document_keywords(
+        &self,
+        index: &str,
+        doc_id: &str,
+        verify: bool,
+    ) -> Result<DocumentKeywords> {
+        self.call(endpoints::document_keywords(index, doc_id, verify))
     }
 
     pub fn add_document_id(
@@ -104,24 +336,9 @@ impl Client {
         body: String,
         lang: Option<&str>,
         content_type: Option<ContentType>,
-    ) -> Result<Document> {
-        let mut url = Url::parse(format!("{}/{}/doc/{}", self.base_url, index, doc_id).as_str())
-            .map_err(ClientError::ParseError)?;
-        if let Some(lang) = lang {
-            url.query_pairs_mut()
-                .append_pair("lang", urlencoding::encode(lang).as_ref());
-        }
-        if let Some(kind) = content_type {
-            url.query_pairs_mut().append_pair(
-                "format",
-                match kind {
-                    ContentType::Json => "json",
-                    ContentType::Text => "text",
-                    ContentType::Binary => "binary",
-                },
-            );
-        }
-        self.request::<Document>(HttpMethod::POST, &url.path(), Some(body), None)
+    ) -> Result<AddDocumentResponse> {
+        let call = endpoints::add_document(index, Some(doc_id), body, lang, content_type);
+        self.write_document(index, call)
     }
 
     pub fn add_document(
@@ -130,25 +347,9 @@ impl Client {
         body: String,
         lang: Option<&str>,
         content_type: Option<ContentType>,
-    ) -> Result<Document> {
-        let mut url = Url::parse(format!("{}/{}/doc", self.base_url, index).as_str())
-            .map_err(ClientError::ParseError)?;
-
-        if let Some(lang) = lang {
-            url.query_pairs_mut()
-                .append_pair("lang", urlencoding::encode(lang).as_ref());
-        }
-        if let Some(kind) = content_type {
-            url.query_pairs_mut().append_pair(
-                "format",
-                match kind {
-                    ContentType::Json => "json",
-                    ContentType::Text => "text",
-                    ContentType::Binary => "binary",
-                },
-            );
-        }
-        self.request::<Document>(HttpMethod::POST, &url.path().to_string(), Some(body), None)
+    ) -> Result<AddDocumentResponse> {
+        let call = endpoints::add_document(index, None, body, lang, content_type);
+        self.write_document(index, call)
     }
 
     pub fn update_document(
@@ -156,33 +357,114 @@ impl Client {
         index: &str,
         doc_id: &str,
         body: String,
-    ) -> Result<UpdateDocumentResponse> {
-        let url = format!("/{}/doc/{}", index, doc_id);
-        self.request::<UpdateDocumentResponse>(HttpMethod::PATCH, &url, Some(body), None)
+    ) -> Result<AddDocumentResponse> {
+        self.call(endpoints::update_document(index, doc_id, body))
     }
 
     pub fn delete_document(&self, index: &str, doc_id: &str) -> Result<DeleteDocumentResponse> {
-        let url = format!("/{}/doc/{}", index, doc_id);
-        self.request::<DeleteDocumentResponse>(HttpMethod::DELETE, &url, None, None)
+        self.call(endpoints::delete_document(index, doc_id))
     }
 
     // Search endpoint
-    pub fn search(&self, index: &str, query: &str, full: Option<bool>) -> Result<SearchResponse> {
-        let mut url = format!("/{}/search?query={}", index, urlencoding::encode(query));
-        if let Some(full) = full {
-            url.push_str(&format!("&full={}", full));
-        }
-        self.request::<SearchResponse>(HttpMethod::POST, &url, None, None)
+    /// Search with `options`, or the server's defaults when `None`
+    pub fn search(
+        &self,
+        index: &str,
+        query: &str,
+        options: Option<SearchOptions>,
+    ) -> Result<SearchResponse> {
+        self.search_with_options(index, query, &options.unwrap_or_default())
+    }
+
+    #[deprecated(note = "use `search` with `SearchOptions::new().full()`")]
+    pub fn search_full(
+        &self,
+        index: &str,
+        query: &str,
+        full: Option<bool>,
+    ) -> Result<SearchResponse> {
+        self.search(
+            index,
+            query,
+            Some(SearchOptions {
+                full,
+                ..SearchOptions::default()
+            }),
+        )
+    }
+
+    /// Search with explicit options, e.g. to trim the fields returned per match
+    pub fn search_with_options(
+        &self,
+        index: &str,
+        query: &str,
+        options: &SearchOptions,
+    ) -> Result<SearchResponse> {
+        self.call(endpoints::search(index, query, options))
     }
 
-    /// Search using a QueryExpr
+    /// Search using a QueryExpr, sent as its AST to servers that take one and as a
+    /// query string to older ones
     pub fn search_expr(
+        &self,
+        index: &str,
+        expr: &QueryExpr,
+        options: Option<SearchOptions>,
+    ) -> Result<SearchResponse> {
+        let options = options.unwrap_or_default();
+        if self.supports_query_ast() {
+            self.call(endpoints::search_ast(index, expr, &options))
+        } else {
+            self.search_with_options(index, &expr.to_query_string(), &options)
+        }
+    }
+
+    #[deprecated(note = "use `search_expr` with `SearchOptions::new().full()`")]
+    pub fn search_expr_full(
         &self,
         index: &str,
         expr: &QueryExpr,
         full: Option<bool>,
     ) -> Result<SearchResponse> {
-        self.search(index, &expr.to_query_string(), full)
+        self.search_expr(
+            index,
+            expr,
+            Some(SearchOptions {
+                full,
+                ..SearchOptions::default()
+            }),
+        )
+    }
+
+    /// The IDs of the documents matching `query`, best first, without their rows
+    pub fn search_ids(&self, index: &str, query: &str) -> Result<Vec<String>> {
+        self.call(endpoints::search_ids(index, query))
+            .map(|response| response.ids)
+    }
+
+    /// Search a search box's free text, whose words the server combines by `mode`,
+    /// also trying adjacent pairs of them as one keyword
+    pub fn search_simple(
+        &self,
+        index: &str,
+        text: &str,
+        mode: SearchMode,
+    ) -> Result<SearchResponse> {
+        self.call(endpoints::search_text(index, text, mode))
+    }
+
+    /// Whether the server takes a search's query as its AST, which servers older
+    /// than the capability don't. A failed check falls back to the query string.
+    fn supports_query_ast(&self) -> bool {
+        if let Some(supported) = self.query_ast.get() {
+            return *supported;
+        }
+        match self.status() {
+            Ok(status) => *self
+                .query_ast
+                .get_or_init(|| status.supports(CAPABILITY_QUERY_AST)),
+            Err(_) => false,
+        }
     }
 
     /// Search using a QueryBuilder
@@ -190,111 +472,512 @@ impl Client {
         &self,
         index: &str,
         builder: QueryBuilder,
-        full: Option<bool>,
+        options: Option<SearchOptions>,
     ) -> Result<SearchResponse> {
         match builder.to_query_string() {
-            Some(query) => self.search(index, &query, full),
-            None => Err(ClientError::Api("Empty query builder".to_string())),
+            Some(query) => self.search(index, &query, options),
+            None => Err(ClientError::EmptyQuery),
         }
     }
 
+    #[deprecated(note = "use `search_builder` with `SearchOptions::new().full()`")]
+    pub fn search_builder_full(
+        &self,
+        index: &str,
+        builder: QueryBuilder,
+        full: Option<bool>,
+    ) -> Result<SearchResponse> {
+        self.search_builder(
+            index,
+            builder,
+            Some(SearchOptions {
+                full,
+                ..SearchOptions::default()
+            }),
+        )
+    }
+
     // Keyword endpoint
-    pub fn get_keyword(&self, index: &str, keyword: &str) -> Result<GetKeywordResponse> {
-        let url = format!("/{}/keyword/{}", index, urlencoding::encode(keyword));
-        self.request::<GetKeywordResponse>(HttpMethod::GET, url.as_str(), None, None)
+    /// Fetch the best scored postings of `keyword`, skipping the first `offset` and
+    /// returning at most `limit` (all of them when `None`). Small pages may be
+    /// gathered from some of the keyword's shards; see [`GetKeywordResponse::exact`].
+    pub fn get_keyword(
+        &self,
+        index: &str,
+        keyword: &str,
+        limit: Option<usize>,
+        offset: Option<usize>,
+    ) -> Result<GetKeywordResponse> {
+        self.call(endpoints::get_keyword(index, keyword, limit, offset))
+    }
+
+    /// Fetch the keywords that most often appear alongside `keyword`, at most `limit`
+    /// of them (10 when `None`)
+    pub fn related_keywords(
+        &self,
+        index: &str,
+        keyword: &str,
+        limit: Option<usize>,
+    ) -> Result<Vec<RelatedKeyword>> {
+        self.call(endpoints::related_keywords(index, keyword, limit))
+    }
+
+    /// Fetch how many documents gained or lost `keyword` on each of the last `days`
+    /// days (30 when `None`)
+    pub fn keyword_trend(
+        &self,
+        index: &str,
+        keyword: &str,
+        days: Option<u32>,
+    ) -> Result<KeywordTrend> {
+        self.call(endpoints::keyword_trend(index, keyword, days))
+    }
+
+    /// Fetch the merged scores of many keywords in a single request
+    pub fn get_keywords(
+        &self,
+        index: &str,
+        keywords: Vec<&str>,
+    ) -> Result<HashMap<String, KeywordScores>> {
+        self.call(endpoints::get_keywords(index, keywords)?)
+    }
+
+    // Stop-list endpoints
+    pub fn get_stoplist(&self, index: &str) -> Result<StopList> {
+        self.call(endpoints::get_stoplist(index))
+    }
+
+    /// Replace the stop-list of an index, returning it as the server normalized it
+    pub fn set_stoplist(&self, index: &str, keywords: &[&str]) -> Result<StopList> {
+        self.call(endpoints::set_stoplist(index, keywords)?)
+    }
+
+    // Index template endpoints
+    pub fn list_templates(&self) -> Result<Vec<IndexTemplate>> {
+        self.call(endpoints::list_templates())
     }
 
-    fn request<T>(
+    pub fn get_template(&self, name: &str) -> Result<IndexTemplate> {
+        self.call(endpoints::get_template(name))
+    }
+
+    /// Create or replace the template `name`, applied to indexes created without
+    /// settings afterwards, returning it as the server normalized it
+    pub fn put_template(&self, name: &str, template: &IndexTemplate) -> Result<IndexTemplate> {
+        self.call(endpoints::put_template(name, template)?)
+    }
+
+    pub fn delete_template(&self, name: &str) -> Result<DeletedResponse> {
+        self.call(endpoints::delete_template(name))
+    }
+
+    // Saved query endpoints
+    pub fn list_queries(&self, index: &str) -> Result<Vec<SavedQuery>> {
+        self.call(endpoints::list_queries(index))
+    }
+
+    pub fn get_query(&self, index: &str, name: &str) -> Result<SavedQuery> {
+        self.call(endpoints::get_query(index, name))
+    }
+
+    /// Create or replace the saved query `name`, which the server refuses unless
+    /// its query parses
+    pub fn save_query(&self, index: &str, name: &str, query: &SavedQuery) -> Result<SavedQuery> {
+        self.call(endpoints::save_query(index, name, query)?)
+    }
+
+    pub fn delete_query(&self, index: &str, name: &str) -> Result<DeletedResponse> {
+        self.call(endpoints::delete_query(index, name))
+    }
+
+    /// Search with the saved query `name`, each of its placeholders replaced by the
+    /// value given for it in `values` as a quoted keyword. Options set in `options`
+    /// replace the saved ones of the same name.
+    pub fn run_query(
         &self,
-        method: HttpMethod,
-        path: &str,
-        body: Option<String>,
-        extra_headers: Option<HashMap<HeaderName, HeaderValue>>,
-    ) -> Result<T>
+        index: &str,
+        name: &str,
+        values: &[(&str, &str)],
+        options: Option<SearchOptions>,
+    ) -> Result<SearchResponse> {
+        self.call(endpoints::run_query(
+            index,
+            name,
+            values,
+            &options.unwrap_or_default(),
+        )?)
+    }
+
+    /// Check the next batch of an index's documents and keyword shards for
+    /// inconsistent postings, fixing them with `repair`. Pass back the returned
+    /// cursor until it is `None` to check the whole index.
+    pub fn fsck(&self, index: &str, cursor: Option<&str>, repair: bool) -> Result<FsckReport> {
+        self.call(endpoints::fsck(index, cursor, repair))
+    }
+
+    /// Run the next batch of moving a frozen index's keyword shards to
+    /// `target_shards` shards. Pass back the returned cursor until it is `None`,
+    /// then unfreeze the index.
+    pub fn reshard(
+        &self,
+        index: &str,
+        target_shards: u32,
+        cursor: Option<&str>,
+    ) -> Result<ReshardReport> {
+        self.call(endpoints::reshard(index, target_shards, cursor))
+    }
+
+    /// Run the next batch of upgrading a frozen index to the latest version. Pass
+    /// back the returned cursor until it is `None`, then unfreeze the index.
+    pub fn upgrade(&self, index: &str, cursor: Option<&str>) -> Result<UpgradeReport> {
+        self.call(endpoints::upgrade(index, cursor))
+    }
+
+    /// The index's daily usage over the last `days` days, 7 when `None`, oldest first
+    pub fn usage(&self, index: &str, days: Option<u32>) -> Result<Vec<UsageDay>> {
+        Ok(self.call(endpoints::usage(index, days))?.days)
+    }
+
+    /// The index's recent document changes, newest first, only those made at or
+    /// after `since` (milliseconds since the epoch) when given
+    pub fn activity(&self, index: &str, since: Option<u64>) -> Result<Vec<ActivityEvent>> {
+        Ok(self.call(endpoints::activity(index, since))?.events)
+    }
+
+    /// Rank the next batch of an index's documents by stored size or keywords by
+    /// postings, the best `limit` of them. `sample` reads only that share of the
+    /// documents. Needs the API key itself; pass back the returned cursor until it
+    /// is `None`, keeping the best entries of every batch.
+    pub fn top(
+        &self,
+        index: &str,
+        by: TopBy,
+        limit: Option<u32>,
+        sample: Option<f64>,
+        cursor: Option<&str>,
+    ) -> Result<TopReport> {
+        self.call(endpoints::top(index, by, limit, sample, cursor))
+    }
+
+    /// Merge `keywords` ahead of the searches for them, keeping the postings of those
+    /// stored in every shard. Only indexes without a recorded shard count can be
+    /// warmed.
+    pub fn warm(&self, index: &str, keywords: &[&str]) -> Result<WarmReport> {
+        self.call(endpoints::warm(index, keywords)?)
+    }
+
+    /// [`Self::warm`] the `top` keywords with the most postings in the next batch of
+    /// a `keyword_postings` walk. Needs the API key itself; pass back the returned
+    /// cursor until it is `None` to warm the whole index.
+    pub fn warm_top(&self, index: &str, top: u32, cursor: Option<&str>) -> Result<WarmReport> {
+        self.call(endpoints::warm_top(index, top, cursor))
+    }
+
+    /// The next `limit` keywords of an index with their shard and document counts,
+    /// resuming from `cursor`, or after the keyword `after`. Needs the API key
+    /// itself; pass back the returned cursor until it is `None`, or use
+    /// [`Self::export_keywords`] to follow it.
+    pub fn export_keywords_page(
+        &self,
+        index: &str,
+        limit: Option<u32>,
+        cursor: Option<&str>,
+        after: Option<&str>,
+    ) -> Result<KeywordExportPage> {
+        let call = endpoints::export_keywords(index, limit, cursor, after);
+        let request = build_request(&self.base_url, self.api_key.as_deref(), call);
+        let response = futures::executor::block_on(self.transport.request(request))?;
+        handle_export(response)
+    }
+
+    /// Every keyword of an index, fetching `limit` at a time as the iterator is
+    /// advanced. Only one page is held at once, and an error ends the iteration.
+    pub fn export_keywords(&self, index: &str, limit: Option<u32>) -> KeywordExport<'_> {
+        KeywordExport {
+            client: self,
+            index: index.to_string(),
+            limit,
+            page: Default::default(),
+            cursor: None,
+            done: false,
+        }
+    }
+
+    /// Write the next batch of a snapshot of a frozen index to R2, starting one
+    /// when none is in progress. Call again until the report is `complete`.
+    pub fn snapshot(&self, index: &str) -> Result<SnapshotReport> {
+        self.call(endpoints::snapshot(index))
+    }
+
+    /// The index's complete snapshots, oldest first
+    pub fn list_snapshots(&self, index: &str) -> Result<Vec<SnapshotListing>> {
+        Ok(self.call(endpoints::list_snapshots(index))?.snapshots)
+    }
+
+    /// Run the next batch of replacing a frozen index's contents with `snapshot`.
+    /// Call again until the report is `complete`, then unfreeze the index.
+    pub fn restore(&self, index: &str, snapshot: u64) -> Result<RestoreReport> {
+        self.call(endpoints::restore(index, snapshot))
+    }
+
+    fn call<T>(&self, call: Call<T>) -> Result<T>
     where
         T: for<'de> Deserialize<'de>,
     {
-        let url = format!("{}{}", self.base_url, path);
-        let client = reqwest::blocking::Client::new();
-        let mut headers = reqwest::header::HeaderMap::new();
+        let request = build_request(&self.base_url, self.api_key.as_deref(), call);
+        let response = futures::executor::block_on(self.transport.request(request))?;
+        handle_response(response)
+    }
 
-        if let Some(api_key) = &self.api_key {
-            headers.insert(HEADER_API_KEY, HeaderValue::from_str(api_key).unwrap());
-        }
+    fn exists(&self, call: Call<bool>) -> Result<bool> {
+        let request = build_request(&self.base_url, self.api_key.as_deref(), call);
+        let response = futures::executor::block_on(self.transport.request(request))?;
+        handle_exists(response)
+    }
 
-        if let Some(extra) = extra_headers {
-            headers.extend(extra);
-        }
-        let response;
-        let default_body = "".to_string();
-        match method {
-            HttpMethod::GET => {
-                response = client
-                    .get(&url)
-                    .headers(headers)
-                    .send()
-                    .map_err(ClientError::Reqwest)?;
+    /// Send a document write, creating its index first if it's missing and
+    /// [`Self::with_auto_create_indexes`] is on
+    fn write_document(
+        &self,
+        index: &str,
+        call: Call<AddDocumentResponse>,
+    ) -> Result<AddDocumentResponse> {
+        let request = build_request(&self.base_url, self.api_key.as_deref(), call);
+        let mut state = AutoCreate::start(self.auto_create_indexes);
+        loop {
+            let response = futures::executor::block_on(self.transport.request(request.clone()))?;
+            let written = handle_response(response);
+            if !state.should_create(&written) {
+                return written;
             }
-            HttpMethod::POST => {
-                response = client
-                    .post(&url)
-                    .headers(headers)
-                    .body(body.unwrap_or(default_body))
-                    .send()
-                    .map_err(ClientError::Reqwest)?;
+            self.call(endpoints::auto_create_index(
+                index,
+                self.auto_create_settings.as_ref(),
+            )?)?;
+        }
+    }
+}
+
+/// The keywords of an index, returned by [`Client::export_keywords`]
+#[cfg(feature = "blocking")]
+pub struct KeywordExport<'a> {
+    client: &'a Client,
+    index: String,
+    limit: Option<u32>,
+    page: std::vec::IntoIter<ExportedKeyword>,
+    cursor: Option<String>,
+    done: bool,
+}
+
+#[cfg(feature = "blocking")]
+impl Iterator for KeywordExport<'_> {
+    type Item = Result<ExportedKeyword>;
+
+    fn next(&mut self) -> Option<Result<ExportedKeyword>> {
+        loop {
+            if let Some(keyword) = self.page.next() {
+                return Some(Ok(keyword));
             }
-            HttpMethod::PUT => {
-                response = client
-                    .put(&url)
-                    .headers(headers)
-                    .body(body.unwrap_or(default_body))
-                    .send()
-                    .map_err(ClientError::Reqwest)?;
+            if self.done {
+                return None;
             }
-            HttpMethod::PATCH => {
-                response = client
-                    .patch(&url)
-                    .headers(headers)
-                    .body(body.unwrap_or(default_body))
-                    .send()
-                    .map_err(ClientError::Reqwest)?;
+            let page = self.client.export_keywords_page(
+                &self.index,
+                self.limit,
+                self.cursor.as_deref(),
+                None,
+            );
+            match page {
+                Ok(page) => {
+                    self.done = page.cursor.is_none();
+                    self.cursor = page.cursor;
+                    self.page = page.keywords.into_iter();
+                }
+                Err(err) => {
+                    self.done = true;
+                    return Some(Err(err));
+                }
             }
-            HttpMethod::DELETE => {
-                response = client
-                    .delete(url)
-                    .headers(headers)
-                    .send()
-                    .map_err(ClientError::Reqwest)?;
+        }
+    }
+}
+
+/// The full request for `call`, authenticated with `api_key` when there is one
+pub(crate) fn build_request<T>(
+    base_url: &str,
+    api_key: Option<&str>,
+    call: crate::endpoints::Call<T>,
+) -> HttpRequest {
+    let mut headers = HashMap::new();
+    if let Some(api_key) = api_key {
+        headers.insert(HEADER_API_KEY.to_string(), api_key.to_string());
+    }
+    for (name, value) in call.headers {
+        headers.insert(name.to_string(), value);
+    }
+    // Every write sends a body, even an empty one
+    let body = match call.method {
+        HttpMethod::GET | HttpMethod::DELETE | HttpMethod::HEAD => None,
+        _ => Some(call.body.unwrap_or_default()),
+    };
+    HttpRequest {
+        method: call.method,
+        url: format!("{}{}", base_url, call.path),
+        headers,
+        body,
+    }
+}
+
+pub(crate) fn handle_response<T>(response: HttpResponse) -> Result<T>
+where
+    T: for<'de> Deserialize<'de>,
+{
+    match response.status {
+        207 => parse_partial(&response.body),
+        // Only conditional requests are answered with 304, and they expect an
+        // `Option` that the empty body leaves `None`
+        304 => serde_json::from_str("null").map_err(ClientError::Json),
+        200..=299 => serde_json::from_str(&response.body).map_err(ClientError::Json),
+        status => Err(parse_error(status, &response.body)),
+    }
+}
+
+/// Read an NDJSON keyword export: a line per keyword, then one with the cursor
+pub(crate) fn handle_export(response: HttpResponse) -> Result<KeywordExportPage> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Line {
+        Keyword(ExportedKeyword),
+        Cursor { cursor: Option<String> },
+    }
+
+    if !(200..=299).contains(&response.status) {
+        return Err(parse_error(response.status, &response.body));
+    }
+    let mut page = KeywordExportPage::default();
+    for line in response.body.lines().filter(|line| !line.trim().is_empty()) {
+        match serde_json::from_str(line).map_err(ClientError::Json)? {
+            Line::Keyword(keyword) => page.keywords.push(keyword),
+            Line::Cursor { cursor } => page.cursor = cursor,
+        }
+    }
+    Ok(page)
+}
+
+/// Read the answer to a `HEAD` existence check
+pub(crate) fn handle_exists(response: HttpResponse) -> Result<bool> {
+    match response.status {
+        200..=299 => Ok(true),
+        404 => Ok(false),
+        status => Err(parse_error(status, &response.body)),
+    }
+}
+
+/// Turn an error response into a [`ClientError`]. Every API error carries the JSON
+/// [`ErrorResponse`] envelope, so anything else came from something in front of the
+/// worker and is reported as a plain HTTP failure.
+fn parse_error(status: u16, body: &str) -> ClientError {
+    match serde_json::from_str::<ErrorResponse>(body) {
+        Ok(response) => {
+            let api = ApiError {
+                status,
+                code: response.code,
+                message: response.error,
+                retryable: response.retryable,
+            };
+            match api.code {
+                ErrorCode::IndexFrozen => ClientError::IndexFrozen(api),
+                _ if api.is_not_found() => ClientError::NotFound(api),
+                _ => ClientError::Api(api),
             }
         }
+        Err(_) => ClientError::Http(format!("HTTP {}: {}", status, body)),
+    }
+}
 
-        self.handle_response::<T>(response)
+/// Read a 207 response, which reports an `AddDocumentResponse` listing the keyword
+/// shards that failed. Requests that expect another body get the report back as a
+/// [`ClientError::PartiallyIndexed`] instead.
+fn parse_partial<T>(body: &str) -> Result<T>
+where
+    T: for<'de> Deserialize<'de>,
+{
+    match serde_json::from_str::<T>(body) {
+        Ok(parsed) => Ok(parsed),
+        Err(err) => match serde_json::from_str::<AddDocumentResponse>(body) {
+            Ok(report) => Err(ClientError::PartiallyIndexed(Box::new(report))),
+            Err(_) => Err(ClientError::Json(err)),
+        },
     }
+}
 
-    fn handle_response<T>(
-        &self,
-        response: reqwest::blocking::Response,
-    ) -> std::result::Result<T, ClientError>
-    where
-        T: for<'de> Deserialize<'de>,
-    {
-        let status_code = response.status().as_u16();
-        if status_code >= 200 && status_code < 300 {
-            response
-                .json::<T>()
-                .map_err(|err| ClientError::Reqwest(err))
-        } else {
-            // Try to parse as error response first
-            let raw_body = response.text().unwrap_or_default();
-            let parsed_err = serde_json::from_str::<ErrorResponse>(&raw_body);
-            if let Ok(error_response) = parsed_err {
-                return Err(ClientError::Api(error_response.error));
-            } else {
-                Err(ClientError::Http(format!(
-                    "HTTP {}: {}",
-                    status_code, raw_body
-                )))
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Document, ErrorCode};
+
+    const PARTIAL: &str = r#"{"id":"doc1","revision":1,"lang":"EN","keywords_added":1,
+        "keywords_removed":0,"keywords_total":1,"index_docs_count":4,"indexed_keywords":[],"failed_keywords":[{"keyword":"ocean","error":"KV store error"}]}"#;
+
+    #[test]
+    fn test_parse_partial_update() {
+        let report = parse_partial::<AddDocumentResponse>(PARTIAL).unwrap();
+        assert_eq!(report.failed_keywords[0].keyword, "ocean");
+
+        match parse_partial::<Document>(PARTIAL) {
+            Err(ClientError::PartiallyIndexed(report)) => assert_eq!(report.revision, 1),
+            other => panic!("expected a partial index error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_error_envelope() {
+        let err = parse_error(
+            404,
+            r#"{"error":"Index 'sample' not found","code":"index_not_found"}"#,
+        );
+        match err {
+            ClientError::NotFound(api) => {
+                assert_eq!(api.status, 404);
+                assert_eq!(api.code, ErrorCode::IndexNotFound);
+                assert_eq!(api.message, "Index 'sample' not found");
+                assert!(api.is_not_found());
             }
+            other => panic!("expected a not found error, got {:?}", other),
         }
     }
+
+    #[test]
+    fn test_parse_error_unknown_code() {
+        let err = parse_error(418, r#"{"error":"Short and stout","code":"teapot"}"#);
+        assert!(matches!(
+            err,
+            ClientError::Api(ApiError {
+                code: ErrorCode::Unknown,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_parse_error_retryable_hint() {
+        let err = parse_error(
+            502,
+            r#"{"error":"KV store error","code":"internal_error","retryable":true}"#,
+        );
+        match err {
+            ClientError::Api(api) => assert!(api.retryable && api.is_retryable()),
+            other => panic!("expected an API error, got {:?}", other),
+        }
+        match parse_error(404, r#"{"error":"Not Found","code":"not_found"}"#) {
+            ClientError::NotFound(api) => assert!(!api.retryable && !api.is_retryable()),
+            other => panic!("expected a not found error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_error_non_api_body() {
+        let err = parse_error(502, "<html>Bad Gateway</html>");
+        assert!(matches!(err, ClientError::Http(_)));
+    }
 }
@@ -0,0 +1,801 @@
+//! [`IndexHandle`], which binds a client to one index so its calls don't repeat the
+//! index name
+
+#[cfg(feature = "async")]
+use crate::async_client::AsyncClient;
+#[cfg(feature = "blocking")]
+use crate::http::{Client, KeywordExport};
+use crate::{
+    http::ContentType,
+    query::{QueryBuilder, QueryExpr},
+    ActivityEvent, AddDocumentResponse, ClientError, DeleteDocumentResponse, DeletedResponse,
+    Document, DocumentKeywords, DocumentPage, FsckReport, GetKeywordResponse, IndexDocument,
+    IndexMetadata, IndexSettings, KeywordExportPage, KeywordScores, KeywordTrend, RelatedKeyword,
+    ReshardReport, RestoreReport, Result, SavedQuery, SearchMode, SearchOptions, SearchResponse,
+    SnapshotListing, SnapshotReport, StopList, TopBy, TopReport, UpgradeReport, UsageDay,
+    WarmReport,
+};
+use std::collections::HashMap;
+
+/// One index of a [`Client`] or [`AsyncClient`], created by their `index` method.
+/// It borrows the client, so make one wherever it is convenient.
+pub struct IndexHandle<'a, C> {
+    client: &'a C,
+    name: String,
+}
+
+impl<'a, C> IndexHandle<'a, C> {
+    pub(crate) fn new(client: &'a C, name: &str) -> Self {
+        IndexHandle {
+            client,
+            name: name.to_string(),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub(crate) fn client(&self) -> &'a C {
+        self.client
+    }
+}
+
+/// The result of fetching an index document, unless it reports the index is missing
+fn existing_index(fetched: Result<IndexDocument>) -> Option<Result<IndexDocument>> {
+    match fetched {
+        Err(ClientError::NotFound(_)) => None,
+        fetched => Some(fetched),
+    }
+}
+
+#[cfg(feature = "blocking")]
+impl<'a> IndexHandle<'a, Client> {
+    /// The index document, with its document count
+    pub fn stats(&self) -> Result<IndexDocument> {
+        self.client.get_index(&self.name)
+    }
+
+    pub fn exists(&self) -> Result<bool> {
+        self.client.index_exists(&self.name)
+    }
+
+    /// Create the index unless it already exists, returning its index document
+    pub fn ensure_exists(&self) -> Result<IndexDocument> {
+        match existing_index(self.stats()) {
+            Some(index) => index,
+            None => self.client.create_index(&self.name),
+        }
+    }
+
+    /// Create the index with `settings` unless it already exists, in which case its
+    /// settings are left alone, returning its index document
+    pub fn ensure_exists_with(&self, settings: &IndexSettings) -> Result<IndexDocument> {
+        match existing_index(self.stats()) {
+            Some(index) => index,
+            None => self.client.set_index_settings(&self.name, settings),
+        }
+    }
+
+    pub fn delete(&self) -> Result<DeletedResponse> {
+        self.client.delete_index(&self.name)
+    }
+
+    pub fn freeze(&self) -> Result<IndexDocument> {
+        self.client.freeze_index(&self.name)
+    }
+
+    pub fn unfreeze(&self) -> Result<IndexDocument> {
+        self.client.unfreeze_index(&self.name)
+    }
+
+    pub fn get_document(&self, doc_id: &str) -> Result<Document> {
+        self.client.get_document(&self.name, doc_id)
+    }
+
+    pub fn document_exists(&self, doc_id: &str) -> Result<bool> {
+        self.client.document_exists(&self.name, doc_id)
+    }
+
+    pub fn list_documents(
+        &self,
+        cursor: Option<&str>,
+        updated_since: Option<u64>,
+    ) -> Result<DocumentPage> {
+        self.client
+            .list_documents(&self.name, cursor, updated_since)
+    }
+
+    pub fn get_document_if_modified(&self, doc_id: &str, etag: &str) -> Result<Option<Document>> {
+        self.client
+            .get_document_if_modified(&self.name, doc_id, etag)
+    }
+
+    pub fn document_keywords(&self, doc_id: &str, verify: bool) -> Result<DocumentKeywords> {
+        self.client.document_keywords(&self.name, doc_id, verify)
+    }
+
+    pub fn add_document(
+        &self,
+        body: String,
+        lang: Option<&str>,
+        content_type: Option<ContentType>,
+    ) -> Result<AddDocumentResponse> {
+        self.client
+            .add_document(&self.name, body, lang, content_type)
+    }
+
+    pub fn add_document_id(
+        &self,
+        doc_id: &str,
+        body: String,
+        lang: Option<&str>,
+        content_type: Option<ContentType>,
+    ) -> Result<AddDocumentResponse> {
+        self.client
+            .add_document_id(&self.name, doc_id, body, lang, content_type)
+    }
+
+    pub fn update_document(&self, doc_id: &str, body: String) -> Result<AddDocumentResponse> {
+        self.client.update_document(&self.name, doc_id, body)
+    }
+
+    pub fn delete_document(&self, doc_id: &str) -> Result<DeleteDocumentResponse> {
+        self.client.delete_document(&self.name, doc_id)
+    }
+
+    pub fn search(&self, query: &str, options: Option<SearchOptions>) -> Result<SearchResponse> {
+        self.client.search(&self.name, query, options)
+    }
+
+    #[deprecated(note = "use `search` with `SearchOptions::new().full()`")]
+    pub fn search_full(&self, query: &str, full: Option<bool>) -> Result<SearchResponse> {
+        self.search(
+            query,
+            Some(SearchOptions {
+                full,
+                ..SearchOptions::default()
+            }),
+        )
+    }
+
+    pub fn search_with_options(
+        &self,
+        query: &str,
+        options: &SearchOptions,
+    ) -> Result<SearchResponse> {
+        self.client.search_with_options(&self.name, query, options)
+    }
+
+    pub fn search_expr(
+        &self,
+        expr: &QueryExpr,
+        options: Option<SearchOptions>,
+    ) -> Result<SearchResponse> {
+        self.client.search_expr(&self.name, expr, options)
+    }
+
+    #[deprecated(note = "use `search_expr` with `SearchOptions::new().full()`")]
+    pub fn search_expr_full(&self, expr: &QueryExpr, full: Option<bool>) -> Result<SearchResponse> {
+        self.search_expr(
+            expr,
+            Some(SearchOptions {
+                full,
+                ..SearchOptions::default()
+            }),
+        )
+    }
+
+    pub fn search_builder(
+        &self,
+        builder: QueryBuilder,
+        options: Option<SearchOptions>,
+    ) -> Result<SearchResponse> {
+        self.client.search_builder(&self.name, builder, options)
+    }
+
+    #[deprecated(note = "use `search_builder` with `SearchOptions::new().full()`")]
+    pub fn search_builder_full(
+        &self,
+        builder: QueryBuilder,
+        full: Option<bool>,
+    ) -> Result<SearchResponse> {
+        self.search_builder(
+            builder,
+            Some(SearchOptions {
+                full,
+                ..SearchOptions::default()
+            }),
+        )
+    }
+
+    pub fn search_simple(&self, text: &str, mode: SearchMode) -> Result<SearchResponse> {
+        self.client.search_simple(&self.name, text, mode)
+    }
+
+    pub fn search_ids(&self, query: &str) -> Result<Vec<String>> {
+        self.client.search_ids(&self.name, query)
+    }
+
+    pub fn keyword(
+        &self,
+        keyword: &str,
+        limit: Option<usize>,
+        offset: Option<usize>,
+    ) -> Result<GetKeywordResponse> {
+        self.client.get_keyword(&self.name, keyword, limit, offset)
+    }
+
+    pub fn related_keywords(
+        &self,
+        keyword: &str,
+        limit: Option<usize>,
+    ) -> Result<Vec<RelatedKeyword>> {
+        self.client.related_keywords(&self.name, keyword, limit)
+    }
+
+    pub fn keyword_trend(&self, keyword: &str, days: Option<u32>) -> Result<KeywordTrend> {
+        self.client.keyword_trend(&self.name, keyword, days)
+    }
+
+    pub fn keywords(&self, keywords: Vec<&str>) -> Result<HashMap<String, KeywordScores>> {
+        self.client.get_keywords(&self.name, keywords)
+    }
+
+    pub fn stoplist(&self) -> Result<StopList> {
+        self.client.get_stoplist(&self.name)
+    }
+
+    pub fn set_stoplist(&self, keywords: &[&str]) -> Result<StopList> {
+        self.client.set_stoplist(&self.name, keywords)
+    }
+
+    pub fn queries(&self) -> Result<Vec<SavedQuery>> {
+        self.client.list_queries(&self.name)
+    }
+
+    pub fn save_query(&self, name: &str, query: &SavedQuery) -> Result<SavedQuery> {
+        self.client.save_query(&self.name, name, query)
+    }
+
+    pub fn run_query(
+        &self,
+        name: &str,
+        values: &[(&str, &str)],
+        options: Option<SearchOptions>,
+    ) -> Result<SearchResponse> {
+        self.client.run_query(&self.name, name, values, options)
+    }
+
+    pub fn set_settings(&self, settings: &IndexSettings) -> Result<IndexDocument> {
+        self.client.set_index_settings(&self.name, settings)
+    }
+
+    pub fn update_metadata(&self, metadata: &IndexMetadata) -> Result<IndexDocument> {
+        self.client.update_index_metadata(&self.name, metadata)
+    }
+
+    pub fn fsck(&self, cursor: Option<&str>, repair: bool) -> Result<FsckReport> {
+        self.client.fsck(&self.name, cursor, repair)
+    }
+
+    pub fn reshard(&self, target_shards: u32, cursor: Option<&str>) -> Result<ReshardReport> {
+        self.client.reshard(&self.name, target_shards, cursor)
+    }
+
+    pub fn upgrade(&self, cursor: Option<&str>) -> Result<UpgradeReport> {
+        self.client.upgrade(&self.name, cursor)
+    }
+
+    pub fn usage(&self, days: Option<u32>) -> Result<Vec<UsageDay>> {
+        self.client.usage(&self.name, days)
+    }
+
+    pub fn activity(&self, since: Option<u64>) -> Result<Vec<ActivityEvent>> {
+        self.client.activity(&self.name, since)
+    }
+
+    pub fn top(
+        &self,
+        by: TopBy,
+        limit: Option<u32>,
+        sample: Option<f64>,
+        cursor: Option<&str>,
+    ) -> Result<TopReport> {
+        self.client.top(&self.name, by, limit, sample, cursor)
+    }
+
+    pub fn warm(&self, keywords: &[&str]) -> Result<WarmReport> {
+        self.client.warm(&self.name, keywords)
+    }
+
+    pub fn warm_top(&self, top: u32, cursor: Option<&str>) -> Result<WarmReport> {
+        self.client.warm_top(&self.name, top, cursor)
+    }
+
+    pub fn export_keywords_page(
+        &self,
+        limit: Option<u32>,
+        cursor: Option<&str>,
+        after: Option<&str>,
+    ) -> Result<KeywordExportPage> {
+        self.client
+            .export_keywords_page(&self.name, limit, cursor, after)
+    }
+
+    pub fn export_keywords(&self, limit: Option<u32>) -> KeywordExport<'a> {
+        self.client.export_keywords(&self.name, limit)
+    }
+
+    pub fn snapshot(&self) -> Result<SnapshotReport> {
+        self.client.snapshot(&self.name)
+    }
+
+    pub fn list_snapshots(&self) -> Result<Vec<SnapshotListing>> {
+        self.client.list_snapshots(&self.name)
+    }
+
+    pub fn restore(&self, snapshot: u64) -> Result<RestoreReport> {
+        self.client.restore(&self.name, snapshot)
+    }
+}
+
+#[cfg(feature = "async")]
+impl IndexHandle<'_, AsyncClient> {
+    /// The index document, with its document count
+    pub async fn stats(&self) -> Result<IndexDocument> {
+        self.client.get_index(&self.name).await
+    }
+
+    pub async fn exists(&self) -> Result<bool> {
+        self.client.index_exists(&self.name).await
+    }
+
+    /// Create the index unless it already exists, returning its index document
+    pub async fn ensure_exists(&self) -> Result<IndexDocument> {
+        match existing_index(self.stats().await) {
+            Some(index) => index,
+            None => self.client.create_index(&self.name).await,
+        }
+    }
+
+    /// Create the index with `settings` unless it already exists, in which case its
+    /// settings are left alone, returning its index document
+    pub async fn ensure_exists_with(&self, settings: &IndexSettings) -> Result<IndexDocument> {
+        match existing_index(self.stats().await) {
+            Some(index) => index,
+            None => self.client.set_index_settings(&self.name, settings).await,
+        }
+    }
+
+    pub async fn delete(&self) -> Result<DeletedResponse> {
+        self.client.delete_index(&self.name).await
+    }
+
+    pub async fn freeze(&self) -> Result<IndexDocument> {
+        self.client.freeze_index(&self.name).await
+    }
+
+    pub async fn unfreeze(&self) -> Result<IndexDocument> {
+        self.client.unfreeze_index(&self.name).await
+    }
+
+    pub async fn get_document(&self, doc_id: &str) -> Result<Document> {
+        self.client.get_document(&self.name, doc_id).await
+    }
+
+    pub async fn document_exists(&self, doc_id: &str) -> Result<bool> {
+        self.client.document_exists(&self.name, doc_id).await
+    }
+
+    pub async fn list_documents(
+        &self,
+        cursor: Option<&str>,
+        updated_since: Option<u64>,
+    ) -> Result<DocumentPage> {
+        self.client
+            .list_documents(&self.name, cursor, updated_since)
+            .await
+    }
+
+    pub async fn get_document_if_modified(
+        &self,
+        doc_id: &str,
+        etag: &str,
+    ) -> Result<Option<Document>> {
+        self.client
+            .get_document_if_modified(&self.name, doc_id, etag)
+            .await
+    }
+
+    pub async fn document_keywords(&self, doc_id: &str, verify: bool) -> Result<DocumentKeywords> {
+        self.client
+            .document_keywords(&self.name, doc_id, verify)
+            .await
+    }
+
+    pub async fn add_document(
+        &self,
+        body: String,
+        lang: Option<&str>,
+        content_type: Option<ContentType>,
+    ) -> Result<AddDocumentResponse> {
+        self.client
+            .add_document(&self.name, body, lang, content_type)
+            .await
+    }
+
+    pub async fn add_document_id(
+        &self,
+        doc_id: &str,
+        body: String,
+        lang: Option<&str>,
+        content_type: Option<ContentType>,
+    ) -> Result<AddDocumentResponse> {
+        self.client
+            .add_document_id(&self.name, doc_id, body, lang, content_type)
+            .await
+    }
+
+    pub async fn update_document(&self, doc_id: &str, body: String) -> Result<AddDocumentResponse> {
+        self.client.update_document(&self.name, doc_id, body).await
+    }
+
+    pub async fn delete_document(&self, doc_id: &str) -> Result<DeleteDocumentResponse> {
+        self.client.delete_document(&self.name, doc_id).await
+    }
+
+    pub async fn search(
+        &self,
+        query: &str,
+        options: Option<SearchOptions>,
+    ) -> Result<SearchResponse> {
+        self.client.search(&self.name, query, options).await
+    }
+
+    #[deprecated(note = "use `search` with `SearchOptions::new().full()`")]
+    pub async fn search_full(&self, query: &str, full: Option<bool>) -> Result<SearchResponse> {
+        self.search(
+            query,
+            Some(SearchOptions {
+                full,
+                ..SearchOptions::default()
+            }),
+        )
+        .await
+    }
+
+    pub async fn search_with_options(
+        &self,
+        query: &str,
+        options: &SearchOptions,
+    ) -> Result<SearchResponse> {
+        self.client
+            .search_with_options(&self.name, query, options)
+            .await
+    }
+
+    pub async fn search_expr(
+        &self,
+        expr: &QueryExpr,
+        options: Option<SearchOptions>,
+    ) -> Result<SearchResponse> {
+        self.client.search_expr(&self.name, expr, options).await
+    }
+
+    #[deprecated(note = "use `search_expr` with `SearchOptions::new().full()`")]
+    pub async fn search_expr_full(
+        &self,
+        expr: &QueryExpr,
+        full: Option<bool>,
+    ) -> Result<SearchResponse> {
+        self.search_expr(
+            expr,
+            Some(SearchOptions {
+                full,
+                ..SearchOptions::default()
+            }),
+        )
+        .await
+    }
+
+    pub async fn search_builder(
+        &self,
+        builder: QueryBuilder,
+        options: Option<SearchOptions>,
+    ) -> Result<SearchResponse> {
+        self.client
+            .search_builder(&self.name, builder, options)
+            .await
+    }
+
+    #[deprecated(note = "use `search_builder` with `SearchOptions::new().full()`")]
+    pub async fn search_builder_full(
+        &self,
+        builder: QueryBuilder,
+        full: Option<bool>,
+    ) -> Result<SearchResponse> {
+        self.search_builder(
+            builder,
+            Some(SearchOptions {
+                full,
+                ..SearchOptions::default()
+            }),
+        )
+        .await
+    }
+
+    pub async fn search_simple(&self, text: &str, mode: SearchMode) -> Result<SearchResponse> {
+        self.client.search_simple(&self.name, text, mode).await
+    }
+
+    pub async fn search_ids(&self, query: &str) -> Result<Vec<String>> {
+        self.client.search_ids(&self.name, query).await
+    }
+
+    pub async fn keyword(
+        &self,
+        keyword: &str,
+        limit: Option<usize>,
+        offset: Option<usize>,
+    ) -> Result<GetKeywordResponse> {
+        self.client
+            .get_keyword(&self.name, keyword, limit, offset)
+            .await
+    }
+
+    pub async fn related_keywords(
+        &self,
+        keyword: &str,
+        limit: Option<usize>,
+    ) -> Result<Vec<RelatedKeyword>> {
+        self.client
+            .related_keywords(&self.name, keyword, limit)
+            .await
+    }
+
+    pub async fn keyword_trend(&self, keyword: &str, days: Option<u32>) -> Result<KeywordTrend> {
+        self.client.keyword_trend(&self.name, keyword, days).await
+    }
+
+    pub async fn keywords(&self, keywords: Vec<&str>) -> Result<HashMap<String, KeywordScores>> {
+        self.client.get_keywords(&self.name, keywords).await
+    }
+
+    pub async fn stoplist(&self) -> Result<StopList> {
+        self.client.get_stoplist(&self.name).await
+    }
+
+    pub async fn set_stoplist(&self, keywords: &[&str]) -> Result<StopList> {
+        self.client.set_stoplist(&self.name, keywords).await
+    }
+
+    pub async fn queries(&self) -> Result<Vec<SavedQuery>> {
+        self.client.list_queries(&self.name).await
+    }
+
+    pub async fn save_query(&self, name: &str, query: &SavedQuery) -> Result<SavedQuery> {
+        self.client.save_query(&self.name, name, query).await
+    }
+
+    pub async fn run_query(
+        &self,
+        name: &str,
+        values: &[(&str, &str)],
+        options: Option<SearchOptions>,
+    ) -> Result<SearchResponse> {
+        self.client
+            .run_query(&self.name, name, values, options)
+            .await
+    }
+
+    pub async fn set_settings(&self, settings: &IndexSettings) -> Result<IndexDocument> {
+        self.client.set_index_settings(&self.name, settings).await
+    }
+
+    pub async fn update_metadata(&self, metadata: &IndexMetadata) -> Result<IndexDocument> {
+        self.client
+            .update_index_metadata(&self.name, metadata)
+            .await
+    }
+
+    pub async fn fsck(&self, cursor: Option<&str>, repair: bool) -> Result<FsckReport> {
+        self.client.fsck(&self.name, cursor, repair).await
+    }
+
+    pub async fn reshard(&self, target_shards: u32, cursor: Option<&str>) -> Result<ReshardReport> {
+        self.client.reshard(&self.name, target_shards, cursor).await
+    }
+
+    pub async fn upgrade(&self, cursor: Option<&str>) -> Result<UpgradeReport> {
+        self.client.upgrade(&self.name, cursor).await
+    }
+
+    pub async fn usage(&self, days: Option<u32>) -> Result<Vec<UsageDay>> {
+        self.client.usage(&self.name, days).await
+    }
+
+    pub async fn activity(&self, since: Option<u64>) -> Result<Vec<ActivityEvent>> {
+        self.client.activity(&self.name, since).await
+    }
+
+    pub async fn top(
+        &self,
+        by: TopBy,
+        limit: Option<u32>,
+        sample: Option<f64>,
+        cursor: Option<&str>,
+    ) -> Result<TopReport> {
+        self.client.top(&self.name, by, limit, sample, cursor).await
+    }
+
+    pub async fn warm(&self, keywords: &[&str]) -> Result<WarmReport> {
+        self.client.warm(&self.name, keywords).await
+    }
+
+    pub async fn warm_top(&self, top: u32, cursor: Option<&str>) -> Result<WarmReport> {
+        self.client.warm_top(&self.name, top, cursor).await
+    }
+
+    pub async fn export_keywords_page(
+        &self,
+        limit: Option<u32>,
+        cursor: Option<&str>,
+        after: Option<&str>,
+    ) -> Result<KeywordExportPage> {
+        self.client
+            .export_keywords_page(&self.name, limit, cursor, after)
+            .await
+    }
+
+    pub async fn snapshot(&self) -> Result<SnapshotReport> {
+        self.client.snapshot(&self.name).await
+    }
+
+    pub async fn list_snapshots(&self) -> Result<Vec<SnapshotListing>> {
+        self.client.list_snapshots(&self.name).await
+    }
+
+    pub async fn restore(&self, snapshot: u64) -> Result<RestoreReport> {
+        self.client.restore(&self.name, snapshot).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{http::HttpMethod, mock::MockTransport};
+
+    const INDEX: &str = r#"{"index":"books","docs_count":2,"version":1,"created":0}"#;
+    const NOT_FOUND: &str = r#"{"error":"Index 'books' not found","code":"index_not_found"}"#;
+
+    fn urls(transport: &MockTransport) -> Vec<(HttpMethod, String)> {
+        transport
+            .requests()
+            .into_iter()
+            .map(|request| (request.method, request.url))
+            .collect()
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn test_handle_urls() {
+        let transport = MockTransport::new();
+        transport
+            .respond(200, r#"{"id":"doc1","rev":1,"lang":"EN","body":"Ocean"}"#)
+            .respond(200, r#"{"deleted":true}"#)
+            .respond(200, r#"{"keyword":"tide","document_count":0,"scores":{}}"#)
+            .respond(200, r#"{"document_count":0,"matches":[]}"#);
+        let client = crate::http::Client::new("https://search.example".into())
+            .with_transport(transport.clone());
+        let books = client.index("books");
+
+        assert_eq!(books.name(), "books");
+        books.get_document("doc1").unwrap();
+        books.delete_document("doc1").unwrap();
+        books.keyword("tide", Some(5), Some(10)).unwrap();
+        books.search("ocean", None).unwrap();
+
+        assert_eq!(
+            urls(&transport),
+            vec![
+                (
+                    HttpMethod::GET,
+                    "https://search.example/books/doc/doc1".into()
+                ),
+                (
+                    HttpMethod::DELETE,
+                    "https://search.example/books/doc/doc1".into()
+                ),
+                (
+                    HttpMethod::GET,
+                    "https://search.example/books/keyword/tide?offset=10&limit=5".into()
+                ),
+                (
+                    HttpMethod::POST,
+                    "https://search.example/books/search?query=ocean".into()
+                ),
+            ]
+        );
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn test_ensure_exists() {
+        let transport = MockTransport::new();
+        transport
+            .respond(404, NOT_FOUND)
+            .respond(201, INDEX)
+            .respond(200, INDEX);
+        let client = crate::http::Client::new("https://search.example".into())
+            .with_transport(transport.clone());
+        let books = client.index("books");
+
+        assert_eq!(books.ensure_exists().unwrap().index, "books");
+        assert_eq!(books.ensure_exists().unwrap().docs_count, 2);
+        assert_eq!(
+            urls(&transport),
+            vec![
+                (HttpMethod::GET, "https://search.example/books".into()),
+                (HttpMethod::PUT, "https://search.example/books".into()),
+                (HttpMethod::GET, "https://search.example/books".into()),
+            ]
+        );
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn test_ensure_exists_with_settings() {
+        let transport = MockTransport::new();
+        transport
+            .respond(404, NOT_FOUND)
+            .respond(201, INDEX)
+            .respond(200, INDEX);
+        let client = crate::http::Client::new("https://search.example".into())
+            .with_transport(transport.clone());
+        let books = client.index("books");
+        let settings = crate::IndexSettings {
+            full: Some(true),
+            ..Default::default()
+        };
+
+        assert_eq!(books.ensure_exists_with(&settings).unwrap().index, "books");
+        let created = transport.last_request().unwrap();
+        assert_eq!(
+            (created.method, created.body.as_deref()),
+            (HttpMethod::PUT, Some(r#"{"full":true}"#))
+        );
+        // An existing index keeps its settings
+        books.ensure_exists_with(&settings).unwrap();
+        assert_eq!(transport.requests().len(), 3);
+        assert_eq!(transport.last_request().unwrap().method, HttpMethod::GET);
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_async_handle() {
+        use futures::executor::block_on;
+
+        let transport = MockTransport::new();
+        transport
+            .respond(404, NOT_FOUND)
+            .respond(201, INDEX)
+            .respond(200, r#"{"keywords":["the"]}"#);
+        let client = crate::async_client::AsyncClient::new("https://search.example".into())
+            .with_transport(transport.clone());
+        let books = client.index("books");
+
+        assert_eq!(block_on(books.ensure_exists()).unwrap().index, "books");
+        block_on(books.set_stoplist(&["the"])).unwrap();
+        assert_eq!(
+            urls(&transport),
+            vec![
+                (HttpMethod::GET, "https://search.example/books".into()),
+                (HttpMethod::PUT, "https://search.example/books".into()),
+                (
+                    HttpMethod::PUT,
+                    "https://search.example/books/stoplist".into()
+                ),
+            ]
+        );
+    }
+}
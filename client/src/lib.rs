@@ -1,6 +1,23 @@
+//! Types for the EdgeSearch API, with HTTP clients behind cargo features:
+//! `blocking` (the default) adds [`http::Client`], and `async` adds
+//! [`async_client::AsyncClient`]. With neither, only the request and response types,
+//! the query builder and [`ClientError`] are built, without any HTTP dependencies.
+
 pub use crate::responses::*;
 
+#[cfg(feature = "async")]
+pub mod async_client;
+#[cfg(any(feature = "blocking", feature = "async"))]
+pub mod bulk;
+#[cfg(any(feature = "blocking", feature = "async"))]
+mod endpoints;
+pub mod filter;
+#[cfg(any(feature = "blocking", feature = "async"))]
 pub mod http;
+#[cfg(any(feature = "blocking", feature = "async"))]
+pub mod index;
+#[cfg(any(feature = "blocking", feature = "async"))]
+pub mod mock;
 pub mod query;
 pub mod responses;
 
@@ -8,16 +25,63 @@ pub mod responses;
 pub enum ClientError {
     #[error("HTTP request failed: {0}")]
     Http(String),
+    #[cfg(any(feature = "blocking", feature = "async"))]
     #[error("Reqwest error: {0}")]
     Reqwest(reqwest::Error),
     #[error("JSON serialization/deserialization failed: {0}")]
     Json(#[from] serde_json::Error),
     #[error("Invalid URL: {0}")]
     InvalidUrl(String),
+    #[cfg(any(feature = "blocking", feature = "async"))]
     #[error("URL parse error: {0}")]
     ParseError(url::ParseError),
     #[error("API error: {0}")]
-    Api(String),
+    Api(ApiError),
+    /// The index is frozen, so document writes are rejected until it is unfrozen
+    #[error("Index is frozen: {0}")]
+    IndexFrozen(ApiError),
+    /// The index, document or route doesn't exist, told apart by the error's `code`
+    #[error("Not found: {0}")]
+    NotFound(ApiError),
+    #[error("The query builder is empty")]
+    EmptyQuery,
+    #[error("Document was stored, but {} keyword shards failed to update", .0.failed_keywords.len())]
+    PartiallyIndexed(Box<AddDocumentResponse>),
+}
+
+/// An error response from the API
+#[derive(Debug, Clone, PartialEq)]
+pub struct ApiError {
+    pub status: u16,
+    pub code: ErrorCode,
+    pub message: String,
+    /// The server's hint that the same request may succeed if sent again
+    pub retryable: bool,
+}
+
+impl ApiError {
+    /// Whether sending the same request again might succeed: the server said so,
+    /// or the status is one that is usually transient
+    pub fn is_retryable(&self) -> bool {
+        self.retryable || self.status == 429 || self.status >= 500
+    }
+
+    pub fn is_not_found(&self) -> bool {
+        matches!(
+            self.code,
+            ErrorCode::NotFound | ErrorCode::IndexNotFound | ErrorCode::DocumentNotFound
+        )
+    }
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} ({:?}, HTTP {})",
+            self.message, self.code, self.status
+        )
+    }
 }
 
 pub type Result<T> = std::result::Result<T, ClientError>;
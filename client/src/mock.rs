@@ -0,0 +1,984 @@
+//! A transport that answers with canned responses, for unit testing code that uses
+//! a client without a running server.
+//!
+//! ```
+//! # #[cfg(feature = "blocking")] {
+//! use edgesearch_client::{http::Client, mock::MockTransport};
+//!
+//! let transport = MockTransport::new();
+//! transport.respond(200, r#"{"ready":true}"#);
+//! let client = Client::new("https://search.example".into()).with_transport(transport.clone());
+//!
+//! assert!(client.status().unwrap().ready);
+//! assert_eq!(transport.requests()[0].url, "https://search.example/");
+//! # }
+//! ```
+
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+use futures::future::BoxFuture;
+use serde::Serialize;
+
+use crate::{
+    http::{HttpClient, HttpRequest, HttpResponse},
+    ClientError, Result,
+};
+
+/// Records every request it is sent, and answers them in order with the responses
+/// queued by [`Self::respond`]. Clones share the same queue and record, so keep a
+/// clone to inspect after handing one to a client.
+#[derive(Clone, Default)]
+pub struct MockTransport {
+    state: Arc<Mutex<MockState>>,
+}
+
+#[derive(Default)]
+struct MockState {
+    responses: VecDeque<HttpResponse>,
+    requests: Vec<HttpRequest>,
+}
+
+impl MockTransport {
+    pub fn new() -> MockTransport {
+        MockTransport::default()
+    }
+
+    /// Queue a response with a raw body
+    pub fn respond(&self, status: u16, body: &str) -> &Self {
+        let response = HttpResponse {
+            status,
+            body: body.to_string(),
+        };
+        self.state.lock().unwrap().responses.push_back(response);
+        self
+    }
+
+    /// Queue a response with `body` serialized as JSON
+    pub fn respond_json<T: Serialize>(&self, status: u16, body: &T) -> &Self {
+        self.respond(status, &serde_json::to_string(body).unwrap())
+    }
+
+    /// Every request sent so far, oldest first
+    pub fn requests(&self) -> Vec<HttpRequest> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    /// The most recently sent request
+    pub fn last_request(&self) -> Option<HttpRequest> {
+        self.state.lock().unwrap().requests.last().cloned()
+    }
+}
+
+impl HttpClient for MockTransport {
+    fn request(&self, request: HttpRequest) -> BoxFuture<'_, Result<HttpResponse>> {
+        let mut state = self.state.lock().unwrap();
+        let response = state.responses.pop_front().ok_or_else(|| {
+            ClientError::Http(format!(
+                "MockTransport has no response queued for {:?} {}",
+                request.method, request.url
+            ))
+        });
+        state.requests.push(request);
+        Box::pin(futures::future::ready(response))
+    }
+}
+
+#[cfg(all(test, feature = "blocking"))]
+mod tests {
+    use super::*;
+    use crate::{
+        http::{Client, ContentType, HttpMethod},
+        query::{QueryBuilder, QueryExpr},
+        ActivityAction, AddDocumentResponse, ErrorCode, IndexMetadata, IndexSettings, ReshardPhase,
+        RestorePhase, SavedQuery, ScoringMode, SearchMode, SearchOptions, TopBy,
+    };
+    use std::collections::HashMap;
+
+    fn client(transport: &MockTransport) -> Client {
+        Client::new("https://search.example/".into())
+            .with_api_key("secret".into())
+            .with_transport(transport.clone())
+    }
+
+    #[test]
+    fn test_search() {
+        let transport = MockTransport::new();
+        transport.respond(
+            200,
+            r#"{"document_count":1,"matches":[{"doc_id":"doc1","score":0.9,"keywords":[["ocean",0.9]],"body":null}]}"#,
+        );
+
+        let response = client(&transport)
+            .search("idx", "ocean && tide", Some(SearchOptions::new().full()))
+            .unwrap();
+        assert_eq!(response.document_count, 1);
+        assert_eq!(response.matches[0].doc_id, "doc1");
+
+        let request = transport.last_request().unwrap();
+        assert_eq!(request.method, HttpMethod::POST);
+        assert_eq!(
+            request.url,
+            "https://search.example/idx/search?query=ocean%20%26%26%20tide&full=true"
+        );
+        assert_eq!(request.headers["X-API-Key"], "secret");
+    }
+
+    #[test]
+    fn test_saved_queries() {
+        let transport = MockTransport::new();
+        transport
+            .respond(
+                200,
+                r#"{"name":"recipes","query":"{{term}} && recipe","options":{"limit":10}}"#,
+            )
+            .respond(200, r#"{"document_count":0,"matches":[]}"#);
+
+        let saved = SavedQuery {
+            query: "{{term}} && recipe".into(),
+            options: [("limit".to_string(), 10.into())].into(),
+            ..SavedQuery::default()
+        };
+        let stored = client(&transport)
+            .save_query("idx", "recipes", &saved)
+            .unwrap();
+        assert_eq!(stored.name, "recipes");
+        let request = transport.last_request().unwrap();
+        assert_eq!(
+            (
+                request.method,
+                request.url.as_str(),
+                request.body.as_deref()
+            ),
+            (
+                HttpMethod::PUT,
+                "https://search.example/idx/queries/recipes",
+                Some(r#"{"query":"{{term}} && recipe","options":{"limit":10}}"#)
+            )
+        );
+
+        client(&transport)
+            .index("idx")
+            .run_query(
+                "recipes",
+                &[("term", r#"lemon "tart""#)],
+                Some(SearchOptions::new().limit(3)),
+            )
+            .unwrap();
+        let request = transport.last_request().unwrap();
+        assert_eq!(request.method, HttpMethod::POST);
+        assert_eq!(
+            request.url,
+            "https://search.example/idx/queries/recipes/run?limit=3"
+        );
+        assert_eq!(
+            request.body.as_deref(),
+            Some(r#"{"term":"lemon \"tart\""}"#)
+        );
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn test_deprecated_search_wrappers_send_full() {
+        let transport = MockTransport::new();
+        let empty = r#"{"document_count":0,"matches":[]}"#;
+        transport.respond(200, empty).respond(200, empty);
+        let client = client(&transport);
+
+        client.search_full("idx", "ocean", Some(false)).unwrap();
+        client
+            .index("idx")
+            .search_builder_full(QueryBuilder::word("ocean"), Some(true))
+            .unwrap();
+        let urls: Vec<String> = transport.requests().into_iter().map(|r| r.url).collect();
+        assert_eq!(
+            urls,
+            vec![
+                "https://search.example/idx/search?query=ocean&full=false",
+                "https://search.example/idx/search?query=ocean&full=true",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_search_expr() {
+        let transport = MockTransport::new();
+        let found = r#"{"document_count":0,"matches":[]}"#;
+        transport
+            .respond(200, r#"{"ready":true,"capabilities":["query_ast"]}"#)
+            .respond(200, found)
+            .respond(200, found);
+        let current = client(&transport);
+        let expr = QueryExpr::word("ocean").and(QueryExpr::doc_id("a 1").not());
+
+        current
+            .search_expr("idx", &expr, Some(SearchOptions::new().full()))
+            .unwrap();
+        current.search_expr("idx", &expr, None).unwrap();
+        let requests = transport.requests();
+        // The capability is only asked for once
+        assert_eq!(requests.len(), 3);
+        assert_eq!(
+            requests[1].url,
+            "https://search.example/idx/search?full=true"
+        );
+        assert_eq!(
+            requests[1].body.as_deref(),
+            Some(expr.to_ast_json().as_str())
+        );
+        assert_eq!(requests[2].url, "https://search.example/idx/search");
+
+        // Servers older than the capability are sent the query string
+        let transport = MockTransport::new();
+        transport
+            .respond(200, r#"{"ready":true}"#)
+            .respond(200, found);
+        client(&transport).search_expr("idx", &expr, None).unwrap();
+        assert_eq!(
+            transport.last_request().unwrap().url,
+            "https://search.example/idx/search?query=%28ocean%20%26%26%20~%28id%3A%22a%201%22%29%29"
+        );
+    }
+
+    #[test]
+    fn test_search_simple() {
+        let transport = MockTransport::new();
+        transport.respond(
+            200,
+            r#"{"document_count":0,"matches":[],"expanded_query":"((async && runtime) || async runtime)"}"#,
+        );
+        let response = client(&transport)
+            .index("idx")
+            .search_simple("async runtime", SearchMode::All)
+            .unwrap();
+        assert!(response.expanded_query.is_some());
+        let request = transport.last_request().unwrap();
+        assert_eq!(request.method, HttpMethod::POST);
+        assert_eq!(
+            request.url,
+            "https://search.example/idx/search?text=async%20runtime&mode=all"
+        );
+    }
+
+    #[test]
+    fn test_search_ids() {
+        let transport = MockTransport::new();
+        transport.respond(200, r#"{"document_count":2,"ids":["b","a"]}"#);
+        let ids = client(&transport)
+            .index("idx")
+            .search_ids("ocean || tide")
+            .unwrap();
+        assert_eq!(ids, vec!["b", "a"]);
+        assert_eq!(
+            transport.last_request().unwrap().url,
+            "https://search.example/idx/search?query=ocean%20%7C%7C%20tide&ids_only=true"
+        );
+    }
+
+    #[test]
+    fn test_add_document() {
+        let transport = MockTransport::new();
+        transport.respond(
+            201,
+            r#"{"id":"doc1","revision":1,"lang":"EN","keywords_added":2,"keywords_removed":0,
+                "keywords_total":2,"index_docs_count":7,"indexed_keywords":["ocean","tide"],
+                "failed_keywords":[]}"#,
+        );
+
+        let added = client(&transport)
+            .add_document_id(
+                "idx",
+                "doc1",
+                "Ocean tides".into(),
+                Some("en"),
+                Some(ContentType::Text),
+            )
+            .unwrap();
+        assert_eq!(added.keywords_added, 2);
+        assert_eq!(added.index_docs_count, Some(7));
+
+        let request = transport.last_request().unwrap();
+        assert_eq!(request.method, HttpMethod::POST);
+        assert_eq!(
+            request.url,
+            "https://search.example/idx/doc/doc1?lang=en&format=text"
+        );
+        assert_eq!(request.body.as_deref(), Some("Ocean tides"));
+    }
+
+    #[test]
+    fn test_titled_document() {
+        let transport = MockTransport::new();
+        transport
+            .respond(
+                201,
+                r#"{"id":"doc1","revision":1,"lang":"EN","keywords_added":2,"keywords_removed":0,
+                    "keywords_total":2,"index_docs_count":1,"indexed_keywords":["lighthouses","lamps"],
+                    "failed_keywords":[]}"#,
+            )
+            .respond(
+                200,
+                r#"{"document_count":1,"matches":[{"doc_id":"doc1","score":1.0,"keywords":[],
+                    "body":"Keepers tended the lamps","title":"Lighthouses"}]}"#,
+            );
+        let client = client(&transport);
+
+        let envelope = r#"{"title":"Lighthouses","body":"Keepers tended the lamps"}"#;
+        client
+            .add_document_id(
+                "idx",
+                "doc1",
+                envelope.into(),
+                None,
+                Some(ContentType::Envelope),
+            )
+            .unwrap();
+        assert_eq!(
+            transport.last_request().unwrap().url,
+            "https://search.example/idx/doc/doc1?format=envelope"
+        );
+
+        let response = client
+            .search("idx", "lighthouses", Some(SearchOptions::new().full()))
+            .unwrap();
+        assert_eq!(response.matches[0].title.as_deref(), Some("Lighthouses"));
+    }
+
+    #[test]
+    fn test_partially_indexed_document() {
+        let transport = MockTransport::new();
+        transport.respond(
+            207,
+            r#"{"id":"doc1","revision":1,"lang":"EN","keywords_added":1,"keywords_removed":0,
+                "keywords_total":1,"index_docs_count":1,"indexed_keywords":[],
+                "failed_keywords":[{"keyword":"ocean","error":"KV store error"}]}"#,
+        );
+        let added: AddDocumentResponse = client(&transport)
+            .add_document("idx", "Ocean".into(), None, None)
+            .unwrap();
+        assert_eq!(added.failed_keywords[0].keyword, "ocean");
+        assert_eq!(
+            transport.last_request().unwrap().url,
+            "https://search.example/idx/doc"
+        );
+    }
+
+    #[test]
+    fn test_auto_create_indexes() {
+        const MISSING: &str = r#"{"error":"Index 'idx' not found","code":"index_not_found"}"#;
+        const INDEX: &str = r#"{"index":"idx","docs_count":0,"version":1,"created":1}"#;
+        const ADDED: &str = r#"{"id":"doc1","revision":1,"lang":"EN","keywords_added":1,
+            "keywords_removed":0,"keywords_total":1,"indexed_keywords":["ocean"],"failed_keywords":[]}"#;
+        let transport = MockTransport::new();
+        transport
+            .respond(404, MISSING)
+            .respond(201, INDEX)
+            .respond(201, ADDED);
+        let settings = IndexSettings {
+            limit: Some(20),
+            ..Default::default()
+        };
+        let client = client(&transport)
+            .with_auto_create_indexes(true)
+            .with_auto_create_settings(settings);
+
+        let added = client
+            .add_document_id("idx", "doc1", "Ocean".into(), None, None)
+            .unwrap();
+        assert_eq!(added.keywords_added, 1);
+        let requests = transport.requests();
+        let sent: Vec<_> = requests
+            .iter()
+            .map(|request| (request.method, request.url.as_str()))
+            .collect();
+        assert_eq!(
+            sent,
+            vec![
+                (HttpMethod::POST, "https://search.example/idx/doc/doc1"),
+                (HttpMethod::PUT, "https://search.example/idx"),
+                (HttpMethod::POST, "https://search.example/idx/doc/doc1"),
+            ]
+        );
+        // The write is sent exactly twice, and the create carries the settings
+        assert_eq!(requests[1].body.as_deref(), Some(r#"{"limit":20}"#));
+        assert_eq!(requests[2].body.as_deref(), Some("Ocean"));
+
+        // A write still missing its index after the create isn't sent a third time
+        transport
+            .respond(404, MISSING)
+            .respond(201, INDEX)
+            .respond(404, MISSING);
+        let failed = client.add_document("idx", "Ocean".into(), None, None);
+        assert!(matches!(failed, Err(ClientError::NotFound(_))));
+        assert_eq!(transport.requests().len(), 6);
+
+        // Searches never create the index
+        transport.respond(404, MISSING);
+        assert!(matches!(
+            client.search("idx", "ocean", None),
+            Err(ClientError::NotFound(_))
+        ));
+        assert_eq!(transport.requests().len(), 7);
+    }
+
+    #[test]
+    fn test_get_document_if_modified() {
+        let transport = MockTransport::new();
+        transport
+            .respond(
+                200,
+                r#"{"id":"doc1","rev":2,"lang":"EN","body":"Ocean tides","keywords":[]}"#,
+            )
+            .respond(304, "");
+        let client = client(&transport);
+
+        let document = client
+            .get_document_if_modified("idx", "doc1", "\"doc1-1\"")
+            .unwrap()
+            .unwrap();
+        assert_eq!(document.etag(), "\"doc1-2\"");
+        let request = transport.last_request().unwrap();
+        assert_eq!(request.url, "https://search.example/idx/doc/doc1");
+        assert_eq!(request.headers["If-None-Match"], "\"doc1-1\"");
+
+        let unchanged = client.get_document_if_modified("idx", "doc1", &document.etag());
+        assert!(unchanged.unwrap().is_none());
+        assert_eq!(
+            transport.last_request().unwrap().headers["If-None-Match"],
+            "\"doc1-2\""
+        );
+    }
+
+    #[test]
+    fn test_index_settings() {
+        let transport = MockTransport::new();
+        transport
+            .respond(
+                200,
+                r#"{"index":"idx","docs_count":0,"version":1,"created":1,"generation":1,
+                    "settings":{"full":false,"limit":20,"scoring":"coverage"}}"#,
+            )
+            .respond(
+                200,
+                r#"{"document_count":0,"matches":[],
+                    "effective_options":{"full":false,"limit":20,"scoring":"coverage"}}"#,
+            );
+        let client = client(&transport);
+
+        let settings = IndexSettings {
+            full: Some(false),
+            limit: Some(20),
+            scoring: Some(ScoringMode::Coverage),
+            position_boost: None,
+            title_boost: None,
+            min_keyword_score: None,
+            extractor: None,
+            version: None,
+        };
+        let index = client.set_index_settings("idx", &settings).unwrap();
+        assert_eq!(index.settings, settings);
+        let request = transport.last_request().unwrap();
+        assert_eq!(request.method, HttpMethod::PUT);
+        assert_eq!(
+            request.body.as_deref(),
+            Some(r#"{"full":false,"limit":20,"scoring":"coverage"}"#)
+        );
+
+        let response = client.search("idx", "ocean", None).unwrap();
+        let applied = response.effective_options.unwrap();
+        assert_eq!(applied.limit, Some(20));
+        assert_eq!(applied.scoring, ScoringMode::Coverage);
+    }
+
+    #[test]
+    fn test_exists_checks() {
+        let transport = MockTransport::new();
+        transport
+            .respond(200, "")
+            .respond(404, "")
+            .respond(200, "")
+            .respond(500, "");
+        let client = client(&transport);
+
+        assert!(client.index_exists("idx").unwrap());
+        assert!(!client.index_exists("missing").unwrap());
+        assert!(client.document_exists("idx", "doc1").unwrap());
+        assert!(matches!(
+            client.document_exists("idx", "doc1"),
+            Err(ClientError::Http(_))
+        ));
+
+        let requests = transport.requests();
+        assert!(requests.iter().all(|r| r.method == HttpMethod::HEAD));
+        assert_eq!(requests[1].url, "https://search.example/missing");
+        assert_eq!(requests[2].url, "https://search.example/idx/doc/doc1");
+    }
+
+    #[test]
+    fn test_list_indexes_detailed() {
+        let transport = MockTransport::new();
+        transport.respond(
+            200,
+            r#"{"names":["idx","logs"],"indexes":[],"detail_truncated":true}"#,
+        );
+
+        let listing = client(&transport).list_indexes_detailed().unwrap();
+        assert_eq!(listing.names, vec!["idx", "logs"]);
+        assert!(listing.detail_truncated);
+        assert_eq!(
+            transport.last_request().unwrap().url,
+            "https://search.example/indexes?detail=true"
+        );
+    }
+
+    #[test]
+    fn test_index_metadata() {
+        let transport = MockTransport::new();
+        transport
+            .respond(
+                200,
+                r#"{"index":"idx","docs_count":3,"version":1,"created":1,"generation":2,"description":"Product docs","labels":{"env":"prod"}}"#,
+            )
+            .respond(
+                200,
+                r#"{"names":["idx"],"indexes":[],"detail_truncated":false}"#,
+            );
+        let client = client(&transport);
+
+        let metadata = IndexMetadata {
+            description: Some("Product docs".into()),
+            labels: Some(HashMap::from([("env".to_string(), "prod".to_string())])),
+        };
+        let index = client.index("idx").update_metadata(&metadata).unwrap();
+        assert_eq!(index.description.as_deref(), Some("Product docs"));
+        assert_eq!(index.labels["env"], "prod");
+        let request = transport.last_request().unwrap();
+        assert_eq!(request.method, HttpMethod::PATCH);
+        assert_eq!(request.url, "https://search.example/idx");
+        assert_eq!(
+            request.body.as_deref(),
+            Some(r#"{"description":"Product docs","labels":{"env":"prod"}}"#)
+        );
+
+        client.list_indexes_with_label("env", "prod").unwrap();
+        assert_eq!(
+            transport.last_request().unwrap().url,
+            "https://search.example/indexes?detail=true&label=env%3Aprod"
+        );
+    }
+
+    #[test]
+    fn test_freeze_index() {
+        let transport = MockTransport::new();
+        transport
+            .respond(
+                200,
+                r#"{"index":"idx","docs_count":3,"version":1,"created":1,"generation":2,"frozen":true}"#,
+            )
+            .respond(
+                423,
+                r#"{"error":"Index 'idx' is frozen and rejects document writes until it is unfrozen","code":"index_frozen"}"#,
+            );
+        let client = client(&transport);
+
+        assert!(client.freeze_index("idx").unwrap().frozen);
+        let request = transport.last_request().unwrap();
+        assert_eq!(request.method, HttpMethod::POST);
+        assert_eq!(request.url, "https://search.example/idx/freeze");
+
+        match client.delete_document("idx", "doc1") {
+            Err(ClientError::IndexFrozen(api)) => {
+                assert_eq!((api.status, api.code), (423, ErrorCode::IndexFrozen));
+            }
+            other => panic!("expected a frozen index error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_delete_document() {
+        let transport = MockTransport::new();
+        transport
+            .respond(
+                207,
+                r#"{"deleted":false,"revision":3,"keywords_removed":11,"shards_touched":12,"failed_shards":[{"keyword":"ocean","error":"KV store error"}]}"#,
+            )
+            .respond(200, r#"{"deleted":true}"#);
+        let client = client(&transport);
+        let index = client.index("idx");
+
+        let partial = index.delete_document("doc1").unwrap();
+        assert!(!partial.deleted);
+        assert_eq!(
+            (
+                partial.revision,
+                partial.keywords_removed,
+                partial.shards_touched
+            ),
+            (Some(3), Some(11), Some(12))
+        );
+        assert_eq!(partial.failed_shards[0].keyword, "ocean");
+        let request = transport.last_request().unwrap();
+        assert_eq!(request.method, HttpMethod::DELETE);
+        assert_eq!(request.url, "https://search.example/idx/doc/doc1");
+
+        // An older server sends `deleted` alone
+        let old = index.delete_document("doc1").unwrap();
+        assert!(old.deleted && old.revision.is_none() && old.failed_shards.is_empty());
+    }
+
+    #[test]
+    fn test_reshard() {
+        let transport = MockTransport::new();
+        transport
+            .respond(
+                200,
+                r#"{"phase":"staging","target_shards":64,"n_shards":32,"keywords":50,
+                    "shards_written":61,"shards_deleted":0,"cursor":"s:50:idx:kw:ocean:17"}"#,
+            )
+            .respond(
+                409,
+                r#"{"error":"A reshard to 64 shards is in progress, and must finish first","code":"reshard_in_progress"}"#,
+            );
+        let client = client(&transport);
+
+        let report = client.reshard("idx", 64, None).unwrap();
+        assert_eq!(report.phase, ReshardPhase::Staging);
+        assert_eq!(
+            transport.last_request().unwrap().url,
+            "https://search.example/idx/reshard?target_shards=64"
+        );
+        match client.reshard("idx", 16, report.cursor.as_deref()) {
+            Err(ClientError::Api(api)) => {
+                assert_eq!((api.status, api.code), (409, ErrorCode::ReshardInProgress));
+            }
+            other => panic!("expected a reshard in progress error, got {:?}", other),
+        }
+        let request = transport.last_request().unwrap();
+        assert_eq!(request.method, HttpMethod::POST);
+        assert_eq!(
+            request.url,
+            "https://search.example/idx/reshard?target_shards=16&cursor=s%3A50%3Aidx%3Akw%3Aocean%3A17"
+        );
+    }
+
+    #[test]
+    fn test_activity() {
+        let transport = MockTransport::new();
+        transport.respond(
+            200,
+            r#"{"index":"idx","events":[{"doc_id":"doc1","action":"deleted","ts":1709218800000,"keywords_changed":12}]}"#,
+        );
+        let events = client(&transport)
+            .index("idx")
+            .activity(Some(1709164800000))
+            .unwrap();
+        assert_eq!(
+            (events[0].doc_id.as_str(), events[0].action),
+            ("doc1", ActivityAction::Deleted)
+        );
+        assert_eq!(
+            transport.last_request().unwrap().url,
+            "https://search.example/idx/activity?since=1709164800000"
+        );
+    }
+
+    #[test]
+    fn test_keyword_trend() {
+        let transport = MockTransport::new();
+        transport.respond(
+            200,
+            r#"{"keyword":"sea tide","days":[{"date":"2024-03-01","net":3,"cumulative":3},{"date":"2024-03-02","net":-1,"cumulative":2}]}"#,
+        );
+        let trend = client(&transport)
+            .index("idx")
+            .keyword_trend("sea tide", Some(2))
+            .unwrap();
+        let series: Vec<(i64, i64)> = trend
+            .days
+            .iter()
+            .map(|day| (day.net, day.cumulative))
+            .collect();
+        assert_eq!(series, vec![(3, 3), (-1, 2)]);
+        assert_eq!(
+            transport.last_request().unwrap().url,
+            "https://search.example/idx/keyword/sea%20tide/trend?days=2"
+        );
+    }
+
+    #[test]
+    fn test_top() {
+        let transport = MockTransport::new();
+        transport.respond(
+            200,
+            r#"{"by":"keyword_postings","scanned":200,"entries":[{"key":"idx:kw:ocean:","name":"ocean","value":12},{"key":"idx:kw:tide:","name":"tide","value":3}],"cursor":"200:"}"#,
+        );
+        let report = client(&transport)
+            .index("idx")
+            .top(TopBy::KeywordPostings, Some(2), None, Some("0:idx:kw:a:0"))
+            .unwrap();
+        assert_eq!(report.by, TopBy::KeywordPostings);
+        assert_eq!(
+            report
+                .entries
+                .iter()
+                .map(|entry| (entry.key.as_str(), entry.value))
+                .collect::<Vec<_>>(),
+            vec![("idx:kw:ocean:", 12), ("idx:kw:tide:", 3)]
+        );
+        assert_eq!(report.cursor.as_deref(), Some("200:"));
+        assert_eq!(
+            transport.last_request().unwrap().url,
+            "https://search.example/idx/top?by=keyword_postings&limit=2&cursor=0%3Aidx%3Akw%3Aa%3A0"
+        );
+    }
+
+    #[test]
+    fn test_warm() {
+        let transport = MockTransport::new();
+        transport.respond(
+            200,
+            r#"{"warmed":["ocean"],"skipped":["tide"],"shards_read":49,"cursor":null}"#,
+        );
+        let client = client(&transport);
+        let report = client.index("idx").warm(&["ocean", "tide"]).unwrap();
+        assert_eq!(
+            (report.warmed, report.skipped),
+            (vec!["ocean".to_string()], vec!["tide".to_string()])
+        );
+        let request = transport.last_request().unwrap();
+        assert_eq!(request.url, "https://search.example/idx/warm");
+        assert_eq!(request.body.as_deref(), Some(r#"["ocean","tide"]"#));
+
+        transport.respond(
+            200,
+            r#"{"warmed":[],"skipped":[],"shards_read":0,"cursor":"200:"}"#,
+        );
+        let report = client
+            .index("idx")
+            .warm_top(5, Some("0:idx:kw:a:0"))
+            .unwrap();
+        assert_eq!(report.cursor.as_deref(), Some("200:"));
+        assert_eq!(
+            transport.last_request().unwrap().url,
+            "https://search.example/idx/warm?top=5&cursor=0%3Aidx%3Akw%3Aa%3A0"
+        );
+    }
+
+    #[test]
+    fn test_export_keywords() {
+        let transport = MockTransport::new();
+        transport
+            .respond(
+                200,
+                "{\"keyword\":\"ocean\",\"shards\":2,\"doc_count\":7}\n\
+                 {\"keyword\":\"tide\",\"shards\":1,\"doc_count\":1}\n{\"cursor\":\"4:tide\"}\n",
+            )
+            .respond(200, "{\"cursor\":\"4:tidepage\"}\n")
+            .respond(
+                200,
+                "{\"keyword\":\"wave\",\"shards\":3,\"doc_count\":9}\n{\"cursor\":null}\n",
+            );
+        let client = client(&transport);
+        let exported: Vec<(String, u64)> = client
+            .index("idx")
+            .export_keywords(Some(2))
+            .map(|keyword| keyword.map(|keyword| (keyword.keyword, keyword.doc_count)))
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(
+            exported,
+            vec![
+                ("ocean".to_string(), 7),
+                ("tide".to_string(), 1),
+                ("wave".to_string(), 9)
+            ]
+        );
+        let urls: Vec<String> = transport.requests().into_iter().map(|r| r.url).collect();
+        assert_eq!(
+            urls,
+            vec![
+                "https://search.example/idx/keywords/export?limit=2",
+                "https://search.example/idx/keywords/export?limit=2&cursor=4%3Atide",
+                "https://search.example/idx/keywords/export?limit=2&cursor=4%3Atidepage",
+            ]
+        );
+
+        transport.respond(
+            403,
+            r#"{"error":"Exporting an index's keywords requires the API key","code":"unauthorized","retryable":false}"#,
+        );
+        let page = client.export_keywords_page("idx", None, None, Some("ocean"));
+        assert!(matches!(page, Err(ClientError::Api(api)) if api.status == 403));
+        assert_eq!(
+            transport.last_request().unwrap().url,
+            "https://search.example/idx/keywords/export?after=ocean"
+        );
+        // An error ends the iteration
+        transport.respond(500, "oops");
+        let mut export = client.export_keywords("idx", None);
+        assert!(export.next().unwrap().is_err());
+        assert!(export.next().is_none());
+    }
+
+    #[test]
+    fn test_usage() {
+        let transport = MockTransport::new();
+        transport.respond(
+            200,
+            r#"{"index":"idx","days":[{"date":"2024-02-29","searches":4,"docs_added":3,"docs_updated":1,"docs_deleted":0,"results":12,"avg_result_count":3.0}]}"#,
+        );
+        let client = client(&transport);
+
+        let days = client.index("idx").usage(Some(30)).unwrap();
+        assert_eq!(days.len(), 1);
+        assert_eq!((days[0].searches, days[0].avg_result_count), (4, 3.0));
+        let request = transport.last_request().unwrap();
+        assert_eq!(request.method, HttpMethod::GET);
+        assert_eq!(request.url, "https://search.example/idx/usage?days=30");
+    }
+
+    #[test]
+    fn test_upgrade() {
+        let transport = MockTransport::new();
+        transport
+            .respond(
+                200,
+                r#"{"from_version":1,"to_version":2,"shards_rewritten":50,"cursor":"50:"}"#,
+            )
+            .respond(
+                426,
+                r#"{"error":"Index version 3 is not one this worker understands (1 to 2)","code":"unsupported_index_version"}"#,
+            );
+        let client = client(&transport);
+
+        let report = client.upgrade("idx", None).unwrap();
+        assert_eq!((report.from_version, report.to_version), (1, 2));
+        assert_eq!(
+            transport.last_request().unwrap().url,
+            "https://search.example/idx/upgrade"
+        );
+        match client.upgrade("idx", report.cursor.as_deref()) {
+            Err(ClientError::Api(api)) => {
+                assert_eq!(
+                    (api.status, api.code),
+                    (426, ErrorCode::UnsupportedIndexVersion)
+                );
+            }
+            other => panic!("expected an unsupported version error, got {:?}", other),
+        }
+        let request = transport.last_request().unwrap();
+        assert_eq!(request.method, HttpMethod::POST);
+        assert_eq!(
+            request.url,
+            "https://search.example/idx/upgrade?cursor=50%3A"
+        );
+    }
+
+    #[test]
+    fn test_snapshot_and_restore() {
+        let transport = MockTransport::new();
+        transport
+            .respond(
+                200,
+                r#"{"snapshot":1000,"key":"snapshots/idx/1000.ndjson","documents":20,"parts":1,"complete":true}"#,
+            )
+            .respond(
+                200,
+                r#"{"snapshots":[{"snapshot":1000,"key":"snapshots/idx/1000.ndjson"}]}"#,
+            )
+            .respond(
+                200,
+                r#"{"snapshot":1000,"phase":"wipe","keys_deleted":200,"documents":0,"complete":false}"#,
+            )
+            .respond(
+                409,
+                r#"{"error":"A restore of snapshot 1000 is in progress, and must finish first","code":"snapshot_in_progress"}"#,
+            );
+        let client = client(&transport);
+
+        let report = client.snapshot("idx").unwrap();
+        assert!(report.complete);
+        assert_eq!(
+            transport.last_request().unwrap().url,
+            "https://search.example/idx/snapshot"
+        );
+        let snapshots = client.list_snapshots("idx").unwrap();
+        assert_eq!(snapshots[0].snapshot, report.snapshot);
+        assert_eq!(transport.last_request().unwrap().method, HttpMethod::GET);
+
+        let restored = client.restore("idx", report.snapshot).unwrap();
+        assert_eq!(
+            (restored.phase, restored.complete),
+            (RestorePhase::Wipe, false)
+        );
+        let request = transport.last_request().unwrap();
+        assert_eq!(request.method, HttpMethod::POST);
+        assert_eq!(
+            request.url,
+            "https://search.example/idx/restore?snapshot=1000"
+        );
+        match client.snapshot("idx") {
+            Err(ClientError::Api(api)) => {
+                assert_eq!((api.status, api.code), (409, ErrorCode::SnapshotInProgress));
+            }
+            other => panic!("expected a snapshot in progress error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_list_documents() {
+        let transport = MockTransport::new();
+        transport
+            .respond(
+                200,
+                r#"{"documents":[{"id":"doc1","rev":2,"lang":"EN","created_at":1000,"updated_at":2000},
+                    {"id":"legacy","rev":1,"lang":null}],"cursor":"2:idx:document:legacy"}"#,
+            )
+            .respond(200, r#"{"documents":[],"cursor":null}"#);
+        let client = client(&transport);
+
+        let page = client.list_documents("idx", None, None).unwrap();
+        assert_eq!(page.documents[0].updated_at, Some(2000));
+        assert_eq!(page.documents[1].created_at, None);
+        assert_eq!(
+            transport.last_request().unwrap().url,
+            "https://search.example/idx/docs"
+        );
+        let page = client
+            .list_documents("idx", page.cursor.as_deref(), Some(1500))
+            .unwrap();
+        assert!(page.cursor.is_none());
+        assert_eq!(
+            transport.last_request().unwrap().url,
+            "https://search.example/idx/docs?cursor=2%3Aidx%3Adocument%3Alegacy&updated_since=1500"
+        );
+    }
+
+    #[test]
+    fn test_error_mapping() {
+        let transport = MockTransport::new();
+        transport
+            .respond(
+                404,
+                r#"{"error":"Index 'idx' not found","code":"index_not_found"}"#,
+            )
+            .respond(502, "<html>Bad Gateway</html>")
+            .respond(200, "not json");
+        let client = client(&transport);
+
+        match client.get_index("idx") {
+            Err(ClientError::NotFound(api)) => {
+                assert_eq!((api.status, api.code), (404, ErrorCode::IndexNotFound));
+            }
+            other => panic!("expected a not found error, got {:?}", other),
+        }
+        assert!(matches!(client.get_index("idx"), Err(ClientError::Http(_))));
+        assert!(matches!(client.get_index("idx"), Err(ClientError::Json(_))));
+
+        // Nothing left to answer with
+        assert!(matches!(client.status(), Err(ClientError::Http(_))));
+        assert_eq!(transport.requests().len(), 4);
+    }
+}
@@ -1,11 +1,15 @@
 use std::fmt::Display;
 
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
 /// Query builder for constructing search expressions programmatically.
 /// This is the inverse of the AST used in the search lexer.
 #[derive(Debug, Clone, PartialEq)]
 pub enum QueryExpr {
     /// A simple word or phrase
     Word(String),
+    /// Exactly the document with this ID, if it exists
+    DocId(String),
     /// Logical NOT operation
     Not(Box<QueryExpr>),
     /// Logical AND operation  
@@ -17,10 +21,17 @@ pub enum QueryExpr {
 impl QueryExpr {
     /// Create a word/phrase expression
     pub fn word<S: Into<String>>(word: S) -> Self {
-        QueryExpr::Word(format!("{}", word.into()))
+        QueryExpr::Word(word.into().to_string())
+    }
+
+    /// Create an expression matching exactly one document, to restrict a search
+    /// with AND or exclude it with NOT
+    pub fn doc_id<S: Into<String>>(id: S) -> Self {
+        QueryExpr::DocId(id.into())
     }
 
     /// Create a NOT expression
+    #[allow(clippy::should_implement_trait)]
     pub fn not(self) -> Self {
         QueryExpr::Not(Box::new(self))
     }
@@ -35,11 +46,48 @@ impl QueryExpr {
         QueryExpr::Or(Box::new(self), Box::new(other))
     }
 
+    /// Words that are empty or contain whitespace or operator characters must be
+    /// quoted, as must words that would otherwise read as an `id:` term
+    fn needs_quoting(word: &str) -> bool {
+        word.is_empty() || word.starts_with("id:") || Self::has_reserved_chars(word)
+    }
+
+    fn has_reserved_chars(word: &str) -> bool {
+        word.chars()
+            .any(|c| c.is_whitespace() || matches!(c, '(' | ')' | '&' | '|' | '~' | '"'))
+    }
+
+    /// `word` in quotes, with each `"` and `\` in it escaped by a backslash, as the
+    /// server's tokenizer reads quoted words
+    fn quote(word: &str) -> String {
+        let mut quoted = String::with_capacity(word.len() + 2);
+        quoted.push('"');
+        for c in word.chars() {
+            if matches!(c, '"' | '\\') {
+                quoted.push('\\');
+            }
+            quoted.push(c);
+        }
+        quoted.push('"');
+        quoted
+    }
+
     /// Convert the expression to a query string that can be parsed by the lexer
     pub fn to_query_string(&self) -> String {
         match self {
             QueryExpr::Word(word) => {
-                format!("\"{}\"", word)
+                if Self::needs_quoting(word) {
+                    Self::quote(word)
+                } else {
+                    word.clone()
+                }
+            }
+            QueryExpr::DocId(id) => {
+                if id.is_empty() || Self::has_reserved_chars(id) {
+                    format!("id:{}", Self::quote(id))
+                } else {
+                    format!("id:{}", id)
+                }
             }
             QueryExpr::Not(inner) => format!("~({})", inner.to_query_string()),
             QueryExpr::And(left, right) => format!(
@@ -56,6 +104,83 @@ impl QueryExpr {
     }
 }
 
+/// One node of the JSON form of a query's AST, which searches can send in place of
+/// the query string: `{"word": ..}`, `{"doc_id": ..}`, `{"not": ..}`, or `{"and": [..]}`
+/// and `{"or": [..]}` with two or more operands folded from the left
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+enum AstNode {
+    Word(String),
+    DocId(String),
+    Not(Box<AstNode>),
+    And(Vec<AstNode>),
+    Or(Vec<AstNode>),
+}
+
+impl From<&QueryExpr> for AstNode {
+    fn from(expr: &QueryExpr) -> AstNode {
+        match expr {
+            QueryExpr::Word(word) => AstNode::Word(word.clone()),
+            QueryExpr::DocId(id) => AstNode::DocId(id.clone()),
+            QueryExpr::Not(inner) => AstNode::Not(Box::new(inner.as_ref().into())),
+            QueryExpr::And(left, right) => {
+                AstNode::And(vec![left.as_ref().into(), right.as_ref().into()])
+            }
+            QueryExpr::Or(left, right) => {
+                AstNode::Or(vec![left.as_ref().into(), right.as_ref().into()])
+            }
+        }
+    }
+}
+
+impl TryFrom<AstNode> for QueryExpr {
+    type Error = String;
+
+    fn try_from(node: AstNode) -> Result<QueryExpr, String> {
+        let fold =
+            |kind: &str, operands: Vec<AstNode>, join: fn(QueryExpr, QueryExpr) -> QueryExpr| {
+                if operands.len() < 2 {
+                    return Err(format!("{} needs at least 2 operands", kind));
+                }
+                let mut operands = operands.into_iter().map(QueryExpr::try_from);
+                let first = operands.next().unwrap()?;
+                operands.try_fold(first, |left, right| Ok(join(left, right?)))
+            };
+        match node {
+            AstNode::Word(word) => Ok(QueryExpr::Word(word)),
+            AstNode::DocId(id) => Ok(QueryExpr::DocId(id)),
+            AstNode::Not(inner) => Ok(QueryExpr::try_from(*inner)?.not()),
+            AstNode::And(operands) => fold("and", operands, QueryExpr::and),
+            AstNode::Or(operands) => fold("or", operands, QueryExpr::or),
+        }
+    }
+}
+
+impl Serialize for QueryExpr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        AstNode::from(self).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for QueryExpr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<QueryExpr, D::Error> {
+        QueryExpr::try_from(AstNode::deserialize(deserializer)?).map_err(serde::de::Error::custom)
+    }
+}
+
+impl QueryExpr {
+    /// The expression's AST as JSON, which the server reads exactly as built, with
+    /// no words to quote or escape
+    pub fn to_ast_json(&self) -> String {
+        serde_json::to_string(self).expect("a query AST always serializes")
+    }
+
+    /// Parse an AST written by [`Self::to_ast_json`] or the server
+    pub fn from_ast_json(json: &str) -> serde_json::Result<QueryExpr> {
+        serde_json::from_str(json)
+    }
+}
+
 impl Display for QueryExpr {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}", self.to_query_string())
@@ -119,6 +244,7 @@ impl QueryBuilder {
     }
 
     /// Negate the entire current expression
+    #[allow(clippy::should_implement_trait)]
     pub fn not(mut self) -> Self {
         if let Some(expr) = self.expr {
             self.expr = Some(expr.not());
@@ -159,6 +285,19 @@ mod tests {
         assert_eq!(expr.to_query_string(), "\"hello world\"");
     }
 
+    #[test]
+    fn test_query_expr_doc_id() {
+        let expr = QueryExpr::doc_id("abc123")
+            .or(QueryExpr::doc_id("def 456"))
+            .and(QueryExpr::word("rust"));
+        assert_eq!(
+            expr.to_query_string(),
+            "((id:abc123 || id:\"def 456\") && rust)"
+        );
+        // A keyword that looks like an ID term stays a keyword
+        assert_eq!(QueryExpr::word("id:abc").to_query_string(), "\"id:abc\"");
+    }
+
     #[test]
     fn test_query_expr_not() {
         let expr = QueryExpr::word("hello").not();
@@ -253,5 +392,56 @@ mod tests {
 
         let expr4 = QueryExpr::word("hello (world)");
         assert_eq!(expr4.to_query_string(), "\"hello (world)\"");
+
+        // Quotes and backslashes inside quotes are escaped; bare words take no escapes
+        let expr5 = QueryExpr::word("say \"cheese\" \\o/");
+        assert_eq!(expr5.to_query_string(), r#""say \"cheese\" \\o/""#);
+        assert_eq!(QueryExpr::word(r"a\b").to_query_string(), r"a\b");
+        assert_eq!(
+            QueryExpr::doc_id("d\"1 2").to_query_string(),
+            r#"id:"d\"1 2""#
+        );
+    }
+
+    #[test]
+    fn test_escaped_words_match_the_server() {
+        // The query strings the worker's tokenizer tests read back as these words
+        let escaped = [
+            (r#"say "cheese""#, r#""say \"cheese\"""#),
+            (r"C:\My Files\", r#""C:\\My Files\\""#),
+            (r#"\""#, r#""\\\"""#),
+            (r#"a" || "b"#, r#""a\" || \"b""#),
+            ("fn(x) && ~y", r#""fn(x) && ~y""#),
+            (r"a\b", r"a\b"),
+        ];
+        for (word, query) in escaped {
+            assert_eq!(QueryExpr::word(word).to_query_string(), query);
+            let combined = QueryExpr::word(word)
+                .not()
+                .and(QueryExpr::doc_id(word))
+                .to_query_string();
+            // IDs are quoted and escaped the same way
+            assert_eq!(combined, format!("(~({}) && id:{})", query, query));
+        }
+    }
+
+    #[test]
+    fn test_query_expr_ast_json() {
+        let expr = QueryExpr::word("ocean")
+            .and(QueryExpr::word("storm").or(QueryExpr::doc_id("a1")).not());
+        // The same JSON the worker's lexer writes for `ocean && ~(storm || id:a1)`
+        let json =
+            r#"{"and":[{"word":"ocean"},{"not":{"or":[{"word":"storm"},{"doc_id":"a1"}]}}]}"#;
+        assert_eq!(expr.to_ast_json(), json);
+        assert_eq!(QueryExpr::from_ast_json(json).unwrap(), expr);
+
+        let chain = r#"{"or":[{"word":"a b"},{"word":"c"},{"doc_id":"d"}]}"#;
+        assert_eq!(
+            QueryExpr::from_ast_json(chain).unwrap(),
+            QueryExpr::word("a b")
+                .or(QueryExpr::word("c"))
+                .or(QueryExpr::doc_id("d"))
+        );
+        assert!(QueryExpr::from_ast_json(r#"{"and":[{"word":"a"}]}"#).is_err());
     }
 }
@@ -1,22 +1,279 @@
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use serde::{ser::SerializeStruct, Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::{BTreeMap, HashMap};
+
+use crate::filter::{percent_encode, Filter};
+
+/// Searches take a [`crate::query::QueryExpr`] AST as their body
+pub const CAPABILITY_QUERY_AST: &str = "query_ast";
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct StatusResponse {
     pub ready: bool,
+    /// Request formats the server accepts beyond those of every version, which is
+    /// none for servers older than the field
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+}
+
+impl StatusResponse {
+    pub fn supports(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|name| name == capability)
+    }
+}
+
+/// The machine-readable reason sent with every API error
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    MissingParameter,
+    InvalidRequest,
+    InvalidIndexName,
+    InvalidDocumentId,
+    InvalidQuery,
+    Unauthorized,
+    NotFound,
+    IndexNotFound,
+    DocumentNotFound,
+    MethodNotAllowed,
+    DocumentExists,
+    PayloadTooLarge,
+    InternalError,
+    Misconfigured,
+    IndexFrozen,
+    IndexNotFrozen,
+    ReshardInProgress,
+    SnapshotInProgress,
+    /// The index is a version this worker doesn't understand, sent with status 426
+    UnsupportedIndexVersion,
+    /// A document sent as a `Content-Type` the server can't index, or a body that
+    /// looks binary without `format=binary`, sent with status 415
+    UnsupportedMediaType,
+    /// A code added to the server after this client was built
+    #[serde(other)]
+    Unknown,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ErrorResponse {
     pub error: String,
+    pub code: ErrorCode,
+    /// Whether the server expects the same request to succeed if sent again
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub retryable: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct IndexDocument {
     pub index: String,
     pub docs_count: u32,
+    /// The format the index is stored in, changed by [`crate::http::Client::upgrade`]
     pub version: u8,
+    /// The version an upgrade in progress is moving the index to
+    #[serde(default)]
+    pub upgrading_to: Option<u8>,
     pub created: u64,
+    /// Bumped every time the stored index document changes
+    #[serde(default)]
+    pub generation: u64,
+    /// The language given to documents whose language couldn't be detected confidently
+    #[serde(default)]
+    pub default_lang: Option<String>,
+    /// Defaults applied to searches of the index that leave them out
+    #[serde(default)]
+    pub settings: IndexSettings,
+    /// Stop-listed keywords that still have stored shards, only set by [`crate::http::Client::get_index`]
+    #[serde(default)]
+    pub stoplisted_keywords: Option<u32>,
+    /// Whether document writes are rejected until the index is unfrozen
+    #[serde(default)]
+    pub frozen: bool,
+    /// The number of shards keywords are written to, once the index was resharded
+    #[serde(default)]
+    pub n_shards: Option<u32>,
+    /// The reshard in progress, if any
+    #[serde(default)]
+    pub reshard: Option<ReshardState>,
+    /// The template the index's settings and stop-list were taken from, when it was
+    /// created without settings
+    #[serde(default)]
+    pub template: Option<String>,
+    /// What the index is for, when described
+    #[serde(default)]
+    pub description: Option<String>,
+    /// Operational labels like `env: prod`, which
+    /// [`crate::http::Client::list_indexes_with_label`] filters on
+    #[serde(default)]
+    pub labels: HashMap<String, String>,
+}
+
+/// An index's description and labels, set with
+/// [`crate::http::Client::update_index_metadata`]. Fields left as `None` are left
+/// alone; an empty description or label map clears it. The server accepts at most
+/// 16 labels, with names and values of at most 64 characters, and names holding no
+/// `:`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct IndexMetadata {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub labels: Option<HashMap<String, String>>,
+}
+
+/// Which part of a reshard is running: staging copies postings into shards
+/// searches don't read, and cleanup moves each keyword onto them
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReshardPhase {
+    Staging,
+    Cleanup,
+    /// A phase added to the server after this client was built
+    #[serde(other)]
+    Unknown,
+}
+
+/// A reshard in progress, stored on the index document
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReshardState {
+    pub target_shards: u32,
+    pub phase: ReshardPhase,
+}
+
+/// Search options an index applies to searches that leave them out, and how it
+/// scores documents written to it, set with `set_index_settings`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct IndexSettings {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub full: Option<bool>,
+    /// At most 1000
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limit: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scoring: Option<ScoringMode>,
+    /// Decay the scores of keywords first appearing late in documents written
+    /// afterwards by this much; 0 or unset leaves YAKE's scores alone
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub position_boost: Option<f64>,
+    /// Multiply the scores of keywords from the titles of documents written
+    /// afterwards by this much, capped at 1; 1.5 when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title_boost: Option<f64>,
+    /// Leave keywords scoring below this out of documents written afterwards; 0 or
+    /// unset keeps every keyword
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_keyword_score: Option<f64>,
+    /// How keywords are picked from documents written afterwards, YAKE when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub extractor: Option<Extractor>,
+    /// The version a new index is created with, the latest when unset. Existing
+    /// indexes only accept their own.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<u8>,
+}
+
+/// What picks the keywords of an index's documents
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Extractor {
+    /// YAKE's phrases, scored by their context
+    #[default]
+    Yake,
+    /// Single words scored by how often they appear, much cheaper to write
+    Tf,
+}
+
+/// How the words of a search box's text are combined by `search_simple`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SearchMode {
+    /// Any of the words
+    Any,
+    /// Every word, the server's default
+    All,
+    /// The whole text as one keyword
+    Phrase,
+}
+
+impl SearchMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SearchMode::Any => "any",
+            SearchMode::All => "all",
+            SearchMode::Phrase => "phrase",
+        }
+    }
+}
+
+/// How a match's keyword scores are combined into its score
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScoringMode {
+    /// The mean score of the matched keywords, the server's default
+    Mean,
+    /// The sum of the matched keywords' scores
+    Sum,
+    /// The best matched keyword's score
+    Max,
+    /// The mean score, scaled by the share of the query's keywords matched
+    Coverage,
+}
+
+impl ScoringMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ScoringMode::Mean => "mean",
+            ScoringMode::Sum => "sum",
+            ScoringMode::Max => "max",
+            ScoringMode::Coverage => "coverage",
+        }
+    }
+}
+
+/// The document timestamp [`SearchOptions::recency_boost`] measures age from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BoostField {
+    Created,
+    /// The server's default
+    #[default]
+    Updated,
+}
+
+impl BoostField {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BoostField::Created => "created",
+            BoostField::Updated => "updated",
+        }
+    }
+}
+
+/// How [`SearchOptions::normalize`] rescales the returned matches' scores into [0, 1]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Normalize {
+    /// The lowest score becomes 0 and the highest 1
+    MinMax,
+    /// Each match gets its share of `exp(score / temperature)`, see
+    /// [`SearchOptions::softmax_temperature`]
+    Softmax,
+}
+
+impl Normalize {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Normalize::MinMax => "minmax",
+            Normalize::Softmax => "softmax",
+        }
+    }
+}
+
+/// Every index name, with their index documents unless the server had too many to read
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IndexListing {
+    pub names: Vec<String>,
+    /// The stored index documents; [`IndexDocument::docs_count`] is only refreshed by
+    /// reading each index
+    pub indexes: Vec<IndexDocument>,
+    /// Set when there were too many indexes to list in detail, leaving `indexes` empty
+    pub detail_truncated: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -34,41 +291,1462 @@ pub struct Document {
     pub revision: u32,
     #[serde(rename = "lang")]
     pub lang: Option<String>,
+    /// How sure the server's detection was of `lang`, when it wasn't given
+    #[serde(default)]
+    pub lang_confidence: Option<f64>,
     #[serde(rename = "body")]
     pub document_body: Option<String>,
+    /// Set by a [`crate::http::ContentType::Envelope`] write
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
     #[serde(rename = "keywords")]
-    pub keywords: Option<Vec<(String, f64)>>,
+    pub keywords: Option<Vec<(String, KeywordScore)>>,
+    /// Set when the body was offloaded to R2; `document_body` is still filled in on reads
+    #[serde(rename = "body_ref", default)]
+    pub body_ref: Option<String>,
+    /// Milliseconds since the epoch when the document was added. Servers leave it out
+    /// for documents written before they kept timestamps.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<u64>,
+    /// Milliseconds since the epoch of the document's latest revision
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub updated_at: Option<u64>,
+    /// A hash of what the latest revision's keywords were extracted from. Servers
+    /// leave it out for documents written before they kept fingerprints.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fingerprint: Option<String>,
+    /// Set when the server cleaned invalid UTF-8 or control characters from the
+    /// latest revision's body
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub sanitized: bool,
+}
+
+/// A listed document, without its body or keywords
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DocumentHeader {
+    pub id: String,
+    pub rev: u32,
+    pub lang: Option<String>,
+    #[serde(default)]
+    pub created_at: Option<u64>,
+    #[serde(default)]
+    pub updated_at: Option<u64>,
+}
+
+/// A page of `GET /:index/docs`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DocumentPage {
+    pub documents: Vec<DocumentHeader>,
+    /// Pass back to list the next page, `None` once every document was listed. A
+    /// page filtered by `updated_since` may be empty and still have one.
+    pub cursor: Option<String>,
+}
+
+/// A document keyword's score: `score` is what searches match it with, and `raw` is
+/// YAKE's score before the index's [`IndexSettings::position_boost`]. The server
+/// sends unboosted scores as a bare number.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KeywordScore {
+    pub score: f64,
+    pub raw: f64,
+}
+
+impl From<f64> for KeywordScore {
+    fn from(raw: f64) -> Self {
+        KeywordScore { score: raw, raw }
+    }
 }
 
+impl Serialize for KeywordScore {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if self.score == self.raw {
+            return serializer.serialize_f64(self.score);
+        }
+        let mut state = serializer.serialize_struct("KeywordScore", 2)?;
+        state.serialize_field("score", &self.score)?;
+        state.serialize_field("raw", &self.raw)?;
+        state.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for KeywordScore {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Sent {
+            Plain(f64),
+            Boosted { score: f64, raw: f64 },
+        }
+        Ok(match Sent::deserialize(deserializer)? {
+            Sent::Plain(raw) => raw.into(),
+            Sent::Boosted { score, raw } => KeywordScore { score, raw },
+        })
+    }
+}
+
+impl Document {
+    /// The ETag the server sends for this revision of the document, to pass to
+    /// `get_document_if_modified`
+    pub fn etag(&self) -> String {
+        format!("\"{}-{}\"", self.uuid, self.revision)
+    }
+}
+
+/// What adding or updating a document changed
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct UpdateDocumentResponse {
-    pub updated: bool,
-    pub scores: Vec<(String, f64)>,
+pub struct AddDocumentResponse {
+    pub id: String,
     pub revision: u32,
+    pub lang: Option<String>,
+    /// How sure the server's detection was of `lang`, when it wasn't given
+    #[serde(default)]
+    pub lang_confidence: Option<f64>,
+    /// Keywords the document didn't have before this revision
+    pub keywords_added: u32,
+    /// Keywords the previous revision had that this one doesn't
+    pub keywords_removed: u32,
+    pub keywords_total: u32,
+    /// Keywords the server didn't index for scoring below the index's
+    /// `min_keyword_score`
+    #[serde(default)]
+    pub keywords_dropped_below_floor: u32,
+    /// The index's document count after the write, `None` if the server couldn't count it
+    pub index_docs_count: Option<u32>,
+    #[serde(default)]
+    pub indexed_keywords: Vec<String>,
+    /// Keywords whose shard the server could not write; the document itself was
+    /// still stored
+    #[serde(default)]
+    pub failed_keywords: Vec<FailedKeyword>,
+    /// Set when the body matched the stored revision, so the server wrote nothing
+    #[serde(default)]
+    pub unchanged: bool,
+    /// Set when the server cleaned invalid UTF-8 or control characters from the body
+    #[serde(default)]
+    pub sanitized: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FailedKeyword {
+    pub keyword: String,
+    pub error: String,
+}
+
+/// The body of a search sent by [`crate::http::Client::search_ids`]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SearchIdsResponse {
+    pub document_count: u32,
+    /// The matching document IDs, best first
+    pub ids: Vec<String>,
+    #[serde(default)]
+    pub partial: bool,
+    #[serde(default)]
+    pub budget_exceeded: Option<BudgetExceeded>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SearchResponse {
     pub document_count: u32,
     pub matches: Vec<SearchResultRow>,
+    /// Per-stage timings, present when requested with [`SearchOptions::timings`]
+    #[serde(default)]
+    pub timings: Option<SearchTimings>,
+    /// What is imperfect about the results, like query keywords that matched no
+    /// documents, or that are stop-listed with [`SearchOptions::warnings`]
+    #[serde(default)]
+    pub warnings: Vec<SearchWarning>,
+    /// Keywords replaced by [`SearchOptions::fuzzy`] or [`SearchOptions::suggest_only`]
+    #[serde(default)]
+    pub corrections: Vec<Correction>,
+    /// The query with every keyword's stored casing variants, with
+    /// [`SearchOptions::case_insensitive`]
+    #[serde(default)]
+    pub expanded_query: Option<String>,
+    /// The full, limit and scoring options the search ran with, after the server
+    /// filled in the index's settings. Only sent by servers with index settings.
+    #[serde(default)]
+    pub effective_options: Option<EffectiveOptions>,
+    /// Whether the server ran out of search budget and stopped reading, so some
+    /// matches or bodies may be missing
+    #[serde(default)]
+    pub partial: bool,
+    /// Which part of the search budget ran out, for partial results
+    #[serde(default)]
+    pub budget_exceeded: Option<BudgetExceeded>,
+    /// The KV keys the search read, when requested with [`SearchOptions::debug`]
+    #[serde(default)]
+    pub diagnostics: Option<SearchDiagnostics>,
+    /// Filtered fields that none of the matched documents have
+    #[serde(default)]
+    pub filter_errors: Vec<FilterError>,
+    /// Match counts per value of each field in [`SearchOptions::facets`]
+    #[serde(default)]
+    pub facets: HashMap<String, Facet>,
 }
 
+/// A metadata field's most common values among the matches, most matches first.
+/// Each element of an array-valued field is counted.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Facet {
+    pub values: Vec<FacetValue>,
+    /// Matches counted under the values beyond the first 50
+    pub other: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FacetValue {
+    pub value: String,
+    pub count: u32,
+}
+
+/// A field given in [`SearchOptions::filters`] that no matched document has
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FilterError {
+    pub field: String,
+    pub error: String,
+}
+
+/// Every KV key a search read
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SearchDiagnostics {
+    pub keywords: Vec<KeywordTrace>,
+    /// Requests the server made to its durable reader
+    pub durable_requests: usize,
+    /// Bytes of stored values the server read
+    pub bytes_read: usize,
+    /// Subrequests the search made, KV operations and durable reader requests
+    #[serde(default)]
+    pub kv_ops_used: usize,
+}
+
+/// The keyword shards a search found and read for one query keyword
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct KeywordTrace {
+    pub keyword: String,
+    /// The KV prefix the keyword's shards are stored under
+    pub prefix: String,
+    /// How the keyword's shard keys were found
+    #[serde(default)]
+    pub lookup: ShardLookup,
+    pub shards: Vec<ShardTrace>,
+    /// The newest timestamp of the shards read, in milliseconds since the epoch. It
+    /// moves on with every write to any of them.
+    #[serde(default)]
+    pub newest_ts: Option<u64>,
+}
+
+/// How a search found a keyword's shard keys
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ShardLookup {
+    /// By listing the keyword's prefix
+    #[default]
+    Listed,
+    /// By naming every shard of an index with a recorded shard count
+    Enumerated,
+    /// A lookup added to the server after this client was built
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ShardTrace {
+    pub key: String,
+    pub postings: usize,
+    /// The shard's last modified time, `None` when the listed shard couldn't be read
+    pub ts: Option<u64>,
+}
+
+/// The part of a search's budget that ran out
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BudgetExceeded {
+    /// The search ran longer than its time budget
+    Time,
+    /// The search made more KV operations than its budget allows
+    Ops,
+    /// The request came within a few percent of the Workers subrequest cap
+    Subrequests,
+    /// A budget added to the server after this client was built
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EffectiveOptions {
+    pub full: bool,
+    /// `None` when every match was returned
+    pub limit: Option<u32>,
+    pub scoring: ScoringMode,
+}
+
+/// Why a search's results are imperfect
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WarningCode {
+    /// A query keyword that matched no documents
+    KeywordNoMatches,
+    /// A query keyword on the index's stop-list
+    StopListed,
+    /// The stop-list couldn't be read to check the query against it
+    StopListUnavailable,
+    /// A code added to the server after this client was built
+    #[serde(other)]
+    Unknown,
+}
+
+/// A note sent beside a search's results when they are imperfect
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SearchWarning {
+    pub code: WarningCode,
+    pub message: String,
+    /// What the warning is about, like the keyword that matched nothing
+    #[serde(default)]
+    pub detail: Option<String>,
+}
+
+/// A query keyword that matched nothing, and the stored keyword used in its place
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Correction {
+    pub original: String,
+    pub used: String,
+}
+
+/// Milliseconds the server spent in each stage of a search
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SearchTimings {
+    pub parse_ms: u64,
+    pub preload_ms: u64,
+    pub shard_reads: usize,
+    pub evaluate_ms: u64,
+    pub sort_ms: u64,
+    pub hydrate_ms: u64,
+    /// Subrequests the search made, KV operations and durable reader requests
+    #[serde(default)]
+    pub kv_ops_used: usize,
+}
+
+/// A search match; fields left out via [`SearchOptions::fields`] are `None`/empty
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SearchResultRow {
     pub doc_id: String,
-    pub score: f64,
+    #[serde(default)]
+    pub score: Option<f64>,
+    #[serde(default)]
     pub keywords: Vec<(String, f64)>,
+    /// How many of the query's distinct keywords the document matched
+    #[serde(default)]
+    pub matched_terms: Option<u32>,
+    /// The query's distinct keywords, negated ones included
+    #[serde(default)]
+    pub total_terms: Option<u32>,
+    #[serde(default)]
     pub body: Option<String>,
+    /// The document's title, sent with its body when it has one
+    #[serde(default)]
+    pub title: Option<String>,
+    /// Whether a `full` search found the matched document no longer exists
+    #[serde(default)]
+    pub missing: bool,
+    /// Byte ranges `[start, end)` of the body where a [`SearchOptions::contains`] or
+    /// [`SearchOptions::contains_ci`] substring was found, for highlighting
+    #[serde(default)]
+    pub matched_spans: Vec<[usize; 2]>,
+    /// What [`SearchOptions::recency_boost`] multiplied the score by, sent with
+    /// timings or diagnostics. `None` for documents without timestamps.
+    #[serde(default)]
+    pub recency_factor: Option<f64>,
+    /// The score before [`SearchOptions::normalize`] rescaled it
+    #[serde(default)]
+    pub raw_score: Option<f64>,
+    /// How many other matches [`SearchOptions::collapse`] folded into this one
+    #[serde(default)]
+    pub collapsed_count: Option<u32>,
+    /// The best-ranked of those, up to [`SearchOptions::collapse_hits`]
+    #[serde(default)]
+    pub collapsed_hits: Vec<SearchResultRow>,
+}
+
+/// A field of [`SearchResultRow`] that can be selected for a search response
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SearchField {
+    DocId,
+    Score,
+    Keywords,
+    /// `matched_terms` and `total_terms`
+    Terms,
+    Body,
+}
+
+impl SearchField {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SearchField::DocId => "doc_id",
+            SearchField::Score => "score",
+            SearchField::Keywords => "keywords",
+            SearchField::Terms => "terms",
+            SearchField::Body => "body",
+        }
+    }
+}
+
+/// How a search runs and what it returns. Options left `None` aren't sent, leaving
+/// them to the index's settings or the server's defaults. They can be built up one
+/// at a time, each method setting the field of the same name:
+///
+/// ```
+/// use edgesearch_client::{filter::Filter, SearchField, SearchOptions};
+///
+/// let options = SearchOptions::new()
+///     .full()
+///     .limit(10)
+///     .fields([SearchField::DocId, SearchField::Body])
+///     .filter(Filter::lt("price", 100));
+/// assert_eq!(
+///     options.to_query_params(),
+///     "&full=true&fields=doc_id,body&limit=10&filter=price%3A%3C100"
+/// );
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct SearchOptions {
+    /// Fetch full document bodies for every match
+    pub full: Option<bool>,
+    /// Only return these fields per match (`doc_id` is always returned), or
+    /// every field when `None`
+    pub fields: Option<Vec<SearchField>>,
+    /// Include a per-stage timing breakdown in the response
+    pub timings: Option<bool>,
+    /// Warn about query keywords that are stop-listed
+    pub warnings: Option<bool>,
+    /// Replace keywords matching nothing with the closest stored keyword
+    pub fuzzy: Option<bool>,
+    /// Only return the corrections a fuzzy search would make, without matches
+    pub suggest_only: Option<bool>,
+    /// Also match keywords stored in other casings, for indexes written before
+    /// keywords were normalized
+    pub case_insensitive: Option<bool>,
+    /// Return at most this many matches (up to 1000)
+    pub limit: Option<u32>,
+    /// How keyword scores combine into each match's score
+    pub scoring: Option<ScoringMode>,
+    /// Stop reading after this many milliseconds and return partial results. The
+    /// server only lets this lower its own budget.
+    pub budget_ms: Option<u64>,
+    /// Stop reading after this many KV operations and return partial results
+    pub budget_ops: Option<usize>,
+    /// Attach [`SearchResponse::diagnostics`]. The client must be configured with
+    /// the server's API key.
+    pub debug: Option<bool>,
+    /// Only return matches whose metadata passes every filter
+    pub filters: Option<Vec<Filter>>,
+    /// Count matches per value of these metadata fields, in [`SearchResponse::facets`].
+    /// The server refuses faceted searches matching too many documents.
+    pub facets: Option<Vec<String>>,
+    /// Leave out matches whose document no longer exists, instead of returning
+    /// them with [`SearchResultRow::missing`] set
+    pub drop_missing: Option<bool>,
+    /// Only return matches whose body contains every one of these substrings. The
+    /// query needs a keyword or `id:` term that isn't negated.
+    pub contains: Option<Vec<String>>,
+    /// Like [`Self::contains`], but ignoring case, including letters like `ß` that
+    /// fold to several
+    pub contains_ci: Option<Vec<String>>,
+    /// Multiply each match's score by `0.5^(age_days / half_life)`, this being the
+    /// half-life in days, and rank them again
+    pub recency_boost: Option<f64>,
+    /// Which timestamp [`Self::recency_boost`] measures age from
+    pub boost_field: Option<BoostField>,
+    /// Return only the best-ranked match per value of this metadata field, such as
+    /// one chunk per page. [`Self::limit`] counts the collapsed results.
+    pub collapse: Option<String>,
+    /// Return this many of the collapsed matches (up to 10) under each result
+    pub collapse_hits: Option<usize>,
+    /// Rescale the returned matches' scores into [0, 1] after everything else,
+    /// keeping each raw score as [`SearchResultRow::raw_score`]
+    pub normalize: Option<Normalize>,
+    /// The temperature of [`Normalize::Softmax`], 1 on the server when unset
+    pub softmax_temperature: Option<f64>,
+}
+
+impl SearchOptions {
+    /// No options, leaving each to the index's settings or the server's default
+    pub fn new() -> Self {
+        SearchOptions::default()
+    }
+
+    pub fn full(mut self) -> Self {
+        self.full = Some(true);
+        self
+    }
+
+    pub fn fields(mut self, fields: impl IntoIterator<Item = SearchField>) -> Self {
+        self.fields = Some(fields.into_iter().collect());
+        self
+    }
+
+    pub fn timings(mut self) -> Self {
+        self.timings = Some(true);
+        self
+    }
+
+    pub fn warnings(mut self) -> Self {
+        self.warnings = Some(true);
+        self
+    }
+
+    pub fn fuzzy(mut self) -> Self {
+        self.fuzzy = Some(true);
+        self
+    }
+
+    pub fn suggest_only(mut self) -> Self {
+        self.suggest_only = Some(true);
+        self
+    }
+
+    pub fn case_insensitive(mut self) -> Self {
+        self.case_insensitive = Some(true);
+        self
+    }
+
+    pub fn limit(mut self, limit: u32) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    pub fn scoring(mut self, scoring: ScoringMode) -> Self {
+        self.scoring = Some(scoring);
+        self
+    }
+
+    pub fn budget_ms(mut self, budget_ms: u64) -> Self {
+        self.budget_ms = Some(budget_ms);
+        self
+    }
+
+    pub fn budget_ops(mut self, budget_ops: usize) -> Self {
+        self.budget_ops = Some(budget_ops);
+        self
+    }
+
+    pub fn debug(mut self) -> Self {
+        self.debug = Some(true);
+        self
+    }
+
+    /// Add a filter to those every match has to pass
+    pub fn filter(mut self, filter: Filter) -> Self {
+        self.filters.get_or_insert_with(Vec::new).push(filter);
+        self
+    }
+
+    pub fn facets<S: Into<String>>(mut self, fields: impl IntoIterator<Item = S>) -> Self {
+        self.facets = Some(fields.into_iter().map(Into::into).collect());
+        self
+    }
+
+    pub fn drop_missing(mut self) -> Self {
+        self.drop_missing = Some(true);
+        self
+    }
+
+    /// Add a substring every match's body has to contain
+    pub fn contains<S: Into<String>>(mut self, needle: S) -> Self {
+        self.contains
+            .get_or_insert_with(Vec::new)
+            .push(needle.into());
+        self
+    }
+
+    /// Add a substring every match's body has to contain, ignoring case
+    pub fn contains_ci<S: Into<String>>(mut self, needle: S) -> Self {
+        self.contains_ci
+            .get_or_insert_with(Vec::new)
+            .push(needle.into());
+        self
+    }
+
+    /// Boost recent matches, halving the score of a match `half_life` days old
+    pub fn recency_boost(mut self, half_life: f64) -> Self {
+        self.recency_boost = Some(half_life);
+        self
+    }
+
+    pub fn boost_field(mut self, boost_field: BoostField) -> Self {
+        self.boost_field = Some(boost_field);
+        self
+    }
+
+    pub fn collapse<S: Into<String>>(mut self, field: S) -> Self {
+        self.collapse = Some(field.into());
+        self
+    }
+
+    pub fn collapse_hits(mut self, collapse_hits: usize) -> Self {
+        self.collapse_hits = Some(collapse_hits);
+        self
+    }
+
+    pub fn normalize(mut self, normalize: Normalize) -> Self {
+        self.normalize = Some(normalize);
+        self
+    }
+
+    pub fn softmax_temperature(mut self, temperature: f64) -> Self {
+        self.softmax_temperature = Some(temperature);
+        self
+    }
+
+    /// Serialize the options as `&`-prefixed query parameters
+    pub fn to_query_params(&self) -> String {
+        let mut params = String::new();
+        if let Some(full) = self.full {
+            params.push_str(&format!("&full={}", full));
+        }
+        if let Some(fields) = &self.fields {
+            let fields: Vec<&str> = fields.iter().map(SearchField::as_str).collect();
+            params.push_str(&format!("&fields={}", fields.join(",")));
+        }
+        if let Some(timings) = self.timings {
+            params.push_str(&format!("&timings={}", timings));
+        }
+        if let Some(warnings) = self.warnings {
+            params.push_str(&format!("&warnings={}", warnings));
+        }
+        if let Some(fuzzy) = self.fuzzy {
+            params.push_str(&format!("&fuzzy={}", fuzzy));
+        }
+        if let Some(suggest_only) = self.suggest_only {
+            params.push_str(&format!("&suggest_only={}", suggest_only));
+        }
+        if let Some(case_insensitive) = self.case_insensitive {
+            params.push_str(&format!("&case_insensitive={}", case_insensitive));
+        }
+        if let Some(limit) = self.limit {
+            params.push_str(&format!("&limit={}", limit));
+        }
+        if let Some(scoring) = self.scoring {
+            params.push_str(&format!("&scoring={}", scoring.as_str()));
+        }
+        if let Some(budget_ms) = self.budget_ms {
+            params.push_str(&format!("&budget_ms={}", budget_ms));
+        }
+        if let Some(budget_ops) = self.budget_ops {
+            params.push_str(&format!("&budget_ops={}", budget_ops));
+        }
+        if let Some(debug) = self.debug {
+            params.push_str(&format!("&debug={}", debug));
+        }
+        for filter in self.filters.iter().flatten() {
+            params.push_str(&filter.to_query_param());
+        }
+        if let Some(facets) = &self.facets {
+            let facets: Vec<String> = facets.iter().map(|f| percent_encode(f)).collect();
+            params.push_str(&format!("&facets={}", facets.join(",")));
+        }
+        if let Some(drop_missing) = self.drop_missing {
+            params.push_str(&format!("&drop_missing={}", drop_missing));
+        }
+        for needle in self.contains.iter().flatten() {
+            params.push_str(&format!("&contains={}", percent_encode(needle)));
+        }
+        for needle in self.contains_ci.iter().flatten() {
+            params.push_str(&format!("&contains_ci={}", percent_encode(needle)));
+        }
+        if let Some(recency_boost) = self.recency_boost {
+            params.push_str(&format!("&recency_boost={}", recency_boost));
+        }
+        if let Some(boost_field) = self.boost_field {
+            params.push_str(&format!("&boost_field={}", boost_field.as_str()));
+        }
+        if let Some(collapse) = &self.collapse {
+            params.push_str(&format!("&collapse={}", percent_encode(collapse)));
+        }
+        if let Some(collapse_hits) = self.collapse_hits {
+            params.push_str(&format!("&collapse_hits={}", collapse_hits));
+        }
+        if let Some(normalize) = self.normalize {
+            params.push_str(&format!("&normalize={}", normalize.as_str()));
+        }
+        if let Some(temperature) = self.softmax_temperature {
+            params.push_str(&format!("&softmax_temperature={}", temperature));
+        }
+        params
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GetKeywordResponse {
     pub keyword: String,
+    /// Postings in this page
     pub document_count: u32,
-    pub scores: HashMap<String, f64>,
+    /// Postings of the keyword, estimated when `exact` is false. Only sent by servers
+    /// that page keyword postings.
+    #[serde(default)]
+    pub total: Option<u32>,
+    /// False when the server read only some of the keyword's shards for a small page,
+    /// so a better scored posting may have been left out
+    #[serde(default = "exact_by_default")]
+    pub exact: bool,
+    /// The newest timestamp of the keyword's shards, in milliseconds since the epoch.
+    /// None when the page was read from shard summaries, the keyword has no shards,
+    /// or the server predates it.
+    #[serde(default)]
+    pub newest_ts: Option<u64>,
+    /// The postings in this page, best scored first
+    #[serde(deserialize_with = "ranked_scores")]
+    pub scores: Vec<DocumentScore>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DocumentScore {
+    pub doc_id: String,
+    pub score: f64,
 }
 
+fn exact_by_default() -> bool {
+    true
+}
+
+/// Read the ranked `scores` array, or the map of scores by document sent by older
+/// servers, and by `format=map`, ranked the same way
+fn ranked_scores<'de, D>(deserializer: D) -> Result<Vec<DocumentScore>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Scores {
+        Ranked(Vec<DocumentScore>),
+        Map(HashMap<String, f64>),
+    }
+
+    match Scores::deserialize(deserializer)? {
+        Scores::Ranked(scores) => Ok(scores),
+        Scores::Map(map) => {
+            let mut scores: Vec<DocumentScore> = map
+                .into_iter()
+                .map(|(doc_id, score)| DocumentScore { doc_id, score })
+                .collect();
+            scores.sort_by(|a, b| b.score.total_cmp(&a.score).then(a.doc_id.cmp(&b.doc_id)));
+            Ok(scores)
+        }
+    }
+}
+
+/// What deleting a document stripped from its keyword shards. Servers before the
+/// counts were added answer with `deleted` alone.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DeleteDocumentResponse {
+    /// False when some keyword shards could not be written, listed in
+    /// `failed_shards`. The document stays pending deletion, hidden from searches,
+    /// until the delete is sent again.
     pub deleted: bool,
+    /// The revision the document had
+    #[serde(default)]
+    pub revision: Option<u32>,
+    /// Keywords whose posting for the document was stripped
+    #[serde(default)]
+    pub keywords_removed: Option<usize>,
+    /// Keyword shards read to strip the document's postings, one per keyword
+    #[serde(default)]
+    pub shards_touched: Option<usize>,
+    #[serde(default)]
+    pub failed_shards: Vec<FailedKeyword>,
+}
+
+/// A keyword found in the same documents as another
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RelatedKeyword {
+    pub keyword: String,
+    /// The keyword's scores summed over the sampled documents it appears in
+    pub cooccurrence: f64,
+    pub avg_score: f64,
+}
+
+/// How many documents gained or lost a keyword on each recent day
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct KeywordTrend {
+    pub keyword: String,
+    /// Oldest first, ending today (UTC)
+    pub days: Vec<TrendDay>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TrendDay {
+    /// The UTC day, as `yyyy-mm-dd`
+    pub date: String,
+    /// Documents that gained the keyword that day, less those that lost it
+    pub net: i64,
+    /// The net changes of the returned days up to and including this one
+    pub cumulative: i64,
+}
+
+/// Whether a keyword's shard holds the document's posting
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PostingStatus {
+    Ok,
+    Missing,
+    Stale,
+    /// A status added to the server after this client was built
+    #[serde(other)]
+    Unknown,
+}
+
+/// One of a document's keywords, and the shard its posting lives in
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct KeywordPosting {
+    pub keyword: String,
+    pub score: f64,
+    pub shard: u32,
+    /// Only set when verified
+    pub status: Option<PostingStatus>,
+    /// The score stored in the shard, when it differs from the document's
+    pub shard_score: Option<f64>,
+}
+
+/// A posting for a document under a keyword it no longer has
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DanglingPosting {
+    pub keyword: String,
+    pub score: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DocumentKeywords {
+    pub id: String,
+    pub keywords: Vec<KeywordPosting>,
+    /// Only set when verified
+    pub dangling: Option<Vec<DanglingPosting>>,
+    pub consistent: Option<bool>,
+}
+
+/// A document's posting within a keyword shard
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PostingKey {
+    pub shard_key: String,
+    pub doc_id: String,
+}
+
+/// How many problems of one kind an index check found, with the first few as examples
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct FsckFinding<T> {
+    pub count: u32,
+    pub examples: Vec<T>,
+}
+
+/// What one batch of an index check found
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FsckReport {
+    pub checked_documents: u32,
+    pub checked_shards: u32,
+    pub orphan_postings: FsckFinding<PostingKey>,
+    pub missing_postings: FsckFinding<PostingKey>,
+    pub empty_shards: FsckFinding<String>,
+    /// Shards under keys from before keywords were escaped, moved by `repair`
+    #[serde(default)]
+    pub legacy_keys: FsckFinding<String>,
+    /// Documents whose delete stopped part way, finished by `repair`
+    #[serde(default)]
+    pub pending_deletions: FsckFinding<String>,
+    pub repaired: u32,
+    /// Pass back to continue the check, `None` once the whole index was checked
+    pub cursor: Option<String>,
+}
+
+/// What one batch of a reshard did
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReshardReport {
+    pub phase: ReshardPhase,
+    pub target_shards: u32,
+    /// The number of shards documents are written to, `target_shards` once staged
+    pub n_shards: u32,
+    pub keywords: u32,
+    pub shards_written: u32,
+    pub shards_deleted: u32,
+    /// Pass back to continue the reshard, `None` once it's complete
+    pub cursor: Option<String>,
+}
+
+/// What one batch of an index upgrade did
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UpgradeReport {
+    /// The version the index had when the upgrade started
+    pub from_version: u8,
+    pub to_version: u8,
+    pub shards_rewritten: u32,
+    /// Pass back to continue the upgrade, `None` once it's complete
+    pub cursor: Option<String>,
+}
+
+/// What one batch of a snapshot wrote
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SnapshotReport {
+    /// When the snapshot was started, in milliseconds, which restores take
+    pub snapshot: u64,
+    /// The R2 key of the snapshot's manifest
+    pub key: String,
+    pub documents: u32,
+    pub parts: u32,
+    /// Whether the snapshot is written and can be restored
+    pub complete: bool,
+}
+
+/// A complete snapshot of an index
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SnapshotListing {
+    pub snapshot: u64,
+    pub key: String,
+}
+
+/// One day of an index's usage
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UsageDay {
+    /// The UTC day counted, as `yyyy-mm-dd`
+    pub date: String,
+    pub searches: u64,
+    pub docs_added: u64,
+    pub docs_updated: u64,
+    pub docs_deleted: u64,
+    /// The matches returned by all of the day's searches
+    pub results: u64,
+    pub avg_result_count: f64,
+}
+
+/// The response to `GET /:index/usage`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UsageSeries {
+    pub index: String,
+    /// Servers before extractors were selectable always use YAKE
+    #[serde(default)]
+    pub extractor: Extractor,
+    /// What the extractor costs each document written
+    #[serde(default)]
+    pub extractor_note: String,
+    /// The keyword floor documents written now are indexed with
+    #[serde(default)]
+    pub min_keyword_score: f64,
+    /// Oldest first and ending today
+    pub days: Vec<UsageDay>,
+}
+
+/// What an [`ActivityEvent`] did to its document
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ActivityAction {
+    Created,
+    Updated,
+    Deleted,
+    /// An action added to the server after this client was built
+    #[serde(other)]
+    Unknown,
+}
+
+/// One recent change to one of an index's documents
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ActivityEvent {
+    pub doc_id: String,
+    pub action: ActivityAction,
+    /// Milliseconds since the epoch
+    pub ts: u64,
+    /// The keywords whose postings the change added or removed
+    pub keywords_changed: usize,
+}
+
+/// The response to `GET /:index/activity`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ActivityResponse {
+    pub index: String,
+    /// Newest first
+    pub events: Vec<ActivityEvent>,
+}
+
+/// What `GET /:index/top` ranks by
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TopBy {
+    /// Each document's stored size in bytes
+    DocSize,
+    /// Each keyword's postings across its shards
+    KeywordPostings,
+}
+
+impl TopBy {
+    pub fn name(&self) -> &'static str {
+        match self {
+            TopBy::DocSize => "doc_size",
+            TopBy::KeywordPostings => "keyword_postings",
+        }
+    }
+}
+
+/// One document or keyword ranked by `GET /:index/top`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TopEntry {
+    /// The document's KV key, or the prefix of the keyword's shard keys
+    pub key: String,
+    /// The document ID or keyword
+    pub name: String,
+    /// The document's size in bytes, or the keyword's postings
+    pub value: u64,
+}
+
+/// The best entries of one batch of an index's keys
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TopReport {
+    pub by: TopBy,
+    /// Listed keys the batch moved past, including those left out of a sample
+    pub scanned: u32,
+    /// Largest first
+    pub entries: Vec<TopEntry>,
+    /// Pass back to continue, `None` once every key was read
+    pub cursor: Option<String>,
+}
+
+/// What `POST /:index/warm` kept in the merge cache
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WarmReport {
+    /// Keywords whose merged postings were kept
+    pub warmed: Vec<String>,
+    /// Keywords not stored in every shard, or with too many postings to keep
+    pub skipped: Vec<String>,
+    pub shards_read: u32,
+    /// With [`crate::Client::warm_top`], pass back to warm the next batch, `None`
+    /// once every key was read
+    #[serde(default)]
+    pub cursor: Option<String>,
+}
+
+/// One line of `GET /:index/keywords/export`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ExportedKeyword {
+    pub keyword: String,
+    /// How many of the keyword's shards exist
+    pub shards: u32,
+    /// The documents holding the keyword
+    pub doc_count: u64,
+}
+
+/// The keywords of one `GET /:index/keywords/export` call
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct KeywordExportPage {
+    pub keywords: Vec<ExportedKeyword>,
+    /// Pass back to continue, `None` once every keyword was exported
+    pub cursor: Option<String>,
+}
+
+/// The response to `GET /:index/snapshots`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SnapshotList {
+    pub snapshots: Vec<SnapshotListing>,
+}
+
+/// Which part of a restore is running: wipe deletes every key of the index, and
+/// replay writes the snapshot's documents back
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RestorePhase {
+    Wipe,
+    Replay,
+    /// A phase added to the server after this client was built
+    #[serde(other)]
+    Unknown,
+}
+
+/// What one batch of a restore did
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RestoreReport {
+    pub snapshot: u64,
+    pub phase: RestorePhase,
+    pub keys_deleted: u32,
+    /// Documents replayed so far
+    pub documents: u32,
+    /// Whether the index matches the snapshot and can be unfrozen
+    pub complete: bool,
+}
+
+/// Keywords dropped from documents when they are indexed
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct StopList {
+    pub keywords: Vec<String>,
+}
+
+/// Settings and a stop-list given to every index created without settings whose
+/// name matches `pattern`, in which `*` matches any characters
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct IndexTemplate {
+    /// Set by the server from the name the template was saved under
+    #[serde(default, skip_serializing)]
+    pub name: String,
+    pub pattern: String,
+    #[serde(default)]
+    pub settings: IndexSettings,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub stoplist: Vec<String>,
+}
+
+/// A query string and search options saved under a name, run by name with its
+/// `{{name}}` placeholders filled in
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SavedQuery {
+    /// Set by the server from the name the query was saved under
+    #[serde(default, skip_serializing)]
+    pub name: String,
+    pub query: String,
+    /// Search query parameters by name, a list giving a repeated one like `filter`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub options: BTreeMap<String, serde_json::Value>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeywordScores {
+    pub document_count: u32,
+    pub scores: HashMap<String, f64>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keyword_scores_formats() {
+        let expected = vec![
+            DocumentScore {
+                doc_id: "doc2".into(),
+                score: 0.9,
+            },
+            DocumentScore {
+                doc_id: "doc1".into(),
+                score: 0.4,
+            },
+        ];
+
+        let ranked: GetKeywordResponse = serde_json::from_str(
+            r#"{"keyword":"ocean","document_count":2,"scores":[
+                {"doc_id":"doc2","score":0.9},{"doc_id":"doc1","score":0.4}]}"#,
+        )
+        .unwrap();
+        assert_eq!(ranked.scores, expected);
+
+        let map: GetKeywordResponse = serde_json::from_str(
+            r#"{"keyword":"ocean","document_count":2,"scores":{"doc1":0.4,"doc2":0.9}}"#,
+        )
+        .unwrap();
+        assert_eq!(map.scores, expected);
+        // Servers that don't page keywords always read every shard
+        assert_eq!((map.total, map.exact, map.newest_ts), (None, true, None));
+
+        let sampled: GetKeywordResponse = serde_json::from_str(
+            r#"{"keyword":"ocean","document_count":0,"total":4000,"exact":false,
+                "newest_ts":1700000000000,"scores":[]}"#,
+        )
+        .unwrap();
+        assert_eq!((sampled.total, sampled.exact), (Some(4000), false));
+        assert_eq!(sampled.newest_ts, Some(1_700_000_000_000));
+    }
+
+    #[test]
+    fn test_search_options_query_params() {
+        let options = SearchOptions {
+            full: Some(true),
+            fields: Some(vec![SearchField::Score, SearchField::Body]),
+            timings: Some(true),
+            warnings: Some(true),
+            fuzzy: Some(true),
+            suggest_only: None,
+            case_insensitive: Some(true),
+            limit: Some(20),
+            scoring: Some(ScoringMode::Coverage),
+            budget_ms: Some(250),
+            budget_ops: None,
+            debug: Some(true),
+            filters: Some(vec![
+                Filter::range("year", 2019, 2023),
+                Filter::gt("price", 5),
+            ]),
+            facets: Some(vec!["category".into(), "tags".into()]),
+            drop_missing: Some(true),
+            contains: Some(vec!["Pacific Ocean".into()]),
+            contains_ci: Some(vec!["straße".into(), "a&b".into()]),
+            recency_boost: Some(7.5),
+            boost_field: Some(BoostField::Created),
+            collapse: Some("page url".into()),
+            collapse_hits: Some(2),
+            normalize: Some(Normalize::Softmax),
+            softmax_temperature: Some(0.5),
+        };
+        assert_eq!(
+            options.to_query_params(),
+            "&full=true&fields=score,body&timings=true&warnings=true&fuzzy=true\
+             &case_insensitive=true&limit=20&scoring=coverage&budget_ms=250&debug=true\
+             &filter=year%3A2019..2023&filter=price%3A%3E5&facets=category,tags&drop_missing=true\
+             &contains=Pacific%20Ocean&contains_ci=stra%C3%9Fe&contains_ci=a%26b\
+             &recency_boost=7.5&boost_field=created&collapse=page%20url&collapse_hits=2\
+             &normalize=softmax&softmax_temperature=0.5"
+        );
+        assert_eq!(SearchOptions::default().to_query_params(), "");
+
+        let built = SearchOptions::new()
+            .full()
+            .fields([SearchField::Score, SearchField::Body])
+            .timings()
+            .warnings()
+            .fuzzy()
+            .case_insensitive()
+            .limit(20)
+            .scoring(ScoringMode::Coverage)
+            .budget_ms(250)
+            .debug()
+            .filter(Filter::range("year", 2019, 2023))
+            .filter(Filter::gt("price", 5))
+            .facets(["category", "tags"])
+            .drop_missing()
+            .contains("Pacific Ocean")
+            .contains_ci("straße")
+            .contains_ci("a&b")
+            .recency_boost(7.5)
+            .boost_field(BoostField::Created)
+            .collapse("page url")
+            .collapse_hits(2)
+            .normalize(Normalize::Softmax)
+            .softmax_temperature(0.5);
+        assert_eq!(built.to_query_params(), options.to_query_params());
+        let suggest = SearchOptions::new().suggest_only().budget_ops(40);
+        assert_eq!(
+            suggest.to_query_params(),
+            "&suggest_only=true&budget_ops=40"
+        );
+    }
+
+    #[test]
+    fn test_document_timestamps() {
+        // Documents written before the server kept timestamps have none
+        let legacy: Document = serde_json::from_str(
+            r#"{"id":"doc1","rev":1,"lang":"EN","body":"Ocean","keywords":null}"#,
+        )
+        .unwrap();
+        assert_eq!((legacy.created_at, legacy.updated_at), (None, None));
+        let json = serde_json::to_string(&legacy).unwrap();
+        assert!(!json.contains("created_at"));
+
+        let document: Document = serde_json::from_str(
+            r#"{"id":"doc1","rev":2,"lang":"EN","body":"Ocean","keywords":null,
+                "created_at":1767225600000,"updated_at":1767312000000}"#,
+        )
+        .unwrap();
+        assert_eq!(document.created_at, Some(1767225600000));
+        assert_eq!(document.updated_at, Some(1767312000000));
+    }
+
+    #[test]
+    fn test_document_keyword_scores() {
+        let document: Document = serde_json::from_str(
+            r#"{"id":"doc1","rev":2,"lang":"EN","body":"Ocean tides",
+                "keywords":[["ocean",0.9],["tides",{"score":0.6,"raw":0.8}]]}"#,
+        )
+        .unwrap();
+        let keywords = document.keywords.unwrap();
+        assert_eq!(keywords[0].1, KeywordScore::from(0.9));
+        assert_eq!(
+            keywords[1].1,
+            KeywordScore {
+                score: 0.6,
+                raw: 0.8
+            }
+        );
+        assert_eq!(
+            serde_json::to_string(&keywords).unwrap(),
+            r#"[["ocean",0.9],["tides",{"score":0.6,"raw":0.8}]]"#
+        );
+    }
+
+    #[test]
+    fn test_partial_search_response() {
+        let partial: SearchResponse = serde_json::from_str(
+            r#"{"document_count":0,"matches":[],"partial":true,"budget_exceeded":"time"}"#,
+        )
+        .unwrap();
+        assert!(partial.partial);
+        assert_eq!(partial.budget_exceeded, Some(BudgetExceeded::Time));
+
+        let complete: SearchResponse =
+            serde_json::from_str(r#"{"document_count":0,"matches":[]}"#).unwrap();
+        assert!(!complete.partial && complete.budget_exceeded.is_none());
+        assert!(complete.diagnostics.is_none());
+        assert!(complete.filter_errors.is_empty());
+
+        let filtered: SearchResponse = serde_json::from_str(
+            r#"{"document_count":0,"matches":[],
+                "filter_errors":[{"field":"price","error":"No matched document has this field"}]}"#,
+        )
+        .unwrap();
+        assert_eq!(filtered.filter_errors[0].field, "price");
+    }
+
+    #[test]
+    fn test_search_facets() {
+        let response: SearchResponse = serde_json::from_str(
+            r#"{"document_count":2,"matches":[],"facets":{"tags":{"values":[
+                {"value":"sea","count":2},{"value":"travel","count":1}],"other":0}}}"#,
+        )
+        .unwrap();
+        let tags = &response.facets["tags"];
+        assert_eq!(tags.values[0].value, "sea");
+        assert_eq!(tags.values.iter().map(|v| v.count).sum::<u32>(), 3);
+        assert_eq!(tags.other, 0);
+    }
+
+    #[test]
+    fn test_search_warnings() {
+        let response: SearchResponse = serde_json::from_str(
+            r#"{"document_count":1,"matches":[],"warnings":[
+                {"code":"keyword_no_matches","message":"'volcano' matched no documents","detail":"volcano"},
+                {"code":"something_new","message":"A newer warning"}]}"#,
+        )
+        .unwrap();
+        assert_eq!(response.warnings[0].code, WarningCode::KeywordNoMatches);
+        assert_eq!(response.warnings[0].detail.as_deref(), Some("volcano"));
+        assert_eq!(response.warnings[1].code, WarningCode::Unknown);
+        assert!(response.warnings[1].detail.is_none());
+
+        let clean: SearchResponse =
+            serde_json::from_str(r#"{"document_count":0,"matches":[]}"#).unwrap();
+        assert!(clean.warnings.is_empty());
+    }
+
+    #[test]
+    fn test_search_diagnostics() {
+        let response: SearchResponse = serde_json::from_str(
+            r#"{"document_count":0,"matches":[],"diagnostics":{"keywords":[{"keyword":"ocean",
+                "prefix":"idx:kw:ocean:","shards":[{"key":"idx:kw:ocean:3","postings":2,"ts":17},
+                {"key":"idx:kw:ocean:9","postings":0,"ts":null}]}],"durable_requests":0,"bytes_read":120,
+                "kv_ops_used":4}}"#,
+        )
+        .unwrap();
+        let diagnostics = response.diagnostics.unwrap();
+        let shards = &diagnostics.keywords[0].shards;
+        assert_eq!((shards[0].postings, shards[0].ts), (2, Some(17)));
+        assert!(shards[1].ts.is_none());
+        assert_eq!(diagnostics.bytes_read, 120);
+        assert_eq!(diagnostics.kv_ops_used, 4);
+    }
+
+    #[test]
+    fn test_trimmed_row_deserializes_missing_fields() {
+        let row: SearchResultRow = serde_json::from_str(r#"{"doc_id":"doc1"}"#).unwrap();
+        assert_eq!(row.doc_id, "doc1");
+        assert!(row.score.is_none());
+        assert!(row.keywords.is_empty());
+        assert!(row.body.is_none());
+        assert!(row.matched_spans.is_empty());
+        assert!(row.recency_factor.is_none());
+        assert!(row.collapsed_count.is_none() && row.collapsed_hits.is_empty());
+    }
+
+    #[test]
+    fn test_responses_tolerate_unknown_fields() {
+        // Fields, codes and budgets a newer server might send
+        let response: SearchResponse = serde_json::from_str(
+            r#"{"document_count":1,"took_ms":12,"budget_exceeded":"memory","partial":true,
+                "matches":[{"doc_id":"doc1","score":0.9,"highlights":["<b>ocean</b>"]}],
+                "warnings":[{"code":"query_too_long","message":"Trimmed","severity":"low"}],
+                "diagnostics":{"keywords":[{"keyword":"ocean","prefix":"idx:kw:ocean:",
+                    "lookup":"cached","shards":[]}],"durable_requests":0,"bytes_read":0}}"#,
+        )
+        .unwrap();
+        assert_eq!(response.matches[0].doc_id, "doc1");
+        assert_eq!(response.budget_exceeded, Some(BudgetExceeded::Unknown));
+        assert_eq!(response.warnings[0].code, WarningCode::Unknown);
+        let diagnostics = response.diagnostics.unwrap();
+        assert_eq!(diagnostics.keywords[0].lookup, ShardLookup::Unknown);
+
+        let event: ActivityEvent = serde_json::from_str(
+            r#"{"doc_id":"doc1","action":"restored","ts":7,"keywords_changed":2,"by":"admin"}"#,
+        )
+        .unwrap();
+        assert_eq!(event.action, ActivityAction::Unknown);
+    }
+
+    #[test]
+    fn test_responses_default_missing_fields() {
+        // What a server from before most optional fields were added sends
+        let response: SearchResponse =
+            serde_json::from_str(r#"{"document_count":0,"matches":[]}"#).unwrap();
+        assert!(response.warnings.is_empty() && response.facets.is_empty());
+        assert!(response.timings.is_none() && response.effective_options.is_none());
+        assert!(!response.partial);
+
+        let added: AddDocumentResponse = serde_json::from_str(
+            r#"{"id":"doc1","revision":1,"lang":"EN","keywords_added":2,
+                "keywords_removed":0,"keywords_total":2,"index_docs_count":null}"#,
+        )
+        .unwrap();
+        assert_eq!(added.keywords_dropped_below_floor, 0);
+        assert!(added.indexed_keywords.is_empty() && added.failed_keywords.is_empty());
+        assert!(!added.unchanged && !added.sanitized);
+
+        let document: Document = serde_json::from_str(
+            r#"{"id":"doc1","rev":1,"lang":null,"body":null,"keywords":null}"#,
+        )
+        .unwrap();
+        assert!(document.title.is_none() && document.fingerprint.is_none());
+        assert!(!document.sanitized);
+    }
+
+    #[test]
+    fn test_collapsed_row_deserializes_hits() {
+        let row: SearchResultRow = serde_json::from_str(
+            r#"{"doc_id":"a1","score":0.9,"collapsed_count":3,
+                "collapsed_hits":[{"doc_id":"a2","score":0.8}]}"#,
+        )
+        .unwrap();
+        assert_eq!(row.collapsed_count, Some(3));
+        assert_eq!(row.collapsed_hits[0].doc_id, "a2");
+        assert!(row.collapsed_hits[0].collapsed_hits.is_empty());
+    }
+
+    /// Deserialize every example payload in the api worker's OpenAPI spec into the
+    /// matching client struct so the two can't silently diverge.
+    #[test]
+    fn test_openapi_examples_deserialize() {
+        fn check<T: serde::de::DeserializeOwned>(examples: &serde_json::Value, name: &str) {
+            let value = examples
+                .get(name)
+                .unwrap_or_else(|| panic!("openapi.json has no {} example", name))["value"]
+                .clone();
+            if let Err(err) = serde_json::from_value::<T>(value) {
+                panic!("{} example does not deserialize: {}", name, err);
+            }
+        }
+
+        let spec: serde_json::Value =
+            serde_json::from_str(include_str!("../../workers/api/openapi.json")).unwrap();
+        let examples = &spec["components"]["examples"];
+
+        check::<StatusResponse>(examples, "StatusResponse");
+        check::<ErrorResponse>(examples, "ErrorResponse");
+        check::<DeletedResponse>(examples, "DeletedResponse");
+        check::<DeleteDocumentResponse>(examples, "DeleteDocumentResponse");
+        check::<IndexDocument>(examples, "IndexDocument");
+        check::<IndexListing>(examples, "IndexListing");
+        check::<Document>(examples, "Document");
+        check::<DocumentPage>(examples, "DocumentPage");
+        check::<AddDocumentResponse>(examples, "AddDocumentResponse");
+        check::<SearchResponse>(examples, "SearchResponse");
+        check::<GetKeywordResponse>(examples, "GetKeywordResponse");
+        check::<HashMap<String, KeywordScores>>(examples, "BatchKeywordsResponse");
+        check::<StopList>(examples, "StopList");
+        check::<IndexTemplate>(examples, "IndexTemplate");
+        check::<SavedQuery>(examples, "SavedQuery");
+        check::<Vec<RelatedKeyword>>(examples, "RelatedKeywordsResponse");
+        check::<KeywordTrend>(examples, "KeywordTrendResponse");
+        check::<DocumentKeywords>(examples, "DocumentKeywords");
+        check::<FsckReport>(examples, "FsckReport");
+        check::<ReshardReport>(examples, "ReshardReport");
+        check::<SnapshotReport>(examples, "SnapshotReport");
+        check::<RestoreReport>(examples, "RestoreReport");
+        check::<WarmReport>(examples, "WarmReport");
+        assert_eq!(examples.as_object().unwrap().len(), 23);
+    }
 }
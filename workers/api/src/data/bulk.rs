@@ -1,38 +1,153 @@
-use futures::future::join_all;
-use serde::Deserialize;
-use worker::{kv::KvStore, Method, ObjectId, RequestInit};
+use std::{collections::HashMap, future::Future};
+
+use worker::{Method, ObjectId, RequestInit};
 
 use crate::{
     data::{
-        document::Document, encoding::read_length_prefixed, keyword_shard::KeywordShardData,
+        codec::CodecSet,
+        document::Document,
+        encoding::{decode, read_length_prefixed_raw, EncodingError},
+        index::IndexDocument,
+        keyword_shard::KeywordShardData,
+        storage::{list_all, Storage},
+        trace::ReadTrace,
         DataStoreError, KvPersistent,
     },
-    durable::reader::{get_document_limit, get_keyword_limit},
+    durable::reader::{
+        decode_continue_from, get_document_limit, get_keyword_limit, CONTINUE_FROM_HEADER,
+    },
+    edge_log,
+    util::concurrency::join_bounded,
 };
 
-pub struct BulkReader<'a> {
+pub struct BulkReader<'a, S: Storage> {
     n_shards: u32,
-    store: &'a KvStore,
-    durable_obj: ObjectId<'a>,
+    store: &'a S,
+    /// The durable reader to fan large reads out to, or `None` to always read
+    /// straight from the store (e.g. in native tests)
+    durable_obj: Option<DurableTarget<'a>>,
+    /// Where keyword shard reads and durable requests are recorded, if anywhere
+    trace: Option<&'a ReadTrace>,
+    /// What keyword shards are decoded with
+    codecs: CodecSet,
+}
+
+/// Where a [`BulkReader`] sends the reads it fans out
+pub enum DurableTarget<'a> {
+    Object(ObjectId<'a>),
+    /// Answer each request from the store in-process, as the durable reader does, so
+    /// native tests see the requests a read makes
+    #[cfg(test)]
+    Local,
+}
+
+/// The number of durable reader requests needed to fetch `n_keys` keyword shard keys
+/// in one [`BulkReader::get_keyword_kv_keys`] call. Below the chunk limit the keys
+/// are read directly from KV and no durable requests are made.
+pub fn keyword_durable_request_count(n_keys: usize, n_shards: u32) -> usize {
+    let chunk_limit = get_keyword_limit(n_shards) as usize;
+    if n_keys < chunk_limit {
+        0
+    } else {
+        n_keys.div_ceil(chunk_limit)
+    }
+}
+
+/// Send `kv_keys` with `send` until every key is answered. A response the durable
+/// reader capped at `READER_MAX_RESPONSE_BYTES` answers the keys up to the first it
+/// left out, which it names, and the keys after those answered are sent again. Entries come back in the order of `kv_keys`,
+/// with the keys of legacy responses, which send none, filled in by position.
+pub async fn read_continued<'k, F, Fut>(
+    kv_keys: &[&'k str],
+    send: F,
+) -> Vec<(String, Result<Vec<u8>, EncodingError>)>
+where
+    F: Fn(Vec<&'k str>) -> Fut,
+    Fut: Future<Output = (Vec<u8>, Option<String>)>,
+{
+    let mut entries = vec![];
+    let mut remaining = kv_keys;
+    while !remaining.is_empty() {
+        let (bytes, continue_from) = send(remaining.to_vec()).await;
+        let mut answered = read_length_prefixed_raw(&bytes);
+        for ((key, _), requested) in answered.iter_mut().zip(remaining.iter()) {
+            if key.is_empty() {
+                *key = requested.to_string();
+            }
+        }
+        let served = answered.len();
+        entries.extend(answered);
+        let Some(continue_from) = continue_from else {
+            break;
+        };
+        // The reader answers at least one key per response, in the order sent, and
+        // names the first it didn't answer, so anything else is a bug. Resuming by
+        // position holds when a key is sent more than once.
+        match remaining.get(served) {
+            Some(next) if served > 0 && *next == continue_from => remaining = &remaining[served..],
+            _ => {
+                edge_log!(
+                    console_warn,
+                    "BulkReader",
+                    continue_from,
+                    "Durable reader asked to continue from a key it wasn't sent"
+                );
+                break;
+            }
+        }
+    }
+    entries
 }
 
 static BULK_READER_DATA_KEYWORDS: &str = "/keywords";
 static BULK_READER_DATA_DOCUMENTS: &str = "/documents";
 
-impl<'a> BulkReader<'a> {
-    pub fn new(n_shards: u32, store: &'a KvStore, durable_obj: ObjectId<'a>) -> BulkReader<'a> {
+impl<'a, S: Storage> BulkReader<'a, S> {
+    pub fn new(
+        n_shards: u32,
+        store: &'a S,
+        durable_obj: Option<ObjectId<'a>>,
+    ) -> BulkReader<'a, S> {
         BulkReader {
             n_shards,
             store,
-            durable_obj,
+            durable_obj: durable_obj.map(DurableTarget::Object),
+            trace: None,
+            codecs: CodecSet::V1,
         }
     }
 
-    async fn chunked_request<S: for<'de> Deserialize<'de> + Clone>(
+    /// Decode keyword shards with the codecs of an index other than a v1 one
+    pub fn with_codecs(mut self, codecs: CodecSet) -> Self {
+        self.codecs = codecs;
+        self
+    }
+
+    /// Fan large reads out as if to a durable reader, answering them from the store
+    #[cfg(test)]
+    pub fn with_local_durable(mut self) -> Self {
+        self.durable_obj = Some(DurableTarget::Local);
+        self
+    }
+
+    pub fn codecs(&self) -> CodecSet {
+        self.codecs
+    }
+
+    /// Record the reads made into `trace`, for search diagnostics
+    pub fn with_trace(mut self, trace: Option<&'a ReadTrace>) -> Self {
+        self.trace = trace;
+        self
+    }
+
+    /// Read `kv_keys` through the durable reader, pairing every undecoded value with
+    /// its key, see [`read_continued`]
+    async fn chunked_request<'k>(
         &self,
+        durable_obj: &DurableTarget<'a>,
         read_type: &str,
-        kv_keys: Vec<&str>,
-    ) -> Vec<S> {
+        kv_keys: Vec<&'k str>,
+    ) -> Vec<(String, Result<Vec<u8>, EncodingError>)> {
         let max_per_chunk: u32;
         let path: &str;
         if read_type == BULK_READER_DATA_KEYWORDS {
@@ -46,105 +161,343 @@ impl<'a> BulkReader<'a> {
         }
 
         // Chunk into max_per_chunk sized pieces
+        let send = |keys: Vec<&'k str>| async move {
+            self.store.count_subrequests(1);
+            let (bytes, continue_from) = match durable_obj {
+                DurableTarget::Object(durable_obj) => {
+                    Self::fetch_durable(durable_obj, path, &keys).await
+                }
+                #[cfg(test)]
+                DurableTarget::Local => {
+                    crate::durable::reader::read_keys_framed(
+                        &keys,
+                        crate::durable::reader::DEFAULT_MAX_RESPONSE_BYTES,
+                        |key| async move {
+                            let value =
+                                self.store.get(&key).await.map_err(|err| err.to_string())?;
+                            Ok(value.map(String::into_bytes))
+                        },
+                    )
+                    .await
+                }
+            };
+            if let Some(trace) = self.trace {
+                trace.durable_request(bytes.len());
+            }
+            (bytes, continue_from)
+        };
         let chunk_futures: Vec<_> = kv_keys
             .chunks(max_per_chunk as usize)
-            .map(async |chunk| {
-                let req = worker::Request::new_with_init(
-                    format!("https://do{}", path).as_str(),
-                    &RequestInit {
-                        method: Method::Post,
-                        body: Some(chunk.join(",").as_str().into()),
-                        ..Default::default()
-                    },
-                )
-                .unwrap();
-
-                self.durable_obj
-                    .get_stub()
-                    .unwrap()
-                    .fetch_with_request(req)
-                    .await
-                    .unwrap()
-                    .bytes()
-                    .await
-                    .unwrap()
-            })
+            .map(|chunk| read_continued(chunk, send))
             .collect();
 
-        let data_chunks: Vec<Vec<u8>> = join_all(chunk_futures).await;
-        data_chunks
+        join_bounded(chunk_futures)
+            .await
             .into_iter()
-            .map(move |bytes| read_length_prefixed::<S>(&bytes))
             .flatten()
             .collect()
     }
 
-    pub async fn list(&self, prefix: &str) -> Result<Vec<String>, DataStoreError> {
-        let mut response = self
-            .store
-            .list()
-            // .prefix(format!("{}:{}{}:", self.index, PREFIX_KEYWORD, keyword))
-            .prefix(prefix.into())
-            .execute()
+    /// Send `keys` to the `path` of `durable_obj`, returning its response and the key
+    /// it names to continue from
+    async fn fetch_durable(
+        durable_obj: &ObjectId<'a>,
+        path: &str,
+        keys: &[&str],
+    ) -> (Vec<u8>, Option<String>) {
+        let req = worker::Request::new_with_init(
+            format!("https://do{}", path).as_str(),
+            &RequestInit {
+                method: Method::Post,
+                body: Some(keys.join(",").as_str().into()),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let mut response = durable_obj
+            .get_stub()
+            .unwrap()
+            .fetch_with_request(req)
             .await
-            .map_err(DataStoreError::Kv)?;
-        let mut keys: Vec<String> = response.keys.iter().map(|k| k.name.to_string()).collect();
-
-        while !response.list_complete {
-            if let Some(cursor) = response.cursor {
-                response = self
-                    .store
-                    .list()
-                    .prefix(prefix.into())
-                    .cursor(cursor)
-                    .execute()
-                    .await
-                    .map_err(DataStoreError::Kv)?;
-
-                keys.extend(
-                    response
-                        .keys
-                        .iter()
-                        .map(|k| k.name.to_string())
-                        .collect::<Vec<String>>(),
-                );
-            } else {
-                break;
-            }
-        }
+            .unwrap();
+        let continue_from = match response.headers().get(CONTINUE_FROM_HEADER) {
+            Ok(Some(value)) => Some(decode_continue_from(&value)),
+            _ => None,
+        };
+        let bytes = response.bytes().await.unwrap();
+        (bytes, continue_from)
+    }
 
-        Ok(keys)
+    pub async fn list(&self, prefix: &str) -> Result<Vec<String>, DataStoreError> {
+        list_all(self.store, prefix).await
     }
 
     /// Directly query a list of keyword shard KV keys from the durable object,
     /// bypassing the 1,000 op limit through invoking extra requests to a durable object.
     pub async fn get_keyword_kv_keys(&self, kv_keys: Vec<&str>) -> Vec<KeywordShardData> {
-        let keyword_chunk_limit = get_keyword_limit(self.n_shards);
-        if kv_keys.len() < keyword_chunk_limit as usize {
-            let futures: Vec<_> = kv_keys
-                .iter()
-                .map(async |kv_key| KeywordShardData::read(kv_key, self.store).await.unwrap())
-                .collect();
-
-            join_all(futures).await
-        } else {
-            self.chunked_request::<KeywordShardData>("/keywords", kv_keys)
-                .await
+        match &self.durable_obj {
+            Some(durable_obj)
+                if keyword_durable_request_count(kv_keys.len(), self.n_shards) > 0 =>
+            {
+                self.chunked_request(durable_obj, "/keywords", kv_keys)
+                    .await
+                    .into_iter()
+                    .map(|(key, payload)| {
+                        let shard = payload.and_then(|payload| self.decode_shard(&key, &payload));
+                        (key, shard)
+                    })
+                    .filter_map(|(key, shard)| match shard {
+                        Ok(shard) => {
+                            if let Some(trace) = self.trace {
+                                // The response's size was counted with the request
+                                trace.shard_read(&key, &shard, 0);
+                            }
+                            Some(shard)
+                        }
+                        Err(EncodingError::NotFound) => None,
+                        Err(err) => {
+                            edge_log!(
+                                console_warn,
+                                "BulkReader",
+                                key,
+                                "Skipping unreadable keyword shard: {}",
+                                err
+                            );
+                            None
+                        }
+                    })
+                    .collect()
+            }
+            _ => {
+                let futures: Vec<_> = kv_keys
+                    .iter()
+                    .map(async |kv_key| {
+                        let raw = self.store.get(kv_key).await.ok()??;
+                        let shard = KeywordShardData::decode(kv_key, &raw, self.codecs).ok()?;
+                        if let Some(trace) = self.trace {
+                            trace.shard_read(kv_key, &shard, raw.len());
+                        }
+                        Some(shard)
+                    })
+                    .collect();
+
+                join_bounded(futures).await.into_iter().flatten().collect()
+            }
         }
     }
 
-    pub async fn get_documents_kv_keys(&self, kv_keys: Vec<&str>) -> Vec<Document> {
+    fn decode_shard(&self, key: &str, payload: &[u8]) -> Result<KeywordShardData, EncodingError> {
+        let raw = std::str::from_utf8(payload)
+            .map_err(|err| EncodingError::Deserialize(err.to_string()))?;
+        KeywordShardData::decode(key, raw, self.codecs)
+            .map_err(|err| EncodingError::Deserialize(err.to_string()))
+    }
+
+    /// Read every document in `kv_keys`, in the same order. Documents that are
+    /// missing or could not be read are `None`.
+    pub async fn get_documents_kv_keys(&self, kv_keys: Vec<&str>) -> Vec<Option<Document>> {
+        self.get_entries_kv_keys(kv_keys).await
+    }
+
+    pub async fn get_indexes_kv_keys(&self, kv_keys: Vec<&str>) -> Vec<Option<IndexDocument>> {
+        self.get_entries_kv_keys(kv_keys).await
+    }
+
+    /// Read JSON entries stored under whole keys, like documents and index documents,
+    /// in the order of `kv_keys`
+    async fn get_entries_kv_keys<T: KvPersistent>(&self, kv_keys: Vec<&str>) -> Vec<Option<T>> {
         let doc_chunk_limit = get_document_limit();
-        if kv_keys.len() < doc_chunk_limit as usize {
-            let futures: Vec<_> = kv_keys
-                .iter()
-                .map(async |kv_key| Document::read(kv_key, self.store).await.unwrap())
-                .collect();
+        match &self.durable_obj {
+            Some(durable_obj) if kv_keys.len() >= doc_chunk_limit as usize => {
+                let mut entries: HashMap<String, T> = HashMap::new();
+                for (key, entry) in self
+                    .chunked_request(durable_obj, BULK_READER_DATA_DOCUMENTS, kv_keys.clone())
+                    .await
+                {
+                    match entry.and_then(|payload| decode::<T>(&payload)) {
+                        Ok(entry) => {
+                            entries.insert(key, entry);
+                        }
+                        Err(EncodingError::NotFound) => {}
+                        Err(err) => {
+                            edge_log!(
+                                console_warn,
+                                "BulkReader",
+                                key,
+                                "Skipping unreadable entry: {}",
+                                err
+                            )
+                        }
+                    }
+                }
+                kv_keys
+                    .iter()
+                    .map(|kv_key| entries.remove(*kv_key))
+                    .collect()
+            }
+            _ => {
+                let futures: Vec<_> = kv_keys
+                    .iter()
+                    .map(async |kv_key| {
+                        let raw = self.store.get(kv_key).await.ok()??;
+                        if let Some(trace) = self.trace {
+                            trace.value_read(raw.len());
+                        }
+                        serde_json::from_str(&raw).ok()
+                    })
+                    .collect();
 
-            join_all(futures).await
-        } else {
-            self.chunked_request::<Document>("/documents", kv_keys)
+                join_bounded(futures).await
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use futures::executor::block_on;
+
+    use super::*;
+    use crate::data::{
+        document::shard_from_document_id, keyword::KeywordManager, keyword_shard::ShardWriteBatch,
+        storage::memory::MemoryStorage,
+    };
+
+    /// Merge each of `batches` through a keyword manager fanning its reads out as to
+    /// the durable reader, returning the requests it made
+    fn merge_durable_requests(
+        store: &MemoryStorage,
+        n_shards: u32,
+        batches: Vec<Vec<String>>,
+    ) -> usize {
+        let trace = ReadTrace::default();
+        let manager = KeywordManager::direct("idx".into(), n_shards, store)
+            .with_local_reader()
+            .with_trace(Some(&trace));
+        for keywords in batches {
+            let merged = block_on(manager.merge_many_keyword_shards(keywords)).unwrap();
+            assert!(merged.values().all(|postings| postings.len() == 25));
+        }
+        trace.finish().durable_requests
+    }
+
+    #[test]
+    fn test_batched_keywords_use_fewer_durable_requests() {
+        let n_shards = 48u32;
+        let store = MemoryStorage::default();
+        // 30 keywords, each spread over 25 shards
+        let keywords: Vec<String> = (0..30).map(|i| format!("kw{}", i)).collect();
+        let mut shards = HashSet::new();
+        let doc_ids = (0..)
+            .map(|i| format!("doc{}", i))
+            .filter(|id| shards.insert(shard_from_document_id(id.clone(), n_shards)))
+            .take(25);
+        for doc_id in doc_ids {
+            let mut batch = ShardWriteBatch::new("idx", &doc_id, n_shards);
+            for keyword in &keywords {
+                batch.upsert(keyword, 0.5);
+            }
+            for (_, result) in block_on(batch.execute(&store, 1)) {
+                result.unwrap();
+            }
+        }
+
+        let per_keyword = keywords.iter().map(|keyword| vec![keyword.clone()]);
+        let naive = merge_durable_requests(&store, n_shards, per_keyword.collect());
+        let batched = merge_durable_requests(&store, n_shards, vec![keywords]);
+
+        assert_eq!(naive, 30 * keyword_durable_request_count(25, n_shards));
+        assert_eq!(naive, 60);
+        assert_eq!(batched, keyword_durable_request_count(30 * 25, n_shards));
+        assert_eq!(batched, 38);
+    }
+
+    type Entries = Vec<(String, Result<Vec<u8>, EncodingError>)>;
+
+    /// Read `keys` from `stored` as the durable reader would, capping its responses
+    /// at `max_bytes`, returning the entries and the keys of each request sent
+    fn read_framed(
+        keys: &[&str],
+        stored: &HashMap<String, Vec<u8>>,
+        max_bytes: usize,
+    ) -> (Entries, Vec<Vec<String>>) {
+        use std::cell::RefCell;
+
+        use crate::durable::reader::read_keys_framed;
+
+        let sent: RefCell<Vec<Vec<String>>> = RefCell::new(vec![]);
+        let send = |requested: Vec<&str>| {
+            let requested: Vec<String> = requested.iter().map(|key| key.to_string()).collect();
+            sent.borrow_mut().push(requested.clone());
+            async move {
+                let requested: Vec<&str> = requested.iter().map(String::as_str).collect();
+                read_keys_framed(&requested, max_bytes, |key| async move {
+                    Ok(stored.get(&key).cloned())
+                })
                 .await
+            }
+        };
+        let entries = block_on(read_continued(keys, send));
+        (entries, sent.into_inner())
+    }
+
+    #[test]
+    fn test_capped_responses_are_continued() {
+        const MAX_BYTES: usize = 1_500;
+        // Documents that fit a few to a response, one larger than a whole response,
+        // and a key that doesn't exist
+        let mut stored: HashMap<String, Vec<u8>> = (0..10)
+            .map(|i| (format!("idx:doc:{}", i), vec![b'a' + i as u8; 400]))
+            .collect();
+        stored.insert("idx:doc:huge".into(), vec![b'z'; MAX_BYTES * 3]);
+        let mut keys: Vec<String> = (0..10).map(|i| format!("idx:doc:{}", i)).collect();
+        keys.insert(4, "idx:doc:huge".into());
+        keys.insert(7, "idx:doc:missing".into());
+        let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+
+        let (entries, sent) = read_framed(&keys, &stored, MAX_BYTES);
+
+        // Every key is answered exactly once, in order, with its whole value
+        let answered: Vec<&str> = entries.iter().map(|(key, _)| key.as_str()).collect();
+        assert_eq!(answered, keys);
+        for (key, payload) in &entries {
+            match stored.get(key) {
+                Some(value) => assert_eq!(payload.as_ref().unwrap(), value),
+                None => assert_eq!(payload, &Err(EncodingError::NotFound)),
+            }
         }
+        assert!(sent.len() > 3, "{} requests", sent.len());
+        // Each request after the first resumes where the previous response stopped
+        let served: usize = sent.windows(2).map(|w| w[0].len() - w[1].len()).sum();
+        assert_eq!(served + sent.last().unwrap().len(), keys.len());
+    }
+
+    #[test]
+    fn test_repeated_key_across_cap_is_continued() {
+        let stored: HashMap<String, Vec<u8>> = (0..3)
+            .map(|i| (format!("idx:doc:{}", i), vec![b'a' + i as u8; 400]))
+            .collect();
+        // Two values fit a response, so the second names the repeated key as the
+        // first left out, which was also the first sent
+        let keys = ["idx:doc:0", "idx:doc:1", "idx:doc:0", "idx:doc:2"];
+        let (entries, sent) = read_framed(&keys, &stored, 1_000);
+
+        let answered: Vec<&str> = entries.iter().map(|(key, _)| key.as_str()).collect();
+        assert_eq!(answered, keys);
+        for (key, payload) in &entries {
+            assert_eq!(payload.as_ref().unwrap(), &stored[key]);
+        }
+        assert_eq!(sent, vec![keys.to_vec(), keys[2..].to_vec()]);
+    }
+
+    #[test]
+    fn test_small_reads_skip_durable_reader() {
+        assert_eq!(keyword_durable_request_count(0, 48), 0);
+        assert_eq!(keyword_durable_request_count(19, 48), 0);
+        assert_eq!(keyword_durable_request_count(20, 48), 1);
+        assert_eq!(keyword_durable_request_count(41, 48), 3);
     }
 }
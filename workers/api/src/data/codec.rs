@@ -0,0 +1,77 @@
+//! How each index version stores its records. An index's `version` selects a
+//! [`CodecSet`], which the data layer passes to every read and write of the records
+//! that versions store differently:
+//!
+//! - v1 stores keyword shards with every field, naming their index, keyword and
+//!   shard, and a copy of their top postings
+//! - v2 stores keyword shards as just their timestamp and postings. The rest is
+//!   recovered from the shard's key and postings, which roughly halves small shards.
+//!
+//! Every other record, and every key layout, is the same in both versions. Each
+//! shard codec reads what the other wrote, so an index being upgraded stays readable
+//! while its shards are rewritten, which uses the codecs of the version it's moving to.
+
+use crate::data::{index::IndexDocument, DataStoreError, INDEX_VERSION_V1, INDEX_VERSION_V2};
+
+/// How keyword shard values are serialized
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShardCodec {
+    /// Every field of the shard
+    Full,
+    /// `{"ts": .., "docs": [..]}`, also reading shards stored [`ShardCodec::Full`]
+    Compact,
+}
+
+/// The codecs of one index version
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CodecSet {
+    pub version: u8,
+    pub shards: ShardCodec,
+}
+
+impl CodecSet {
+    pub const V1: CodecSet = CodecSet {
+        version: INDEX_VERSION_V1,
+        shards: ShardCodec::Full,
+    };
+    pub const V2: CodecSet = CodecSet {
+        version: INDEX_VERSION_V2,
+        shards: ShardCodec::Compact,
+    };
+    pub const LATEST: CodecSet = CodecSet::V2;
+
+    /// The codecs of `version`, refusing versions this worker doesn't know, such as
+    /// those of indexes written by a newer worker
+    pub fn for_version(version: u8) -> Result<CodecSet, DataStoreError> {
+        match version {
+            INDEX_VERSION_V1 => Ok(CodecSet::V1),
+            INDEX_VERSION_V2 => Ok(CodecSet::V2),
+            _ => Err(DataStoreError::UnsupportedVersion(version)),
+        }
+    }
+
+    /// The codecs the index's records are read and written with, which are those of
+    /// the version it's upgrading to once an upgrade started
+    pub fn for_index(index: &IndexDocument) -> Result<CodecSet, DataStoreError> {
+        CodecSet::for_version(index.codec_version())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::INDEX_VERSION_LATEST;
+
+    #[test]
+    fn test_for_version() {
+        assert_eq!(CodecSet::for_version(1).unwrap(), CodecSet::V1);
+        assert_eq!(
+            CodecSet::for_version(INDEX_VERSION_LATEST).unwrap(),
+            CodecSet::LATEST
+        );
+        let newer = CodecSet::for_version(INDEX_VERSION_LATEST + 1).unwrap_err();
+        assert!(matches!(newer, DataStoreError::UnsupportedVersion(3)));
+        assert!(newer.to_string().contains("1 to 2"));
+        assert!(CodecSet::for_version(0).is_err());
+    }
+}
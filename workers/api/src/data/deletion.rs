@@ -0,0 +1,372 @@
+//! Deleting a document in two phases, so a delete that stops part way leaves neither
+//! postings naming a deleted document nor a searchable document without postings.
+//! A delete first writes a `{index}:deleting:{id}` marker, then strips the
+//! document's postings from its keyword shards, and only then deletes the document
+//! and the marker. A shard that can't be written keeps both, and is reported in the
+//! [`DeleteOutcome`] rather than as an error.
+//!
+//! From the moment its marker is written, searches fetching the document and the
+//! keyword endpoint treat it as deleted. A delete that stopped part way is finished
+//! by repeating it, or by fsck once its marker is older than
+//! [`DELETION_RESUME_AFTER_MS`].
+
+use std::collections::HashSet;
+
+use serde::{Deserialize, Serialize};
+
+use crate::data::{
+    codec::CodecSet,
+    document::{read_index_document, Document},
+    keyword_shard::{get_n_shards, ShardWriteBatch},
+    storage::{list_up_to, Storage},
+    DataStoreError, KvEntry, KvPersistent, PREFIX_DELETING,
+};
+
+/// How old a marker must be before fsck finishes its deletion, leaving deletes
+/// still under way alone
+pub const DELETION_RESUME_AFTER_MS: u64 = 60_000;
+
+/// The most deletions one fsck call finishes, each costing two KV operations per
+/// keyword of its document
+pub const MAX_RESUMED_DELETIONS: usize = 5;
+
+/// The most markers a search reads, one listing page
+pub const MAX_PENDING_DELETIONS: usize = 1000;
+
+pub fn deletion_marker_prefix(index: &str) -> String {
+    format!("{}:{}", index, PREFIX_DELETING)
+}
+
+pub fn deletion_marker_key(index: &str, doc_id: &str) -> String {
+    format!("{}{}", deletion_marker_prefix(index), doc_id)
+}
+
+/// A delete under way
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DeletionMarker {
+    pub index: String,
+    pub doc_id: String,
+    /// When the delete started, in milliseconds since the epoch
+    pub started: u64,
+}
+
+impl KvEntry for DeletionMarker {
+    type Key = String;
+
+    fn get_kv_key(&self) -> String {
+        deletion_marker_key(&self.index, &self.doc_id)
+    }
+}
+
+impl KvPersistent for DeletionMarker {}
+
+/// How a document's postings are stripped
+#[derive(Debug, Clone, Copy)]
+pub struct DeleteOptions {
+    pub n_shards: u32,
+    pub codecs: CodecSet,
+    pub now: u64,
+}
+
+impl DeleteOptions {
+    /// The options of the index's shards, read from its index document
+    pub async fn for_index<S: Storage>(
+        store: &S,
+        env: &worker::Env,
+        index: &str,
+        now: u64,
+    ) -> Result<DeleteOptions, DataStoreError> {
+        let mut options = DeleteOptions {
+            n_shards: get_n_shards(env),
+            codecs: CodecSet::V1,
+            now,
+        };
+        if let Some(index) = read_index_document(store, index).await? {
+            options.n_shards = index.shard_count(options.n_shards);
+            options.codecs = CodecSet::for_index(&index)?;
+        }
+        Ok(options)
+    }
+}
+
+/// What a delete did to the document's postings
+#[derive(Debug)]
+pub struct DeleteOutcome {
+    /// Keywords whose posting for the document is gone
+    pub keywords_removed: usize,
+    /// Keyword shards read to strip the document's postings, one per keyword
+    pub shards_touched: usize,
+    /// Keyword shards that could not be written, even after a retry, by keyword.
+    /// The document and its marker are kept until the delete is finished.
+    pub failed_shards: Vec<(String, DataStoreError)>,
+}
+
+impl DeleteOutcome {
+    pub fn is_complete(&self) -> bool {
+        self.failed_shards.is_empty()
+    }
+}
+
+/// Delete `document` from KV along with its postings. On an error or a failed
+/// shard the marker is left behind, and the document is treated as deleted until
+/// the delete is finished.
+pub async fn delete_document<S: Storage>(
+    store: &S,
+    document: &Document,
+    options: &DeleteOptions,
+) -> Result<DeleteOutcome, DataStoreError> {
+    let mut marker = DeletionMarker {
+        index: document.index.clone(),
+        doc_id: document.get_uuid(),
+        started: options.now,
+    };
+    marker.write(store).await?;
+    finish_deletion(store, document, options).await
+}
+
+/// Strip the document's postings, then delete it and its marker once every shard
+/// was written
+async fn finish_deletion<S: Storage>(
+    store: &S,
+    document: &Document,
+    options: &DeleteOptions,
+) -> Result<DeleteOutcome, DataStoreError> {
+    let doc_id = document.get_uuid();
+    let mut batch = ShardWriteBatch::new(&document.index, &doc_id, options.n_shards)
+        .with_codecs(options.codecs);
+    for (keyword, _) in document.keywords.iter().flatten() {
+        batch.remove(keyword);
+    }
+    let results = match batch.is_empty() {
+        true => vec![],
+        false => batch.execute_with_retry(store, options.now).await,
+    };
+    let shards_touched = results.len();
+    let failed_shards: Vec<(String, DataStoreError)> = results
+        .into_iter()
+        .filter_map(|(keyword, result)| result.err().map(|err| (keyword, err)))
+        .collect();
+    let outcome = DeleteOutcome {
+        keywords_removed: shards_touched - failed_shards.len(),
+        shards_touched,
+        failed_shards,
+    };
+    if outcome.is_complete() {
+        document.delete(store).await?;
+        store
+            .delete(&deletion_marker_key(&document.index, &doc_id))
+            .await?;
+    }
+    Ok(outcome)
+}
+
+/// The IDs of the index's documents being deleted
+pub async fn pending_deletions<S: Storage>(
+    store: &S,
+    index: &str,
+) -> Result<HashSet<String>, DataStoreError> {
+    let prefix = deletion_marker_prefix(index);
+    let keys = list_up_to(store, &prefix, MAX_PENDING_DELETIONS).await?;
+    Ok(keys
+        .iter()
+        .filter_map(|key| key.strip_prefix(prefix.as_str()))
+        .map(str::to_string)
+        .collect())
+}
+
+/// The deletions of the index that stopped part way, at most [`MAX_RESUMED_DELETIONS`],
+/// finishing them with `repair`. A marker whose document is gone only needs deleting,
+/// since a document is deleted after its postings.
+pub async fn resume_deletions<S: Storage>(
+    store: &S,
+    index: &str,
+    options: &DeleteOptions,
+    repair: bool,
+) -> Result<Vec<String>, DataStoreError> {
+    let mut stale = vec![];
+    for doc_id in pending_deletions(store, index).await? {
+        if stale.len() >= MAX_RESUMED_DELETIONS {
+            break;
+        }
+        let marker = match DeletionMarker::read(&deletion_marker_key(index, &doc_id), store).await {
+            Ok(marker) => marker,
+            Err(DataStoreError::NotFound(_)) => continue,
+            Err(err) => return Err(err),
+        };
+        if options.now.saturating_sub(marker.started) < DELETION_RESUME_AFTER_MS {
+            continue;
+        }
+        if repair {
+            match Document::from_remote(store, index, doc_id.clone()).await {
+                Ok(document) => {
+                    let outcome = finish_deletion(store, &document, options).await?;
+                    if let Some((_, err)) = outcome.failed_shards.into_iter().next() {
+                        return Err(err);
+                    }
+                }
+                Err(DataStoreError::NotFound(_)) => store.delete(&marker.get_kv_key()).await?,
+                Err(err) => return Err(err),
+            }
+        }
+        stale.push(doc_id);
+    }
+    stale.sort();
+    Ok(stale)
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+
+    use super::*;
+    use crate::data::{
+        document::testing::index_text, keyword::KeywordManager,
+        keyword_shard::keyword_shard_prefix, storage::memory::MemoryStorage, DEFAULT_N_SHARDS,
+    };
+
+    const STARTED: u64 = 1_000_000;
+
+    fn options(now: u64) -> DeleteOptions {
+        DeleteOptions {
+            n_shards: DEFAULT_N_SHARDS,
+            codecs: CodecSet::V1,
+            now,
+        }
+    }
+
+    /// The documents holding a posting for any of `document`'s keywords
+    fn posted(store: &MemoryStorage, document: &Document) -> Vec<String> {
+        let manager = KeywordManager::direct(document.index.clone(), DEFAULT_N_SHARDS, store);
+        let mut docs: Vec<String> = document
+            .keywords
+            .iter()
+            .flatten()
+            .flat_map(|(keyword, _)| {
+                let merged = block_on(manager.merge_keyword_shards(keyword.clone())).unwrap();
+                merged.into_iter().map(|(doc_id, _)| doc_id)
+            })
+            .collect();
+        docs.sort();
+        docs.dedup();
+        docs
+    }
+
+    fn indexed(store: &MemoryStorage, index: &str) -> (Document, Document) {
+        let gone = index_text(
+            store,
+            index,
+            "gone",
+            "Ocean waves crash onto the sandy beach.",
+        );
+        let kept = index_text(
+            store,
+            index,
+            "kept",
+            "Ocean waves roll across the sandy shore.",
+        );
+        assert_eq!(posted(store, &gone), vec!["gone", "kept"]);
+        (gone, kept)
+    }
+
+    /// Fail writing one of the document's shards, even when retried
+    fn fail_stripping(store: &MemoryStorage, document: &Document) {
+        let keyword = &document.keywords.as_ref().unwrap()[0].0;
+        store.fail_puts(&keyword_shard_prefix(&document.index, keyword), 2);
+    }
+
+    /// Resume the index's stalled deletions once they're old enough
+    fn resume(store: &MemoryStorage, index: &str) -> Vec<String> {
+        let early = block_on(resume_deletions(store, index, &options(STARTED + 1), true));
+        assert!(early.unwrap().is_empty());
+        let later = options(STARTED + DELETION_RESUME_AFTER_MS);
+        block_on(resume_deletions(store, index, &later, true)).unwrap()
+    }
+
+    fn assert_deleted(store: &MemoryStorage, index: &str, kept: &Document) {
+        assert!(block_on(Document::from_remote(store, index, "gone".into())).is_err());
+        assert_eq!(posted(store, kept), vec!["kept"]);
+        assert!(block_on(pending_deletions(store, index))
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_delete_strips_postings() {
+        let store = MemoryStorage::default();
+        let (gone, kept) = indexed(&store, "delete-clean");
+        let outcome = block_on(delete_document(&store, &gone, &options(STARTED))).unwrap();
+        let keywords = gone.keywords.as_ref().unwrap().len();
+        assert_eq!(
+            (outcome.keywords_removed, outcome.shards_touched),
+            (keywords, keywords)
+        );
+        assert!(outcome.is_complete());
+        assert_deleted(&store, "delete-clean", &kept);
+        assert!(!store.keys().iter().any(|key| key.contains(":deleting:")));
+    }
+
+    #[test]
+    fn test_crash_while_stripping_postings_resumes() {
+        let store = MemoryStorage::default();
+        let (gone, kept) = indexed(&store, "delete-strip");
+        fail_stripping(&store, &gone);
+        let outcome = block_on(delete_document(&store, &gone, &options(STARTED))).unwrap();
+        let failed: Vec<&str> = outcome
+            .failed_shards
+            .iter()
+            .map(|(keyword, _)| keyword.as_str())
+            .collect();
+        assert_eq!(failed, vec![gone.keywords.as_ref().unwrap()[0].0.as_str()]);
+        assert_eq!(outcome.keywords_removed, outcome.shards_touched - 1);
+
+        // The document is still stored, but pending deletion
+        assert!(block_on(Document::from_remote(&store, "delete-strip", "gone".into())).is_ok());
+        let pending = block_on(pending_deletions(&store, "delete-strip")).unwrap();
+        assert!(pending.contains("gone"));
+
+        assert_eq!(resume(&store, "delete-strip"), vec!["gone"]);
+        assert_deleted(&store, "delete-strip", &kept);
+    }
+
+    #[test]
+    fn test_crash_before_deleting_document_resumes() {
+        let store = MemoryStorage::default();
+        let (gone, kept) = indexed(&store, "delete-doc");
+        store.fail_deletes("delete-doc:document:gone", 1);
+        assert!(block_on(delete_document(&store, &gone, &options(STARTED))).is_err());
+        assert_eq!(posted(&store, &kept), vec!["kept"]);
+
+        assert_eq!(resume(&store, "delete-doc"), vec!["gone"]);
+        assert_deleted(&store, "delete-doc", &kept);
+    }
+
+    #[test]
+    fn test_crash_before_deleting_marker_resumes() {
+        let store = MemoryStorage::default();
+        let (gone, kept) = indexed(&store, "delete-marker");
+        store.fail_deletes("delete-marker:deleting:", 1);
+        assert!(block_on(delete_document(&store, &gone, &options(STARTED))).is_err());
+
+        let gets = store.counts().gets;
+        assert_eq!(resume(&store, "delete-marker"), vec!["gone"]);
+        // Only the marker and the missing document were read, no shards
+        assert_eq!(store.counts().gets - gets, 3);
+        assert_deleted(&store, "delete-marker", &kept);
+    }
+
+    #[test]
+    fn test_report_without_repair() {
+        let store = MemoryStorage::default();
+        let (gone, _) = indexed(&store, "delete-report");
+        fail_stripping(&store, &gone);
+        let outcome = block_on(delete_document(&store, &gone, &options(STARTED))).unwrap();
+        assert!(!outcome.is_complete());
+
+        let later = options(STARTED + DELETION_RESUME_AFTER_MS);
+        let stale = block_on(resume_deletions(&store, "delete-report", &later, false));
+        assert_eq!(stale.unwrap(), vec!["gone"]);
+        assert!(block_on(pending_deletions(&store, "delete-report"))
+            .unwrap()
+            .contains("gone"));
+    }
+}
@@ -1,24 +1,36 @@
-use crate::data::keyword_shard::KeywordShardData;
+use crate::data::codec::CodecSet;
+use crate::data::index::{get_index_key, IndexDocument};
+use crate::data::keyword_shard::{get_n_shards, scores_equal, ShardWriteBatch};
+use crate::data::stoplist::StopList;
+use crate::data::storage::Storage;
+use crate::data::trend::KeywordDeltas;
 use crate::data::DocumentRef;
 use crate::data::DocumentScore;
 use crate::data::IndexName;
-use crate::data::DEFAULT_YAKE_MIN_CHARS;
-use crate::data::DEFAULT_YAKE_NGRAMS;
-use crate::data::PREFIX_DOCUMENT;
+use crate::data::{
+    DEFAULT_LANG_CONFIDENCE_MIN, DEFAULT_MAX_DOCUMENT_BYTES, DEFAULT_N_SHARDS,
+    DEFAULT_R2_OFFLOAD_BYTES, ENV_VAR_LANG_CONFIDENCE_MIN, ENV_VAR_LANG_DETECT_LANGUAGES,
+    ENV_VAR_MAX_DOCUMENT_BYTES, ENV_VAR_R2_OFFLOAD_BYTES, PREFIX_DOCUMENT,
+};
 use crate::edge_log;
-use crate::lexer::document::DocumentLexer;
-use futures::future::join_all;
-use lingua::IsoCode639_1;
+use crate::lexer::{
+    document::{
+        boost_title, default_yake_config, get_yake_config_from_env, merge_title_keywords,
+        DocumentLexer, DEFAULT_TITLE_BOOST,
+    },
+    extractor::Extractor,
+};
+use crate::util::env::parse_env_usize;
+use crate::util::kv::get_body_bucket;
+use crate::util::time::{now_ms, worker_clock, SharedClock};
+use lingua::{IsoCode639_1, LanguageDetector, LanguageDetectorBuilder};
 use nanoid::nanoid;
-use once_cell::sync::Lazy;
-use serde::de::Error;
-use serde::{Deserialize, Serialize};
+use once_cell::sync::OnceCell;
+use serde::{ser::SerializeStruct, Deserialize, Deserializer, Serialize, Serializer};
 use sha2::Digest;
 use sha2::Sha256;
-use std::collections::HashSet;
-use worker::kv::KvStore;
+use std::{collections::HashMap, str::FromStr};
 use worker::Env;
-use yake_rust::{Config, StopWords};
 
 use crate::data::{DataStoreError, KvEntry, KvPersistent};
 
@@ -38,7 +50,7 @@ pub fn shard_from_document_id(doc_id: String, num_shards: u32) -> u32 {
 impl KvEntry for Document {
     type Key = DocumentRef;
     fn get_kv_key(&self) -> DocumentRef {
-        return document_kv_key(&self.index, &self.uuid);
+        document_kv_key(&self.index, &self.uuid)
     }
 }
 
@@ -52,234 +64,1713 @@ pub struct Document {
     pub revision: u32,
     #[serde(rename = "lang", alias = "lang")]
     pub lang: Option<IsoCode639_1>,
+    /// How sure detection was of `lang`, when it wasn't given. Below the
+    /// `LANG_CONFIDENCE_MIN` threshold, `lang` is the index's fallback language.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lang_confidence: Option<f64>,
     #[serde(rename = "body", alias = "document_body")]
     pub document_body: Option<String>,
+    /// Set with a `format=envelope` write, and indexed apart from the body
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
     #[serde(rename = "keywords", alias = "keywords")]
-    pub keywords: Option<Vec<(String, f64)>>,
+    pub keywords: Option<Vec<(String, KeywordScore)>>,
+    /// The R2 object holding the body when it was too large to keep in KV
+    #[serde(rename = "body_ref", default, skip_serializing_if = "Option::is_none")]
+    pub body_ref: Option<String>,
+    /// Milliseconds since the epoch when the document was added. Documents written
+    /// before timestamps were kept have neither.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<u64>,
+    /// Milliseconds since the epoch of the document's latest revision
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub updated_at: Option<u64>,
+    /// The [`content_fingerprint`] of the latest revision. Documents written before
+    /// fingerprints were kept have none until they're next rewritten, and neither do
+    /// those whose latest write failed to update some of their shards.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fingerprint: Option<String>,
+    /// Set when control characters or invalid UTF-8 were cleaned from the body of
+    /// the latest revision as it arrived
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub sanitized: bool,
 }
 
-impl KvPersistent for Document {
-    async fn read(key: &str, store: &KvStore) -> Result<Document, DataStoreError> {
-        let result = store
-            .get(&key)
-            .json::<Document>()
-            .await
-            .map_err(DataStoreError::Kv)?
-            .ok_or_else(|| DataStoreError::NotFound(key.to_string()));
-        result
+impl KvPersistent for Document {}
+
+/// The body of a `format=envelope` write: a text body and an optional title, whose
+/// keywords are picked on their own and boosted by the index's `title_boost`. On an
+/// update, a field left out keeps its stored value, so `{"title": ...}` retitles a
+/// document without resending its body. An empty title removes it.
+#[derive(Deserialize, Debug, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct DocumentEnvelope {
+    #[serde(default)]
+    pub title: Option<String>,
+    #[serde(default)]
+    pub body: Option<String>,
+}
+
+impl DocumentEnvelope {
+    pub fn parse(body: &str) -> Result<DocumentEnvelope, DataStoreError> {
+        serde_json::from_str(body).map_err(|err| {
+            DataStoreError::InvalidFormat(format!("document body is not a valid envelope: {}", err))
+        })
+    }
+}
+
+/// A hash of everything a revision's keywords are extracted from: its body, title,
+/// format and language, and the index's extraction settings. Writing a body whose
+/// fingerprint matches the stored one would store the same keywords and postings.
+pub fn content_fingerprint(
+    body: &str,
+    title: Option<&str>,
+    format: &str,
+    lang: &str,
+    options: &IndexingOptions,
+) -> String {
+    let settings = format!(
+        "{:?}:{}:{}:{}",
+        options.extractor,
+        options.position_boost.to_bits(),
+        options.yake.ngrams,
+        options.yake.minimum_chars
+    );
+    let stoplist = serde_json::to_string(&options.stoplist).unwrap_or_default();
+    let mut hasher = Sha256::new();
+    // Each part is prefixed by its length, so no two sets of parts hash alike
+    for part in [body, format, lang, settings.as_str(), stoplist.as_str()] {
+        hasher.update((part.len() as u64).to_be_bytes());
+        hasher.update(part.as_bytes());
+    }
+    // With no floor, documents hash as they did before floors were kept
+    if options.min_keyword_score > 0.0 {
+        let floor = options.min_keyword_score.to_bits().to_string();
+        hasher.update((floor.len() as u64).to_be_bytes());
+        hasher.update(floor.as_bytes());
+    }
+    // Untitled documents hash as they did before titles were kept
+    if let Some(title) = title {
+        let boost = options.title_boost.to_bits().to_string();
+        for part in [title, boost.as_str()] {
+            hasher.update((part.len() as u64).to_be_bytes());
+            hasher.update(part.as_bytes());
+        }
+    }
+    hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// A document keyword's stored score: `score` is what its postings carry, and `raw`
+/// is YAKE's score before the index's positional boost. Unboosted scores serialize
+/// as a plain number, which is also how documents written before the boost store
+/// them, so both shapes are read back.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KeywordScore {
+    pub score: f64,
+    pub raw: f64,
+}
+
+impl From<f64> for KeywordScore {
+    fn from(raw: f64) -> Self {
+        KeywordScore { score: raw, raw }
+    }
+}
+
+impl Serialize for KeywordScore {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if scores_equal(self.score, self.raw) {
+            return serializer.serialize_f64(self.score);
+        }
+        let mut state = serializer.serialize_struct("KeywordScore", 2)?;
+        state.serialize_field("score", &self.score)?;
+        state.serialize_field("raw", &self.raw)?;
+        state.end()
     }
 }
 
-static KEYWORD_DETECTOR: Lazy<lingua::LanguageDetector> =
-    Lazy::new(|| lingua::LanguageDetectorBuilder::from_all_languages().build());
+impl<'de> Deserialize<'de> for KeywordScore {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Stored {
+            Plain(f64),
+            Boosted { score: f64, raw: f64 },
+        }
+        Ok(match Stored::deserialize(deserializer)? {
+            Stored::Plain(raw) => raw.into(),
+            Stored::Boosted { score, raw } => KeywordScore { score, raw },
+        })
+    }
+}
+
+/// The keyword changes between two revisions of a document
+#[derive(Debug, Default, PartialEq)]
+pub struct KeywordDiff {
+    pub added: Vec<(String, f64)>,
+    pub removed: Vec<String>,
+    pub rescored: Vec<(String, f64)>,
+    pub unchanged: usize,
+}
+
+impl KeywordDiff {
+    /// Compare the scores postings carry, so a changed raw score alone rewrites nothing
+    pub fn between(old: &[(String, KeywordScore)], new: &[(String, KeywordScore)]) -> KeywordDiff {
+        let old_scores: HashMap<&str, f64> = old
+            .iter()
+            .map(|(kw, score)| (kw.as_str(), score.score))
+            .collect();
+        let new_scores: HashMap<&str, f64> = new
+            .iter()
+            .map(|(kw, score)| (kw.as_str(), score.score))
+            .collect();
+
+        let mut diff = KeywordDiff::default();
+        for (kw, score) in new.iter() {
+            match old_scores.get(kw.as_str()) {
+                None => diff.added.push((kw.clone(), score.score)),
+                Some(old_score) if scores_equal(*old_score, score.score) => diff.unchanged += 1,
+                Some(_) => diff.rescored.push((kw.clone(), score.score)),
+            }
+        }
+        for (kw, _) in old.iter() {
+            if !new_scores.contains_key(kw.as_str()) {
+                diff.removed.push(kw.clone());
+            }
+        }
+        diff
+    }
+
+    /// Queue the shard changes for this diff; unchanged keywords queue nothing
+    pub fn queue(&self, batch: &mut ShardWriteBatch) {
+        for kw in self.removed.iter() {
+            batch.remove(kw);
+        }
+        for (kw, score) in self.added.iter().chain(self.rescored.iter()) {
+            batch.upsert(kw, *score);
+        }
+    }
+}
+
+fn get_f64_from_env(env: &Env, name: &str, default: f64) -> f64 {
+    env.var(name)
+        .ok()
+        .and_then(|v| v.to_string().parse::<f64>().ok())
+        .unwrap_or(default)
+}
+
+/// The result of writing a document: its new revision, and which of its keyword
+/// shards were updated. The document itself is always written before its shards,
+/// so a keyword can fail while the document is still stored.
+#[derive(Debug)]
+pub struct UpdateOutcome {
+    pub revision: u32,
+    /// Keywords the document didn't have before this revision
+    pub keywords_added: usize,
+    /// Keywords the previous revision had that this one doesn't
+    pub keywords_removed: usize,
+    /// The document's keywords whose postings are in place
+    pub indexed_keywords: Vec<String>,
+    /// Keywords whose shard could not be written, even after a retry. The handlers
+    /// report them to the index's journal, see [`crate::data::shard_repair`].
+    pub failed_keywords: Vec<(String, DataStoreError)>,
+    /// Whether the body matched the stored revision's fingerprint, so nothing was
+    /// extracted or written
+    pub unchanged: bool,
+    /// Keywords extracted but not indexed for scoring below the index's
+    /// `min_keyword_score`
+    pub keywords_dropped_below_floor: usize,
+    /// +1 for each keyword added and -1 for each removed, leaving out those whose
+    /// shard failed, for the keyword trends
+    pub keyword_deltas: KeywordDeltas,
+}
+
+impl UpdateOutcome {
+    pub fn is_complete(&self) -> bool {
+        self.failed_keywords.is_empty()
+    }
+}
+
+/// The largest document body accepted, in bytes
+pub fn get_max_document_bytes(env: &Env) -> usize {
+    parse_env_usize(
+        env,
+        ENV_VAR_MAX_DOCUMENT_BYTES,
+        DEFAULT_MAX_DOCUMENT_BYTES,
+        1..=usize::MAX,
+    )
+}
+
+/// The env-driven settings that control how a document is indexed
+#[derive(Clone)]
+pub struct IndexingOptions {
+    pub n_shards: u32,
+    pub yake: yake_rust::Config,
+    /// Bodies larger than this are stored in R2 rather than KV, when a bucket is bound
+    pub offload_bytes: usize,
+    /// Keywords dropped before any keyword shards are written
+    pub stoplist: StopList,
+    /// Detected languages less certain than this are replaced by `default_lang`
+    pub lang_confidence_min: f64,
+    /// The index's fallback language, for bodies too short or ambiguous to detect
+    pub default_lang: IsoCode639_1,
+    /// How strongly keywords first appearing late in the body are decayed, `k` in
+    /// [`crate::lexer::document::position_decay`]; 0 leaves YAKE's scores as they are
+    pub position_boost: f64,
+    /// What the scores of keywords picked from a document's title are multiplied by
+    pub title_boost: f64,
+    /// Keywords scoring below this are dropped before any keyword shards are written
+    pub min_keyword_score: f64,
+    /// What picks the body's keywords
+    pub extractor: Extractor,
+    /// The languages the detector chooses between, every language when empty
+    pub detect_languages: Vec<IsoCode639_1>,
+    /// Read once per update to stamp the document and its shards alike
+    pub clock: SharedClock,
+    /// What the index's keyword shards are written with
+    pub codecs: CodecSet,
+}
+
+impl IndexingOptions {
+    pub fn from_env(env: &Env) -> IndexingOptions {
+        IndexingOptions {
+            n_shards: get_n_shards(env),
+            yake: get_yake_config_from_env(env),
+            offload_bytes: parse_env_usize(
+                env,
+                ENV_VAR_R2_OFFLOAD_BYTES,
+                DEFAULT_R2_OFFLOAD_BYTES,
+                0..=usize::MAX,
+            ),
+            stoplist: StopList::default(),
+            lang_confidence_min: get_f64_from_env(
+                env,
+                ENV_VAR_LANG_CONFIDENCE_MIN,
+                DEFAULT_LANG_CONFIDENCE_MIN,
+            ),
+            default_lang: IsoCode639_1::EN,
+            position_boost: 0.0,
+            title_boost: DEFAULT_TITLE_BOOST,
+            min_keyword_score: 0.0,
+            extractor: Extractor::default(),
+            detect_languages: get_detect_languages(env),
+            clock: worker_clock(),
+            codecs: CodecSet::V1,
+        }
+    }
+}
+
+impl Default for IndexingOptions {
+    fn default() -> Self {
+        IndexingOptions {
+            n_shards: DEFAULT_N_SHARDS,
+            yake: default_yake_config(),
+            offload_bytes: DEFAULT_R2_OFFLOAD_BYTES,
+            stoplist: StopList::default(),
+            lang_confidence_min: DEFAULT_LANG_CONFIDENCE_MIN,
+            default_lang: IsoCode639_1::EN,
+            position_boost: 0.0,
+            title_boost: DEFAULT_TITLE_BOOST,
+            min_keyword_score: 0.0,
+            extractor: Extractor::default(),
+            detect_languages: vec![],
+            clock: worker_clock(),
+            codecs: CodecSet::V1,
+        }
+    }
+}
+
+/// Built by the first document that needs detection, or at startup with
+/// `PRELOAD_DETECTOR=true`. Every request in an isolate sees the same env, so the
+/// first caller's `LANG_DETECT_LANGUAGES` holds for the isolate's lifetime.
+static KEYWORD_DETECTOR: OnceCell<LanguageDetector> = OnceCell::new();
+
+#[cfg(test)]
+thread_local! {
+    /// How many times this thread asked for the detector, which tests use to check
+    /// that documents with a given language never load it
+    static DETECTOR_REQUESTS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+/// The language detector, built from `languages` (every language when empty) if
+/// this is the first time it is needed
+fn keyword_detector(languages: &[IsoCode639_1]) -> &'static LanguageDetector {
+    #[cfg(test)]
+    DETECTOR_REQUESTS.with(|requests| requests.set(requests.get() + 1));
+    KEYWORD_DETECTOR.get_or_init(|| {
+        let mut builder = match languages.is_empty() {
+            true => LanguageDetectorBuilder::from_all_languages(),
+            false => LanguageDetectorBuilder::from_iso_codes_639_1(languages),
+        };
+        builder.build()
+    })
+}
+
+/// Build the language detector now rather than on the first document that needs it,
+/// so no request pays for loading its models
+pub fn preload_detector(env: &Env) {
+    let started = now_ms();
+    keyword_detector(&get_detect_languages(env));
+    edge_log!(
+        console_log,
+        "Documents",
+        "",
+        "Preloaded the language detector in {}ms",
+        (now_ms().saturating_sub(started))
+    );
+}
+
+/// The languages named by a comma-separated `LANG_DETECT_LANGUAGES`, skipping codes
+/// that aren't ISO 639-1 or whose models aren't built in. Empty means every language.
+pub fn parse_detect_languages(value: &str) -> Vec<IsoCode639_1> {
+    let mut languages = vec![];
+    for code in value
+        .split(',')
+        .map(str::trim)
+        .filter(|code| !code.is_empty())
+    {
+        match IsoCode639_1::from_str(&code.to_lowercase()) {
+            Ok(lang) if !languages.contains(&lang) => languages.push(lang),
+            Ok(_) => {}
+            Err(_) => edge_log!(
+                console_warn,
+                "Documents",
+                "",
+                "Ignoring unknown language '{}' in LANG_DETECT_LANGUAGES",
+                code
+            ),
+        }
+    }
+    languages
+}
+
+fn get_detect_languages(env: &Env) -> Vec<IsoCode639_1> {
+    env.var(ENV_VAR_LANG_DETECT_LANGUAGES)
+        .map(|value| parse_detect_languages(&value.to_string()))
+        .unwrap_or_default()
+}
+
+/// When a document's language is detected from its body
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LangDetection {
+    /// Only when the document has no language yet
+    WhenMissing,
+    /// Never; a document without a language gets the index's fallback language,
+    /// and the detector isn't loaded at all
+    Never,
+}
+
+/// Bodies shorter than this, in characters, are given the fallback language without
+/// running detection, which is little better than a guess on so little text
+const MIN_DETECTION_CHARS: usize = 20;
+
+/// The index's document, or `None` when it hasn't been created
+pub(crate) async fn read_index_document<S: Storage>(
+    store: &S,
+    index: &str,
+) -> Result<Option<IndexDocument>, DataStoreError> {
+    match IndexDocument::read(&get_index_key(index), store).await {
+        Ok(index) => Ok(Some(index)),
+        Err(DataStoreError::NotFound(_)) => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// Keep a detected language if detection was confident enough, otherwise fall back
+/// to `default_lang`. Returns the language and the detector's confidence, which is 0
+/// when detection was skipped.
+fn choose_language(
+    detected: Option<(IsoCode639_1, f64)>,
+    options: &IndexingOptions,
+) -> (IsoCode639_1, f64) {
+    match detected {
+        Some((lang, confidence)) if confidence >= options.lang_confidence_min => (lang, confidence),
+        Some((_, confidence)) => (options.default_lang, confidence),
+        None => (options.default_lang, 0.0),
+    }
+}
 
 impl Document {
     const MAX_CUSTOM_ID_LENGTH: usize = 64;
     const MIN_CUSTOM_ID_LENGTH: usize = 1;
 
+    /// The characters of generated IDs, which always pass [`Self::is_valid_id`]
+    const GENERATED_ID_ALPHABET: [char; 62] = [
+        '0', '1', '2', '3', '4', '5', '6', '7', '8', '9', 'a', 'b', 'c', 'd', 'e', 'f', 'g', 'h',
+        'i', 'j', 'k', 'l', 'm', 'n', 'o', 'p', 'q', 'r', 's', 't', 'u', 'v', 'w', 'x', 'y', 'z',
+        'A', 'B', 'C', 'D', 'E', 'F', 'G', 'H', 'I', 'J', 'K', 'L', 'M', 'N', 'O', 'P', 'Q', 'R',
+        'S', 'T', 'U', 'V', 'W', 'X', 'Y', 'Z',
+    ];
+
+    pub const INVALID_ID_ERROR: &'static str =
+        "Invalid document ID format. Must match [a-zA-Z0-9-_]+, at most 64 characters, not starting with _";
+
     pub fn get_uuid(&self) -> String {
-        return self.uuid.clone();
+        self.uuid.clone()
     }
 
-    /// Determine if the provided ID is a valid (custom)
-    /// document identifier
+    /// Determine if the provided ID is a valid document identifier. Custom and
+    /// generated IDs share this grammar, and a leading `_` is reserved for the
+    /// internal keys stored under a document's key.
     pub fn is_valid_id(id: &str) -> bool {
         id.len() <= Self::MAX_CUSTOM_ID_LENGTH
             && id.len() >= Self::MIN_CUSTOM_ID_LENGTH
+            && !id.starts_with('_')
             && id
                 .chars()
                 .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
     }
 
     pub fn new(index: &str) -> Document {
-        let uuid: DocumentRef = nanoid!(16);
-        return Document {
-            uuid: uuid,
-            index: index.to_string(),
-            revision: 0u32,
-            lang: None,
-            keywords: None,
-            document_body: None,
-        };
+        let uuid: DocumentRef = nanoid!(16, &Self::GENERATED_ID_ALPHABET);
+        Document::new_with_id(index, &uuid)
     }
 
     pub fn new_with_id(index: &str, id: &str) -> Document {
-        return Document {
+        let now = now_ms();
+        Document {
             uuid: id.to_string(),
             index: index.to_string(),
             revision: 0u32,
             lang: None,
+            lang_confidence: None,
             keywords: None,
             document_body: None,
-        };
+            title: None,
+            body_ref: None,
+            created_at: Some(now),
+            updated_at: Some(now),
+            fingerprint: None,
+            sanitized: false,
+        }
     }
 
-    pub async fn from_remote(
-        store: &KvStore,
+    pub async fn from_remote<S: Storage>(
+        store: &S,
         index: &str,
         uuid: DocumentRef,
     ) -> Result<Document, DataStoreError> {
-        let mut document = Document::read(&document_kv_key(&index, &uuid), &store).await?;
+        let mut document = Document::read(&document_kv_key(index, &uuid), store).await?;
         document.index = index.to_string();
         Ok(document)
     }
 
     pub fn set_language(&mut self, lang: IsoCode639_1) {
         self.lang = Some(lang);
+        self.lang_confidence = None;
     }
 
-    fn detect_language(content: &String) -> Option<IsoCode639_1> {
-        let lang = KEYWORD_DETECTOR.detect_language_of(content)?;
-        Some(lang.iso_code_639_1())
+    /// The most likely language of `content` and the detector's confidence in it,
+    /// or `None` when the body is too short to detect
+    fn detect_language(content: &str, languages: &[IsoCode639_1]) -> Option<(IsoCode639_1, f64)> {
+        if content.trim().chars().count() < MIN_DETECTION_CHARS {
+            return None;
+        }
+        let (lang, confidence) = keyword_detector(languages)
+            .compute_language_confidence_values(content)
+            .into_iter()
+            .next()?;
+        Some((lang.iso_code_639_1(), confidence))
     }
 
-    pub async fn update(
+    pub async fn update<S: Storage>(
         &mut self,
-        store: &KvStore,
+        store: &S,
         env: &Env,
         document_body: String,
         format: Option<String>,
-        recalculate_lang: bool,
-    ) -> Result<u32, DataStoreError> {
-        // If there is no language set, try to detect it based on our new content
-        if self.lang.is_none() || recalculate_lang {
-            // TODO: make this also use DocumentLexer
-            let detected_lang = Document::detect_language(&document_body);
-            if detected_lang.is_some() {
-                self.lang = detected_lang;
-            }
+        detection: LangDetection,
+    ) -> Result<UpdateOutcome, DataStoreError> {
+        let mut options = IndexingOptions::from_env(env);
+        options.stoplist = StopList::load(store, &self.index).await?;
+        if let Some(index) = read_index_document(store, &self.index).await? {
+            options.default_lang = index.default_lang.unwrap_or(IsoCode639_1::EN);
+            options.position_boost = index.settings.position_boost.unwrap_or(0.0);
+            options.title_boost = index.settings.title_boost.unwrap_or(DEFAULT_TITLE_BOOST);
+            options.min_keyword_score = index.settings.min_keyword_score.unwrap_or(0.0);
+            options.extractor = index.settings.extractor.unwrap_or_default();
+            options.n_shards = index.shard_count(options.n_shards);
+            options.codecs = CodecSet::for_index(&index)?;
         }
+        let bodies = get_body_bucket(env);
+        self.update_with_bodies(
+            store,
+            bodies.as_ref(),
+            &options,
+            document_body,
+            format,
+            detection,
+        )
+        .await
+    }
 
-        let lang_str = format!("{}", &self.lang.unwrap());
-        let doc_lexer = DocumentLexer::new(env, &document_body);
-        let _keywords: Vec<DocumentScore>;
+    /// [`Self::update`] with explicit indexing options instead of reading them from the env,
+    /// keeping the body in KV regardless of its size
+    #[cfg(test)]
+    pub async fn update_with<S: Storage>(
+        &mut self,
+        store: &S,
+        options: &IndexingOptions,
+        document_body: String,
+        format: Option<String>,
+        detection: LangDetection,
+    ) -> Result<UpdateOutcome, DataStoreError> {
+        self.update_with_bodies(store, None::<&S>, options, document_body, format, detection)
+            .await
+    }
 
-        let format_name = format.unwrap_or_else(|| "text".to_string());
-        match format_name.as_str() {
-            "json" => {
-                _keywords = doc_lexer.try_json(lang_str.as_str()).unwrap();
-            }
-            "binary" => {
-                // Do not run keyword extraction on binary data
-                _keywords = vec![];
+    /// [`Self::update_with`], storing bodies over `options.offload_bytes` in `bodies`
+    /// and keeping only a reference to them in KV
+    pub async fn update_with_bodies<S: Storage, B: Storage>(
+        &mut self,
+        store: &S,
+        bodies: Option<&B>,
+        options: &IndexingOptions,
+        document_body: String,
+        format: Option<String>,
+        detection: LangDetection,
+    ) -> Result<UpdateOutcome, DataStoreError> {
+        let mut format_name = format.unwrap_or_else(|| "text".to_string());
+        let mut document_body = document_body;
+        let mut title = None;
+        if format_name == "envelope" {
+            let envelope = DocumentEnvelope::parse(&document_body)?;
+            // Whatever the envelope leaves out is kept from the stored revision
+            if envelope.body.is_none() {
+                if let Some(bodies) = bodies {
+                    self.load_body(bodies).await?;
+                }
             }
-            "text" | _ => {
-                _keywords = doc_lexer.try_string(lang_str.as_str()).unwrap();
+            document_body = envelope
+                .body
+                .or_else(|| self.document_body.clone())
+                .unwrap_or_default();
+            title = envelope
+                .title
+                .or_else(|| self.title.clone())
+                .filter(|title| !title.is_empty());
+            format_name = "text".to_string();
+        }
+
+        // If there is no language set, try to detect it based on our new content
+        if self.lang.is_none() {
+            match detection {
+                LangDetection::WhenMissing => {
+                    // TODO: make this also use DocumentLexer
+                    let detected =
+                        Document::detect_language(&document_body, &options.detect_languages);
+                    let (lang, confidence) = choose_language(detected, options);
+                    self.lang = Some(lang);
+                    self.lang_confidence = Some(confidence);
+                }
+                LangDetection::Never => self.set_language(options.default_lang),
             }
         }
 
-        // Calculate which keywords were added/removed
-        let mut kw_removed: Vec<&str> = vec![];
-        let old_keywords = self.keywords.clone().unwrap_or_else(|| vec![]);
-        let new_keywords = _keywords.clone();
-        self.keywords = Some(_keywords);
+        let lang_str = format!("{}", self.lang.unwrap_or(options.default_lang));
+        // A retried import rewrites documents exactly as they're stored
+        let fingerprint = content_fingerprint(
+            &document_body,
+            title.as_deref(),
+            &format_name,
+            &lang_str,
+            options,
+        );
+        if self.revision > 0 && self.fingerprint.as_deref() == Some(fingerprint.as_str()) {
+            return Ok(UpdateOutcome {
+                revision: self.revision,
+                keywords_added: 0,
+                keywords_removed: 0,
+                indexed_keywords: self
+                    .keywords
+                    .iter()
+                    .flatten()
+                    .map(|(keyword, _)| keyword.clone())
+                    .collect(),
+                failed_keywords: vec![],
+                unchanged: true,
+                keywords_dropped_below_floor: 0,
+                keyword_deltas: KeywordDeltas::new(),
+            });
+        }
+        // A revision without a fingerprint may have postings that were never written,
+        // by a write whose shards partly failed, so every one of them is written again
+        let unverified = self.revision > 0 && self.fingerprint.is_none();
+        self.fingerprint = Some(fingerprint);
+
+        let doc_lexer = DocumentLexer::with_config(options.yake.clone(), &document_body)
+            .with_extractor(options.extractor);
+        let _keywords: Vec<DocumentScore> = match format_name.as_str() {
+            "json" => doc_lexer.try_json(lang_str.as_str()).ok_or_else(|| {
+                DataStoreError::InvalidFormat("document body is not valid JSON".into())
+            })?,
+            // Do not run keyword extraction on binary data
+            "binary" => vec![],
+            _ => doc_lexer.try_string(lang_str.as_str()).unwrap(),
+        };
 
-        let new_kw_set = HashSet::from_iter(new_keywords.iter().map(|(kw, _)| kw.as_str()));
-        let existing_kw_set: HashSet<&str> =
-            old_keywords.iter().map(|(kw, _)| kw.as_str()).collect();
+        let _keywords = options.stoplist.filter(_keywords);
+        let _keywords = doc_lexer.boost_by_position(_keywords, options.position_boost);
+        let _keywords = match title.as_deref() {
+            Some(title) => {
+                let title_lexer = DocumentLexer::with_config(options.yake.clone(), title)
+                    .with_extractor(options.extractor);
+                let title_keywords = title_lexer.try_string(lang_str.as_str()).unwrap();
+                let title_keywords = options.stoplist.filter(title_keywords);
+                merge_title_keywords(_keywords, boost_title(title_keywords, options.title_boost))
+            }
+            None => _keywords,
+        };
+        self.title = title;
+        let extracted = _keywords.len();
+        let _keywords: Vec<(String, KeywordScore)> = _keywords
+            .into_iter()
+            .filter(|(_, score)| score.score >= options.min_keyword_score)
+            .collect();
+        let keywords_dropped_below_floor = extracted - _keywords.len();
 
-        for kw in existing_kw_set.difference(&new_kw_set) {
-            kw_removed.push(kw);
+        // Calculate which keywords were added/removed/rescored
+        let old_keywords = self.keywords.take().unwrap_or_default();
+        let diff = KeywordDiff::between(&old_keywords, &_keywords);
+        self.keywords = Some(_keywords);
+        self.store_body(bodies, options, document_body).await?;
+        // A new document's first revision is when it was added
+        let now = options.clock.now_millis();
+        if self.revision == 0 {
+            self.created_at = Some(now);
         }
-        self.document_body = Some(document_body);
         self.revision += 1;
-        self.write(&store).await?;
+        self.updated_at = Some(now);
+        self.write(store).await?;
 
-        // Actually update all of the keyword shards
+        // Actually update the keyword shards that changed, coalescing every change
+        // into a single read and at most one write per touched shard key
         let doc_id = self.uuid.clone();
-        let current_keywords = self.keywords.as_ref().unwrap();
+        let mut batch = ShardWriteBatch::new(&self.index, &doc_id, options.n_shards)
+            .with_codecs(options.codecs);
+        diff.queue(&mut batch);
+        if unverified {
+            for (keyword, score) in self.keywords.iter().flatten() {
+                batch.upsert(keyword, score.score);
+            }
+        }
+
+        let shard_count = batch.len();
+        let shard = batch.shard();
+        edge_log!(
+            console_debug,
+            "Documents",
+            &self.index,
+            "Updating {} keyword shards (shard {}) for document {}",
+            shard_count,
+            shard,
+            doc_id
+        );
 
-        // Collect all removal futures
-        let removal_futures: Vec<_> = kw_removed
+        let results = match batch.is_empty() {
+            true => vec![],
+            false => batch.execute_with_retry(store, now).await,
+        };
+        let mut failed_keywords = vec![];
+        for (keyword, result) in results {
+            if let Err(err) = result {
+                edge_log!(
+                    console_warn,
+                    "Documents",
+                    &self.index,
+                    "Failed to update keyword shard for keyword '{}' on document {}: {}",
+                    keyword,
+                    doc_id,
+                    err
+                );
+                failed_keywords.push((keyword, err));
+            }
+        }
+        if !failed_keywords.is_empty() {
+            // Sending the same body again must write the failed shards, not match the
+            // fingerprint and change nothing
+            self.fingerprint = None;
+            self.write(store).await?;
+        }
+        let indexed_keywords = self
+            .keywords
             .iter()
-            .map(|removed_kw| {
-                let store = &store;
-                let index = &self.index;
-                let doc_id = &doc_id;
-                let removed_kw = removed_kw.as_ref();
-                async move {
-                    let mut shard =
-                        KeywordShardData::from_keyword(store, env, index, doc_id, &removed_kw)
-                            .await
-                            .ok()
-                            .unwrap();
-
-                    edge_log!(
-                        console_debug,
-                        "Documents",
-                        index,
-                        "Removing document {} from keyword shard for keyword '{}'",
-                        doc_id,
-                        removed_kw
-                    );
-                    shard
-                        .remove_document(store, doc_id)
-                        .await
-                        .unwrap_or_else(|_| {
-                            edge_log!(
-                                console_warn,
-                                "Documents",
-                                index,
-                                "Failed to remove document {} from keyword shard for keyword '{}'",
-                                doc_id,
-                                removed_kw
-                            );
-                        });
+            .flatten()
+            .map(|(keyword, _)| keyword.clone())
+            .filter(|keyword| !failed_keywords.iter().any(|(failed, _)| failed == keyword))
+            .collect();
+        let added = diff.added.iter().map(|(keyword, _)| (keyword, 1));
+        let removed = diff.removed.iter().map(|keyword| (keyword, -1));
+        let keyword_deltas = added
+            .chain(removed)
+            .filter(|(keyword, _)| !failed_keywords.iter().any(|(failed, _)| failed == *keyword))
+            .map(|(keyword, delta)| (keyword.clone(), delta))
+            .collect();
+        Ok(UpdateOutcome {
+            revision: self.revision,
+            keywords_added: diff.added.len(),
+            keywords_removed: diff.removed.len(),
+            indexed_keywords,
+            failed_keywords,
+            unchanged: false,
+            keywords_dropped_below_floor,
+            keyword_deltas,
+        })
+    }
+
+    /// Write a document read back from a snapshot as it was saved, keeping its
+    /// revision and stored keywords rather than extracting them again, and add its
+    /// postings. Its body is offloaded to `bodies` as [`Self::update_with_bodies`]
+    /// would.
+    pub async fn restore<S: Storage, B: Storage>(
+        &mut self,
+        store: &S,
+        bodies: Option<&B>,
+        options: &IndexingOptions,
+        now: u64,
+    ) -> Result<(), DataStoreError> {
+        let document_body = self.document_body.take().unwrap_or_default();
+        self.body_ref = None;
+        self.store_body(bodies, options, document_body).await?;
+        self.write(store).await?;
+
+        let mut batch = ShardWriteBatch::new(&self.index, &self.uuid, options.n_shards)
+            .with_codecs(options.codecs);
+        for (keyword, score) in self.keywords.iter().flatten() {
+            batch.upsert(keyword, score.score);
+        }
+        for (_, result) in batch.execute_with_retry(store, now).await {
+            result?;
+        }
+        Ok(())
+    }
+
+    /// Keep the body inline, or offload it to `bodies` when it is over the threshold.
+    /// A previously offloaded body is removed once the new one fits in KV.
+    async fn store_body<B: Storage>(
+        &mut self,
+        bodies: Option<&B>,
+        options: &IndexingOptions,
+        document_body: String,
+    ) -> Result<(), DataStoreError> {
+        match bodies {
+            Some(bodies) if document_body.len() > options.offload_bytes => {
+                let object_key = self.get_kv_key();
+                bodies.put(&object_key, document_body).await?;
+                self.document_body = None;
+                self.body_ref = Some(object_key);
+            }
+            _ => {
+                if let (Some(bodies), Some(object_key)) = (bodies, self.body_ref.take()) {
+                    bodies.delete(&object_key).await?;
                 }
-            })
+                self.document_body = Some(document_body);
+            }
+        }
+        Ok(())
+    }
+
+    /// Fetch an offloaded body from `bodies` into `document_body`, if it isn't already loaded
+    pub async fn load_body<B: Storage>(&mut self, bodies: &B) -> Result<(), DataStoreError> {
+        if self.document_body.is_some() {
+            return Ok(());
+        }
+        if let Some(object_key) = self.body_ref.as_ref() {
+            let body = bodies
+                .get(object_key)
+                .await?
+                .ok_or_else(|| DataStoreError::NotFound(object_key.clone()))?;
+            self.document_body = Some(body);
+        }
+        Ok(())
+    }
+
+    /// The top-level fields of the body, which search filters test, when the body
+    /// is a JSON object. Offloaded bodies need [`Self::load_body`] first.
+    pub fn metadata(&self) -> Option<serde_json::Map<String, serde_json::Value>> {
+        match serde_json::from_str(self.document_body.as_deref()?) {
+            Ok(serde_json::Value::Object(fields)) => Some(fields),
+            _ => None,
+        }
+    }
+
+    pub async fn delete<S: Storage>(&self, store: &S) -> Result<(), DataStoreError> {
+        store.delete(&self.get_kv_key()).await
+    }
+
+    /// Remove this document's offloaded body, if it has one
+    pub async fn delete_body<B: Storage>(&self, bodies: &B) -> Result<(), DataStoreError> {
+        bodies.delete(&self.get_kv_key()).await
+    }
+}
+
+/// Test helpers for indexing documents into an in-memory store
+#[cfg(test)]
+pub(crate) mod testing {
+    use futures::executor::block_on;
+
+    use super::*;
+
+    /// Index `body` as an English text document, as `handle_add_document` would
+    pub fn index_text<S: Storage>(store: &S, index: &str, id: &str, body: &str) -> Document {
+        let mut document = Document::new_with_id(index, id);
+        document.set_language(IsoCode639_1::EN);
+        block_on(document.update_with(
+            store,
+            &IndexingOptions::default(),
+            body.to_string(),
+            None,
+            LangDetection::WhenMissing,
+        ))
+        .unwrap();
+        document
+    }
+
+    /// Write the `format=envelope` body `envelope` to document `id`, with `options`
+    pub fn index_envelope<S: Storage>(
+        store: &S,
+        index: &str,
+        id: &str,
+        envelope: serde_json::Value,
+        options: &IndexingOptions,
+    ) -> UpdateOutcome {
+        let mut document = block_on(Document::from_remote(store, index, id.to_string()))
+            .unwrap_or_else(|_| Document::new_with_id(index, id));
+        document.set_language(IsoCode639_1::EN);
+        block_on(document.update_with(
+            store,
+            options,
+            envelope.to_string(),
+            Some("envelope".into()),
+            LangDetection::WhenMissing,
+        ))
+        .unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+
+    use super::*;
+    use crate::data::{
+        keyword_shard::{keyword_shard_kv_key, testing::execute_counted, KeywordShardData},
+        storage::memory::MemoryStorage,
+    };
+    use crate::util::time::ManualClock;
+
+    fn keywords<T: From<f64>>(items: &[(&str, f64)]) -> Vec<(String, T)> {
+        items
+            .iter()
+            .map(|(kw, s)| (kw.to_string(), (*s).into()))
+            .collect()
+    }
+
+    /// Index `old` into a fresh store, then apply the diff to `new` and return
+    /// the (reads, writes) the second update cost.
+    fn update_cost(
+        old: &[(String, KeywordScore)],
+        new: &[(String, KeywordScore)],
+    ) -> (usize, usize) {
+        let store = MemoryStorage::default();
+        let mut initial = ShardWriteBatch::new("idx", "doc1", 48);
+        KeywordDiff::between(&[], old).queue(&mut initial);
+        execute_counted(&store, &initial, 1);
+
+        let mut batch = ShardWriteBatch::new("idx", "doc1", 48);
+        KeywordDiff::between(old, new).queue(&mut batch);
+        execute_counted(&store, &batch, 2)
+    }
+
+    #[test]
+    fn test_keyword_diff() {
+        let old = keywords(&[("a", 0.5), ("b", 0.5), ("c", 0.5)]);
+        let new = keywords(&[("a", 0.5), ("b", 0.7), ("d", 0.1)]);
+        let diff = KeywordDiff::between(&old, &new);
+        assert_eq!(diff.added, keywords(&[("d", 0.1)]));
+        assert_eq!(diff.rescored, keywords(&[("b", 0.7)]));
+        assert_eq!(diff.removed, vec!["c".to_string()]);
+        assert_eq!(diff.unchanged, 1);
+    }
+
+    #[test]
+    fn test_noop_update_costs_no_kv_operations() {
+        let old = keywords(&[("a", 0.5), ("b", 0.4), ("c", 0.3)]);
+        assert_eq!(update_cost(&old, &old), (0, 0));
+    }
+
+    #[test]
+    fn test_score_only_change_rewrites_changed_shards() {
+        let old = keywords(&[("a", 0.5), ("b", 0.4), ("c", 0.3)]);
+        let new = keywords(&[("a", 0.5), ("b", 0.45), ("c", 0.3)]);
+        // The one rescored shard is read once, then written with its top postings'
+        // summary
+        assert_eq!(update_cost(&old, &new), (1, 2));
+    }
+
+    #[test]
+    fn test_full_keyword_turnover() {
+        let old = keywords(&[("a", 0.5), ("b", 0.4), ("c", 0.3)]);
+        let new = keywords(&[("x", 0.5), ("y", 0.4)]);
+        // Three removals and two additions, each read once and written with its
+        // summary
+        assert_eq!(update_cost(&old, &new), (5, 10));
+    }
+
+    fn stored_shard(store: &MemoryStorage, doc: &Document, keyword: &str) -> KeywordShardData {
+        let shard = shard_from_document_id(doc.get_uuid(), DEFAULT_N_SHARDS);
+        let key = keyword_shard_kv_key("idx", keyword, shard);
+        block_on(KeywordShardData::read(&key, store)).unwrap()
+    }
+
+    /// Assert the shard holds exactly the one posting, tolerating JSON float round-trips
+    fn assert_only_posting(shard: &KeywordShardData, doc_id: &str, score: f64) {
+        assert_eq!(shard.docs.len(), 1, "{:?}", shard.docs);
+        assert_eq!(shard.docs[0].0, doc_id);
+        assert!(scores_equal(shard.docs[0].1, score));
+    }
+
+    #[test]
+    fn test_indexing_writes_document_and_shards() {
+        let store = MemoryStorage::default();
+        let doc = testing::index_text(
+            &store,
+            "idx",
+            "doc1",
+            "The ocean tide rolls over the sandy beach at dawn.",
+        );
+
+        let stored = block_on(Document::from_remote(&store, "idx", "doc1".into())).unwrap();
+        assert_eq!(stored.revision, 1);
+        assert_eq!(stored.document_body, doc.document_body);
+
+        let keywords = doc.keywords.clone().unwrap();
+        assert!(!keywords.is_empty());
+        for (keyword, score) in keywords.iter() {
+            assert_only_posting(&stored_shard(&store, &doc, keyword), "doc1", score.score);
+        }
+        // One document key plus a shard key and its summary key per keyword
+        assert_eq!(store.keys().len(), keywords.len() * 2 + 1);
+    }
+
+    fn detect_and_index(options: &IndexingOptions, body: &str) -> Document {
+        let store = MemoryStorage::default();
+        let mut doc = Document::new_with_id("idx", "doc1");
+        block_on(doc.update_with(
+            &store,
+            options,
+            body.into(),
+            None,
+            LangDetection::WhenMissing,
+        ))
+        .unwrap();
+        doc
+    }
+
+    #[test]
+    fn test_choose_language() {
+        let options = IndexingOptions::default();
+        let confident = Some((IsoCode639_1::EN, 0.92));
+        assert_eq!(
+            choose_language(confident, &options),
+            (IsoCode639_1::EN, 0.92)
+        );
+
+        let options = IndexingOptions {
+            lang_confidence_min: 0.95,
+            ..IndexingOptions::default()
+        };
+        assert_eq!(
+            choose_language(confident, &options),
+            (options.default_lang, 0.92)
+        );
+        assert_eq!(choose_language(None, &options), (options.default_lang, 0.0));
+    }
+
+    #[test]
+    fn test_short_bodies_skip_detection() {
+        for body in ["ok", "  la   la  ", "42!", "Tide pools."] {
+            assert_eq!(Document::detect_language(body, &[]), None);
+            let doc = detect_and_index(&IndexingOptions::default(), body);
+            assert_eq!(doc.lang, Some(IsoCode639_1::EN), "{:?}", body);
+            assert_eq!(doc.lang_confidence, Some(0.0));
+        }
+    }
+
+    #[test]
+    fn test_unconfident_detection_records_fallback() {
+        let body = "The ocean tide rolls over the sandy beach at dawn.";
+        let confident = detect_and_index(&IndexingOptions::default(), body);
+        assert_eq!(confident.lang, Some(IsoCode639_1::EN));
+        let confidence = confident.lang_confidence.unwrap();
+        assert!(confidence >= DEFAULT_LANG_CONFIDENCE_MIN);
+
+        let options = IndexingOptions {
+            lang_confidence_min: 1.5,
+            ..IndexingOptions::default()
+        };
+        let fallback = detect_and_index(&options, body);
+        assert_eq!(fallback.lang, Some(options.default_lang));
+        assert_eq!(fallback.lang_confidence, Some(confidence));
+
+        // Text in no supported language still gets one
+        let doc = detect_and_index(&options, "東京は日本の首都であり、最大の都市でもあります。");
+        assert_eq!(doc.lang, Some(options.default_lang));
+        assert!(doc.lang_confidence.unwrap() < 1.5);
+    }
+
+    #[test]
+    fn test_given_language_has_no_confidence() {
+        let store = MemoryStorage::default();
+        let doc = testing::index_text(&store, "idx", "doc1", "Tide pools.");
+        assert_eq!(doc.lang, Some(IsoCode639_1::EN));
+        assert_eq!(doc.lang_confidence, None);
+    }
+
+    fn detector_requests() -> usize {
+        DETECTOR_REQUESTS.with(|requests| requests.get())
+    }
+
+    #[test]
+    fn test_given_or_skipped_language_never_loads_detector() {
+        let body = "The ocean tide rolls over the sandy beach at dawn.";
+        let store = MemoryStorage::default();
+        let requests = detector_requests();
+
+        let mut given = Document::new_with_id("idx", "doc1");
+        given.set_language(IsoCode639_1::EN);
+        let detection = LangDetection::WhenMissing;
+        block_on(given.update_with(
+            &store,
+            &IndexingOptions::default(),
+            body.into(),
+            None,
+            detection,
+        ))
+        .unwrap();
+        assert_eq!(detector_requests(), requests);
+
+        let options = IndexingOptions {
+            default_lang: IsoCode639_1::EN,
+            ..IndexingOptions::default()
+        };
+        let mut skipped = Document::new_with_id("idx", "doc2");
+        block_on(skipped.update_with(&store, &options, body.into(), None, LangDetection::Never))
+            .unwrap();
+        assert_eq!(detector_requests(), requests);
+        assert_eq!(
+            (skipped.lang, skipped.lang_confidence),
+            (Some(IsoCode639_1::EN), None)
+        );
+
+        detect_and_index(&options, body);
+        assert_eq!(detector_requests(), requests + 1);
+    }
+
+    #[test]
+    fn test_parse_detect_languages() {
+        assert_eq!(parse_detect_languages(""), vec![]);
+        assert_eq!(
+            parse_detect_languages(" EN, klingon,en ,"),
+            vec![IsoCode639_1::EN]
+        );
+    }
+
+    #[test]
+    fn test_reindexing_moves_postings() {
+        let store = MemoryStorage::default();
+        let mut doc = testing::index_text(&store, "idx", "doc1", "Ocean tides and sandy beaches.");
+        let old_keywords = doc.keywords.clone().unwrap();
+
+        let outcome = block_on(doc.update_with(
+            &store,
+            &IndexingOptions::default(),
+            "Mountain glaciers and alpine meadows.".into(),
+            None,
+            LangDetection::WhenMissing,
+        ))
+        .unwrap();
+        assert_eq!(doc.revision, 2);
+
+        let new_keywords = doc.keywords.clone().unwrap();
+        let diff = KeywordDiff::between(&old_keywords, &new_keywords);
+        assert_eq!(outcome.keywords_added, diff.added.len());
+        assert_eq!(outcome.keywords_removed, diff.removed.len());
+        assert!(outcome.keywords_added > 0 && outcome.keywords_removed > 0);
+        for (keyword, _) in old_keywords.iter() {
+            if !new_keywords.iter().any(|(kw, _)| kw == keyword) {
+                assert!(stored_shard(&store, &doc, keyword).docs.is_empty());
+            }
+        }
+        for (keyword, score) in new_keywords.iter() {
+            assert_only_posting(&stored_shard(&store, &doc, keyword), "doc1", score.score);
+        }
+    }
+
+    #[test]
+    fn test_position_boost_decays_later_keywords() {
+        let store = MemoryStorage::default();
+        let body = "Glaciers carve valleys. Far below, rivers wind through quiet farmland.";
+        let options = IndexingOptions {
+            position_boost: 2.0,
+            ..IndexingOptions::default()
+        };
+        let mut doc = Document::new_with_id("idx", "doc1");
+        doc.set_language(IsoCode639_1::EN);
+        block_on(doc.update_with(
+            &store,
+            &options,
+            body.into(),
+            None,
+            LangDetection::WhenMissing,
+        ))
+        .unwrap();
+
+        let keywords = doc.keywords.clone().unwrap();
+        let lower = body.to_lowercase();
+        for (keyword, score) in keywords.iter() {
+            let ratio =
+                lower.find(keyword.as_str()).unwrap_or(lower.len()) as f64 / lower.len() as f64;
+            assert!((score.score - score.raw / (1.0 + 2.0 * ratio)).abs() < 1e-9);
+            assert_only_posting(&stored_shard(&store, &doc, keyword), "doc1", score.score);
+        }
+        let glaciers = keywords.iter().find(|(kw, _)| kw == "glaciers").unwrap();
+        assert_eq!(glaciers.1.score, glaciers.1.raw);
+        assert!(keywords.iter().any(|(_, score)| score.score < score.raw));
+    }
+
+    #[test]
+    fn test_tf_extractor_writes_single_words() {
+        let store = MemoryStorage::default();
+        let options = IndexingOptions {
+            extractor: Extractor::Tf,
+            ..IndexingOptions::default()
+        };
+        let mut doc = Document::new_with_id("idx", "doc1");
+        doc.set_language(IsoCode639_1::EN);
+        block_on(doc.update_with(
+            &store,
+            &options,
+            r#"{"title":"Steel water bottle","tags":["steel","bottle"]}"#.into(),
+            Some("json".into()),
+            LangDetection::WhenMissing,
+        ))
+        .unwrap();
+
+        let keywords = doc.keywords.clone().unwrap();
+        let words: Vec<&str> = keywords.iter().map(|(kw, _)| kw.as_str()).collect();
+        assert_eq!(words, vec!["steel", "bottle", "water"]);
+        assert_eq!(keywords[0].1.score, 1.0);
+        assert_only_posting(&stored_shard(&store, &doc, "water"), "doc1", 0.5);
+    }
+
+    #[test]
+    fn test_keyword_score_formats() {
+        // Documents written before positional boosts store bare scores
+        let legacy: Document = serde_json::from_str(
+            r#"{"id":"doc1","rev":1,"lang":"EN","body":"Ocean","keywords":[["ocean",0.9]]}"#,
+        )
+        .unwrap();
+        let (_, score) = &legacy.keywords.as_ref().unwrap()[0];
+        assert_eq!((score.score, score.raw), (0.9, 0.9));
+
+        let boosted = KeywordScore {
+            score: 0.6,
+            raw: 0.9,
+        };
+        let json = serde_json::to_string(&[("ocean", boosted), ("tide", 0.4.into())]).unwrap();
+        assert_eq!(json, r#"[["ocean",{"score":0.6,"raw":0.9}],["tide",0.4]]"#);
+        let read: Vec<(String, KeywordScore)> = serde_json::from_str(&json).unwrap();
+        assert_eq!(read[0].1, boosted);
+        assert_eq!(read[1].1, KeywordScore::from(0.4));
+    }
+
+    #[test]
+    fn test_timestamps() {
+        // Documents written before timestamps were kept read back without them
+        let legacy: Document =
+            serde_json::from_str(r#"{"id":"doc1","rev":1,"lang":"EN","body":"Ocean"}"#).unwrap();
+        assert_eq!((legacy.created_at, legacy.updated_at), (None, None));
+        let json = serde_json::to_string(&legacy).unwrap();
+        assert!(!json.contains("created_at") && !json.contains("updated_at"));
+
+        // One reading stamps the document and its shards alike
+        let store = MemoryStorage::default();
+        let clock = ManualClock::at(1_000);
+        let options = IndexingOptions {
+            clock: clock.clone(),
+            ..IndexingOptions::default()
+        };
+        let mut doc = Document::new_with_id("idx", "doc1");
+        doc.set_language(IsoCode639_1::EN);
+        let mut update = |body: &str| {
+            block_on(doc.update_with(
+                &store,
+                &options,
+                body.into(),
+                None,
+                LangDetection::WhenMissing,
+            ))
+            .unwrap();
+            block_on(Document::from_remote(&store, "idx", "doc1".into())).unwrap()
+        };
+        let read = update("Ocean tides.");
+        assert_eq!(
+            (read.created_at, read.updated_at),
+            (Some(1_000), Some(1_000))
+        );
+        clock.advance(500);
+        let read = update("Desert dunes.");
+        assert_eq!(
+            (read.created_at, read.updated_at),
+            (Some(1_000), Some(1_500))
+        );
+        let (keyword, _) = &read.keywords.as_ref().unwrap()[0];
+        assert_eq!(stored_shard(&store, &read, keyword).ts, 1_500);
+    }
+
+    #[test]
+    fn test_metadata() {
+        let mut doc = Document::new_with_id("idx", "doc1");
+        assert!(doc.metadata().is_none());
+        for body in ["Ocean tides", "[1, 2]", "{\"price\": "] {
+            doc.document_body = Some(body.into());
+            assert!(doc.metadata().is_none(), "{}", body);
+        }
+        doc.document_body = Some(r#"{"price": 12.5, "tags": ["sea"]}"#.into());
+        let metadata = doc.metadata().unwrap();
+        assert_eq!(metadata["price"], 12.5);
+        assert_eq!(metadata.len(), 2);
+    }
+
+    #[test]
+    fn test_stoplisted_keywords_are_not_indexed() {
+        let store = MemoryStorage::default();
+        let body = "Acme Corp ships ocean freight. Acme Corp thanks you for reading.";
+        let unfiltered = testing::index_text(&store, "other", "doc1", body);
+        assert!(unfiltered
+            .keywords
+            .unwrap()
+            .iter()
+            .any(|(kw, _)| kw == "acme corp"));
+
+        let options = IndexingOptions {
+            stoplist: StopList::new("idx", &["ACME  Corp"]),
+            ..IndexingOptions::default()
+        };
+        let mut doc = Document::new_with_id("idx", "doc1");
+        doc.set_language(IsoCode639_1::EN);
+        block_on(doc.update_with(
+            &store,
+            &options,
+            body.into(),
+            None,
+            LangDetection::WhenMissing,
+        ))
+        .unwrap();
+
+        let keywords = doc.keywords.clone().unwrap();
+        assert!(!keywords.is_empty());
+        assert!(keywords.iter().all(|(kw, _)| kw != "acme corp"));
+        assert!(!store
+            .keys()
+            .iter()
+            .any(|key| key.starts_with("idx:kw:acme corp:")));
+    }
+
+    #[test]
+    fn test_keywords_below_the_floor_are_not_indexed() {
+        let store = MemoryStorage::default();
+        let body = "Lighthouse keepers tended the lamps through winter storms. Ferries ran \
+                    twice a week, carrying supplies out to the islands.";
+        let write = |id: &str, options: &IndexingOptions| {
+            let before = store.counts().puts;
+            let mut doc = block_on(Document::from_remote(&store, "idx", id.into()))
+                .unwrap_or_else(|_| Document::new_with_id("idx", id));
+            doc.set_language(IsoCode639_1::EN);
+            let outcome = block_on(doc.update_with(
+                &store,
+                options,
+                body.into(),
+                None,
+                LangDetection::WhenMissing,
+            ))
+            .unwrap();
+            (doc, outcome, store.counts().puts - before)
+        };
+
+        let (unfloored, outcome, unfloored_puts) = write("doc1", &IndexingOptions::default());
+        assert_eq!(outcome.keywords_dropped_below_floor, 0);
+        let mut scores: Vec<f64> = unfloored
+            .keywords
+            .iter()
+            .flatten()
+            .map(|(_, score)| score.score)
             .collect();
+        scores.sort_by(f64::total_cmp);
+        let floored = IndexingOptions {
+            min_keyword_score: scores[scores.len() / 2],
+            ..IndexingOptions::default()
+        };
+        let (weakest, _) = unfloored
+            .keywords
+            .iter()
+            .flatten()
+            .find(|(_, score)| score.score == scores[0])
+            .unwrap();
 
-        // Collect all addition futures
-        let addition_futures: Vec<_> = current_keywords
+        let (doc, outcome, floored_puts) = write("doc2", &floored);
+        let kept = doc.keywords.as_ref().unwrap();
+        assert_eq!(outcome.keywords_dropped_below_floor, scores.len() / 2);
+        assert_eq!(kept.len(), scores.len() - scores.len() / 2);
+        assert!(kept
             .iter()
-            .map(|(added_kw, score)| {
-                let store = &store;
-                let index = &self.index;
-                let doc_id = &doc_id;
-                let added_kw = added_kw.clone();
-                let score = *score;
-                async move {
-                    let mut shard =
-                        KeywordShardData::from_keyword(store, env, index, doc_id, &added_kw)
-                            .await
-                            .ok()
-                            .unwrap();
-
-                    edge_log!(
-                        console_debug,
-                        "Documents",
-                        index,
-                        "Adding document {} to keyword shard for keyword '{}'",
-                        doc_id,
-                        added_kw
-                    );
-                    shard
-                        .add_document(store, doc_id, score)
-                        .await
-                        .unwrap_or_else(|_| {
-                            edge_log!(
-                                console_warn,
-                                "Documents",
-                                index,
-                                "Failed to add document {} to keyword shard for keyword '{}'",
-                                doc_id,
-                                added_kw
-                            );
-                        });
-                }
+            .all(|(_, score)| score.score >= floored.min_keyword_score));
+        // The dropped keywords' shards are never written
+        assert!(floored_puts < unfloored_puts, "{} puts", floored_puts);
+
+        // Raising the floor leaves doc1's weak postings alone until it's rewritten
+        let shard = stored_shard(&store, &unfloored, weakest);
+        assert!(shard.docs.iter().any(|(id, _)| id == "doc1"));
+        let (_, outcome, _) = write("doc1", &floored);
+        assert!(!outcome.unchanged);
+        assert_eq!(outcome.keywords_removed, scores.len() / 2);
+        let shard = stored_shard(&store, &unfloored, weakest);
+        assert!(!shard.docs.iter().any(|(id, _)| id == "doc1"));
+    }
+
+    #[test]
+    fn test_reindexing_same_body_writes_nothing() {
+        let store = MemoryStorage::default();
+        let body = "Ocean tides and sandy beaches.";
+        let mut doc = testing::index_text(&store, "idx", "doc1", body);
+        let before = store.counts();
+
+        let outcome = block_on(doc.update_with(
+            &store,
+            &IndexingOptions::default(),
+            body.into(),
+            None,
+            LangDetection::WhenMissing,
+        ))
+        .unwrap();
+        let after = store.counts();
+        assert!(outcome.unchanged);
+        assert_eq!((outcome.revision, outcome.keywords_added), (1, 0));
+        assert_eq!(outcome.indexed_keywords.len(), doc.keywords.unwrap().len());
+        assert_eq!((after.puts, after.gets), (before.puts, before.gets));
+    }
+
+    #[test]
+    fn test_retitling_keeps_body_and_rediffs_keywords() {
+        let store = MemoryStorage::default();
+        let options = IndexingOptions::default();
+        let body = "Ferries run twice a week, carrying supplies out to the islands.";
+        let envelope = serde_json::json!({ "title": "Lighthouses", "body": body });
+        testing::index_envelope(&store, "idx", "doc1", envelope, &options);
+        let doc = |store: &MemoryStorage| {
+            block_on(Document::from_remote(store, "idx", "doc1".into())).unwrap()
+        };
+        let keywords = |doc: &Document| -> Vec<String> {
+            doc.keywords
+                .iter()
+                .flatten()
+                .map(|(kw, _)| kw.clone())
+                .collect()
+        };
+        let first = doc(&store);
+        assert_eq!(first.title.as_deref(), Some("Lighthouses"));
+        assert!(keywords(&first).contains(&"lighthouses".to_string()));
+        let shard = stored_shard(&store, &first, "lighthouses");
+        let title_score = first.keywords.as_ref().unwrap().last().unwrap().1;
+        assert_eq!(
+            title_score.score,
+            (title_score.raw * DEFAULT_TITLE_BOOST).min(1.0)
+        );
+        assert_only_posting(&shard, "doc1", title_score.score);
+
+        let retitle = serde_json::json!({ "title": "Coastal ferries" });
+        let outcome = testing::index_envelope(&store, "idx", "doc1", retitle.clone(), &options);
+        let second = doc(&store);
+        assert_eq!(second.document_body.as_deref(), Some(body));
+        assert_eq!(second.title.as_deref(), Some("Coastal ferries"));
+        assert_eq!((outcome.revision, outcome.keywords_removed), (2, 1));
+        assert!(!keywords(&second).contains(&"lighthouses".to_string()));
+        assert!(stored_shard(&store, &second, "lighthouses").docs.is_empty());
+
+        // The same title again changes nothing, and an empty one removes it
+        let outcome = testing::index_envelope(&store, "idx", "doc1", retitle, &options);
+        assert!(outcome.unchanged);
+        let untitle = serde_json::json!({ "title": "" });
+        testing::index_envelope(&store, "idx", "doc1", untitle, &options);
+        let untitled = doc(&store);
+        assert_eq!(untitled.title, None);
+        assert_eq!(untitled.document_body.as_deref(), Some(body));
+        assert_eq!(
+            keywords(&untitled),
+            keywords(&testing::index_text(
+                &MemoryStorage::default(),
+                "idx",
+                "doc1",
+                body
+            ))
+        );
+
+        let mut document = Document::new_with_id("idx", "doc2");
+        let invalid = block_on(document.update_with(
+            &store,
+            &options,
+            r#"{"heading":"Lighthouses"}"#.into(),
+            Some("envelope".into()),
+            LangDetection::Never,
+        ));
+        assert!(matches!(invalid, Err(DataStoreError::InvalidFormat(_))));
+    }
+
+    #[test]
+    fn test_changed_settings_rewrite_same_body() {
+        let store = MemoryStorage::default();
+        let body = "Ocean tides and sandy beaches.";
+        let mut doc = testing::index_text(&store, "idx", "doc1", body);
+        let tf = IndexingOptions {
+            extractor: Extractor::Tf,
+            ..IndexingOptions::default()
+        };
+        for (options, format) in [(&tf, None), (&tf, Some("binary"))] {
+            let outcome = block_on(doc.update_with(
+                &store,
+                options,
+                body.into(),
+                format.map(str::to_string),
+                LangDetection::WhenMissing,
+            ));
+            assert!(outcome.is_ok_and(|outcome| !outcome.unchanged));
+        }
+        assert_ne!(
+            content_fingerprint(body, None, "text", "en", &IndexingOptions::default()),
+            content_fingerprint(body, None, "text", "de", &IndexingOptions::default())
+        );
+    }
+
+    /// Write each `(id, body)` as `_bulk` does, updating the document when it's stored
+    fn import(store: &MemoryStorage, documents: &[(String, String)]) -> Vec<UpdateOutcome> {
+        documents
+            .iter()
+            .map(|(id, body)| {
+                let mut doc = block_on(Document::from_remote(store, "import", id.clone()))
+                    .unwrap_or_else(|_| Document::new_with_id("import", id));
+                doc.set_language(IsoCode639_1::EN);
+                block_on(doc.update_with(
+                    store,
+                    &IndexingOptions::default(),
+                    body.clone(),
+                    None,
+                    LangDetection::WhenMissing,
+                ))
+                .unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_repeated_import_writes_nothing() {
+        let store = MemoryStorage::default();
+        let topics = [
+            "ocean tides",
+            "mountain trails",
+            "river deltas",
+            "desert dunes",
+        ];
+        let documents: Vec<(String, String)> = (0..100)
+            .map(|i| {
+                let topic = topics[i % topics.len()];
+                let body = format!("Notes {} on {} and their seasonal changes.", i, topic);
+                (format!("doc{}", i), body)
             })
             .collect();
+        let first = import(&store, &documents);
+        assert!(first.iter().all(|outcome| !outcome.unchanged));
+        let written = store.counts();
 
-        join_all(removal_futures).await;
-        join_all(addition_futures).await;
-        Ok(self.revision)
+        let second = import(&store, &documents);
+        assert!(second.iter().all(|outcome| outcome.unchanged));
+        let rewritten = store.counts();
+        assert_eq!(
+            (rewritten.puts, rewritten.deletes),
+            (written.puts, written.deletes)
+        );
+        // Only the stored documents were read back
+        assert_eq!(rewritten.gets - written.gets, documents.len());
     }
 
-    pub async fn delete(&self, store: &KvStore) -> Result<(), DataStoreError> {
-        store
-            .delete(&self.get_kv_key())
-            .await
-            .map_err(DataStoreError::Kv)?;
-        Ok(())
+    /// Index `body` as doc1 of "idx" after making the next `failures` writes to the
+    /// shard of its first keyword fail, returning the document, outcome and keyword
+    fn index_with_failing_shard(
+        store: &MemoryStorage,
+        body: &str,
+        failures: usize,
+    ) -> (Document, UpdateOutcome, String) {
+        let keyword = testing::index_text(store, "other", "doc1", body)
+            .keywords
+            .unwrap()[0]
+            .0
+            .clone();
+        store.fail_puts(&format!("idx:kw:{}:", keyword), failures);
+
+        let mut doc = Document::new_with_id("idx", "doc1");
+        doc.set_language(IsoCode639_1::EN);
+        let outcome = block_on(doc.update_with(
+            store,
+            &IndexingOptions::default(),
+            body.into(),
+            None,
+            LangDetection::WhenMissing,
+        ))
+        .unwrap();
+        (doc, outcome, keyword)
+    }
+
+    #[test]
+    fn test_transient_shard_failure_is_retried() {
+        let store = MemoryStorage::default();
+        let (doc, outcome, keyword) =
+            index_with_failing_shard(&store, "Ocean tides and sandy beaches.", 1);
+        assert!(outcome.is_complete());
+        assert_eq!(
+            outcome.indexed_keywords.len(),
+            doc.keywords.clone().unwrap().len()
+        );
+        assert_eq!(stored_shard(&store, &doc, &keyword).docs.len(), 1);
+    }
+
+    #[test]
+    fn test_persistent_shard_failure_is_reported() {
+        let store = MemoryStorage::default();
+        let (doc, outcome, keyword) =
+            index_with_failing_shard(&store, "Ocean tides and sandy beaches.", 2);
+        assert_eq!(outcome.revision, 1);
+        assert_eq!(outcome.failed_keywords.len(), 1);
+        assert_eq!(outcome.failed_keywords[0].0, keyword);
+        assert!(!outcome.indexed_keywords.contains(&keyword));
+        assert_eq!(
+            outcome.indexed_keywords.len(),
+            doc.keywords.clone().unwrap().len() - 1
+        );
+
+        // The document and its other shards were still written
+        assert_eq!(doc_after(&store).revision, 1);
+        let shard = shard_from_document_id(doc.get_uuid(), DEFAULT_N_SHARDS);
+        assert!(!store
+            .keys()
+            .contains(&keyword_shard_kv_key("idx", &keyword, shard)));
+        for other in outcome.indexed_keywords.iter() {
+            assert_eq!(stored_shard(&store, &doc, other).docs.len(), 1);
+        }
+    }
+
+    #[test]
+    fn test_retry_after_shard_failure_rewrites_failed_shards() {
+        let store = MemoryStorage::default();
+        let body = "Ocean tides and sandy beaches.";
+        let (_, _, keyword) = index_with_failing_shard(&store, body, 2);
+        assert_eq!(doc_after(&store).fingerprint, None);
+
+        // Sending the same body again writes the shard that failed
+        let retry = |store: &MemoryStorage| {
+            let mut doc = doc_after(store);
+            block_on(doc.update_with(
+                store,
+                &IndexingOptions::default(),
+                body.into(),
+                None,
+                LangDetection::WhenMissing,
+            ))
+            .unwrap()
+        };
+        let outcome = retry(&store);
+        assert!(!outcome.unchanged);
+        assert!(outcome.is_complete());
+        assert!(outcome.indexed_keywords.contains(&keyword));
+        let doc = doc_after(&store);
+        assert!(doc.fingerprint.is_some());
+        assert_eq!(stored_shard(&store, &doc, &keyword).docs.len(), 1);
+
+        // Once every shard is written, the same body is a no-op again
+        assert!(retry(&store).unchanged);
+    }
+
+    fn doc_after(store: &MemoryStorage) -> Document {
+        block_on(Document::from_remote(store, "idx", "doc1".into())).unwrap()
+    }
+
+    #[test]
+    fn test_large_body_is_offloaded() {
+        let store = MemoryStorage::default();
+        let bodies = MemoryStorage::default();
+        let options = IndexingOptions {
+            offload_bytes: 16,
+            ..IndexingOptions::default()
+        };
+        let large = "Ocean tides roll over the sandy beaches at dawn.";
+
+        let mut doc = Document::new_with_id("idx", "doc1");
+        doc.set_language(IsoCode639_1::EN);
+        block_on(doc.update_with_bodies(
+            &store,
+            Some(&bodies),
+            &options,
+            large.into(),
+            None,
+            LangDetection::WhenMissing,
+        ))
+        .unwrap();
+        assert!(!doc.keywords.clone().unwrap().is_empty());
+        assert_eq!(bodies.keys(), vec!["idx:document:doc1"]);
+
+        // KV holds only the keywords and the reference; the body loads from R2
+        let mut stored = block_on(Document::from_remote(&store, "idx", "doc1".into())).unwrap();
+        assert_eq!(stored.document_body, None);
+        assert_eq!(stored.body_ref.as_deref(), Some("idx:document:doc1"));
+        block_on(stored.load_body(&bodies)).unwrap();
+        assert_eq!(stored.document_body.as_deref(), Some(large));
+
+        // Shrinking the body below the threshold moves it back into KV
+        block_on(doc.update_with_bodies(
+            &store,
+            Some(&bodies),
+            &options,
+            "Tides.".into(),
+            None,
+            LangDetection::WhenMissing,
+        ))
+        .unwrap();
+        assert_eq!(doc.body_ref, None);
+        assert_eq!(doc.document_body.as_deref(), Some("Tides."));
+        assert!(bodies.keys().is_empty());
+    }
+
+    #[test]
+    fn test_small_body_stays_in_kv() {
+        let store = MemoryStorage::default();
+        let bodies = MemoryStorage::default();
+        let mut doc = Document::new_with_id("idx", "doc1");
+        doc.set_language(IsoCode639_1::EN);
+        block_on(doc.update_with_bodies(
+            &store,
+            Some(&bodies),
+            &IndexingOptions::default(),
+            "Ocean tides.".into(),
+            None,
+            LangDetection::WhenMissing,
+        ))
+        .unwrap();
+        assert_eq!(doc.body_ref, None);
+        assert!(bodies.keys().is_empty());
+
+        let json = serde_json::to_string(&doc).unwrap();
+        assert!(!json.contains("body_ref"));
     }
 }
@@ -1,78 +1,463 @@
-use serde::de::Visitor;
+//! The container the durable reader answers bulk reads with. It starts with a magic
+//! and version header, followed by one frame per requested key:
+//!
+//! `key_len: u32 | key | flags: u8 | payload_len: u32 | payload`
+//!
+//! Version 2 containers also stamp every frame with the newest timestamp of the
+//! shards behind it, so a caller holding the value can be told it is unchanged:
+//!
+//! `key_len: u32 | key | flags: u8 | ts: u64 | payload_len: u32 | payload`
+//!
+//! All integers are little endian. The flags mark whether the payload is the value,
+//! a missing key, an error message, or left out as unchanged, so one bad key no
+//! longer poisons the batch.
+//! Containers without the magic are read as the legacy format of bare
+//! `len: u32 | payload` frames, which carry no keys.
 
-pub struct LengthPrefixed {
-    pub bytes: Vec<u8>,
+use serde::de::DeserializeOwned;
+use thiserror::Error;
+
+/// Marks a keyed container, and can't be mistaken for a legacy frame length
+pub const FRAME_MAGIC: &[u8; 4] = b"ESFR";
+pub const FRAME_VERSION: u8 = 1;
+/// The version of containers whose frames carry a timestamp
+pub const STAMPED_FRAME_VERSION: u8 = 2;
+
+const FLAG_FOUND: u8 = 0;
+const FLAG_NOT_FOUND: u8 = 1;
+const FLAG_ERROR: u8 = 2;
+const FLAG_UNCHANGED: u8 = 3;
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum EncodingError {
+    #[error("no value exists for this key")]
+    NotFound,
+    #[error("the reader failed to load this key: {0}")]
+    Remote(String),
+    #[error("the value could not be deserialized: {0}")]
+    Deserialize(String),
+    #[error("the container ended partway through a frame")]
+    Truncated,
+    #[error("unsupported container version {0}")]
+    UnsupportedVersion(u8),
+    #[error("unknown frame flags {0}")]
+    UnknownFlags(u8),
+    #[error("the value is unchanged since the timestamp the caller sent")]
+    Unchanged,
 }
 
-impl LengthPrefixed {}
+/// One frame of a container: its key, the timestamp a version 2 container stamped
+/// it with, and its value
+pub type StampedFrame<T> = (String, Option<u64>, Result<T, EncodingError>);
 
-impl ToString for LengthPrefixed {
-    fn to_string(&self) -> String {
-        std::str::from_utf8(&self.bytes).unwrap().to_string()
+/// Builds a keyed container, one frame per key
+pub struct FrameWriter {
+    bytes: Vec<u8>,
+    stamped: bool,
+}
+
+impl Default for FrameWriter {
+    fn default() -> Self {
+        FrameWriter::new()
     }
 }
 
-impl Into<Vec<u8>> for LengthPrefixed {
-    fn into(self) -> Vec<u8> {
+impl FrameWriter {
+    pub fn new() -> FrameWriter {
+        FrameWriter::with_version(FRAME_VERSION)
+    }
+
+    /// A version 2 container, whose frames carry a timestamp. Frames written without
+    /// one are stamped 0.
+    pub fn stamped() -> FrameWriter {
+        FrameWriter::with_version(STAMPED_FRAME_VERSION)
+    }
+
+    fn with_version(version: u8) -> FrameWriter {
+        let mut bytes = Vec::with_capacity(FRAME_MAGIC.len() + 1);
+        bytes.extend_from_slice(FRAME_MAGIC);
+        bytes.push(version);
+        FrameWriter {
+            bytes,
+            stamped: version == STAMPED_FRAME_VERSION,
+        }
+    }
+
+    pub fn found(&mut self, key: &str, payload: &[u8]) {
+        self.frame(key, FLAG_FOUND, 0, payload);
+    }
+
+    /// The value of `key`, last changed at `ts`
+    pub fn found_at(&mut self, key: &str, ts: u64, payload: &[u8]) {
+        self.frame(key, FLAG_FOUND, ts, payload);
+    }
+
+    /// Leave out the value of `key`, which the caller already holds as of `ts`. Only
+    /// a stamped container can say so.
+    pub fn unchanged(&mut self, key: &str, ts: u64) {
+        debug_assert!(self.stamped, "unchanged frames need a stamped container");
+        self.frame(key, FLAG_UNCHANGED, ts, &[]);
+    }
+
+    pub fn not_found(&mut self, key: &str) {
+        self.frame(key, FLAG_NOT_FOUND, 0, &[]);
+    }
+
+    pub fn error(&mut self, key: &str, message: &str) {
+        self.frame(key, FLAG_ERROR, 0, message.as_bytes());
+    }
+
+    /// How many bytes the container holds so far
+    pub fn byte_len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    /// How many bytes a frame of `key` with a payload of `payload_len` bytes adds
+    pub fn frame_len(&self, key: &str, payload_len: usize) -> usize {
+        let ts_len = if self.stamped { 8 } else { 0 };
+        4 + key.len() + 1 + ts_len + 4 + payload_len
+    }
+
+    fn frame(&mut self, key: &str, flags: u8, ts: u64, payload: &[u8]) {
+        self.bytes.reserve(17 + key.len() + payload.len());
         self.bytes
+            .extend_from_slice(&(key.len() as u32).to_le_bytes());
+        self.bytes.extend_from_slice(key.as_bytes());
+        self.bytes.push(flags);
+        if self.stamped {
+            self.bytes.extend_from_slice(&ts.to_le_bytes());
+        }
+        self.bytes
+            .extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        self.bytes.extend_from_slice(payload);
     }
-}
 
-impl AsRef<[u8]> for LengthPrefixed {
-    fn as_ref(&self) -> &[u8] {
-        &self.bytes
+    pub fn finish(self) -> Vec<u8> {
+        self.bytes
     }
 }
 
-impl<'de> Visitor<'de> for LengthPrefixed {
-    type Value = LengthPrefixed;
+/// Reads little endian `u32`s and byte runs off the front of a buffer
+struct Cursor<'a> {
+    data: &'a [u8],
+}
 
-    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
-        formatter.write_str("a length-prefixed byte array")
+impl<'a> Cursor<'a> {
+    fn is_empty(&self) -> bool {
+        self.data.is_empty()
     }
 
-    fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
-    where
-        E: serde::de::Error,
-    {
-        if v.len() < 4 {
-            return Err(E::custom("buffer too short for length prefix"));
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.data.len() < len {
+            return None;
         }
+        let (taken, rest) = self.data.split_at(len);
+        self.data = rest;
+        Some(taken)
+    }
 
-        // first 4 bytes are length (u32, little endian)
-        let len = u32::from_le_bytes([v[0], v[1], v[2], v[3]]) as usize;
-        if v.len() < 4 + len {
-            return Err(E::custom("buffer shorter than length prefix"));
-        }
-        Ok(LengthPrefixed {
-            bytes: v[4..4 + len].to_vec(),
-        })
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u32(&mut self) -> Option<usize> {
+        self.take(4)
+            .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize)
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        self.take(8)
+            .map(|b| u64::from_le_bytes(b.try_into().expect("took 8 bytes")))
+    }
+
+    fn sized(&mut self) -> Option<&'a [u8]> {
+        let len = self.u32()?;
+        self.take(len)
+    }
+}
+
+pub fn decode<T: DeserializeOwned>(payload: &[u8]) -> Result<T, EncodingError> {
+    serde_json::from_slice::<T>(payload).map_err(|err| EncodingError::Deserialize(err.to_string()))
+}
+
+/// Decode every frame of a container into its key, timestamp and value. Problems are
+/// reported per frame rather than panicking: a truncated or unreadable container
+/// ends with a single entry holding the error, keyed by an empty string. Legacy
+/// containers have no keys, so their entries are keyed by an empty string too, and
+/// only version 2 frames have a timestamp.
+pub fn read_length_prefixed_stamped<T: DeserializeOwned>(data: &[u8]) -> Vec<StampedFrame<T>> {
+    read_frames(data, decode)
+}
+
+/// Like [`read_length_prefixed_stamped`], without the timestamps
+#[cfg(test)]
+pub fn read_length_prefixed<T: DeserializeOwned>(
+    data: &[u8],
+) -> Vec<(String, Result<T, EncodingError>)> {
+    unstamped(read_frames(data, decode))
+}
+
+/// Like [`read_length_prefixed_stamped`], but leaving every payload undecoded and
+/// dropping the timestamps, for values that need their key to be decoded
+pub fn read_length_prefixed_raw(data: &[u8]) -> Vec<(String, Result<Vec<u8>, EncodingError>)> {
+    unstamped(read_frames(data, |payload| Ok(payload.to_vec())))
+}
+
+fn unstamped<T>(frames: Vec<StampedFrame<T>>) -> Vec<(String, Result<T, EncodingError>)> {
+    frames
+        .into_iter()
+        .map(|(key, _, value)| (key, value))
+        .collect()
+}
+
+fn read_frames<T>(
+    data: &[u8],
+    decode: impl Fn(&[u8]) -> Result<T, EncodingError>,
+) -> Vec<StampedFrame<T>> {
+    match data.strip_prefix(FRAME_MAGIC.as_slice()) {
+        Some(rest) => read_keyed(rest, decode),
+        None => read_legacy(data, decode),
     }
 }
 
-// Read the first 4 bytes as u8, convert to a u32 size of n, then
-// read the next n bytes as the data. Then repeat until we run out of data
-pub fn read_length_prefixed<'se, T: serde::Deserialize<'se>>(data: &'se Vec<u8>) -> Vec<T> {
-    let mut pos = 0u32;
-    let mut results: Vec<T> = Vec::new();
+fn read_keyed<T>(
+    data: &[u8],
+    decode: impl Fn(&[u8]) -> Result<T, EncodingError>,
+) -> Vec<StampedFrame<T>> {
+    let mut cursor = Cursor { data };
+    let stamped = match cursor.u8() {
+        Some(FRAME_VERSION) => false,
+        Some(STAMPED_FRAME_VERSION) => true,
+        Some(version) => {
+            return vec![(
+                String::new(),
+                None,
+                Err(EncodingError::UnsupportedVersion(version)),
+            )]
+        }
+        None => return vec![(String::new(), None, Err(EncodingError::Truncated))],
+    };
+
+    let mut results = vec![];
+    while !cursor.is_empty() {
+        let frame = cursor.sized().and_then(|key| {
+            let flags = cursor.u8()?;
+            let ts = match stamped {
+                true => Some(cursor.u64()?),
+                false => None,
+            };
+            Some((key, flags, ts, cursor.sized()?))
+        });
+        let Some((key, flags, ts, payload)) = frame else {
+            results.push((String::new(), None, Err(EncodingError::Truncated)));
+            break;
+        };
 
-    while pos < data.len() as u32 {
-        let lp = read_one_length_prefixed(&data[pos as usize..]).unwrap_or(&[]);
-        let obj = serde_json::from_slice::<T>(lp).unwrap();
-        pos += 4 + lp.len() as u32;
-        results.push(obj);
+        let value = match flags {
+            FLAG_FOUND => decode(payload),
+            FLAG_NOT_FOUND => Err(EncodingError::NotFound),
+            FLAG_ERROR => Err(EncodingError::Remote(
+                String::from_utf8_lossy(payload).into_owned(),
+            )),
+            FLAG_UNCHANGED if stamped => Err(EncodingError::Unchanged),
+            other => Err(EncodingError::UnknownFlags(other)),
+        };
+        results.push((String::from_utf8_lossy(key).into_owned(), ts, value));
     }
+    results
+}
 
+fn read_legacy<T>(
+    data: &[u8],
+    decode: impl Fn(&[u8]) -> Result<T, EncodingError>,
+) -> Vec<StampedFrame<T>> {
+    let mut cursor = Cursor { data };
+    let mut results = vec![];
+    while !cursor.is_empty() {
+        match cursor.sized() {
+            Some(payload) => results.push((String::new(), None, decode(payload))),
+            None => {
+                results.push((String::new(), None, Err(EncodingError::Truncated)));
+                break;
+            }
+        }
+    }
     results
 }
 
-fn read_one_length_prefixed(data: &[u8]) -> Option<&[u8]> {
-    if data.len() < 4 {
-        return None;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Postings = Vec<(String, f64)>;
+
+    /// A tiny xorshift generator, so the round-trip tests need no extra crates
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: u64) -> u64 {
+            self.next() % n
+        }
+
+        fn string(&mut self) -> String {
+            const CHARS: &[char] = &['a', 'z', '0', ':', ' ', '"', ',', 'é', '海', '\n'];
+            (0..self.below(12))
+                .map(|_| CHARS[self.below(CHARS.len() as u64) as usize])
+                .collect()
+        }
+
+        fn postings(&mut self) -> Postings {
+            (0..self.below(6))
+                .map(|_| (self.string(), self.below(1_000) as f64 / 8.0))
+                .collect()
+        }
+    }
+
+    fn random_entries(rng: &mut Rng) -> Vec<(String, Result<Postings, EncodingError>)> {
+        (0..rng.below(10))
+            .map(|_| {
+                let key = rng.string();
+                let value = match rng.below(3) {
+                    0 => Ok(rng.postings()),
+                    1 => Err(EncodingError::NotFound),
+                    _ => Err(EncodingError::Remote(rng.string())),
+                };
+                (key, value)
+            })
+            .collect()
+    }
+
+    fn encode(entries: &[(String, Result<Postings, EncodingError>)]) -> Vec<u8> {
+        let mut writer = FrameWriter::new();
+        for (key, value) in entries.iter() {
+            match value {
+                Ok(postings) => writer.found(key, &serde_json::to_vec(postings).unwrap()),
+                Err(EncodingError::NotFound) => writer.not_found(key),
+                Err(EncodingError::Remote(message)) => writer.error(key, message),
+                Err(other) => panic!("cannot encode {:?}", other),
+            }
+        }
+        writer.finish()
+    }
+
+    #[test]
+    fn test_random_containers_round_trip() {
+        let mut rng = Rng(0x9E37_79B9_7F4A_7C15);
+        for _ in 0..500 {
+            let entries = random_entries(&mut rng);
+            let decoded = read_length_prefixed::<Postings>(&encode(&entries));
+            assert_eq!(decoded, entries);
+        }
+    }
+
+    #[test]
+    fn test_truncated_containers_never_panic() {
+        let mut rng = Rng(42);
+        for _ in 0..50 {
+            let entries = random_entries(&mut rng);
+            let bytes = encode(&entries);
+            for len in 0..bytes.len() {
+                let decoded = read_length_prefixed::<Postings>(&bytes[..len]);
+                // Every complete frame survives; a cut-off one is reported as truncation
+                let complete = decoded
+                    .iter()
+                    .take_while(|(_, value)| *value != Err(EncodingError::Truncated))
+                    .count();
+                assert_eq!(decoded[..complete], entries[..complete]);
+                assert!(decoded.len() <= complete + 1);
+            }
+        }
     }
-    let size = u32::from_le_bytes([data[0], data[1], data[2], data[3]]) as usize;
-    if data.len() < 4 + size {
-        return None;
+
+    #[test]
+    fn test_corrupt_payload_only_fails_its_frame() {
+        let mut writer = FrameWriter::new();
+        writer.found("good", br#"[["doc1",0.5]]"#);
+        writer.found("bad", b"");
+        writer.not_found("missing");
+        let decoded = read_length_prefixed::<Postings>(&writer.finish());
+
+        assert_eq!(decoded[0], ("good".into(), Ok(vec![("doc1".into(), 0.5)])));
+        assert!(matches!(decoded[1].1, Err(EncodingError::Deserialize(_))));
+        assert_eq!(decoded[2], ("missing".into(), Err(EncodingError::NotFound)));
+    }
+
+    #[test]
+    fn test_unsupported_version() {
+        let mut bytes = FrameWriter::new().finish();
+        bytes[FRAME_MAGIC.len()] = STAMPED_FRAME_VERSION + 1;
+        let decoded = read_length_prefixed::<Postings>(&bytes);
+        assert_eq!(
+            decoded,
+            vec![(
+                String::new(),
+                Err(EncodingError::UnsupportedVersion(STAMPED_FRAME_VERSION + 1))
+            )]
+        );
+    }
+
+    #[test]
+    fn test_stamped_frames_carry_their_timestamp() {
+        let mut writer = FrameWriter::stamped();
+        writer.found_at("ocean", 1_700, br#"[["doc1",0.5]]"#);
+        writer.unchanged("tide", 1_650);
+        writer.not_found("missing");
+        let bytes = writer.finish();
+        assert_eq!(bytes[FRAME_MAGIC.len()], STAMPED_FRAME_VERSION);
+
+        let decoded = read_length_prefixed_stamped::<Postings>(&bytes);
+        assert_eq!(
+            decoded,
+            vec![
+                ("ocean".into(), Some(1_700), Ok(vec![("doc1".into(), 0.5)])),
+                ("tide".into(), Some(1_650), Err(EncodingError::Unchanged)),
+                ("missing".into(), Some(0), Err(EncodingError::NotFound)),
+            ]
+        );
+        // Readers that don't need timestamps read either version
+        let unstamped = read_length_prefixed::<Postings>(&bytes);
+        assert_eq!(
+            unstamped[0],
+            ("ocean".into(), Ok(vec![("doc1".into(), 0.5)]))
+        );
+        for len in 0..bytes.len() {
+            let cut = read_length_prefixed_stamped::<Postings>(&bytes[..len]);
+            assert!(cut.len() <= 3);
+        }
+
+        // Version 1 frames have no timestamp, and can't be marked unchanged
+        let mut bytes = encode(&[("ocean".into(), Ok(vec![]))]);
+        // The flags sit before the payload length and the two byte payload `[]`
+        let flags = bytes.len() - 7;
+        assert_eq!(read_length_prefixed_stamped::<Postings>(&bytes)[0].1, None);
+        bytes[flags] = FLAG_UNCHANGED;
+        assert_eq!(
+            read_length_prefixed::<Postings>(&bytes)[0].1,
+            Err(EncodingError::UnknownFlags(FLAG_UNCHANGED))
+        );
+    }
+
+    #[test]
+    fn test_legacy_containers_are_still_read() {
+        let mut bytes = vec![];
+        for payload in [br#"[["doc1",0.5]]"#.as_slice(), b""] {
+            bytes.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(payload);
+        }
+        bytes.extend_from_slice(&[9, 0]);
+
+        let decoded = read_length_prefixed::<Postings>(&bytes);
+        assert_eq!(decoded.len(), 3);
+        assert_eq!(decoded[0], (String::new(), Ok(vec![("doc1".into(), 0.5)])));
+        // The empty stand-in the old reader wrote for missing keys no longer panics
+        assert!(matches!(decoded[1].1, Err(EncodingError::Deserialize(_))));
+        assert_eq!(decoded[2].1, Err(EncodingError::Truncated));
     }
-    Some(&data[4..4 + size])
 }
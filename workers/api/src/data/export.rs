@@ -0,0 +1,369 @@
+//! Exporting every keyword of an index with its shard and posting counts, for
+//! building datasets like autocomplete suggestions elsewhere. Keywords come in the
+//! order KV lists their shard keys in, which is the byte order of their escaped
+//! prefixes. Each call reads one KV listing page and the shards of at most `limit`
+//! keywords, so nothing is held across calls but the cursor.
+//!
+//! Shards still stored under the unescaped legacy key of a keyword are counted with
+//! the keyword, but a keyword with only legacy shards isn't exported.
+
+use std::{collections::HashMap, fmt, str::FromStr};
+
+use serde::Serialize;
+
+use crate::data::{
+    bulk::BulkReader,
+    keyword_shard::{
+        escape_keyword, keyword_shard_prefix, legacy_keyword_shard_prefix, list_keyword_shards,
+        unescape_keyword,
+    },
+    storage::Storage,
+    DataStoreError, PREFIX_KEYWORD,
+};
+
+pub const DEFAULT_EXPORT_LIMIT: usize = 500;
+pub const MAX_EXPORT_LIMIT: usize = 1_000;
+
+/// One exported keyword
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ExportedKeyword {
+    pub keyword: String,
+    /// How many of the keyword's shards exist
+    pub shards: u32,
+    /// The postings across those shards. Each document is kept in a single shard,
+    /// so this is the number of documents holding the keyword.
+    pub doc_count: u64,
+}
+
+/// Where to resume an export: the KV listing page, and the last keyword exported,
+/// which the page may list again
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ExportCursor {
+    pub page: Option<String>,
+    pub after: Option<String>,
+}
+
+impl ExportCursor {
+    /// Resume after `keyword`, from the start of the listing
+    pub fn after(keyword: &str) -> ExportCursor {
+        ExportCursor {
+            page: None,
+            after: Some(keyword.to_string()),
+        }
+    }
+}
+
+/// `{length of after}:{after}{page}`, since neither is free of any separator
+impl fmt::Display for ExportCursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let after = self.after.as_deref().unwrap_or("");
+        let page = self.page.as_deref().unwrap_or("");
+        write!(f, "{}:{}{}", after.len(), after, page)
+    }
+}
+
+impl FromStr for ExportCursor {
+    type Err = String;
+
+    fn from_str(cursor: &str) -> Result<ExportCursor, String> {
+        let invalid = || format!("Invalid export cursor '{}'", cursor);
+        let (len, rest) = cursor.split_once(':').ok_or_else(invalid)?;
+        let len: usize = len.parse().map_err(|_| invalid())?;
+        if !rest.is_char_boundary(len.min(rest.len())) || len > rest.len() {
+            return Err(invalid());
+        }
+        let (after, page) = rest.split_at(len);
+        Ok(ExportCursor {
+            page: (!page.is_empty()).then(|| page.to_string()),
+            after: (!after.is_empty()).then(|| after.to_string()),
+        })
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ExportPage {
+    pub keywords: Vec<ExportedKeyword>,
+    /// Pass back to continue, `None` once every keyword was exported
+    pub cursor: Option<ExportCursor>,
+}
+
+/// A keyword and the shard keys listed for it
+#[derive(Debug, PartialEq)]
+struct KeywordGroup {
+    keyword: String,
+    shards: Vec<String>,
+    /// Whether the listed keys may not be the keyword's shards, so they're listed
+    /// again with [`list_keyword_shards`]: it's being resharded, or has legacy keys
+    relist: bool,
+}
+
+/// The keywords of the shard keys in `listed`, in listing order, leaving out
+/// keywords whose shards are listed no later than `after`'s
+fn group_by_keyword(index: &str, listed: Vec<String>, after: Option<&str>) -> Vec<KeywordGroup> {
+    let prefix = format!("{}:{}", index, PREFIX_KEYWORD);
+    let after = after.map(|after| keyword_shard_prefix(index, after));
+    let mut groups: Vec<KeywordGroup> = vec![];
+    for key in listed {
+        let Some((escaped, shard)) = key
+            .strip_prefix(prefix.as_str())
+            .and_then(|rest| rest.rsplit_once(':'))
+        else {
+            continue;
+        };
+        let keyword = unescape_keyword(escaped);
+        // A legacy key, counted with the keyword when it's listed again
+        if escape_keyword(&keyword) != escaped {
+            continue;
+        }
+        let resharding = shard == "resharding";
+        if !resharding && shard.parse::<u32>().is_err() {
+            continue;
+        }
+        if after
+            .as_deref()
+            .is_some_and(|after| keyword_shard_prefix(index, &keyword).as_str() <= after)
+        {
+            continue;
+        }
+        let group = match groups.last_mut() {
+            Some(group) if group.keyword == keyword => group,
+            _ => {
+                let relist = legacy_keyword_shard_prefix(index, &keyword).is_some();
+                groups.push(KeywordGroup {
+                    keyword,
+                    shards: vec![],
+                    relist,
+                });
+                groups.last_mut().unwrap()
+            }
+        };
+        group.relist |= resharding;
+        if !resharding {
+            group.shards.push(key);
+        }
+    }
+    groups
+}
+
+/// Export the next keywords of `index` after `cursor`, at most `limit` of them
+pub async fn export_batch<S: Storage>(
+    index: &str,
+    store: &S,
+    bulk_reader: &BulkReader<'_, S>,
+    cursor: ExportCursor,
+    limit: usize,
+) -> Result<ExportPage, DataStoreError> {
+    let prefix = format!("{}:{}", index, PREFIX_KEYWORD);
+    let listed = store.list(&prefix, cursor.page.clone()).await?;
+    let mut groups = group_by_keyword(index, listed.keys, cursor.after.as_deref());
+    let last_of_page = groups.len() <= limit;
+    groups.truncate(limit);
+
+    // The page's last keyword may have more shards on the next page, so all of them
+    // are listed, and the next page skips the keyword
+    if let Some(last) = groups.last_mut() {
+        last.relist |= last_of_page && listed.cursor.is_some();
+    }
+    for group in groups.iter_mut().filter(|group| group.relist) {
+        group.shards = list_keyword_shards(store, index, &group.keyword).await?;
+    }
+
+    let keys = groups
+        .iter()
+        .flat_map(|group| group.shards.iter().map(String::as_str))
+        .collect();
+    let mut counts: HashMap<String, (u32, u64)> = HashMap::new();
+    for shard in bulk_reader.get_keyword_kv_keys(keys).await {
+        let (shards, docs) = counts.entry(shard.keyword).or_default();
+        *shards += 1;
+        *docs += shard.docs.len() as u64;
+    }
+    let keywords: Vec<ExportedKeyword> = groups
+        .into_iter()
+        .filter_map(|group| {
+            // Deleted since it was listed
+            let (shards, doc_count) = counts.remove(&group.keyword)?;
+            Some(ExportedKeyword {
+                keyword: group.keyword,
+                shards,
+                doc_count,
+            })
+        })
+        .collect();
+
+    let after = keywords
+        .last()
+        .map(|last| last.keyword.clone())
+        .or(cursor.after);
+    let cursor = match (last_of_page, listed.cursor) {
+        (false, _) => Some(ExportCursor {
+            page: cursor.page,
+            after,
+        }),
+        (true, Some(page)) => Some(ExportCursor {
+            page: Some(page),
+            after,
+        }),
+        (true, None) => None,
+    };
+    Ok(ExportPage { keywords, cursor })
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+
+    use super::*;
+    use crate::data::{
+        keyword_shard::{reshard_marker_key, staged_shard_kv_key, testing::seed_postings},
+        storage::memory::MemoryStorage,
+    };
+
+    const N_SHARDS: u32 = 8;
+
+    /// Follow the cursor to the end, returning every exported keyword and the most
+    /// keywords, and KV reads, a single call took
+    fn walk(
+        store: &MemoryStorage,
+        cursor: ExportCursor,
+        limit: usize,
+    ) -> (Vec<ExportedKeyword>, usize, usize) {
+        let bulk_reader = BulkReader::new(N_SHARDS, store, None);
+        let (mut exported, mut widest, mut most_reads) = (vec![], 0, 0);
+        let mut cursor = Some(cursor);
+        while let Some(next) = cursor {
+            // Cursors survive being sent to the client and back
+            let next: ExportCursor = next.to_string().parse().unwrap();
+            let before = store.counts();
+            let page = block_on(export_batch("idx", store, &bulk_reader, next, limit)).unwrap();
+            let after = store.counts();
+            most_reads = most_reads.max(after.gets + after.lists - before.gets - before.lists);
+            widest = widest.max(page.keywords.len());
+            exported.extend(page.keywords);
+            cursor = page.cursor;
+        }
+        (exported, widest, most_reads)
+    }
+
+    fn seed(store: &MemoryStorage, keywords: usize) -> Vec<String> {
+        let mut names: Vec<String> = (0..keywords).map(|i| format!("kw{:04}", i)).collect();
+        for (i, keyword) in names.iter().enumerate() {
+            let docs: Vec<String> = (0..=i % 5).map(|d| format!("doc{}-{}", i, d)).collect();
+            let docs: Vec<(&str, f64)> = docs.iter().map(|id| (id.as_str(), 0.5)).collect();
+            seed_postings(store, "idx", N_SHARDS, keyword, &docs);
+        }
+        names.sort_by_key(|keyword| keyword_shard_prefix("idx", keyword));
+        names
+    }
+
+    #[test]
+    fn test_export_counts_every_keyword_once() {
+        let store = MemoryStorage::with_page_size(7);
+        let names = seed(&store, 40);
+        let (exported, _, _) = walk(&store, ExportCursor::default(), 3);
+        let keywords: Vec<&str> = exported.iter().map(|kw| kw.keyword.as_str()).collect();
+        assert_eq!(keywords, names);
+
+        // Shards are named by document, so postings are counted once across them
+        let expected = block_on(list_keyword_shards(&store, "idx", "kw0004")).unwrap();
+        let kw4 = exported.iter().find(|kw| kw.keyword == "kw0004").unwrap();
+        assert_eq!(kw4.doc_count, 5);
+        assert_eq!(kw4.shards as usize, expected.len());
+        assert!(exported
+            .iter()
+            .all(|kw| kw.doc_count >= 1 && kw.shards >= 1));
+    }
+
+    #[test]
+    fn test_export_is_bounded_per_call() {
+        let store = MemoryStorage::with_page_size(100);
+        let names = seed(&store, 2_000);
+        let (exported, widest, most_reads) = walk(&store, ExportCursor::default(), 25);
+        assert_eq!(exported.len(), names.len());
+        assert_eq!(widest, 25);
+        // A listing page, a relisted keyword and the shards of the keywords exported
+        assert!(most_reads <= 2 + 25 * N_SHARDS as usize, "{}", most_reads);
+    }
+
+    #[test]
+    fn test_export_resumes_after_a_keyword() {
+        let store = MemoryStorage::with_page_size(5);
+        let names = seed(&store, 30);
+        let (exported, _, _) = walk(&store, ExportCursor::after(&names[11]), 4);
+        let keywords: Vec<&str> = exported.iter().map(|kw| kw.keyword.as_str()).collect();
+        assert_eq!(keywords, names[12..]);
+        assert!(walk(&store, ExportCursor::after("zzz"), 4).0.is_empty());
+    }
+
+    #[test]
+    fn test_export_cursor_round_trip() {
+        for cursor in [
+            ExportCursor::default(),
+            ExportCursor::after("a:b é"),
+            ExportCursor {
+                page: Some("idx:kw:a:3".into()),
+                after: Some("ocean".into()),
+            },
+        ] {
+            assert_eq!(cursor.to_string().parse::<ExportCursor>().unwrap(), cursor);
+        }
+        for invalid in ["", "x:ab", "9:ab", "1:é"] {
+            assert!(invalid.parse::<ExportCursor>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_groups_skip_markers_and_legacy_keys() {
+        let keys = [
+            "idx:kw:a:0",
+            "idx:kw:a:3",
+            "idx:kw:b:resharding",
+            "idx:kw:b:1",
+            "idx:kw:http://x:2",
+            "idx:kw:http%3A//x:5",
+            "idx:kw:junk",
+        ];
+        let groups = group_by_keyword("idx", keys.map(String::from).to_vec(), None);
+        let summary: Vec<(&str, usize, bool)> = groups
+            .iter()
+            .map(|group| (group.keyword.as_str(), group.shards.len(), group.relist))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![("a", 2, false), ("b", 1, true), ("http://x", 1, true)]
+        );
+        let after_a = group_by_keyword("idx", keys.map(String::from).to_vec(), Some("a"));
+        assert_eq!(after_a[0].keyword, "b");
+    }
+
+    #[test]
+    fn test_resharding_keywords_count_staged_shards() {
+        let store = MemoryStorage::default();
+        let docs: Vec<String> = (0..20).map(|i| format!("doc{}", i)).collect();
+        let docs: Vec<(&str, f64)> = docs.iter().map(|id| (id.as_str(), 0.5)).collect();
+        seed_postings(&store, "idx", N_SHARDS, "ocean", &docs);
+        seed_postings(&store, "idx", N_SHARDS, "tide", &docs[..1]);
+
+        // Halfway through a reshard, only one shard was staged so far
+        let shards = block_on(list_keyword_shards(&store, "idx", "ocean")).unwrap();
+        assert!(shards.len() > 1);
+        let raw = block_on(store.get(&shards[0])).unwrap().unwrap();
+        block_on(store.put(&staged_shard_kv_key("idx", "ocean", 0), raw)).unwrap();
+        block_on(store.put(&reshard_marker_key("idx", "ocean"), "{}".into())).unwrap();
+        let staged = block_on(list_keyword_shards(&store, "idx", "ocean")).unwrap();
+        let bulk_reader = BulkReader::new(N_SHARDS, &store, None);
+        let staged_docs = block_on(bulk_reader.get_keyword_kv_keys(vec![&staged[0]]))[0]
+            .docs
+            .len();
+
+        let (exported, _, _) = walk(&store, ExportCursor::default(), 10);
+        let counts: Vec<(&str, u32, u64)> = exported
+            .iter()
+            .map(|kw| (kw.keyword.as_str(), kw.shards, kw.doc_count))
+            .collect();
+        assert_eq!(
+            counts,
+            vec![("ocean", 1, staged_docs as u64), ("tide", 1, 1)]
+        );
+    }
+}
@@ -0,0 +1,713 @@
+//! Checking an index for postings left inconsistent by partially failed writes, and
+//! optionally repairing them. The walk covers every document and then every keyword
+//! shard, a bounded batch per call, so large indexes are checked over several
+//! requests by passing back the returned cursor.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::str::FromStr;
+
+use serde::Serialize;
+
+use crate::data::{
+    bulk::BulkReader,
+    codec::CodecSet,
+    deletion::{resume_deletions, DeleteOptions},
+    document::{document_kv_key, shard_from_document_id},
+    keyword_shard::{
+        keyword_shard_kv_key, legacy_keyword_shard_prefix, parse_keyword_shard_key,
+        KeywordShardData, ShardWriteBatch,
+    },
+    storage::Storage,
+    DataStoreError, KvEntry, KvPersistent, PREFIX_DOCUMENT, PREFIX_KEYWORD, PREFIX_KEYWORD_TOP,
+};
+
+/// The most documents or keyword shards checked per call, keeping each request well
+/// under the KV operation limit
+pub const FSCK_BATCH_SIZE: usize = 50;
+
+pub const DEFAULT_FSCK_EXAMPLES: usize = 10;
+pub const MAX_FSCK_EXAMPLES: usize = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FsckPhase {
+    Documents,
+    Shards,
+}
+
+/// Where to resume a check: the phase, the KV listing page within it, and how many
+/// keys of that page were already checked
+#[derive(Debug, Clone, PartialEq)]
+pub struct FsckCursor {
+    pub phase: FsckPhase,
+    pub offset: usize,
+    pub page: Option<String>,
+}
+
+impl FsckCursor {
+    pub fn start() -> FsckCursor {
+        FsckCursor {
+            phase: FsckPhase::Documents,
+            offset: 0,
+            page: None,
+        }
+    }
+}
+
+impl fmt::Display for FsckCursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let phase = match self.phase {
+            FsckPhase::Documents => "d",
+            FsckPhase::Shards => "s",
+        };
+        let page = self.page.as_deref().unwrap_or("");
+        write!(f, "{}:{}:{}", phase, self.offset, page)
+    }
+}
+
+impl FromStr for FsckCursor {
+    type Err = String;
+
+    fn from_str(cursor: &str) -> Result<FsckCursor, String> {
+        let invalid = || format!("Invalid fsck cursor '{}'", cursor);
+        let mut parts = cursor.splitn(3, ':');
+        let phase = match parts.next() {
+            Some("d") => FsckPhase::Documents,
+            Some("s") => FsckPhase::Shards,
+            _ => return Err(invalid()),
+        };
+        let offset = parts
+            .next()
+            .and_then(|offset| offset.parse().ok())
+            .ok_or_else(invalid)?;
+        let page = parts.next().ok_or_else(invalid)?;
+        Ok(FsckCursor {
+            phase,
+            offset,
+            page: (!page.is_empty()).then(|| page.to_string()),
+        })
+    }
+}
+
+/// A document's posting within a keyword shard
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct PostingKey {
+    pub shard_key: String,
+    pub doc_id: String,
+}
+
+/// How many problems of one kind were found, with the first few as examples
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct FsckFinding<T> {
+    pub count: u32,
+    pub examples: Vec<T>,
+    #[serde(skip)]
+    max_examples: usize,
+}
+
+impl<T> FsckFinding<T> {
+    fn new(max_examples: usize) -> FsckFinding<T> {
+        FsckFinding {
+            count: 0,
+            examples: vec![],
+            max_examples,
+        }
+    }
+
+    fn record(&mut self, example: T) {
+        self.count += 1;
+        if self.examples.len() < self.max_examples {
+            self.examples.push(example);
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct FsckReport {
+    pub checked_documents: u32,
+    pub checked_shards: u32,
+    /// Postings in a shard whose document doesn't exist
+    pub orphan_postings: FsckFinding<PostingKey>,
+    /// Keywords stored on a document that are absent from its shard
+    pub missing_postings: FsckFinding<PostingKey>,
+    /// Shards without any postings
+    pub empty_shards: FsckFinding<String>,
+    /// Shards stored under a key that doesn't escape their keyword, written before
+    /// keywords were escaped in keys. `repair` moves them to the escaped key.
+    pub legacy_keys: FsckFinding<String>,
+    /// Documents whose delete stopped part way, found by the first call of a check.
+    /// `repair` finishes deleting them, see [`crate::data::deletion`].
+    pub pending_deletions: FsckFinding<String>,
+    /// The number of problems fixed, with `repair`
+    pub repaired: u32,
+    /// Pass back to continue the check, `None` once every key has been checked
+    pub cursor: Option<String>,
+}
+
+impl FsckReport {
+    fn new(max_examples: usize) -> FsckReport {
+        FsckReport {
+            checked_documents: 0,
+            checked_shards: 0,
+            orphan_postings: FsckFinding::new(max_examples),
+            missing_postings: FsckFinding::new(max_examples),
+            empty_shards: FsckFinding::new(max_examples),
+            legacy_keys: FsckFinding::new(max_examples),
+            pending_deletions: FsckFinding::new(max_examples),
+            repaired: 0,
+            cursor: None,
+        }
+    }
+}
+
+pub struct FsckOptions {
+    pub repair: bool,
+    pub max_examples: usize,
+    pub n_shards: u32,
+    /// The codecs of the index, which repaired shards are written with
+    pub codecs: CodecSet,
+    pub now: u64,
+}
+
+/// Check the next batch of documents or shards of `index` after `cursor`
+pub async fn fsck_batch<S: Storage>(
+    index: &str,
+    store: &S,
+    bulk_reader: &BulkReader<'_, S>,
+    cursor: FsckCursor,
+    options: &FsckOptions,
+) -> Result<FsckReport, DataStoreError> {
+    let mut report = FsckReport::new(options.max_examples);
+    // Finished before any document is checked, so none is checked half deleted
+    if cursor == FsckCursor::start() {
+        let delete_options = DeleteOptions {
+            n_shards: options.n_shards,
+            codecs: options.codecs,
+            now: options.now,
+        };
+        for doc_id in resume_deletions(store, index, &delete_options, options.repair).await? {
+            report.pending_deletions.record(doc_id);
+            if options.repair {
+                report.repaired += 1;
+            }
+        }
+    }
+
+    let prefix = match cursor.phase {
+        FsckPhase::Documents => format!("{}:{}", index, PREFIX_DOCUMENT),
+        FsckPhase::Shards => format!("{}:{}", index, PREFIX_KEYWORD),
+    };
+    let page = store.list(&prefix, cursor.page.clone()).await?;
+    let keys: Vec<&str> = page
+        .keys
+        .iter()
+        .skip(cursor.offset)
+        .take(FSCK_BATCH_SIZE)
+        .map(String::as_str)
+        .collect();
+    let checked = cursor.offset + keys.len();
+
+    match cursor.phase {
+        FsckPhase::Documents => {
+            check_documents(index, store, bulk_reader, keys, options, &mut report).await
+        }
+        FsckPhase::Shards => {
+            check_shards(index, store, bulk_reader, keys, options, &mut report).await
+        }
+    }?;
+
+    // Stay on this listing page until all of it is checked, then move on to the next
+    // page, and from the last document page to the first shard page
+    let next = if checked < page.keys.len() {
+        Some(FsckCursor {
+            offset: checked,
+            ..cursor
+        })
+    } else if page.cursor.is_some() {
+        Some(FsckCursor {
+            offset: 0,
+            page: page.cursor,
+            ..cursor
+        })
+    } else if cursor.phase == FsckPhase::Documents {
+        Some(FsckCursor {
+            phase: FsckPhase::Shards,
+            offset: 0,
+            page: None,
+        })
+    } else {
+        None
+    };
+    report.cursor = next.map(|cursor| cursor.to_string());
+    Ok(report)
+}
+
+/// Find the keywords of each document that are absent from its shard, re-adding them
+/// from the document's stored keywords with `repair`
+async fn check_documents<S: Storage>(
+    index: &str,
+    store: &S,
+    bulk_reader: &BulkReader<'_, S>,
+    doc_keys: Vec<&str>,
+    options: &FsckOptions,
+    report: &mut FsckReport,
+) -> Result<(), DataStoreError> {
+    let documents: Vec<_> = bulk_reader
+        .get_documents_kv_keys(doc_keys)
+        .await
+        .into_iter()
+        .flatten()
+        .collect();
+    report.checked_documents = documents.len() as u32;
+
+    let mut expected: Vec<(String, String, String, f64)> = vec![];
+    let mut legacy_keys: Vec<String> = vec![];
+    for document in &documents {
+        let doc_id = document.get_uuid();
+        let shard = shard_from_document_id(doc_id.clone(), options.n_shards);
+        for (keyword, score) in document.keywords.iter().flatten() {
+            let shard_key = keyword_shard_kv_key(index, keyword, shard);
+            expected.push((shard_key, doc_id.clone(), keyword.clone(), score.score));
+            if let Some(prefix) = legacy_keyword_shard_prefix(index, keyword) {
+                legacy_keys.push(format!("{}{}", prefix, shard));
+            }
+        }
+    }
+
+    // A posting still under a legacy key is reported by the shard walk instead
+    let shard_keys: Vec<&str> = expected
+        .iter()
+        .map(|(key, ..)| key.as_str())
+        .chain(legacy_keys.iter().map(String::as_str))
+        .collect();
+    let postings: HashSet<(String, String)> = bulk_reader
+        .get_keyword_kv_keys(shard_keys)
+        .await
+        .into_iter()
+        .flat_map(|shard| {
+            let shard_key = shard.get_kv_key();
+            shard
+                .docs
+                .into_iter()
+                .map(move |(id, _)| (shard_key.clone(), id))
+        })
+        .collect();
+
+    let mut repairs: HashMap<String, ShardWriteBatch> = HashMap::new();
+    for (shard_key, doc_id, keyword, score) in expected {
+        if postings.contains(&(shard_key.clone(), doc_id.clone())) {
+            continue;
+        }
+        report.missing_postings.record(PostingKey {
+            shard_key,
+            doc_id: doc_id.clone(),
+        });
+        if options.repair {
+            repairs
+                .entry(doc_id.clone())
+                .or_insert_with(|| {
+                    ShardWriteBatch::new(index, &doc_id, options.n_shards)
+                        .with_codecs(options.codecs)
+                })
+                .upsert(&keyword, score);
+        }
+    }
+
+    for batch in repairs.values() {
+        for (_, result) in batch.execute(store, options.now).await {
+            result?;
+            report.repaired += 1;
+        }
+    }
+    Ok(())
+}
+
+/// Find empty shards and postings of documents that no longer exist, deleting them
+/// with `repair`
+async fn check_shards<S: Storage>(
+    index: &str,
+    store: &S,
+    bulk_reader: &BulkReader<'_, S>,
+    shard_keys: Vec<&str>,
+    options: &FsckOptions,
+    report: &mut FsckReport,
+) -> Result<(), DataStoreError> {
+    let (legacy_keys, shard_keys): (Vec<&str>, Vec<&str>) = shard_keys
+        .into_iter()
+        .partition(|key| is_legacy_shard_key(index, key));
+    for legacy_key in legacy_keys {
+        report.checked_shards += 1;
+        report.legacy_keys.record(legacy_key.to_string());
+        if options.repair && migrate_legacy_shard(index, store, legacy_key, options).await? {
+            report.repaired += 1;
+        }
+    }
+
+    let shards = bulk_reader.get_keyword_kv_keys(shard_keys).await;
+    report.checked_shards += shards.len() as u32;
+
+    let doc_ids: Vec<&String> = shards
+        .iter()
+        .flat_map(|shard| shard.docs.iter().map(|(id, _)| id))
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect();
+    let doc_keys: Vec<String> = doc_ids
+        .iter()
+        .map(|id| document_kv_key(index, id))
+        .collect();
+    let existing: HashSet<&String> = doc_ids
+        .iter()
+        .zip(
+            bulk_reader
+                .get_documents_kv_keys(doc_keys.iter().map(String::as_str).collect())
+                .await,
+        )
+        .filter_map(|(id, document)| document.map(|_| *id))
+        .collect();
+
+    for mut shard in shards.iter().cloned() {
+        let shard_key = shard.get_kv_key();
+        if shard.docs.is_empty() {
+            report.empty_shards.record(shard_key.clone());
+            if options.repair {
+                shard.delete(store).await?;
+                report.repaired += 1;
+            }
+            continue;
+        }
+
+        let orphans: Vec<String> = shard
+            .docs
+            .iter()
+            .filter(|(id, _)| !existing.contains(id))
+            .map(|(id, _)| id.clone())
+            .collect();
+        for doc_id in &orphans {
+            report.orphan_postings.record(PostingKey {
+                shard_key: shard_key.clone(),
+                doc_id: doc_id.clone(),
+            });
+        }
+        if !options.repair || orphans.is_empty() {
+            continue;
+        }
+        for doc_id in &orphans {
+            shard.apply_remove(doc_id, options.now);
+        }
+        match shard.docs.is_empty() {
+            true => shard.delete(store).await?,
+            false => shard.save(store, options.codecs).await?,
+        }
+        report.repaired += orphans.len() as u32;
+    }
+    Ok(())
+}
+
+/// Whether a shard key predates keyword escaping, so it differs from the key its
+/// keyword and shard number are stored under now
+fn is_legacy_shard_key(index: &str, key: &str) -> bool {
+    parse_keyword_shard_key(index, key)
+        .is_some_and(|(keyword, shard)| keyword_shard_kv_key(index, &keyword, shard) != key)
+}
+
+/// Move the postings of a shard under a legacy key into the shard under its escaped
+/// key, keeping the escaped shard's score where both have a document, then delete
+/// the legacy shard and its summary. Returns whether a legacy shard was moved.
+async fn migrate_legacy_shard<S: Storage>(
+    index: &str,
+    store: &S,
+    legacy_key: &str,
+    options: &FsckOptions,
+) -> Result<bool, DataStoreError> {
+    let (codecs, now) = (options.codecs, options.now);
+    let legacy = match KeywordShardData::read_with(legacy_key, store, codecs).await {
+        Ok(legacy) => legacy,
+        Err(DataStoreError::NotFound(_)) => return Ok(false),
+        Err(err) => return Err(err),
+    };
+    let existing =
+        KeywordShardData::load(store, codecs, index, &legacy.keyword, legacy.shard).await?;
+    let mut shard = existing.unwrap_or_else(|| {
+        KeywordShardData::new(
+            index.into(),
+            legacy.keyword.clone(),
+            legacy.shard,
+            now,
+            vec![],
+        )
+    });
+    let mut changed = false;
+    for (doc_id, score) in &legacy.docs {
+        if !shard.docs.iter().any(|(id, _)| id == doc_id) {
+            changed |= shard.apply_upsert(doc_id, *score, now);
+        }
+    }
+    if changed {
+        shard.save(store, codecs).await?;
+    }
+    store.delete(legacy_key).await?;
+    let legacy_top_key = format!(
+        "{}:{}{}:{}",
+        index, PREFIX_KEYWORD_TOP, legacy.keyword, legacy.shard
+    );
+    store.delete(&legacy_top_key).await?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+
+    use super::*;
+    use crate::data::{
+        deletion::{delete_document, DELETION_RESUME_AFTER_MS},
+        document::{testing::index_text, Document},
+        keyword_shard::testing::{seed_postings, write_legacy_shard},
+        storage::memory::MemoryStorage,
+        KvPersistent, DEFAULT_N_SHARDS,
+    };
+
+    fn options(repair: bool) -> FsckOptions {
+        FsckOptions {
+            repair,
+            max_examples: DEFAULT_FSCK_EXAMPLES,
+            n_shards: DEFAULT_N_SHARDS,
+            now: DELETION_RESUME_AFTER_MS,
+            codecs: CodecSet::V1,
+        }
+    }
+
+    /// Run a whole check, following cursors, and sum up the reports
+    fn fsck(store: &MemoryStorage, repair: bool) -> (FsckReport, usize) {
+        let bulk_reader = BulkReader::new(DEFAULT_N_SHARDS, store, None);
+        let mut total = FsckReport::new(DEFAULT_FSCK_EXAMPLES);
+        let mut cursor = Some(FsckCursor::start());
+        let mut calls = 0;
+        while let Some(next) = cursor {
+            let report = block_on(fsck_batch(
+                "idx",
+                store,
+                &bulk_reader,
+                next,
+                &options(repair),
+            ))
+            .unwrap();
+            total.checked_documents += report.checked_documents;
+            total.checked_shards += report.checked_shards;
+            total.orphan_postings.count += report.orphan_postings.count;
+            total
+                .orphan_postings
+                .examples
+                .extend(report.orphan_postings.examples);
+            total.missing_postings.count += report.missing_postings.count;
+            total
+                .missing_postings
+                .examples
+                .extend(report.missing_postings.examples);
+            total.legacy_keys.count += report.legacy_keys.count;
+            total
+                .legacy_keys
+                .examples
+                .extend(report.legacy_keys.examples);
+            total.empty_shards.count += report.empty_shards.count;
+            total
+                .empty_shards
+                .examples
+                .extend(report.empty_shards.examples);
+            total.pending_deletions.count += report.pending_deletions.count;
+            total
+                .pending_deletions
+                .examples
+                .extend(report.pending_deletions.examples);
+            total.repaired += report.repaired;
+            cursor = report.cursor.map(|cursor| cursor.parse().unwrap());
+            calls += 1;
+        }
+        (total, calls)
+    }
+
+    fn shard_key(doc_id: &str, keyword: &str) -> String {
+        let shard = shard_from_document_id(doc_id.to_string(), DEFAULT_N_SHARDS);
+        keyword_shard_kv_key("idx", keyword, shard)
+    }
+
+    /// Two indexed documents, with one posting dropped, one posting for a deleted
+    /// document, and an empty shard
+    fn inconsistent_index(store: &MemoryStorage) -> (Document, String) {
+        let ocean = index_text(store, "idx", "doc1", "Ocean tides wash the sandy beach.");
+        index_text(
+            store,
+            "idx",
+            "doc2",
+            "Volcanic ash covers the mountain village.",
+        );
+        let dropped = ocean.keywords.clone().unwrap()[0].0.clone();
+        block_on(store.delete(&shard_key("doc1", &dropped))).unwrap();
+
+        seed_postings(store, "idx", DEFAULT_N_SHARDS, "glacier", &[("ghost", 0.4)]);
+        let mut empty = KeywordShardData::new("idx".into(), "desert".into(), 3, 1, vec![]);
+        block_on(empty.write(store)).unwrap();
+        (ocean, dropped)
+    }
+
+    #[test]
+    fn test_cursor_round_trips() {
+        for cursor in [
+            FsckCursor::start(),
+            FsckCursor {
+                phase: FsckPhase::Shards,
+                offset: 7,
+                page: Some("idx:kw:ocean:3".into()),
+            },
+        ] {
+            assert_eq!(cursor.to_string().parse::<FsckCursor>(), Ok(cursor));
+        }
+        assert!("x:0:".parse::<FsckCursor>().is_err());
+        assert!("d:zero:".parse::<FsckCursor>().is_err());
+        assert!("d:0".parse::<FsckCursor>().is_err());
+    }
+
+    #[test]
+    fn test_consistent_index() {
+        let store = MemoryStorage::default();
+        index_text(&store, "idx", "doc1", "Ocean tides wash the sandy beach.");
+        let (report, _) = fsck(&store, false);
+        assert_eq!(report.checked_documents, 1);
+        assert!(report.checked_shards > 0);
+        assert_eq!(report.orphan_postings.count, 0);
+        assert_eq!(report.missing_postings.count, 0);
+        assert_eq!(report.empty_shards.count, 0);
+    }
+
+    #[test]
+    fn test_reports_inconsistencies() {
+        let store = MemoryStorage::default();
+        let (_, dropped) = inconsistent_index(&store);
+        let keys_before = store.keys();
+
+        let (report, calls) = fsck(&store, false);
+        // The small listing pages of the memory store take several calls to walk
+        assert!(calls > 2);
+        assert_eq!(report.checked_documents, 2);
+        assert_eq!(
+            report.missing_postings.examples,
+            vec![PostingKey {
+                shard_key: shard_key("doc1", &dropped),
+                doc_id: "doc1".into()
+            }]
+        );
+        assert_eq!(
+            report.orphan_postings.examples,
+            vec![PostingKey {
+                shard_key: shard_key("ghost", "glacier"),
+                doc_id: "ghost".into()
+            }]
+        );
+        assert_eq!(report.empty_shards.examples, vec!["idx:kw:desert:3"]);
+        assert_eq!(report.repaired, 0);
+        assert_eq!(store.keys(), keys_before);
+    }
+
+    #[test]
+    fn test_repair_fixes_inconsistencies() {
+        let store = MemoryStorage::default();
+        let (ocean, dropped) = inconsistent_index(&store);
+
+        let (report, _) = fsck(&store, true);
+        assert_eq!(report.repaired, 3);
+
+        let restored = block_on(KeywordShardData::read(&shard_key("doc1", &dropped), &store));
+        let score = ocean.keywords.unwrap()[0].1.score;
+        assert_eq!(restored.unwrap().docs, vec![("doc1".to_string(), score)]);
+        assert!(!store.keys().contains(&shard_key("ghost", "glacier")));
+        assert!(!store.keys().contains(&"idx:kw:desert:3".to_string()));
+
+        let (report, _) = fsck(&store, false);
+        assert_eq!(report.missing_postings.count, 0);
+        assert_eq!(report.orphan_postings.count, 0);
+        assert_eq!(report.empty_shards.count, 0);
+    }
+
+    #[test]
+    fn test_repair_finishes_stalled_deletes() {
+        let store = MemoryStorage::default();
+        let ocean = index_text(&store, "idx", "doc1", "Ocean tides wash the sandy beach.");
+        index_text(&store, "idx", "doc2", "Ocean waves roll onto the shore.");
+        store.fail_deletes("idx:document:doc1", 1);
+        let started = DeleteOptions {
+            n_shards: DEFAULT_N_SHARDS,
+            codecs: CodecSet::V1,
+            now: 0,
+        };
+        assert!(block_on(delete_document(&store, &ocean, &started)).is_err());
+
+        // The document is still stored, but its postings are already gone
+        let (report, _) = fsck(&store, false);
+        assert_eq!(report.pending_deletions.examples, vec!["doc1".to_string()]);
+        assert!(report.missing_postings.count > 0);
+
+        let (report, _) = fsck(&store, true);
+        // Stripping the document's postings left its shards empty
+        assert_eq!(report.pending_deletions.count, 1);
+        assert_eq!(report.repaired, 1 + report.empty_shards.count);
+        assert!(block_on(Document::from_remote(&store, "idx", "doc1".into())).is_err());
+
+        let (report, _) = fsck(&store, false);
+        assert_eq!(report.pending_deletions.count, 0);
+        assert_eq!(report.missing_postings.count, 0);
+        assert_eq!(report.orphan_postings.count, 0);
+        assert_eq!(report.checked_documents, 1);
+    }
+
+    #[test]
+    fn test_legacy_keys_are_migrated() {
+        let store = MemoryStorage::default();
+        let mut document = Document::new_with_id("idx", "doc1");
+        document.keywords = Some(vec![("http://example".into(), 0.6.into())]);
+        block_on(document.write(&store)).unwrap();
+        let shard = shard_from_document_id("doc1".into(), DEFAULT_N_SHARDS);
+        let legacy = write_legacy_shard(&store, "idx", "http://example", shard, &[("doc1", 0.6)]);
+
+        // The posting is found under the legacy key, so it isn't reported missing
+        let (report, _) = fsck(&store, false);
+        assert_eq!(report.legacy_keys.examples, vec![legacy.clone()]);
+        assert_eq!(report.missing_postings.count, 0);
+        assert_eq!(report.orphan_postings.count, 0);
+
+        let (report, _) = fsck(&store, true);
+        assert_eq!(report.repaired, 1);
+        assert!(!store.keys().contains(&legacy));
+        let migrated = block_on(KeywordShardData::read(
+            &shard_key("doc1", "http://example"),
+            &store,
+        ));
+        assert_eq!(migrated.unwrap().docs, vec![("doc1".to_string(), 0.6)]);
+
+        let (report, _) = fsck(&store, false);
+        assert_eq!(report.legacy_keys.count, 0);
+        assert_eq!(report.missing_postings.count, 0);
+    }
+
+    #[test]
+    fn test_examples_are_capped() {
+        let store = MemoryStorage::default();
+        let ghosts = (0..5).map(|i| (format!("ghost{}", i), 0.1)).collect();
+        let mut shard = KeywordShardData::new("idx".into(), "glacier".into(), 3, 1, ghosts);
+        block_on(shard.write(&store)).unwrap();
+
+        let bulk_reader = BulkReader::new(DEFAULT_N_SHARDS, &store, None);
+        let options = FsckOptions {
+            max_examples: 2,
+            ..options(false)
+        };
+        let cursor = FsckCursor {
+            phase: FsckPhase::Shards,
+            offset: 0,
+            page: None,
+        };
+        let report = block_on(fsck_batch("idx", &store, &bulk_reader, cursor, &options)).unwrap();
+        assert_eq!(report.orphan_postings.count, 5);
+        assert_eq!(report.orphan_postings.examples.len(), 2);
+    }
+}
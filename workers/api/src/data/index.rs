@@ -1,15 +1,22 @@
 use std::collections::HashMap;
 
+use lingua::IsoCode639_1;
 use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
-use worker::kv::KvStore;
 
-use crate::data::{DataStoreError, IndexName, KvEntry, KvPersistent, PREFIX_INDEX};
+use crate::{
+    data::{
+        reshard::ReshardState, IndexName, KvEntry, KvPersistent, INDEX_VERSION_LATEST,
+        INDEX_VERSION_V1, PREFIX_INDEX,
+    },
+    lexer::{extractor::Extractor, scoring::ScoringMode},
+};
 
 static RESERVED_INDEXES: Lazy<HashMap<&'static str, &'static str>> = Lazy::new(|| {
     let mut m = HashMap::new();
     m.insert("indexes", "Reserved for EdgeSearch system use");
     m.insert("_internal", "Internal service index");
+    m.insert("_templates", "Reserved for index templates");
     m
 });
 
@@ -17,18 +24,338 @@ static RESERVED_INDEXES: Lazy<HashMap<&'static str, &'static str>> = Lazy::new(|
 pub struct IndexDocument {
     pub index: IndexName,
     pub docs_count: u32,
+    /// Which [`crate::data::codec::CodecSet`] the index's records are stored with
     pub version: u8,
     pub created: u64,
+    /// Bumped every time the index document is rewritten after its creation
+    #[serde(default)]
+    pub generation: u64,
+    /// The language given to documents whose language couldn't be detected confidently
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default_lang: Option<IsoCode639_1>,
+    /// Defaults for searches of this index, set with the body of `PUT /:index`
+    #[serde(default, skip_serializing_if = "IndexSettings::is_empty")]
+    pub settings: IndexSettings,
+    /// Set with `POST /:index/freeze` to reject document writes, during a migration
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub frozen: bool,
+    /// Milliseconds since the epoch when the index was last frozen. Indexes frozen
+    /// before the time was kept have none.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub frozen_at: Option<u64>,
+    /// The number of shards documents are written to: the `N_SHARDS` the index was
+    /// created with, or the count a reshard moved it to. Older indexes record none
+    /// until they're resharded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub n_shards: Option<u32>,
+    /// The reshard in progress, started with `POST /:index/reshard`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reshard: Option<ReshardState>,
+    /// The template the index's settings and stop-list were taken from when it was
+    /// created without settings of its own
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub template: Option<String>,
+    /// The version `POST /:index/upgrade` is moving the index to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upgrading_to: Option<u8>,
+    /// What the index is for, set with `PUT /:index` or `PATCH /:index`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// Operational labels like `env: prod`, which `GET /indexes?label=` filters on
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub labels: HashMap<String, String>,
+}
+
+/// An index's description and labels, which say what it is for without changing how
+/// it is searched. Fields left out are left alone when applied to an index; an empty
+/// description or label map clears it.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct IndexMetadata {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub labels: Option<HashMap<String, String>>,
+}
+
+impl IndexMetadata {
+    /// The most labels an index can have
+    pub const MAX_LABELS: usize = 16;
+    /// The most characters in a label's name or value
+    pub const MAX_LABEL_LENGTH: usize = 64;
+    /// The most characters in a description
+    pub const MAX_DESCRIPTION_LENGTH: usize = 1024;
+
+    /// The fields of a `PUT /:index` body that are metadata rather than settings, see
+    /// [`parse_index_body`]
+    const FIELDS: [&'static str; 2] = ["description", "labels"];
+
+    pub fn is_empty(&self) -> bool {
+        *self == IndexMetadata::default()
+    }
+
+    /// Parse metadata from the JSON body of `PATCH /:index`
+    pub fn parse(body: &str) -> Result<IndexMetadata, String> {
+        let metadata: IndexMetadata =
+            serde_json::from_str(body).map_err(|err| format!("Invalid index metadata: {}", err))?;
+        metadata.validate()?;
+        Ok(metadata)
+    }
+
+    /// Reject more than [`Self::MAX_LABELS`] labels, label names and values longer
+    /// than [`Self::MAX_LABEL_LENGTH`], and empty label names or ones holding the `:`
+    /// that separates a label filter's name from its value
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(description) = &self.description {
+            if description.chars().count() > Self::MAX_DESCRIPTION_LENGTH {
+                return Err(format!(
+                    "description must be at most {} characters",
+                    Self::MAX_DESCRIPTION_LENGTH
+                ));
+            }
+        }
+        let Some(labels) = &self.labels else {
+            return Ok(());
+        };
+        if labels.len() > Self::MAX_LABELS {
+            return Err(format!(
+                "An index can have at most {} labels, got {}",
+                Self::MAX_LABELS,
+                labels.len()
+            ));
+        }
+        for (name, value) in labels {
+            if name.is_empty() || name.contains(':') {
+                return Err(format!(
+                    "Label names must be non-empty and can't contain ':', got '{}'",
+                    name
+                ));
+            }
+            if name.chars().count() > Self::MAX_LABEL_LENGTH
+                || value.chars().count() > Self::MAX_LABEL_LENGTH
+            {
+                return Err(format!(
+                    "Label names and values must be at most {} characters, '{}' is longer",
+                    Self::MAX_LABEL_LENGTH,
+                    name
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Split the JSON body of `PUT /:index` into the index's settings and its metadata.
+/// A body holding only metadata leaves the settings alone, which is `None`.
+pub fn parse_index_body(body: &str) -> Result<(Option<IndexSettings>, IndexMetadata), String> {
+    let Ok(serde_json::Value::Object(mut fields)) = serde_json::from_str(body) else {
+        // Reported the way settings always were
+        return IndexSettings::parse(body)
+            .map(|settings| (Some(settings), IndexMetadata::default()));
+    };
+    let mut metadata = serde_json::Map::new();
+    for field in IndexMetadata::FIELDS {
+        if let Some(value) = fields.remove(field) {
+            metadata.insert(field.to_string(), value);
+        }
+    }
+    let metadata = match metadata.is_empty() {
+        true => IndexMetadata::default(),
+        false => IndexMetadata::parse(&serde_json::Value::Object(metadata).to_string())?,
+    };
+    if fields.is_empty() && !metadata.is_empty() {
+        return Ok((None, metadata));
+    }
+    let settings = IndexSettings::parse(&serde_json::Value::Object(fields).to_string())?;
+    Ok((Some(settings), metadata))
+}
+
+/// Parse a `label=name:value` filter of `GET /indexes`
+pub fn parse_label_filter(filter: &str) -> Result<(String, String), String> {
+    match filter.split_once(':') {
+        Some((name, value)) if !name.is_empty() => Ok((name.to_string(), value.to_string())),
+        _ => Err(format!(
+            "label must be given as name:value, got '{}'",
+            filter
+        )),
+    }
+}
+
+/// Search options applied to searches of an index that leave them out, and how the
+/// index's documents are scored when they are written
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct IndexSettings {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub full: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limit: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scoring: Option<ScoringMode>,
+    /// Decay keywords that first appear late in a document by this much, or leave
+    /// YAKE's scores alone when 0 or unset. Applies to documents written afterwards.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub position_boost: Option<f64>,
+    /// What the scores of keywords from the titles of documents written afterwards
+    /// are multiplied by, 1.5 when unset. Boosted scores are capped at 1.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title_boost: Option<f64>,
+    /// Keywords of documents written afterwards scoring below this aren't indexed,
+    /// saving a shard write each on keywords too weak to rank. 0 keeps every keyword.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_keyword_score: Option<f64>,
+    /// How keywords are picked from documents written afterwards, YAKE by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub extractor: Option<Extractor>,
+    /// The version a new index is created at, the latest by default. An existing
+    /// index keeps its version until it's upgraded, so this isn't stored with the
+    /// index's settings.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<u8>,
+}
+
+impl IndexSettings {
+    /// The most matches a search can be limited to
+    pub const MAX_LIMIT: u32 = 1000;
+
+    pub fn is_empty(&self) -> bool {
+        *self == IndexSettings::default()
+    }
+
+    /// Parse settings from the JSON body of `PUT /:index`, rejecting unknown fields,
+    /// unknown scoring and extractor names, out of bounds limits and keyword floors,
+    /// and negative position or title boosts
+    pub fn parse(body: &str) -> Result<IndexSettings, String> {
+        let settings: IndexSettings = serde_json::from_str(body).map_err(|err| {
+            format!(
+                "Invalid index settings: {} (scoring must be one of {}, extractor one of {})",
+                err,
+                ScoringMode::NAMES.join(", "),
+                Extractor::NAMES.join(", ")
+            )
+        })?;
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    /// Reject out of bounds limits and keyword floors, negative position or title
+    /// boosts and unknown versions
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(version) = self.version {
+            if !(INDEX_VERSION_V1..=INDEX_VERSION_LATEST).contains(&version) {
+                return Err(format!(
+                    "version must be between {} and {}, got {}",
+                    INDEX_VERSION_V1, INDEX_VERSION_LATEST, version
+                ));
+            }
+        }
+        if let Some(limit) = self.limit {
+            check_limit(limit)?;
+        }
+        if let Some(boost) = self.position_boost {
+            if !boost.is_finite() || boost < 0.0 {
+                return Err(format!(
+                    "position_boost must be a non-negative number, got {}",
+                    boost
+                ));
+            }
+        }
+        if let Some(boost) = self.title_boost {
+            if !boost.is_finite() || boost < 0.0 {
+                return Err(format!(
+                    "title_boost must be a non-negative number, got {}",
+                    boost
+                ));
+            }
+        }
+        if let Some(floor) = self.min_keyword_score {
+            if !(0.0..=1.0).contains(&floor) {
+                return Err(format!(
+                    "min_keyword_score must be between 0 and 1, got {}",
+                    floor
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// An error naming the bounds when `limit` is outside of them
+pub fn check_limit(limit: u32) -> Result<u32, String> {
+    match (1..=IndexSettings::MAX_LIMIT).contains(&limit) {
+        true => Ok(limit),
+        false => Err(format!(
+            "limit must be between 1 and {}, got {}",
+            IndexSettings::MAX_LIMIT,
+            limit
+        )),
+    }
 }
 
 impl IndexDocument {
+    const MAX_NAME_LENGTH: usize = 48;
+    const MIN_NAME_LENGTH: usize = 1;
+
+    pub const INVALID_NAME_MESSAGE: &'static str =
+        "Invalid index name. Must be 1-48 characters matching [a-z0-9-_]+";
+
+    /// The number of shards the index's documents are written to, `default` being
+    /// the `N_SHARDS` of the worker
+    pub fn shard_count(&self, default: u32) -> u32 {
+        self.n_shards.unwrap_or(default)
+    }
+
+    /// The shard count every one of the index's shard keys is below, when it was
+    /// recorded on the index and no reshard is moving its shards. Indexes created
+    /// before shard counts were recorded, which were written with whatever `N_SHARDS`
+    /// was at the time, have none.
+    pub fn known_shard_count(&self) -> Option<u32> {
+        self.n_shards.filter(|_| self.reshard.is_none())
+    }
+
+    /// The version whose codecs the index is read and written with: the one it's
+    /// upgrading to, once an upgrade started
+    pub fn codec_version(&self) -> u8 {
+        self.upgrading_to.unwrap_or(self.version)
+    }
+
+    /// Apply the fields `metadata` sets, returning whether the index changed
+    pub fn apply_metadata(&mut self, metadata: IndexMetadata) -> bool {
+        let before = (self.description.clone(), self.labels.clone());
+        if let Some(description) = metadata.description {
+            self.description = Some(description).filter(|description| !description.is_empty());
+        }
+        if let Some(labels) = metadata.labels {
+            self.labels = labels;
+        }
+        (self.description.clone(), self.labels.clone()) != before
+    }
+
+    /// Whether the index has the label `name` set to `value`
+    pub fn has_label(&self, name: &str, value: &str) -> bool {
+        self.labels
+            .get(name)
+            .is_some_and(|labelled| labelled == value)
+    }
+
     pub fn is_reserved_index(index: &str) -> bool {
-        return RESERVED_INDEXES.contains_key(index);
+        RESERVED_INDEXES.contains_key(index)
+    }
+
+    /// Determine if the provided name is usable as an index name. Names are
+    /// restricted so that `{index}:{prefix}` KV keys can never collide, e.g. an
+    /// index named `foo:document` would otherwise shadow documents of `foo`.
+    pub fn is_valid_name(index: &str) -> bool {
+        index.len() <= Self::MAX_NAME_LENGTH
+            && index.len() >= Self::MIN_NAME_LENGTH
+            && index
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
     }
 }
 
 pub fn get_index_key(index: &str) -> IndexName {
-    return format!("{}{}", PREFIX_INDEX, index) as IndexName;
+    format!("{}{}", PREFIX_INDEX, index) as IndexName
 }
 
 impl KvEntry for IndexDocument {
@@ -39,27 +366,168 @@ impl KvEntry for IndexDocument {
     }
 }
 
-impl KvPersistent for IndexDocument {
-    async fn read(key: &str, store: &KvStore) -> Result<Self, DataStoreError> {
-        let result = store
-            .get(key)
-            .json::<IndexDocument>()
-            .await
-            .map_err(DataStoreError::Kv)?
-            .unwrap();
-        Ok(result)
-    }
-
-    async fn write(&mut self, store: &KvStore) -> Result<(), DataStoreError> {
-        store
-            .put(
-                self.get_kv_key().as_str(),
-                serde_json::to_string(self).unwrap().as_str(),
-            )
-            .map_err(DataStoreError::Kv)?
-            .execute()
-            .await
-            .map_err(DataStoreError::Kv)?;
-        Ok(())
+impl KvPersistent for IndexDocument {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::{document::document_kv_key, PREFIX_DOCUMENT};
+
+    #[test]
+    fn test_valid_index_names() {
+        assert!(IndexDocument::is_valid_name("sample"));
+        assert!(IndexDocument::is_valid_name("tenant-42_logs"));
+        assert!(IndexDocument::is_valid_name("a"));
+        assert!(IndexDocument::is_valid_name(&"a".repeat(48)));
+    }
+
+    #[test]
+    fn test_invalid_index_names() {
+        assert!(!IndexDocument::is_valid_name(""));
+        assert!(!IndexDocument::is_valid_name(&"a".repeat(49)));
+        assert!(!IndexDocument::is_valid_name("Sample"));
+        assert!(!IndexDocument::is_valid_name("has space"));
+        assert!(!IndexDocument::is_valid_name("foo:bar"));
+        assert!(!IndexDocument::is_valid_name("caf\u{e9}"));
+    }
+
+    #[test]
+    fn test_parse_settings() {
+        let settings =
+            IndexSettings::parse(r#"{"full":false,"limit":20,"scoring":"coverage"}"#).unwrap();
+        assert_eq!(
+            settings,
+            IndexSettings {
+                full: Some(false),
+                limit: Some(20),
+                scoring: Some(ScoringMode::Coverage),
+                position_boost: None,
+                title_boost: None,
+                min_keyword_score: None,
+                extractor: None,
+                version: None,
+            }
+        );
+        assert_eq!(
+            IndexSettings::parse(r#"{"extractor":"tf"}"#)
+                .unwrap()
+                .extractor,
+            Some(Extractor::Tf)
+        );
+        assert!(IndexSettings::parse("{}").unwrap().is_empty());
+        assert_eq!(
+            IndexSettings::parse(r#"{"version":1}"#).unwrap().version,
+            Some(1)
+        );
+        assert_eq!(
+            IndexSettings::parse(r#"{"title_boost":2}"#)
+                .unwrap()
+                .title_boost,
+            Some(2.0)
+        );
+        assert_eq!(
+            IndexSettings::parse(r#"{"min_keyword_score":0.1}"#)
+                .unwrap()
+                .min_keyword_score,
+            Some(0.1)
+        );
+
+        for invalid in [
+            r#"{"limit":0}"#,
+            r#"{"limit":1001}"#,
+            r#"{"scoring":"bm25"}"#,
+            r#"{"extractor":"rake"}"#,
+            r#"{"position_boost":-1}"#,
+            r#"{"title_boost":-0.5}"#,
+            r#"{"min_keyword_score":1.5}"#,
+            r#"{"min_keyword_score":-0.1}"#,
+            r#"{"version":0}"#,
+            r#"{"version":3}"#,
+            r#"{"sort":"asc"}"#,
+            "not json",
+        ] {
+            assert!(IndexSettings::parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_parse_metadata() {
+        let metadata =
+            IndexMetadata::parse(r#"{"description":"Docs","labels":{"env":"prod"}}"#).unwrap();
+        assert_eq!(metadata.description.as_deref(), Some("Docs"));
+        assert_eq!(metadata.labels.unwrap()["env"], "prod");
+        assert!(IndexMetadata::parse("{}").unwrap().is_empty());
+
+        let labels = |n: usize| {
+            let labels: HashMap<String, String> =
+                (0..n).map(|i| (format!("l{}", i), "v".into())).collect();
+            serde_json::json!({ "labels": labels }).to_string()
+        };
+        assert!(IndexMetadata::parse(&labels(IndexMetadata::MAX_LABELS)).is_ok());
+        let long = "x".repeat(IndexMetadata::MAX_LABEL_LENGTH + 1);
+        for invalid in [
+            labels(IndexMetadata::MAX_LABELS + 1),
+            format!(r#"{{"labels":{{"{}":"v"}}}}"#, long),
+            format!(r#"{{"labels":{{"env":"{}"}}}}"#, long),
+            r#"{"labels":{"":"v"}}"#.to_string(),
+            r#"{"labels":{"env:prod":"v"}}"#.to_string(),
+            r#"{"labels":{"env":3}}"#.to_string(),
+            format!(
+                r#"{{"description":"{}"}}"#,
+                "x".repeat(IndexMetadata::MAX_DESCRIPTION_LENGTH + 1)
+            ),
+            r#"{"limit":5}"#.to_string(),
+        ] {
+            assert!(IndexMetadata::parse(&invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_parse_index_body() {
+        let (settings, metadata) =
+            parse_index_body(r#"{"limit":5,"labels":{"team":"search"}}"#).unwrap();
+        assert_eq!(settings.unwrap().limit, Some(5));
+        assert_eq!(metadata.labels.unwrap()["team"], "search");
+
+        let (settings, metadata) = parse_index_body(r#"{"description":"Docs"}"#).unwrap();
+        assert!(settings.is_none() && !metadata.is_empty());
+        let (settings, metadata) = parse_index_body("{}").unwrap();
+        assert!(settings.unwrap().is_empty() && metadata.is_empty());
+
+        assert!(parse_index_body(r#"{"labels":{"a:b":"c"}}"#).is_err());
+        assert!(parse_index_body(r#"{"sort":"asc","description":"Docs"}"#).is_err());
+        assert!(parse_index_body("not json").is_err());
+    }
+
+    #[test]
+    fn test_parse_label_filter() {
+        assert_eq!(
+            parse_label_filter("env:prod").unwrap(),
+            ("env".to_string(), "prod".to_string())
+        );
+        assert_eq!(parse_label_filter("url:a:b").unwrap().1, "a:b");
+        assert!(parse_label_filter("env").is_err());
+        assert!(parse_label_filter(":prod").is_err());
+    }
+
+    #[test]
+    fn test_index_documents_without_metadata_still_read() {
+        let stored = r#"{"index":"old","docs_count":3,"version":1,"created":1}"#;
+        let index: IndexDocument = serde_json::from_str(stored).unwrap();
+        assert_eq!(index.description, None);
+        assert!(index.labels.is_empty());
+        // Nor are they written back with empty metadata
+        let written = serde_json::to_string(&index).unwrap();
+        assert!(!written.contains("description") && !written.contains("labels"));
+    }
+
+    #[test]
+    fn test_prefix_collision_name_rejected() {
+        // Documents of `foo` live under `foo:document:*`. An index named
+        // `foo:document` would store its own keys within that prefix.
+        let colliding = format!("foo:{}", PREFIX_DOCUMENT.trim_end_matches(':'));
+        let foo_doc_prefix = format!("foo:{}", PREFIX_DOCUMENT);
+        assert!(document_kv_key(&colliding, &"x".to_string()).starts_with(&foo_doc_prefix));
+        assert!(!IndexDocument::is_valid_name(&colliding));
     }
 }
@@ -1,37 +1,162 @@
-use std::sync::Arc;
+use std::{collections::HashMap, sync::Mutex};
 
-use worker::kv::KvStore;
+use lingua::IsoCode639_1;
+use once_cell::sync::Lazy;
 
 use crate::{
     data::{
-        index::{get_index_key, IndexDocument},
-        DataStoreError, INDEX_VERSION_V1, PREFIX_DOCUMENT, PREFIX_INDEX,
+        bulk::BulkReader,
+        codec::CodecSet,
+        index::{get_index_key, IndexDocument, IndexMetadata, IndexSettings},
+        stoplist::StopList,
+        storage::{list_all, Storage},
+        template::{best_match, list_templates},
+        DataStoreError, KvPersistent, INDEX_VERSION_LATEST, PREFIX_DOCUMENT, PREFIX_INDEX,
     },
     edge_log,
+    util::time::{worker_clock, SharedClock},
 };
 
-pub struct IndexManager<'a> {
-    store: &'a Arc<KvStore>,
+/// How long a successful index existence lookup is trusted within an isolate that
+/// doesn't read the index document again in the meantime
+const INDEX_EXISTS_TTL_MS: u64 = 30_000;
+
+/// When an index was created and its generation, which together name one revision
+/// of its index document, even across a delete and a recreate
+type IndexRevision = (u64, u64);
+
+/// What an isolate remembers of an index it found
+#[derive(Clone, Copy)]
+struct CachedIndex {
+    cached_at: u64,
+    revision: IndexRevision,
+    frozen: bool,
+    /// See [`IndexDocument::codec_version`]
+    codec_version: u8,
+}
+
+/// Memoized index existence checks: index name -> the revision of the index document
+/// last read, when, and whether the index was frozen and which codecs it used then.
+/// Every read of the index document replaces an entry of an older revision, and one
+/// that finds it missing drops it. Only positive results are cached so newly created
+/// indexes are visible immediately.
+static INDEX_EXISTS_CACHE: Lazy<Mutex<HashMap<String, CachedIndex>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn cached_index(index: &str, now: u64) -> Option<CachedIndex> {
+    let cache = INDEX_EXISTS_CACHE.lock().unwrap();
+    cache
+        .get(index)
+        .filter(|cached| now.saturating_sub(cached.cached_at) < INDEX_EXISTS_TTL_MS)
+        .copied()
 }
 
-impl<'a> IndexManager<'a> {
-    pub fn new(store: &'a Arc<KvStore>) -> IndexManager<'a> {
-        return IndexManager { store };
+fn remember_index_exists(
+    index: &str,
+    now: u64,
+    revision: IndexRevision,
+    frozen: bool,
+    codec_version: u8,
+) {
+    let mut cache = INDEX_EXISTS_CACHE.lock().unwrap();
+    // A slower read of an older revision doesn't undo what a newer one found
+    if cache
+        .get(index)
+        .is_some_and(|cached| cached.revision > revision)
+    {
+        return;
     }
+    let cached = CachedIndex {
+        cached_at: now,
+        revision,
+        frozen,
+        codec_version,
+    };
+    cache.insert(index.to_string(), cached);
+}
 
-    pub async fn list_indexes(&self) -> Result<Vec<String>, DataStoreError> {
-        let found_indexes = self
-            .store
-            .list()
-            .prefix(PREFIX_INDEX.into())
-            .execute()
-            .await
-            .map_err(DataStoreError::Kv)?;
+fn remember_index(index_doc: &IndexDocument, now: u64) {
+    remember_index_exists(
+        &index_doc.index,
+        now,
+        (index_doc.created, index_doc.generation),
+        index_doc.frozen,
+        index_doc.codec_version(),
+    );
+}
 
-        let indexes: Vec<String> = found_indexes
-            .keys
+/// How much longer, at `now`, another isolate may still take the frozen `index_doc`
+/// for writable from its cached record, `None` once none can. Migrations that copy
+/// the index's shards wait this out before they start, so no write lands behind
+/// them. An index frozen before the time was kept has been frozen long enough.
+pub fn freeze_settling_ms(index_doc: &IndexDocument, now: u64) -> Option<u64> {
+    let settled_at = index_doc.frozen_at? + INDEX_EXISTS_TTL_MS;
+    (now < settled_at).then(|| settled_at - now)
+}
+
+fn forget_index_exists(index: &str) {
+    let mut cache = INDEX_EXISTS_CACHE.lock().unwrap();
+    cache.remove(index);
+}
+
+/// Every index name, with their index documents unless there were too many to read
+#[derive(serde::Serialize)]
+pub struct IndexListing {
+    pub names: Vec<String>,
+    /// The stored index documents, with the `docs_count` their journals last flushed
+    pub indexes: Vec<IndexDocument>,
+    /// Set when there were more indexes than the detail limit, leaving `indexes` empty
+    pub detail_truncated: bool,
+}
+
+impl IndexListing {
+    /// Keep only the indexes labelled `name: value`, and their names. A truncated
+    /// listing has no index documents to check, so none are kept.
+    pub fn retain_labelled(&mut self, name: &str, value: &str) {
+        self.indexes.retain(|index| index.has_label(name, value));
+        self.names = self
+            .indexes
             .iter()
-            .map(|key| -> String { key.name.strip_prefix(PREFIX_INDEX).unwrap().to_string() })
+            .map(|index| index.index.clone())
+            .collect();
+    }
+}
+
+pub struct IndexManager<'a, S: Storage> {
+    store: &'a S,
+    clock: SharedClock,
+    /// The shard count recorded on the indexes this creates
+    n_shards: Option<u32>,
+}
+
+impl<'a, S: Storage> IndexManager<'a, S> {
+    pub fn new(store: &'a S) -> IndexManager<'a, S> {
+        IndexManager {
+            store,
+            clock: worker_clock(),
+            n_shards: None,
+        }
+    }
+
+    /// Record `n_shards`, the worker's `N_SHARDS`, on the indexes this creates, so
+    /// searches can name their shard keys instead of listing them
+    pub fn with_n_shards(mut self, n_shards: u32) -> Self {
+        self.n_shards = Some(n_shards);
+        self
+    }
+
+    /// Stamp and expire records with `clock` rather than the real time
+    #[cfg(test)]
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub async fn list_indexes(&self) -> Result<Vec<String>, DataStoreError> {
+        let indexes: Vec<String> = list_all(self.store, PREFIX_INDEX)
+            .await?
+            .iter()
+            .filter_map(|key| key.strip_prefix(PREFIX_INDEX).map(str::to_string))
             .collect();
 
         let index_count = indexes.len();
@@ -45,76 +170,715 @@ impl<'a> IndexManager<'a> {
         Ok(indexes)
     }
 
+    /// List every index with its index document, read through `reader`, unless there
+    /// are more than `limit` indexes
+    pub async fn list_indexes_detailed(
+        &self,
+        reader: &BulkReader<'_, S>,
+        limit: usize,
+    ) -> Result<IndexListing, DataStoreError> {
+        let names = self.list_indexes().await?;
+        let index_count = names.len();
+        if index_count > limit {
+            edge_log!(
+                console_warn,
+                "IndexManager",
+                "",
+                "{} indexes is over the detail limit of {}, listing names only",
+                index_count,
+                limit
+            );
+            return Ok(IndexListing {
+                names,
+                indexes: vec![],
+                detail_truncated: true,
+            });
+        }
+
+        let keys: Vec<String> = names.iter().map(|name| get_index_key(name)).collect();
+        let indexes = reader
+            .get_indexes_kv_keys(keys.iter().map(|key| key.as_str()).collect())
+            .await
+            .into_iter()
+            .flatten()
+            .collect();
+        Ok(IndexListing {
+            names,
+            indexes,
+            detail_truncated: false,
+        })
+    }
+
     pub async fn read_index(&self, index: &str) -> Result<IndexDocument, DataStoreError> {
         let key = get_index_key(index);
-        let document = self
-            .store
-            .get(&key)
-            .json::<IndexDocument>()
-            .await
-            .map_err(DataStoreError::Kv)?;
+        match IndexDocument::read(&key, self.store).await {
+            Ok(document) => {
+                edge_log!(console_debug, "IndexManager", index, "load from KV");
+                remember_index(&document, self.clock.now_millis());
+                Ok(document)
+            }
+            Err(DataStoreError::NotFound(_)) => {
+                edge_log!(console_warn, "IndexManager", index, "index not found in KV");
+                forget_index_exists(index);
+                Err(DataStoreError::NotFound(index.to_string()))
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Check whether an index exists, from the isolate's cached record when it has
+    /// one, so handlers that don't read the index document anyway don't pay an extra
+    /// KV read on every request.
+    pub async fn index_exists(&self, index: &str) -> Result<bool, DataStoreError> {
+        let now: u64 = self.clock.now_millis();
+        if cached_index(index, now).is_some() {
+            return Ok(true);
+        }
+
+        match self.read_index(index).await {
+            Ok(_) => Ok(true),
+            Err(DataStoreError::NotFound(_)) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Whether the index is frozen, from the isolate's cached record when it has
+    /// one. A missing index isn't frozen.
+    pub async fn is_frozen(&self, index: &str) -> Result<bool, DataStoreError> {
+        let now: u64 = self.clock.now_millis();
+        if let Some(cached) = cached_index(index, now) {
+            return Ok(cached.frozen);
+        }
+
+        match self.read_index(index).await {
+            Ok(index_doc) => Ok(index_doc.frozen),
+            Err(DataStoreError::NotFound(_)) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// The codecs the index's records are read and written with, from the isolate's
+    /// cached record when it has one. Fails for a version this worker doesn't know.
+    pub async fn codecs(&self, index: &str) -> Result<CodecSet, DataStoreError> {
+        let now: u64 = self.clock.now_millis();
+        if let Some(cached) = cached_index(index, now) {
+            return CodecSet::for_version(cached.codec_version);
+        }
+
+        let index_doc = self.read_index(index).await?;
+        CodecSet::for_index(&index_doc)
+    }
 
-        if document.is_none() {
-            edge_log!(console_warn, "IndexManager", index, "index not found in KV");
-            return Err(DataStoreError::NotFound(index.to_string()));
+    /// The number of shards the index's documents are written to, or `default` when
+    /// the index document can't be read
+    pub async fn shard_count(&self, index: &str, default: u32) -> u32 {
+        match self.read_index(index).await {
+            Ok(index_doc) => index_doc.shard_count(default),
+            Err(_) => default,
         }
+    }
+
+    /// Freeze or unfreeze an existing index. Other isolates see the change once
+    /// they read the index document again or their cached record expires, see
+    /// [`freeze_settling_ms`].
+    pub async fn set_frozen(
+        &self,
+        index_name: &str,
+        frozen: bool,
+    ) -> Result<IndexDocument, DataStoreError> {
+        let mut index_doc = self.read_index(index_name).await?;
+        if index_doc.frozen != frozen {
+            index_doc.frozen = frozen;
+            index_doc.frozen_at = frozen.then(|| self.clock.now_millis());
+            index_doc.generation += 1;
+            index_doc.write(self.store).await?;
+            edge_log!(console_log, "IndexManager", index_name, "frozen={}", frozen);
+        }
+        remember_index(&index_doc, self.clock.now_millis());
+        Ok(index_doc)
+    }
 
-        edge_log!(console_debug, "IndexManager", index, "load from KV");
-        Ok(document.unwrap())
+    /// [`Self::create_index_with_metadata`] without a description or labels
+    #[cfg(test)]
+    pub async fn create_index(
+        &self,
+        index_name: &str,
+        default_lang: Option<IsoCode639_1>,
+        settings: Option<IndexSettings>,
+    ) -> Result<IndexDocument, DataStoreError> {
+        self.create_index_with_metadata(
+            index_name,
+            default_lang,
+            settings,
+            IndexMetadata::default(),
+        )
+        .await
     }
 
-    pub async fn create_index(&self, index_name: &str) -> Result<IndexDocument, DataStoreError> {
+    /// Create an index described and labelled by `metadata`, or return the existing
+    /// one. Without `settings`, the index takes the settings and stop-list of the
+    /// template matching its name, if any.
+    pub async fn create_index_with_metadata(
+        &self,
+        index_name: &str,
+        default_lang: Option<IsoCode639_1>,
+        settings: Option<IndexSettings>,
+        metadata: IndexMetadata,
+    ) -> Result<IndexDocument, DataStoreError> {
         // First, read to see if it already exists.
-        let existing_version = self.read_index(index_name).await;
-        // Return the existing version if it exists NOT AN ERROR
-        if existing_version.is_ok() {
-            edge_log!(
-                console_warn,
-                "IndexManager",
-                index_name,
-                "index already exists, skipping creation"
-            );
-            return Ok(existing_version.unwrap());
+        // Return the existing version if it exists NOT AN ERROR. Any other read
+        // error is returned, rather than overwriting an index that couldn't be read.
+        match self.read_index(index_name).await {
+            Ok(existing_version) => {
+                edge_log!(
+                    console_warn,
+                    "IndexManager",
+                    index_name,
+                    "index already exists, skipping creation"
+                );
+                return Ok(existing_version);
+            }
+            Err(DataStoreError::NotFound(_)) => {}
+            Err(err) => return Err(err),
         }
 
-        let index_doc = IndexDocument {
+        let (settings, template) = match settings {
+            Some(settings) => (settings, None),
+            None => match best_match(&list_templates(self.store).await?, index_name) {
+                Some(template) => {
+                    // Written first, so the index never exists without its stop-list
+                    if !template.stoplist.is_empty() {
+                        StopList::new(index_name, &template.stoplist)
+                            .write(self.store)
+                            .await?;
+                    }
+                    let name = template.name.clone();
+                    edge_log!(
+                        console_log,
+                        "IndexManager",
+                        index_name,
+                        "applying template {}",
+                        name
+                    );
+                    (template.settings.clone(), Some(name))
+                }
+                None => (IndexSettings::default(), None),
+            },
+        };
+
+        // The version is only chosen at creation, and isn't kept with the settings
+        let mut settings = settings;
+        let version = settings.version.take().unwrap_or(INDEX_VERSION_LATEST);
+        let mut index_doc = IndexDocument {
             index: index_name.to_string(),
             docs_count: 0,
-            version: INDEX_VERSION_V1,
-            created: worker::Date::now().as_millis().into(),
+            version,
+            created: self.clock.now_millis(),
+            generation: 0,
+            default_lang,
+            settings,
+            frozen: false,
+            frozen_at: None,
+            n_shards: self.n_shards,
+            reshard: None,
+            template,
+            upgrading_to: None,
+            description: None,
+            labels: HashMap::new(),
         };
-        let index_json =
-            serde_json::to_string(&index_doc).map_err(DataStoreError::Serialization)?;
-
-        self.store
-            .put(get_index_key(index_name).as_str(), &index_json)
-            .map_err(DataStoreError::Kv)
-            .unwrap()
-            .execute()
-            .await
-            .map_err(DataStoreError::Kv)?;
+        index_doc.apply_metadata(metadata);
+        index_doc.write(self.store).await?;
 
+        remember_index(&index_doc, index_doc.created);
         edge_log!(console_log, "IndexManager", index_name, "created index");
         Ok(index_doc.to_owned())
     }
 
+    /// Replace the search defaults of an existing index, see [`Self::update_index`]
+    #[cfg(test)]
+    pub async fn update_settings(
+        &self,
+        index_name: &str,
+        settings: IndexSettings,
+    ) -> Result<IndexDocument, DataStoreError> {
+        self.update_index(index_name, Some(settings), IndexMetadata::default())
+            .await
+    }
+
+    /// Change the description and labels of an existing index, leaving its settings
+    /// alone
+    pub async fn update_metadata(
+        &self,
+        index_name: &str,
+        metadata: IndexMetadata,
+    ) -> Result<IndexDocument, DataStoreError> {
+        self.update_index(index_name, None, metadata).await
+    }
+
+    /// Replace the search defaults of an existing index when `settings` are given,
+    /// and apply `metadata`, rewriting the index only if either changed it. Its
+    /// version is changed by upgrading it instead, so settings naming another one
+    /// are refused.
+    pub async fn update_index(
+        &self,
+        index_name: &str,
+        settings: Option<IndexSettings>,
+        metadata: IndexMetadata,
+    ) -> Result<IndexDocument, DataStoreError> {
+        let mut index_doc = self.read_index(index_name).await?;
+        let mut changed = false;
+        if let Some(mut settings) = settings {
+            match settings.version.take() {
+                Some(version) if version != index_doc.version => {
+                    return Err(DataStoreError::InvalidFormat(format!(
+                        "Index '{}' is version {}; change its version by upgrading it",
+                        index_name, index_doc.version
+                    )));
+                }
+                _ => {}
+            }
+            if index_doc.settings != settings {
+                index_doc.settings = settings;
+                changed = true;
+            }
+        }
+        changed |= index_doc.apply_metadata(metadata);
+        if changed {
+            index_doc.generation += 1;
+            index_doc.write(self.store).await?;
+            edge_log!(console_log, "IndexManager", index_name, "updated index");
+        }
+        Ok(index_doc)
+    }
+
     pub async fn delete_index(&self, index_name: &str) -> Result<(), DataStoreError> {
         let key = get_index_key(index_name);
-        self.store.delete(&key).await.map_err(DataStoreError::Kv)?;
+        self.store.delete(&key).await?;
+        forget_index_exists(index_name);
         edge_log!(console_log, "IndexManager", index_name, "deleted index");
         Ok(())
     }
 
     pub async fn count_index_documents(&self, index: &str) -> Result<u32, DataStoreError> {
         let search_prefix = format!("{}:{}", index, PREFIX_DOCUMENT);
-        let list_response = self
-            .store
-            .list()
-            .prefix(search_prefix)
-            .execute()
-            .await
-            .map_err(DataStoreError::Kv)?;
+        let keys = list_all(self.store, &search_prefix).await?;
+        Ok(keys.len() as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
 
-        Ok(list_response.keys.len() as u32)
+    use super::*;
+    use crate::{
+        data::template::IndexTemplate,
+        data::{document::testing::index_text, storage::memory::MemoryStorage, INDEX_VERSION_V1},
+        lexer::scoring::ScoringMode,
+        util::time::ManualClock,
+    };
+
+    #[test]
+    fn test_create_list_and_count() {
+        let store = MemoryStorage::with_page_size(2);
+        let manager = IndexManager::new(&store);
+        block_on(async {
+            for name in ["alpha", "beta", "gamma"] {
+                manager.create_index(name, None, None).await.unwrap();
+            }
+            assert_eq!(
+                manager.list_indexes().await.unwrap(),
+                vec!["alpha", "beta", "gamma"]
+            );
+        });
+
+        for i in 0..5 {
+            index_text(&store, "beta", &format!("doc{}", i), "Ocean tides.");
+        }
+        block_on(async {
+            assert_eq!(manager.count_index_documents("beta").await.unwrap(), 5);
+            assert_eq!(manager.count_index_documents("alpha").await.unwrap(), 0);
+
+            // Creating an existing index returns it unchanged
+            let created = manager.read_index("beta").await.unwrap().created;
+            assert_eq!(
+                manager
+                    .create_index("beta", None, None)
+                    .await
+                    .unwrap()
+                    .created,
+                created
+            );
+        });
+    }
+
+    #[test]
+    fn test_update_settings() {
+        let store = MemoryStorage::default();
+        let manager = IndexManager::new(&store);
+        let settings = IndexSettings {
+            limit: Some(20),
+            scoring: Some(ScoringMode::Coverage),
+            ..Default::default()
+        };
+        block_on(async {
+            manager.create_index("idx", None, None).await.unwrap();
+            let updated = manager
+                .update_settings("idx", settings.clone())
+                .await
+                .unwrap();
+            assert_eq!(updated.generation, 1);
+
+            let stored = manager.read_index("idx").await.unwrap();
+            assert_eq!(stored.settings, settings);
+            // Unchanged settings aren't rewritten
+            let before = store.counts();
+            manager.update_settings("idx", settings).await.unwrap();
+            assert_eq!(store.counts().puts, before.puts);
+            assert!(manager
+                .update_settings("missing", IndexSettings::default())
+                .await
+                .is_err());
+        });
+    }
+
+    #[test]
+    fn test_index_version_is_chosen_at_creation() {
+        let store = MemoryStorage::default();
+        let manager = IndexManager::new(&store);
+        let v1 = IndexSettings {
+            version: Some(INDEX_VERSION_V1),
+            ..Default::default()
+        };
+        block_on(async {
+            let latest = manager.create_index("versioned-latest", None, None);
+            assert_eq!(latest.await.unwrap().version, INDEX_VERSION_LATEST);
+            let created = manager
+                .create_index("versioned-v1", None, Some(v1.clone()))
+                .await
+                .unwrap();
+            assert_eq!(created.version, INDEX_VERSION_V1);
+            assert_eq!(created.settings, IndexSettings::default());
+            assert_eq!(manager.codecs("versioned-v1").await.unwrap(), CodecSet::V1);
+
+            // Settings naming the version the index has are fine, others are refused
+            manager.update_settings("versioned-v1", v1).await.unwrap();
+            let other = IndexSettings {
+                version: Some(INDEX_VERSION_LATEST),
+                ..Default::default()
+            };
+            let refused = manager.update_settings("versioned-v1", other).await;
+            assert!(matches!(refused, Err(DataStoreError::InvalidFormat(_))));
+
+            let mut newer = manager.read_index("versioned-latest").await.unwrap();
+            newer.version = INDEX_VERSION_LATEST + 1;
+            newer.write(&store).await.unwrap();
+            forget_index_exists("versioned-latest");
+            assert!(matches!(
+                manager.codecs("versioned-latest").await,
+                Err(DataStoreError::UnsupportedVersion(_))
+            ));
+        });
+    }
+
+    #[test]
+    fn test_list_indexes_detailed() {
+        let store = MemoryStorage::default();
+        let manager = IndexManager::new(&store);
+        let reader = BulkReader::new(3, &store, None);
+        block_on(async {
+            manager.create_index("listed-a", None, None).await.unwrap();
+            manager
+                .create_index("listed-b", Some(IsoCode639_1::EN), None)
+                .await
+                .unwrap();
+
+            let listing = manager.list_indexes_detailed(&reader, 2).await.unwrap();
+            assert_eq!(listing.names, vec!["listed-a", "listed-b"]);
+            assert!(!listing.detail_truncated);
+            let detailed: Vec<_> = listing
+                .indexes
+                .iter()
+                .map(|index| (index.index.as_str(), index.default_lang))
+                .collect();
+            assert_eq!(
+                detailed,
+                vec![("listed-a", None), ("listed-b", Some(IsoCode639_1::EN))]
+            );
+
+            // Over the limit, only the names are listed
+            let listing = manager.list_indexes_detailed(&reader, 1).await.unwrap();
+            assert_eq!(listing.names.len(), 2);
+            assert!(listing.indexes.is_empty());
+            assert!(listing.detail_truncated);
+        });
+    }
+
+    #[test]
+    fn test_metadata_updates_and_label_filter() {
+        let store = MemoryStorage::default();
+        let manager = IndexManager::new(&store);
+        let reader = BulkReader::new(3, &store, None);
+        let labelled = |env: &str| IndexMetadata {
+            description: None,
+            labels: Some(HashMap::from([("env".to_string(), env.to_string())])),
+        };
+        block_on(async {
+            manager
+                .create_index_with_metadata("labelled-a", None, None, labelled("prod"))
+                .await
+                .unwrap();
+            manager
+                .create_index("labelled-b", None, None)
+                .await
+                .unwrap();
+            let settings = IndexSettings {
+                limit: Some(9),
+                ..Default::default()
+            };
+            manager
+                .update_settings("labelled-b", settings.clone())
+                .await
+                .unwrap();
+            let updated = manager
+                .update_metadata("labelled-b", labelled("prod"))
+                .await
+                .unwrap();
+            assert_eq!((updated.settings, updated.generation), (settings, 2));
+
+            // Metadata that changes nothing isn't rewritten
+            let before = store.counts();
+            manager
+                .update_metadata("labelled-b", labelled("prod"))
+                .await
+                .unwrap();
+            assert_eq!(store.counts().puts, before.puts);
+            manager
+                .update_metadata("labelled-a", labelled("dev"))
+                .await
+                .unwrap();
+
+            let mut listing = manager.list_indexes_detailed(&reader, 10).await.unwrap();
+            listing.retain_labelled("env", "prod");
+            assert_eq!(listing.names, vec!["labelled-b"]);
+            assert_eq!(listing.indexes.len(), 1);
+            assert!(manager
+                .update_metadata("missing", labelled("prod"))
+                .await
+                .is_err());
+        });
+    }
+
+    #[test]
+    fn test_new_indexes_take_the_matching_template() {
+        let store = MemoryStorage::default();
+        let manager = IndexManager::new(&store);
+        block_on(async {
+            for (name, body) in [
+                ("any", r#"{"pattern":"tmpl-*"}"#),
+                (
+                    "tenants",
+                    r#"{"pattern":"tmpl-tenant-*","settings":{"limit":7},"stoplist":["Acme"]}"#,
+                ),
+            ] {
+                IndexTemplate::parse(name, body)
+                    .unwrap()
+                    .write(&store)
+                    .await
+                    .unwrap();
+            }
+
+            let created = manager
+                .create_index("tmpl-tenant-1", None, None)
+                .await
+                .unwrap();
+            assert_eq!(created.template.as_deref(), Some("tenants"));
+            assert_eq!(created.settings.limit, Some(7));
+            let stoplist = StopList::load(&store, "tmpl-tenant-1").await.unwrap();
+            assert_eq!(stoplist.keywords(), vec!["acme"]);
+
+            // Settings given explicitly leave templates alone
+            let settings = IndexSettings {
+                limit: Some(3),
+                ..IndexSettings::default()
+            };
+            let explicit = manager
+                .create_index("tmpl-tenant-2", None, Some(settings.clone()))
+                .await
+                .unwrap();
+            assert_eq!((explicit.template, explicit.settings), (None, settings));
+            assert!(StopList::load(&store, "tmpl-tenant-2")
+                .await
+                .unwrap()
+                .is_empty());
+
+            let other = manager
+                .create_index("tmpl-other", None, None)
+                .await
+                .unwrap();
+            assert_eq!(other.template.as_deref(), Some("any"));
+            assert!(other.settings.is_empty());
+            let untemplated = manager.create_index("plain", None, None).await.unwrap();
+            assert_eq!(untemplated.template, None);
+        });
+    }
+
+    #[test]
+    fn test_missing_index() {
+        let store = MemoryStorage::default();
+        let manager = IndexManager::new(&store);
+        block_on(async {
+            assert!(matches!(
+                manager.read_index("storage-missing").await,
+                Err(DataStoreError::NotFound(_))
+            ));
+            assert!(!manager.index_exists("storage-missing").await.unwrap());
+
+            manager
+                .create_index("storage-created", None, None)
+                .await
+                .unwrap();
+            manager.delete_index("storage-created").await.unwrap();
+            assert!(!manager.index_exists("storage-created").await.unwrap());
+        });
+    }
+
+    #[test]
+    fn test_index_exists_cache_expires() {
+        remember_index_exists("cache-expiry", 1_000, (0, 0), false, INDEX_VERSION_LATEST);
+        assert!(cached_index("cache-expiry", 1_000).is_some());
+        assert!(cached_index("cache-expiry", 1_000 + INDEX_EXISTS_TTL_MS - 1).is_some());
+        assert!(cached_index("cache-expiry", 1_000 + INDEX_EXISTS_TTL_MS).is_none());
+    }
+
+    #[test]
+    fn test_index_is_stamped_and_cached_by_the_clock() {
+        let store = MemoryStorage::default();
+        let clock = ManualClock::at(5_000);
+        let manager = IndexManager::new(&store).with_clock(clock.clone());
+        block_on(async {
+            let created = manager.create_index("clocked", None, None).await.unwrap();
+            assert_eq!(created.created, 5_000);
+            assert!(manager.index_exists("clocked").await.unwrap());
+
+            // Deleted behind the cache's back, it's still trusted until the TTL passes
+            store.delete(&get_index_key("clocked")).await.unwrap();
+            clock.advance(INDEX_EXISTS_TTL_MS - 1);
+            assert!(manager.index_exists("clocked").await.unwrap());
+            clock.advance(1);
+            assert!(!manager.index_exists("clocked").await.unwrap());
+        });
+    }
+
+    #[test]
+    fn test_index_cache_follows_the_generation() {
+        let store = MemoryStorage::default();
+        let clock = ManualClock::at(1_000);
+        let manager = IndexManager::new(&store).with_clock(clock.clone());
+        block_on(async {
+            manager
+                .create_index("regenerated", None, None)
+                .await
+                .unwrap();
+            let cached = || cached_index("regenerated", 1_000).unwrap();
+            assert_eq!(cached().revision, (1_000, 0));
+
+            // Frozen by another isolate, the change shows as soon as this one reads the
+            // index document again, as a search does
+            let mut stored = manager.read_index("regenerated").await.unwrap();
+            stored.frozen = true;
+            stored.generation += 1;
+            stored.write(&store).await.unwrap();
+            assert!(!cached().frozen);
+            manager.read_index("regenerated").await.unwrap();
+            assert_eq!(cached().revision, (1_000, 1));
+            assert!(cached().frozen);
+
+            // An older revision read back late doesn't replace it
+            stored.frozen = false;
+            stored.generation = 0;
+            remember_index(&stored, 1_000);
+            assert!(cached().frozen);
+
+            // Deleted and recreated behind the cache's back, the new index replaces
+            // the old one's entry even though its generation starts over
+            store.delete(&get_index_key("regenerated")).await.unwrap();
+            assert!(manager.read_index("regenerated").await.is_err());
+            assert!(cached_index("regenerated", 1_000).is_none());
+            clock.advance(1);
+            manager
+                .create_index("regenerated", None, None)
+                .await
+                .unwrap();
+            assert_eq!(cached().revision, (1_001, 0));
+        });
+    }
+
+    #[test]
+    fn test_freeze_settles_after_the_cache_ttl() {
+        let store = MemoryStorage::default();
+        let clock = ManualClock::at(1_000);
+        let manager = IndexManager::new(&store).with_clock(clock.clone());
+        block_on(async {
+            manager.create_index("settling", None, None).await.unwrap();
+            let mut frozen = manager.set_frozen("settling", true).await.unwrap();
+            assert_eq!(frozen.frozen_at, Some(1_000));
+            assert_eq!(
+                freeze_settling_ms(&frozen, 1_000),
+                Some(INDEX_EXISTS_TTL_MS)
+            );
+            assert_eq!(
+                freeze_settling_ms(&frozen, 1_000 + INDEX_EXISTS_TTL_MS - 1),
+                Some(1)
+            );
+            assert_eq!(
+                freeze_settling_ms(&frozen, 1_000 + INDEX_EXISTS_TTL_MS),
+                None
+            );
+
+            // Indexes frozen before the time was kept have settled
+            frozen.frozen_at = None;
+            assert_eq!(freeze_settling_ms(&frozen, 1_000), None);
+        });
+    }
+
+    #[test]
+    fn test_index_exists_cache_forget() {
+        remember_index_exists("cache-forget", 1_000, (0, 0), false, INDEX_VERSION_LATEST);
+        forget_index_exists("cache-forget");
+        assert!(cached_index("cache-forget", 1_000).is_none());
+        assert!(cached_index("never-created", 1_000).is_none());
+    }
+
+    #[test]
+    fn test_frozen_index_is_cached() {
+        let store = MemoryStorage::default();
+        let manager = IndexManager::new(&store);
+        block_on(async {
+            manager.create_index("freezing", None, None).await.unwrap();
+            assert!(!manager.is_frozen("freezing").await.unwrap());
+
+            let frozen = manager.set_frozen("freezing", true).await.unwrap();
+            assert!(frozen.frozen && frozen.generation == 1);
+            // Answered from the isolate's cache, without reading the index again
+            let gets = store.counts().gets;
+            assert!(manager.is_frozen("freezing").await.unwrap());
+            assert_eq!(store.counts().gets, gets);
+            assert!(manager.read_index("freezing").await.unwrap().frozen);
+
+            // Unchanged flags aren't rewritten
+            let puts = store.counts().puts;
+            manager.set_frozen("freezing", true).await.unwrap();
+            assert_eq!(store.counts().puts, puts);
+
+            manager.set_frozen("freezing", false).await.unwrap();
+            assert!(!manager.is_frozen("freezing").await.unwrap());
+            assert_eq!(
+                manager.read_index("freezing").await.unwrap().frozen_at,
+                None
+            );
+            assert!(!manager.is_frozen("never-frozen").await.unwrap());
+            assert!(manager.set_frozen("never-frozen", true).await.is_err());
+        });
     }
 }
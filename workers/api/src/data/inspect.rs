@@ -0,0 +1,254 @@
+//! Cross-checking a document's stored keywords against the keyword shards that are
+//! supposed to hold its postings, for debugging queries and checking consistency
+//! after interrupted writes.
+
+use std::collections::HashSet;
+
+use serde::Serialize;
+
+use crate::{
+    data::{
+        bulk::BulkReader,
+        document::{shard_from_document_id, Document},
+        keyword_shard::{parse_keyword_shard_key, scores_equal, KeywordShardData},
+        storage::Storage,
+        DataStoreError, PREFIX_KEYWORD,
+    },
+    util::concurrency::join_bounded,
+};
+
+/// Whether a keyword's shard actually holds the document's posting
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PostingStatus {
+    /// The posting is present with the document's score
+    Ok,
+    /// The shard, or the posting within it, is missing
+    Missing,
+    /// The posting is present with a different score
+    Stale,
+}
+
+/// One of a document's keywords, and the shard its posting lives in
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct KeywordPosting {
+    pub keyword: String,
+    pub score: f64,
+    pub shard: u32,
+    /// Only checked with `verify=true`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<PostingStatus>,
+    /// The score found in the shard, when it differs from the document's
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shard_score: Option<f64>,
+}
+
+/// A posting for the document in the shard of a keyword the document doesn't have
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct DanglingPosting {
+    pub keyword: String,
+    pub score: f64,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct DocumentKeywords {
+    pub id: String,
+    pub keywords: Vec<KeywordPosting>,
+    /// Only checked with `verify=true`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dangling: Option<Vec<DanglingPosting>>,
+    /// Whether every posting was found as expected, with `verify=true`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub consistent: Option<bool>,
+}
+
+/// Compare the document's score for a keyword against its shard
+fn posting_status(
+    doc_id: &str,
+    score: f64,
+    shard: Option<&KeywordShardData>,
+) -> (PostingStatus, Option<f64>) {
+    let found = shard.and_then(|shard| shard.docs.iter().find(|(id, _)| id == doc_id));
+    match found {
+        None => (PostingStatus::Missing, None),
+        Some((_, found)) if scores_equal(*found, score) => (PostingStatus::Ok, None),
+        Some((_, found)) => (PostingStatus::Stale, Some(*found)),
+    }
+}
+
+/// List the shard each of the document's keywords is stored in. With `verify`, also
+/// read those shards to confirm the postings are there, and scan every keyword shard
+/// of the document's shard number for postings of keywords it no longer has.
+pub async fn inspect_document_keywords<S: Storage>(
+    document: &Document,
+    store: &S,
+    bulk_reader: &BulkReader<'_, S>,
+    n_shards: u32,
+    verify: bool,
+) -> Result<DocumentKeywords, DataStoreError> {
+    let doc_id = document.get_uuid();
+    let shard = shard_from_document_id(doc_id.clone(), n_shards);
+    let mut keywords: Vec<KeywordPosting> = document
+        .keywords
+        .iter()
+        .flatten()
+        .map(|(keyword, score)| KeywordPosting {
+            keyword: keyword.clone(),
+            score: score.score,
+            shard,
+            status: None,
+            shard_score: None,
+        })
+        .collect();
+    if !verify {
+        return Ok(DocumentKeywords {
+            id: doc_id,
+            keywords,
+            dangling: None,
+            consistent: None,
+        });
+    }
+
+    let codecs = bulk_reader.codecs();
+    let loads = keywords.iter().map(|posting| {
+        KeywordShardData::load(store, codecs, &document.index, &posting.keyword, shard)
+    });
+    let loaded = join_bounded(loads).await;
+    for (posting, loaded) in keywords.iter_mut().zip(loaded) {
+        let (status, shard_score) = posting_status(&doc_id, posting.score, loaded?.as_ref());
+        posting.status = Some(status);
+        posting.shard_score = shard_score;
+    }
+
+    // Shards of every other keyword at this shard number may still hold the document
+    let known: HashSet<&str> = keywords.iter().map(|p| p.keyword.as_str()).collect();
+    let keyword_prefix = format!("{}:{}", document.index, PREFIX_KEYWORD);
+    let other_shards: Vec<String> = bulk_reader
+        .list(&keyword_prefix)
+        .await?
+        .into_iter()
+        .filter(|key| match parse_keyword_shard_key(&document.index, key) {
+            Some((keyword, key_shard)) => key_shard == shard && !known.contains(keyword.as_str()),
+            None => false,
+        })
+        .collect();
+    let dangling: Vec<DanglingPosting> = bulk_reader
+        .get_keyword_kv_keys(other_shards.iter().map(|key| key.as_str()).collect())
+        .await
+        .into_iter()
+        .filter_map(|shard| {
+            let (_, score) = shard.docs.iter().find(|(id, _)| *id == doc_id)?;
+            Some(DanglingPosting {
+                keyword: shard.keyword.clone(),
+                score: *score,
+            })
+        })
+        .collect();
+
+    let consistent = dangling.is_empty()
+        && keywords
+            .iter()
+            .all(|posting| posting.status == Some(PostingStatus::Ok));
+    Ok(DocumentKeywords {
+        id: doc_id,
+        keywords,
+        dangling: Some(dangling),
+        consistent: Some(consistent),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+
+    use super::*;
+    use crate::data::{
+        document::testing::index_text,
+        keyword_shard::{keyword_shard_kv_key, testing::seed_postings},
+        storage::memory::MemoryStorage,
+        KvPersistent, DEFAULT_N_SHARDS,
+    };
+
+    fn inspect(store: &MemoryStorage, document: &Document, verify: bool) -> DocumentKeywords {
+        let bulk_reader = BulkReader::new(DEFAULT_N_SHARDS, store, None);
+        block_on(inspect_document_keywords(
+            document,
+            store,
+            &bulk_reader,
+            DEFAULT_N_SHARDS,
+            verify,
+        ))
+        .unwrap()
+    }
+
+    fn shard_of(store: &MemoryStorage, document: &Document, keyword: &str) -> KeywordShardData {
+        let shard = shard_from_document_id(document.get_uuid(), DEFAULT_N_SHARDS);
+        let key = keyword_shard_kv_key("idx", keyword, shard);
+        block_on(KeywordShardData::read(&key, store)).unwrap()
+    }
+
+    #[test]
+    fn test_unverified_lists_shards_without_reading_them() {
+        let store = MemoryStorage::default();
+        let document = index_text(&store, "idx", "doc1", "Ocean tides wash the sandy beach.");
+        let reads = store.counts().gets;
+
+        let inspected = inspect(&store, &document, false);
+        let shard = shard_from_document_id("doc1".into(), DEFAULT_N_SHARDS);
+        assert_eq!(inspected.keywords.len(), document.keywords.unwrap().len());
+        assert!(inspected
+            .keywords
+            .iter()
+            .all(|p| p.shard == shard && p.status.is_none()));
+        assert_eq!(inspected.consistent, None);
+        assert_eq!(store.counts().gets, reads);
+    }
+
+    #[test]
+    fn test_verify_consistent_document() {
+        let store = MemoryStorage::default();
+        let document = index_text(&store, "idx", "doc1", "Ocean tides wash the sandy beach.");
+        let inspected = inspect(&store, &document, true);
+        assert_eq!(inspected.consistent, Some(true));
+        assert_eq!(inspected.dangling, Some(vec![]));
+    }
+
+    #[test]
+    fn test_verify_reports_corrupted_shards() {
+        let store = MemoryStorage::default();
+        let document = index_text(&store, "idx", "doc1", "Ocean tides wash the sandy beach.");
+        let keywords = document.keywords.clone().unwrap();
+        let (missing, stale) = (&keywords[0].0, &keywords[1].0);
+
+        // Drop one posting, rescore another, and leave a posting for a keyword the
+        // document doesn't have
+        let mut shard = shard_of(&store, &document, missing);
+        shard.docs.clear();
+        block_on(shard.write(&store)).unwrap();
+        let mut shard = shard_of(&store, &document, stale);
+        shard.docs[0].1 = 0.123;
+        block_on(shard.write(&store)).unwrap();
+        seed_postings(&store, "idx", DEFAULT_N_SHARDS, "volcano", &[("doc1", 0.5)]);
+
+        let inspected = inspect(&store, &document, true);
+        let status = |keyword: &str| {
+            let posting = inspected
+                .keywords
+                .iter()
+                .find(|p| p.keyword == keyword)
+                .unwrap();
+            (posting.status.unwrap(), posting.shard_score)
+        };
+        assert_eq!(status(missing), (PostingStatus::Missing, None));
+        assert_eq!(status(stale), (PostingStatus::Stale, Some(0.123)));
+        assert_eq!(status(&keywords[2].0), (PostingStatus::Ok, None));
+        assert_eq!(
+            inspected.dangling,
+            Some(vec![DanglingPosting {
+                keyword: "volcano".into(),
+                score: 0.5
+            }])
+        );
+        assert_eq!(inspected.consistent, Some(false));
+    }
+}
@@ -1,65 +1,333 @@
-use std::sync::Arc;
+use std::collections::{BTreeSet, HashMap, HashSet};
 
-use worker::{kv::KvStore, Env};
+use worker::{Env, Method, ObjectNamespace, Request, RequestInit};
 
 use crate::{
     data::{
-        bulk::BulkReader, keyword_shard::get_n_shards, DataStoreError, IndexName, PREFIX_KEYWORD,
+        bulk::BulkReader,
+        codec::CodecSet,
+        document::{document_kv_key, Document},
+        encoding::read_length_prefixed_stamped,
+        export::{export_batch, ExportCursor, ExportPage},
+        fsck::{fsck_batch, FsckCursor, FsckOptions, FsckReport},
+        inspect::{inspect_document_keywords, DocumentKeywords},
+        keyword_shard::{
+            escape_keyword, get_n_shards, keyword_shard_kv_key, keyword_shard_prefix,
+            keyword_top_kv_key, keyword_top_prefix, legacy_keyword_shard_prefix,
+            list_keyword_shards, parse_keyword_shard_key, KeywordShardData, KeywordShardTop, TOP_K,
+        },
+        merge_cache::{merged_keyword_kv_key, MergedKeyword, WarmReport},
+        related::{rank_related, RelatedKeyword, RELATED_DOCUMENT_SAMPLE},
+        storage::PageCursor,
+        storage::{list_all, Storage},
+        top::{top_batch, TopOptions, TopReport},
+        trace::ReadTrace,
+        DataStoreError, IndexName, KvPersistent, PREFIX_KEYWORD,
+    },
+    durable::{
+        reader::{
+            get_durable_reader_namespace, get_merged_keyword_limit, MergedKeywordsRequest,
+            ReaderPlacement, SHARD_READS_HEADER,
+        },
+        reader_cache,
     },
-    durable::reader::get_durable_reader_namespace,
     edge_log,
-    util::http::url_decode,
+    util::{
+        concurrency::join_bounded,
+        time::{worker_clock, SharedClock},
+    },
 };
 
-pub struct KeywordManager<'a> {
+pub struct KeywordManager<'a, S: Storage> {
     index: IndexName,
-    env: &'a Env,
-    state: &'a Arc<KvStore>,
+    n_shards: u32,
+    /// The durable reader namespace large reads fan out to, if bound
+    reader: Option<ObjectNamespace>,
+    /// Which of its readers the index's reads go to
+    placement: ReaderPlacement,
+    state: &'a S,
+    /// Where shard listings and reads are recorded, for search diagnostics
+    trace: Option<&'a ReadTrace>,
+    /// What repaired shards are stamped with
+    clock: SharedClock,
+    /// What the index's shards are read and repaired with
+    codecs: CodecSet,
+    /// Whether `n_shards` is the index's own recorded shard count, so every shard key
+    /// is below it and can be named instead of listed
+    known_n_shards: bool,
+    /// Whether merges read and write the merged postings kept in KV, see
+    /// [`crate::data::merge_cache`]
+    merge_cache: bool,
+    /// Whether bulk reads fan out as if to a durable reader, answered from `state`
+    #[cfg(test)]
+    local_reader: bool,
+}
+
+pub type MergedKeywordData = Vec<(String, f64)>;
+
+/// A keyword's merged postings, with the newest timestamp of the shards they were
+/// merged from. Any write to one of the shards moves the timestamp on, so it tells a
+/// cached copy of the postings from a stale one.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StampedPostings {
+    pub postings: MergedKeywordData,
+    /// `None` when the keyword has no shards
+    pub newest_ts: Option<u64>,
+}
+
+/// What [`KeywordManager::merge_many_keyword_shards_cached`] merged, and what it cost
+#[derive(Debug, Default)]
+pub struct CachedMerge {
+    pub merged: HashMap<String, StampedPostings>,
+    pub shard_reads: usize,
+    /// Keywords whose shards were listed, having no current entry
+    pub listings: usize,
+    /// Entries read and written
+    pub cache_ops: usize,
+    /// Keywords whose postings came from their entry
+    pub cache_hits: usize,
+}
+
+/// Shards read at a time while gathering the best postings of a keyword
+const TOP_SHARD_ROUND: usize = 4;
+
+/// Postings gathered per wanted posting before the remaining shards are skipped.
+/// Documents are spread over shards by ID, so the shards read are a random sample.
+const TOP_OVERSAMPLE: usize = 2;
+
+/// The best scored postings of a keyword, possibly from only some of its shards
+#[derive(Debug)]
+pub struct TopPostings {
+    /// Sorted by score descending
+    pub postings: MergedKeywordData,
+    /// The keyword's posting count, estimated from the shards read unless `exact`
+    pub total: usize,
+    /// Whether every shard was read, so no better posting was left out
+    pub exact: bool,
+    /// The newest timestamp of the keyword's shards, when every shard was read whole
+    /// rather than through its summary
+    pub newest_ts: Option<u64>,
+}
+
+/// Flatten the postings of several shards into one list sorted by score descending
+fn merge_shard_postings<'s>(
+    shards: impl Iterator<Item = &'s KeywordShardData>,
+) -> MergedKeywordData {
+    let mut merged: MergedKeywordData = shards.flat_map(|data| data.docs.clone()).collect();
+    merged.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+    merged
+}
+
+/// [`merge_shard_postings`], stamped with the newest of the shards' timestamps
+fn stamp_shard_postings(shards: &[&KeywordShardData]) -> StampedPostings {
+    StampedPostings {
+        postings: merge_shard_postings(shards.iter().copied()),
+        newest_ts: shards.iter().map(|shard| shard.ts).max(),
+    }
 }
 
-type MergedKeywordData = Vec<(String, f64)>;
-impl<'a> KeywordManager<'a> {
-    pub fn new(index: IndexName, env: &'a Env, state: &'a Arc<KvStore>) -> KeywordManager<'a> {
-        return KeywordManager { index, env, state };
+impl<'a, S: Storage> KeywordManager<'a, S> {
+    pub fn new(index: IndexName, env: &Env, state: &'a S) -> KeywordManager<'a, S> {
+        let reader = match get_durable_reader_namespace(env) {
+            Ok(reader) => Some(reader),
+            Err(err) => {
+                edge_log!(
+                    console_warn,
+                    "KeywordManager",
+                    &index,
+                    "durable reader unavailable, reading shards directly from KV: {}",
+                    err
+                );
+                None
+            }
+        };
+        KeywordManager {
+            index,
+            n_shards: get_n_shards(env),
+            reader,
+            placement: ReaderPlacement::from_env(env),
+            state,
+            trace: None,
+            clock: worker_clock(),
+            codecs: CodecSet::V1,
+            known_n_shards: false,
+            merge_cache: false,
+            #[cfg(test)]
+            local_reader: false,
+        }
+    }
+
+    /// A manager that reads every shard straight from `state`, without a durable reader
+    pub fn direct(index: IndexName, n_shards: u32, state: &'a S) -> KeywordManager<'a, S> {
+        KeywordManager {
+            index,
+            n_shards,
+            reader: None,
+            placement: ReaderPlacement::default(),
+            state,
+            trace: None,
+            clock: worker_clock(),
+            codecs: CodecSet::V1,
+            known_n_shards: false,
+            merge_cache: false,
+            #[cfg(test)]
+            local_reader: false,
+        }
+    }
+
+    /// Expect documents in `n_shards` shards, for an index resharded off `N_SHARDS`
+    pub fn with_n_shards(mut self, n_shards: u32) -> Self {
+        self.n_shards = n_shards;
+        self
+    }
+
+    /// Read the shards of an index whose shard count is recorded, see
+    /// [`crate::data::index::IndexDocument::known_shard_count`], by naming each of its
+    /// `n_shards` shard keys rather than listing them. Missing shards are skipped.
+    pub fn with_known_n_shards(mut self, n_shards: u32) -> Self {
+        self.n_shards = n_shards;
+        self.known_n_shards = true;
+        self
+    }
+
+    /// Keep the merged postings of keywords stored in every shard, see
+    /// [`crate::data::merge_cache`]. Indexes with a known shard count don't list their
+    /// shards, so never use them, and neither do traced merges, which record every
+    /// shard as it's found.
+    pub fn with_merge_cache(mut self, merge_cache: bool) -> Self {
+        self.merge_cache = merge_cache;
+        self
+    }
+
+    /// Whether merges go through [`Self::merge_many_keyword_shards_cached`]
+    pub fn caches_merges(&self) -> bool {
+        self.merge_cache && !self.known_n_shards && self.trace.is_none()
+    }
+
+    /// Whether finding the shards of `keyword` costs a listing. Keywords whose shards
+    /// may still be stored under their legacy unescaped key are always listed.
+    pub fn lists_shards(&self, keyword: &str) -> bool {
+        !self.known_n_shards || legacy_keyword_shard_prefix(&self.index, keyword).is_some()
+    }
+
+    /// The shard keys of `keyword`, named when [`Self::lists_shards`] is false and
+    /// listed otherwise, recorded into the trace either way
+    async fn shard_keys(&self, keyword: &str) -> Result<Vec<String>, DataStoreError> {
+        let prefix = keyword_shard_prefix(&self.index, keyword);
+        if !self.lists_shards(keyword) {
+            let keys: Vec<String> = (0..self.n_shards)
+                .map(|shard| keyword_shard_kv_key(&self.index, keyword, shard))
+                .collect();
+            if let Some(trace) = self.trace {
+                trace.enumerated(keyword, &prefix, &keys);
+            }
+            return Ok(keys);
+        }
+        let keys = list_keyword_shards(self.state, &self.index, keyword).await?;
+        if let Some(trace) = self.trace {
+            trace.listed(keyword, &prefix, &keys);
+        }
+        Ok(keys)
+    }
+
+    /// Read and repair shards with the codecs of the index's version
+    pub fn with_codecs(mut self, codecs: CodecSet) -> Self {
+        self.codecs = codecs;
+        self
+    }
+
+    /// Stamp the shards fsck repairs with `clock` rather than the real time
+    #[cfg(test)]
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Fan bulk reads out as a durable reader would get them, answering each request
+    /// from `state`, so the requests a merge makes can be counted
+    #[cfg(test)]
+    pub fn with_local_reader(mut self) -> Self {
+        self.local_reader = true;
+        self
+    }
+
+    /// Record every shard key merges list and read into `trace`. Traced merges read
+    /// shards through the bulk reader rather than merging inside the durable reader,
+    /// which only answers with the merged postings.
+    pub fn with_trace(mut self, trace: Option<&'a ReadTrace>) -> Self {
+        self.trace = trace;
+        self
+    }
+
+    fn bulk_reader(&self) -> Result<BulkReader<'_, S>, DataStoreError> {
+        let durable_obj = match &self.reader {
+            Some(reader) => Some(self.placement.reader_id(reader, &self.index)?),
+            None => None,
+        };
+        let bulk_reader = BulkReader::new(self.n_shards, self.state, durable_obj)
+            .with_trace(self.trace)
+            .with_codecs(self.codecs);
+        #[cfg(test)]
+        if self.local_reader {
+            return Ok(bulk_reader.with_local_durable());
+        }
+        Ok(bulk_reader)
     }
 
     pub async fn merge_keyword_shards(
         &self,
-        keyword_raw: String,
+        keyword: String,
     ) -> Result<MergedKeywordData, DataStoreError> {
-        let durable_obj_ns = get_durable_reader_namespace(self.env)?;
-        let durable_obj = durable_obj_ns.unique_id()?;
-        let bulk_reader = BulkReader::new(get_n_shards(self.env), &self.state, durable_obj);
+        let (merged, _) = self.merge_keyword_shards_counted(keyword).await?;
+        Ok(merged)
+    }
+
+    /// Like [`Self::merge_keyword_shards`], but also returns how many shards were read
+    pub async fn merge_keyword_shards_counted(
+        &self,
+        keyword: String,
+    ) -> Result<(MergedKeywordData, usize), DataStoreError> {
+        let (stamped, shard_count) = self.merge_keyword_shards_stamped(keyword).await?;
+        Ok((stamped.postings, shard_count))
+    }
+
+    /// Like [`Self::merge_keyword_shards_counted`], but also returns the newest
+    /// timestamp of the keyword's shards
+    pub async fn merge_keyword_shards_stamped(
+        &self,
+        keyword: String,
+    ) -> Result<(StampedPostings, usize), DataStoreError> {
+        if let Some(reader) = self.reader.as_ref().filter(|_| self.trace.is_none()) {
+            let (mut merged, shard_count) =
+                self.merge_via_reader(reader, vec![keyword.clone()]).await?;
+            return Ok((merged.remove(&keyword).unwrap_or_default(), shard_count));
+        }
 
-        let keyword: String = url_decode(keyword_raw.as_str());
-        let keyword_shards = bulk_reader
-            .list(format!("{}:{}{}:", self.index, PREFIX_KEYWORD, keyword).as_str())
-            .await?;
+        let bulk_reader = self.bulk_reader()?;
+        let keyword_shards = self.shard_keys(&keyword).await?;
 
         let shard_count = keyword_shards.len();
         edge_log!(
             console_debug,
             "KeywordManager",
             &self.index,
-            "keyword shard merge initiated  keyword={}, shard_count={}",
+            "keyword shard merge initiated  keyword={}, shard_count={}, listed={}",
             keyword,
-            shard_count
+            shard_count,
+            (self.lists_shards(&keyword))
         );
 
         let keyword_shards_str: Vec<&str> =
             keyword_shards.iter().map(|entry| entry.as_str()).collect();
 
-        // Use our new Durable Object reader to fetch the keyword shards in bulk async
-        let kv_keys_len = keyword_shards.len();
+        // Use our new Durable Object reader to fetch the keyword shards in bulk async.
+        // Shards that were named but don't exist are left out.
         let kv_data = bulk_reader.get_keyword_kv_keys(keyword_shards_str).await;
-        assert!(kv_data.len() == kv_keys_len);
 
         // Flatten and sort documents by score
-        let mut merged_keywords: Vec<(String, f64)> =
-            kv_data.iter().flat_map(|data| data.docs.clone()).collect();
-        merged_keywords.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+        let merged_keywords = stamp_shard_postings(&kv_data.iter().collect::<Vec<_>>());
 
-        let total_doc_count = merged_keywords.len();
+        let total_doc_count = merged_keywords.postings.len();
         edge_log!(
             console_log,
             "KeywordManager",
@@ -69,6 +337,1100 @@ impl<'a> KeywordManager<'a> {
             total_doc_count
         );
 
-        Ok(merged_keywords)
+        Ok((merged_keywords, shard_count))
+    }
+
+    /// Merge the shards of many keywords at once. The shard keys of every keyword are
+    /// pooled into a single bulk read so the durable reader chunks are filled as
+    /// densely as possible, instead of issuing partial chunks per keyword.
+    pub async fn merge_many_keyword_shards(
+        &self,
+        keywords: Vec<String>,
+    ) -> Result<HashMap<String, MergedKeywordData>, DataStoreError> {
+        let (merged, _) = self.merge_many_keyword_shards_counted(keywords).await?;
+        Ok(merged)
+    }
+
+    /// Like [`Self::merge_many_keyword_shards`], but also returns how many shards were
+    /// read. With a durable reader the whole merge happens inside it, costing one
+    /// subrequest per [`get_merged_keyword_limit`] keywords.
+    pub async fn merge_many_keyword_shards_counted(
+        &self,
+        keywords: Vec<String>,
+    ) -> Result<(HashMap<String, MergedKeywordData>, usize), DataStoreError> {
+        let (merged, shard_count) = self.merge_many_keyword_shards_stamped(keywords).await?;
+        let merged = merged
+            .into_iter()
+            .map(|(keyword, stamped)| (keyword, stamped.postings))
+            .collect();
+        Ok((merged, shard_count))
+    }
+
+    /// Like [`Self::merge_many_keyword_shards_counted`], but also returns the newest
+    /// shard timestamp of each keyword
+    pub async fn merge_many_keyword_shards_stamped(
+        &self,
+        keywords: Vec<String>,
+    ) -> Result<(HashMap<String, StampedPostings>, usize), DataStoreError> {
+        let mut seen = HashSet::new();
+        let keywords: Vec<String> = keywords
+            .into_iter()
+            .filter(|kw| seen.insert(kw.clone()))
+            .collect();
+
+        if let Some(reader) = self.reader.as_ref().filter(|_| self.trace.is_none()) {
+            let (mut merged, shard_count) = self.merge_via_reader(reader, keywords.clone()).await?;
+            for keyword in keywords {
+                merged.entry(keyword).or_default();
+            }
+            return Ok((merged, shard_count));
+        }
+
+        let (merged, shard_keys) = self.merge_by_keys(&keywords).await?;
+        Ok((merged, shard_keys.iter().map(Vec::len).sum()))
+    }
+
+    /// Find the shard keys of `keywords` and merge them in one bulk read, returning
+    /// the keys of each keyword alongside its postings
+    async fn merge_by_keys(
+        &self,
+        keywords: &[String],
+    ) -> Result<(HashMap<String, StampedPostings>, Vec<Vec<String>>), DataStoreError> {
+        let bulk_reader = self.bulk_reader()?;
+
+        let list_futures: Vec<_> = keywords
+            .iter()
+            .map(|keyword| self.shard_keys(keyword))
+            .collect();
+        let mut shard_keys: Vec<Vec<String>> = vec![];
+        for keys in join_bounded(list_futures).await {
+            shard_keys.push(keys?);
+        }
+        let all_shard_keys: Vec<&str> = shard_keys.iter().flatten().map(String::as_str).collect();
+
+        let keyword_count = keywords.len();
+        let listed = keywords.iter().filter(|kw| self.lists_shards(kw)).count();
+        let total_shards = all_shard_keys.len();
+        edge_log!(
+            console_debug,
+            "KeywordManager",
+            &self.index,
+            "batch keyword shard merge initiated keywords={}, listed={}, shard_count={}",
+            keyword_count,
+            listed,
+            total_shards
+        );
+
+        let kv_data = bulk_reader.get_keyword_kv_keys(all_shard_keys).await;
+
+        let mut shards_by_keyword: HashMap<&str, Vec<&KeywordShardData>> = keywords
+            .iter()
+            .map(|keyword| (keyword.as_str(), vec![]))
+            .collect();
+        for shard in kv_data.iter() {
+            if let Some(shards) = shards_by_keyword.get_mut(shard.keyword.as_str()) {
+                shards.push(shard);
+            }
+        }
+
+        let merged = shards_by_keyword
+            .into_iter()
+            .map(|(keyword, shards)| (keyword.to_string(), stamp_shard_postings(&shards)))
+            .collect();
+        Ok((merged, shard_keys))
+    }
+
+    /// Like [`Self::merge_many_keyword_shards_stamped`], answering keywords from their
+    /// entries in the merge cache when the shards they were merged from are unchanged.
+    /// An entry's shards are read back by name instead of listed. Keywords without a
+    /// current entry are listed and merged here rather than in the durable reader, so
+    /// the entries of those stored in every shard can be written.
+    pub async fn merge_many_keyword_shards_cached(
+        &self,
+        keywords: Vec<String>,
+    ) -> Result<CachedMerge, DataStoreError> {
+        let mut seen = HashSet::new();
+        let keywords: Vec<String> = keywords
+            .into_iter()
+            .filter(|kw| seen.insert(kw.clone()))
+            .collect();
+        let mut result = CachedMerge {
+            cache_ops: keywords.len(),
+            ..Default::default()
+        };
+
+        let loads = keywords
+            .iter()
+            .map(|keyword| MergedKeyword::load(self.state, &self.index, keyword));
+        let mut entries = vec![];
+        let mut misses = vec![];
+        // Keywords whose entry no longer holds them, dropped unless it's rewritten
+        let mut outdated = HashSet::new();
+        for (keyword, entry) in keywords.iter().zip(join_bounded(loads).await) {
+            match entry {
+                Ok(Some(entry)) if entry.n_shards == self.n_shards => entries.push(entry),
+                Ok(Some(_)) => {
+                    outdated.insert(keyword.clone());
+                    misses.push(keyword.clone());
+                }
+                Ok(None) => misses.push(keyword.clone()),
+                Err(err) => {
+                    edge_log!(
+                        console_warn,
+                        "KeywordManager",
+                        &self.index,
+                        "unreadable merge cache entry keyword={}: {}",
+                        keyword,
+                        err
+                    );
+                    misses.push(keyword.clone());
+                }
+            }
+        }
+
+        let entry_keys: Vec<&str> = entries
+            .iter()
+            .flat_map(|entry| entry.shard_keys.iter().map(String::as_str))
+            .collect();
+        result.shard_reads += entry_keys.len();
+        let kv_data = match entry_keys.is_empty() {
+            true => vec![],
+            false => self.bulk_reader()?.get_keyword_kv_keys(entry_keys).await,
+        };
+        let mut shards_by_keyword: HashMap<&str, Vec<&KeywordShardData>> = HashMap::new();
+        for shard in kv_data.iter() {
+            shards_by_keyword
+                .entry(shard.keyword.as_str())
+                .or_default()
+                .push(shard);
+        }
+        for entry in entries {
+            let shards = shards_by_keyword
+                .remove(entry.keyword.as_str())
+                .unwrap_or_default();
+            let shard_ts: Vec<u64> = shards.iter().map(|shard| shard.ts).collect();
+            if entry.is_current(self.n_shards, &shard_ts) {
+                result.cache_hits += 1;
+                let stamped = StampedPostings {
+                    postings: entry.postings,
+                    newest_ts: Some(entry.newest_ts),
+                };
+                result.merged.insert(entry.keyword, stamped);
+            } else if shard_ts.len() == entry.shard_keys.len() {
+                // Written since, but every shard is still there, and a keyword in
+                // every shard has no others, so what was read is the whole keyword
+                let stamped = stamp_shard_postings(&shards);
+                result.cache_ops += self
+                    .keep_merged(&entry.keyword, &entry.shard_keys, &stamped)
+                    .await;
+                result.merged.insert(entry.keyword, stamped);
+            } else {
+                outdated.insert(entry.keyword.clone());
+                misses.push(entry.keyword);
+            }
+        }
+
+        if !misses.is_empty() {
+            let (merged, shard_keys) = self.merge_by_keys(&misses).await?;
+            result.listings += misses.iter().filter(|kw| self.lists_shards(kw)).count();
+            result.shard_reads += shard_keys.iter().map(Vec::len).sum::<usize>();
+            for (keyword, keys) in misses.iter().zip(&shard_keys) {
+                let kept = match merged.get(keyword) {
+                    Some(stamped) => self.keep_merged(keyword, keys, stamped).await,
+                    None => 0,
+                };
+                result.cache_ops += kept;
+                if kept == 0 && outdated.contains(keyword) {
+                    let key = merged_keyword_kv_key(&self.index, keyword);
+                    // Left behind, it only costs later merges a read until it expires
+                    let _ = self.state.delete(&key).await;
+                    result.cache_ops += 1;
+                }
+            }
+            result.merged.extend(merged);
+        }
+        Ok(result)
+    }
+
+    /// Write the merge cache entry of `keyword` if it's one to keep, returning how
+    /// many writes that took. A failed write only costs the next search a listing.
+    async fn keep_merged(
+        &self,
+        keyword: &str,
+        shard_keys: &[String],
+        stamped: &StampedPostings,
+    ) -> usize {
+        let Some(entry) =
+            MergedKeyword::from_merge(&self.index, keyword, self.n_shards, shard_keys, stamped)
+        else {
+            return 0;
+        };
+        if let Err(err) = entry.store(self.state).await {
+            edge_log!(
+                console_warn,
+                "KeywordManager",
+                &self.index,
+                "failed to write merge cache entry keyword={}: {}",
+                keyword,
+                err
+            );
+        }
+        1
+    }
+
+    /// List and merge `keywords`, writing the merge cache entry of each stored in
+    /// every shard, for `POST /:index/warm`
+    pub async fn warm(&self, keywords: Vec<String>) -> Result<WarmReport, DataStoreError> {
+        let mut seen = HashSet::new();
+        let keywords: Vec<String> = keywords
+            .into_iter()
+            .filter(|kw| seen.insert(kw.clone()))
+            .collect();
+        let (merged, shard_keys) = self.merge_by_keys(&keywords).await?;
+        let mut report = WarmReport {
+            shards_read: shard_keys.iter().map(Vec::len).sum(),
+            ..Default::default()
+        };
+        for (keyword, keys) in keywords.into_iter().zip(&shard_keys) {
+            let entry = merged.get(&keyword).and_then(|stamped| {
+                MergedKeyword::from_merge(&self.index, &keyword, self.n_shards, keys, stamped)
+            });
+            match entry {
+                Some(entry) => {
+                    entry.store(self.state).await?;
+                    report.warmed.push(keyword);
+                }
+                None => report.skipped.push(keyword),
+            }
+        }
+        Ok(report)
+    }
+
+    /// Gather at least `wanted` of the best postings of `keyword_raw`. Up to
+    /// [`TOP_K`] postings are read exactly from the shards' top-K summaries;
+    /// otherwise shards are read a few at a time straight from KV, stopping once
+    /// comfortably more than `wanted` postings were read. Stopping early makes the
+    /// result approximate: an unread shard can hold a better posting than the last
+    /// ones returned.
+    pub async fn top_keyword_postings(
+        &self,
+        keyword: String,
+        wanted: usize,
+    ) -> Result<TopPostings, DataStoreError> {
+        let keyword_shards = list_keyword_shards(self.state, &self.index, &keyword).await?;
+        if wanted <= TOP_K {
+            return self
+                .top_postings_from_summaries(&keyword, &keyword_shards)
+                .await;
+        }
+
+        let mut shards: Vec<KeywordShardData> = vec![];
+        let mut shards_read = 0;
+        let mut gathered = 0;
+        for round in keyword_shards.chunks(TOP_SHARD_ROUND) {
+            if gathered >= wanted.max(1) * TOP_OVERSAMPLE {
+                break;
+            }
+            let reads = round.iter().map(async |key| {
+                KeywordShardData::read_with(key, self.state, self.codecs)
+                    .await
+                    .ok()
+            });
+            for shard in join_bounded(reads).await.into_iter().flatten() {
+                gathered += shard.docs.len();
+                shards.push(shard);
+            }
+            shards_read += round.len();
+        }
+
+        let postings = merge_shard_postings(shards.iter());
+        let shard_count = keyword_shards.len();
+        let exact = shards_read == shard_count;
+        let newest_ts = shards.iter().map(|shard| shard.ts).max().filter(|_| exact);
+        let total = match exact {
+            true => postings.len(),
+            false => postings.len() * shard_count / shards_read,
+        };
+        edge_log!(
+            console_debug,
+            "KeywordManager",
+            &self.index,
+            "top postings keyword={}, shards_read={}/{}, exact={}",
+            keyword,
+            shards_read,
+            shard_count,
+            exact
+        );
+        Ok(TopPostings {
+            postings,
+            total,
+            exact,
+            newest_ts,
+        })
+    }
+
+    /// Merge the top-K summaries of every shard in `keyword_shards`, reading the whole
+    /// shard instead where it has no summary yet
+    async fn top_postings_from_summaries(
+        &self,
+        keyword: &str,
+        keyword_shards: &[String],
+    ) -> Result<TopPostings, DataStoreError> {
+        let top_prefix = keyword_top_prefix(&self.index, keyword);
+        let summary_keys: HashSet<String> = list_all(self.state, &top_prefix)
+            .await?
+            .into_iter()
+            .collect();
+
+        let reads = keyword_shards.iter().map(async |shard_key| {
+            // Legacy unescaped shards have no summary under the escaped prefix
+            let summary_key = parse_keyword_shard_key(&self.index, shard_key)
+                .filter(|(_, shard)| {
+                    *shard_key == keyword_shard_kv_key(&self.index, keyword, *shard)
+                })
+                .map(|(_, shard)| keyword_top_kv_key(&self.index, keyword, shard))
+                .filter(|summary_key| summary_keys.contains(summary_key));
+            // Summaries aren't rewritten on every write, so only whole shards have a ts
+            match summary_key {
+                Some(summary_key) => KeywordShardTop::read(&summary_key, self.state)
+                    .await
+                    .ok()
+                    .map(|summary| (summary.top, summary.count, None)),
+                None => KeywordShardData::read_with(shard_key, self.state, self.codecs)
+                    .await
+                    .ok()
+                    .map(|shard| {
+                        let count = shard.docs.len();
+                        (shard.docs, count, Some(shard.ts))
+                    }),
+            }
+        });
+        let mut postings: MergedKeywordData = vec![];
+        let mut total = 0;
+        let mut stamps = vec![];
+        for (top, count, ts) in join_bounded(reads).await.into_iter().flatten() {
+            postings.extend(top);
+            total += count;
+            stamps.push(ts);
+        }
+        // Every stamp, or none if a summary was read
+        let newest_ts = stamps
+            .into_iter()
+            .collect::<Option<Vec<u64>>>()
+            .and_then(|stamps| stamps.into_iter().max());
+        postings.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+
+        let summaries = summary_keys.len();
+        let shard_count = keyword_shards.len();
+        edge_log!(
+            console_debug,
+            "KeywordManager",
+            &self.index,
+            "top postings keyword={}, summaries={}/{}",
+            keyword,
+            summaries,
+            shard_count
+        );
+        Ok(TopPostings {
+            postings,
+            total,
+            exact: true,
+            newest_ts,
+        })
+    }
+
+    /// The keywords co-occurring with `keyword` in its best scored documents, read
+    /// from the `keywords` stored on each sampled document
+    pub async fn related_keywords(
+        &self,
+        keyword: String,
+        limit: usize,
+    ) -> Result<Vec<RelatedKeyword>, DataStoreError> {
+        let mut postings = self.merge_keyword_shards(keyword.clone()).await?;
+        postings.truncate(RELATED_DOCUMENT_SAMPLE);
+
+        let doc_kv_keys: Vec<String> = postings
+            .iter()
+            .map(|(doc_id, _)| document_kv_key(&self.index, doc_id))
+            .collect();
+        let documents = self
+            .bulk_reader()?
+            .get_documents_kv_keys(doc_kv_keys.iter().map(|key| key.as_str()).collect())
+            .await;
+
+        Ok(rank_related(
+            &keyword,
+            documents
+                .iter()
+                .flatten()
+                .filter_map(|document| document.keywords.as_deref()),
+            limit,
+        ))
+    }
+
+    /// The shard each of `document`'s keywords is stored in, checked against the
+    /// shards themselves with `verify`
+    pub async fn inspect_document(
+        &self,
+        document: &Document,
+        verify: bool,
+    ) -> Result<DocumentKeywords, DataStoreError> {
+        let bulk_reader = self.bulk_reader()?;
+        inspect_document_keywords(document, self.state, &bulk_reader, self.n_shards, verify).await
+    }
+
+    /// Check the next batch of this index's documents or shards for inconsistent
+    /// postings, see [`fsck_batch`]
+    pub async fn fsck(
+        &self,
+        cursor: FsckCursor,
+        repair: bool,
+        max_examples: usize,
+    ) -> Result<FsckReport, DataStoreError> {
+        let options = FsckOptions {
+            repair,
+            max_examples,
+            n_shards: self.n_shards,
+            now: self.clock.now_millis(),
+            codecs: self.codecs,
+        };
+        let bulk_reader = self.bulk_reader()?;
+        fsck_batch(&self.index, self.state, &bulk_reader, cursor, &options).await
+    }
+
+    /// Rank the next batch of the index's documents or keyword shards after `cursor`
+    pub async fn top(
+        &self,
+        cursor: Option<PageCursor>,
+        options: &TopOptions,
+    ) -> Result<TopReport, DataStoreError> {
+        let bulk_reader = self.bulk_reader()?;
+        top_batch(&self.index, self.state, &bulk_reader, cursor, options).await
+    }
+
+    /// Export the next `limit` of the index's keywords after `cursor`, with their
+    /// shard and document counts
+    pub async fn export(
+        &self,
+        cursor: ExportCursor,
+        limit: usize,
+    ) -> Result<ExportPage, DataStoreError> {
+        let bulk_reader = self.bulk_reader()?;
+        export_batch(&self.index, self.state, &bulk_reader, cursor, limit).await
+    }
+
+    /// The distinct stored keywords starting with `prefix`, found by listing their
+    /// shard keys rather than reading any shards
+    pub async fn list_keywords_with_prefix(
+        &self,
+        prefix: &str,
+    ) -> Result<Vec<String>, DataStoreError> {
+        let listing_prefix = format!(
+            "{}:{}{}",
+            self.index,
+            PREFIX_KEYWORD,
+            escape_keyword(prefix)
+        );
+        let shard_keys = list_all(self.state, &listing_prefix).await?;
+        let keywords: BTreeSet<String> = shard_keys
+            .iter()
+            .filter_map(|key| parse_keyword_shard_key(&self.index, key))
+            .map(|(keyword, _shard)| keyword)
+            .collect();
+        Ok(keywords.into_iter().collect())
+    }
+
+    /// Have the durable reader list, read and merge the shards of `keywords`, returning
+    /// the merged postings and the number of shards it read. Keywords held in the
+    /// [`reader_cache`] are only sent back when one of their shards was written since.
+    async fn merge_via_reader(
+        &self,
+        reader: &ObjectNamespace,
+        keywords: Vec<String>,
+    ) -> Result<(HashMap<String, StampedPostings>, usize), DataStoreError> {
+        let stub = self.placement.reader_id(reader, &self.index)?.get_stub()?;
+        let limit = get_merged_keyword_limit(self.n_shards) as usize;
+        let version = self.codecs.version;
+        let requests: Vec<_> = keywords
+            .chunks(limit)
+            .map(async |chunk| {
+                // Kept aside, in case the cache drops an entry before the answer arrives
+                let held = reader_cache::snapshot(&self.index, version, chunk);
+                let body = serde_json::to_string(&MergedKeywordsRequest {
+                    index: self.index.clone(),
+                    keywords: chunk.to_vec(),
+                    version,
+                    n_shards: self.known_n_shards.then_some(self.n_shards),
+                    if_shard_newer_than: reader_cache::validators(&held),
+                })
+                .map_err(DataStoreError::Serialization)?;
+                let req = Request::new_with_init(
+                    "https://do/merged-keywords",
+                    &RequestInit {
+                        method: Method::Post,
+                        body: Some(body.as_str().into()),
+                        ..Default::default()
+                    },
+                )?;
+
+                self.state.count_subrequests(1);
+                let mut response = stub.fetch_with_request(req).await?;
+                if response.status_code() != 200 {
+                    return Err(DataStoreError::Worker(worker::Error::RustError(format!(
+                        "durable reader returned {}: {}",
+                        response.status_code(),
+                        response.text().await.unwrap_or_default()
+                    ))));
+                }
+                let shard_count: usize = response
+                    .headers()
+                    .get(SHARD_READS_HEADER)?
+                    .and_then(|count| count.parse().ok())
+                    .unwrap_or(0);
+                let bytes = response.bytes().await?;
+                let frames = read_length_prefixed_stamped::<MergedKeywordData>(&bytes);
+                Ok((
+                    reader_cache::resolve(&held, frames)?,
+                    held.len(),
+                    shard_count,
+                ))
+            })
+            .collect();
+
+        let mut merged = HashMap::new();
+        let mut total_held = 0;
+        let mut total_shards = 0;
+        for result in join_bounded(requests).await {
+            let (entries, held, shard_count) = result?;
+            merged.extend(entries);
+            total_held += held;
+            total_shards += shard_count;
+        }
+        reader_cache::store(&self.index, version, &merged);
+
+        edge_log!(
+            console_debug,
+            "KeywordManager",
+            &self.index,
+            "durable keyword merge completed keywords={}, cached={}, shard_count={}",
+            (keywords.len()),
+            total_held,
+            total_shards
+        );
+        Ok((merged, total_shards))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+
+    use super::*;
+    use crate::data::{
+        document::{shard_from_document_id, testing::index_text, Document},
+        fsck::DEFAULT_FSCK_EXAMPLES,
+        keyword_shard::{
+            keyword_shard_kv_key, keyword_top_kv_key,
+            testing::{seed_postings, write_legacy_shard},
+            ShardWriteBatch,
+        },
+        merge_cache::MERGE_CACHE_TTL_SECS,
+        storage::memory::MemoryStorage,
+        trace::ShardLookup,
+        KvPersistent, DEFAULT_N_SHARDS,
+    };
+    use crate::util::time::ManualClock;
+
+    const N_SHARDS: u32 = 8;
+
+    fn seeded_store() -> MemoryStorage {
+        let store = MemoryStorage::default();
+        let ocean: Vec<(String, f64)> = (0..10)
+            .map(|i| (format!("doc{}", i), i as f64 / 10.0))
+            .collect();
+        let ocean: Vec<(&str, f64)> = ocean.iter().map(|(d, s)| (d.as_str(), *s)).collect();
+        seed_postings(&store, "idx", N_SHARDS, "ocean", &ocean);
+        seed_postings(
+            &store,
+            "idx",
+            N_SHARDS,
+            "storm",
+            &[("doc1", 0.9), ("doc2", 0.8)],
+        );
+        seed_postings(&store, "other", N_SHARDS, "ocean", &[("doc99", 1.0)]);
+        store
+    }
+
+    #[test]
+    fn test_merge_keyword_shards_across_shards() {
+        let store = seeded_store();
+        let manager = KeywordManager::direct("idx".into(), N_SHARDS, &store);
+        let (merged, shards_read) =
+            block_on(manager.merge_keyword_shards_counted("ocean".into())).unwrap();
+
+        assert_eq!(merged.len(), 10);
+        assert!(
+            shards_read > 1,
+            "postings should be spread over several shards"
+        );
+        assert_eq!(merged[0], ("doc9".to_string(), 0.9));
+        assert!(merged.windows(2).all(|w| w[0].1 >= w[1].1));
+        assert!(merged.iter().all(|(doc, _)| doc != "doc99"));
+    }
+
+    #[test]
+    fn test_both_versions_read_alike() {
+        let stored = [CodecSet::V1, CodecSet::V2].map(|codecs| {
+            let store = MemoryStorage::default();
+            for i in 0..60 {
+                let doc_id = format!("doc{}", i);
+                let mut batch = ShardWriteBatch::new("idx", &doc_id, N_SHARDS).with_codecs(codecs);
+                batch.upsert("ocean", (i % 25) as f64 / 25.0);
+                if i % 3 == 0 {
+                    batch.upsert("storm", i as f64 / 60.0);
+                }
+                for (_, result) in block_on(batch.execute(&store, 1)) {
+                    result.unwrap();
+                }
+            }
+            let mut batch = ShardWriteBatch::new("idx", "doc3", N_SHARDS).with_codecs(codecs);
+            batch.remove("storm");
+            batch.upsert("ocean", 2.0);
+            for (_, result) in block_on(batch.execute(&store, 2)) {
+                result.unwrap();
+            }
+
+            let manager =
+                KeywordManager::direct("idx".into(), N_SHARDS, &store).with_codecs(codecs);
+            let keywords = vec!["ocean".to_string(), "storm".to_string()];
+            let merged = block_on(manager.merge_many_keyword_shards(keywords)).unwrap();
+            let top = block_on(manager.top_keyword_postings("ocean".into(), 3)).unwrap();
+            let sampled =
+                block_on(manager.top_keyword_postings("ocean".into(), TOP_K + 1)).unwrap();
+            let bytes: usize = store
+                .keys()
+                .iter()
+                .map(|key| block_on(store.get(key)).unwrap().unwrap().len())
+                .sum();
+            let read = (merged, top.postings, top.total, sampled.postings);
+            (read, bytes)
+        });
+        let [(v1, v1_bytes), (v2, v2_bytes)] = stored;
+        assert_eq!(v1.1[0], ("doc3".to_string(), 2.0));
+        assert_eq!(v1, v2);
+        assert!(v2_bytes < v1_bytes, "{} < {}", v2_bytes, v1_bytes);
+    }
+
+    #[test]
+    fn test_top_postings_of_small_keyword_are_exact() {
+        let store = seeded_store();
+        let manager = KeywordManager::direct("idx".into(), N_SHARDS, &store);
+        let top = block_on(manager.top_keyword_postings("ocean".into(), 5)).unwrap();
+
+        // Ten postings never reach the early exit, so every shard is read
+        assert!(top.exact);
+        assert_eq!(top.total, 10);
+        let (merged, _) = block_on(manager.merge_keyword_shards_counted("ocean".into())).unwrap();
+        assert_eq!(top.postings, merged);
+    }
+
+    #[test]
+    fn test_top_postings_stop_early() {
+        let store = MemoryStorage::default();
+        let postings: Vec<(String, f64)> = (0..1600)
+            .map(|i| (format!("doc{}", i), (i % 100) as f64 / 100.0))
+            .collect();
+        let postings: Vec<(&str, f64)> = postings.iter().map(|(d, s)| (d.as_str(), *s)).collect();
+        seed_postings(&store, "idx", 64, "common", &postings);
+        let manager = KeywordManager::direct("idx".into(), 64, &store);
+
+        // More than the summaries hold, so whole shards are sampled
+        let wanted = TOP_K + 1;
+        let before = store.counts();
+        let top = block_on(manager.top_keyword_postings("common".into(), wanted)).unwrap();
+        assert!(!top.exact);
+        assert_eq!(store.counts().gets - before.gets, TOP_SHARD_ROUND);
+        assert!(top.postings.len() >= wanted * TOP_OVERSAMPLE);
+        assert!(top.postings.windows(2).all(|w| w[0].1 >= w[1].1));
+        // Estimated from the sampled shards
+        assert!(top.total > top.postings.len() && top.total < 4_000);
+    }
+
+    #[test]
+    fn test_top_postings_from_summaries() {
+        let store = MemoryStorage::default();
+        let postings: Vec<(String, f64)> = (0..400)
+            .map(|i| (format!("doc{}", i), (i % 100) as f64 / 100.0))
+            .collect();
+        let postings: Vec<(&str, f64)> = postings.iter().map(|(d, s)| (d.as_str(), *s)).collect();
+        seed_postings(&store, "idx", 4, "common", &postings);
+        let manager = KeywordManager::direct("idx".into(), 4, &store);
+        let (merged, _) = block_on(manager.merge_keyword_shards_counted("common".into())).unwrap();
+        let scores = |postings: &MergedKeywordData| -> Vec<f64> {
+            postings[..10].iter().map(|(_, score)| *score).collect()
+        };
+
+        let top = block_on(manager.top_keyword_postings("common".into(), 10)).unwrap();
+        assert!(top.exact);
+        assert_eq!(top.total, 400);
+        // Each shard contributes only its summary
+        assert_eq!(top.postings.len(), 4 * TOP_K);
+        assert_eq!(scores(&top.postings), scores(&merged));
+
+        // A shard without a summary, as written before summaries existed, is read whole
+        block_on(store.delete(&keyword_top_kv_key("idx", "common", 0))).unwrap();
+        let top = block_on(manager.top_keyword_postings("common".into(), 10)).unwrap();
+        assert_eq!(top.total, 400);
+        assert!(top.postings.len() > 4 * TOP_K);
+        assert_eq!(scores(&top.postings), scores(&merged));
+    }
+
+    /// The postings of `keywords` merged by `manager`, and the gets and lists it made
+    fn merge_counting(
+        store: &MemoryStorage,
+        manager: &KeywordManager<'_, MemoryStorage>,
+        keywords: &[&str],
+    ) -> (HashMap<String, MergedKeywordData>, usize, usize) {
+        let before = store.counts();
+        let keywords = keywords.iter().map(|kw| kw.to_string()).collect();
+        let merged = block_on(manager.merge_many_keyword_shards(keywords)).unwrap();
+        let after = store.counts();
+        (merged, after.gets - before.gets, after.lists - before.lists)
+    }
+
+    #[test]
+    fn test_known_shard_count_skips_listing() {
+        let store = seeded_store();
+        let keywords = ["ocean", "storm", "missing"];
+        let listing = KeywordManager::direct("idx".into(), N_SHARDS, &store);
+        let (listed, listed_gets, listed_lists) = merge_counting(&store, &listing, &keywords);
+        let naming = KeywordManager::direct("idx".into(), 1, &store).with_known_n_shards(N_SHARDS);
+        let (named, named_gets, named_lists) = merge_counting(&store, &naming, &keywords);
+
+        assert_eq!(named, listed);
+        // At least one listing per keyword and a get per stored shard, against a get
+        // per shard whether it's stored or not
+        assert!(listed_lists >= keywords.len());
+        assert!(listed_gets < N_SHARDS as usize * 2);
+        assert_eq!(
+            (named_lists, named_gets),
+            (0, N_SHARDS as usize * keywords.len())
+        );
+        assert!(!naming.lists_shards("ocean") && listing.lists_shards("ocean"));
+    }
+
+    #[test]
+    fn test_known_shard_count_traces_lookup() {
+        let store = MemoryStorage::default();
+        seed_postings(&store, "idx", N_SHARDS, "ocean", &[("doc1", 0.5)]);
+        seed_postings(&store, "idx", N_SHARDS, "http://example", &[("doc2", 0.7)]);
+        write_legacy_shard(&store, "idx", "http://example", 3, &[("doc3", 0.2)]);
+        let trace = ReadTrace::default();
+        let manager = KeywordManager::direct("idx".into(), N_SHARDS, &store)
+            .with_known_n_shards(N_SHARDS)
+            .with_trace(Some(&trace));
+
+        // Shards that may be stored under a legacy unescaped key are still listed
+        let merged = block_on(
+            manager.merge_many_keyword_shards(vec!["ocean".into(), "http://example".into()]),
+        )
+        .unwrap();
+        assert_eq!(merged["http://example"].len(), 2);
+        let diagnostics = trace.finish();
+        let lookups: Vec<(&str, ShardLookup, usize)> = diagnostics
+            .keywords
+            .iter()
+            .map(|kw| (kw.keyword.as_str(), kw.lookup, kw.shards.len()))
+            .collect();
+        assert_eq!(
+            lookups,
+            vec![
+                ("ocean", ShardLookup::Enumerated, N_SHARDS as usize),
+                ("http://example", ShardLookup::Listed, 2),
+            ]
+        );
+        let read: Vec<usize> = diagnostics.keywords[0]
+            .shards
+            .iter()
+            .map(|shard| shard.postings)
+            .collect();
+        assert_eq!(read.iter().sum::<usize>(), 1);
+    }
+
+    /// A store where "ocean" is in every shard and "storm" in only one
+    fn hot_store() -> MemoryStorage {
+        let store = MemoryStorage::default();
+        let ocean: Vec<(String, f64)> = (0..64)
+            .map(|i| (format!("doc{}", i), i as f64 / 64.0))
+            .collect();
+        let ocean: Vec<(&str, f64)> = ocean.iter().map(|(d, s)| (d.as_str(), *s)).collect();
+        seed_postings(&store, "idx", N_SHARDS, "ocean", &ocean);
+        seed_postings(&store, "idx", N_SHARDS, "storm", &[("doc1", 0.9)]);
+        store
+    }
+
+    #[test]
+    fn test_merge_cache_hit_skips_listing() {
+        let store = hot_store();
+        let manager = KeywordManager::direct("idx".into(), N_SHARDS, &store).with_merge_cache(true);
+        assert!(manager.caches_merges());
+        let keywords = vec!["ocean".to_string(), "storm".to_string()];
+        let first = block_on(manager.merge_many_keyword_shards_cached(keywords.clone())).unwrap();
+        assert_eq!((first.cache_hits, first.listings), (0, 2));
+        assert_eq!(first.merged["ocean"].postings.len(), 64);
+
+        // Only the keyword stored in every shard is kept
+        let ocean_key = merged_keyword_kv_key("idx", "ocean");
+        assert_eq!(store.ttl(&ocean_key), Some(MERGE_CACHE_TTL_SECS));
+        assert!(!store
+            .keys()
+            .contains(&merged_keyword_kv_key("idx", "storm")));
+
+        let before = store.counts();
+        let second = block_on(manager.merge_many_keyword_shards_cached(keywords)).unwrap();
+        let after = store.counts();
+        assert_eq!((second.cache_hits, second.listings), (1, 1));
+        assert_eq!(after.lists - before.lists, 1, "only storm is listed");
+        assert_eq!(second.merged, first.merged);
+        assert_eq!(
+            second.merged,
+            block_on(
+                manager.merge_many_keyword_shards_stamped(vec!["ocean".into(), "storm".into()])
+            )
+            .unwrap()
+            .0
+        );
+
+        // Known shard counts name their keys and keep nothing
+        let naming = KeywordManager::direct("idx".into(), N_SHARDS, &store)
+            .with_known_n_shards(N_SHARDS)
+            .with_merge_cache(true);
+        assert!(!naming.caches_merges());
+    }
+
+    #[test]
+    fn test_merge_cache_invalidated_by_shard_write() {
+        let store = hot_store();
+        let manager = KeywordManager::direct("idx".into(), N_SHARDS, &store).with_merge_cache(true);
+        let report = block_on(manager.warm(vec!["ocean".into(), "storm".into()])).unwrap();
+        assert_eq!(
+            (report.warmed, report.skipped),
+            (vec!["ocean".to_string()], vec!["storm".to_string()])
+        );
+
+        // Rescoring a posting moves its shard's timestamp past the entry's
+        let mut batch = ShardWriteBatch::new("idx", "doc3", N_SHARDS);
+        batch.upsert("ocean", 2.0);
+        for (_, result) in block_on(batch.execute(&store, 5)) {
+            result.unwrap();
+        }
+        let before = store.counts();
+        let stale =
+            block_on(manager.merge_many_keyword_shards_cached(vec!["ocean".into()])).unwrap();
+        assert_eq!((stale.cache_hits, stale.listings), (0, 0));
+        assert_eq!(store.counts().lists, before.lists);
+        let ocean = &stale.merged["ocean"];
+        assert_eq!(ocean.postings[0], ("doc3".to_string(), 2.0));
+        assert_eq!(ocean.newest_ts, Some(5));
+
+        // The entry was rewritten from the shards read, so the next merge hits it
+        let fresh =
+            block_on(manager.merge_many_keyword_shards_cached(vec!["ocean".into()])).unwrap();
+        assert_eq!(fresh.cache_hits, 1);
+        assert_eq!(&fresh.merged["ocean"], ocean);
+
+        // A shard gone since the entry was written falls back to listing
+        block_on(store.delete(&keyword_shard_kv_key("idx", "ocean", 0))).unwrap();
+        let relisted =
+            block_on(manager.merge_many_keyword_shards_cached(vec!["ocean".into()])).unwrap();
+        assert_eq!((relisted.cache_hits, relisted.listings), (0, 1));
+        assert!(relisted.merged["ocean"].postings.len() < 64);
+        assert!(!store
+            .keys()
+            .contains(&merged_keyword_kv_key("idx", "ocean")));
+    }
+
+    #[test]
+    fn test_merge_missing_keyword_is_empty() {
+        let store = seeded_store();
+        let manager = KeywordManager::direct("idx".into(), N_SHARDS, &store);
+        let merged = block_on(manager.merge_keyword_shards("missing".into())).unwrap();
+        assert!(merged.is_empty());
+    }
+
+    #[test]
+    fn test_merge_many_matches_individual_merges() {
+        let store = seeded_store();
+        let manager = KeywordManager::direct("idx".into(), N_SHARDS, &store);
+        let batch = block_on(manager.merge_many_keyword_shards(vec![
+            "ocean".into(),
+            "storm".into(),
+            "ocean".into(),
+            "missing".into(),
+        ]))
+        .unwrap();
+
+        assert_eq!(batch.len(), 3);
+        for keyword in ["ocean", "storm"] {
+            let single = block_on(manager.merge_keyword_shards(keyword.into())).unwrap();
+            assert_eq!(batch[keyword], single);
+        }
+        assert!(batch["missing"].is_empty());
+    }
+
+    #[test]
+    fn test_merge_many_counts_every_shard_read() {
+        let store = seeded_store();
+        let manager = KeywordManager::direct("idx".into(), N_SHARDS, &store);
+        let (_, batch_reads) = block_on(
+            manager.merge_many_keyword_shards_counted(vec!["ocean".into(), "storm".into()]),
+        )
+        .unwrap();
+
+        let single_reads: usize = ["ocean", "storm"]
+            .iter()
+            .map(|kw| {
+                block_on(manager.merge_keyword_shards_counted(kw.to_string()))
+                    .unwrap()
+                    .1
+            })
+            .sum();
+        assert_eq!(batch_reads, single_reads);
+    }
+
+    #[test]
+    fn test_list_keywords_with_prefix() {
+        let store = seeded_store();
+        seed_postings(&store, "idx", N_SHARDS, "oceans", &[("doc3", 0.4)]);
+        let manager = KeywordManager::direct("idx".into(), N_SHARDS, &store);
+
+        let keywords = block_on(manager.list_keywords_with_prefix("oc")).unwrap();
+        assert_eq!(keywords, vec!["ocean", "oceans"]);
+        assert!(block_on(manager.list_keywords_with_prefix("zz"))
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_keywords_containing_separator() {
+        let store = MemoryStorage::default();
+        seed_postings(&store, "idx", N_SHARDS, "http", &[("doc1", 0.5)]);
+        seed_postings(&store, "idx", N_SHARDS, "http://example", &[("doc2", 0.7)]);
+        write_legacy_shard(&store, "idx", "http://example", 3, &[("doc3", 0.2)]);
+        let manager = KeywordManager::direct("idx".into(), N_SHARDS, &store);
+
+        // The shards of `http://example` share `http`'s old unescaped prefix
+        let http = block_on(manager.merge_keyword_shards("http".into())).unwrap();
+        assert_eq!(http, vec![("doc1".to_string(), 0.5)]);
+        let url = block_on(manager.merge_keyword_shards("http://example".into())).unwrap();
+        assert_eq!(
+            url,
+            vec![("doc2".to_string(), 0.7), ("doc3".to_string(), 0.2)]
+        );
+
+        let many = block_on(
+            manager.merge_many_keyword_shards(vec!["http".into(), "http://example".into()]),
+        )
+        .unwrap();
+        assert_eq!((&many["http"], &many["http://example"]), (&http, &url));
+
+        let top = block_on(manager.top_keyword_postings("http://example".into(), 5)).unwrap();
+        assert_eq!((top.postings, top.total), (url, 2));
+
+        let keywords = block_on(manager.list_keywords_with_prefix("http")).unwrap();
+        assert_eq!(keywords, vec!["http", "http://example"]);
+    }
+
+    #[test]
+    fn test_keywords_are_read_exactly() {
+        let store = MemoryStorage::default();
+        seed_postings(&store, "idx", N_SHARDS, "c%20sharp", &[("doc1", 0.5)]);
+        seed_postings(&store, "idx", N_SHARDS, "c sharp", &[("doc2", 0.6)]);
+        seed_postings(&store, "idx", N_SHARDS, "c++", &[("doc3", 0.7)]);
+        let manager = KeywordManager::direct("idx".into(), N_SHARDS, &store);
+
+        // Nothing is decoded below the HTTP layer, so each keyword only finds itself
+        for (keyword, doc, score) in [
+            ("c%20sharp", "doc1", 0.5),
+            ("c sharp", "doc2", 0.6),
+            ("c++", "doc3", 0.7),
+        ] {
+            let expected = vec![(doc.to_string(), score)];
+            let merged = block_on(manager.merge_keyword_shards(keyword.into())).unwrap();
+            assert_eq!(merged, expected, "{}", keyword);
+            let top = block_on(manager.top_keyword_postings(keyword.into(), 5)).unwrap();
+            assert_eq!(top.postings, expected, "{}", keyword);
+        }
+        let missing = block_on(manager.merge_keyword_shards("c%2B%2B".into())).unwrap();
+        assert!(missing.is_empty());
+    }
+
+    /// Store a document with precomputed keywords, and its postings
+    fn store_document(store: &MemoryStorage, id: &str, keywords: &[(&str, f64)]) {
+        let mut document = Document::new_with_id("idx", id);
+        document.keywords = Some(
+            keywords
+                .iter()
+                .map(|(kw, s)| (kw.to_string(), (*s).into()))
+                .collect(),
+        );
+        block_on(document.write(store)).unwrap();
+        for (keyword, score) in keywords {
+            seed_postings(store, "idx", N_SHARDS, keyword, &[(id, *score)]);
+        }
+    }
+
+    #[test]
+    fn test_related_keywords_over_corpus() {
+        let store = MemoryStorage::default();
+        store_document(
+            &store,
+            "d1",
+            &[("ocean", 0.9), ("tide", 0.5), ("reef", 0.3)],
+        );
+        store_document(
+            &store,
+            "d2",
+            &[("ocean", 0.6), ("tide", 0.4), ("storm", 0.8)],
+        );
+        store_document(&store, "d3", &[("ocean", 0.2), ("reef", 0.9)]);
+        store_document(&store, "d4", &[("forest", 0.9), ("tide", 0.9)]);
+        let manager = KeywordManager::direct("idx".into(), N_SHARDS, &store);
+
+        let related = block_on(manager.related_keywords("ocean".into(), 10)).unwrap();
+        let ranking: Vec<(&str, f64)> = related
+            .iter()
+            .map(|r| (r.keyword.as_str(), (r.cooccurrence * 100.0).round() / 100.0))
+            .collect();
+        // d4 doesn't mention ocean, so its tide and forest don't count
+        assert_eq!(ranking, vec![("reef", 1.2), ("tide", 0.9), ("storm", 0.8)]);
+
+        let top = block_on(manager.related_keywords("ocean".into(), 1)).unwrap();
+        assert_eq!(top.len(), 1);
+        assert!(block_on(manager.related_keywords("volcano".into(), 10))
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_fsck_stamps_repairs_with_the_clock() {
+        let store = MemoryStorage::default();
+        let ocean = index_text(&store, "idx", "doc1", "Ocean tides wash the sandy beach.");
+        let dropped = ocean.keywords.unwrap()[0].0.clone();
+        let shard = shard_from_document_id("doc1".into(), DEFAULT_N_SHARDS);
+        let key = keyword_shard_kv_key("idx", &dropped, shard);
+        block_on(store.delete(&key)).unwrap();
+
+        let manager = KeywordManager::direct("idx".into(), DEFAULT_N_SHARDS, &store)
+            .with_clock(ManualClock::at(42));
+        let mut cursor = Some(FsckCursor::start());
+        while let Some(next) = cursor {
+            let report = block_on(manager.fsck(next, true, DEFAULT_FSCK_EXAMPLES)).unwrap();
+            cursor = report.cursor.map(|cursor| cursor.parse().unwrap());
+        }
+        assert_eq!(
+            block_on(KeywordShardData::read(&key, &store)).unwrap().ts,
+            42
+        );
     }
 }
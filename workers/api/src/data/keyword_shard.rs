@@ -1,24 +1,193 @@
+use std::{borrow::Cow, collections::BTreeMap};
+
 use serde::{Deserialize, Serialize};
-use worker::{kv::KvStore, Env};
 
 use crate::{
     data::{
-        document::shard_from_document_id, DataStoreError, DocumentRef, IndexName, KeywordRef,
-        KvEntry, KvPersistent, DEFAULT_N_SHARDS, ENV_VAR_N_SHARDS, PREFIX_KEYWORD,
+        codec::{CodecSet, ShardCodec},
+        document::shard_from_document_id,
+        storage::{list_all, Storage},
+        DataStoreError, DocumentRef, IndexName, KeywordRef, KvEntry, KvPersistent,
+        DEFAULT_N_SHARDS, ENV_VAR_N_SHARDS, MAX_N_SHARDS, PREFIX_KEYWORD, PREFIX_KEYWORD_STAGED,
+        PREFIX_KEYWORD_TOP,
     },
     edge_log,
+    util::{concurrency::join_bounded, env::parse_env_u32},
 };
 
+/// The shard count new indexes are created with. Never 0, which documents couldn't
+/// be assigned a shard of.
 pub fn get_n_shards(env: &worker::Env) -> u32 {
-    env.var(ENV_VAR_N_SHARDS)
-        .map_err(DataStoreError::Worker)
-        .map(|v| v.to_string().parse::<u32>())
-        .unwrap_or(Ok(DEFAULT_N_SHARDS))
-        .unwrap()
+    parse_env_u32(env, ENV_VAR_N_SHARDS, DEFAULT_N_SHARDS, 1..=MAX_N_SHARDS)
+}
+
+/// The keyword as it appears in shard keys, with `%` and `:` percent-encoded so a
+/// keyword like `http://example` can't be mistaken for the shard number separator
+/// or match the shard prefix of `http`
+pub fn escape_keyword(keyword: &str) -> Cow<'_, str> {
+    match keyword.contains(['%', ':']) {
+        true => Cow::Owned(keyword.replace('%', "%25").replace(':', "%3A")),
+        false => Cow::Borrowed(keyword),
+    }
+}
+
+/// Reverse [`escape_keyword`], leaving any other `%` as it is
+pub fn unescape_keyword(escaped: &str) -> String {
+    let mut keyword = String::with_capacity(escaped.len());
+    let mut rest = escaped;
+    while let Some(position) = rest.find('%') {
+        keyword.push_str(&rest[..position]);
+        let code = rest.get(position + 1..position + 3);
+        match code.map(str::to_ascii_uppercase).as_deref() {
+            Some("25") => keyword.push('%'),
+            Some("3A") => keyword.push(':'),
+            _ => {
+                keyword.push('%');
+                rest = &rest[position + 1..];
+                continue;
+            }
+        }
+        rest = &rest[position + 3..];
+    }
+    keyword.push_str(rest);
+    keyword
+}
+
+/// The KV prefix under which every shard of a keyword is stored
+pub fn keyword_shard_prefix(index: &str, keyword: &str) -> String {
+    format!("{}:{}{}:", index, PREFIX_KEYWORD, escape_keyword(keyword))
+}
+
+/// The prefix shards of `keyword` were stored under before keywords were escaped in
+/// keys, when that differs from [`keyword_shard_prefix`]
+pub fn legacy_keyword_shard_prefix(index: &str, keyword: &str) -> Option<String> {
+    let escaped = escape_keyword(keyword);
+    matches!(escaped, Cow::Owned(_)).then(|| format!("{}:{}{}:", index, PREFIX_KEYWORD, keyword))
+}
+
+/// Whether `key` is a shard of the keyword listed by `prefix`, rather than a shard of a
+/// legacy unescaped keyword that merely starts with the same text
+pub fn is_keyword_shard_key(prefix: &str, key: &str) -> bool {
+    key.strip_prefix(prefix)
+        .is_some_and(|shard| !shard.is_empty() && shard.bytes().all(|b| b.is_ascii_digit()))
+}
+
+/// The keyword and shard number of a shard key of `index`. Legacy unescaped keys
+/// parse too, by splitting at their last `:`.
+pub fn parse_keyword_shard_key(index: &str, key: &str) -> Option<(String, u32)> {
+    parse_shard_key(index, PREFIX_KEYWORD, key)
+}
+
+fn parse_shard_key(index: &str, kind: &str, key: &str) -> Option<(String, u32)> {
+    let rest = key
+        .strip_prefix(index)?
+        .strip_prefix(':')?
+        .strip_prefix(kind)?;
+    let (keyword, shard) = rest.rsplit_once(':')?;
+    Some((unescape_keyword(keyword), shard.parse().ok()?))
+}
+
+/// The KV prefix a reshard stages the shards of a keyword under, in the new layout,
+/// before they replace the keyword's shards
+pub fn staged_shard_prefix(index: &str, keyword: &str) -> String {
+    format!(
+        "{}:{}{}:",
+        index,
+        PREFIX_KEYWORD_STAGED,
+        escape_keyword(keyword)
+    )
+}
+
+pub fn staged_shard_kv_key(index: &str, keyword: &str, shard: u32) -> String {
+    format!("{}{}", staged_shard_prefix(index, keyword), shard)
+}
+
+/// The keyword and shard number of a staged shard key of `index`
+pub fn parse_staged_shard_key(index: &str, key: &str) -> Option<(String, u32)> {
+    parse_shard_key(index, PREFIX_KEYWORD_STAGED, key)
+}
+
+/// The index, keyword and shard number named by a shard key, staged or not. Index
+/// names can't hold a `:`, so the index is everything before the first one.
+pub fn parse_any_shard_key(key: &str) -> Option<(IndexName, String, u32)> {
+    let (index, _) = key.split_once(':')?;
+    let (keyword, shard) =
+        parse_keyword_shard_key(index, key).or_else(|| parse_staged_shard_key(index, key))?;
+    Some((index.to_string(), keyword, shard))
+}
+
+/// Stored beside a keyword's shards while a reshard replaces them, telling readers
+/// to read the keyword's staged shards instead
+pub fn reshard_marker_key(index: &str, keyword: &str) -> String {
+    format!("{}resharding", keyword_shard_prefix(index, keyword))
+}
+
+/// Every shard key of `keyword`, including shards still stored under the unescaped
+/// legacy key. While a reshard is replacing the keyword's shards, these are its
+/// staged shards instead, which hold every posting in the new layout.
+pub async fn list_keyword_shards<S: Storage>(
+    store: &S,
+    index: &str,
+    keyword: &str,
+) -> Result<Vec<String>, DataStoreError> {
+    let prefix = keyword_shard_prefix(index, keyword);
+    let listed = list_all(store, &prefix).await?;
+    if listed.contains(&reshard_marker_key(index, keyword)) {
+        let staged_prefix = staged_shard_prefix(index, keyword);
+        let staged = list_all(store, &staged_prefix).await?;
+        return Ok(staged
+            .into_iter()
+            .filter(|key| is_keyword_shard_key(&staged_prefix, key))
+            .collect());
+    }
+
+    let mut keys: Vec<String> = listed
+        .into_iter()
+        .filter(|key| is_keyword_shard_key(&prefix, key))
+        .collect();
+    if let Some(legacy_prefix) = legacy_keyword_shard_prefix(index, keyword) {
+        let listed = list_all(store, &legacy_prefix).await?;
+        keys.extend(
+            listed
+                .into_iter()
+                .filter(|key| is_keyword_shard_key(&legacy_prefix, key)),
+        );
+    }
+    Ok(keys)
+}
+
+/// Scores closer than this are considered identical when diffing postings
+pub const SCORE_EPSILON: f64 = 1e-9;
+
+pub fn scores_equal(a: f64, b: f64) -> bool {
+    (a - b).abs() < SCORE_EPSILON
 }
 
 pub fn keyword_shard_kv_key(index: &str, keyword: &str, shard: u32) -> KeywordRef {
-    return format!("{}:{}{}:{}", index, PREFIX_KEYWORD, keyword, shard) as KeywordRef;
+    format!("{}{}", keyword_shard_prefix(index, keyword), shard) as KeywordRef
+}
+
+/// Postings kept in a shard's top-K summary
+pub const TOP_K: usize = 32;
+
+/// The KV prefix under which the top-K summary of every shard of a keyword is stored.
+/// Summaries live beside the shards rather than under them, so listing a keyword's
+/// shards by prefix never returns summary keys.
+pub fn keyword_top_prefix(index: &str, keyword: &str) -> String {
+    format!(
+        "{}:{}{}:",
+        index,
+        PREFIX_KEYWORD_TOP,
+        escape_keyword(keyword)
+    )
+}
+
+pub fn keyword_top_kv_key(index: &str, keyword: &str, shard: u32) -> KeywordRef {
+    format!("{}{}", keyword_top_prefix(index, keyword), shard) as KeywordRef
+}
+
+fn by_score_descending(a: &(DocumentRef, f64), b: &(DocumentRef, f64)) -> std::cmp::Ordering {
+    b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal)
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -35,124 +204,701 @@ pub struct KeywordShardData {
     // Last modified timestamp (versioning)
     pub ts: u64,
 
-    // List of document references containing this keyword (sets loaded)
+    // List of document references containing this keyword, sorted by score descending.
+    // Shards written before postings were kept sorted are sorted on their next change.
     pub docs: Vec<(DocumentRef, f64)>,
+
+    // The first TOP_K postings of `docs`, also stored as the shard's KeywordShardTop
+    #[serde(default)]
+    pub top: Vec<(DocumentRef, f64)>,
+
+    // Whether the stored KeywordShardTop no longer matches this shard
+    #[serde(skip)]
+    top_stale: bool,
 }
 
 impl KvEntry for KeywordShardData {
     type Key = KeywordRef;
 
     fn get_kv_key(&self) -> Self::Key {
-        return keyword_shard_kv_key(&self.index.as_str(), &self.keyword.as_str(), self.shard);
+        keyword_shard_kv_key(self.index.as_str(), self.keyword.as_str(), self.shard)
     }
 }
 
+/// What [`ShardCodec::Compact`] stores of a shard
+#[derive(Serialize)]
+struct CompactShardRef<'a> {
+    ts: u64,
+    docs: &'a [(DocumentRef, f64)],
+}
+
+/// A shard read back with [`ShardCodec::Compact`], which ignores the extra fields
+/// of a shard stored [`ShardCodec::Full`]
+#[derive(Deserialize)]
+struct CompactShard {
+    ts: u64,
+    docs: Vec<(DocumentRef, f64)>,
+}
+
 impl KvPersistent for KeywordShardData {
-    async fn read(key: &str, store: &KvStore) -> Result<Self, DataStoreError> {
-        let result = store
-            .get(key)
-            .json::<KeywordShardData>()
-            .await
-            .map_err(DataStoreError::Kv)?;
+    fn encode(&self, codecs: CodecSet) -> Result<String, DataStoreError> {
+        let encoded = match codecs.shards {
+            ShardCodec::Full => serde_json::to_string(self),
+            ShardCodec::Compact => serde_json::to_string(&CompactShardRef {
+                ts: self.ts,
+                docs: &self.docs,
+            }),
+        };
+        encoded.map_err(DataStoreError::Serialization)
+    }
 
-        result.ok_or_else(|| DataStoreError::NotFound(key.to_string()))
+    /// Shards stored either way are read with either codec. Isolates still caching
+    /// an index's version from before its upgrade then read its rewritten shards.
+    fn decode(key: &str, raw: &str, codecs: CodecSet) -> Result<Self, DataStoreError> {
+        match codecs.shards {
+            ShardCodec::Full => serde_json::from_str(raw)
+                .or_else(|err| decode_compact(key, raw).map_err(|_| err))
+                .map_err(DataStoreError::Serialization),
+            ShardCodec::Compact => decode_compact(key, raw),
+        }
+    }
+}
+
+fn decode_compact(key: &str, raw: &str) -> Result<KeywordShardData, DataStoreError> {
+    let stored: CompactShard = serde_json::from_str(raw).map_err(DataStoreError::Serialization)?;
+    let (index, keyword, shard) = parse_any_shard_key(key).ok_or_else(|| {
+        DataStoreError::InvalidFormat(format!("'{}' is not a keyword shard key", key))
+    })?;
+    Ok(KeywordShardData::restored(
+        index,
+        keyword,
+        shard,
+        stored.ts,
+        stored.docs,
+    ))
+}
+
+/// The best postings of one keyword shard and its posting count, stored in a small
+/// KV entry of its own so low-limit reads don't have to load whole shards
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct KeywordShardTop {
+    pub index: IndexName,
+    pub keyword: String,
+    pub shard: u32,
+    // The number of postings in the shard
+    pub count: usize,
+    // The shard's first TOP_K postings, sorted by score descending
+    pub top: Vec<(DocumentRef, f64)>,
+}
+
+impl KvEntry for KeywordShardTop {
+    type Key = KeywordRef;
+
+    fn get_kv_key(&self) -> Self::Key {
+        keyword_top_kv_key(self.index.as_str(), self.keyword.as_str(), self.shard)
     }
 }
 
+impl KvPersistent for KeywordShardTop {}
+
 impl KeywordShardData {
     pub fn new(
         index: IndexName,
         keyword: String,
         shard: u32,
         ts: u64,
-        docs: Vec<(DocumentRef, f64)>,
+        mut docs: Vec<(DocumentRef, f64)>,
     ) -> KeywordShardData {
-        return KeywordShardData {
+        docs.sort_by(by_score_descending);
+        let mut shard = KeywordShardData {
             index,
             keyword,
             shard,
             ts,
             docs,
+            top: vec![],
+            top_stale: true,
         };
+        shard.refresh_top();
+        shard
+    }
+
+    /// A shard as stored, whose summary was written with it. Its top postings are
+    /// the first of `docs`, like those of the shard it was stored from.
+    fn restored(
+        index: IndexName,
+        keyword: String,
+        shard: u32,
+        ts: u64,
+        docs: Vec<(DocumentRef, f64)>,
+    ) -> KeywordShardData {
+        let top = docs[..docs.len().min(TOP_K)].to_vec();
+        KeywordShardData {
+            index,
+            keyword,
+            shard,
+            ts,
+            docs,
+            top,
+            top_stale: false,
+        }
     }
 
-    pub async fn from_keyword(
-        store: &KvStore,
-        env: &Env,
+    /// Load a keyword shard, returning `None` when the shard has never been written
+    pub async fn load<S: Storage>(
+        store: &S,
+        codecs: CodecSet,
         index: &str,
-        doc_id: &str,
         keyword: &str,
-    ) -> Result<KeywordShardData, DataStoreError> {
-        let shard = shard_from_document_id(doc_id.to_string(), get_n_shards(env));
+        shard: u32,
+    ) -> Result<Option<KeywordShardData>, DataStoreError> {
         let shard_key = keyword_shard_kv_key(index, keyword, shard);
         edge_log!(
             console_debug,
             "KeywordShardData",
             index,
-            "KeywordShardData::from_keyword({}, {}) kv={}",
-            doc_id,
+            "KeywordShardData::load({}, {}) kv={}",
             keyword,
+            shard,
             shard_key
         );
 
-        let found_shard = Self::read(&keyword_shard_kv_key(&index, &keyword, shard), &store).await;
-        if let Ok(shard_data) = found_shard {
-            edge_log!(
-                console_debug,
-                "KeywordShardData",
-                index,
-                "loaded existing shard data for keyword '{}' shard {}",
-                keyword,
-                shard
-            );
-            Ok(shard_data)
-        } else {
-            edge_log!(
-                console_debug,
-                "KeywordShardData",
-                index,
-                "creating new shard data for keyword '{}' shard {}",
-                keyword,
-                shard
-            );
-            let mut shard = KeywordShardData::new(
-                index.to_string(),
-                keyword.to_string(),
-                shard,
-                worker::Date::now().as_millis().into(),
-                vec![],
-            );
-            shard.write(&store).await?;
-            Ok(shard)
+        match Self::read_with(&shard_key, store, codecs).await {
+            Ok(shard_data) => Ok(Some(shard_data)),
+            Err(DataStoreError::NotFound(_)) => Ok(None),
+            Err(err) => Err(err),
         }
     }
 
-    pub async fn add_document(
-        &mut self,
-        store: &KvStore,
-        doc_id: &str,
-        score: f64,
-    ) -> Result<(), DataStoreError> {
-        // Check if the document already exists in the list
-        if !self.docs.iter().any(|(d, _)| d == doc_id) {
-            self.docs.push((doc_id.to_string(), score));
-            self.ts = worker::Date::now().as_millis().into();
-            self.write(store).await?;
+    /// Insert or update a document posting in memory, returning whether the shard
+    /// changed. A posting whose score is unchanged (within [`SCORE_EPSILON`]) is
+    /// left alone so it doesn't cost a write.
+    pub fn apply_upsert(&mut self, doc_id: &str, score: f64, now: u64) -> bool {
+        let existing = self.docs.iter().position(|(d, _)| d == doc_id);
+        if let Some(position) = existing {
+            if scores_equal(self.docs[position].1, score) {
+                return false;
+            }
+            self.docs.remove(position);
         }
-        Ok(())
+        if !self.docs.is_sorted_by(|a, b| a.1 >= b.1) {
+            self.docs.sort_by(by_score_descending);
+        }
+        let position = self.docs.partition_point(|(_, s)| *s >= score);
+        self.docs.insert(position, (doc_id.to_string(), score));
+        if existing.is_none() {
+            // The posting count is part of the summary
+            self.top_stale = true;
+        }
+        self.refresh_top();
+        self.ts = now;
+        true
     }
 
-    pub async fn remove_document(
-        &mut self,
-        store: &KvStore,
-        doc_id: &str,
-    ) -> Result<(), DataStoreError> {
+    /// Remove a document posting in memory, returning whether the shard changed
+    pub fn apply_remove(&mut self, doc_id: &str, now: u64) -> bool {
         let original_len = self.docs.len();
         self.docs.retain(|(d, _)| d != doc_id);
-        if self.docs.len() != original_len {
-            self.ts = worker::Date::now().as_millis().into();
-            self.write(store).await?;
+        if self.docs.len() == original_len {
+            return false;
+        }
+        self.top_stale = true;
+        self.refresh_top();
+        self.ts = now;
+        true
+    }
+
+    /// This shard's top-K summary
+    pub fn summary(&self) -> KeywordShardTop {
+        KeywordShardTop {
+            index: self.index.clone(),
+            keyword: self.keyword.clone(),
+            shard: self.shard,
+            count: self.docs.len(),
+            top: self.top.clone(),
+        }
+    }
+
+    /// Write the shard, and its summary when that changed. The summary goes first:
+    /// if either write fails the shard still holds its old postings, so
+    /// retrying the change writes both again.
+    pub async fn save<S: Storage>(
+        &mut self,
+        store: &S,
+        codecs: CodecSet,
+    ) -> Result<(), DataStoreError> {
+        if self.top_stale {
+            self.summary().write(store).await?;
         }
+        self.write_with(store, codecs).await?;
+        self.top_stale = false;
         Ok(())
     }
+
+    /// Delete the shard and its summary
+    pub async fn delete<S: Storage>(&self, store: &S) -> Result<(), DataStoreError> {
+        store.delete(&self.get_kv_key()).await?;
+        store.delete(&self.summary().get_kv_key()).await
+    }
+
+    fn refresh_top(&mut self) {
+        let top = &self.docs[..self.docs.len().min(TOP_K)];
+        if self.top.as_slice() != top {
+            self.top = top.to_vec();
+            self.top_stale = true;
+        }
+    }
+}
+
+/// A pending change to one document's posting within a keyword shard
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PostingChange {
+    Upsert(f64),
+    Remove,
+}
+
+/// Collects every posting change a single document update makes, so each touched
+/// shard key is read once and written at most once, regardless of how many times
+/// a keyword was added or removed while computing the update.
+pub struct ShardWriteBatch {
+    index: IndexName,
+    doc_id: DocumentRef,
+    shard: u32,
+    changes: BTreeMap<String, PostingChange>,
+    codecs: CodecSet,
+}
+
+impl ShardWriteBatch {
+    pub fn new(index: &str, doc_id: &str, n_shards: u32) -> ShardWriteBatch {
+        ShardWriteBatch {
+            index: index.to_string(),
+            doc_id: doc_id.to_string(),
+            shard: shard_from_document_id(doc_id.to_string(), n_shards),
+            changes: BTreeMap::new(),
+            codecs: CodecSet::V1,
+        }
+    }
+
+    /// Read and write the shards with the codecs of an index other than a v1 one
+    pub fn with_codecs(mut self, codecs: CodecSet) -> Self {
+        self.codecs = codecs;
+        self
+    }
+
+    /// Queue adding or rescoring the document in a keyword's shard, superseding
+    /// earlier changes
+    pub fn upsert(&mut self, keyword: &str, score: f64) {
+        self.changes
+            .insert(keyword.to_string(), PostingChange::Upsert(score));
+    }
+
+    /// Queue removing the document from a keyword's shard, superseding earlier changes
+    pub fn remove(&mut self, keyword: &str) {
+        self.changes
+            .insert(keyword.to_string(), PostingChange::Remove);
+    }
+
+    pub fn len(&self) -> usize {
+        self.changes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    pub fn shard(&self) -> u32 {
+        self.shard
+    }
+
+    /// Apply the pending change for `keyword` to the stored shard (`None` when
+    /// the shard doesn't exist yet), returning the shard to write if it changed.
+    pub fn apply(
+        &self,
+        keyword: &str,
+        existing: Option<KeywordShardData>,
+        now: u64,
+    ) -> Option<KeywordShardData> {
+        let change = self.changes.get(keyword)?;
+        match (change, existing) {
+            (PostingChange::Upsert(score), Some(mut shard)) => shard
+                .apply_upsert(&self.doc_id, *score, now)
+                .then_some(shard),
+            (PostingChange::Upsert(score), None) => Some(KeywordShardData::new(
+                self.index.clone(),
+                keyword.to_string(),
+                self.shard,
+                now,
+                vec![(self.doc_id.clone(), *score)],
+            )),
+            (PostingChange::Remove, Some(mut shard)) => {
+                shard.apply_remove(&self.doc_id, now).then_some(shard)
+            }
+            // Nothing to remove from a shard that was never written
+            (PostingChange::Remove, None) => None,
+        }
+    }
+
+    /// Read every touched shard once, apply the queued changes in memory, and
+    /// write each changed shard exactly once. Returns the per-keyword outcome.
+    pub async fn execute<S: Storage>(
+        &self,
+        store: &S,
+        now: u64,
+    ) -> Vec<(String, Result<(), DataStoreError>)> {
+        self.execute_keywords(store, now, self.changes.keys()).await
+    }
+
+    /// [`Self::execute`], then retry each keyword that failed once more, reading its
+    /// shard again so a transient KV error doesn't leave the posting unwritten
+    pub async fn execute_with_retry<S: Storage>(
+        &self,
+        store: &S,
+        now: u64,
+    ) -> Vec<(String, Result<(), DataStoreError>)> {
+        let mut results = self.execute(store, now).await;
+        let failed: Vec<String> = results
+            .iter()
+            .filter(|(_, result)| result.is_err())
+            .map(|(keyword, _)| keyword.clone())
+            .collect();
+        if failed.is_empty() {
+            return results;
+        }
+
+        let retried = self.execute_keywords(store, now, failed.iter()).await;
+        for (keyword, result) in retried {
+            if let Some(entry) = results.iter_mut().find(|(kw, _)| *kw == keyword) {
+                entry.1 = result;
+            }
+        }
+        results
+    }
+
+    async fn execute_keywords<'k, S: Storage>(
+        &self,
+        store: &S,
+        now: u64,
+        keywords: impl Iterator<Item = &'k String>,
+    ) -> Vec<(String, Result<(), DataStoreError>)> {
+        let futures: Vec<_> = keywords
+            .map(async |keyword| {
+                let loaded =
+                    KeywordShardData::load(store, self.codecs, &self.index, keyword, self.shard);
+                let result = match loaded.await {
+                    Ok(existing) => match self.apply(keyword, existing, now) {
+                        Some(mut shard) => shard.save(store, self.codecs).await,
+                        None => Ok(()),
+                    },
+                    Err(err) => Err(err),
+                };
+                (keyword.clone(), result)
+            })
+            .collect();
+
+        join_bounded(futures).await
+    }
+}
+
+/// Test helpers shared by the modules that build shard write batches
+#[cfg(test)]
+pub(crate) mod testing {
+    use futures::executor::block_on;
+
+    use super::*;
+    use crate::data::storage::memory::MemoryStorage;
+
+    /// Write postings for `keyword` into `store`, one shard write batch per document
+    pub fn seed_postings<S: Storage>(
+        store: &S,
+        index: &str,
+        n_shards: u32,
+        keyword: &str,
+        postings: &[(&str, f64)],
+    ) {
+        for (doc_id, score) in postings {
+            let mut batch = ShardWriteBatch::new(index, doc_id, n_shards);
+            batch.upsert(keyword, *score);
+            for (_, result) in block_on(batch.execute(store, 1)) {
+                result.unwrap();
+            }
+        }
+    }
+
+    /// Store a shard under the unescaped key keywords were stored under before they
+    /// were escaped, returning the key
+    pub fn write_legacy_shard<S: Storage>(
+        store: &S,
+        index: &str,
+        keyword: &str,
+        shard: u32,
+        docs: &[(&str, f64)],
+    ) -> String {
+        let docs = docs.iter().map(|(id, s)| (id.to_string(), *s)).collect();
+        let data = KeywordShardData::new(index.into(), keyword.into(), shard, 1, docs);
+        let key = format!("{}:{}{}:{}", index, PREFIX_KEYWORD, keyword, shard);
+        block_on(store.put(&key, serde_json::to_string(&data).unwrap())).unwrap();
+        key
+    }
+
+    /// Execute `batch` against `store`, returning the (gets, puts) it cost
+    pub fn execute_counted(
+        store: &MemoryStorage,
+        batch: &ShardWriteBatch,
+        now: u64,
+    ) -> (usize, usize) {
+        let before = store.counts();
+        for (_, result) in block_on(batch.execute(store, now)) {
+            result.unwrap();
+        }
+        let after = store.counts();
+        (after.gets - before.gets, after.puts - before.puts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+
+    use super::{
+        testing::{execute_counted, seed_postings},
+        *,
+    };
+    use crate::data::storage::memory::MemoryStorage;
+
+    #[test]
+    fn test_one_read_and_write_per_shard_key() {
+        let store = MemoryStorage::default();
+        let mut batch = ShardWriteBatch::new("idx", "doc1", 48);
+        for i in 0..50 {
+            batch.upsert(&format!("kw{}", i % 40), 0.5);
+        }
+
+        assert_eq!(batch.len(), 40);
+        // Each shard key is read once, then written with its top postings' summary
+        assert_eq!(execute_counted(&store, &batch, 1), (40, 80));
+        // New shards are written once with the posting, never empty first
+        for i in 0..40 {
+            let keyword = format!("kw{}", i);
+            let loaded = block_on(KeywordShardData::load(
+                &store,
+                CodecSet::V1,
+                "idx",
+                &keyword,
+                batch.shard,
+            ));
+            assert_eq!(loaded.unwrap().unwrap().docs.len(), 1);
+        }
+    }
+
+    #[test]
+    fn test_add_then_remove_coalesces() {
+        let store = MemoryStorage::default();
+        let mut batch = ShardWriteBatch::new("idx", "doc1", 48);
+        batch.upsert("kept", 0.9);
+        batch.upsert("dropped", 0.9);
+        batch.remove("dropped");
+
+        // `dropped` has no stored shard, so removing it costs a read but no write
+        assert_eq!(execute_counted(&store, &batch, 1), (2, 2));
+        assert!(!store.keys().iter().any(|key| key.contains("dropped")));
+    }
+
+    #[test]
+    fn test_upsert_updates_changed_scores() {
+        let mut shard = KeywordShardData::new("idx".into(), "kw".into(), 0, 0, vec![]);
+        assert!(shard.apply_upsert("doc1", 0.5, 1));
+        assert!(!shard.apply_upsert("doc1", 0.5 + SCORE_EPSILON / 2.0, 2));
+        assert_eq!(shard.ts, 1);
+        assert!(shard.apply_upsert("doc1", 0.75, 3));
+        assert_eq!(shard.docs, vec![("doc1".to_string(), 0.75)]);
+        assert_eq!(shard.ts, 3);
+    }
+
+    #[test]
+    fn test_postings_stay_sorted() {
+        let mut shard = KeywordShardData::new("idx".into(), "kw".into(), 0, 0, vec![]);
+        for (i, score) in [0.3, 0.9, 0.1, 0.5, 0.9].iter().enumerate() {
+            shard.apply_upsert(&format!("doc{}", i), *score, 1);
+        }
+        shard.apply_upsert("doc2", 0.7, 2);
+        let order: Vec<&str> = shard.docs.iter().map(|(d, _)| d.as_str()).collect();
+        assert_eq!(order, vec!["doc1", "doc4", "doc2", "doc3", "doc0"]);
+
+        // A shard stored before postings were sorted is sorted on its next change
+        let mut legacy: KeywordShardData = serde_json::from_str(
+            r#"{"index":"idx","keyword":"kw","shard":0,"ts":0,"docs":[["a",0.1],["b",0.8]]}"#,
+        )
+        .unwrap();
+        assert!(legacy.top.is_empty());
+        legacy.apply_upsert("c", 0.5, 1);
+        let order: Vec<&str> = legacy.docs.iter().map(|(d, _)| d.as_str()).collect();
+        assert_eq!(order, vec!["b", "c", "a"]);
+        assert_eq!(legacy.top, legacy.docs);
+    }
+
+    #[test]
+    fn test_top_summary_maintenance() {
+        let docs = (0..TOP_K + 8)
+            .map(|i| (format!("doc{}", i), i as f64))
+            .collect();
+        let mut shard = KeywordShardData::new("idx".into(), "kw".into(), 0, 0, docs);
+        assert_eq!(shard.top.len(), TOP_K);
+        assert_eq!(shard.top[0].0, format!("doc{}", TOP_K + 7));
+
+        // Rescoring a posting outside the top-K leaves the summary alone, except
+        // for the shard's first write
+        let store = MemoryStorage::default();
+        block_on(shard.save(&store, CodecSet::V1)).unwrap();
+        assert_eq!(store.keys().len(), 2);
+        shard.apply_upsert("doc0", 0.5, 1);
+        let before = store.counts();
+        block_on(shard.save(&store, CodecSet::V1)).unwrap();
+        assert_eq!(store.counts().puts - before.puts, 1);
+
+        // Removing a posting within the top-K promotes the best one below it
+        let promoted = shard.docs[TOP_K].clone();
+        assert!(shard.apply_remove("doc39", 2));
+        assert_eq!(shard.top.len(), TOP_K);
+        assert_eq!(shard.top.last(), Some(&promoted));
+        assert!(!shard.top.iter().any(|(d, _)| d == "doc39"));
+        block_on(shard.save(&store, CodecSet::V1)).unwrap();
+
+        let summary_key = keyword_top_kv_key("idx", "kw", 0);
+        let summary = block_on(KeywordShardTop::read(&summary_key, &store)).unwrap();
+        assert_eq!(summary.count, TOP_K + 7);
+        assert_eq!(summary.top, shard.top);
+
+        block_on(shard.delete(&store)).unwrap();
+        assert!(store.keys().is_empty());
+    }
+
+    #[test]
+    fn test_keyword_escaping_round_trips() {
+        for keyword in [
+            "ocean",
+            "http://example",
+            "100% cotton",
+            "a%3Ab",
+            "über straße",
+            "東京:駅",
+        ] {
+            let escaped = escape_keyword(keyword);
+            assert!(!escaped.contains(':'), "{:?}", escaped);
+            assert_eq!(unescape_keyword(&escaped), keyword);
+
+            let key = keyword_shard_kv_key("idx", keyword, 7);
+            assert_eq!(
+                parse_keyword_shard_key("idx", &key),
+                Some((keyword.to_string(), 7))
+            );
+            assert!(is_keyword_shard_key(
+                &keyword_shard_prefix("idx", keyword),
+                &key
+            ));
+        }
+        assert_eq!(
+            keyword_shard_kv_key("idx", "http://x", 3),
+            "idx:kw:http%3A//x:3"
+        );
+        assert_eq!(keyword_top_kv_key("idx", "a:b", 3), "idx:kwtop:a%3Ab:3");
+        assert_eq!(unescape_keyword("50%off%"), "50%off%");
+        assert_eq!(legacy_keyword_shard_prefix("idx", "ocean"), None);
+        assert_eq!(
+            legacy_keyword_shard_prefix("idx", "a:b").as_deref(),
+            Some("idx:kw:a:b:")
+        );
+    }
+
+    #[test]
+    fn test_compact_shards_round_trip() {
+        let docs: Vec<(DocumentRef, f64)> = (0..TOP_K + 5)
+            .map(|i| (format!("doc{}", i), 1.0 - i as f64 / 64.0))
+            .collect();
+        let shard = KeywordShardData::new("idx".into(), "a:b".into(), 5, 9, docs);
+        let key = shard.get_kv_key();
+        let compact = shard.encode(CodecSet::V2).unwrap();
+        let full = shard.encode(CodecSet::V1).unwrap();
+        assert!(compact.starts_with(r#"{"ts":9,"docs":"#) && compact.len() < full.len());
+
+        // Either codec reads what either wrote, recovering the rest from the key
+        for (raw, codecs) in [
+            (&compact, CodecSet::V2),
+            (&compact, CodecSet::V1),
+            (&full, CodecSet::V2),
+        ] {
+            let read = KeywordShardData::decode(&key, raw, codecs).unwrap();
+            assert_eq!((read.index.as_str(), read.keyword.as_str()), ("idx", "a:b"));
+            assert_eq!((read.shard, read.ts), (5, 9));
+            assert_eq!(
+                (read.docs.clone(), read.top),
+                (shard.docs.clone(), shard.top.clone())
+            );
+        }
+        let staged = staged_shard_kv_key("idx", "a:b", 5);
+        let read = KeywordShardData::decode(&staged, &compact, CodecSet::V2).unwrap();
+        assert_eq!((read.keyword.as_str(), read.shard), ("a:b", 5));
+        assert!(KeywordShardData::decode("idx:doc:1", &compact, CodecSet::V2).is_err());
+    }
+
+    #[test]
+    fn test_listing_skips_longer_legacy_keywords() {
+        let store = MemoryStorage::default();
+        seed_postings(&store, "idx", 8, "http", &[("doc1", 0.5)]);
+        seed_postings(&store, "idx", 8, "http://example", &[("doc2", 0.7)]);
+        let legacy =
+            testing::write_legacy_shard(&store, "idx", "http://example", 3, &[("doc3", 0.2)]);
+
+        let http = block_on(list_keyword_shards(&store, "idx", "http")).unwrap();
+        assert_eq!(
+            http,
+            vec![keyword_shard_kv_key(
+                "idx",
+                "http",
+                shard_from_document_id("doc1".into(), 8)
+            )]
+        );
+
+        let mut url = block_on(list_keyword_shards(&store, "idx", "http://example")).unwrap();
+        url.sort();
+        let mut expected = vec![
+            keyword_shard_kv_key(
+                "idx",
+                "http://example",
+                shard_from_document_id("doc2".into(), 8),
+            ),
+            legacy,
+        ];
+        expected.sort();
+        assert_eq!(url, expected);
+    }
+
+    #[test]
+    fn test_unchanged_shards_are_not_rewritten() {
+        let store = MemoryStorage::default();
+        let mut batch = ShardWriteBatch::new("idx", "doc1", 48);
+        batch.upsert("a", 0.9);
+        batch.upsert("b", 0.8);
+        assert_eq!(execute_counted(&store, &batch, 1), (2, 4));
+        assert_eq!(execute_counted(&store, &batch, 2), (2, 0));
+    }
+
+    #[test]
+    fn test_batch_keeps_kv_concurrency() {
+        use crate::util::concurrency::{set_limit, DEFAULT_KV_CONCURRENCY};
+
+        let store = MemoryStorage::default();
+        let mut batch = ShardWriteBatch::new("idx", "doc1", 4);
+        for i in 0..30 {
+            batch.upsert(&format!("kw{}", i), 0.5);
+        }
+        set_limit(4);
+        store.track_in_flight();
+        let results = block_on(batch.execute(&store, 1));
+        set_limit(DEFAULT_KV_CONCURRENCY);
+
+        assert_eq!(results.len(), 30);
+        assert!(results.iter().all(|(_, result)| result.is_ok()));
+        // Each shard key is read then written, with no more than 4 keys at a time
+        assert_eq!(store.max_in_flight(), 4);
+    }
 }
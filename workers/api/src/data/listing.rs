@@ -0,0 +1,135 @@
+//! Listing an index's documents a page at a time, as headers without their bodies
+//! or keywords. Each listed document is read to fill in its header, so pages are
+//! kept small, and `updated_since` is applied to the headers of each page rather
+//! than narrowing what is read.
+
+use lingua::IsoCode639_1;
+use serde::Serialize;
+
+use crate::data::{
+    document::Document,
+    storage::{load_found, PageCursor, Storage},
+    DataStoreError, PREFIX_DOCUMENT,
+};
+
+/// How many document keys one listing call reads
+pub const DOCUMENT_PAGE_SIZE: usize = 50;
+
+/// A listed document, without its body or keywords
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct DocumentHeader {
+    pub id: String,
+    pub rev: u32,
+    pub lang: Option<IsoCode639_1>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub updated_at: Option<u64>,
+}
+
+impl From<&Document> for DocumentHeader {
+    fn from(document: &Document) -> Self {
+        DocumentHeader {
+            id: document.get_uuid(),
+            rev: document.revision,
+            lang: document.lang,
+            created_at: document.created_at,
+            updated_at: document.updated_at,
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct DocumentPage {
+    pub documents: Vec<DocumentHeader>,
+    /// Pass back to list the next page, `null` once every document was listed. A
+    /// page may hold no documents and still have a cursor when `updated_since`
+    /// filtered them all out.
+    pub cursor: Option<String>,
+}
+
+/// List the next page of the index's documents, keeping those updated at or after
+/// `updated_since`. Documents without a timestamp were written before they were
+/// kept, so they never pass it.
+pub async fn list_documents<S: Storage>(
+    store: &S,
+    index: &str,
+    cursor: Option<PageCursor>,
+    updated_since: Option<u64>,
+) -> Result<DocumentPage, DataStoreError> {
+    let PageCursor { offset, page } = cursor.unwrap_or_default();
+    let prefix = format!("{}:{}", index, PREFIX_DOCUMENT);
+    let listed = store.list(&prefix, page.clone()).await?;
+    let doc_ids: Vec<&str> = listed
+        .keys
+        .iter()
+        .skip(offset)
+        .take(DOCUMENT_PAGE_SIZE)
+        .filter_map(|key| key.strip_prefix(&prefix))
+        .collect();
+    let checked = offset + doc_ids.len();
+
+    let mut documents: Vec<DocumentHeader> = load_found(doc_ids, |doc_id| {
+        Document::from_remote(store, index, doc_id)
+    })
+    .await?
+    .iter()
+    .map(DocumentHeader::from)
+    .collect();
+    if let Some(since) = updated_since {
+        documents.retain(|header| header.updated_at.is_some_and(|at| at >= since));
+    }
+
+    let cursor = match checked < listed.keys.len() {
+        true => Some(PageCursor {
+            offset: checked,
+            page,
+        }),
+        false => listed.cursor.map(|page| PageCursor {
+            offset: 0,
+            page: Some(page),
+        }),
+    };
+    Ok(DocumentPage {
+        documents,
+        cursor: cursor.map(|cursor| cursor.to_string()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+
+    use super::*;
+    use crate::data::{storage::memory::MemoryStorage, KvPersistent};
+
+    #[test]
+    fn test_list_documents_updated_since() {
+        let store = MemoryStorage::default();
+        for (id, updated_at) in [
+            ("a", Some(100)),
+            ("b", Some(300)),
+            ("c", None),
+            ("d", Some(200)),
+        ] {
+            let mut document = Document::new_with_id("idx", id);
+            document.updated_at = updated_at;
+            block_on(document.write(&store)).unwrap();
+        }
+
+        // Follow cursors across the store's small listing pages
+        let (mut all, mut since, mut cursor) = (vec![], vec![], None);
+        loop {
+            let page = block_on(list_documents(&store, "idx", cursor.clone(), None)).unwrap();
+            all.extend(page.documents.into_iter().map(|header| header.id));
+            let filtered = block_on(list_documents(&store, "idx", cursor, Some(200))).unwrap();
+            since.extend(filtered.documents.into_iter().map(|header| header.id));
+            match page.cursor {
+                Some(next) => cursor = Some(next.parse().unwrap()),
+                None => break,
+            }
+        }
+        assert_eq!(all, vec!["a", "b", "c", "d"]);
+        assert_eq!(since, vec!["b", "d"]);
+    }
+}
@@ -0,0 +1,137 @@
+//! Merged postings of hot keywords, kept under `{index}:kwmerge:{keyword}` so a
+//! search can skip listing their shards. Listing is what makes the first search for
+//! a popular keyword slow after a deploy, once the isolate's [`reader_cache`] is
+//! gone: `POST /:index/warm` merges keywords ahead of time, and searches write the
+//! entries of keywords they merged the slow way.
+//!
+//! An entry names the shard keys it was merged from and the newest of their
+//! timestamps. Reading it back reads those shards by name and compares the newest
+//! timestamp with the stored one, so a write to any of them shows. Only keywords
+//! stored in every one of the index's shards are kept: a new shard of such a keyword
+//! can't appear without the shard count changing, which the entry also records.
+//! Indexes with a recorded shard count name their shard keys anyway and don't keep
+//! entries.
+//!
+//! [`reader_cache`]: crate::durable::reader_cache
+
+use std::collections::HashSet;
+
+use serde::{Deserialize, Serialize};
+
+use crate::data::{
+    keyword::{MergedKeywordData, StampedPostings},
+    keyword_shard::{escape_keyword, keyword_shard_kv_key},
+    storage::Storage,
+    DataStoreError, KvEntry, KvPersistent,
+};
+
+pub static SUFFIX_KEYWORD_MERGED: &str = "kwmerge:";
+
+/// How long an entry is kept after it's written, in seconds
+pub const MERGE_CACHE_TTL_SECS: u64 = 3600;
+
+/// The most postings an entry holds, keeping it well under KV's value size limit
+pub const MAX_MERGE_CACHE_POSTINGS: usize = 50_000;
+
+pub fn merged_keyword_kv_key(index: &str, keyword: &str) -> String {
+    format!(
+        "{}:{}{}",
+        index,
+        SUFFIX_KEYWORD_MERGED,
+        escape_keyword(keyword)
+    )
+}
+
+/// What `POST /:index/warm` merged
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct WarmReport {
+    /// Keywords whose entries were written
+    pub warmed: Vec<String>,
+    /// Keywords not stored in every shard, or with too many postings to keep
+    pub skipped: Vec<String>,
+    pub shards_read: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MergedKeyword {
+    #[serde(skip)]
+    pub index: String,
+    pub keyword: String,
+    /// The shard count the entry was written under
+    pub n_shards: u32,
+    /// Every shard key the postings were merged from
+    pub shard_keys: Vec<String>,
+    pub newest_ts: u64,
+    /// Sorted by score descending
+    pub postings: MergedKeywordData,
+}
+
+impl KvEntry for MergedKeyword {
+    type Key = String;
+
+    fn get_kv_key(&self) -> String {
+        merged_keyword_kv_key(&self.index, &self.keyword)
+    }
+}
+
+impl KvPersistent for MergedKeyword {}
+
+impl MergedKeyword {
+    /// The entry to keep for `keyword`, merged from the listed `shard_keys`, if it's
+    /// stored in every one of the `n_shards` shards and isn't too large
+    pub fn from_merge(
+        index: &str,
+        keyword: &str,
+        n_shards: u32,
+        shard_keys: &[String],
+        stamped: &StampedPostings,
+    ) -> Option<MergedKeyword> {
+        let newest_ts = stamped.newest_ts?;
+        let listed: HashSet<&str> = shard_keys.iter().map(String::as_str).collect();
+        let in_every_shard = (0..n_shards)
+            .all(|shard| listed.contains(keyword_shard_kv_key(index, keyword, shard).as_str()));
+        if !in_every_shard || stamped.postings.len() > MAX_MERGE_CACHE_POSTINGS {
+            return None;
+        }
+        Some(MergedKeyword {
+            index: index.to_string(),
+            keyword: keyword.to_string(),
+            n_shards,
+            shard_keys: shard_keys.to_vec(),
+            newest_ts,
+            postings: stamped.postings.clone(),
+        })
+    }
+
+    /// Read the entry of `keyword`, `None` when there isn't one
+    pub async fn load<S: Storage>(
+        store: &S,
+        index: &str,
+        keyword: &str,
+    ) -> Result<Option<MergedKeyword>, DataStoreError> {
+        match MergedKeyword::read(&merged_keyword_kv_key(index, keyword), store).await {
+            Ok(mut entry) => {
+                entry.index = index.to_string();
+                Ok(Some(entry))
+            }
+            Err(DataStoreError::NotFound(_)) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Write the entry, to expire after [`MERGE_CACHE_TTL_SECS`]
+    pub async fn store<S: Storage>(&self, store: &S) -> Result<(), DataStoreError> {
+        let encoded = serde_json::to_string(self).map_err(DataStoreError::Serialization)?;
+        store
+            .put_expiring(&self.get_kv_key(), encoded, MERGE_CACHE_TTL_SECS)
+            .await
+    }
+
+    /// Whether the shards the entry was merged from, as read back now, are the ones it
+    /// holds the postings of: every one still there, and none written since
+    pub fn is_current(&self, n_shards: u32, shard_ts: &[u64]) -> bool {
+        self.n_shards == n_shards
+            && shard_ts.len() == self.shard_keys.len()
+            && shard_ts.iter().max() == Some(&self.newest_ts)
+    }
+}
@@ -1,6 +1,7 @@
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
-use worker::kv::KvStore;
+
+use crate::data::{codec::CodecSet, storage::Storage};
 
 pub type KeywordRef = String;
 pub type DocumentRef = String;
@@ -11,17 +12,50 @@ pub type DocumentScore<'a> = (String, f64);
 pub static PREFIX_INDEX: &str = "index:";
 pub static PREFIX_DOCUMENT: &str = "document:";
 pub static PREFIX_KEYWORD: &str = "kw:";
+pub static PREFIX_KEYWORD_TOP: &str = "kwtop:";
+pub static PREFIX_KEYWORD_STAGED: &str = "kwstage:";
+/// Marks a document whose delete is under way, see [`deletion`]
+pub static PREFIX_DELETING: &str = "deleting:";
 
 pub const INDEX_VERSION_V1: u8 = 1u8;
+/// Keyword shards store only their postings, see [`codec`]
+pub const INDEX_VERSION_V2: u8 = 2u8;
+/// The newest version this worker reads and writes, which new indexes are created at
+pub const INDEX_VERSION_LATEST: u8 = INDEX_VERSION_V2;
 
 pub static ENV_VAR_N_SHARDS: &str = "N_SHARDS";
+pub static ENV_VAR_YAKE_NGRAMS: &str = "YAKE_NGRAMS";
+pub static ENV_VAR_YAKE_MIN_CHARS: &str = "YAKE_MINIMUM_CHARS";
 pub static ENV_VAR_API_KEY: &str = "API_KEY";
+pub static ENV_VAR_AUTH_DISABLED: &str = "AUTH_DISABLED";
+pub static ENV_VAR_MAX_DOCUMENT_BYTES: &str = "MAX_DOCUMENT_BYTES";
+pub static ENV_VAR_R2_OFFLOAD_BYTES: &str = "R2_OFFLOAD_BYTES";
+pub static ENV_VAR_LANG_CONFIDENCE_MIN: &str = "LANG_CONFIDENCE_MIN";
+pub static ENV_VAR_LANG_DETECT_LANGUAGES: &str = "LANG_DETECT_LANGUAGES";
+pub static ENV_VAR_PRELOAD_DETECTOR: &str = "PRELOAD_DETECTOR";
+pub static ENV_VAR_SEARCH_BUDGET_MS: &str = "SEARCH_BUDGET_MS";
+pub static ENV_VAR_SEARCH_BUDGET_OPS: &str = "SEARCH_BUDGET_OPS";
+pub static ENV_VAR_SEARCH_FACET_MAX_DOCS: &str = "SEARCH_FACET_MAX_DOCS";
+pub static ENV_VAR_ACTIVITY_RETENTION: &str = "ACTIVITY_RETENTION";
+pub static ENV_VAR_KEYWORD_TREND_RETENTION_DAYS: &str = "KEYWORD_TREND_RETENTION_DAYS";
+pub static ENV_VAR_LOG_LEVEL: &str = "LOG_LEVEL";
+pub static ENV_VAR_KV_CONCURRENCY: &str = "KV_CONCURRENCY";
+pub static ENV_VAR_READER_MAX_RESPONSE_BYTES: &str = "READER_MAX_RESPONSE_BYTES";
+pub static ENV_VAR_READER_UNIQUE_IDS: &str = "READER_UNIQUE_IDS";
 
 pub static DEFAULT_N_SHARDS: u32 = 48;
+/// The most shards `N_SHARDS` may ask for
+pub static MAX_N_SHARDS: u32 = 1024;
 pub static DEFAULT_YAKE_NGRAMS: u8 = 3;
 pub static DEFAULT_YAKE_MIN_CHARS: u8 = 2;
+pub static DEFAULT_MAX_DOCUMENT_BYTES: usize = 1024 * 1024;
+pub static DEFAULT_R2_OFFLOAD_BYTES: usize = 256 * 1024;
+pub static DEFAULT_LANG_CONFIDENCE_MIN: f64 = 0.7;
+pub static DEFAULT_SEARCH_BUDGET_MS: u64 = 10_000;
+pub static DEFAULT_SEARCH_BUDGET_OPS: usize = 5_000;
+pub static DEFAULT_SEARCH_FACET_MAX_DOCS: usize = 1_000;
 
-pub trait KvEntry: Sized + Serialize + Deserialize<'static> {
+pub trait KvEntry: Sized + Serialize + for<'de> Deserialize<'de> {
     type Key: Into<String>;
     fn get_kv_key(&self) -> Self::Key;
 }
@@ -38,28 +72,84 @@ pub enum DataStoreError {
     Worker(#[from] worker::Error),
     #[error("Invalid document format: {0}")]
     InvalidFormat(String),
+    #[error("Durable reader response error: {0}")]
+    Encoding(#[from] encoding::EncodingError),
+    #[error("Index version {0} is not one this worker understands (1 to {latest})", latest = INDEX_VERSION_LATEST)]
+    UnsupportedVersion(u8),
 }
 
-pub trait KvPersistent: KvEntry + Deserialize<'static> + Serialize {
-    async fn write(&mut self, store: &KvStore) -> Result<(), DataStoreError> {
+/// An entry stored as JSON under its own KV key. Entries stored differently by some
+/// index version override [`Self::encode`] and [`Self::decode`], and are read and
+/// written with the [`CodecSet`] of their index.
+pub trait KvPersistent: KvEntry {
+    fn encode(&self, _codecs: CodecSet) -> Result<String, DataStoreError> {
+        serde_json::to_string(self).map_err(DataStoreError::Serialization)
+    }
+
+    fn decode(_key: &str, raw: &str, _codecs: CodecSet) -> Result<Self, DataStoreError> {
+        serde_json::from_str(raw).map_err(DataStoreError::Serialization)
+    }
+
+    async fn write_with<S: Storage>(
+        &mut self,
+        store: &S,
+        codecs: CodecSet,
+    ) -> Result<(), DataStoreError> {
         let kv_key = self.get_kv_key().into();
-        let serialized = serde_json::to_string(self).unwrap();
-        store
-            .put(&kv_key, serialized)
-            .map_err(DataStoreError::Kv)?
-            .execute()
-            .await
-            .map_err(DataStoreError::Kv)
+        let serialized = self.encode(codecs)?;
+        store.put(&kv_key, serialized).await
+    }
+
+    async fn read_with<S: Storage>(
+        key: &str,
+        store: &S,
+        codecs: CodecSet,
+    ) -> Result<Self, DataStoreError> {
+        let raw = store
+            .get(key)
+            .await?
+            .ok_or_else(|| DataStoreError::NotFound(key.to_string()))?;
+        Self::decode(key, &raw, codecs)
+    }
+
+    /// Write an entry that every index version stores the same way
+    async fn write<S: Storage>(&mut self, store: &S) -> Result<(), DataStoreError> {
+        self.write_with(store, CodecSet::V1).await
+    }
+
+    /// Read an entry that every index version stores the same way
+    async fn read<S: Storage>(key: &str, store: &S) -> Result<Self, DataStoreError> {
+        Self::read_with(key, store, CodecSet::V1).await
     }
-    async fn read(key: &str, store: &KvStore) -> Result<Self, DataStoreError>;
 }
 
 #[macro_use]
 pub mod document;
 pub mod bulk;
+pub mod codec;
+pub mod deletion;
 pub mod encoding;
+pub mod export;
+pub mod fsck;
 pub mod index;
 pub mod index_manager;
+pub mod inspect;
 pub mod keyword_shard;
+pub mod listing;
+pub mod merge_cache;
+pub mod op_budget;
+pub mod related;
+pub mod reshard;
+pub mod saved_query;
+pub mod shard_repair;
+pub mod snapshot;
+pub mod stoplist;
+pub mod storage;
+pub mod template;
+pub mod top;
+pub mod trace;
+pub mod trend;
+pub mod upgrade;
+pub mod usage;
 #[macro_use]
 pub mod keyword;
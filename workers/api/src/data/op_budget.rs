@@ -0,0 +1,156 @@
+//! Counting the subrequests one request makes against the Workers cap on them.
+//!
+//! Every KV operation and durable reader request is a subrequest, and a request
+//! that runs past the cap has its later ones fail with errors that don't say why.
+//! A handler owns an [`OpBudget`] and wraps its store in a [`CountedStorage`], so
+//! everything the request reads or writes through it is counted, and optional work
+//! stops once it is within [`CAP_HEADROOM_PERCENT`] of the cap.
+
+use std::{cell::Cell, rc::Rc};
+
+use crate::{
+    data::{
+        storage::{ListPage, Storage},
+        DataStoreError,
+    },
+    edge_log,
+};
+
+/// The most subrequests a Worker invocation may make
+pub const SUBREQUEST_CAP: usize = 1000;
+
+/// How close to the cap, in percent of it, optional work is no longer started
+pub const CAP_HEADROOM_PERCENT: usize = 5;
+
+/// The subrequests made so far by one request, shared by everything counting them
+#[derive(Debug)]
+pub struct OpBudget {
+    cap: usize,
+    used: Cell<usize>,
+    overrun_logged: Cell<bool>,
+}
+
+impl OpBudget {
+    pub fn new(cap: usize) -> Rc<OpBudget> {
+        Rc::new(OpBudget {
+            cap,
+            used: Cell::new(0),
+            overrun_logged: Cell::new(false),
+        })
+    }
+
+    /// Count `ops` subrequests. Going over the cap means some path didn't check
+    /// [`Self::near_cap`], so it is logged as an error the first time.
+    pub fn count(&self, ops: usize) {
+        let used = self.used.get().saturating_add(ops);
+        self.used.set(used);
+        if used > self.cap && !self.overrun_logged.replace(true) {
+            let cap = self.cap;
+            edge_log!(
+                console_error,
+                "OpBudget",
+                "",
+                "made {} subrequests, over the cap of {}; later KV operations will fail",
+                used,
+                cap
+            );
+        }
+    }
+
+    pub fn used(&self) -> usize {
+        self.used.get()
+    }
+
+    /// Whether the request is within [`CAP_HEADROOM_PERCENT`] of the cap, leaving
+    /// the rest for the operations it can't do without
+    pub fn near_cap(&self) -> bool {
+        self.used() >= self.cap - self.cap * CAP_HEADROOM_PERCENT / 100
+    }
+
+    pub fn exceeded(&self) -> bool {
+        self.used() > self.cap
+    }
+}
+
+/// A [`Storage`] counting every operation against an [`OpBudget`]
+pub struct CountedStorage<'a, S: Storage> {
+    store: &'a S,
+    ops: Rc<OpBudget>,
+}
+
+impl<'a, S: Storage> CountedStorage<'a, S> {
+    pub fn new(store: &'a S, ops: Rc<OpBudget>) -> CountedStorage<'a, S> {
+        CountedStorage { store, ops }
+    }
+}
+
+impl<S: Storage> Storage for CountedStorage<'_, S> {
+    async fn get(&self, key: &str) -> Result<Option<String>, DataStoreError> {
+        self.ops.count(1);
+        self.store.get(key).await
+    }
+
+    async fn put(&self, key: &str, value: String) -> Result<(), DataStoreError> {
+        self.ops.count(1);
+        self.store.put(key, value).await
+    }
+
+    async fn put_expiring(
+        &self,
+        key: &str,
+        value: String,
+        ttl_secs: u64,
+    ) -> Result<(), DataStoreError> {
+        self.ops.count(1);
+        self.store.put_expiring(key, value, ttl_secs).await
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), DataStoreError> {
+        self.ops.count(1);
+        self.store.delete(key).await
+    }
+
+    async fn list(&self, prefix: &str, cursor: Option<String>) -> Result<ListPage, DataStoreError> {
+        self.ops.count(1);
+        self.store.list(prefix, cursor).await
+    }
+
+    fn count_subrequests(&self, requests: usize) {
+        self.ops.count(requests);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+
+    use super::*;
+    use crate::data::storage::memory::MemoryStorage;
+
+    #[test]
+    fn test_near_cap_leaves_headroom() {
+        let ops = OpBudget::new(200);
+        ops.count(189);
+        assert!(!ops.near_cap());
+        ops.count(1);
+        assert!(ops.near_cap() && !ops.exceeded());
+        ops.count(11);
+        assert!(ops.exceeded());
+        assert_eq!(ops.used(), 201);
+    }
+
+    #[test]
+    fn test_counts_every_operation() {
+        let store = MemoryStorage::default();
+        let ops = OpBudget::new(SUBREQUEST_CAP);
+        let counted = CountedStorage::new(&store, ops.clone());
+        block_on(async {
+            counted.put("a", "1".into()).await.unwrap();
+            counted.get("a").await.unwrap();
+            counted.list("", None).await.unwrap();
+            counted.delete("a").await.unwrap();
+        });
+        counted.count_subrequests(2);
+        assert_eq!(ops.used(), 6);
+    }
+}
@@ -0,0 +1,95 @@
+//! Ranking the keywords that co-occur with a keyword across its documents, for
+//! "related searches".
+
+use std::collections::HashMap;
+
+use serde::Serialize;
+
+use crate::data::document::KeywordScore;
+
+/// The most documents of a keyword sampled for co-occurring keywords, best scored first
+pub const RELATED_DOCUMENT_SAMPLE: usize = 200;
+
+pub const DEFAULT_RELATED_LIMIT: usize = 10;
+pub const MAX_RELATED_LIMIT: usize = 100;
+
+/// A keyword found alongside the requested one
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct RelatedKeyword {
+    pub keyword: String,
+    /// The keyword's scores summed over every sampled document it appears in
+    pub cooccurrence: f64,
+    /// The keyword's mean score in the sampled documents it appears in
+    pub avg_score: f64,
+}
+
+/// Rank the keywords of `documents` by their summed scores, best first, leaving out
+/// `keyword` itself. Ties are broken alphabetically so the ranking is stable.
+pub fn rank_related<'d>(
+    keyword: &str,
+    documents: impl Iterator<Item = &'d [(String, KeywordScore)]>,
+    limit: usize,
+) -> Vec<RelatedKeyword> {
+    // keyword -> (summed score, documents)
+    let mut totals: HashMap<&str, (f64, usize)> = HashMap::new();
+    for keywords in documents {
+        for (other, score) in keywords.iter().filter(|(other, _)| other != keyword) {
+            let total = totals.entry(other.as_str()).or_default();
+            total.0 += score.score;
+            total.1 += 1;
+        }
+    }
+
+    let mut related: Vec<RelatedKeyword> = totals
+        .into_iter()
+        .map(|(other, (sum, docs))| RelatedKeyword {
+            keyword: other.to_string(),
+            cooccurrence: sum,
+            avg_score: sum / docs as f64,
+        })
+        .collect();
+    related.sort_by(|a, b| {
+        b.cooccurrence
+            .partial_cmp(&a.cooccurrence)
+            .unwrap_or(std::cmp::Ordering::Equal)
+            .then_with(|| a.keyword.cmp(&b.keyword))
+    });
+    related.truncate(limit);
+    related
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keywords(items: &[(&str, f64)]) -> Vec<(String, KeywordScore)> {
+        items
+            .iter()
+            .map(|(kw, s)| (kw.to_string(), (*s).into()))
+            .collect()
+    }
+
+    #[test]
+    fn test_rank_related() {
+        let documents = [
+            keywords(&[("ocean", 0.9), ("tide", 0.5), ("beach", 0.4)]),
+            keywords(&[("ocean", 0.8), ("tide", 0.6), ("storm", 0.9)]),
+            keywords(&[("ocean", 0.7), ("beach", 0.2)]),
+        ];
+        let related = rank_related("ocean", documents.iter().map(Vec::as_slice), 10);
+
+        let ranking: Vec<&str> = related.iter().map(|r| r.keyword.as_str()).collect();
+        assert_eq!(ranking, vec!["tide", "storm", "beach"]);
+        assert!((related[0].cooccurrence - 1.1).abs() < 1e-9);
+        assert!((related[0].avg_score - 0.55).abs() < 1e-9);
+        assert!((related[2].avg_score - 0.3).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_rank_related_limit_and_ties() {
+        let documents = [keywords(&[("b", 0.5), ("a", 0.5), ("c", 0.1)])];
+        let related = rank_related("ocean", documents.iter().map(Vec::as_slice), 2);
+        let ranking: Vec<&str> = related.iter().map(|r| r.keyword.as_str()).collect();
+        assert_eq!(ranking, vec!["a", "b"]);
+    }
+}
@@ -0,0 +1,769 @@
+//! Moving an index's keyword shards to a different shard count. A reshard first
+//! stages every keyword's postings in the new layout under keys searches never read,
+//! a bounded batch per call, then flips the index's `n_shards` and replaces each
+//! keyword's shards with its staged ones. While a keyword's shards are replaced, a
+//! marker beside them points readers at the staged copy, so every read sees one
+//! complete layout. The index stays frozen throughout, and a call that fails part
+//! way is safe to repeat with the same cursor.
+
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    data::{
+        codec::CodecSet,
+        document::shard_from_document_id,
+        index::IndexDocument,
+        index_manager::freeze_settling_ms,
+        keyword_shard::{
+            is_keyword_shard_key, keyword_shard_prefix, keyword_top_kv_key,
+            legacy_keyword_shard_prefix, list_keyword_shards, parse_keyword_shard_key,
+            parse_staged_shard_key, reshard_marker_key, staged_shard_kv_key, staged_shard_prefix,
+            KeywordShardData,
+        },
+        storage::{list_all, Storage},
+        DataStoreError, DocumentRef, KvPersistent, PREFIX_KEYWORD, PREFIX_KEYWORD_STAGED,
+        PREFIX_KEYWORD_TOP,
+    },
+    edge_log,
+};
+
+/// The most shard keys staged or replaced per call, keeping each request well under
+/// the KV operation limit
+pub const RESHARD_BATCH_SIZE: usize = 50;
+
+/// The most shards an index can be resharded to
+pub const MAX_N_SHARDS: u32 = 256;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ReshardPhase {
+    /// Postings are copied into staged shards, and searches read the old shards
+    Staging,
+    /// `n_shards` was flipped, and keywords are moved onto their staged shards
+    Cleanup,
+}
+
+/// A reshard in progress, stored on the index document
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ReshardState {
+    pub target_shards: u32,
+    pub phase: ReshardPhase,
+}
+
+/// Where to resume a reshard: within staging, the KV listing page and how many
+/// keys of it were already staged. Cleanup has no position, since it deletes the
+/// staged keys of every keyword it's done with.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReshardCursor {
+    pub phase: ReshardPhase,
+    pub offset: usize,
+    pub page: Option<String>,
+}
+
+impl ReshardCursor {
+    pub fn start() -> ReshardCursor {
+        ReshardCursor {
+            phase: ReshardPhase::Staging,
+            offset: 0,
+            page: None,
+        }
+    }
+
+    pub fn cleanup() -> ReshardCursor {
+        ReshardCursor {
+            phase: ReshardPhase::Cleanup,
+            offset: 0,
+            page: None,
+        }
+    }
+}
+
+impl fmt::Display for ReshardCursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.phase {
+            ReshardPhase::Staging => {
+                let page = self.page.as_deref().unwrap_or("");
+                write!(f, "s:{}:{}", self.offset, page)
+            }
+            ReshardPhase::Cleanup => write!(f, "c"),
+        }
+    }
+}
+
+impl FromStr for ReshardCursor {
+    type Err = String;
+
+    fn from_str(cursor: &str) -> Result<ReshardCursor, String> {
+        let invalid = || format!("Invalid reshard cursor '{}'", cursor);
+        if cursor == "c" {
+            return Ok(ReshardCursor::cleanup());
+        }
+        let mut parts = cursor.splitn(3, ':');
+        if parts.next() != Some("s") {
+            return Err(invalid());
+        }
+        let offset = parts
+            .next()
+            .and_then(|offset| offset.parse().ok())
+            .ok_or_else(invalid)?;
+        let page = parts.next().ok_or_else(invalid)?;
+        Ok(ReshardCursor {
+            phase: ReshardPhase::Staging,
+            offset,
+            page: (!page.is_empty()).then(|| page.to_string()),
+        })
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum ReshardError {
+    #[error("target_shards must be between 1 and {max}, got {0}", max = MAX_N_SHARDS)]
+    InvalidTarget(u32),
+    #[error("Freeze the index with POST /:index/freeze before resharding it")]
+    NotFrozen,
+    #[error(
+        "The index was frozen too recently for every isolate to have seen it; retry in {0} ms"
+    )]
+    FreezeSettling(u64),
+    #[error("The index already has {0} shards")]
+    AlreadySharded(u32),
+    #[error("A reshard to {0} shards is in progress, and must finish first")]
+    InProgress(u32),
+    #[error(transparent)]
+    Store(#[from] DataStoreError),
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ReshardReport {
+    /// The phase this call worked on
+    pub phase: ReshardPhase,
+    pub target_shards: u32,
+    /// The number of shards documents are written to, `target_shards` once flipped
+    pub n_shards: u32,
+    /// Keywords staged or moved onto their staged shards
+    pub keywords: u32,
+    pub shards_written: u32,
+    pub shards_deleted: u32,
+    /// Pass back to continue the reshard, `None` once it's complete
+    pub cursor: Option<String>,
+}
+
+/// Run the next batch of the index's reshard to `target` shards, starting it when
+/// none is in progress. `default_shards` is the worker's `N_SHARDS`.
+pub async fn reshard_batch<S: Storage>(
+    store: &S,
+    mut index_doc: IndexDocument,
+    target: u32,
+    cursor: Option<ReshardCursor>,
+    default_shards: u32,
+    now: u64,
+) -> Result<ReshardReport, ReshardError> {
+    if !(1..=MAX_N_SHARDS).contains(&target) {
+        return Err(ReshardError::InvalidTarget(target));
+    }
+    if !index_doc.frozen {
+        return Err(ReshardError::NotFrozen);
+    }
+    let state = match index_doc.reshard.clone() {
+        Some(state) if state.target_shards != target => {
+            return Err(ReshardError::InProgress(state.target_shards));
+        }
+        Some(state) => state,
+        None if index_doc.shard_count(default_shards) == target => {
+            return Err(ReshardError::AlreadySharded(target));
+        }
+        None => {
+            if let Some(wait_ms) = freeze_settling_ms(&index_doc, now) {
+                return Err(ReshardError::FreezeSettling(wait_ms));
+            }
+            let state = ReshardState {
+                target_shards: target,
+                phase: ReshardPhase::Staging,
+            };
+            index_doc.reshard = Some(state.clone());
+            index_doc.generation += 1;
+            index_doc.write(store).await?;
+            edge_log!(
+                console_log,
+                "Reshard",
+                (index_doc.index.as_str()),
+                "started resharding to {} shards",
+                target
+            );
+            state
+        }
+    };
+
+    let codecs = CodecSet::for_index(&index_doc)?;
+    let mut report = ReshardReport {
+        phase: state.phase,
+        target_shards: target,
+        n_shards: index_doc.shard_count(default_shards),
+        keywords: 0,
+        shards_written: 0,
+        shards_deleted: 0,
+        cursor: None,
+    };
+    match state.phase {
+        // A missing or cleanup cursor restarts staging, which is only ever repeated
+        ReshardPhase::Staging => {
+            let cursor = cursor
+                .filter(|cursor| cursor.phase == ReshardPhase::Staging)
+                .unwrap_or_else(ReshardCursor::start);
+            stage_batch(store, &mut index_doc, cursor, codecs, now, &mut report).await?;
+        }
+        ReshardPhase::Cleanup => {
+            cleanup_batch(store, &mut index_doc, codecs, now, &mut report).await?
+        }
+    }
+    Ok(report)
+}
+
+/// Stage the keywords of the next batch of shard keys, and flip `n_shards` once
+/// every keyword is staged
+async fn stage_batch<S: Storage>(
+    store: &S,
+    index_doc: &mut IndexDocument,
+    cursor: ReshardCursor,
+    codecs: CodecSet,
+    now: u64,
+    report: &mut ReshardReport,
+) -> Result<(), DataStoreError> {
+    let index = index_doc.index.clone();
+    let prefix = format!("{}:{}", index, PREFIX_KEYWORD);
+    let page = store.list(&prefix, cursor.page.clone()).await?;
+    let keys: Vec<&String> = page
+        .keys
+        .iter()
+        .skip(cursor.offset)
+        .take(RESHARD_BATCH_SIZE)
+        .collect();
+    let checked = cursor.offset + keys.len();
+
+    // A keyword whose shards span two batches is staged by both, to the same result
+    let keywords: BTreeSet<String> = keys
+        .iter()
+        .filter_map(|key| parse_keyword_shard_key(&index, key))
+        .map(|(keyword, _)| keyword)
+        .collect();
+    for keyword in &keywords {
+        let staged =
+            stage_keyword(store, codecs, &index, keyword, report.target_shards, now).await?;
+        report.shards_written += staged as u32;
+    }
+    report.keywords = keywords.len() as u32;
+
+    let next = if checked < page.keys.len() {
+        Some(ReshardCursor {
+            offset: checked,
+            ..cursor
+        })
+    } else {
+        page.cursor.map(|page| ReshardCursor {
+            offset: 0,
+            page: Some(page),
+            ..cursor
+        })
+    };
+    if let Some(next) = next {
+        report.cursor = Some(next.to_string());
+        return Ok(());
+    }
+
+    // Every posting is staged, so documents can be written in the new layout
+    index_doc.n_shards = Some(report.target_shards);
+    index_doc.reshard = Some(ReshardState {
+        target_shards: report.target_shards,
+        phase: ReshardPhase::Cleanup,
+    });
+    index_doc.generation += 1;
+    index_doc.write(store).await?;
+    edge_log!(
+        console_log,
+        "Reshard",
+        index,
+        "staged every keyword, n_shards={}",
+        (report.target_shards)
+    );
+    report.n_shards = report.target_shards;
+    report.cursor = Some(ReshardCursor::cleanup().to_string());
+    Ok(())
+}
+
+/// Write every posting of `keyword` to the staged shard it belongs to under
+/// `target` shards, returning the number of staged shards written
+async fn stage_keyword<S: Storage>(
+    store: &S,
+    codecs: CodecSet,
+    index: &str,
+    keyword: &str,
+    target: u32,
+    now: u64,
+) -> Result<usize, DataStoreError> {
+    let mut seen = HashSet::new();
+    let mut staged: BTreeMap<u32, Vec<(DocumentRef, f64)>> = BTreeMap::new();
+    for key in list_keyword_shards(store, index, keyword).await? {
+        let shard = match KeywordShardData::read_with(&key, store, codecs).await {
+            Ok(shard) => shard,
+            Err(DataStoreError::NotFound(_)) => continue,
+            Err(err) => return Err(err),
+        };
+        // A legacy shard listed after the escaped one can repeat its documents
+        for (doc_id, score) in shard.docs {
+            if seen.insert(doc_id.clone()) {
+                let shard = shard_from_document_id(doc_id.clone(), target);
+                staged.entry(shard).or_default().push((doc_id, score));
+            }
+        }
+    }
+    for (shard, docs) in &staged {
+        let data = KeywordShardData::new(index.into(), keyword.into(), *shard, now, docs.clone());
+        store
+            .put(
+                &staged_shard_kv_key(index, keyword, *shard),
+                data.encode(codecs)?,
+            )
+            .await?;
+    }
+    Ok(staged.len())
+}
+
+/// Move the keywords of the first batch of staged keys onto their staged shards,
+/// and finish the reshard once no staged keys are left
+async fn cleanup_batch<S: Storage>(
+    store: &S,
+    index_doc: &mut IndexDocument,
+    codecs: CodecSet,
+    now: u64,
+    report: &mut ReshardReport,
+) -> Result<(), DataStoreError> {
+    let index = index_doc.index.clone();
+    let prefix = format!("{}:{}", index, PREFIX_KEYWORD_STAGED);
+    let page = store.list(&prefix, None).await?;
+    let keys: Vec<&String> = page.keys.iter().take(RESHARD_BATCH_SIZE).collect();
+
+    let mut keywords = BTreeSet::new();
+    for key in &keys {
+        match parse_staged_shard_key(&index, key) {
+            Some((keyword, _)) => {
+                keywords.insert(keyword);
+            }
+            // Not a key staging writes, and left alone it would be listed forever
+            None => {
+                store.delete(key).await?;
+                report.shards_deleted += 1;
+            }
+        }
+    }
+    for keyword in &keywords {
+        let (written, deleted) =
+            swap_keyword(store, codecs, &index, keyword, report.target_shards, now).await?;
+        report.shards_written += written;
+        report.shards_deleted += deleted;
+    }
+    report.keywords = keywords.len() as u32;
+
+    // Each keyword's staged keys are deleted once it's moved, so listing again from
+    // the start continues with the keywords left
+    if keys.len() < page.keys.len() || page.cursor.is_some() {
+        report.cursor = Some(ReshardCursor::cleanup().to_string());
+        return Ok(());
+    }
+    index_doc.reshard = None;
+    index_doc.generation += 1;
+    index_doc.write(store).await?;
+    edge_log!(
+        console_log,
+        "Reshard",
+        index,
+        "resharded to {} shards",
+        (report.target_shards)
+    );
+    Ok(())
+}
+
+/// Replace the shards of `keyword` with its staged shards, returning the number of
+/// shards written and deleted. Readers follow the marker to the staged shards until
+/// the replacement is complete, and the staged shards are deleted last, after the
+/// marker.
+async fn swap_keyword<S: Storage>(
+    store: &S,
+    codecs: CodecSet,
+    index: &str,
+    keyword: &str,
+    target: u32,
+    now: u64,
+) -> Result<(u32, u32), DataStoreError> {
+    let staged_prefix = staged_shard_prefix(index, keyword);
+    let staged_keys: Vec<String> = list_all(store, &staged_prefix)
+        .await?
+        .into_iter()
+        .filter(|key| is_keyword_shard_key(&staged_prefix, key))
+        .collect();
+    let marker = reshard_marker_key(index, keyword);
+    let prefix = keyword_shard_prefix(index, keyword);
+    let listed = list_all(store, &prefix).await?;
+    let marked = listed.contains(&marker);
+    let current: Vec<String> = listed
+        .into_iter()
+        .filter(|key| is_keyword_shard_key(&prefix, key))
+        .collect();
+    let legacy: Vec<String> = match legacy_keyword_shard_prefix(index, keyword) {
+        Some(legacy_prefix) => list_all(store, &legacy_prefix)
+            .await?
+            .into_iter()
+            .filter(|key| is_keyword_shard_key(&legacy_prefix, key))
+            .collect(),
+        None => vec![],
+    };
+
+    // An earlier call replaced the shards and then failed deleting the staged ones,
+    // which may be incomplete by now, so only the deletion is finished
+    if !marked && legacy.is_empty() && in_layout(store, codecs, index, &current, target).await? {
+        for key in &staged_keys {
+            store.delete(key).await?;
+        }
+        return Ok((0, staged_keys.len() as u32));
+    }
+
+    store.put(&marker, "{}".to_string()).await?;
+    let mut kept = HashSet::new();
+    for key in &staged_keys {
+        let mut shard = KeywordShardData::read_with(key, store, codecs).await?;
+        shard.ts = now;
+        shard.summary().write(store).await?;
+        shard.write_with(store, codecs).await?;
+        kept.insert(shard.shard);
+    }
+    let mut deleted = 0;
+    for key in &current {
+        let Some((_, shard)) = parse_keyword_shard_key(index, key) else {
+            continue;
+        };
+        if !kept.contains(&shard) {
+            store.delete(key).await?;
+            store
+                .delete(&keyword_top_kv_key(index, keyword, shard))
+                .await?;
+            deleted += 1;
+        }
+    }
+    for key in &legacy {
+        let Some((_, shard)) = parse_keyword_shard_key(index, key) else {
+            continue;
+        };
+        store.delete(key).await?;
+        let legacy_top_key = format!("{}:{}{}:{}", index, PREFIX_KEYWORD_TOP, keyword, shard);
+        store.delete(&legacy_top_key).await?;
+        deleted += 1;
+    }
+    store.delete(&marker).await?;
+    for key in &staged_keys {
+        store.delete(key).await?;
+    }
+    Ok((staged_keys.len() as u32, deleted))
+}
+
+/// Whether every posting of the shards under `keys` is in the shard it belongs to
+/// under `target` shards
+async fn in_layout<S: Storage>(
+    store: &S,
+    codecs: CodecSet,
+    index: &str,
+    keys: &[String],
+    target: u32,
+) -> Result<bool, DataStoreError> {
+    let mut shards = vec![];
+    for key in keys {
+        match parse_keyword_shard_key(index, key) {
+            Some((_, shard)) if shard < target => shards.push((key, shard)),
+            _ => return Ok(false),
+        }
+    }
+    if shards.is_empty() {
+        return Ok(false);
+    }
+    for (key, shard) in shards {
+        let data = match KeywordShardData::read_with(key, store, codecs).await {
+            Ok(data) => data,
+            Err(DataStoreError::NotFound(_)) => continue,
+            Err(err) => return Err(err),
+        };
+        let misplaced = data
+            .docs
+            .iter()
+            .any(|(doc_id, _)| shard_from_document_id(doc_id.clone(), target) != shard);
+        if misplaced {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+
+    use super::*;
+    use crate::data::{
+        index_manager::IndexManager,
+        keyword::{KeywordManager, MergedKeywordData},
+        keyword_shard::testing::seed_postings,
+        storage::memory::MemoryStorage,
+    };
+    use crate::util::time::ManualClock;
+
+    const KEYWORDS: [&str; 3] = ["ocean", "storm", "tide"];
+
+    /// An index of 8 shards holding a dozen documents per keyword, frozen
+    fn seeded_index(store: &MemoryStorage, index: &str) {
+        let manager = IndexManager::new(store);
+        block_on(manager.create_index(index, None, None)).unwrap();
+        for (i, keyword) in KEYWORDS.iter().enumerate() {
+            let postings: Vec<(String, f64)> = (0..12)
+                .map(|doc| (format!("doc{}", doc), 0.1 + (doc + i) as f64 / 100.0))
+                .collect();
+            let postings: Vec<(&str, f64)> =
+                postings.iter().map(|(d, s)| (d.as_str(), *s)).collect();
+            seed_postings(store, index, 8, keyword, &postings);
+        }
+        block_on(manager.set_frozen(index, true)).unwrap();
+    }
+
+    fn merged(store: &MemoryStorage, index: &str) -> Vec<MergedKeywordData> {
+        let manager = KeywordManager::direct(index.into(), 8, store);
+        KEYWORDS
+            .iter()
+            .map(|keyword| block_on(manager.merge_keyword_shards(keyword.to_string())).unwrap())
+            .collect()
+    }
+
+    fn run(
+        store: &MemoryStorage,
+        index: &str,
+        target: u32,
+        cursor: Option<&str>,
+    ) -> Result<ReshardReport, ReshardError> {
+        let mut index_doc = block_on(IndexManager::new(store).read_index(index)).unwrap();
+        // As if frozen long enough ago for every isolate to have seen it
+        index_doc.frozen_at = None;
+        let cursor = cursor.map(|cursor| cursor.parse().unwrap());
+        block_on(reshard_batch(store, index_doc, target, cursor, 8, 2))
+    }
+
+    /// Run staging calls until `n_shards` flips, checking that searches read the old
+    /// layout before then
+    fn stage_until_flip(store: &MemoryStorage, index: &str, target: u32) -> String {
+        let before = merged(store, index);
+        let shard_keys = store
+            .keys()
+            .into_iter()
+            .filter(|key| key.starts_with(&format!("{}:kw:", index)))
+            .collect::<Vec<_>>();
+        let mut cursor = None;
+        loop {
+            let report = run(store, index, target, cursor.as_deref()).unwrap();
+            assert_eq!(report.phase, ReshardPhase::Staging);
+            cursor = report.cursor;
+            if report.n_shards == target {
+                return cursor.unwrap();
+            }
+            let index_doc = block_on(IndexManager::new(store).read_index(index)).unwrap();
+            assert_eq!(index_doc.n_shards, None);
+            assert_eq!(merged(store, index), before);
+            let unchanged: Vec<String> = store
+                .keys()
+                .into_iter()
+                .filter(|key| key.starts_with(&format!("{}:kw:", index)))
+                .collect();
+            assert_eq!(unchanged, shard_keys);
+        }
+    }
+
+    fn finish(store: &MemoryStorage, index: &str, target: u32, mut cursor: Option<String>) {
+        while let Some(next) = cursor {
+            let report = run(store, index, target, Some(&next)).unwrap();
+            assert_eq!(report.phase, ReshardPhase::Cleanup);
+            cursor = report.cursor;
+        }
+    }
+
+    /// Every shard of the index holds only postings that belong to it under `target`
+    fn assert_layout(store: &MemoryStorage, index: &str, target: u32) {
+        for key in store.keys() {
+            assert!(!key.starts_with(&format!("{}:kwstage:", index)), "{}", key);
+            let Some((_, shard)) = parse_keyword_shard_key(index, &key) else {
+                assert!(!key.starts_with(&format!("{}:kw:", index)), "{}", key);
+                continue;
+            };
+            assert!(shard < target, "{}", key);
+            let data = block_on(KeywordShardData::read(&key, store)).unwrap();
+            for (doc_id, _) in data.docs {
+                assert_eq!(shard_from_document_id(doc_id, target), shard);
+            }
+        }
+    }
+
+    #[test]
+    fn test_reshard_flips_after_staging() {
+        let store = MemoryStorage::default();
+        seeded_index(&store, "reshard-flip");
+        let before = merged(&store, "reshard-flip");
+
+        let cursor = stage_until_flip(&store, "reshard-flip", 3);
+        let index_doc = block_on(IndexManager::new(&store).read_index("reshard-flip")).unwrap();
+        assert_eq!(index_doc.n_shards, Some(3));
+        assert_eq!(
+            index_doc.reshard.map(|state| state.phase),
+            Some(ReshardPhase::Cleanup)
+        );
+        assert_eq!(merged(&store, "reshard-flip"), before);
+
+        finish(&store, "reshard-flip", 3, Some(cursor));
+        assert_layout(&store, "reshard-flip", 3);
+        assert_eq!(merged(&store, "reshard-flip"), before);
+        let index_doc = block_on(IndexManager::new(&store).read_index("reshard-flip")).unwrap();
+        assert_eq!((index_doc.n_shards, index_doc.reshard), (Some(3), None));
+        assert!(index_doc.frozen);
+    }
+
+    #[test]
+    fn test_marker_points_reads_at_staged_shards() {
+        let store = MemoryStorage::default();
+        seeded_index(&store, "reshard-marker");
+        let before = merged(&store, "reshard-marker");
+        let cursor = stage_until_flip(&store, "reshard-marker", 3);
+
+        // A call failed after removing some of a keyword's old shards
+        let marker = reshard_marker_key("reshard-marker", "ocean");
+        block_on(store.put(&marker, "{}".into())).unwrap();
+        let prefix = keyword_shard_prefix("reshard-marker", "ocean");
+        let old_shard = block_on(list_all(&store, &prefix)).unwrap();
+        let old_shard = old_shard
+            .into_iter()
+            .rfind(|key| is_keyword_shard_key(&prefix, key));
+        let old_shard = old_shard.unwrap();
+        block_on(store.delete(&old_shard)).unwrap();
+        assert_eq!(merged(&store, "reshard-marker"), before);
+
+        finish(&store, "reshard-marker", 3, Some(cursor));
+        assert_layout(&store, "reshard-marker", 3);
+        assert_eq!(merged(&store, "reshard-marker"), before);
+    }
+
+    #[test]
+    fn test_failed_calls_resume() {
+        let store = MemoryStorage::default();
+        seeded_index(&store, "reshard-resume");
+        let before = merged(&store, "reshard-resume");
+
+        // A staged write fails: the index isn't flipped, and the same cursor retries
+        let first = run(&store, "reshard-resume", 5, None).unwrap();
+        store.fail_puts("reshard-resume:kwstage:", 1);
+        let failed = run(&store, "reshard-resume", 5, first.cursor.as_deref());
+        assert!(matches!(failed, Err(ReshardError::Store(_))));
+        let index_doc = block_on(IndexManager::new(&store).read_index("reshard-resume")).unwrap();
+        assert_eq!(index_doc.n_shards, None);
+        let cursor = stage_until_flip(&store, "reshard-resume", 5);
+
+        // Replacing a keyword's shards fails after the marker was written
+        store.fail_puts("reshard-resume:kwtop:", 1);
+        let failed = run(&store, "reshard-resume", 5, Some(&cursor));
+        assert!(matches!(failed, Err(ReshardError::Store(_))));
+        assert!(store.keys().iter().any(|key| key.ends_with(":resharding")));
+        assert_eq!(merged(&store, "reshard-resume"), before);
+
+        finish(&store, "reshard-resume", 5, Some(cursor));
+        assert_layout(&store, "reshard-resume", 5);
+        assert_eq!(merged(&store, "reshard-resume"), before);
+    }
+
+    #[test]
+    fn test_leftover_staged_shards_are_only_deleted() {
+        let store = MemoryStorage::default();
+        seeded_index(&store, "reshard-leftover");
+        let cursor = stage_until_flip(&store, "reshard-leftover", 3);
+        finish(&store, "reshard-leftover", 3, Some(cursor));
+        let after = merged(&store, "reshard-leftover");
+
+        // A call failed part way through deleting a keyword's staged shards
+        let manager = IndexManager::new(&store);
+        let mut index_doc = block_on(manager.read_index("reshard-leftover")).unwrap();
+        index_doc.reshard = Some(ReshardState {
+            target_shards: 3,
+            phase: ReshardPhase::Cleanup,
+        });
+        block_on(index_doc.write(&store)).unwrap();
+        let prefix = keyword_shard_prefix("reshard-leftover", "tide");
+        let shard_key = block_on(list_all(&store, &prefix)).unwrap().remove(0);
+        let (_, shard) = parse_keyword_shard_key("reshard-leftover", &shard_key).unwrap();
+        let leftover = block_on(store.get(&shard_key)).unwrap().unwrap();
+        let staged_key = staged_shard_kv_key("reshard-leftover", "tide", shard);
+        block_on(store.put(&staged_key, leftover)).unwrap();
+
+        let report = run(&store, "reshard-leftover", 3, Some("c")).unwrap();
+        assert_eq!((report.shards_written, report.shards_deleted), (0, 1));
+        assert_eq!(report.cursor, None);
+        assert_layout(&store, "reshard-leftover", 3);
+        assert_eq!(merged(&store, "reshard-leftover"), after);
+    }
+
+    #[test]
+    fn test_reshard_rejections() {
+        let store = MemoryStorage::default();
+        seeded_index(&store, "reshard-rejected");
+        assert!(matches!(
+            run(&store, "reshard-rejected", 8, None),
+            Err(ReshardError::AlreadySharded(8))
+        ));
+        assert!(matches!(
+            run(&store, "reshard-rejected", 0, None),
+            Err(ReshardError::InvalidTarget(0))
+        ));
+
+        run(&store, "reshard-rejected", 4, None).unwrap();
+        assert!(matches!(
+            run(&store, "reshard-rejected", 2, None),
+            Err(ReshardError::InProgress(4))
+        ));
+
+        let manager = IndexManager::new(&store);
+        block_on(manager.set_frozen("reshard-rejected", false)).unwrap();
+        assert!(matches!(
+            run(&store, "reshard-rejected", 4, None),
+            Err(ReshardError::NotFrozen)
+        ));
+    }
+
+    #[test]
+    fn test_reshard_waits_for_the_freeze_to_settle() {
+        let store = MemoryStorage::default();
+        seeded_index(&store, "reshard-settling");
+        let manager = IndexManager::new(&store).with_clock(ManualClock::at(1_000));
+        block_on(manager.set_frozen("reshard-settling", false)).unwrap();
+        let index_doc = block_on(manager.set_frozen("reshard-settling", true)).unwrap();
+
+        let early = reshard_batch(&store, index_doc.clone(), 16, None, 8, 21_000);
+        assert!(matches!(
+            block_on(early),
+            Err(ReshardError::FreezeSettling(10_000))
+        ));
+        let index_doc = block_on(manager.read_index("reshard-settling")).unwrap();
+        assert!(index_doc.reshard.is_none());
+        block_on(reshard_batch(&store, index_doc, 16, None, 8, 31_000)).unwrap();
+    }
+
+    #[test]
+    fn test_cursor_round_trips() {
+        for cursor in ["s:0:", "s:12:idx:kw:ocean:3", "c"] {
+            assert_eq!(cursor.parse::<ReshardCursor>().unwrap().to_string(), cursor);
+        }
+        assert!("bogus".parse::<ReshardCursor>().is_err());
+        assert!("s:x:".parse::<ReshardCursor>().is_err());
+    }
+}
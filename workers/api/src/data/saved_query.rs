@@ -0,0 +1,303 @@
+//! Saved queries: a query string and search options stored under a name, so a
+//! canned search can be changed without redeploying the clients that run it.
+//!
+//! A query may hold `{{name}}` placeholders standing for whole terms. Running it
+//! replaces each with the value given for it as a quoted keyword, escaped the way
+//! [`StringTokenizer::quote`] writes one, so a value like `a" || "b` is searched for
+//! as it is rather than read as operators.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    data::{
+        storage::{load_listed, Storage},
+        DataStoreError, IndexName, KvEntry, KvPersistent,
+    },
+    lexer::{tokenizer::StringTokenizer, Expr},
+};
+
+pub static SUFFIX_SAVED_QUERY: &str = "query:";
+
+pub fn saved_query_prefix(index: &str) -> String {
+    format!("{}:{}", index, SUFFIX_SAVED_QUERY)
+}
+
+pub fn saved_query_kv_key(index: &str, name: &str) -> String {
+    format!("{}{}", saved_query_prefix(index), name)
+}
+
+/// Search parameters a saved query can't set, since its query is the query
+const RESERVED_OPTIONS: [&str; 2] = ["query", "text"];
+
+/// Whether `name` can name a placeholder: ASCII letters, digits and `_`
+fn is_placeholder_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// The query with each `{{name}}` placeholder replaced by `value(name)`, or what's
+/// wrong with a placeholder
+fn replace_placeholders(
+    query: &str,
+    mut value: impl FnMut(&str) -> Result<String, String>,
+) -> Result<String, String> {
+    let mut replaced = String::with_capacity(query.len());
+    let mut rest = query;
+    while let Some(start) = rest.find("{{") {
+        replaced.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let Some(end) = after.find("}}") else {
+            return Err("Unclosed placeholder, expected {{name}}".to_string());
+        };
+        let name = &after[..end];
+        if !is_placeholder_name(name) {
+            return Err(format!(
+                "Invalid placeholder '{{{{{}}}}}', names are letters, digits and _",
+                name
+            ));
+        }
+        replaced.push_str(&value(name)?);
+        rest = &after[end + 2..];
+    }
+    replaced.push_str(rest);
+    Ok(replaced)
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct SavedQuery {
+    #[serde(skip)]
+    pub index: IndexName,
+    /// Taken from the path of `PUT /:index/queries/:name`
+    #[serde(default)]
+    pub name: String,
+    pub query: String,
+    /// Search parameters, like `limit` or `filter`, by name. A list sets a repeated
+    /// parameter once per value.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub options: BTreeMap<String, serde_json::Value>,
+}
+
+impl KvEntry for SavedQuery {
+    type Key = String;
+
+    fn get_kv_key(&self) -> String {
+        saved_query_kv_key(&self.index, &self.name)
+    }
+}
+
+impl KvPersistent for SavedQuery {}
+
+impl SavedQuery {
+    /// The most queries an index may save. Listing them reads every one.
+    pub const MAX_QUERIES: usize = 64;
+
+    /// Parse the JSON body of `PUT /:index/queries/:name`, checking that its query
+    /// parses with every placeholder filled in and that its options are parameters
+    pub fn parse(index: &str, name: &str, body: &str) -> Result<SavedQuery, String> {
+        let mut saved: SavedQuery =
+            serde_json::from_str(body).map_err(|err| format!("Invalid saved query: {}", err))?;
+        saved.index = index.to_string();
+        saved.name = name.to_string();
+
+        let filled =
+            replace_placeholders(&saved.query, |_| Ok(StringTokenizer::quote("placeholder")))?;
+        Expr::parse(&filled).map_err(|err| format!("Invalid query: {}", err))?;
+
+        for (option, value) in &saved.options {
+            if RESERVED_OPTIONS.contains(&option.as_str()) {
+                return Err(format!("options can't set '{}'", option));
+            }
+            let values = match value {
+                serde_json::Value::Array(values) => values.as_slice(),
+                value => std::slice::from_ref(value),
+            };
+            if !values.iter().all(|value| value_param(value).is_some()) {
+                return Err(format!(
+                    "Option '{}' must be a string, number or boolean, or a list of them",
+                    option
+                ));
+            }
+        }
+        Ok(saved)
+    }
+
+    /// The names of the query's placeholders, each once
+    pub fn placeholders(&self) -> BTreeSet<String> {
+        let mut names = BTreeSet::new();
+        let _ = replace_placeholders(&self.query, |name| {
+            names.insert(name.to_string());
+            Ok(String::new())
+        });
+        names
+    }
+
+    /// The query with its placeholders replaced by the quoted `values`, every one of
+    /// which must be given, and no others
+    pub fn substitute(&self, values: &BTreeMap<String, String>) -> Result<String, String> {
+        let placeholders = self.placeholders();
+        if let Some(unknown) = values.keys().find(|name| !placeholders.contains(*name)) {
+            return Err(format!("The query has no placeholder '{}'", unknown));
+        }
+        replace_placeholders(&self.query, |name| match values.get(name) {
+            Some(value) => Ok(StringTokenizer::quote(value)),
+            None => Err(format!("Missing a value for placeholder '{}'", name)),
+        })
+    }
+
+    /// The options as query parameters of a search, a list giving one per value
+    pub fn query_pairs(&self) -> Vec<(String, String)> {
+        let mut pairs = vec![];
+        for (option, value) in &self.options {
+            let values = match value {
+                serde_json::Value::Array(values) => values.as_slice(),
+                value => std::slice::from_ref(value),
+            };
+            pairs.extend(
+                values
+                    .iter()
+                    .filter_map(value_param)
+                    .map(|value| (option.clone(), value)),
+            );
+        }
+        pairs
+    }
+
+    /// Read a single saved query by name
+    pub async fn load<S: Storage>(
+        store: &S,
+        index: &str,
+        name: &str,
+    ) -> Result<SavedQuery, DataStoreError> {
+        let mut saved = SavedQuery::read(&saved_query_kv_key(index, name), store).await?;
+        saved.index = index.to_string();
+        saved.name = name.to_string();
+        Ok(saved)
+    }
+}
+
+/// A scalar option value as a query parameter
+fn value_param(value: &serde_json::Value) -> Option<String> {
+    match value {
+        serde_json::Value::String(value) => Some(value.clone()),
+        serde_json::Value::Number(value) => Some(value.to_string()),
+        serde_json::Value::Bool(value) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+/// Every query saved for `index`, ordered by name
+pub async fn list_saved_queries<S: Storage>(
+    store: &S,
+    index: &str,
+) -> Result<Vec<SavedQuery>, DataStoreError> {
+    load_listed(store, &saved_query_prefix(index), |name| async move {
+        SavedQuery::load(store, index, &name).await
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+
+    use super::*;
+    use crate::data::storage::memory::MemoryStorage;
+
+    fn saved(body: &str) -> SavedQuery {
+        SavedQuery::parse("idx", "q", body).unwrap()
+    }
+
+    #[test]
+    fn test_parse_saved_query() {
+        let parsed = saved(
+            r#"{"query":"{{term}} && ~spam","options":{"limit":5,"full":true,"filter":["lang=en","year>2000"]}}"#,
+        );
+        assert_eq!(parsed.placeholders(), BTreeSet::from(["term".to_string()]));
+        assert_eq!(
+            parsed.query_pairs(),
+            vec![
+                ("filter".to_string(), "lang=en".to_string()),
+                ("filter".to_string(), "year>2000".to_string()),
+                ("full".to_string(), "true".to_string()),
+                ("limit".to_string(), "5".to_string()),
+            ]
+        );
+
+        for invalid in [
+            r#"{"query":"apple &&"}"#,
+            r#"{"query":"{{term"}"#,
+            r#"{"query":"{{te rm}}"}"#,
+            r#"{"query":"\"{{term}} pie\""}"#,
+            r#"{"query":"apple","options":{"query":"pear"}}"#,
+            r#"{"query":"apple","options":{"limit":{"max":5}}}"#,
+            r#"{"query":"apple","options":{"filter":[["lang=en"]]}}"#,
+            r#"{"query":"apple","limit":5}"#,
+            r#"{"options":{}}"#,
+        ] {
+            assert!(
+                SavedQuery::parse("idx", "q", invalid).is_err(),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn test_substitution_cannot_inject_operators() {
+        let parsed = saved(r#"{"query":"{{term}} && ~({{other}} || spam)"}"#);
+        for value in [
+            r#"a" || "b"#,
+            r#"say "cheese""#,
+            r"trailing\",
+            r#"\""#,
+            ") || (everything",
+            "~id:secret",
+            "{{other}}",
+        ] {
+            let values = BTreeMap::from([
+                ("term".to_string(), value.to_string()),
+                ("other".to_string(), "ham".to_string()),
+            ]);
+            let query = parsed.substitute(&values).unwrap();
+            let expected = Expr::And(
+                Box::new(Expr::Word(value.to_string())),
+                Box::new(Expr::Not(Box::new(Expr::Or(
+                    Box::new(Expr::Word("ham".into())),
+                    Box::new(Expr::Word("spam".into())),
+                )))),
+            );
+            assert_eq!(Expr::parse(&query).unwrap(), expected, "{}", query);
+        }
+
+        let missing = parsed.substitute(&BTreeMap::from([("term".into(), "a".into())]));
+        assert_eq!(
+            missing.unwrap_err(),
+            "Missing a value for placeholder 'other'"
+        );
+        let unknown = saved(r#"{"query":"apple"}"#)
+            .substitute(&BTreeMap::from([("term".into(), "a".into())]));
+        assert_eq!(unknown.unwrap_err(), "The query has no placeholder 'term'");
+    }
+
+    #[test]
+    fn test_list_saved_queries() {
+        let store = MemoryStorage::default();
+        for (index, name) in [("idx", "b"), ("idx", "a"), ("other", "c")] {
+            let mut query = SavedQuery::parse(index, name, r#"{"query":"apple"}"#).unwrap();
+            block_on(query.write(&store)).unwrap();
+        }
+        let names: Vec<String> = block_on(list_saved_queries(&store, "idx"))
+            .unwrap()
+            .into_iter()
+            .map(|saved| saved.name)
+            .collect();
+        assert_eq!(names, vec!["a", "b"]);
+        let loaded = block_on(SavedQuery::load(&store, "other", "c")).unwrap();
+        assert_eq!(
+            (loaded.index.as_str(), loaded.query.as_str()),
+            ("other", "apple")
+        );
+    }
+}
@@ -0,0 +1,274 @@
+//! Keyword shards a document write couldn't update, even after a retry. The write
+//! reports them to the index's journal, which keeps them in its durable storage and
+//! replays them on its alarm, as it does usage, so a posting a transient KV error
+//! left unwritten doesn't wait for fsck to find it.
+//!
+//! A replay doesn't repeat the change that failed. It reads the document as it's
+//! stored now and writes the posting it has for the keyword, or removes the posting
+//! when the document lost the keyword since, is gone or is being deleted. Replaying
+//! after a later write of the document, or twice, leaves the shard as that write
+//! left its others.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde::{Deserialize, Serialize};
+
+use crate::data::{
+    codec::CodecSet,
+    deletion::deletion_marker_key,
+    document::{read_index_document, Document, UpdateOutcome},
+    keyword_shard::ShardWriteBatch,
+    storage::Storage,
+    DataStoreError,
+};
+
+/// The most documents one replay writes the shards of, keeping the journal's alarm
+/// well within its subrequest limit. The rest wait for the next.
+pub const MAX_REPAIR_FLUSH_DOCUMENTS: usize = 20;
+
+/// Shard writes reported to an index's journal and not yet replayed
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct PendingShardRepairs {
+    pub index: String,
+    /// The keywords of each document whose shard is to be written again
+    pub documents: BTreeMap<String, BTreeSet<String>>,
+}
+
+impl PendingShardRepairs {
+    pub fn new(index: &str) -> PendingShardRepairs {
+        PendingShardRepairs {
+            index: index.to_string(),
+            documents: BTreeMap::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.documents.is_empty()
+    }
+
+    /// Add the keywords whose shard a write of `doc_id` failed
+    pub fn record(&mut self, doc_id: &str, keywords: impl IntoIterator<Item = String>) {
+        let mut keywords = keywords.into_iter().peekable();
+        if keywords.peek().is_some() {
+            self.documents
+                .entry(doc_id.to_string())
+                .or_default()
+                .extend(keywords);
+        }
+    }
+
+    /// Add the keywords whose shard `outcome`, a write of `doc_id`, failed
+    pub fn record_outcome(&mut self, doc_id: &str, outcome: &UpdateOutcome) {
+        let failed = outcome
+            .failed_keywords
+            .iter()
+            .map(|(keyword, _)| keyword.clone());
+        self.record(doc_id, failed);
+    }
+
+    /// Add the documents of `other`, such as those a failed replay kept
+    pub fn merge(&mut self, other: PendingShardRepairs) {
+        for (doc_id, keywords) in other.documents {
+            self.record(&doc_id, keywords);
+        }
+    }
+
+    /// Write the shards of up to `max_documents` documents again, with the index's
+    /// codecs and its shard count, `default_n_shards` when it records none. A
+    /// keyword stays pending until its shard is written, and what didn't fit waits
+    /// for the next replay. Nothing is written while the index is frozen or
+    /// resharding, and a deleted index's repairs are dropped.
+    pub async fn flush<S: Storage>(
+        &mut self,
+        store: &S,
+        default_n_shards: u32,
+        now: u64,
+        max_documents: usize,
+    ) -> Result<(), DataStoreError> {
+        let Some(index) = read_index_document(store, &self.index).await? else {
+            self.documents.clear();
+            return Ok(());
+        };
+        if index.frozen || index.reshard.is_some() {
+            return Ok(());
+        }
+        let n_shards = index.shard_count(default_n_shards);
+        let codecs = CodecSet::for_index(&index)?;
+
+        for _ in 0..max_documents {
+            let Some((doc_id, keywords)) = self.documents.pop_first() else {
+                break;
+            };
+            let replayed = self
+                .replay(store, &doc_id, &keywords, n_shards, codecs, now)
+                .await;
+            let failed = match replayed {
+                Ok(failed) => failed,
+                Err(err) => {
+                    self.record(&doc_id, keywords);
+                    return Err(err);
+                }
+            };
+            let mut failed = failed.into_iter();
+            if let Some((keyword, err)) = failed.next() {
+                let keywords = std::iter::once(keyword).chain(failed.map(|(keyword, _)| keyword));
+                self.record(&doc_id, keywords);
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the posting `doc_id` has now for each of `keywords`, returning those
+    /// whose shard failed with their errors
+    async fn replay<S: Storage>(
+        &self,
+        store: &S,
+        doc_id: &str,
+        keywords: &BTreeSet<String>,
+        n_shards: u32,
+        codecs: CodecSet,
+        now: u64,
+    ) -> Result<Vec<(String, DataStoreError)>, DataStoreError> {
+        let deleting = store
+            .get(&deletion_marker_key(&self.index, doc_id))
+            .await?
+            .is_some();
+        let document = match Document::from_remote(store, &self.index, doc_id.into()).await {
+            Ok(document) if !deleting => Some(document),
+            Ok(_) | Err(DataStoreError::NotFound(_)) => None,
+            Err(err) => return Err(err),
+        };
+        let stored = document
+            .iter()
+            .flat_map(|document| document.keywords.iter().flatten());
+
+        let mut batch = ShardWriteBatch::new(&self.index, doc_id, n_shards).with_codecs(codecs);
+        for keyword in keywords {
+            batch.remove(keyword);
+        }
+        for (keyword, score) in stored.filter(|(keyword, _)| keywords.contains(keyword)) {
+            batch.upsert(keyword, score.score);
+        }
+        Ok(batch
+            .execute(store, now)
+            .await
+            .into_iter()
+            .filter_map(|(keyword, result)| result.err().map(|err| (keyword, err)))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+
+    use super::*;
+    use crate::data::{
+        document::{shard_from_document_id, testing::index_text, IndexingOptions, LangDetection},
+        index_manager::IndexManager,
+        keyword_shard::KeywordShardData,
+        storage::memory::MemoryStorage,
+        DEFAULT_N_SHARDS,
+    };
+
+    /// Index `body` as doc1 of "idx" while its first keyword's shard refuses every
+    /// write, returning the keyword and the repairs the write reported
+    fn write_with_failing_shard(
+        store: &MemoryStorage,
+        body: &str,
+    ) -> (String, PendingShardRepairs) {
+        let keyword = index_text(store, "other", "doc1", body).keywords.unwrap()[0]
+            .0
+            .clone();
+        store.fail_puts(&format!("idx:kw:{}:", keyword), 2);
+        let mut document = Document::new_with_id("idx", "doc1");
+        document.set_language(lingua::IsoCode639_1::EN);
+        let outcome = block_on(document.update_with(
+            store,
+            &IndexingOptions::default(),
+            body.into(),
+            None,
+            LangDetection::WhenMissing,
+        ))
+        .unwrap();
+
+        let mut pending = PendingShardRepairs::new("idx");
+        pending.record_outcome("doc1", &outcome);
+        (keyword, pending)
+    }
+
+    fn posting(store: &MemoryStorage, keyword: &str) -> Option<f64> {
+        let shard = shard_from_document_id("doc1".into(), DEFAULT_N_SHARDS);
+        let loaded = block_on(KeywordShardData::load(
+            store,
+            CodecSet::V1,
+            "idx",
+            keyword,
+            shard,
+        ))
+        .unwrap();
+        let shard = loaded?;
+        shard
+            .docs
+            .iter()
+            .find(|(id, _)| id == "doc1")
+            .map(|(_, score)| *score)
+    }
+
+    fn flush(store: &MemoryStorage, pending: &mut PendingShardRepairs) -> bool {
+        block_on(pending.flush(store, DEFAULT_N_SHARDS, 1, MAX_REPAIR_FLUSH_DOCUMENTS)).is_ok()
+    }
+
+    #[test]
+    fn test_failed_shard_writes_are_replayed() {
+        let store = MemoryStorage::default();
+        block_on(IndexManager::new(&store).create_index("idx", None, None)).unwrap();
+        let (keyword, mut pending) =
+            write_with_failing_shard(&store, "Ocean tides and sandy beaches.");
+        assert_eq!(pending.documents["doc1"], BTreeSet::from([keyword.clone()]));
+        assert_eq!(posting(&store, &keyword), None);
+
+        // A replay that fails again keeps the keyword for the next alarm
+        store.fail_puts(&format!("idx:kw:{}:", keyword), 1);
+        assert!(!flush(&store, &mut pending));
+        assert_eq!(pending.documents["doc1"].len(), 1);
+
+        assert!(flush(&store, &mut pending));
+        assert!(pending.is_empty());
+        assert!(posting(&store, &keyword).is_some());
+    }
+
+    #[test]
+    fn test_replay_writes_the_stored_document() {
+        let store = MemoryStorage::default();
+        block_on(IndexManager::new(&store).create_index("idx", None, None)).unwrap();
+        let (keyword, mut pending) =
+            write_with_failing_shard(&store, "Ocean tides and sandy beaches.");
+
+        // The document lost the keyword before the replay, so its posting stays out
+        index_text(&store, "idx", "doc1", "Glaciers melt slowly.");
+        let mut rewritten = pending.clone();
+        assert!(flush(&store, &mut rewritten));
+        assert_eq!(posting(&store, &keyword), None);
+
+        // A deleted index's repairs are dropped
+        block_on(IndexManager::new(&store).delete_index("idx")).unwrap();
+        assert!(flush(&store, &mut pending));
+        assert!(pending.is_empty());
+    }
+
+    #[test]
+    fn test_record_and_merge() {
+        let mut pending = PendingShardRepairs::new("idx");
+        pending.record("doc1", vec![]);
+        assert!(pending.is_empty());
+        pending.record("doc1", vec!["ocean".to_string()]);
+        let mut other = PendingShardRepairs::new("idx");
+        other.record("doc1", vec!["tide".to_string(), "ocean".to_string()]);
+        other.record("doc2", vec!["tide".to_string()]);
+        pending.merge(other);
+        assert_eq!(pending.documents["doc1"].len(), 2);
+        assert_eq!(pending.documents.len(), 2);
+    }
+}
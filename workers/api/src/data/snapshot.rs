@@ -0,0 +1,741 @@
+//! Point-in-time copies of a frozen index in R2. A snapshot writes the index's
+//! documents a bounded batch per call, each batch as an NDJSON part object, and
+//! writes the snapshot's manifest, the index's settings, stop-list and saved
+//! queries, last, so a snapshot only lists once it is complete. A restore deletes
+//! every key of the index and replays the parts, one per call. Both keep their progress under
+//! `_internal:`, so each call continues where the last one stopped, and a call
+//! that fails part way is safe to repeat.
+
+use lingua::IsoCode639_1;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    data::{
+        document::{document_kv_key, Document, IndexingOptions},
+        index::{IndexDocument, IndexSettings},
+        index_manager::freeze_settling_ms,
+        saved_query::{list_saved_queries, SavedQuery},
+        stoplist::StopList,
+        storage::{list_all, list_up_to, load_found, Storage},
+        DataStoreError, KvPersistent, PREFIX_DOCUMENT,
+    },
+    edge_log,
+};
+
+/// Where snapshots are written in the R2 bucket
+pub const SNAPSHOT_PREFIX: &str = "snapshots/";
+
+/// The most documents written to one snapshot part, and so replayed per restore
+/// call, keeping each restore well under the KV operation limit
+pub const SNAPSHOT_BATCH_SIZE: usize = 20;
+
+/// The most keys a restore deletes per call while wiping the index
+pub const RESTORE_WIPE_BATCH_SIZE: usize = 200;
+
+/// The R2 key of the snapshot of `index` taken at `created`
+pub fn snapshot_key(index: &str, created: u64) -> String {
+    format!("{}{}/{}.ndjson", SNAPSHOT_PREFIX, index, created)
+}
+
+/// The R2 key of a snapshot's `part`th batch of documents
+fn part_key(snapshot: &str, part: u32) -> String {
+    format!("{}.{:05}", snapshot, part)
+}
+
+pub fn snapshot_progress_key(index: &str) -> String {
+    format!("_internal:snapshot:{}", index)
+}
+
+pub fn restore_progress_key(index: &str) -> String {
+    format!("_internal:restore:{}", index)
+}
+
+/// A snapshot's manifest, the single line of its `.ndjson` object
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SnapshotManifest {
+    pub index: String,
+    pub created: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default_lang: Option<IsoCode639_1>,
+    #[serde(default, skip_serializing_if = "IndexSettings::is_empty")]
+    pub settings: IndexSettings,
+    #[serde(default)]
+    pub stoplist: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub queries: Vec<SavedQuery>,
+    pub documents: u32,
+    pub parts: u32,
+}
+
+/// A snapshot being written, stored under [`snapshot_progress_key`]: the KV listing
+/// page of documents, and how many keys of it were already written
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct SnapshotProgress {
+    created: u64,
+    page: Option<String>,
+    offset: usize,
+    parts: u32,
+    documents: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RestorePhase {
+    /// Every key of the index is deleted
+    Wipe,
+    /// The snapshot's parts are written back
+    Replay,
+}
+
+/// A restore in progress, stored under [`restore_progress_key`]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct RestoreProgress {
+    snapshot: u64,
+    phase: RestorePhase,
+    /// The next part to replay
+    part: u32,
+    documents: u32,
+}
+
+#[derive(Error, Debug)]
+pub enum SnapshotError {
+    #[error("Freeze the index with POST /:index/freeze before taking or restoring a snapshot")]
+    NotFrozen,
+    #[error(
+        "The index was frozen too recently for every isolate to have seen it; retry in {0} ms"
+    )]
+    FreezeSettling(u64),
+    #[error("Snapshot {0} of the index doesn't exist")]
+    NotFound(u64),
+    #[error("{0} is in progress, and must finish first")]
+    InProgress(String),
+    #[error("A reshard to {0} shards is in progress, and must finish first")]
+    Resharding(u32),
+    #[error(transparent)]
+    Store(#[from] DataStoreError),
+}
+
+/// A snapshot listed by `GET /:index/snapshots`
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct SnapshotListing {
+    /// The snapshot's creation time in milliseconds, which `restore` takes
+    pub snapshot: u64,
+    pub key: String,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct SnapshotReport {
+    pub snapshot: u64,
+    pub key: String,
+    /// Documents written so far
+    pub documents: u32,
+    pub parts: u32,
+    /// Whether the manifest is written, and the snapshot can be restored
+    pub complete: bool,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct RestoreReport {
+    pub snapshot: u64,
+    /// The phase this call worked on
+    pub phase: RestorePhase,
+    pub keys_deleted: u32,
+    /// Documents replayed so far
+    pub documents: u32,
+    /// Whether the index matches the snapshot, and can be unfrozen
+    pub complete: bool,
+}
+
+async fn read_progress<S: Storage, T: DeserializeOwned>(
+    store: &S,
+    key: &str,
+) -> Result<Option<T>, DataStoreError> {
+    match store.get(key).await? {
+        Some(raw) => serde_json::from_str(&raw)
+            .map(Some)
+            .map_err(DataStoreError::Serialization),
+        None => Ok(None),
+    }
+}
+
+async fn write_progress<S: Storage, T: Serialize>(
+    store: &S,
+    key: &str,
+    progress: &T,
+) -> Result<(), DataStoreError> {
+    let serialized = serde_json::to_string(progress).map_err(DataStoreError::Serialization)?;
+    store.put(key, serialized).await
+}
+
+/// Every complete snapshot of `index` in `bucket`, oldest first
+pub async fn list_snapshots<B: Storage>(
+    bucket: &B,
+    index: &str,
+) -> Result<Vec<SnapshotListing>, DataStoreError> {
+    let prefix = format!("{}{}/", SNAPSHOT_PREFIX, index);
+    let mut snapshots: Vec<SnapshotListing> = list_all(bucket, &prefix)
+        .await?
+        .into_iter()
+        .filter_map(|key| {
+            let created = key.strip_prefix(&prefix)?.strip_suffix(".ndjson")?;
+            Some(SnapshotListing {
+                snapshot: created.parse().ok()?,
+                key,
+            })
+        })
+        .collect();
+    snapshots.sort_by_key(|listing| listing.snapshot);
+    Ok(snapshots)
+}
+
+/// Write the next batch of the snapshot of a frozen index to `bucket`, starting one
+/// at `now` when none is in progress. Offloaded bodies are read from `bucket` too,
+/// and written into the snapshot with the rest of their document.
+pub async fn snapshot_batch<S: Storage, B: Storage>(
+    store: &S,
+    bucket: &B,
+    index_doc: &IndexDocument,
+    now: u64,
+) -> Result<SnapshotReport, SnapshotError> {
+    if !index_doc.frozen {
+        return Err(SnapshotError::NotFrozen);
+    }
+    let index = index_doc.index.as_str();
+    let restore: Option<RestoreProgress> =
+        read_progress(store, &restore_progress_key(index)).await?;
+    if let Some(restore) = restore {
+        return Err(SnapshotError::InProgress(format!(
+            "A restore of snapshot {}",
+            restore.snapshot
+        )));
+    }
+    let progress_key = snapshot_progress_key(index);
+    let mut progress = match read_progress(store, &progress_key).await? {
+        Some(progress) => progress,
+        None => {
+            if let Some(wait_ms) = freeze_settling_ms(index_doc, now) {
+                return Err(SnapshotError::FreezeSettling(wait_ms));
+            }
+            edge_log!(console_log, "Snapshot", index, "started snapshot {}", now);
+            SnapshotProgress {
+                created: now,
+                page: None,
+                offset: 0,
+                parts: 0,
+                documents: 0,
+            }
+        }
+    };
+    let key = snapshot_key(index, progress.created);
+
+    let prefix = format!("{}:{}", index, PREFIX_DOCUMENT);
+    let page = store.list(&prefix, progress.page.clone()).await?;
+    let keys: Vec<&String> = page
+        .keys
+        .iter()
+        .skip(progress.offset)
+        .take(SNAPSHOT_BATCH_SIZE)
+        .collect();
+    let checked = progress.offset + keys.len();
+
+    let doc_ids = keys.iter().filter_map(|key| key.strip_prefix(&prefix));
+    let documents = load_found(doc_ids, |doc_id| {
+        Document::from_remote(store, index, doc_id)
+    })
+    .await?;
+    let mut lines = String::new();
+    let mut written = 0;
+    for mut document in documents {
+        document.load_body(bucket).await?;
+        document.body_ref = None;
+        lines.push_str(&serde_json::to_string(&document).map_err(DataStoreError::Serialization)?);
+        lines.push('\n');
+        written += 1;
+    }
+    // Repeating a batch rewrites the same part, so a failed call is safe to repeat
+    if written > 0 {
+        bucket.put(&part_key(&key, progress.parts), lines).await?;
+        progress.parts += 1;
+        progress.documents += written;
+    }
+
+    let next_page = match checked < page.keys.len() {
+        true => Some((progress.page.clone(), checked)),
+        false => page.cursor.map(|cursor| (Some(cursor), 0)),
+    };
+    if let Some((page, offset)) = next_page {
+        progress.page = page;
+        progress.offset = offset;
+        write_progress(store, &progress_key, &progress).await?;
+        return Ok(SnapshotReport {
+            snapshot: progress.created,
+            key,
+            documents: progress.documents,
+            parts: progress.parts,
+            complete: false,
+        });
+    }
+
+    let manifest = SnapshotManifest {
+        index: index.to_string(),
+        created: progress.created,
+        default_lang: index_doc.default_lang,
+        settings: index_doc.settings.clone(),
+        stoplist: StopList::load(store, index).await?.keywords(),
+        queries: list_saved_queries(store, index).await?,
+        documents: progress.documents,
+        parts: progress.parts,
+    };
+    let mut manifest_line =
+        serde_json::to_string(&manifest).map_err(DataStoreError::Serialization)?;
+    manifest_line.push('\n');
+    bucket.put(&key, manifest_line).await?;
+    store.delete(&progress_key).await?;
+    edge_log!(
+        console_log,
+        "Snapshot",
+        index,
+        "wrote snapshot {} of {} documents",
+        (progress.created),
+        (progress.documents)
+    );
+    Ok(SnapshotReport {
+        snapshot: progress.created,
+        key,
+        documents: progress.documents,
+        parts: progress.parts,
+        complete: true,
+    })
+}
+
+/// Run the next batch of restoring a frozen index to `snapshot`, starting the
+/// restore when none is in progress. The index keeps its shard count, which
+/// `options.n_shards` must be, and its documents' offloaded bodies are written to
+/// `bucket`.
+pub async fn restore_batch<S: Storage, B: Storage>(
+    store: &S,
+    bucket: &B,
+    mut index_doc: IndexDocument,
+    snapshot: u64,
+    options: &IndexingOptions,
+    now: u64,
+) -> Result<RestoreReport, SnapshotError> {
+    if !index_doc.frozen {
+        return Err(SnapshotError::NotFrozen);
+    }
+    let index = index_doc.index.clone();
+    if let Some(reshard) = index_doc.reshard.as_ref() {
+        return Err(SnapshotError::Resharding(reshard.target_shards));
+    }
+    let snapshotting: Option<SnapshotProgress> =
+        read_progress(store, &snapshot_progress_key(&index)).await?;
+    if let Some(snapshotting) = snapshotting {
+        return Err(SnapshotError::InProgress(format!(
+            "Snapshot {}",
+            snapshotting.created
+        )));
+    }
+
+    let key = snapshot_key(&index, snapshot);
+    let manifest: SnapshotManifest = match bucket.get(&key).await? {
+        Some(raw) => serde_json::from_str(raw.trim()).map_err(DataStoreError::Serialization)?,
+        None => return Err(SnapshotError::NotFound(snapshot)),
+    };
+    let progress_key = restore_progress_key(&index);
+    let mut progress = match read_progress::<_, RestoreProgress>(store, &progress_key).await? {
+        Some(progress) if progress.snapshot != snapshot => {
+            return Err(SnapshotError::InProgress(format!(
+                "A restore of snapshot {}",
+                progress.snapshot
+            )));
+        }
+        Some(progress) => progress,
+        None => {
+            if let Some(wait_ms) = freeze_settling_ms(&index_doc, now) {
+                return Err(SnapshotError::FreezeSettling(wait_ms));
+            }
+            let progress = RestoreProgress {
+                snapshot,
+                phase: RestorePhase::Wipe,
+                part: 0,
+                documents: 0,
+            };
+            write_progress(store, &progress_key, &progress).await?;
+            edge_log!(
+                console_log,
+                "Snapshot",
+                (index.as_str()),
+                "started restoring snapshot {}",
+                snapshot
+            );
+            progress
+        }
+    };
+
+    let mut report = RestoreReport {
+        snapshot,
+        phase: progress.phase,
+        keys_deleted: 0,
+        documents: progress.documents,
+        complete: false,
+    };
+    match progress.phase {
+        RestorePhase::Wipe => {
+            report.keys_deleted = wipe_batch(store, bucket, &index).await?;
+            if report.keys_deleted < RESTORE_WIPE_BATCH_SIZE as u32 {
+                // The index is empty, so the snapshot's settings go back first
+                StopList::new(&index, &manifest.stoplist)
+                    .write(store)
+                    .await?;
+                for saved in &manifest.queries {
+                    let mut saved = SavedQuery {
+                        index: index.clone(),
+                        ..saved.clone()
+                    };
+                    saved.write(store).await?;
+                }
+                index_doc.default_lang = manifest.default_lang;
+                index_doc.settings = manifest.settings;
+                index_doc.docs_count = 0;
+                index_doc.generation += 1;
+                index_doc.write(store).await?;
+                progress.phase = RestorePhase::Replay;
+            }
+            write_progress(store, &progress_key, &progress).await?;
+        }
+        RestorePhase::Replay => {
+            if progress.part < manifest.parts {
+                let part = part_key(&key, progress.part);
+                let lines = bucket
+                    .get(&part)
+                    .await?
+                    .ok_or_else(|| DataStoreError::NotFound(part.clone()))?;
+                for line in lines.lines().filter(|line| !line.trim().is_empty()) {
+                    let mut document: Document =
+                        serde_json::from_str(line).map_err(DataStoreError::Serialization)?;
+                    document.index = index.clone();
+                    document.restore(store, Some(bucket), options, now).await?;
+                    progress.documents += 1;
+                }
+                progress.part += 1;
+                report.documents = progress.documents;
+            }
+            if progress.part < manifest.parts {
+                write_progress(store, &progress_key, &progress).await?;
+            } else {
+                index_doc.docs_count = progress.documents;
+                index_doc.generation += 1;
+                index_doc.write(store).await?;
+                store.delete(&progress_key).await?;
+                report.complete = true;
+                edge_log!(
+                    console_log,
+                    "Snapshot",
+                    (index.as_str()),
+                    "restored snapshot {} of {} documents",
+                    snapshot,
+                    (progress.documents)
+                );
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// Delete the first batch of the index's keys, and the offloaded bodies of the
+/// documents among them, returning the number of keys deleted. Deleted keys no
+/// longer list, so every batch lists from the start.
+async fn wipe_batch<S: Storage, B: Storage>(
+    store: &S,
+    bucket: &B,
+    index: &str,
+) -> Result<u32, DataStoreError> {
+    let prefix = format!("{}:", index);
+    let document_prefix = document_kv_key(index, &String::new());
+    let keys = list_up_to(store, &prefix, RESTORE_WIPE_BATCH_SIZE).await?;
+    for key in &keys {
+        store.delete(key).await?;
+        if key.starts_with(&document_prefix) {
+            bucket.delete(key).await?;
+        }
+    }
+    Ok(keys.len() as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+
+    use super::*;
+    use crate::data::{
+        document::{testing::index_text, LangDetection},
+        index_manager::IndexManager,
+        keyword_shard::list_keyword_shards,
+        storage::memory::MemoryStorage,
+        KvEntry,
+    };
+    use crate::util::time::ManualClock;
+
+    fn frozen_index(store: &MemoryStorage, index: &str) -> IndexDocument {
+        let index_doc = block_on(IndexManager::new(store).set_frozen(index, true)).unwrap();
+        // As if frozen long enough ago for every isolate to have seen it
+        IndexDocument {
+            frozen_at: None,
+            ..index_doc
+        }
+    }
+
+    fn snapshot(store: &MemoryStorage, bucket: &MemoryStorage, index: &str) -> (u64, usize) {
+        let mut calls = 0;
+        loop {
+            calls += 1;
+            let index_doc = frozen_index(store, index);
+            let report = block_on(snapshot_batch(store, bucket, &index_doc, 1_000)).unwrap();
+            if report.complete {
+                return (report.snapshot, calls);
+            }
+        }
+    }
+
+    fn restore(store: &MemoryStorage, bucket: &MemoryStorage, index: &str, snapshot: u64) -> u32 {
+        let options = IndexingOptions::default();
+        loop {
+            let index_doc = frozen_index(store, index);
+            let report = block_on(restore_batch(
+                store, bucket, index_doc, snapshot, &options, 2_000,
+            ))
+            .unwrap();
+            if report.complete {
+                return report.documents;
+            }
+        }
+    }
+
+    #[test]
+    fn test_snapshot_and_restore_round_trip() {
+        let store = MemoryStorage::default();
+        let bucket = MemoryStorage::default();
+        let manager = IndexManager::new(&store);
+        let settings = IndexSettings {
+            limit: Some(5),
+            ..IndexSettings::default()
+        };
+        block_on(manager.create_index("snap-round-trip", None, Some(settings.clone()))).unwrap();
+        block_on(StopList::new("snap-round-trip", &["the"]).write(&store)).unwrap();
+        let mut saved = SavedQuery::parse(
+            "snap-round-trip",
+            "tides",
+            r#"{"query":"{{term}} && ocean"}"#,
+        )
+        .unwrap();
+        block_on(saved.write(&store)).unwrap();
+        let bodies = [
+            "Ocean tides rise over sandy beaches.",
+            "Glaciers melt into the ocean.",
+            "Mountain trails climb through forests.",
+            "Desert dunes shift with the wind.",
+            "River deltas feed coastal wetlands.",
+        ];
+        for (i, body) in bodies.iter().enumerate() {
+            index_text(&store, "snap-round-trip", &format!("doc{}", i), body);
+        }
+        let (created, calls) = snapshot(&store, &bucket, "snap-round-trip");
+        // Listing pages of three keys, so the five documents take two calls
+        assert_eq!((created, calls), (1_000, 2));
+        assert!(!store
+            .keys()
+            .contains(&snapshot_progress_key("snap-round-trip")));
+        let listed = block_on(list_snapshots(&bucket, "snap-round-trip")).unwrap();
+        assert_eq!(
+            listed,
+            vec![SnapshotListing {
+                snapshot: 1_000,
+                key: "snapshots/snap-round-trip/1000.ndjson".into(),
+            }]
+        );
+
+        // Change the index after the snapshot, then put it back
+        block_on(manager.set_frozen("snap-round-trip", false)).unwrap();
+        let original = block_on(Document::from_remote(
+            &store,
+            "snap-round-trip",
+            "doc0".into(),
+        ))
+        .unwrap();
+        index_text(&store, "snap-round-trip", "doc0", "Volcanic ash clouds.");
+        index_text(&store, "snap-round-trip", "extra", "Volcanic islands.");
+        block_on(manager.update_settings("snap-round-trip", IndexSettings::default())).unwrap();
+        block_on(StopList::new::<&str>("snap-round-trip", &[]).write(&store)).unwrap();
+        block_on(store.delete(&saved.get_kv_key())).unwrap();
+
+        assert_eq!(restore(&store, &bucket, "snap-round-trip", created), 5);
+        let index_doc = block_on(manager.read_index("snap-round-trip")).unwrap();
+        assert_eq!((index_doc.docs_count, index_doc.settings), (5, settings));
+        let stoplist = block_on(StopList::load(&store, "snap-round-trip")).unwrap();
+        assert_eq!(stoplist.keywords(), vec!["the"]);
+        let queries = block_on(list_saved_queries(&store, "snap-round-trip")).unwrap();
+        assert_eq!(queries, vec![saved]);
+        assert!(!store
+            .keys()
+            .contains(&restore_progress_key("snap-round-trip")));
+
+        let restored = block_on(Document::from_remote(
+            &store,
+            "snap-round-trip",
+            "doc0".into(),
+        ))
+        .unwrap();
+        assert_eq!(restored.revision, original.revision);
+        assert_eq!(restored.document_body, original.document_body);
+        let missing = block_on(Document::from_remote(
+            &store,
+            "snap-round-trip",
+            "extra".into(),
+        ));
+        assert!(matches!(missing, Err(DataStoreError::NotFound(_))));
+        // The replaced revision's postings are gone, and the snapshot's are back
+        let volcanic = block_on(list_keyword_shards(&store, "snap-round-trip", "volcanic"));
+        assert!(volcanic.unwrap().is_empty());
+        for (keyword, _) in original.keywords.unwrap() {
+            let shards = block_on(list_keyword_shards(&store, "snap-round-trip", &keyword));
+            assert!(!shards.unwrap().is_empty(), "no shard for '{}'", keyword);
+        }
+    }
+
+    #[test]
+    fn test_offloaded_bodies_are_snapshotted() {
+        let store = MemoryStorage::default();
+        let bucket = MemoryStorage::default();
+        block_on(IndexManager::new(&store).create_index("snap-offload", None, None)).unwrap();
+        let options = IndexingOptions {
+            offload_bytes: 16,
+            ..IndexingOptions::default()
+        };
+        let body = "Ocean tides roll over the sandy beaches at dawn.";
+        let mut document = Document::new_with_id("snap-offload", "doc1");
+        document.set_language(IsoCode639_1::EN);
+        block_on(document.update_with_bodies(
+            &store,
+            Some(&bucket),
+            &options,
+            body.into(),
+            None,
+            LangDetection::WhenMissing,
+        ))
+        .unwrap();
+
+        let (created, _) = snapshot(&store, &bucket, "snap-offload");
+        let part = block_on(bucket.get(&part_key(&snapshot_key("snap-offload", created), 0)));
+        let part: Document = serde_json::from_str(part.unwrap().unwrap().trim()).unwrap();
+        assert_eq!(
+            (part.document_body.as_deref(), part.body_ref),
+            (Some(body), None)
+        );
+
+        let index_doc = frozen_index(&store, "snap-offload");
+        let report = block_on(restore_batch(
+            &store, &bucket, index_doc, created, &options, 2_000,
+        ))
+        .unwrap();
+        assert_eq!(report.phase, RestorePhase::Wipe);
+        assert!(!bucket
+            .keys()
+            .contains(&"snap-offload:document:doc1".to_string()));
+
+        assert_eq!(restore(&store, &bucket, "snap-offload", created), 1);
+        let mut restored =
+            block_on(Document::from_remote(&store, "snap-offload", "doc1".into())).unwrap();
+        block_on(restored.load_body(&bucket)).unwrap();
+        assert_eq!(restored.document_body.as_deref(), Some(body));
+    }
+
+    #[test]
+    fn test_rejected_snapshots_and_restores() {
+        let store = MemoryStorage::default();
+        let bucket = MemoryStorage::default();
+        let manager = IndexManager::new(&store);
+        let index_doc = block_on(manager.create_index("snap-rejects", None, None)).unwrap();
+        let options = IndexingOptions::default();
+
+        let not_frozen = block_on(snapshot_batch(&store, &bucket, &index_doc, 1));
+        assert!(matches!(not_frozen, Err(SnapshotError::NotFrozen)));
+        let not_frozen = block_on(restore_batch(
+            &store,
+            &bucket,
+            index_doc.clone(),
+            1,
+            &options,
+            1,
+        ));
+        assert!(matches!(not_frozen, Err(SnapshotError::NotFrozen)));
+
+        let index_doc = frozen_index(&store, "snap-rejects");
+        let missing = block_on(restore_batch(
+            &store,
+            &bucket,
+            index_doc.clone(),
+            1,
+            &options,
+            1,
+        ));
+        assert!(matches!(missing, Err(SnapshotError::NotFound(1))));
+
+        // Start a restore, part way, then try to snapshot or restore another snapshot
+        index_text(&store, "snap-rejects", "doc1", "Ocean tides.");
+        let (first, _) = snapshot(&store, &bucket, "snap-rejects");
+        let index_doc = frozen_index(&store, "snap-rejects");
+        let report = block_on(snapshot_batch(&store, &bucket, &index_doc, 5_000)).unwrap();
+        assert!(report.complete);
+        block_on(restore_batch(
+            &store,
+            &bucket,
+            index_doc.clone(),
+            first,
+            &options,
+            1,
+        ))
+        .unwrap();
+        let busy = block_on(snapshot_batch(&store, &bucket, &index_doc, 6_000));
+        assert!(matches!(busy, Err(SnapshotError::InProgress(_))));
+        let busy = block_on(restore_batch(
+            &store, &bucket, index_doc, 5_000, &options, 1,
+        ));
+        assert!(matches!(busy, Err(SnapshotError::InProgress(_))));
+    }
+
+    #[test]
+    fn test_snapshots_and_restores_wait_for_the_freeze_to_settle() {
+        let store = MemoryStorage::default();
+        let bucket = MemoryStorage::default();
+        block_on(IndexManager::new(&store).create_index("snap-settling", None, None)).unwrap();
+        index_text(&store, "snap-settling", "doc1", "Ocean tides.");
+        let (created, _) = snapshot(&store, &bucket, "snap-settling");
+        let manager = IndexManager::new(&store).with_clock(ManualClock::at(1_000));
+        block_on(manager.set_frozen("snap-settling", false)).unwrap();
+        let index_doc = block_on(manager.set_frozen("snap-settling", true)).unwrap();
+        let options = IndexingOptions::default();
+
+        let early = block_on(snapshot_batch(&store, &bucket, &index_doc, 21_000));
+        assert!(matches!(early, Err(SnapshotError::FreezeSettling(10_000))));
+        let early = block_on(restore_batch(
+            &store,
+            &bucket,
+            index_doc.clone(),
+            created,
+            &options,
+            21_000,
+        ));
+        assert!(matches!(early, Err(SnapshotError::FreezeSettling(10_000))));
+        assert!(!store
+            .keys()
+            .contains(&restore_progress_key("snap-settling")));
+
+        let report = block_on(snapshot_batch(&store, &bucket, &index_doc, 31_000)).unwrap();
+        assert!(report.complete);
+        block_on(restore_batch(
+            &store, &bucket, index_doc, created, &options, 31_000,
+        ))
+        .unwrap();
+    }
+}
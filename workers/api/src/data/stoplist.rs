@@ -0,0 +1,179 @@
+use std::collections::BTreeSet;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    data::{
+        keyword_shard::list_keyword_shards, storage::Storage, DataStoreError, IndexName, KvEntry,
+        KvPersistent,
+    },
+    util::concurrency::join_bounded,
+};
+
+pub static SUFFIX_STOPLIST: &str = "stoplist";
+
+pub fn stoplist_kv_key(index: &str) -> String {
+    format!("{}:{}", index, SUFFIX_STOPLIST)
+}
+
+/// Normalize a keyword the way YAKE emits them: lowercased, with runs of
+/// whitespace collapsed to a single space
+pub fn normalize_keyword(keyword: &str) -> String {
+    keyword
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+/// Keywords that are dropped from every document of an index before its keyword
+/// shards are written. Changing the list does not rewrite existing shards; a
+/// document only loses its stop-listed keywords the next time it is indexed.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct StopList {
+    #[serde(skip)]
+    pub index: IndexName,
+    keywords: BTreeSet<String>,
+}
+
+impl KvEntry for StopList {
+    type Key = String;
+
+    fn get_kv_key(&self) -> String {
+        stoplist_kv_key(&self.index)
+    }
+}
+
+impl KvPersistent for StopList {}
+
+impl StopList {
+    /// The most keywords a stop-list may hold. Counting the stop-listed keywords an
+    /// index has stored costs one list per entry.
+    pub const MAX_ENTRIES: usize = 256;
+
+    /// Build a stop-list from raw keywords, normalizing them and dropping blanks
+    /// and duplicates
+    pub fn new<K: AsRef<str>>(index: &str, keywords: &[K]) -> StopList {
+        StopList {
+            index: index.to_string(),
+            keywords: keywords
+                .iter()
+                .map(|keyword| normalize_keyword(keyword.as_ref()))
+                .filter(|keyword| !keyword.is_empty())
+                .collect(),
+        }
+    }
+
+    /// Read the stop-list of `index`, which is empty until one has been set
+    pub async fn load<S: Storage>(store: &S, index: &str) -> Result<StopList, DataStoreError> {
+        match StopList::read(&stoplist_kv_key(index), store).await {
+            Ok(mut stoplist) => {
+                stoplist.index = index.to_string();
+                Ok(stoplist)
+            }
+            Err(DataStoreError::NotFound(_)) => Ok(StopList::new::<&str>(index, &[])),
+            Err(err) => Err(err),
+        }
+    }
+
+    pub fn keywords(&self) -> Vec<String> {
+        self.keywords.iter().cloned().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.keywords.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keywords.is_empty()
+    }
+
+    pub fn contains(&self, keyword: &str) -> bool {
+        !self.is_empty() && self.keywords.contains(&normalize_keyword(keyword))
+    }
+
+    /// Drop every stop-listed keyword from a document's extracted keywords
+    pub fn filter(&self, keywords: Vec<(String, f64)>) -> Vec<(String, f64)> {
+        keywords
+            .into_iter()
+            .filter(|(keyword, _)| !self.contains(keyword))
+            .collect()
+    }
+
+    /// The number of stop-listed keywords that still have shards stored in the index,
+    /// left over from before they were stop-listed
+    pub async fn count_stored<S: Storage>(&self, store: &S) -> Result<u32, DataStoreError> {
+        let lists: Vec<_> = self
+            .keywords
+            .iter()
+            .map(|keyword| list_keyword_shards(store, &self.index, keyword))
+            .collect();
+
+        let mut stored = 0;
+        for shard_keys in join_bounded(lists).await {
+            if !shard_keys?.is_empty() {
+                stored += 1;
+            }
+        }
+        Ok(stored)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+
+    use super::*;
+    use crate::data::{document::testing::index_text, storage::memory::MemoryStorage};
+
+    #[test]
+    fn test_normalize_keyword() {
+        assert_eq!(normalize_keyword("Acme Corp"), "acme corp");
+        assert_eq!(normalize_keyword("  ACME \t\n corp "), "acme corp");
+        assert_eq!(normalize_keyword("Ünïcode"), "ünïcode");
+        assert_eq!(normalize_keyword("   "), "");
+    }
+
+    #[test]
+    fn test_new_dedupes_normalized_keywords() {
+        let stoplist = StopList::new("idx", &["Acme Corp", "acme  corp", " ", "Footer"]);
+        assert_eq!(stoplist.keywords(), vec!["acme corp", "footer"]);
+        assert!(stoplist.contains("ACME corp"));
+        assert!(!stoplist.contains("acme"));
+    }
+
+    #[test]
+    fn test_filter_drops_stoplisted_keywords() {
+        let stoplist = StopList::new("idx", &["Acme"]);
+        let keywords = vec![("acme".to_string(), 0.9), ("ocean".to_string(), 0.5)];
+        assert_eq!(stoplist.filter(keywords), vec![("ocean".to_string(), 0.5)]);
+    }
+
+    #[test]
+    fn test_load_round_trips() {
+        let store = MemoryStorage::default();
+        block_on(async {
+            assert!(StopList::load(&store, "idx").await.unwrap().is_empty());
+
+            let mut stoplist = StopList::new("idx", &["Acme"]);
+            stoplist.write(&store).await.unwrap();
+            assert_eq!(store.keys(), vec!["idx:stoplist"]);
+            assert_eq!(StopList::load(&store, "idx").await.unwrap(), stoplist);
+        });
+    }
+
+    #[test]
+    fn test_count_stored() {
+        let store = MemoryStorage::default();
+        let doc = index_text(
+            &store,
+            "idx",
+            "doc1",
+            "The ocean tide rolls over the beach.",
+        );
+        let stored = doc.keywords.unwrap()[0].0.clone();
+
+        let stoplist = StopList::new("idx", &[stored.as_str(), "never indexed"]);
+        assert_eq!(block_on(stoplist.count_stored(&store)).unwrap(), 1);
+    }
+}
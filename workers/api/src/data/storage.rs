@@ -0,0 +1,601 @@
+//! The key-value operations the data layer needs, abstracted away from
+//! [`worker::kv::KvStore`] so the indexing and query logic can run against an
+//! in-memory store under plain `cargo test`. R2 buckets implement it too, for
+//! document bodies too large to keep in KV.
+
+use std::{fmt, future::Future, str::FromStr, sync::Arc};
+
+use worker::{kv::KvStore, Bucket};
+
+use crate::{data::DataStoreError, util::concurrency::join_bounded};
+
+/// One page of keys returned by [`Storage::list`]
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ListPage {
+    pub keys: Vec<String>,
+    /// Pass back to [`Storage::list`] to fetch the next page, `None` once complete
+    pub cursor: Option<String>,
+}
+
+/// Where to resume a walk of the keys under a prefix a listing page at a time: the
+/// KV listing page, and how many of its keys were already walked. Written as
+/// `{offset}:{page}`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PageCursor {
+    pub offset: usize,
+    pub page: Option<String>,
+}
+
+impl fmt::Display for PageCursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.offset, self.page.as_deref().unwrap_or(""))
+    }
+}
+
+impl FromStr for PageCursor {
+    type Err = String;
+
+    fn from_str(cursor: &str) -> Result<PageCursor, String> {
+        let invalid = || format!("Invalid cursor '{}'", cursor);
+        let (offset, page) = cursor.split_once(':').ok_or_else(invalid)?;
+        Ok(PageCursor {
+            offset: offset.parse().map_err(|_| invalid())?,
+            page: (!page.is_empty()).then(|| page.to_string()),
+        })
+    }
+}
+
+// Workers run on a single thread, so the futures never need to be `Send`
+#[allow(async_fn_in_trait)]
+pub trait Storage {
+    async fn get(&self, key: &str) -> Result<Option<String>, DataStoreError>;
+    async fn put(&self, key: &str, value: String) -> Result<(), DataStoreError>;
+    async fn delete(&self, key: &str) -> Result<(), DataStoreError>;
+    async fn list(&self, prefix: &str, cursor: Option<String>) -> Result<ListPage, DataStoreError>;
+
+    /// Put `value` under `key`, to be deleted by the store `ttl_secs` later. Stores
+    /// that can't expire keys keep it like any other.
+    async fn put_expiring(
+        &self,
+        key: &str,
+        value: String,
+        _ttl_secs: u64,
+    ) -> Result<(), DataStoreError> {
+        self.put(key, value).await
+    }
+
+    /// Record `requests` subrequests made outside the store on its behalf, such as to
+    /// the durable reader. Only a [`CountedStorage`](crate::data::op_budget::CountedStorage)
+    /// keeps count.
+    fn count_subrequests(&self, _requests: usize) {}
+}
+
+/// List every key under `prefix`, following cursors until the listing is complete
+pub async fn list_all<S: Storage>(store: &S, prefix: &str) -> Result<Vec<String>, DataStoreError> {
+    list_up_to(store, prefix, usize::MAX).await
+}
+
+/// Read every entry under `prefix` with `load`, given the rest of its key, in
+/// listing order and leaving out those deleted since they were listed
+pub async fn load_listed<S, T, F, Fut>(
+    store: &S,
+    prefix: &str,
+    load: F,
+) -> Result<Vec<T>, DataStoreError>
+where
+    S: Storage,
+    F: Fn(String) -> Fut,
+    Fut: Future<Output = Result<T, DataStoreError>>,
+{
+    let keys = list_all(store, prefix).await?;
+    let names = keys.iter().filter_map(|key| key.strip_prefix(prefix));
+    load_found(names, load).await
+}
+
+/// Read each of the listed `names` with `load`, as [`load_listed`] does, for
+/// callers that list a page at a time
+pub async fn load_found<'n, T, F, Fut>(
+    names: impl IntoIterator<Item = &'n str>,
+    load: F,
+) -> Result<Vec<T>, DataStoreError>
+where
+    F: Fn(String) -> Fut,
+    Fut: Future<Output = Result<T, DataStoreError>>,
+{
+    let reads = names.into_iter().map(|name| load(name.to_string()));
+    let mut loaded = vec![];
+    for read in join_bounded(reads).await {
+        match read {
+            Ok(value) => loaded.push(value),
+            // Deleted since it was listed
+            Err(DataStoreError::NotFound(_)) => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(loaded)
+}
+
+/// List the first `limit` keys under `prefix`, following cursors only until that
+/// many were listed, for callers that don't need every key
+pub async fn list_up_to<S: Storage>(
+    store: &S,
+    prefix: &str,
+    limit: usize,
+) -> Result<Vec<String>, DataStoreError> {
+    let mut page = store.list(prefix, None).await?;
+    let mut keys = std::mem::take(&mut page.keys);
+    while keys.len() < limit {
+        let Some(cursor) = page.cursor.take() else {
+            break;
+        };
+        page = store.list(prefix, Some(cursor)).await?;
+        keys.append(&mut page.keys);
+    }
+    keys.truncate(limit);
+    Ok(keys)
+}
+
+impl Storage for KvStore {
+    async fn get(&self, key: &str) -> Result<Option<String>, DataStoreError> {
+        KvStore::get(self, key)
+            .text()
+            .await
+            .map_err(DataStoreError::Kv)
+    }
+
+    async fn put(&self, key: &str, value: String) -> Result<(), DataStoreError> {
+        KvStore::put(self, key, value)
+            .map_err(DataStoreError::Kv)?
+            .execute()
+            .await
+            .map_err(DataStoreError::Kv)
+    }
+
+    async fn put_expiring(
+        &self,
+        key: &str,
+        value: String,
+        ttl_secs: u64,
+    ) -> Result<(), DataStoreError> {
+        KvStore::put(self, key, value)
+            .map_err(DataStoreError::Kv)?
+            .expiration_ttl(ttl_secs)
+            .execute()
+            .await
+            .map_err(DataStoreError::Kv)
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), DataStoreError> {
+        KvStore::delete(self, key).await.map_err(DataStoreError::Kv)
+    }
+
+    async fn list(&self, prefix: &str, cursor: Option<String>) -> Result<ListPage, DataStoreError> {
+        let mut request = KvStore::list(self).prefix(prefix.into());
+        if let Some(cursor) = cursor {
+            request = request.cursor(cursor);
+        }
+        let response = request.execute().await.map_err(DataStoreError::Kv)?;
+        Ok(ListPage {
+            keys: response.keys.into_iter().map(|key| key.name).collect(),
+            cursor: match response.list_complete {
+                true => None,
+                false => response.cursor,
+            },
+        })
+    }
+}
+
+impl Storage for Bucket {
+    async fn get(&self, key: &str) -> Result<Option<String>, DataStoreError> {
+        match Bucket::get(self, key).execute().await? {
+            Some(object) => match object.body() {
+                Some(body) => Ok(Some(body.text().await?)),
+                None => Ok(None),
+            },
+            None => Ok(None),
+        }
+    }
+
+    async fn put(&self, key: &str, value: String) -> Result<(), DataStoreError> {
+        Bucket::put(self, key, value).execute().await?;
+        Ok(())
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), DataStoreError> {
+        Ok(Bucket::delete(self, key).await?)
+    }
+
+    async fn list(&self, prefix: &str, cursor: Option<String>) -> Result<ListPage, DataStoreError> {
+        let mut request = Bucket::list(self).prefix(prefix);
+        if let Some(cursor) = cursor {
+            request = request.cursor(cursor);
+        }
+        let response = request.execute().await?;
+        Ok(ListPage {
+            keys: response
+                .objects()
+                .iter()
+                .map(|object| object.key())
+                .collect(),
+            cursor: match response.truncated() {
+                true => response.cursor(),
+                false => None,
+            },
+        })
+    }
+}
+
+impl<S: Storage> Storage for Arc<S> {
+    async fn get(&self, key: &str) -> Result<Option<String>, DataStoreError> {
+        self.as_ref().get(key).await
+    }
+
+    async fn put(&self, key: &str, value: String) -> Result<(), DataStoreError> {
+        self.as_ref().put(key, value).await
+    }
+
+    async fn put_expiring(
+        &self,
+        key: &str,
+        value: String,
+        ttl_secs: u64,
+    ) -> Result<(), DataStoreError> {
+        self.as_ref().put_expiring(key, value, ttl_secs).await
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), DataStoreError> {
+        self.as_ref().delete(key).await
+    }
+
+    async fn list(&self, prefix: &str, cursor: Option<String>) -> Result<ListPage, DataStoreError> {
+        self.as_ref().list(prefix, cursor).await
+    }
+
+    fn count_subrequests(&self, requests: usize) {
+        self.as_ref().count_subrequests(requests)
+    }
+}
+
+/// An in-memory [`Storage`] for native tests, counting every operation so tests can
+/// assert on KV costs. Listing pages are deliberately small to exercise cursors.
+#[cfg(test)]
+pub mod memory {
+    use std::{
+        cell::{Cell, RefCell},
+        collections::BTreeMap,
+        task::Poll,
+        time::Duration,
+    };
+
+    use super::*;
+
+    #[derive(Debug, Default, Clone, Copy, PartialEq)]
+    pub struct OpCounts {
+        pub gets: usize,
+        pub puts: usize,
+        pub deletes: usize,
+        pub lists: usize,
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    enum Op {
+        Get,
+        Put,
+        Delete,
+        List,
+    }
+
+    pub struct MemoryStorage {
+        data: RefCell<BTreeMap<String, String>>,
+        /// The TTL, in seconds, each key was last put with, see [`Self::ttl`]
+        ttls: RefCell<BTreeMap<String, u64>>,
+        counts: RefCell<OpCounts>,
+        page_size: usize,
+        /// Operations on key prefixes that fail next, and how many more times they will
+        failing: RefCell<Vec<(Op, String, usize)>>,
+        /// How long every get and list blocks for, standing in for a slow store
+        read_delay: Cell<Duration>,
+        /// Whether operations yield once before running, see [`Self::track_in_flight`]
+        yields: Cell<bool>,
+        in_flight: Cell<usize>,
+        max_in_flight: Cell<usize>,
+    }
+
+    /// An operation counted in flight until it's dropped
+    struct InFlight<'a>(&'a Cell<usize>);
+
+    impl Drop for InFlight<'_> {
+        fn drop(&mut self) {
+            self.0.set(self.0.get() - 1);
+        }
+    }
+
+    impl Default for MemoryStorage {
+        fn default() -> Self {
+            MemoryStorage::with_page_size(3)
+        }
+    }
+
+    impl MemoryStorage {
+        pub fn with_page_size(page_size: usize) -> MemoryStorage {
+            MemoryStorage {
+                data: RefCell::new(BTreeMap::new()),
+                ttls: RefCell::new(BTreeMap::new()),
+                counts: RefCell::new(OpCounts::default()),
+                page_size,
+                failing: RefCell::new(vec![]),
+                read_delay: Cell::new(Duration::ZERO),
+                yields: Cell::new(false),
+                in_flight: Cell::new(0),
+                max_in_flight: Cell::new(0),
+            }
+        }
+
+        /// Make every later operation yield once before it runs, as a KV request
+        /// would, so operations that are awaited together are in flight together
+        /// and [`Self::max_in_flight`] counts them
+        pub fn track_in_flight(&self) {
+            self.yields.set(true);
+            self.max_in_flight.set(0);
+        }
+
+        /// The most operations in flight at once since [`Self::track_in_flight`]
+        pub fn max_in_flight(&self) -> usize {
+            self.max_in_flight.get()
+        }
+
+        async fn start(&self) -> InFlight<'_> {
+            let in_flight = self.in_flight.get() + 1;
+            self.in_flight.set(in_flight);
+            self.max_in_flight
+                .set(self.max_in_flight.get().max(in_flight));
+            let mut yielded = !self.yields.get();
+            let flight = InFlight(&self.in_flight);
+            futures::future::poll_fn(|cx| match yielded {
+                true => Poll::Ready(()),
+                false => {
+                    yielded = true;
+                    cx.waker().wake_by_ref();
+                    Poll::Pending
+                }
+            })
+            .await;
+            flight
+        }
+
+        /// Make every later get and list take at least `delay`
+        pub fn slow_reads(&self, delay: Duration) {
+            self.read_delay.set(delay);
+        }
+
+        fn wait(&self) {
+            if !self.read_delay.get().is_zero() {
+                std::thread::sleep(self.read_delay.get());
+            }
+        }
+
+        /// Make the next `times` puts to keys starting with `prefix` fail, to stand
+        /// in for transient KV errors
+        pub fn fail_puts(&self, prefix: &str, times: usize) {
+            self.fail(Op::Put, prefix, times);
+        }
+
+        /// Like [`Self::fail_puts`], for gets
+        pub fn fail_gets(&self, prefix: &str, times: usize) {
+            self.fail(Op::Get, prefix, times);
+        }
+
+        /// Like [`Self::fail_puts`], for deletes
+        pub fn fail_deletes(&self, prefix: &str, times: usize) {
+            self.fail(Op::Delete, prefix, times);
+        }
+
+        /// Like [`Self::fail_puts`], for listings of prefixes starting with `prefix`
+        pub fn fail_lists(&self, prefix: &str, times: usize) {
+            self.fail(Op::List, prefix, times);
+        }
+
+        fn fail(&self, op: Op, prefix: &str, times: usize) {
+            self.failing
+                .borrow_mut()
+                .push((op, prefix.to_string(), times));
+        }
+
+        /// Fail `op` on `key` if a failure was injected for it
+        fn check_failure(&self, op: Op, key: &str) -> Result<(), DataStoreError> {
+            let mut failing = self.failing.borrow_mut();
+            let Some((_, _, times)) = failing.iter_mut().find(|(failing_op, prefix, times)| {
+                *failing_op == op && *times > 0 && key.starts_with(prefix.as_str())
+            }) else {
+                return Ok(());
+            };
+            *times -= 1;
+            Err(DataStoreError::Worker(worker::Error::RustError(format!(
+                "injected {:?} failure for '{}'",
+                op, key
+            ))))
+        }
+
+        pub fn counts(&self) -> OpCounts {
+            *self.counts.borrow()
+        }
+
+        pub fn keys(&self) -> Vec<String> {
+            self.data.borrow().keys().cloned().collect()
+        }
+
+        /// The TTL `key` was last put with, `None` when it doesn't expire. Keys never
+        /// expire here.
+        pub fn ttl(&self, key: &str) -> Option<u64> {
+            self.ttls.borrow().get(key).copied()
+        }
+    }
+
+    impl Storage for MemoryStorage {
+        async fn get(&self, key: &str) -> Result<Option<String>, DataStoreError> {
+            let _flight = self.start().await;
+            self.counts.borrow_mut().gets += 1;
+            self.wait();
+            self.check_failure(Op::Get, key)?;
+            Ok(self.data.borrow().get(key).cloned())
+        }
+
+        async fn put(&self, key: &str, value: String) -> Result<(), DataStoreError> {
+            let _flight = self.start().await;
+            self.counts.borrow_mut().puts += 1;
+            self.check_failure(Op::Put, key)?;
+            self.ttls.borrow_mut().remove(key);
+            self.data.borrow_mut().insert(key.to_string(), value);
+            Ok(())
+        }
+
+        async fn put_expiring(
+            &self,
+            key: &str,
+            value: String,
+            ttl_secs: u64,
+        ) -> Result<(), DataStoreError> {
+            self.put(key, value).await?;
+            self.ttls.borrow_mut().insert(key.to_string(), ttl_secs);
+            Ok(())
+        }
+
+        async fn delete(&self, key: &str) -> Result<(), DataStoreError> {
+            let _flight = self.start().await;
+            self.counts.borrow_mut().deletes += 1;
+            self.check_failure(Op::Delete, key)?;
+            self.ttls.borrow_mut().remove(key);
+            self.data.borrow_mut().remove(key);
+            Ok(())
+        }
+
+        async fn list(
+            &self,
+            prefix: &str,
+            cursor: Option<String>,
+        ) -> Result<ListPage, DataStoreError> {
+            let _flight = self.start().await;
+            self.counts.borrow_mut().lists += 1;
+            self.wait();
+            self.check_failure(Op::List, prefix)?;
+            // The cursor is the last key of the previous page
+            let data = self.data.borrow();
+            let mut keys: Vec<String> = data
+                .keys()
+                .filter(|key| key.starts_with(prefix))
+                .filter(|key| cursor.as_ref().is_none_or(|after| *key > after))
+                .take(self.page_size + 1)
+                .cloned()
+                .collect();
+            let cursor = match keys.len() > self.page_size {
+                true => {
+                    keys.truncate(self.page_size);
+                    keys.last().cloned()
+                }
+                false => None,
+            };
+            Ok(ListPage { keys, cursor })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+
+    use super::{memory::MemoryStorage, *};
+
+    #[test]
+    fn test_page_cursor_round_trips() {
+        let cursor: PageCursor = "3:idx:document:b".parse().unwrap();
+        assert_eq!(
+            (cursor.offset, cursor.page.as_deref()),
+            (3, Some("idx:document:b"))
+        );
+        for cursor in ["0:", "12:upgrade:kw:ocean:3"] {
+            assert_eq!(cursor.parse::<PageCursor>().unwrap().to_string(), cursor);
+        }
+        assert_eq!("0:".parse::<PageCursor>().unwrap(), PageCursor::default());
+        for invalid in ["bogus", "x:page", "x:"] {
+            assert!(invalid.parse::<PageCursor>().is_err());
+        }
+    }
+
+    #[test]
+    fn test_list_all_follows_cursors() {
+        let store = MemoryStorage::with_page_size(2);
+        block_on(async {
+            for i in 0..5 {
+                store
+                    .put(&format!("idx:kw:a:{}", i), "{}".into())
+                    .await
+                    .unwrap();
+            }
+            store.put("idx:kw:b:0", "{}".into()).await.unwrap();
+
+            let keys = list_all(&store, "idx:kw:a:").await.unwrap();
+            assert_eq!(keys.len(), 5);
+            assert!(keys.iter().all(|key| key.starts_with("idx:kw:a:")));
+            assert_eq!(store.counts().lists, 3);
+        });
+    }
+
+    #[test]
+    fn test_list_up_to_stops_early() {
+        let store = MemoryStorage::with_page_size(2);
+        block_on(async {
+            for i in 0..7 {
+                store
+                    .put(&format!("idx:doc:{}", i), "{}".into())
+                    .await
+                    .unwrap();
+            }
+            let keys = list_up_to(&store, "idx:doc:", 3).await.unwrap();
+            assert_eq!(keys, vec!["idx:doc:0", "idx:doc:1", "idx:doc:2"]);
+            assert_eq!(store.counts().lists, 2);
+
+            assert_eq!(list_up_to(&store, "idx:doc:", 50).await.unwrap().len(), 7);
+            assert!(list_up_to(&store, "idx:doc:", 0).await.unwrap().is_empty());
+        });
+    }
+
+    #[test]
+    fn test_load_listed_skips_deleted_entries() {
+        let store = MemoryStorage::with_page_size(2);
+        block_on(async {
+            for name in ["a", "b", "c"] {
+                store
+                    .put(&format!("idx:t:{}", name), name.into())
+                    .await
+                    .unwrap();
+            }
+            let store = &store;
+            let load = |name: String| async move {
+                // "b" is deleted between the listing and its read
+                if name == "b" {
+                    store.delete("idx:t:b").await?;
+                }
+                let key = format!("idx:t:{}", name);
+                store.get(&key).await?.ok_or(DataStoreError::NotFound(key))
+            };
+            let loaded = load_listed(store, "idx:t:", load).await.unwrap();
+            assert_eq!(loaded, vec!["a", "c"]);
+
+            store.fail_gets("idx:t:c", 1);
+            let failed = load_listed(store, "idx:t:", load).await;
+            assert!(matches!(failed, Err(DataStoreError::Worker(_))));
+        });
+    }
+
+    #[test]
+    fn test_get_put_delete() {
+        let store = MemoryStorage::default();
+        block_on(async {
+            assert_eq!(store.get("missing").await.unwrap(), None);
+            store.put("key", "value".into()).await.unwrap();
+            assert_eq!(store.get("key").await.unwrap(), Some("value".into()));
+            store.delete("key").await.unwrap();
+            assert!(store.keys().is_empty());
+        });
+    }
+}
@@ -0,0 +1,239 @@
+//! Index templates: settings and a stop-list applied to every new index whose name
+//! matches a pattern, for indexes created programmatically, such as one per tenant.
+//!
+//! A pattern is an index name in which `*` matches any run of characters, so
+//! `tenant-*` matches `tenant-42` and `tenant-` alike. When several templates match,
+//! the one whose pattern has the longest literal text before its first `*` wins,
+//! then the one with the most literal text overall, then the first by name.
+
+use serde::{Deserialize, Serialize};
+
+use crate::data::{
+    index::{IndexDocument, IndexSettings},
+    stoplist::StopList,
+    storage::{load_listed, Storage},
+    DataStoreError, KvEntry, KvPersistent,
+};
+
+pub static PREFIX_TEMPLATE: &str = "_internal:templates:";
+
+pub fn template_kv_key(name: &str) -> String {
+    format!("{}{}", PREFIX_TEMPLATE, name)
+}
+
+/// Whether `name` matches `pattern`, where `*` matches any run of characters
+pub fn glob_matches(pattern: &str, name: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let Some(mut rest) = name.strip_prefix(parts.next().unwrap_or_default()) else {
+        return false;
+    };
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        // No `*`, so the whole name was the prefix
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(at) => rest = &rest[at + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+/// The literal text of `pattern` before its first `*`, which ranks overlapping templates
+pub fn literal_prefix(pattern: &str) -> &str {
+    pattern.split('*').next().unwrap_or_default()
+}
+
+/// How specific a pattern is, the most specific of the matching templates winning
+fn specificity(pattern: &str) -> (usize, usize) {
+    let literal = pattern.chars().filter(|c| *c != '*').count();
+    (literal_prefix(pattern).len(), literal)
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct IndexTemplate {
+    /// Taken from the path of `PUT /_templates/:name`
+    #[serde(default)]
+    pub name: String,
+    pub pattern: String,
+    #[serde(default, skip_serializing_if = "IndexSettings::is_empty")]
+    pub settings: IndexSettings,
+    /// Stop-listed keywords, normalized like those of `PUT /:index/stoplist`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub stoplist: Vec<String>,
+}
+
+impl KvEntry for IndexTemplate {
+    type Key = String;
+
+    fn get_kv_key(&self) -> String {
+        template_kv_key(&self.name)
+    }
+}
+
+impl KvPersistent for IndexTemplate {}
+
+impl IndexTemplate {
+    /// The most templates that may be stored. Creating an index reads all of them.
+    pub const MAX_TEMPLATES: usize = 64;
+
+    /// Parse the JSON body of `PUT /_templates/:name`, validating its pattern,
+    /// settings and stop-list the way an index's own are validated
+    pub fn parse(name: &str, body: &str) -> Result<IndexTemplate, String> {
+        let mut template: IndexTemplate =
+            serde_json::from_str(body).map_err(|err| format!("Invalid index template: {}", err))?;
+        template.name = name.to_string();
+
+        let literal = template.pattern.replace('*', "");
+        if template.pattern.is_empty()
+            || !(literal.is_empty() || IndexDocument::is_valid_name(&literal))
+        {
+            return Err(
+                "pattern must be an index name, in which * matches any characters".to_string(),
+            );
+        }
+        template.settings.validate()?;
+
+        let stoplist = StopList::new(name, &template.stoplist);
+        if stoplist.len() > StopList::MAX_ENTRIES {
+            return Err(format!(
+                "Too many stop-listed keywords. Current limit: {}",
+                StopList::MAX_ENTRIES
+            ));
+        }
+        template.stoplist = stoplist.keywords();
+        Ok(template)
+    }
+
+    pub fn matches(&self, index: &str) -> bool {
+        glob_matches(&self.pattern, index)
+    }
+
+    /// Read a single template by name
+    pub async fn load<S: Storage>(store: &S, name: &str) -> Result<IndexTemplate, DataStoreError> {
+        let mut template = IndexTemplate::read(&template_kv_key(name), store).await?;
+        template.name = name.to_string();
+        Ok(template)
+    }
+}
+
+/// Every stored template, ordered by name
+pub async fn list_templates<S: Storage>(store: &S) -> Result<Vec<IndexTemplate>, DataStoreError> {
+    load_listed(store, PREFIX_TEMPLATE, |name| async move {
+        IndexTemplate::load(store, &name).await
+    })
+    .await
+}
+
+/// The template a new index named `index` is created from, if any matches it
+pub fn best_match<'t>(templates: &'t [IndexTemplate], index: &str) -> Option<&'t IndexTemplate> {
+    let mut best: Option<&IndexTemplate> = None;
+    for template in templates.iter().filter(|template| template.matches(index)) {
+        let more_specific =
+            best.is_none_or(|best| specificity(&template.pattern) > specificity(&best.pattern));
+        if more_specific {
+            best = Some(template);
+        }
+    }
+    best
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+
+    use super::*;
+    use crate::data::storage::memory::MemoryStorage;
+
+    fn template(name: &str, pattern: &str) -> IndexTemplate {
+        IndexTemplate::parse(name, &format!(r#"{{"pattern":"{}"}}"#, pattern)).unwrap()
+    }
+
+    #[test]
+    fn test_glob_matches() {
+        for (pattern, name) in [
+            ("tenant-*", "tenant-42"),
+            ("tenant-*", "tenant-"),
+            ("*", "anything"),
+            ("*-logs", "acme-logs"),
+            ("t*-*-logs", "tenant-7-logs"),
+            ("a*a", "aba"),
+            ("exact", "exact"),
+        ] {
+            assert!(glob_matches(pattern, name), "{} {}", pattern, name);
+        }
+        for (pattern, name) in [
+            ("tenant-*", "tenants"),
+            ("*-logs", "acme-logs-old"),
+            ("a*a", "a"),
+            ("t*-*-logs", "tenant-logs"),
+            ("exact", "exactly"),
+        ] {
+            assert!(!glob_matches(pattern, name), "{} {}", pattern, name);
+        }
+    }
+
+    #[test]
+    fn test_longest_literal_prefix_wins() {
+        let templates = vec![
+            template("any", "*"),
+            template("logs", "*-logs"),
+            template("tenant", "tenant-*"),
+            template("tenant-a", "tenant-a*"),
+            template("tenant-b", "tenant-*-logs"),
+            template("tenant-c", "tenant-*-logs"),
+        ];
+        let best = |index| best_match(&templates, index).map(|t| t.name.as_str());
+        assert_eq!(best("tenant-acme"), Some("tenant-a"));
+        assert_eq!(best("tenant-42"), Some("tenant"));
+        // Equally long prefixes fall back to the most literal text, then the name
+        assert_eq!(best("tenant-42-logs"), Some("tenant-b"));
+        assert_eq!(best("acme-logs"), Some("logs"));
+        assert_eq!(best("other"), Some("any"));
+        assert_eq!(best_match(&templates[2..], "other"), None);
+    }
+
+    #[test]
+    fn test_parse_template() {
+        let parsed = IndexTemplate::parse(
+            "tenants",
+            r#"{"pattern":"tenant-*","settings":{"limit":20},"stoplist":["Acme  Corp","acme corp"]}"#,
+        )
+        .unwrap();
+        assert_eq!(parsed.name, "tenants");
+        assert_eq!(parsed.settings.limit, Some(20));
+        assert_eq!(parsed.stoplist, vec!["acme corp"]);
+
+        for invalid in [
+            r#"{"pattern":""}"#,
+            r#"{"pattern":"Tenant-*"}"#,
+            r#"{"pattern":"tenant:*"}"#,
+            r#"{"pattern":"tenant-*","settings":{"limit":0}}"#,
+            r#"{"pattern":"tenant-*","synonyms":{}}"#,
+            r#"{"settings":{}}"#,
+        ] {
+            assert!(IndexTemplate::parse("t", invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_list_templates() {
+        let store = MemoryStorage::default();
+        for (name, pattern) in [("logs", "*-logs"), ("any", "*"), ("tenant", "tenant-*")] {
+            block_on(template(name, pattern).write(&store)).unwrap();
+        }
+        let names: Vec<String> = block_on(list_templates(&store))
+            .unwrap()
+            .into_iter()
+            .map(|template| template.name)
+            .collect();
+        assert_eq!(names, vec!["any", "logs", "tenant"]);
+        assert_eq!(
+            block_on(IndexTemplate::load(&store, "tenant")).unwrap(),
+            template("tenant", "tenant-*")
+        );
+    }
+}
@@ -0,0 +1,322 @@
+//! Finding an index's largest documents and the keywords with the most postings, for
+//! capacity planning. Like fsck, the walk reads a bounded batch of keys per call and
+//! returns a cursor. Each call ranks only the keys it read, so a caller after the
+//! whole index keeps the best entries of every page, summing the entries of a keyword
+//! that turns up on two pages.
+
+use std::collections::HashMap;
+
+use serde::Serialize;
+
+use crate::data::{
+    bulk::BulkReader,
+    document::shard_from_document_id,
+    keyword_shard::{keyword_shard_prefix, parse_keyword_shard_key},
+    storage::PageCursor,
+    storage::{load_found, Storage},
+    DataStoreError, PREFIX_DOCUMENT, PREFIX_KEYWORD,
+};
+
+/// The most keys one call reads, each one KV read
+pub const TOP_BATCH_SIZE: usize = 200;
+
+pub const DEFAULT_TOP_LIMIT: usize = 20;
+pub const MAX_TOP_LIMIT: usize = 100;
+
+/// How many buckets keys are hashed into when sampling
+const SAMPLE_BUCKETS: u32 = 10_000;
+
+/// What the entries are ranked by
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TopBy {
+    /// The byte length of each stored document
+    DocSize,
+    /// The number of postings across each keyword's shards
+    KeywordPostings,
+}
+
+impl TopBy {
+    pub const NAMES: [&'static str; 2] = ["doc_size", "keyword_postings"];
+
+    pub fn from_name(name: &str) -> Option<TopBy> {
+        match name {
+            "doc_size" => Some(TopBy::DocSize),
+            "keyword_postings" => Some(TopBy::KeywordPostings),
+            _ => None,
+        }
+    }
+}
+
+/// One document or keyword, with the KV key to inspect it under
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct TopEntry {
+    /// The document's key, or the prefix of the keyword's shard keys
+    pub key: String,
+    /// The document ID or keyword
+    pub name: String,
+    /// The document's size in bytes, or the keyword's postings
+    pub value: u64,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct TopReport {
+    pub by: TopBy,
+    /// How many listed keys this call moved past, counting those left out of a sample
+    pub scanned: u32,
+    /// The best `limit` of the keys read, largest first
+    pub entries: Vec<TopEntry>,
+    /// Pass back to continue the walk, `None` once every key has been read
+    pub cursor: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct TopOptions {
+    pub by: TopBy,
+    pub limit: usize,
+    /// The share of documents read with `doc_size`, in (0, 1]. Keys are picked by
+    /// their hash, so a sample is the same on every walk.
+    pub sample: f64,
+}
+
+/// Whether a sample of `share` of the keys includes `key`
+fn sampled(key: &str, share: f64) -> bool {
+    let kept = (share * SAMPLE_BUCKETS as f64).ceil() as u32;
+    shard_from_document_id(key.to_string(), SAMPLE_BUCKETS) < kept
+}
+
+/// Read the next batch of `index`'s documents or keyword shards after `cursor`, and
+/// rank them
+pub async fn top_batch<S: Storage>(
+    index: &str,
+    store: &S,
+    bulk_reader: &BulkReader<'_, S>,
+    cursor: Option<PageCursor>,
+    options: &TopOptions,
+) -> Result<TopReport, DataStoreError> {
+    let PageCursor { offset, page } = cursor.unwrap_or_default();
+    let prefix = match options.by {
+        TopBy::DocSize => format!("{}:{}", index, PREFIX_DOCUMENT),
+        TopBy::KeywordPostings => format!("{}:{}", index, PREFIX_KEYWORD),
+    };
+    let listed = store.list(&prefix, page.clone()).await?;
+    let rest = listed.keys.get(offset..).unwrap_or_default();
+
+    let (scanned, mut entries) = match options.by {
+        TopBy::DocSize => {
+            let keys: Vec<&String> = rest
+                .iter()
+                .filter(|key| options.sample >= 1.0 || sampled(key, options.sample))
+                .take(TOP_BATCH_SIZE)
+                .collect();
+            // Stop after the last key read, leaving the rest of the page to the next call
+            let skipped = match keys.last() {
+                Some(last) if keys.len() == TOP_BATCH_SIZE => rest
+                    .iter()
+                    .position(|key| key == *last)
+                    .map_or(0, |i| i + 1),
+                _ => rest.len(),
+            };
+            let entries = document_sizes(store, &prefix, &keys).await?;
+            (skipped, entries)
+        }
+        TopBy::KeywordPostings => {
+            let taken = keyword_batch_len(index, rest);
+            let keys = rest[..taken].iter().map(String::as_str).collect();
+            (taken, keyword_postings(index, bulk_reader, keys).await)
+        }
+    };
+
+    entries.sort_by(|a, b| b.value.cmp(&a.value).then_with(|| a.key.cmp(&b.key)));
+    entries.truncate(options.limit);
+    let checked = offset + scanned;
+    let cursor = match checked < listed.keys.len() {
+        true => Some(PageCursor {
+            offset: checked,
+            page,
+        }),
+        false => listed.cursor.map(|page| PageCursor {
+            offset: 0,
+            page: Some(page),
+        }),
+    };
+    Ok(TopReport {
+        by: options.by,
+        scanned: scanned as u32,
+        entries,
+        cursor: cursor.map(|cursor| cursor.to_string()),
+    })
+}
+
+/// How many of `keys` one call reads: a batch, extended to the end of the last
+/// keyword's shards so its postings are counted together
+fn keyword_batch_len(index: &str, keys: &[String]) -> usize {
+    let mut taken = keys.len().min(TOP_BATCH_SIZE);
+    let keyword = |key: &String| parse_keyword_shard_key(index, key).map(|(keyword, _)| keyword);
+    if let Some(last) = taken.checked_sub(1).map(|i| keyword(&keys[i])) {
+        while taken < keys.len() && keyword(&keys[taken]) == last {
+            taken += 1;
+        }
+    }
+    taken
+}
+
+async fn document_sizes<S: Storage>(
+    store: &S,
+    prefix: &str,
+    keys: &[&String],
+) -> Result<Vec<TopEntry>, DataStoreError> {
+    let keys = keys.iter().map(|key| key.as_str());
+    load_found(keys, |key| async move {
+        let raw = store.get(&key).await?;
+        let raw = raw.ok_or_else(|| DataStoreError::NotFound(key.clone()))?;
+        Ok(TopEntry {
+            name: key.strip_prefix(prefix).unwrap_or(&key).to_string(),
+            value: raw.len() as u64,
+            key,
+        })
+    })
+    .await
+}
+
+async fn keyword_postings<S: Storage>(
+    index: &str,
+    bulk_reader: &BulkReader<'_, S>,
+    keys: Vec<&str>,
+) -> Vec<TopEntry> {
+    let mut postings: HashMap<String, u64> = HashMap::new();
+    for shard in bulk_reader.get_keyword_kv_keys(keys).await {
+        *postings.entry(shard.keyword).or_default() += shard.docs.len() as u64;
+    }
+    postings
+        .into_iter()
+        .map(|(keyword, value)| TopEntry {
+            key: keyword_shard_prefix(index, &keyword),
+            name: keyword,
+            value,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+
+    use super::*;
+    use crate::data::{
+        document::Document, keyword_shard::testing::seed_postings, storage::memory::MemoryStorage,
+        KvPersistent, DEFAULT_N_SHARDS,
+    };
+
+    fn options(by: TopBy, limit: usize) -> TopOptions {
+        TopOptions {
+            by,
+            limit,
+            sample: 1.0,
+        }
+    }
+
+    /// Follow the cursor to the end, keeping the best entries of every page
+    fn walk(store: &MemoryStorage, index: &str, options: &TopOptions) -> (Vec<TopEntry>, u32) {
+        let bulk_reader = BulkReader::new(DEFAULT_N_SHARDS, store, None);
+        let (mut best, mut scanned, mut cursor) = (HashMap::<String, TopEntry>::new(), 0, None);
+        loop {
+            let report = block_on(top_batch(index, store, &bulk_reader, cursor, options)).unwrap();
+            scanned += report.scanned;
+            for entry in report.entries {
+                best.entry(entry.key.clone())
+                    .and_modify(|seen| seen.value += entry.value)
+                    .or_insert(entry);
+            }
+            match report.cursor {
+                Some(next) => cursor = Some(next.parse().unwrap()),
+                None => break,
+            }
+        }
+        let mut best: Vec<TopEntry> = best.into_values().collect();
+        best.sort_by(|a, b| b.value.cmp(&a.value).then_with(|| a.key.cmp(&b.key)));
+        best.truncate(options.limit);
+        (best, scanned)
+    }
+
+    fn names(entries: &[TopEntry]) -> Vec<&str> {
+        entries.iter().map(|entry| entry.name.as_str()).collect()
+    }
+
+    fn seed_documents(store: &MemoryStorage, index: &str, sizes: &[(&str, usize)]) {
+        for (id, size) in sizes {
+            let mut document = Document::new_with_id(index, id);
+            document.document_body = Some("x".repeat(*size));
+            block_on(document.write(store)).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_largest_documents() {
+        let store = MemoryStorage::default();
+        seed_documents(
+            &store,
+            "top-docs",
+            &[("a", 10), ("b", 500), ("c", 40), ("d", 2000), ("e", 300)],
+        );
+        let (top, scanned) = walk(&store, "top-docs", &options(TopBy::DocSize, 3));
+        assert_eq!(names(&top), vec!["d", "b", "e"]);
+        assert_eq!(scanned, 5);
+        assert_eq!(top[0].key, "top-docs:document:d");
+        let stored = block_on(store.get("top-docs:document:d")).unwrap().unwrap();
+        assert_eq!(top[0].value, stored.len() as u64);
+    }
+
+    #[test]
+    fn test_heaviest_keywords() {
+        let store = MemoryStorage::default();
+        let postings = |n: usize| -> Vec<(String, f64)> {
+            (0..n).map(|i| (format!("doc{}", i), 0.5)).collect()
+        };
+        for (keyword, n) in [("ocean", 12), ("tide", 3), ("harbor", 7), ("boat", 1)] {
+            let docs = postings(n);
+            let docs: Vec<(&str, f64)> = docs.iter().map(|(id, s)| (id.as_str(), *s)).collect();
+            seed_postings(&store, "top-kw", DEFAULT_N_SHARDS, keyword, &docs);
+        }
+        let (top, _) = walk(&store, "top-kw", &options(TopBy::KeywordPostings, 3));
+        assert_eq!(names(&top), vec!["ocean", "harbor", "tide"]);
+        assert_eq!(
+            top.iter().map(|entry| entry.value).collect::<Vec<_>>(),
+            vec![12, 7, 3]
+        );
+        assert_eq!(top[0].key, keyword_shard_prefix("top-kw", "ocean"));
+    }
+
+    #[test]
+    fn test_keyword_batch_ends_with_a_keyword() {
+        let keys: Vec<String> = (0..TOP_BATCH_SIZE + 4)
+            .map(|i| match i < TOP_BATCH_SIZE - 1 {
+                true => format!("idx:kw:k{}:0", i),
+                false => format!("idx:kw:last:{}", i),
+            })
+            .collect();
+        assert_eq!(keyword_batch_len("idx", &keys), keys.len());
+        assert_eq!(keyword_batch_len("idx", &keys[..3]), 3);
+        assert_eq!(keyword_batch_len("idx", &[]), 0);
+    }
+
+    #[test]
+    fn test_sample_reads_a_share_of_documents() {
+        let store = MemoryStorage::default();
+        let sizes: Vec<(String, usize)> = (0..40).map(|i| (format!("doc{:02}", i), i)).collect();
+        let sizes: Vec<(&str, usize)> = sizes.iter().map(|(id, n)| (id.as_str(), *n)).collect();
+        seed_documents(&store, "top-sample", &sizes);
+
+        let sample = TopOptions {
+            sample: 0.25,
+            ..options(TopBy::DocSize, 100)
+        };
+        let gets = store.counts().gets;
+        let (top, scanned) = walk(&store, "top-sample", &sample);
+        assert_eq!(scanned, 40);
+        assert!(!top.is_empty() && top.len() < 40);
+        assert_eq!(store.counts().gets - gets, top.len());
+        // The same documents are picked on every walk
+        assert_eq!(walk(&store, "top-sample", &sample).0, top);
+    }
+}
@@ -0,0 +1,121 @@
+//! Recording the KV keys a search read, for the `debug=true` diagnostics of
+//! `handle_search`.
+
+use std::cell::RefCell;
+
+use serde::Serialize;
+
+use crate::data::keyword_shard::KeywordShardData;
+
+/// One keyword shard read by a search
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ShardTrace {
+    pub key: String,
+    /// Postings in the shard, or 0 when the listed shard could not be read
+    pub postings: usize,
+    /// The shard's last modified timestamp, when it was read
+    pub ts: Option<u64>,
+}
+
+/// How a keyword's shard keys were found
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ShardLookup {
+    /// By listing the keyword's prefix
+    Listed,
+    /// By naming every shard of an index whose shard count is known, without a listing
+    Enumerated,
+}
+
+/// The shards found for one keyword
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct KeywordTrace {
+    pub keyword: String,
+    /// The prefix the keyword's shards are stored under
+    pub prefix: String,
+    pub lookup: ShardLookup,
+    pub shards: Vec<ShardTrace>,
+    /// The newest timestamp of the shards read, which moves on with every write to
+    /// any of them
+    pub newest_ts: Option<u64>,
+}
+
+/// Every KV key a search consulted, and what reading them cost
+#[derive(Serialize, Debug, Default, Clone, PartialEq)]
+pub struct SearchDiagnostics {
+    pub keywords: Vec<KeywordTrace>,
+    /// Requests made to the durable reader
+    pub durable_requests: usize,
+    /// Bytes of stored values read, from KV and the durable reader
+    pub bytes_read: usize,
+    /// Subrequests the search made: KV operations and durable reader requests
+    pub kv_ops_used: usize,
+}
+
+/// Collects [`SearchDiagnostics`] while readers run. Readers only take `&self`, so
+/// they share a trace by reference and record into it as they go.
+#[derive(Debug, Default)]
+pub struct ReadTrace {
+    diagnostics: RefCell<SearchDiagnostics>,
+}
+
+impl ReadTrace {
+    /// Record the shard keys listed under `prefix` for `keyword`
+    pub fn listed(&self, keyword: &str, prefix: &str, shard_keys: &[String]) {
+        self.found(keyword, prefix, ShardLookup::Listed, shard_keys);
+    }
+
+    /// Record the shard keys of `keyword` named without listing `prefix`, some of which
+    /// may not exist
+    pub fn enumerated(&self, keyword: &str, prefix: &str, shard_keys: &[String]) {
+        self.found(keyword, prefix, ShardLookup::Enumerated, shard_keys);
+    }
+
+    fn found(&self, keyword: &str, prefix: &str, lookup: ShardLookup, shard_keys: &[String]) {
+        self.diagnostics.borrow_mut().keywords.push(KeywordTrace {
+            keyword: keyword.to_string(),
+            prefix: prefix.to_string(),
+            lookup,
+            shards: shard_keys
+                .iter()
+                .map(|key| ShardTrace {
+                    key: key.clone(),
+                    postings: 0,
+                    ts: None,
+                })
+                .collect(),
+            newest_ts: None,
+        });
+    }
+
+    /// Record a keyword shard read from `key`, `bytes` long as stored
+    pub fn shard_read(&self, key: &str, shard: &KeywordShardData, bytes: usize) {
+        let mut diagnostics = self.diagnostics.borrow_mut();
+        diagnostics.bytes_read += bytes;
+        let listed = diagnostics.keywords.iter_mut().find_map(|keyword| {
+            let traced = keyword.shards.iter_mut().find(|traced| traced.key == key)?;
+            Some((traced, &mut keyword.newest_ts))
+        });
+        if let Some((traced, newest_ts)) = listed {
+            traced.postings = shard.docs.len();
+            traced.ts = Some(shard.ts);
+            *newest_ts = (*newest_ts).max(Some(shard.ts));
+        }
+    }
+
+    /// Record a request to the durable reader answered with `bytes`
+    pub fn durable_request(&self, bytes: usize) {
+        let mut diagnostics = self.diagnostics.borrow_mut();
+        diagnostics.durable_requests += 1;
+        diagnostics.bytes_read += bytes;
+    }
+
+    /// Record a value other than a keyword shard read from KV, like a document
+    pub fn value_read(&self, bytes: usize) {
+        self.diagnostics.borrow_mut().bytes_read += bytes;
+    }
+
+    pub fn finish(self) -> SearchDiagnostics {
+        self.diagnostics.into_inner()
+    }
+}
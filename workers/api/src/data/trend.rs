@@ -0,0 +1,323 @@
+//! Daily posting counts of each keyword, for seeing whether a topic is growing in an
+//! index. Each day a keyword gained or lost documents has a counter under
+//! `{index}:kwstats:{keyword}:{yyyy-mm-dd}`, holding the day's net change as a JSON
+//! integer.
+//!
+//! A document write counts +1 for each keyword the document didn't have before and
+//! -1 for each it lost, and a delete -1 for each of its keywords, only once their
+//! shards were written. The writes report these to the index's journal, which adds
+//! them up in its durable storage and writes the pending counters to KV on its
+//! alarm, as it does usage, so concurrent writers never race to rewrite a counter.
+//! Counters expire `KEYWORD_TREND_RETENTION_DAYS` after they're written.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    data::{
+        document::Document,
+        storage::Storage,
+        usage::{utc_date, MS_PER_DAY},
+        DataStoreError,
+    },
+    util::concurrency::join_bounded,
+};
+
+pub static SUFFIX_KEYWORD_STATS: &str = "kwstats:";
+
+/// How many days `GET /:index/keyword/:keyword/trend` returns without `days`
+pub const DEFAULT_TREND_DAYS: u32 = 30;
+
+/// The most days a trend returns, each one KV read
+pub const MAX_TREND_DAYS: u32 = 90;
+
+/// How many days a counter is kept without `KEYWORD_TREND_RETENTION_DAYS`
+pub const DEFAULT_TREND_RETENTION_DAYS: usize = 90;
+pub const MAX_TREND_RETENTION_DAYS: usize = 365;
+
+/// The most counters one flush writes, each a read and a write, keeping the
+/// journal's alarm well within its subrequest limit. The rest wait for the next.
+pub const MAX_TREND_FLUSH_COUNTERS: usize = 200;
+
+/// The change a write made to each keyword's posting count
+pub type KeywordDeltas = BTreeMap<String, i64>;
+
+pub fn keyword_stats_kv_key(index: &str, keyword: &str, date: &str) -> String {
+    format!("{}:{}{}:{}", index, SUFFIX_KEYWORD_STATS, keyword, date)
+}
+
+/// What deleting `document` takes from each of its keywords
+pub fn deletion_deltas(document: &Document) -> KeywordDeltas {
+    document
+        .keywords
+        .iter()
+        .flatten()
+        .map(|(keyword, _)| (keyword.clone(), -1))
+        .collect()
+}
+
+/// Add `deltas` to `into`, dropping keywords whose changes cancel out
+pub fn add_deltas(into: &mut KeywordDeltas, deltas: &KeywordDeltas) {
+    for (keyword, delta) in deltas {
+        let total = into.entry(keyword.clone()).or_default();
+        *total += delta;
+        if *total == 0 {
+            into.remove(keyword);
+        }
+    }
+}
+
+/// Keyword changes reported to an index's journal and not yet added to KV, by date
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct PendingTrends {
+    pub index: String,
+    pub days: BTreeMap<String, KeywordDeltas>,
+}
+
+impl PendingTrends {
+    pub fn new(index: &str) -> PendingTrends {
+        PendingTrends {
+            index: index.to_string(),
+            days: BTreeMap::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.days.is_empty()
+    }
+
+    /// Add `deltas` to the day `now` falls on
+    pub fn record(&mut self, now: u64, deltas: &KeywordDeltas) {
+        self.add_day(utc_date(now), deltas);
+    }
+
+    /// Add the days of `other`, such as those a failed flush kept
+    pub fn merge(&mut self, other: PendingTrends) {
+        for (date, deltas) in other.days {
+            self.add_day(date, &deltas);
+        }
+    }
+
+    fn add_day(&mut self, date: String, deltas: &KeywordDeltas) {
+        let day = self.days.entry(date.clone()).or_default();
+        add_deltas(day, deltas);
+        if day.is_empty() {
+            self.days.remove(&date);
+        }
+    }
+
+    /// Add up to `max_counters` pending changes to the counters stored in KV, each
+    /// written to expire after `ttl_secs`. A change stays pending until it's
+    /// written, so a failed flush can be repeated without counting twice, and what
+    /// didn't fit waits for the next flush.
+    pub async fn flush<S: Storage>(
+        &mut self,
+        store: &S,
+        ttl_secs: u64,
+        max_counters: usize,
+    ) -> Result<(), DataStoreError> {
+        for _ in 0..max_counters {
+            let Some(mut entry) = self.days.first_entry() else {
+                break;
+            };
+            let date = entry.key().clone();
+            let Some((keyword, delta)) = entry.get_mut().pop_first() else {
+                entry.remove();
+                continue;
+            };
+            if entry.get().is_empty() {
+                entry.remove();
+            }
+            let key = keyword_stats_kv_key(&self.index, &keyword, &date);
+            let written = async {
+                let net = read_counter(store, &key).await? + delta;
+                store.put_expiring(&key, net.to_string(), ttl_secs).await
+            };
+            if let Err(err) = written.await {
+                self.days.entry(date).or_default().insert(keyword, delta);
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The net change stored under `key`, zero when the keyword didn't change that day
+async fn read_counter<S: Storage>(store: &S, key: &str) -> Result<i64, DataStoreError> {
+    match store.get(key).await? {
+        Some(value) => value
+            .trim()
+            .parse()
+            .map_err(|_| DataStoreError::InvalidFormat(format!("'{}' is not a counter", key))),
+        None => Ok(0),
+    }
+}
+
+/// One day of a keyword's trend
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct TrendDay {
+    pub date: String,
+    /// Documents that gained the keyword that day, less those that lost it
+    pub net: i64,
+    /// The net changes of the trend's days up to and including this one
+    pub cumulative: i64,
+}
+
+/// The keyword's net posting changes on each of the `days` days up to the one `now`
+/// falls on, oldest first
+pub async fn read_trend<S: Storage>(
+    store: &S,
+    index: &str,
+    keyword: &str,
+    now: u64,
+    days: u32,
+) -> Result<Vec<TrendDay>, DataStoreError> {
+    let dates: Vec<String> = (0..days as u64)
+        .rev()
+        .map(|ago| utc_date(now.saturating_sub(ago * MS_PER_DAY)))
+        .collect();
+    let keys: Vec<String> = dates
+        .iter()
+        .map(|date| keyword_stats_kv_key(index, keyword, date))
+        .collect();
+    let nets = join_bounded(keys.iter().map(|key| read_counter(store, key))).await;
+
+    let mut cumulative = 0;
+    let mut trend = Vec::with_capacity(dates.len());
+    for (date, net) in dates.into_iter().zip(nets) {
+        let net = net?;
+        cumulative += net;
+        trend.push(TrendDay {
+            date,
+            net,
+            cumulative,
+        });
+    }
+    Ok(trend)
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+
+    use super::*;
+    use crate::{
+        data::{
+            document::{testing::index_text, IndexingOptions, LangDetection},
+            storage::memory::MemoryStorage,
+        },
+        util::time::{Clock, ManualClock},
+    };
+
+    const LEAP_DAY: u64 = 1_709_164_800_000;
+
+    fn deltas(changes: &[(&str, i64)]) -> KeywordDeltas {
+        changes
+            .iter()
+            .map(|(keyword, delta)| (keyword.to_string(), *delta))
+            .collect()
+    }
+
+    #[test]
+    fn test_trend_over_several_days() {
+        let store = MemoryStorage::default();
+        let clock = ManualClock::at(LEAP_DAY + 1000);
+        let mut pending = PendingTrends::new("trends");
+
+        // Two documents gain "ocean", and one of them loses "tide", on the first day
+        pending.record(clock.now_millis(), &deltas(&[("ocean", 1), ("tide", 1)]));
+        pending.record(clock.now_millis(), &deltas(&[("ocean", 1), ("tide", -1)]));
+        assert_eq!(pending.days["2024-02-29"], deltas(&[("ocean", 2)]));
+        block_on(pending.flush(&store, 86_400, MAX_TREND_FLUSH_COUNTERS)).unwrap();
+        assert!(pending.is_empty());
+
+        // A later report the same day adds to the stored counter
+        pending.record(clock.now_millis(), &deltas(&[("ocean", 1)]));
+        clock.advance(2 * MS_PER_DAY);
+        pending.record(clock.now_millis(), &deltas(&[("ocean", -2)]));
+        clock.advance(MS_PER_DAY);
+        pending.record(clock.now_millis(), &deltas(&[("ocean", 1)]));
+        block_on(pending.flush(&store, 86_400, MAX_TREND_FLUSH_COUNTERS)).unwrap();
+
+        let trend = block_on(read_trend(&store, "trends", "ocean", clock.now_millis(), 5)).unwrap();
+        let series: Vec<(&str, i64, i64)> = trend
+            .iter()
+            .map(|day| (day.date.as_str(), day.net, day.cumulative))
+            .collect();
+        assert_eq!(
+            series,
+            vec![
+                ("2024-02-28", 0, 0),
+                ("2024-02-29", 3, 3),
+                ("2024-03-01", 0, 3),
+                ("2024-03-02", -2, 1),
+                ("2024-03-03", 1, 2),
+            ]
+        );
+        let key = keyword_stats_kv_key("trends", "ocean", "2024-03-02");
+        assert_eq!(block_on(store.get(&key)).unwrap().as_deref(), Some("-2"));
+        assert_eq!(store.ttl(&key), Some(86_400));
+        assert!(!store
+            .keys()
+            .contains(&keyword_stats_kv_key("trends", "tide", "2024-02-29")));
+    }
+
+    #[test]
+    fn test_flush_is_bounded_and_retried() {
+        let store = MemoryStorage::default();
+        let mut pending = PendingTrends::new("trend-flush");
+        pending.record(LEAP_DAY, &deltas(&[("a", 1), ("b", 1), ("c", 1)]));
+        pending.record(LEAP_DAY + MS_PER_DAY, &deltas(&[("a", 1)]));
+
+        block_on(pending.flush(&store, 60, 2)).unwrap();
+        assert_eq!(pending.days.values().map(BTreeMap::len).sum::<usize>(), 2);
+
+        store.fail_puts(&keyword_stats_kv_key("trend-flush", "c", ""), 1);
+        assert!(block_on(pending.flush(&store, 60, 10)).is_err());
+        let mut later = PendingTrends::new("trend-flush");
+        later.record(LEAP_DAY, &deltas(&[("c", 1)]));
+        later.merge(pending);
+        block_on(later.flush(&store, 60, 10)).unwrap();
+
+        let now = LEAP_DAY + MS_PER_DAY;
+        let c = block_on(read_trend(&store, "trend-flush", "c", now, 2)).unwrap();
+        assert_eq!((c[0].net, c[1].net), (2, 0));
+        let a = block_on(read_trend(&store, "trend-flush", "a", now, 2)).unwrap();
+        assert_eq!(a.last().unwrap().cumulative, 2);
+    }
+
+    #[test]
+    fn test_writes_report_keyword_deltas() {
+        let store = MemoryStorage::default();
+        let document = index_text(&store, "trend-docs", "doc1", "Ocean tides rise at dawn.");
+        let keywords = deletion_deltas(&document);
+        assert!(!keywords.is_empty() && keywords.values().all(|delta| *delta == -1));
+
+        let mut rewritten =
+            block_on(Document::from_remote(&store, "trend-docs", "doc1".into())).unwrap();
+        let outcome = block_on(rewritten.update_with(
+            &store,
+            &IndexingOptions::default(),
+            "Mountain trails climb through forests.".into(),
+            None,
+            LangDetection::WhenMissing,
+        ))
+        .unwrap();
+        let gained = outcome.keyword_deltas.values().filter(|d| **d == 1).count();
+        let lost = outcome
+            .keyword_deltas
+            .values()
+            .filter(|d| **d == -1)
+            .count();
+        assert_eq!(
+            (gained, lost),
+            (outcome.keywords_added, outcome.keywords_removed)
+        );
+        assert!(outcome
+            .keyword_deltas
+            .keys()
+            .filter(|keyword| outcome.keyword_deltas[*keyword] == -1)
+            .all(|keyword| keywords.contains_key(keyword)));
+    }
+}
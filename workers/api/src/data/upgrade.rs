@@ -0,0 +1,288 @@
+//! Upgrading an index to the latest version, see [`crate::data::codec`]. An upgrade
+//! rewrites every keyword shard of a frozen index with the new version's codecs, a
+//! bounded batch per call, and then stamps the index with the new version. From its
+//! first call the index is read and written with the new codecs, which read shards
+//! of either version, so every read sees every shard throughout and a call that
+//! fails part way is safe to repeat with the same cursor.
+
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::{
+    data::{
+        codec::CodecSet,
+        index::IndexDocument,
+        keyword_shard::{parse_keyword_shard_key, KeywordShardData},
+        storage::{PageCursor, Storage},
+        DataStoreError, KvPersistent, INDEX_VERSION_LATEST, PREFIX_KEYWORD,
+    },
+    edge_log,
+};
+
+/// The most shard keys rewritten per call, keeping each request well under the KV
+/// operation limit
+pub const UPGRADE_BATCH_SIZE: usize = 50;
+
+#[derive(Error, Debug)]
+pub enum UpgradeError {
+    #[error("Freeze the index with POST /:index/freeze before upgrading it")]
+    NotFrozen,
+    #[error("The index is already version {0}")]
+    AlreadyUpgraded(u8),
+    #[error("A reshard to {0} shards is in progress, and must finish first")]
+    Resharding(u32),
+    #[error(transparent)]
+    Store(#[from] DataStoreError),
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct UpgradeReport {
+    /// The version the index had when the upgrade started
+    pub from_version: u8,
+    pub to_version: u8,
+    pub shards_rewritten: u32,
+    /// Pass back to continue the upgrade, `None` once it's complete
+    pub cursor: Option<String>,
+}
+
+/// Rewrite the next batch of the index's keyword shards for the latest version,
+/// starting the upgrade when none is in progress
+pub async fn upgrade_batch<S: Storage>(
+    store: &S,
+    mut index_doc: IndexDocument,
+    cursor: Option<PageCursor>,
+) -> Result<UpgradeReport, UpgradeError> {
+    // Refuses indexes of a newer version than this worker understands
+    CodecSet::for_index(&index_doc)?;
+    if !index_doc.frozen {
+        return Err(UpgradeError::NotFrozen);
+    }
+    if let Some(reshard) = index_doc.reshard.as_ref() {
+        return Err(UpgradeError::Resharding(reshard.target_shards));
+    }
+    let index = index_doc.index.clone();
+    let target = match index_doc.upgrading_to {
+        Some(target) => target,
+        None if index_doc.version >= INDEX_VERSION_LATEST => {
+            return Err(UpgradeError::AlreadyUpgraded(index_doc.version));
+        }
+        None => {
+            index_doc.upgrading_to = Some(INDEX_VERSION_LATEST);
+            index_doc.generation += 1;
+            index_doc.write(store).await?;
+            edge_log!(
+                console_log,
+                "Upgrade",
+                (index.as_str()),
+                "started upgrading from version {} to {}",
+                (index_doc.version),
+                INDEX_VERSION_LATEST
+            );
+            INDEX_VERSION_LATEST
+        }
+    };
+    let codecs = CodecSet::for_version(target)?;
+
+    let cursor = cursor.unwrap_or_default();
+    let prefix = format!("{}:{}", index, PREFIX_KEYWORD);
+    let page = store.list(&prefix, cursor.page.clone()).await?;
+    let keys: Vec<&String> = page
+        .keys
+        .iter()
+        .skip(cursor.offset)
+        .take(UPGRADE_BATCH_SIZE)
+        .collect();
+    let checked = cursor.offset + keys.len();
+
+    let mut report = UpgradeReport {
+        from_version: index_doc.version,
+        to_version: target,
+        shards_rewritten: 0,
+        cursor: None,
+    };
+    for key in keys {
+        // Reshard markers aren't shards
+        if parse_keyword_shard_key(&index, key).is_none() {
+            continue;
+        }
+        let shard = match KeywordShardData::read_with(key, store, codecs).await {
+            Ok(shard) => shard,
+            Err(DataStoreError::NotFound(_)) => continue,
+            Err(err) => return Err(err.into()),
+        };
+        // Written back under the key it was read from, which legacy unescaped
+        // shards don't share with the key they'd be written to
+        store.put(key, shard.encode(codecs)?).await?;
+        report.shards_rewritten += 1;
+    }
+
+    let next = if checked < page.keys.len() {
+        Some(PageCursor {
+            offset: checked,
+            ..cursor
+        })
+    } else {
+        page.cursor.map(|page| PageCursor {
+            offset: 0,
+            page: Some(page),
+        })
+    };
+    if let Some(next) = next {
+        report.cursor = Some(next.to_string());
+        return Ok(report);
+    }
+
+    index_doc.version = target;
+    index_doc.upgrading_to = None;
+    index_doc.generation += 1;
+    index_doc.write(store).await?;
+    edge_log!(
+        console_log,
+        "Upgrade",
+        index,
+        "upgraded to version {}",
+        target
+    );
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+
+    use super::*;
+    use crate::data::{
+        index::IndexSettings,
+        index_manager::IndexManager,
+        keyword::{KeywordManager, MergedKeywordData},
+        keyword_shard::testing::seed_postings,
+        storage::memory::MemoryStorage,
+        INDEX_VERSION_V1,
+    };
+
+    const KEYWORDS: [&str; 3] = ["ocean", "storm", "tide"];
+
+    fn seeded_v1_index(store: &MemoryStorage, index: &str) {
+        let manager = IndexManager::new(store);
+        let settings = IndexSettings {
+            version: Some(INDEX_VERSION_V1),
+            ..IndexSettings::default()
+        };
+        block_on(manager.create_index(index, None, Some(settings))).unwrap();
+        for (i, keyword) in KEYWORDS.iter().enumerate() {
+            let postings: Vec<(String, f64)> = (0..12)
+                .map(|doc| (format!("doc{}", doc), 0.1 + (doc + i) as f64 / 100.0))
+                .collect();
+            let postings: Vec<(&str, f64)> =
+                postings.iter().map(|(d, s)| (d.as_str(), *s)).collect();
+            seed_postings(store, index, 8, keyword, &postings);
+        }
+        block_on(manager.set_frozen(index, true)).unwrap();
+    }
+
+    fn merged(store: &MemoryStorage, index: &str, codecs: CodecSet) -> Vec<MergedKeywordData> {
+        let manager = KeywordManager::direct(index.into(), 8, store).with_codecs(codecs);
+        KEYWORDS
+            .iter()
+            .map(|keyword| block_on(manager.merge_keyword_shards(keyword.to_string())).unwrap())
+            .collect()
+    }
+
+    fn run(
+        store: &MemoryStorage,
+        index: &str,
+        cursor: Option<&str>,
+    ) -> Result<UpgradeReport, UpgradeError> {
+        let index_doc = block_on(IndexManager::new(store).read_index(index)).unwrap();
+        let cursor = cursor.map(|cursor| cursor.parse().unwrap());
+        block_on(upgrade_batch(store, index_doc, cursor))
+    }
+
+    #[test]
+    fn test_upgrade_rewrites_every_shard() {
+        let store = MemoryStorage::default();
+        seeded_v1_index(&store, "upgrade-all");
+        let before = merged(&store, "upgrade-all", CodecSet::V1);
+        let shard_key = store
+            .keys()
+            .into_iter()
+            .find(|key| key.starts_with("upgrade-all:kw:ocean:"))
+            .unwrap();
+        let stored_v1 = block_on(store.get(&shard_key)).unwrap().unwrap();
+
+        let mut cursor = None;
+        let mut rewritten = 0;
+        loop {
+            let report = run(&store, "upgrade-all", cursor.as_deref()).unwrap();
+            assert_eq!((report.from_version, report.to_version), (1, 2));
+            rewritten += report.shards_rewritten;
+            cursor = report.cursor;
+            let index_doc = block_on(IndexManager::new(&store).read_index("upgrade-all")).unwrap();
+            if cursor.is_none() {
+                assert_eq!((index_doc.version, index_doc.upgrading_to), (2, None));
+                break;
+            }
+            // Part way, shards of both versions are read alike
+            assert_eq!(index_doc.upgrading_to, Some(2));
+            assert_eq!(merged(&store, "upgrade-all", CodecSet::V2), before);
+        }
+        let shards = store
+            .keys()
+            .iter()
+            .filter(|key| parse_keyword_shard_key("upgrade-all", key).is_some())
+            .count();
+        assert_eq!(rewritten as usize, shards);
+        assert_eq!(merged(&store, "upgrade-all", CodecSet::V2), before);
+
+        let stored_v2 = block_on(store.get(&shard_key)).unwrap().unwrap();
+        assert!(stored_v2.len() < stored_v1.len());
+        assert!(!stored_v2.contains("\"keyword\""));
+    }
+
+    #[test]
+    fn test_failed_calls_resume() {
+        let store = MemoryStorage::default();
+        seeded_v1_index(&store, "upgrade-resume");
+        let before = merged(&store, "upgrade-resume", CodecSet::V1);
+
+        let first = run(&store, "upgrade-resume", None).unwrap();
+        store.fail_puts("upgrade-resume:kw:", 1);
+        let failed = run(&store, "upgrade-resume", first.cursor.as_deref());
+        assert!(matches!(failed, Err(UpgradeError::Store(_))));
+
+        let mut cursor = first.cursor;
+        while let Some(next) = cursor {
+            cursor = run(&store, "upgrade-resume", Some(&next)).unwrap().cursor;
+        }
+        let index_doc = block_on(IndexManager::new(&store).read_index("upgrade-resume")).unwrap();
+        assert_eq!(index_doc.version, 2);
+        assert!(index_doc.frozen);
+        assert_eq!(merged(&store, "upgrade-resume", CodecSet::V2), before);
+    }
+
+    #[test]
+    fn test_upgrade_rejections() {
+        let store = MemoryStorage::default();
+        let manager = IndexManager::new(&store);
+        block_on(manager.create_index("upgrade-latest", None, None)).unwrap();
+        block_on(manager.set_frozen("upgrade-latest", true)).unwrap();
+        assert!(matches!(
+            run(&store, "upgrade-latest", None),
+            Err(UpgradeError::AlreadyUpgraded(2))
+        ));
+
+        seeded_v1_index(&store, "upgrade-rejected");
+        block_on(manager.set_frozen("upgrade-rejected", false)).unwrap();
+        assert!(matches!(
+            run(&store, "upgrade-rejected", None),
+            Err(UpgradeError::NotFrozen)
+        ));
+
+        let mut newer = block_on(manager.read_index("upgrade-rejected")).unwrap();
+        newer.version = INDEX_VERSION_LATEST + 1;
+        assert!(matches!(
+            block_on(upgrade_batch(&store, newer, None)),
+            Err(UpgradeError::Store(DataStoreError::UnsupportedVersion(3)))
+        ));
+    }
+}
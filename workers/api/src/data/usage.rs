@@ -0,0 +1,306 @@
+//! Daily usage counters of each index, kept under the reserved `_internal` index as
+//! `_internal:stats:{index}:{yyyy-mm-dd}`.
+//!
+//! Searches and document writes report what they did to the index's journal once
+//! they've responded. The journal adds each report to a [`PendingUsage`] in its
+//! durable storage, so concurrent reports never race to rewrite a day, and adds the
+//! pending days to KV on its alarm. Days are UTC, and a day the index wasn't used
+//! has no key and reads as zeros.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    data::{storage::Storage, DataStoreError, KvEntry, KvPersistent},
+    util::concurrency::join_bounded,
+};
+
+pub static PREFIX_STATS: &str = "_internal:stats:";
+
+/// How many days `GET /:index/usage` returns without `days`
+pub const DEFAULT_USAGE_DAYS: u32 = 7;
+
+/// The most days `GET /:index/usage` returns, each one KV read
+pub const MAX_USAGE_DAYS: u32 = 90;
+
+pub const MS_PER_DAY: u64 = 86_400_000;
+
+pub fn usage_kv_key(index: &str, date: &str) -> String {
+    format!("{}{}:{}", PREFIX_STATS, index, date)
+}
+
+/// The UTC date of `millis` since the epoch, as `yyyy-mm-dd`
+pub fn utc_date(millis: u64) -> String {
+    // Howard Hinnant's civil_from_days, with days counted from 0000-03-01
+    let z = millis / MS_PER_DAY + 719_468;
+    let (era, doe) = (z / 146_097, z % 146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// What one or more operations on an index did
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct UsageDelta {
+    #[serde(default)]
+    pub searches: u64,
+    #[serde(default)]
+    pub docs_added: u64,
+    #[serde(default)]
+    pub docs_updated: u64,
+    #[serde(default)]
+    pub docs_deleted: u64,
+    /// The matches the searches returned
+    #[serde(default)]
+    pub results: u64,
+}
+
+impl UsageDelta {
+    /// One search, which returned `results` matches
+    pub fn search(results: usize) -> UsageDelta {
+        UsageDelta {
+            searches: 1,
+            results: results as u64,
+            ..UsageDelta::default()
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == UsageDelta::default()
+    }
+
+    pub fn add(&mut self, other: &UsageDelta) {
+        self.searches += other.searches;
+        self.docs_added += other.docs_added;
+        self.docs_updated += other.docs_updated;
+        self.docs_deleted += other.docs_deleted;
+        self.results += other.results;
+    }
+}
+
+/// One day of an index's usage
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct UsageDay {
+    /// Taken from the key it's stored under
+    #[serde(skip)]
+    pub index: String,
+    pub date: String,
+    pub searches: u64,
+    pub docs_added: u64,
+    pub docs_updated: u64,
+    pub docs_deleted: u64,
+    /// The matches returned by all of the day's searches
+    pub results: u64,
+    pub avg_result_count: f64,
+}
+
+impl KvEntry for UsageDay {
+    type Key = String;
+
+    fn get_kv_key(&self) -> String {
+        usage_kv_key(&self.index, &self.date)
+    }
+}
+
+impl KvPersistent for UsageDay {}
+
+impl UsageDay {
+    pub fn empty(index: &str, date: &str) -> UsageDay {
+        UsageDay {
+            index: index.to_string(),
+            date: date.to_string(),
+            searches: 0,
+            docs_added: 0,
+            docs_updated: 0,
+            docs_deleted: 0,
+            results: 0,
+            avg_result_count: 0.0,
+        }
+    }
+
+    pub fn apply(&mut self, delta: &UsageDelta) {
+        self.searches += delta.searches;
+        self.docs_added += delta.docs_added;
+        self.docs_updated += delta.docs_updated;
+        self.docs_deleted += delta.docs_deleted;
+        self.results += delta.results;
+        self.avg_result_count = match self.searches {
+            0 => 0.0,
+            searches => self.results as f64 / searches as f64,
+        };
+    }
+
+    /// Read the index's usage on `date`, which is empty when the index wasn't used
+    pub async fn load<S: Storage>(
+        store: &S,
+        index: &str,
+        date: &str,
+    ) -> Result<UsageDay, DataStoreError> {
+        match UsageDay::read(&usage_kv_key(index, date), store).await {
+            Ok(mut day) => {
+                day.index = index.to_string();
+                Ok(day)
+            }
+            Err(DataStoreError::NotFound(_)) => Ok(UsageDay::empty(index, date)),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+/// The index's usage on each of the `days` days up to the one `now` falls on,
+/// oldest first
+pub async fn read_usage<S: Storage>(
+    store: &S,
+    index: &str,
+    now: u64,
+    days: u32,
+) -> Result<Vec<UsageDay>, DataStoreError> {
+    let dates: Vec<String> = (0..days as u64)
+        .rev()
+        .map(|ago| utc_date(now.saturating_sub(ago * MS_PER_DAY)))
+        .collect();
+    join_bounded(dates.iter().map(|date| UsageDay::load(store, index, date)))
+        .await
+        .into_iter()
+        .collect()
+}
+
+/// Usage reported to an index's journal and not yet added to KV, by date
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct PendingUsage {
+    pub index: String,
+    pub days: BTreeMap<String, UsageDelta>,
+}
+
+impl PendingUsage {
+    pub fn new(index: &str) -> PendingUsage {
+        PendingUsage {
+            index: index.to_string(),
+            days: BTreeMap::new(),
+        }
+    }
+
+    /// Add `delta` to the day `now` falls on
+    pub fn record(&mut self, now: u64, delta: &UsageDelta) {
+        self.days.entry(utc_date(now)).or_default().add(delta);
+    }
+
+    /// Add the days of `other`, such as those a failed flush kept
+    pub fn merge(&mut self, other: PendingUsage) {
+        for (date, delta) in other.days {
+            self.days.entry(date).or_default().add(&delta);
+        }
+    }
+
+    /// Add every pending day to the day stored in KV. A day stays pending until
+    /// it's written, so a failed flush can be repeated without counting twice.
+    pub async fn flush<S: Storage>(&mut self, store: &S) -> Result<(), DataStoreError> {
+        while let Some((date, delta)) = self.days.pop_first() {
+            let written = async {
+                let mut day = UsageDay::load(store, &self.index, &date).await?;
+                day.apply(&delta);
+                day.write(store).await
+            };
+            if let Err(err) = written.await {
+                self.days.insert(date, delta);
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+
+    use super::*;
+    use crate::{
+        data::storage::memory::MemoryStorage,
+        util::time::{Clock, ManualClock},
+    };
+
+    const LEAP_DAY: u64 = 1_709_164_800_000;
+
+    #[test]
+    fn test_utc_date() {
+        assert_eq!(utc_date(0), "1970-01-01");
+        assert_eq!(utc_date(951_782_400_000), "2000-02-29");
+        assert_eq!(utc_date(LEAP_DAY + MS_PER_DAY - 1), "2024-02-29");
+        assert_eq!(utc_date(LEAP_DAY + MS_PER_DAY), "2024-03-01");
+        assert_eq!(utc_date(4_102_444_800_000), "2100-01-01");
+    }
+
+    #[test]
+    fn test_usage_aggregates_by_day() {
+        let store = MemoryStorage::default();
+        let clock = ManualClock::at(LEAP_DAY + 1000);
+        let mut pending = PendingUsage::new("usage-days");
+        for results in [4, 0, 2] {
+            pending.record(clock.now_millis(), &UsageDelta::search(results));
+        }
+        let writes = UsageDelta {
+            docs_added: 3,
+            docs_updated: 1,
+            ..UsageDelta::default()
+        };
+        pending.record(clock.now_millis(), &writes);
+        block_on(pending.flush(&store)).unwrap();
+        assert!(pending.days.is_empty());
+
+        // The next report adds to the stored day, and the day after starts over
+        pending.record(clock.now_millis(), &UsageDelta::search(6));
+        clock.advance(2 * MS_PER_DAY);
+        let deleted = UsageDelta {
+            docs_deleted: 2,
+            ..UsageDelta::default()
+        };
+        pending.record(clock.now_millis(), &deleted);
+        block_on(pending.flush(&store)).unwrap();
+
+        let days = block_on(read_usage(&store, "usage-days", clock.now_millis(), 4)).unwrap();
+        let dates: Vec<&str> = days.iter().map(|day| day.date.as_str()).collect();
+        assert_eq!(
+            dates,
+            vec!["2024-02-28", "2024-02-29", "2024-03-01", "2024-03-02"]
+        );
+        assert_eq!(days[0], UsageDay::empty("usage-days", "2024-02-28"));
+        assert_eq!(
+            (days[1].searches, days[1].results, days[1].avg_result_count),
+            (4, 12, 3.0)
+        );
+        assert_eq!((days[1].docs_added, days[1].docs_updated), (3, 1));
+        assert_eq!(days[2].searches, 0);
+        assert_eq!((days[3].docs_deleted, days[3].avg_result_count), (2, 0.0));
+        assert!(store
+            .keys()
+            .contains(&"_internal:stats:usage-days:2024-02-29".to_string()));
+    }
+
+    #[test]
+    fn test_failed_flush_stays_pending() {
+        let store = MemoryStorage::default();
+        let mut pending = PendingUsage::new("usage-retry");
+        pending.record(LEAP_DAY, &UsageDelta::search(1));
+        pending.record(LEAP_DAY + MS_PER_DAY, &UsageDelta::search(3));
+
+        store.fail_puts("_internal:stats:usage-retry:2024-03-01", 1);
+        assert!(block_on(pending.flush(&store)).is_err());
+        assert_eq!(pending.days.len(), 1);
+
+        let mut later = PendingUsage::new("usage-retry");
+        later.record(LEAP_DAY + MS_PER_DAY, &UsageDelta::search(5));
+        later.merge(pending);
+        block_on(later.flush(&store)).unwrap();
+
+        let days = block_on(read_usage(&store, "usage-retry", LEAP_DAY + MS_PER_DAY, 2)).unwrap();
+        let searches: Vec<(u64, u64)> =
+            days.iter().map(|day| (day.searches, day.results)).collect();
+        assert_eq!(searches, vec![(1, 1), (2, 8)]);
+    }
+}
@@ -0,0 +1,237 @@
+//! The recent document changes of each index, kept by its [`Journal`] for
+//! `GET /:index/activity`. Document handlers report each change they made once
+//! they've responded, and the journal keeps the last `ACTIVITY_RETENTION` of them
+//! in its durable storage, dropping the oldest as new ones arrive.
+//!
+//! [`Journal`]: crate::durable::journal::Journal
+
+use std::collections::VecDeque;
+
+use serde::{Deserialize, Serialize};
+
+use crate::data::document::{Document, UpdateOutcome};
+
+/// How many changes a journal keeps without `ACTIVITY_RETENTION`
+pub const DEFAULT_ACTIVITY_RETENTION: usize = 200;
+
+/// The most changes a journal keeps, so the log stays well within the 128 KiB a
+/// durable storage value can hold
+pub const MAX_ACTIVITY_RETENTION: usize = 500;
+
+/// How many changes `GET /:index/activity` returns without `limit`
+pub const DEFAULT_ACTIVITY_LIMIT: usize = 50;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ActivityAction {
+    Created,
+    Updated,
+    Deleted,
+}
+
+/// One change to one document
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ActivityEvent {
+    pub doc_id: String,
+    pub action: ActivityAction,
+    /// When the change was made, in milliseconds since the epoch
+    pub ts: u64,
+    /// The keywords whose postings the change added or removed
+    pub keywords_changed: usize,
+}
+
+impl ActivityEvent {
+    /// The change a write of the document made, a new one when `created`
+    pub fn written(doc_id: &str, created: bool, outcome: &UpdateOutcome, ts: u64) -> Self {
+        ActivityEvent {
+            doc_id: doc_id.to_string(),
+            action: match created {
+                true => ActivityAction::Created,
+                false => ActivityAction::Updated,
+            },
+            ts,
+            keywords_changed: outcome.keywords_added + outcome.keywords_removed,
+        }
+    }
+
+    /// The deletion of `document`, which removed the postings of all its keywords
+    pub fn deleted(document: &Document, ts: u64) -> Self {
+        ActivityEvent {
+            doc_id: document.get_uuid(),
+            action: ActivityAction::Deleted,
+            ts,
+            keywords_changed: document.keywords.as_ref().map_or(0, Vec::len),
+        }
+    }
+}
+
+/// Which of the log's changes to return
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ActivityFilter {
+    /// Only changes made at or after this, in milliseconds since the epoch
+    pub since: Option<u64>,
+    /// Only changes to this document
+    pub doc_id: Option<String>,
+    pub limit: Option<usize>,
+}
+
+/// An index's recent changes, oldest first
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ActivityLog {
+    pub events: VecDeque<ActivityEvent>,
+}
+
+impl ActivityLog {
+    /// Add `events`, keeping only the newest `retention`
+    pub fn record(&mut self, events: Vec<ActivityEvent>, retention: usize) {
+        self.events.extend(events);
+        while self.events.len() > retention {
+            self.events.pop_front();
+        }
+    }
+
+    /// The changes matching `filter`, newest first
+    pub fn query(&self, filter: &ActivityFilter) -> Vec<ActivityEvent> {
+        self.events
+            .iter()
+            .rev()
+            .filter(|event| filter.since.is_none_or(|since| event.ts >= since))
+            .filter(|event| {
+                filter
+                    .doc_id
+                    .as_ref()
+                    .is_none_or(|doc_id| event.doc_id == *doc_id)
+            })
+            .take(filter.limit.unwrap_or(DEFAULT_ACTIVITY_LIMIT))
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::document::testing::index_text;
+
+    fn event(doc_id: &str, action: ActivityAction, ts: u64) -> ActivityEvent {
+        ActivityEvent {
+            doc_id: doc_id.to_string(),
+            action,
+            ts,
+            keywords_changed: 3,
+        }
+    }
+
+    /// The log as the journal reads it back from its durable storage
+    fn stored(log: &ActivityLog) -> ActivityLog {
+        serde_json::from_str(&serde_json::to_string(log).unwrap()).unwrap()
+    }
+
+    fn ids(events: &[ActivityEvent]) -> Vec<(&str, u64)> {
+        events
+            .iter()
+            .map(|event| (event.doc_id.as_str(), event.ts))
+            .collect()
+    }
+
+    #[test]
+    fn test_events_count_changed_keywords() {
+        let store = crate::data::storage::memory::MemoryStorage::default();
+        let document = index_text(&store, "activity-events", "doc1", "Ocean tides rise.");
+        let deleted = ActivityEvent::deleted(&document, 7);
+        assert_eq!((deleted.doc_id.as_str(), deleted.ts), ("doc1", 7));
+        assert_eq!(
+            deleted.keywords_changed,
+            document.keywords.as_ref().unwrap().len()
+        );
+
+        let outcome = UpdateOutcome {
+            revision: 2,
+            keywords_added: 2,
+            keywords_removed: 1,
+            indexed_keywords: vec![],
+            failed_keywords: vec![],
+            unchanged: false,
+            keywords_dropped_below_floor: 0,
+            keyword_deltas: Default::default(),
+        };
+        let updated = ActivityEvent::written("doc1", false, &outcome, 8);
+        assert_eq!(
+            (updated.action, updated.keywords_changed),
+            (ActivityAction::Updated, 3)
+        );
+        assert_eq!(
+            ActivityEvent::written("doc1", true, &outcome, 8).action,
+            ActivityAction::Created
+        );
+    }
+
+    #[test]
+    fn test_retention_drops_the_oldest() {
+        let mut log = ActivityLog::default();
+        log.record(
+            vec![
+                event("a", ActivityAction::Created, 1),
+                event("b", ActivityAction::Created, 2),
+            ],
+            3,
+        );
+        let mut log = stored(&log);
+        log.record(
+            vec![
+                event("a", ActivityAction::Updated, 3),
+                event("b", ActivityAction::Deleted, 4),
+            ],
+            3,
+        );
+        let log = stored(&log);
+        assert_eq!(log.events.len(), 3);
+        assert_eq!(
+            ids(&log.query(&ActivityFilter::default())),
+            vec![("b", 4), ("a", 3), ("b", 2)]
+        );
+
+        // A lower retention truncates the log on the next change
+        let mut log = log;
+        log.record(vec![event("c", ActivityAction::Created, 5)], 1);
+        assert_eq!(
+            ids(&stored(&log).query(&ActivityFilter::default())),
+            vec![("c", 5)]
+        );
+    }
+
+    #[test]
+    fn test_since_and_doc_id_filters() {
+        let mut log = ActivityLog::default();
+        log.record(
+            (1..=6)
+                .map(|ts| {
+                    let doc_id = if ts % 2 == 0 { "even" } else { "odd" };
+                    event(doc_id, ActivityAction::Updated, ts * 100)
+                })
+                .collect(),
+            DEFAULT_ACTIVITY_RETENTION,
+        );
+        let log = stored(&log);
+
+        let since = ActivityFilter {
+            since: Some(400),
+            ..ActivityFilter::default()
+        };
+        assert_eq!(
+            ids(&log.query(&since)),
+            vec![("even", 600), ("odd", 500), ("even", 400)]
+        );
+        let doc = ActivityFilter {
+            since: Some(200),
+            doc_id: Some("odd".into()),
+            limit: Some(1),
+        };
+        assert_eq!(ids(&log.query(&doc)), vec![("odd", 500)]);
+        let future = ActivityFilter {
+            since: Some(700),
+            ..ActivityFilter::default()
+        };
+        assert!(log.query(&future).is_empty());
+    }
+}
@@ -1,108 +1,829 @@
-use core::panic;
+use std::{sync::Arc, time::Duration};
 
-use crate::durable::journal_data::{JournalCommand, JournalData, JournalUpdateEntry};
 use serde::{Deserialize, Serialize};
-use worker::*;
+use worker::{kv::KvStore, *};
 
+use crate::{
+    data::{
+        index_manager::IndexManager,
+        keyword_shard::get_n_shards,
+        shard_repair::{PendingShardRepairs, MAX_REPAIR_FLUSH_DOCUMENTS},
+        storage::Storage as DataStorage,
+        trend::{
+            KeywordDeltas, PendingTrends, DEFAULT_TREND_RETENTION_DAYS, MAX_TREND_FLUSH_COUNTERS,
+            MAX_TREND_RETENTION_DAYS,
+        },
+        usage::{PendingUsage, UsageDelta},
+        DataStoreError, KvPersistent, ENV_VAR_ACTIVITY_RETENTION,
+        ENV_VAR_KEYWORD_TREND_RETENTION_DAYS,
+    },
+    durable::activity::{
+        ActivityEvent, ActivityFilter, ActivityLog, DEFAULT_ACTIVITY_RETENTION,
+        MAX_ACTIVITY_RETENTION,
+    },
+    edge_log,
+    http::{json_error, ErrorCode},
+    util::{env::parse_env_usize, kv::get_kv_data_store_from_env, time::now_ms},
+};
+
+/// How long after a count changes the journal writes it to the index document
+pub const FLUSH_DELAY: Duration = Duration::from_secs(10);
+
+/// The durable storage key of an index journal's counter
+static COUNTER_KEY: &str = "counter";
+
+/// The durable storage key of the usage an index journal hasn't written to KV yet
+static USAGE_KEY: &str = "usage";
+
+/// The durable storage key of the keyword changes an index journal hasn't added to
+/// the trend counters yet
+static TRENDS_KEY: &str = "trends";
+
+/// The durable storage key of the keyword shards an index journal hasn't written
+/// again yet
+static REPAIRS_KEY: &str = "repairs";
+
+/// The durable storage key of an index journal's [`ActivityLog`]
+static ACTIVITY_KEY: &str = "activity";
+
+/// The body of a `POST /counter/:index` request
+#[derive(Serialize, Deserialize)]
+pub struct CounterCommand {
+    pub delta: i64,
+}
+
+/// The response to every `/counter/:index` request
+#[derive(Serialize, Deserialize)]
+pub struct CounterResponse {
+    pub index: String,
+    pub docs_count: u32,
+}
+
+/// An index's authoritative document count, and the count last written to KV
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DocsCounter {
+    pub index: String,
+    pub count: u32,
+    pub flushed: Option<u32>,
+}
+
+impl DocsCounter {
+    /// Start counting from the index's listed documents, which already include the
+    /// change that made the journal start counting. When listing fails, start from
+    /// the `docs_count` stored on the index document.
+    pub async fn seed<S: DataStorage>(store: &S, index: &str) -> DocsCounter {
+        let indexer = IndexManager::new(store);
+        let stored = indexer
+            .read_index(index)
+            .await
+            .ok()
+            .map(|index| index.docs_count);
+        let count = match indexer.count_index_documents(index).await {
+            Ok(count) => count,
+            Err(_) => stored.unwrap_or(0),
+        };
+        DocsCounter {
+            index: index.to_string(),
+            count,
+            flushed: stored,
+        }
+    }
+
+    /// Add `delta` to the count, which never drops below zero
+    pub fn apply(&mut self, delta: i64) -> u32 {
+        self.count = (self.count as i64 + delta).clamp(0, u32::MAX as i64) as u32;
+        self.count
+    }
+
+    pub fn needs_flush(&self) -> bool {
+        self.flushed != Some(self.count)
+    }
+
+    /// Write the count to the index document if it changed since the last flush,
+    /// returning whether it wrote. A deleted index is left alone.
+    pub async fn flush<S: DataStorage>(
+        &mut self,
+        store: &S,
+    ) -> std::result::Result<bool, DataStoreError> {
+        if !self.needs_flush() {
+            return Ok(false);
+        }
+        let mut index_data = match IndexManager::new(store).read_index(&self.index).await {
+            Ok(index_data) => index_data,
+            Err(DataStoreError::NotFound(_)) => return Ok(false),
+            Err(err) => return Err(err),
+        };
+        // Maintenance waits while the index is frozen, and the count stays unflushed
+        if index_data.frozen {
+            return Ok(false);
+        }
+        if index_data.docs_count != self.count {
+            index_data.docs_count = self.count;
+            index_data.generation += 1;
+            index_data.write(store).await?;
+        }
+        self.flushed = Some(self.count);
+        Ok(true)
+    }
+}
+
+fn counter_url(index: &str) -> String {
+    format!("https://journal/counter/{}", index)
+}
+
+/// The index's journal, one durable object per index
+fn journal_stub(env: &Env, index: &str) -> Result<Stub> {
+    env.durable_object(Journal::BINDING_ID)?
+        .id_from_name(index)?
+        .get_stub()
+}
+
+async fn read_counter_response(mut response: Response) -> Result<u32> {
+    if response.status_code() != 200 {
+        return Err(Error::RustError(format!(
+            "journal returned {}: {}",
+            response.status_code(),
+            response.text().await.unwrap_or_default()
+        )));
+    }
+    Ok(response.json::<CounterResponse>().await?.docs_count)
+}
+
+/// Tell the index's journal that `delta` documents were created (or deleted, when
+/// negative), returning the new count. Failures are logged, since the document
+/// write they follow already succeeded.
+pub async fn send_docs_delta(env: &Env, index: &str, delta: i64) -> Option<u32> {
+    let sent = async {
+        let body = serde_json::to_string(&CounterCommand { delta })?;
+        let req = Request::new_with_init(
+            &counter_url(index),
+            &RequestInit {
+                method: Method::Post,
+                body: Some(body.as_str().into()),
+                ..Default::default()
+            },
+        )?;
+        read_counter_response(journal_stub(env, index)?.fetch_with_request(req).await?).await
+    };
+    match sent.await {
+        Ok(count) => Some(count),
+        Err(err) => {
+            edge_log!(
+                console_warn,
+                "Journal",
+                index,
+                "Failed to send a document count change of {}: {}",
+                delta,
+                err
+            );
+            None
+        }
+    }
+}
+
+fn usage_url(index: &str) -> String {
+    format!("https://journal/usage/{}", index)
+}
+
+async fn send_usage(env: &Env, index: &str, delta: UsageDelta) {
+    let sent = async {
+        let body = serde_json::to_string(&delta)?;
+        let req = Request::new_with_init(
+            &usage_url(index),
+            &RequestInit {
+                method: Method::Post,
+                body: Some(body.as_str().into()),
+                ..Default::default()
+            },
+        )?;
+        let response = journal_stub(env, index)?.fetch_with_request(req).await?;
+        match response.status_code() {
+            200 => Ok(()),
+            status => Err(Error::RustError(format!("journal returned {}", status))),
+        }
+    };
+    if let Err(err) = sent.await {
+        edge_log!(
+            console_warn,
+            "Journal",
+            index,
+            "Failed to record usage: {}",
+            err
+        );
+    }
+}
+
+/// Report what a request did to the index's usage, see [`crate::data::usage`]. The
+/// report is sent after the response, and a failure only loses the report.
+pub fn record_usage(ctx: &RouteContext<Context>, index: &str, delta: UsageDelta) {
+    if delta.is_empty() {
+        return;
+    }
+    let (env, index) = (ctx.env.clone(), index.to_string());
+    ctx.data
+        .wait_until(async move { send_usage(&env, &index, delta).await });
+}
+
+fn trends_url(index: &str) -> String {
+    format!("https://journal/trends/{}", index)
+}
+
+async fn send_keyword_trends(env: &Env, index: &str, deltas: KeywordDeltas) {
+    let sent = async {
+        let body = serde_json::to_string(&deltas)?;
+        let req = Request::new_with_init(
+            &trends_url(index),
+            &RequestInit {
+                method: Method::Post,
+                body: Some(body.as_str().into()),
+                ..Default::default()
+            },
+        )?;
+        let response = journal_stub(env, index)?.fetch_with_request(req).await?;
+        match response.status_code() {
+            200 => Ok(()),
+            status => Err(Error::RustError(format!("journal returned {}", status))),
+        }
+    };
+    if let Err(err) = sent.await {
+        edge_log!(
+            console_warn,
+            "Journal",
+            index,
+            "Failed to record keyword trends: {}",
+            err
+        );
+    }
+}
+
+/// Report how a request changed the index's keyword posting counts, see
+/// [`crate::data::trend`]. Like usage, it's sent after the response.
+pub fn record_keyword_trends(ctx: &RouteContext<Context>, index: &str, deltas: KeywordDeltas) {
+    if deltas.is_empty() {
+        return;
+    }
+    let (env, index) = (ctx.env.clone(), index.to_string());
+    ctx.data
+        .wait_until(async move { send_keyword_trends(&env, &index, deltas).await });
+}
+
+fn repairs_url(index: &str) -> String {
+    format!("https://journal/repairs/{}", index)
+}
+
+async fn send_shard_repairs(env: &Env, index: &str, repairs: PendingShardRepairs) {
+    let sent = async {
+        let body = serde_json::to_string(&repairs)?;
+        let req = Request::new_with_init(
+            &repairs_url(index),
+            &RequestInit {
+                method: Method::Post,
+                body: Some(body.as_str().into()),
+                ..Default::default()
+            },
+        )?;
+        let response = journal_stub(env, index)?.fetch_with_request(req).await?;
+        match response.status_code() {
+            200 => Ok(()),
+            status => Err(Error::RustError(format!("journal returned {}", status))),
+        }
+    };
+    if let Err(err) = sent.await {
+        edge_log!(
+            console_warn,
+            "Journal",
+            index,
+            "Failed to record shard repairs, fsck will find them: {}",
+            err
+        );
+    }
+}
+
+/// Report the keyword shards a request's document writes failed, for the journal to
+/// write again, see [`crate::data::shard_repair`]. Like usage, it's sent after the
+/// response.
+pub fn record_shard_repairs(
+    ctx: &RouteContext<Context>,
+    index: &str,
+    repairs: PendingShardRepairs,
+) {
+    if repairs.is_empty() {
+        return;
+    }
+    let (env, index) = (ctx.env.clone(), index.to_string());
+    ctx.data
+        .wait_until(async move { send_shard_repairs(&env, &index, repairs).await });
+}
+
+fn activity_url(index: &str) -> String {
+    format!("https://journal/activity/{}", index)
+}
+
+async fn send_activity(env: &Env, index: &str, events: Vec<ActivityEvent>) {
+    let sent = async {
+        let body = serde_json::to_string(&events)?;
+        let req = Request::new_with_init(
+            &activity_url(index),
+            &RequestInit {
+                method: Method::Post,
+                body: Some(body.as_str().into()),
+                ..Default::default()
+            },
+        )?;
+        let response = journal_stub(env, index)?.fetch_with_request(req).await?;
+        match response.status_code() {
+            200 => Ok(()),
+            status => Err(Error::RustError(format!("journal returned {}", status))),
+        }
+    };
+    if let Err(err) = sent.await {
+        edge_log!(
+            console_warn,
+            "Journal",
+            index,
+            "Failed to record activity: {}",
+            err
+        );
+    }
+}
+
+/// Add the document changes a request made to the index's activity log, see
+/// [`crate::durable::activity`]. Like usage, they're sent after the response.
+pub fn record_activity(ctx: &RouteContext<Context>, index: &str, events: Vec<ActivityEvent>) {
+    if events.is_empty() {
+        return;
+    }
+    let (env, index) = (ctx.env.clone(), index.to_string());
+    ctx.data
+        .wait_until(async move { send_activity(&env, &index, events).await });
+}
+
+/// The index's recent document changes matching `filter`, newest first
+pub async fn read_activity(
+    env: &Env,
+    index: &str,
+    filter: &ActivityFilter,
+) -> Result<Vec<ActivityEvent>> {
+    let mut url = Url::parse(&activity_url(index))?;
+    {
+        let mut params = url.query_pairs_mut();
+        if let Some(since) = filter.since {
+            params.append_pair("since", &since.to_string());
+        }
+        if let Some(doc_id) = &filter.doc_id {
+            params.append_pair("doc_id", doc_id);
+        }
+        if let Some(limit) = filter.limit {
+            params.append_pair("limit", &limit.to_string());
+        }
+    }
+    let mut response = journal_stub(env, index)?
+        .fetch_with_str(url.as_str())
+        .await?;
+    if response.status_code() != 200 {
+        return Err(Error::RustError(format!(
+            "journal returned {}: {}",
+            response.status_code(),
+            response.text().await.unwrap_or_default()
+        )));
+    }
+    response.json::<Vec<ActivityEvent>>().await
+}
+
+/// The index's exact document count, including changes not yet flushed to KV
+pub async fn read_exact_docs_count(env: &Env, index: &str) -> Result<u32> {
+    let stub = journal_stub(env, index)?;
+    read_counter_response(stub.fetch_with_str(&counter_url(index)).await?).await
+}
+
+/// Holds each index's document count. Document handlers send it increments and
+/// decrements, and an alarm writes the count to the index document in KV, so
+/// concurrent writers in any colo never race to rewrite `docs_count` themselves.
+/// The index's usage reports and keyword changes are collected and written the same
+/// way, as are the keyword shards its document writes failed, and its recent
+/// document changes are kept for `GET /:index/activity`.
 #[durable_object]
 pub struct Journal {
     state: State,
-    journal: JournalData,
-    sql: SqlStorage,
+    store: Arc<KvStore>,
+    /// How many document changes the activity log keeps
+    activity_retention: usize,
+    /// How many days the keyword trend counters are kept
+    trend_retention_days: usize,
+    /// The shard count of indexes that don't record one
+    n_shards: u32,
 }
 
-static TABLE_NAME_JOURNAL_DATA: &str = "journal_data";
-static DATA_KEY_JOURNALDATA: &str = "journal";
-
 impl Journal {
-    fn get_journal(sql: &SqlStorage) -> JournalData {
-        #[derive(Deserialize)]
-        struct JournalDataRow {
-            value: String,
-        }
-        // Select the data if it exists
-        let result: Vec<JournalDataRow> = sql
-            .exec("SELECT * FROM ?", vec![TABLE_NAME_JOURNAL_DATA.into()])
-            .unwrap()
-            .to_array::<JournalDataRow>()
-            .unwrap();
-
-        if result.len() == 0 {
-            panic!("No journal data found");
-        }
-
-        // Get the string value in the first row and first column
-        let journal_raw_data = &result[0].value;
-        let journal = serde_json::from_str::<JournalData>(journal_raw_data).unwrap();
-
-        journal
-    }
-
-    fn save_journal(&mut self) -> worker::Result<()> {
-        self.sql
-            .exec(
-                "INSERT OR REPLACE INTO ?(value) VALUES (?) WHERE key='?';",
-                vec![
-                    TABLE_NAME_JOURNAL_DATA.into(),
-                    serde_json::to_string(&self.journal)
-                        .unwrap()
-                        .to_string()
-                        .as_str()
-                        .into(),
-                    DATA_KEY_JOURNALDATA.into(),
-                ],
-            )
-            .unwrap();
+    pub const BINDING_ID: &'static str = "JOURNAL";
+
+    /// The stored counter, or a newly seeded one and `true`
+    async fn load_counter(&self, index: &str) -> (DocsCounter, bool) {
+        match self.state.storage().get::<DocsCounter>(COUNTER_KEY).await {
+            Ok(counter) => (counter, false),
+            Err(_) => (DocsCounter::seed(&self.store, index).await, true),
+        }
+    }
+
+    /// Add a usage report to the pending days, written to KV by the next alarm
+    async fn add_usage(&self, index: &str, mut req: Request) -> Result<Response> {
+        let Ok(delta) = req.json::<UsageDelta>().await else {
+            return json_error(
+                400,
+                ErrorCode::InvalidRequest,
+                "Body must be a usage report",
+            );
+        };
+        let storage = self.state.storage();
+        let mut pending = storage
+            .get::<PendingUsage>(USAGE_KEY)
+            .await
+            .unwrap_or_else(|_| PendingUsage::new(index));
+        pending.record(now_ms(), &delta);
+        storage.put(USAGE_KEY, &pending).await?;
+        if storage.get_alarm().await?.is_none() {
+            storage.set_alarm(FLUSH_DELAY).await?;
+        }
+        Response::ok("Recorded")
+    }
+
+    /// Add reported keyword changes to the pending days, added to the trend counters
+    /// by the next alarm
+    async fn add_keyword_trends(&self, index: &str, mut req: Request) -> Result<Response> {
+        let Ok(deltas) = req.json::<KeywordDeltas>().await else {
+            return json_error(
+                400,
+                ErrorCode::InvalidRequest,
+                "Body must be an object of keyword changes",
+            );
+        };
+        let storage = self.state.storage();
+        let mut pending = storage
+            .get::<PendingTrends>(TRENDS_KEY)
+            .await
+            .unwrap_or_else(|_| PendingTrends::new(index));
+        pending.record(now_ms(), &deltas);
+        storage.put(TRENDS_KEY, &pending).await?;
+        if storage.get_alarm().await?.is_none() {
+            storage.set_alarm(FLUSH_DELAY).await?;
+        }
+        Response::ok("Recorded")
+    }
+
+    /// Add reported shard writes to those pending, written again by the next alarm
+    async fn add_shard_repairs(&self, index: &str, mut req: Request) -> Result<Response> {
+        let Ok(repairs) = req.json::<PendingShardRepairs>().await else {
+            return json_error(
+                400,
+                ErrorCode::InvalidRequest,
+                "Body must be the keywords of each document to repair",
+            );
+        };
+        let storage = self.state.storage();
+        let mut pending = storage
+            .get::<PendingShardRepairs>(REPAIRS_KEY)
+            .await
+            .unwrap_or_else(|_| PendingShardRepairs::new(index));
+        pending.merge(repairs);
+        storage.put(REPAIRS_KEY, &pending).await?;
+        if storage.get_alarm().await?.is_none() {
+            storage.set_alarm(FLUSH_DELAY).await?;
+        }
+        Response::ok("Recorded")
+    }
+
+    /// Add reported document changes to the activity log, or answer a query of it
+    async fn activity(&self, mut req: Request) -> Result<Response> {
+        let storage = self.state.storage();
+        let mut log = storage
+            .get::<ActivityLog>(ACTIVITY_KEY)
+            .await
+            .unwrap_or_default();
+        match req.method() {
+            Method::Post => {
+                let Ok(events) = req.json::<Vec<ActivityEvent>>().await else {
+                    return json_error(
+                        400,
+                        ErrorCode::InvalidRequest,
+                        "Body must be a list of activity events",
+                    );
+                };
+                log.record(events, self.activity_retention);
+                storage.put(ACTIVITY_KEY, &log).await?;
+                Response::ok("Recorded")
+            }
+            Method::Get => {
+                let Ok(filter) = req.query::<ActivityFilter>() else {
+                    return json_error(400, ErrorCode::InvalidRequest, "Invalid activity filter");
+                };
+                Response::from_json(&log.query(&filter))
+            }
+            _ => json_error(405, ErrorCode::MethodNotAllowed, "Method Not Allowed"),
+        }
+    }
+
+    /// Write the pending usage to KV. Reports arriving meanwhile start new pending
+    /// days, and the days a failure kept are merged back into them.
+    async fn flush_usage(&self) -> Result<()> {
+        let storage = self.state.storage();
+        let Ok(mut pending) = storage.get::<PendingUsage>(USAGE_KEY).await else {
+            return Ok(());
+        };
+        storage.delete(USAGE_KEY).await?;
+        if let Err(err) = pending.flush(&self.store).await {
+            let mut kept = storage
+                .get::<PendingUsage>(USAGE_KEY)
+                .await
+                .unwrap_or_else(|_| PendingUsage::new(&pending.index));
+            kept.merge(pending);
+            storage.put(USAGE_KEY, &kept).await?;
+            storage.set_alarm(FLUSH_DELAY).await?;
+            edge_log!(
+                console_warn,
+                "Journal",
+                (kept.index.as_str()),
+                "Failed to flush usage, retrying: {}",
+                err
+            );
+        }
         Ok(())
     }
 
-    fn create_table(sql: &SqlStorage) {
-        sql.exec(
-            &format!(
-                "CREATE TABLE IF NOT EXISTS {} (key TEXT PRIMARY KEY, value TEXT);",
-                TABLE_NAME_JOURNAL_DATA
-            ),
-            vec![],
-        )
-        .unwrap();
+    /// Add the pending keyword changes to the trend counters, at most
+    /// [`MAX_TREND_FLUSH_COUNTERS`] of them. Those left over or kept by a failure
+    /// are merged into the changes reported meanwhile, for the next alarm.
+    async fn flush_keyword_trends(&self) -> Result<()> {
+        let storage = self.state.storage();
+        let Ok(mut pending) = storage.get::<PendingTrends>(TRENDS_KEY).await else {
+            return Ok(());
+        };
+        storage.delete(TRENDS_KEY).await?;
+        let index = pending.index.clone();
+        let ttl_secs = self.trend_retention_days as u64 * 86_400;
+        let flushed = pending
+            .flush(&self.store, ttl_secs, MAX_TREND_FLUSH_COUNTERS)
+            .await;
+        if !pending.is_empty() {
+            let mut kept = storage
+                .get::<PendingTrends>(TRENDS_KEY)
+                .await
+                .unwrap_or_else(|_| PendingTrends::new(&index));
+            kept.merge(pending);
+            storage.put(TRENDS_KEY, &kept).await?;
+            storage.set_alarm(FLUSH_DELAY).await?;
+        }
+        if let Err(err) = flushed {
+            edge_log!(
+                console_warn,
+                "Journal",
+                (index.as_str()),
+                "Failed to flush keyword trends, retrying: {}",
+                err
+            );
+        }
+        Ok(())
     }
 
-    // Return the current time to maximum precision
-    pub fn now() -> u64 {
-        worker::Date::now().as_millis()
+    /// Write the pending shards again, those of at most
+    /// [`MAX_REPAIR_FLUSH_DOCUMENTS`] documents. Those left over or kept by a failure
+    /// are merged into the repairs reported meanwhile, for the next alarm.
+    async fn flush_shard_repairs(&self) -> Result<()> {
+        let storage = self.state.storage();
+        let Ok(mut pending) = storage.get::<PendingShardRepairs>(REPAIRS_KEY).await else {
+            return Ok(());
+        };
+        storage.delete(REPAIRS_KEY).await?;
+        let index = pending.index.clone();
+        let flushed = pending
+            .flush(
+                &self.store,
+                self.n_shards,
+                now_ms(),
+                MAX_REPAIR_FLUSH_DOCUMENTS,
+            )
+            .await;
+        if !pending.is_empty() {
+            let mut kept = storage
+                .get::<PendingShardRepairs>(REPAIRS_KEY)
+                .await
+                .unwrap_or_else(|_| PendingShardRepairs::new(&index));
+            kept.merge(pending);
+            storage.put(REPAIRS_KEY, &kept).await?;
+            storage.set_alarm(FLUSH_DELAY).await?;
+        }
+        if let Err(err) = flushed {
+            edge_log!(
+                console_warn,
+                "Journal",
+                (index.as_str()),
+                "Failed to repair keyword shards, retrying: {}",
+                err
+            );
+        }
+        Ok(())
     }
 }
 
 impl DurableObject for Journal {
-    fn new(state: State, _: Env) -> Self {
-        let sql = state.storage().sql();
-
-        Self::create_table(&sql);
-        let journal = Self::get_journal(&sql);
-
-        Self {
-            sql,
+    fn new(state: State, env: Env) -> Self {
+        crate::util::log::set_level_from_env(&env);
+        crate::util::concurrency::set_limit_from_env(&env);
+        let store = get_kv_data_store_from_env(&env);
+        let activity_retention = parse_env_usize(
+            &env,
+            ENV_VAR_ACTIVITY_RETENTION,
+            DEFAULT_ACTIVITY_RETENTION,
+            1..=MAX_ACTIVITY_RETENTION,
+        );
+        let trend_retention_days = parse_env_usize(
+            &env,
+            ENV_VAR_KEYWORD_TREND_RETENTION_DAYS,
+            DEFAULT_TREND_RETENTION_DAYS,
+            1..=MAX_TREND_RETENTION_DAYS,
+        );
+        Journal {
             state,
-            journal,
+            store,
+            activity_retention,
+            trend_retention_days,
+            n_shards: get_n_shards(&env),
         }
     }
 
-    async fn fetch(&self, req: Request) -> worker::Result<worker::Response> {
-        let doFullIndex = self.journal.last_index < (Self::now() - 1000);
-
+    async fn fetch(&self, mut req: Request) -> Result<Response> {
+        let path = req.path();
+        if let Some(index) = path.strip_prefix("/usage/") {
+            return match req.method() {
+                Method::Post => self.add_usage(index, req).await,
+                _ => json_error(405, ErrorCode::MethodNotAllowed, "Method Not Allowed"),
+            };
+        }
+        if let Some(index) = path.strip_prefix("/trends/") {
+            return match req.method() {
+                Method::Post => self.add_keyword_trends(index, req).await,
+                _ => json_error(405, ErrorCode::MethodNotAllowed, "Method Not Allowed"),
+            };
+        }
+        if let Some(index) = path.strip_prefix("/repairs/") {
+            return match req.method() {
+                Method::Post => self.add_shard_repairs(index, req).await,
+                _ => json_error(405, ErrorCode::MethodNotAllowed, "Method Not Allowed"),
+            };
+        }
+        if path.starts_with("/activity/") {
+            return self.activity(req).await;
+        }
+        let Some(index) = path.strip_prefix("/counter/").map(str::to_string) else {
+            return json_error(404, ErrorCode::NotFound, "Not Found");
+        };
+        let (mut counter, seeded) = self.load_counter(&index).await;
         match req.method() {
+            Method::Get => {}
             Method::Post => {
-                req.json::<Vec>().await.unwrap();
-
-                // Handle POST request
-                worker::Response::ok(r#"{"ok":true}"#)
+                let Ok(command) = req.json::<CounterCommand>().await else {
+                    return json_error(
+                        400,
+                        ErrorCode::InvalidRequest,
+                        "Body must be {\"delta\": ...}",
+                    );
+                };
+                // A seeded count listed the documents after this change was made
+                if !seeded {
+                    counter.apply(command.delta);
+                }
+                let storage = self.state.storage();
+                storage.put(COUNTER_KEY, &counter).await?;
+                if counter.needs_flush() && storage.get_alarm().await?.is_none() {
+                    storage.set_alarm(FLUSH_DELAY).await?;
+                }
             }
-            _ => worker::Response::error("Method Not Allowed", 405),
+            _ => return json_error(405, ErrorCode::MethodNotAllowed, "Method Not Allowed"),
         }
+        Response::from_json(&CounterResponse {
+            index,
+            docs_count: counter.count,
+        })
     }
 
     async fn alarm(&self) -> Result<Response> {
-        // Handle alarm event
-        worker::Response::ok("Alarm triggered")
+        self.flush_usage().await?;
+        self.flush_keyword_trends().await?;
+        self.flush_shard_repairs().await?;
+        let storage = self.state.storage();
+        let Ok(mut counter) = storage.get::<DocsCounter>(COUNTER_KEY).await else {
+            return Response::ok("Nothing to flush");
+        };
+        if let Err(err) = counter.flush(&self.store).await {
+            // Try again later rather than losing the change
+            storage.set_alarm(FLUSH_DELAY).await?;
+            return Err(Error::RustError(format!(
+                "Failed to flush docs_count: {}",
+                err
+            )));
+        }
+        storage.put(COUNTER_KEY, &counter).await?;
+        let indexer = IndexManager::new(&self.store);
+        if counter.needs_flush() && indexer.is_frozen(&counter.index).await.unwrap_or(false) {
+            storage.set_alarm(FLUSH_DELAY).await?;
+            return Response::ok("Paused while the index is frozen");
+        }
+        Response::ok("Flushed")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+
+    use super::*;
+    use crate::data::{
+        document::testing::index_text,
+        index::{get_index_key, IndexDocument},
+        storage::memory::MemoryStorage,
+    };
+
+    fn stored_index(store: &MemoryStorage) -> IndexDocument {
+        block_on(IndexDocument::read(&get_index_key("idx"), store)).unwrap()
+    }
+
+    #[test]
+    fn test_seed_counts_listed_documents() {
+        let store = MemoryStorage::default();
+        block_on(IndexManager::new(&store).create_index("idx", None, None)).unwrap();
+        index_text(&store, "idx", "doc1", "Ocean tides rise.");
+        index_text(&store, "idx", "doc2", "Glaciers melt.");
+
+        let mut counter = block_on(DocsCounter::seed(&store, "idx"));
+        assert_eq!((counter.count, counter.flushed), (2, Some(0)));
+        assert!(block_on(counter.flush(&store)).unwrap());
+        assert_eq!(stored_index(&store).docs_count, 2);
+    }
+
+    #[test]
+    fn test_interleaved_changes_flush_once() {
+        let store = MemoryStorage::default();
+        block_on(IndexManager::new(&store).create_index("idx", None, None)).unwrap();
+        let generation = stored_index(&store).generation;
+
+        let mut counter = block_on(DocsCounter::seed(&store, "idx"));
+        assert_eq!(counter.count, 0);
+        assert!(!counter.needs_flush());
+
+        // Creations and deletions from several writers arrive interleaved
+        for delta in [1, 1, -1, 1, 1, -1, 1] {
+            counter.apply(delta);
+        }
+        assert_eq!(counter.count, 3);
+        let puts = store.counts().puts;
+        assert!(block_on(counter.flush(&store)).unwrap());
+        assert_eq!(store.counts().puts, puts + 1);
+
+        let index = stored_index(&store);
+        assert_eq!(index.docs_count, 3);
+        assert_eq!(index.generation, generation + 1);
+
+        // Nothing changed since, so the next alarm doesn't write
+        assert!(!block_on(counter.flush(&store)).unwrap());
+        assert_eq!(store.counts().puts, puts + 1);
+
+        counter.apply(1);
+        assert!(block_on(counter.flush(&store)).unwrap());
+        assert_eq!(stored_index(&store).docs_count, 4);
+    }
+
+    #[test]
+    fn test_frozen_index_pauses_flushes() {
+        let store = MemoryStorage::default();
+        let manager = IndexManager::new(&store);
+        block_on(manager.create_index("journal-frozen", None, None)).unwrap();
+        let mut counter = block_on(DocsCounter::seed(&store, "journal-frozen"));
+        counter.apply(1);
+
+        block_on(manager.set_frozen("journal-frozen", true)).unwrap();
+        let puts = store.counts().puts;
+        assert!(!block_on(counter.flush(&store)).unwrap());
+        assert!(counter.needs_flush());
+        assert_eq!(store.counts().puts, puts);
+
+        block_on(manager.set_frozen("journal-frozen", false)).unwrap();
+        assert!(block_on(counter.flush(&store)).unwrap());
+        assert_eq!(
+            block_on(manager.read_index("journal-frozen"))
+                .unwrap()
+                .docs_count,
+            1
+        );
+    }
+
+    #[test]
+    fn test_counter_never_drops_below_zero() {
+        let store = MemoryStorage::default();
+        let mut counter = block_on(DocsCounter::seed(&store, "missing"));
+        assert_eq!(counter.apply(-2), 0);
+        assert_eq!(counter.apply(1), 1);
+
+        // The index was deleted before the flush
+        assert!(!block_on(counter.flush(&store)).unwrap());
+        assert!(store.keys().is_empty());
     }
 }
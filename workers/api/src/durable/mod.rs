@@ -1,6 +1,8 @@
 //! This module includes the implementation for the DO reader that lets us bypass
-//! the 1k OP limit for extremely large queries, or other indexing actions.
+//! the 1k OP limit for extremely large queries, or other indexing actions, and the
+//! journal that keeps each index's document count.
 
+pub mod activity;
+pub mod journal;
 pub mod reader;
-// pub mod journal;
-// pub mod journal_data;
+pub mod reader_cache;
@@ -1,27 +1,131 @@
-use std::sync::Arc;
+use std::{collections::BTreeMap, future::Future, sync::Arc};
 
-use futures::future::join_all;
 use worker::{kv::KvStore, *};
 
 use crate::{
-    data::{encoding::LengthPrefixed, keyword_shard::get_n_shards},
-    util::kv::get_kv_data_store_from_env,
+    data::{
+        codec::CodecSet,
+        encoding::FrameWriter,
+        keyword::{KeywordManager, StampedPostings},
+        keyword_shard::get_n_shards,
+    },
+    data::{ENV_VAR_READER_MAX_RESPONSE_BYTES, ENV_VAR_READER_UNIQUE_IDS},
+    http::{json_error, ErrorCode},
+    util::{
+        auth::is_truthy,
+        concurrency::{self, join_bounded},
+        env::parse_env_usize,
+        kv::get_kv_data_store_from_env,
+    },
 };
 
-trait DurableReaderInterface {
-    async fn get_documents(store: &KvStore, doc_ids: Vec<&str>) -> Vec<Vec<u8>>;
-    async fn get_keywords(store: &KvStore, keywords: Vec<&str>) -> Vec<Vec<u8>>;
+/// The body of a `POST /merged-keywords` request
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct MergedKeywordsRequest {
+    pub index: String,
+    pub keywords: Vec<String>,
+    /// The index's codec version, see [`crate::data::index::IndexDocument::codec_version`]
+    #[serde(default = "default_version")]
+    pub version: u8,
+    /// The index's recorded shard count, when its shard keys can be named rather than
+    /// listed, see [`KeywordManager::with_known_n_shards`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub n_shards: Option<u32>,
+    /// If-Shard-Newer-Than: the newest shard timestamp of each keyword whose postings
+    /// the caller already holds. Keywords none of whose shards were written since are
+    /// answered with an unchanged frame instead of their postings. Keywords aren't
+    /// safe in a header, so the values travel in the body.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub if_shard_newer_than: BTreeMap<String, u64>,
 }
 
-fn length_prefix_data(data: &[u8], output: &mut Vec<u8>) -> LengthPrefixed {
-    let size = data.len() as u32;
-    output.extend_from_slice(&size.to_le_bytes());
-    output.extend_from_slice(&data);
-    LengthPrefixed {
-        bytes: output.clone(),
+/// Workers from before index versions send none, and only had v1 indexes
+fn default_version() -> u8 {
+    crate::data::INDEX_VERSION_V1
+}
+
+/// The response header carrying how many shards a `/merged-keywords` request read
+pub static SHARD_READS_HEADER: &str = "X-Shard-Reads";
+
+/// The response header of a `/keywords` or `/documents` request that stopped at
+/// `READER_MAX_RESPONSE_BYTES`, naming the first key left out, form-urlencoded. The
+/// caller sends the keys from there again.
+pub static CONTINUE_FROM_HEADER: &str = "X-Continue-From";
+
+pub const DEFAULT_MAX_RESPONSE_BYTES: usize = 8 * 1024 * 1024;
+pub const MAX_RESPONSE_BYTES_RANGE: std::ops::RangeInclusive<usize> = 1024..=128 * 1024 * 1024;
+
+/// Read `keys` with `read` into a keyed container, marking missing keys and failed
+/// reads in their own frames, until the next frame would take it past `max_bytes`.
+/// Keys are read a round of [`concurrency::limit`] at a time, so no more values are
+/// held than might fit. The first frame is always written, however large, so each
+/// response makes progress. Returns the container and the first key left out.
+pub async fn read_keys_framed<F, Fut>(
+    keys: &[&str],
+    max_bytes: usize,
+    read: F,
+) -> (Vec<u8>, Option<String>)
+where
+    F: Fn(String) -> Fut,
+    Fut: Future<Output = std::result::Result<Option<Vec<u8>>, String>>,
+{
+    let mut writer = FrameWriter::new();
+    let mut written = 0;
+    for round in keys.chunks(concurrency::limit()) {
+        let reads = round.iter().map(|key| read(key.to_string()));
+        for (key, result) in round.iter().zip(join_bounded(reads).await) {
+            let payload_len = match &result {
+                Ok(Some(bytes)) => bytes.len(),
+                Ok(None) => 0,
+                Err(err) => err.len(),
+            };
+            if written > 0 && writer.byte_len() + writer.frame_len(key, payload_len) > max_bytes {
+                return (writer.finish(), Some(key.to_string()));
+            }
+            match result {
+                Ok(Some(bytes)) => writer.found(key, &bytes),
+                Ok(None) => writer.not_found(key),
+                Err(err) => writer.error(key, &err),
+            }
+            written += 1;
+        }
+    }
+    (writer.finish(), None)
+}
+
+/// [`read_keys_framed`] from KV, as the response to send back
+async fn respond_framed(store: &KvStore, keys: &[&str], max_bytes: usize) -> Result<Response> {
+    let (bytes, continue_from) = read_keys_framed(keys, max_bytes, |key| async move {
+        store
+            .get(&key)
+            .bytes()
+            .await
+            .map_err(|err| format!("{:?}", err))
+    })
+    .await;
+    let mut response = Response::from_bytes(bytes)?;
+    if let Some(key) = continue_from {
+        response
+            .headers_mut()
+            .set(CONTINUE_FROM_HEADER, &encode_continue_from(&key))?;
     }
+    Ok(response)
 }
-fn parse_body<'a>(body: &'a str) -> Vec<&'a str> {
+
+/// The [`CONTINUE_FROM_HEADER`] value naming `key`, which may hold any character
+pub fn encode_continue_from(key: &str) -> String {
+    url::form_urlencoded::byte_serialize(key.as_bytes()).collect()
+}
+
+/// The key a [`CONTINUE_FROM_HEADER`] value names
+pub fn decode_continue_from(value: &str) -> String {
+    url::form_urlencoded::parse(value.as_bytes())
+        .next()
+        .map(|(key, _)| key.into_owned())
+        .unwrap_or_default()
+}
+
+fn parse_body(body: &str) -> Vec<&str> {
     body.split(',').filter(|s| !s.trim().is_empty()).collect()
 }
 /// A hard limit for the maximum number of keywords that can be requested
@@ -31,64 +135,136 @@ pub fn get_keyword_limit(n_shards: u32) -> u32 {
     1_000u32 / n_shards
 }
 
+/// The maximum number of keywords accepted by a single batch keyword lookup,
+/// allowing up to 10 fully packed durable reader requests.
+pub fn get_batch_keyword_limit(n_shards: u32) -> u32 {
+    get_keyword_limit(n_shards) * 10
+}
+
 pub fn get_document_limit() -> u32 {
     990u32
 }
 
+/// The most indexes a detailed listing reads the index documents of, at most a few
+/// durable reader requests; longer listings only return names
+pub fn get_index_detail_limit() -> u32 {
+    get_document_limit() * 5
+}
+
+/// The maximum number of keywords merged by a single `/merged-keywords` request. Each
+/// keyword costs one list plus up to one read per shard inside the durable object.
+pub fn get_merged_keyword_limit(n_shards: u32) -> u32 {
+    (1_000u32 / (n_shards + 1)).max(1)
+}
+
+/// Frame the postings of every keyword, keyed by the keyword and stamped with its
+/// newest shard timestamp, or 0 when it has no shards. Keywords whose newest shard
+/// is no newer than `if_shard_newer_than` says the caller holds are marked unchanged.
+pub fn encode_merged_keywords(
+    merged: &[(String, StampedPostings)],
+    if_shard_newer_than: &BTreeMap<String, u64>,
+) -> Vec<u8> {
+    let mut writer = FrameWriter::stamped();
+    for (keyword, stamped) in merged.iter() {
+        match (stamped.newest_ts, if_shard_newer_than.get(keyword)) {
+            (Some(ts), Some(&held)) if ts <= held => writer.unchanged(keyword, ts),
+            (ts, _) => {
+                let json = serde_json::to_vec(&stamped.postings)
+                    .expect("merged keyword data is serializable");
+                writer.found_at(keyword, ts.unwrap_or(0), &json);
+            }
+        }
+    }
+    writer.finish()
+}
+
 pub fn get_durable_reader_namespace(
     env: &worker::Env,
 ) -> std::result::Result<worker::ObjectNamespace, worker::Error> {
     env.durable_object(DurableReader::BINDING_ID)
 }
 
-#[durable_object]
-pub struct DurableReader {
-    store: Arc<worker::kv::KvStore>,
-    n_shards: u32,
-}
+/// The reader that lists of indexes, which belong to no one index, are read through
+pub const INDEX_LISTING_READER: &str = "indexes";
 
-impl DurableReader {
-    pub const BINDING_ID: &'static str = "READER";
+/// Which reader instance a request is sent to
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ReaderPlacement {
+    /// One reader per index, named after it, so its KV binding stays warm across
+    /// requests for the index
+    #[default]
+    PerIndex,
+    /// A new reader for every request, with `READER_UNIQUE_IDS=true`, spreading a
+    /// busy index's reads over many instances
+    Unique,
 }
 
-impl DurableReaderInterface for DurableReader {
-    async fn get_documents(store: &KvStore, doc_ids: Vec<&str>) -> Vec<Vec<u8>> {
-        let futures: Vec<_> = doc_ids
-            .iter()
-            .map(async |doc_kw| {
-                store
-                    .get(doc_kw)
-                    .bytes()
-                    .await
-                    .unwrap_or(Some(vec![]))
-                    .unwrap()
-            })
-            .collect();
+impl ReaderPlacement {
+    pub fn parse(unique_ids: Option<&str>) -> ReaderPlacement {
+        match unique_ids.is_some_and(is_truthy) {
+            true => ReaderPlacement::Unique,
+            false => ReaderPlacement::PerIndex,
+        }
+    }
 
-        join_all(futures).await
+    pub fn from_env(env: &Env) -> ReaderPlacement {
+        let unique_ids = env
+            .var(ENV_VAR_READER_UNIQUE_IDS)
+            .ok()
+            .map(|v| v.to_string());
+        ReaderPlacement::parse(unique_ids.as_deref())
     }
 
-    async fn get_keywords(store: &KvStore, keywords: Vec<&str>) -> Vec<Vec<u8>> {
-        let keyword_data_futures: Vec<_> = keywords
-            .iter()
-            .map(async |kv_id| store.get(kv_id).bytes().await)
-            .collect();
+    /// The name of the reader requests about `index` go to, `None` for a new one
+    pub fn reader_name(self, index: &str) -> Option<&str> {
+        match self {
+            ReaderPlacement::PerIndex => Some(index),
+            ReaderPlacement::Unique => None,
+        }
+    }
 
-        join_all(keyword_data_futures)
-            .await
-            .into_iter()
-            .filter_map(|res| res.ok().flatten())
-            .collect::<Vec<Vec<u8>>>()
+    /// The ID of the reader requests about `index` go to
+    pub fn reader_id<'n>(
+        self,
+        namespace: &'n ObjectNamespace,
+        index: &str,
+    ) -> Result<ObjectId<'n>> {
+        match self.reader_name(index) {
+            Some(name) => namespace.id_from_name(name),
+            None => namespace.unique_id(),
+        }
     }
 }
 
+#[durable_object]
+pub struct DurableReader {
+    store: Arc<worker::kv::KvStore>,
+    n_shards: u32,
+    /// The most bytes a `/keywords` or `/documents` response holds, from
+    /// `READER_MAX_RESPONSE_BYTES`
+    max_response_bytes: usize,
+}
+
+impl DurableReader {
+    pub const BINDING_ID: &'static str = "READER";
+}
+
 impl DurableObject for DurableReader {
     fn new(_state: State, env: Env) -> Self {
+        crate::util::log::set_level_from_env(&env);
+        crate::util::concurrency::set_limit_from_env(&env);
         let n_shards = get_n_shards(&env);
         let store = get_kv_data_store_from_env(&env);
+        let max_response_bytes = parse_env_usize(
+            &env,
+            ENV_VAR_READER_MAX_RESPONSE_BYTES,
+            DEFAULT_MAX_RESPONSE_BYTES,
+            MAX_RESPONSE_BYTES_RANGE,
+        );
         DurableReader {
-            store: store,
+            store,
             n_shards,
+            max_response_bytes,
         }
     }
 
@@ -99,58 +275,243 @@ impl DurableObject for DurableReader {
                     let text = req.text().await.unwrap();
                     let entries = parse_body(text.as_str());
                     if entries.len() as u32 > get_keyword_limit(self.n_shards) {
-                        return Response::error(
-                            &format!(
+                        return json_error(
+                            400,
+                            ErrorCode::InvalidRequest,
+                            format!(
                                 "Too many keywords requested. Current limit: {}",
                                 get_keyword_limit(self.n_shards)
                             ),
-                            400,
                         );
                     } else if entries.is_empty() {
-                        return Response::error("No keywords provided", 400);
+                        return json_error(400, ErrorCode::InvalidRequest, "No keywords provided");
                     }
 
-                    let keyword_docs = Self::get_keywords(&self.store, entries).await;
-                    let body_sizes = keyword_docs.iter().map(|b| b.len() as u32).sum::<u32>();
-                    let mut output: Vec<u8> =
-                        Vec::with_capacity((4 * keyword_docs.len()) + body_sizes as usize);
-                    for doc in keyword_docs.iter() {
-                        length_prefix_data(doc.as_slice(), &mut output);
-                    }
-                    return Response::from_bytes(output);
+                    respond_framed(&self.store, &entries, self.max_response_bytes).await
                 }
                 "/documents" => {
                     let mut req = req;
                     let text = req.text().await.unwrap();
                     let entries = parse_body(text.as_str());
                     if entries.len() as u32 > get_keyword_limit(self.n_shards) {
-                        return Response::error(
-                            &format!(
+                        return json_error(
+                            400,
+                            ErrorCode::InvalidRequest,
+                            format!(
                                 "Too many document IDs requested. Current limit: {}",
                                 get_keyword_limit(self.n_shards)
                             ),
-                            400,
                         );
                     } else if entries.is_empty() {
-                        return Response::error("No document IDs provided", 400);
+                        return json_error(
+                            400,
+                            ErrorCode::InvalidRequest,
+                            "No document IDs provided",
+                        );
                     }
 
-                    let doc_bodies = Self::get_documents(&self.store, entries).await;
-                    let body_sizes = doc_bodies.iter().map(|b| b.len() as u32).sum::<u32>();
-                    let mut output: Vec<u8> =
-                        Vec::with_capacity((doc_bodies.len() * 4) + body_sizes as usize);
-                    for lp in doc_bodies.iter() {
-                        length_prefix_data(lp, &mut output);
-                    }
-                    return Response::from_bytes(output);
+                    respond_framed(&self.store, &entries, self.max_response_bytes).await
                 }
-                _ => {
-                    return Response::error("Method Not Allowed", 405);
+                "/merged-keywords" => {
+                    let Ok(request) = req.json::<MergedKeywordsRequest>().await else {
+                        return json_error(
+                            400,
+                            ErrorCode::InvalidRequest,
+                            "Body must be {\"index\": ..., \"keywords\": [...]}",
+                        );
+                    };
+                    let limit = get_merged_keyword_limit(request.n_shards.unwrap_or(self.n_shards));
+                    if request.keywords.len() as u32 > limit {
+                        return json_error(
+                            400,
+                            ErrorCode::InvalidRequest,
+                            format!("Too many keywords requested. Current limit: {}", limit),
+                        );
+                    } else if request.keywords.is_empty() {
+                        return json_error(400, ErrorCode::InvalidRequest, "No keywords provided");
+                    }
+
+                    let codecs = match CodecSet::for_version(request.version) {
+                        Ok(codecs) => codecs,
+                        Err(err) => {
+                            return json_error(
+                                426,
+                                ErrorCode::UnsupportedIndexVersion,
+                                err.to_string(),
+                            )
+                        }
+                    };
+                    let mut manager =
+                        KeywordManager::direct(request.index, self.n_shards, &self.store)
+                            .with_codecs(codecs);
+                    if let Some(n_shards) = request.n_shards {
+                        manager = manager.with_known_n_shards(n_shards);
+                    }
+                    let (mut merged, shard_reads) = match manager
+                        .merge_many_keyword_shards_stamped(request.keywords.clone())
+                        .await
+                    {
+                        Ok(result) => result,
+                        Err(err) => {
+                            return json_error(
+                                500,
+                                ErrorCode::InternalError,
+                                format!("Failed to merge keywords: {}", err),
+                            )
+                        }
+                    };
+
+                    // Answer in request order, so callers can zip the result with their keywords
+                    let ordered: Vec<(String, StampedPostings)> = request
+                        .keywords
+                        .into_iter()
+                        .filter_map(|keyword| {
+                            let postings = merged.remove(&keyword)?;
+                            Some((keyword, postings))
+                        })
+                        .collect();
+                    let mut response = Response::from_bytes(encode_merged_keywords(
+                        &ordered,
+                        &request.if_shard_newer_than,
+                    ))?;
+                    response
+                        .headers_mut()
+                        .set(SHARD_READS_HEADER, &shard_reads.to_string())?;
+                    Ok(response)
                 }
+                _ => json_error(405, ErrorCode::MethodNotAllowed, "Method Not Allowed"),
             },
-            _ => {
-                return Response::error("Method Not Allowed", 405);
-            }
+            _ => json_error(405, ErrorCode::MethodNotAllowed, "Method Not Allowed"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::{
+        encoding::{read_length_prefixed, read_length_prefixed_stamped, EncodingError},
+        keyword::MergedKeywordData,
+    };
+
+    fn stamped(postings: MergedKeywordData, newest_ts: Option<u64>) -> StampedPostings {
+        StampedPostings {
+            postings,
+            newest_ts,
         }
     }
+
+    #[test]
+    fn test_reader_placement() {
+        assert_eq!(ReaderPlacement::parse(None), ReaderPlacement::PerIndex);
+        assert_eq!(
+            ReaderPlacement::parse(Some("false")),
+            ReaderPlacement::PerIndex
+        );
+        assert_eq!(
+            ReaderPlacement::parse(Some("true")),
+            ReaderPlacement::Unique
+        );
+        assert_eq!(ReaderPlacement::PerIndex.reader_name("docs"), Some("docs"));
+        assert_eq!(
+            ReaderPlacement::PerIndex.reader_name(INDEX_LISTING_READER),
+            Some("indexes")
+        );
+        assert_eq!(ReaderPlacement::Unique.reader_name("docs"), None);
+    }
+
+    #[test]
+    fn test_merged_keywords_framing_round_trips() {
+        let merged = vec![
+            (
+                "ocean".to_string(),
+                stamped(
+                    vec![("doc1".to_string(), 0.9), ("doc2".to_string(), 0.25)],
+                    Some(5),
+                ),
+            ),
+            ("missing".to_string(), stamped(vec![], None)),
+            (
+                "tide, \"pool\"".to_string(),
+                stamped(vec![("doc3".to_string(), 1.0)], Some(7)),
+            ),
+        ];
+        let bytes = encode_merged_keywords(&merged, &BTreeMap::new());
+        let decoded: Vec<(String, MergedKeywordData)> =
+            read_length_prefixed::<MergedKeywordData>(&bytes)
+                .into_iter()
+                .map(|(keyword, postings)| (keyword, postings.unwrap()))
+                .collect();
+        let postings: Vec<(String, MergedKeywordData)> = merged
+            .iter()
+            .map(|(keyword, stamped)| (keyword.clone(), stamped.postings.clone()))
+            .collect();
+        assert_eq!(decoded, postings);
+        let stamps: Vec<Option<u64>> = read_length_prefixed_stamped::<MergedKeywordData>(&bytes)
+            .into_iter()
+            .map(|(_, ts, _)| ts)
+            .collect();
+        assert_eq!(stamps, vec![Some(5), Some(0), Some(7)]);
+    }
+
+    #[test]
+    fn test_merged_keywords_held_by_the_caller_are_unchanged() {
+        let merged = vec![
+            (
+                "ocean".to_string(),
+                stamped(vec![("doc1".into(), 0.9)], Some(5)),
+            ),
+            (
+                "tide".to_string(),
+                stamped(vec![("doc2".into(), 0.4)], Some(9)),
+            ),
+            ("missing".to_string(), stamped(vec![], None)),
+        ];
+        let held = BTreeMap::from([
+            ("ocean".to_string(), 5),
+            ("tide".to_string(), 8),
+            ("missing".to_string(), 3),
+        ]);
+        let decoded = read_length_prefixed_stamped::<MergedKeywordData>(&encode_merged_keywords(
+            &merged, &held,
+        ));
+        assert_eq!(
+            decoded,
+            vec![
+                ("ocean".into(), Some(5), Err(EncodingError::Unchanged)),
+                ("tide".into(), Some(9), Ok(vec![("doc2".into(), 0.4)])),
+                ("missing".into(), Some(0), Ok(vec![])),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_merged_keywords_request_without_validators() {
+        // Requests from workers without a cache leave the field out
+        let request: MergedKeywordsRequest =
+            serde_json::from_str(r#"{"index":"idx","keywords":["ocean"]}"#).unwrap();
+        assert!(request.if_shard_newer_than.is_empty());
+        let body = serde_json::to_string(&request).unwrap();
+        assert!(!body.contains("if_shard_newer_than"));
+    }
+
+    #[test]
+    fn test_continue_from_round_trips() {
+        for key in [
+            "idx:doc:abc",
+            "idx:kw:café au lait:3",
+            "idx:kw:a+b%2C=c&d:0",
+        ] {
+            let encoded = encode_continue_from(key);
+            assert!(encoded.is_ascii());
+            assert_eq!(decode_continue_from(&encoded), key);
+        }
+    }
+
+    #[test]
+    fn test_merged_keyword_limit() {
+        assert_eq!(get_merged_keyword_limit(48), 20);
+        assert_eq!(get_merged_keyword_limit(2), 333);
+        assert_eq!(get_merged_keyword_limit(10_000), 1);
+    }
 }
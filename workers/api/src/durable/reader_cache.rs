@@ -0,0 +1,248 @@
+//! The merged postings the durable reader last answered with, kept by each api
+//! worker isolate between requests. Cached keywords are sent with the newest
+//! timestamp of the shards they were merged from, see
+//! [`MergedKeywordsRequest::if_shard_newer_than`], and the reader only sends back
+//! the postings of those with a shard written since.
+//!
+//! Shard timestamps come from the worker clock of each write, so a cached keyword
+//! is only as fresh as those clocks are ordered: a write stamped no later than the
+//! keyword's newest shard, like a second write within the same millisecond, leaves
+//! the entry stale until the keyword is written again.
+//!
+//! [`MergedKeywordsRequest::if_shard_newer_than`]: crate::durable::reader::MergedKeywordsRequest::if_shard_newer_than
+
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashMap, VecDeque},
+};
+
+use crate::data::{
+    encoding::{EncodingError, StampedFrame},
+    keyword::{MergedKeywordData, StampedPostings},
+};
+
+/// The most keywords an isolate keeps, dropping the oldest first
+pub const READER_CACHE_CAPACITY: usize = 512;
+
+/// Keywords with more postings than this aren't kept, so a few common keywords
+/// can't take up the isolate's memory
+pub const MAX_CACHED_POSTINGS: usize = 5_000;
+
+/// An index, its codec version and a keyword
+type CacheKey = (String, u8, String);
+
+#[derive(Debug, Default)]
+pub struct ReaderCache {
+    entries: HashMap<CacheKey, StampedPostings>,
+    /// Keys in the order they were first cached
+    order: VecDeque<CacheKey>,
+}
+
+impl ReaderCache {
+    /// Copies of the cached postings of `keywords`, keyed by keyword
+    pub fn snapshot(
+        &self,
+        index: &str,
+        version: u8,
+        keywords: &[String],
+    ) -> HashMap<String, StampedPostings> {
+        keywords
+            .iter()
+            .filter_map(|keyword| {
+                let key = (index.to_string(), version, keyword.clone());
+                Some((keyword.clone(), self.entries.get(&key)?.clone()))
+            })
+            .collect()
+    }
+
+    /// Keep `merged`, replacing what was cached for the same keywords. Keywords
+    /// without shards, or with too many postings, are dropped instead.
+    pub fn store(&mut self, index: &str, version: u8, merged: &HashMap<String, StampedPostings>) {
+        for (keyword, stamped) in merged {
+            let key = (index.to_string(), version, keyword.clone());
+            if stamped.newest_ts.is_none() || stamped.postings.len() > MAX_CACHED_POSTINGS {
+                self.entries.remove(&key);
+                continue;
+            }
+            if self.entries.insert(key.clone(), stamped.clone()).is_none() {
+                self.order.push_back(key);
+            }
+        }
+        while self.entries.len() > READER_CACHE_CAPACITY {
+            match self.order.pop_front() {
+                Some(oldest) => self.entries.remove(&oldest),
+                None => break,
+            };
+        }
+        // Drop the keys of entries removed above
+        let entries = &self.entries;
+        self.order.retain(|key| entries.contains_key(key));
+    }
+}
+
+thread_local! {
+    static CACHE: RefCell<ReaderCache> = RefCell::new(ReaderCache::default());
+}
+
+/// [`ReaderCache::snapshot`] of this isolate's cache
+pub fn snapshot(index: &str, version: u8, keywords: &[String]) -> HashMap<String, StampedPostings> {
+    CACHE.with(|cache| cache.borrow().snapshot(index, version, keywords))
+}
+
+/// [`ReaderCache::store`] into this isolate's cache
+pub fn store(index: &str, version: u8, merged: &HashMap<String, StampedPostings>) {
+    CACHE.with(|cache| cache.borrow_mut().store(index, version, merged));
+}
+
+/// The newest shard timestamp of every keyword in `held`, to send as
+/// `if_shard_newer_than`
+pub fn validators(held: &HashMap<String, StampedPostings>) -> BTreeMap<String, u64> {
+    held.iter()
+        .filter_map(|(keyword, stamped)| Some((keyword.clone(), stamped.newest_ts?)))
+        .collect()
+}
+
+/// The postings of every frame the durable reader answered with, taking those it
+/// marked unchanged from `held`. Keywords stamped 0 have no shards.
+pub fn resolve(
+    held: &HashMap<String, StampedPostings>,
+    frames: Vec<StampedFrame<MergedKeywordData>>,
+) -> Result<HashMap<String, StampedPostings>, EncodingError> {
+    let mut merged = HashMap::new();
+    for (keyword, ts, postings) in frames {
+        let stamped = match postings {
+            Ok(postings) => StampedPostings {
+                postings,
+                newest_ts: ts.filter(|ts| *ts > 0),
+            },
+            Err(EncodingError::NotFound) => StampedPostings::default(),
+            Err(EncodingError::Unchanged) => match held.get(&keyword) {
+                Some(stamped) => stamped.clone(),
+                None => return Err(EncodingError::Unchanged),
+            },
+            Err(err) => return Err(err),
+        };
+        merged.insert(keyword, stamped);
+    }
+    Ok(merged)
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+
+    use super::*;
+    use crate::{
+        data::{
+            encoding::read_length_prefixed_stamped,
+            keyword::KeywordManager,
+            keyword_shard::{testing::seed_postings, ShardWriteBatch},
+            storage::memory::MemoryStorage,
+        },
+        durable::reader::encode_merged_keywords,
+    };
+
+    const N_SHARDS: u32 = 4;
+
+    /// One `/merged-keywords` exchange: what the durable reader would answer given the
+    /// cache's validators, how many keywords it marked unchanged, and the postings
+    /// the caller ends up with
+    fn exchange(
+        store: &MemoryStorage,
+        cache: &mut ReaderCache,
+        keywords: &[&str],
+    ) -> (HashMap<String, StampedPostings>, usize) {
+        let keywords: Vec<String> = keywords.iter().map(|kw| kw.to_string()).collect();
+        let held = cache.snapshot("idx", 1, &keywords);
+
+        let manager = KeywordManager::direct("idx".into(), N_SHARDS, store);
+        let (mut merged, _) =
+            block_on(manager.merge_many_keyword_shards_stamped(keywords.clone())).unwrap();
+        let ordered: Vec<(String, StampedPostings)> = keywords
+            .iter()
+            .map(|kw| (kw.clone(), merged.remove(kw).unwrap()))
+            .collect();
+        let bytes = encode_merged_keywords(&ordered, &validators(&held));
+
+        let frames = read_length_prefixed_stamped::<MergedKeywordData>(&bytes);
+        let unchanged = frames
+            .iter()
+            .filter(|(_, _, postings)| *postings == Err(EncodingError::Unchanged))
+            .count();
+        let resolved = resolve(&held, frames).unwrap();
+        cache.store("idx", 1, &resolved);
+        (resolved, unchanged)
+    }
+
+    #[test]
+    fn test_bumped_shards_invalidate_cached_postings() {
+        let store = MemoryStorage::default();
+        seed_postings(
+            &store,
+            "idx",
+            N_SHARDS,
+            "ocean",
+            &[("doc1", 0.5), ("doc2", 0.7)],
+        );
+        seed_postings(&store, "idx", N_SHARDS, "storm", &[("doc3", 0.9)]);
+        let mut cache = ReaderCache::default();
+        let keywords = ["ocean", "storm", "missing"];
+
+        let (first, unchanged) = exchange(&store, &mut cache, &keywords);
+        assert_eq!(unchanged, 0);
+        assert_eq!(first["ocean"].newest_ts, Some(1));
+        assert_eq!(first["missing"], StampedPostings::default());
+        // Keywords without shards have nothing to validate against
+        assert_eq!(cache.entries.len(), 2);
+
+        let (second, unchanged) = exchange(&store, &mut cache, &keywords);
+        assert_eq!(unchanged, 2);
+        assert_eq!(second, first);
+
+        // A later write to one of ocean's shards moves its timestamp on
+        let mut batch = ShardWriteBatch::new("idx", "doc4", N_SHARDS);
+        batch.upsert("ocean", 0.9);
+        for (_, result) in block_on(batch.execute(&store, 2)) {
+            result.unwrap();
+        }
+        let (third, unchanged) = exchange(&store, &mut cache, &keywords);
+        assert_eq!(unchanged, 1);
+        assert_eq!(third["ocean"].newest_ts, Some(2));
+        assert_eq!(third["ocean"].postings[0], ("doc4".to_string(), 0.9));
+        assert_eq!(third["storm"], first["storm"]);
+        assert_eq!(exchange(&store, &mut cache, &keywords).1, 2);
+    }
+
+    #[test]
+    fn test_unchanged_frames_need_a_held_copy() {
+        let frames = vec![("ocean".to_string(), Some(4), Err(EncodingError::Unchanged))];
+        assert_eq!(
+            resolve(&HashMap::new(), frames),
+            Err(EncodingError::Unchanged)
+        );
+    }
+
+    #[test]
+    fn test_capacity_drops_the_oldest_keywords() {
+        let mut cache = ReaderCache::default();
+        let stamped = |n: usize| StampedPostings {
+            postings: vec![("doc".to_string(), 0.5); n],
+            newest_ts: Some(1),
+        };
+        for i in 0..READER_CACHE_CAPACITY + 3 {
+            cache.store("idx", 1, &HashMap::from([(format!("kw{}", i), stamped(1))]));
+        }
+        assert_eq!(cache.entries.len(), READER_CACHE_CAPACITY);
+        assert!(cache.snapshot("idx", 1, &["kw2".into()]).is_empty());
+        assert_eq!(cache.snapshot("idx", 1, &["kw3".into()]).len(), 1);
+        // Versions are cached apart, and huge keywords not at all
+        assert!(cache.snapshot("idx", 2, &["kw3".into()]).is_empty());
+        cache.store(
+            "idx",
+            1,
+            &HashMap::from([("kw3".to_string(), stamped(MAX_CACHED_POSTINGS + 1))]),
+        );
+        assert!(cache.snapshot("idx", 1, &["kw3".into()]).is_empty());
+        assert_eq!(cache.order.len(), cache.entries.len());
+    }
+}
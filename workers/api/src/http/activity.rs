@@ -0,0 +1,112 @@
+use serde::Serialize;
+use worker::{Context, Request, Response, Result, RouteContext};
+
+use crate::{
+    durable::{
+        activity::{ActivityEvent, ActivityFilter, MAX_ACTIVITY_RETENTION},
+        journal::read_activity,
+    },
+    http::{check_index, json_error, ErrorCode, Rejection},
+    util::kv::get_kv_data_store,
+};
+
+#[derive(serde::Deserialize, Default)]
+pub struct ActivityParams {
+    since: Option<u64>,
+    limit: Option<usize>,
+    doc_id: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct ActivityResponse {
+    pub index: String,
+    /// Newest first
+    pub events: Vec<ActivityEvent>,
+}
+
+/// Which of the index's recent changes to return
+pub fn parse_activity_filter(
+    params: ActivityParams,
+) -> std::result::Result<ActivityFilter, Rejection> {
+    if let Some(limit) = params.limit {
+        if !(1..=MAX_ACTIVITY_RETENTION).contains(&limit) {
+            return Err(Rejection::new(
+                400,
+                ErrorCode::InvalidRequest,
+                format!("limit must be between 1 and {}", MAX_ACTIVITY_RETENTION),
+            ));
+        }
+    }
+    Ok(ActivityFilter {
+        since: params.since,
+        doc_id: params.doc_id,
+        limit: params.limit,
+    })
+}
+
+/// `GET /:index/activity`: the index's recent document changes, kept by its journal
+pub async fn handle_activity(req: Request, ctx: RouteContext<Context>) -> Result<Response> {
+    let Some(index) = ctx.param("index") else {
+        return json_error(400, ErrorCode::MissingParameter, "Missing index name");
+    };
+    let Ok(params) = req.query::<ActivityParams>() else {
+        return json_error(400, ErrorCode::InvalidRequest, "Invalid query parameters");
+    };
+    let filter = match parse_activity_filter(params) {
+        Ok(filter) => filter,
+        Err(rejection) => return rejection.into_response(),
+    };
+
+    let store = get_kv_data_store(&ctx);
+    if let Some(response) = check_index(&store, index, false).await? {
+        return Ok(response);
+    }
+    match read_activity(&ctx.env, index, &filter).await {
+        Ok(events) => Response::from_json(&ActivityResponse {
+            index: index.to_string(),
+            events,
+        }),
+        Err(err) => json_error(
+            500,
+            ErrorCode::InternalError,
+            format!("Failed to read the index's activity: {}", err),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_activity_filter() {
+        let params = ActivityParams {
+            since: Some(1000),
+            limit: Some(10),
+            doc_id: Some("doc1".into()),
+        };
+        assert_eq!(
+            parse_activity_filter(params).unwrap(),
+            ActivityFilter {
+                since: Some(1000),
+                doc_id: Some("doc1".into()),
+                limit: Some(10),
+            }
+        );
+        assert_eq!(
+            parse_activity_filter(ActivityParams::default()).unwrap(),
+            ActivityFilter::default()
+        );
+        for limit in [0, MAX_ACTIVITY_RETENTION + 1] {
+            let params = ActivityParams {
+                limit: Some(limit),
+                ..ActivityParams::default()
+            };
+            let rejection = parse_activity_filter(params).unwrap_err();
+            assert_eq!(
+                (rejection.status, rejection.code),
+                (400, ErrorCode::InvalidRequest)
+            );
+        }
+    }
+}
@@ -1,202 +1,1484 @@
+use std::str::FromStr;
+
 use lingua::IsoCode639_1;
-use worker::{Request, Response, Result, RouteContext};
+use url::form_urlencoded;
+use worker::{Context, Env, Request, Response, Result, RouteContext};
+
+use crate::{
+    data::{
+        deletion::{delete_document, DeleteOptions, DeleteOutcome},
+        document::{
+            document_kv_key, get_max_document_bytes, Document, LangDetection, UpdateOutcome,
+        },
+        index_manager::IndexManager,
+        keyword::KeywordManager,
+        keyword_shard::get_n_shards,
+        listing::list_documents,
+        shard_repair::PendingShardRepairs,
+        storage::{PageCursor, Storage},
+        trend::deletion_deltas,
+        usage::UsageDelta,
+        DataStoreError,
+    },
+    durable::{
+        activity::ActivityEvent,
+        journal::{
+            read_exact_docs_count, record_activity, record_keyword_trends, record_shard_repairs,
+            record_usage, send_docs_delta,
+        },
+    },
+    edge_log,
+    http::{
+        allows_missing_index, check_index, check_writable_index, decoded_param, etag,
+        head_response, index_codecs, json_error, json_length, not_modified, with_etag, ErrorCode,
+        Rejection,
+    },
+    util::{
+        kv::{get_body_bucket, get_kv_data_store},
+        sanitize::{check_body, looks_binary, sanitize_body},
+        time::now_ms,
+    },
+};
+
+/// The error message for a body of `len` bytes, when that is over `limit`
+fn document_size_error(len: usize, limit: usize) -> Option<String> {
+    (len > limit).then(|| {
+        format!(
+            "Document body is {} bytes, larger than the {} byte limit (MAX_DOCUMENT_BYTES)",
+            len, limit
+        )
+    })
+}
+
+/// The body size the client declared in `Content-Length`, if any
+fn declared_content_length(req: &Request) -> Option<usize> {
+    req.headers()
+        .get("Content-Length")
+        .ok()
+        .flatten()
+        .and_then(|value| value.trim().parse().ok())
+}
 
-use crate::{data::document::Document, http::ErrorResponse, util::kv::get_kv_data_store};
+/// The ETag of a document's current revision, which changes on every write
+fn document_etag(document: &Document) -> String {
+    etag(&document.get_uuid(), document.revision)
+}
+
+/// Returns a 413 response when `len` is over the configured document size limit
+fn reject_oversized(len: Option<usize>, limit: usize) -> Result<Option<Response>> {
+    match len.and_then(|len| document_size_error(len, limit)) {
+        Some(error) => json_error(413, ErrorCode::PayloadTooLarge, error).map(Some),
+        None => Ok(None),
+    }
+}
 
-pub async fn handle_get_document(_req: Request, ctx: RouteContext<()>) -> Result<Response> {
+pub async fn handle_get_document(req: Request, ctx: RouteContext<Context>) -> Result<Response> {
     if let Some(index) = ctx.param("index") {
-        if let Some(doc_id) = ctx.param("id") {
+        if let Some(doc_id) = decoded_param(&ctx, "id") {
+            if let Err(rejection) = check_document_id(&doc_id) {
+                return rejection.into_response();
+            }
             let store = get_kv_data_store(&ctx);
-            if let Ok(document) = Document::from_remote(&store, index, doc_id.to_string()).await {
-                return Response::from_json(&document);
+            if let Some(response) = check_index(&store, index, allows_missing_index(&req)).await? {
+                return Ok(response);
+            }
+            if let Ok(mut document) = Document::from_remote(&store, index, doc_id.to_string()).await
+            {
+                let etag = document_etag(&document);
+                if let Some(response) = not_modified(&req, &etag)? {
+                    return Ok(response);
+                }
+                if let Some(bodies) = get_body_bucket(&ctx.env) {
+                    if let Err(err) = document.load_body(&bodies).await {
+                        return json_error(
+                            500,
+                            ErrorCode::InternalError,
+                            format!("Failed to load document body: {}", err),
+                        );
+                    }
+                }
+                return with_etag(Response::from_json(&document)?, &etag);
             } else {
-                return Response::error(
-                    ErrorResponse {
-                        error: "Document not found".into(),
-                    },
-                    404,
-                );
+                return json_error(404, ErrorCode::DocumentNotFound, "Document not found");
             }
         }
-        return Response::error(
-            ErrorResponse {
-                error: "Missing document ID".into(),
-            },
+        return json_error(400, ErrorCode::MissingParameter, "Missing document ID");
+    }
+
+    json_error(400, ErrorCode::MissingParameter, "Missing index name")
+}
+
+/// `HEAD /:index/doc/:id`: the status and headers `GET` would send, from the same KV
+/// lookup. An offloaded body isn't fetched from R2, so its `Content-Length` is left
+/// out.
+pub async fn handle_head_document(req: Request, ctx: RouteContext<Context>) -> Result<Response> {
+    let (Some(index), Some(doc_id)) = (ctx.param("index"), decoded_param(&ctx, "id")) else {
+        return json_error(
             400,
+            ErrorCode::MissingParameter,
+            "Missing index or document ID",
         );
+    };
+    if let Err(rejection) = check_document_id(&doc_id) {
+        return rejection.into_response();
+    }
+    let store = get_kv_data_store(&ctx);
+    if let Some(response) = check_index(&store, index, allows_missing_index(&req)).await? {
+        return Ok(response);
     }
+    let Ok(document) = Document::from_remote(&store, index, doc_id.to_string()).await else {
+        return json_error(404, ErrorCode::DocumentNotFound, "Document not found");
+    };
 
-    return Response::error(
-        ErrorResponse {
-            error: "Missing index name".into(),
-        },
-        400,
-    );
+    let etag = document_etag(&document);
+    if let Some(response) = not_modified(&req, &etag)? {
+        return Ok(response);
+    }
+    let content_length = match document.body_ref {
+        Some(_) => None,
+        None => json_length(&document),
+    };
+    head_response(&etag, content_length)
 }
 
 #[derive(serde::Deserialize)]
-struct AddDocumentQueryParams {
-    lang: Option<IsoCode639_1>,
+struct DocumentKeywordsParams {
+    verify: Option<bool>,
+}
+
+/// `GET /:index/doc/:id/keywords`: the document's stored keywords and the shard each
+/// posting lives in. With `verify=true`, the shards are read to flag postings that
+/// are missing, stale, or left behind for keywords the document no longer has.
+pub async fn handle_document_keywords(
+    req: Request,
+    ctx: RouteContext<Context>,
+) -> Result<Response> {
+    let (Some(index), Some(doc_id)) = (ctx.param("index"), decoded_param(&ctx, "id")) else {
+        return json_error(
+            400,
+            ErrorCode::MissingParameter,
+            "Missing index or document ID",
+        );
+    };
+    if let Err(rejection) = check_document_id(&doc_id) {
+        return rejection.into_response();
+    }
+    let Ok(params) = req.query::<DocumentKeywordsParams>() else {
+        return json_error(
+            400,
+            ErrorCode::InvalidRequest,
+            "verify must be true or false",
+        );
+    };
+
+    let store = get_kv_data_store(&ctx);
+    if let Some(response) = check_index(&store, index, allows_missing_index(&req)).await? {
+        return Ok(response);
+    }
+    let Ok(document) = Document::from_remote(&store, index, doc_id.to_string()).await else {
+        return json_error(404, ErrorCode::DocumentNotFound, "Document not found");
+    };
+
+    let n_shards = IndexManager::new(&store)
+        .shard_count(index, get_n_shards(&ctx.env))
+        .await;
+    let codecs = match index_codecs(&store, index).await {
+        Ok(codecs) => codecs,
+        Err(rejection) => return rejection.into_response(),
+    };
+    let manager = KeywordManager::new(index.into(), &ctx.env, &store)
+        .with_n_shards(n_shards)
+        .with_codecs(codecs);
+    match manager
+        .inspect_document(&document, params.verify.unwrap_or(false))
+        .await
+    {
+        Ok(inspected) => Response::from_json(&inspected),
+        Err(err) => json_error(
+            500,
+            ErrorCode::InternalError,
+            format!("Failed to inspect document keywords: {}", err),
+        ),
+    }
+}
+
+#[derive(serde::Deserialize, Default)]
+pub struct ListDocumentsParams {
+    cursor: Option<String>,
+    updated_since: Option<u64>,
+}
+
+/// Where to resume the listing
+pub fn parse_list_documents_params(
+    params: &ListDocumentsParams,
+) -> std::result::Result<Option<PageCursor>, Rejection> {
+    match params.cursor.as_deref() {
+        None | Some("") => Ok(None),
+        Some(cursor) => cursor
+            .parse()
+            .map(Some)
+            .map_err(|err| Rejection::new(400, ErrorCode::InvalidRequest, err)),
+    }
+}
+
+/// `GET /:index/docs`: the next page of the index's documents, without their bodies.
+/// With `updated_since`, only documents updated at or after that epoch millisecond
+/// are kept. Keep passing back `cursor` until it comes back `null`.
+pub async fn handle_list_documents(req: Request, ctx: RouteContext<Context>) -> Result<Response> {
+    let Some(index) = ctx.param("index") else {
+        return json_error(400, ErrorCode::MissingParameter, "Missing index name");
+    };
+    let Ok(params) = req.query::<ListDocumentsParams>() else {
+        return json_error(
+            400,
+            ErrorCode::InvalidRequest,
+            "updated_since must be a timestamp in milliseconds",
+        );
+    };
+    let cursor = match parse_list_documents_params(&params) {
+        Ok(cursor) => cursor,
+        Err(rejection) => return rejection.into_response(),
+    };
+
+    let store = get_kv_data_store(&ctx);
+    if let Some(response) = check_index(&store, index, allows_missing_index(&req)).await? {
+        return Ok(response);
+    }
+    match list_documents(&store, index, cursor, params.updated_since).await {
+        Ok(page) => Response::from_json(&page),
+        Err(err) => Rejection::from_store_error(err, ErrorCode::DocumentNotFound).into_response(),
+    }
+}
+
+/// The validated query string of an update-document request
+#[derive(Debug, PartialEq, Default)]
+struct UpdateDocumentQueryParams {
     format: Option<String>,
+    /// Reject a body that would need sanitizing instead of cleaning it
+    strict: bool,
 }
 
-#[derive(serde::Serialize)]
-struct UpdateDocumentResponse {
-    pub updated: bool,
-    pub scores: Vec<(String, f64)>,
+#[derive(serde::Serialize, Debug, PartialEq)]
+struct FailedKeyword {
+    pub keyword: String,
+    pub error: String,
+}
+
+/// What adding or updating a document changed, returned by both instead of the
+/// document itself
+#[derive(serde::Serialize, Debug, PartialEq)]
+struct AddDocumentResponse {
+    pub id: String,
     pub revision: u32,
+    pub lang: Option<IsoCode639_1>,
+    /// Set when `lang` was detected rather than given
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lang_confidence: Option<f64>,
+    pub keywords_added: usize,
+    pub keywords_removed: usize,
+    pub keywords_total: usize,
+    /// Keywords left unindexed for scoring below the index's `min_keyword_score`
+    pub keywords_dropped_below_floor: usize,
+    /// The index's document count after the write, `null` if it couldn't be counted
+    pub index_docs_count: Option<u32>,
+    pub indexed_keywords: Vec<String>,
+    pub failed_keywords: Vec<FailedKeyword>,
+    /// Set when the body matched the stored revision, so nothing was written
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub unchanged: bool,
+    /// Set when control characters or invalid UTF-8 were cleaned from the body
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub sanitized: bool,
+}
+
+impl AddDocumentResponse {
+    fn new(
+        document: &Document,
+        outcome: UpdateOutcome,
+        index_docs_count: Option<u32>,
+    ) -> AddDocumentResponse {
+        AddDocumentResponse {
+            id: document.get_uuid(),
+            revision: outcome.revision,
+            lang: document.lang,
+            lang_confidence: document.lang_confidence,
+            keywords_added: outcome.keywords_added,
+            keywords_removed: outcome.keywords_removed,
+            keywords_total: document.keywords.as_ref().map_or(0, Vec::len),
+            keywords_dropped_below_floor: outcome.keywords_dropped_below_floor,
+            index_docs_count,
+            unchanged: outcome.unchanged,
+            sanitized: document.sanitized,
+            indexed_keywords: outcome.indexed_keywords,
+            failed_keywords: outcome
+                .failed_keywords
+                .into_iter()
+                .map(|(keyword, err)| FailedKeyword {
+                    keyword,
+                    error: err.to_string(),
+                })
+                .collect(),
+        }
+    }
+
+    /// `complete` when every keyword shard was written, or 207 when the document was
+    /// written but some of its keyword shards weren't
+    fn status(&self, complete: u16) -> u16 {
+        match self.failed_keywords.is_empty() {
+            true => complete,
+            false => 207,
+        }
+    }
+}
+
+/// The index's document count from its journal, for reporting after an update
+async fn count_documents(env: &Env, index: &str) -> Option<u32> {
+    match read_exact_docs_count(env, index).await {
+        Ok(count) => Some(count),
+        Err(err) => {
+            edge_log!(
+                console_warn,
+                "Documents",
+                index,
+                "Failed to count documents: {}",
+                err
+            );
+            None
+        }
+    }
 }
 
-pub async fn handle_update_document(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
+pub async fn handle_update_document(
+    mut req: Request,
+    ctx: RouteContext<Context>,
+) -> Result<Response> {
     if let Some(index) = ctx.param("index") {
-        if let Some(doc_id) = ctx.param("id") {
+        if let Some(doc_id) = decoded_param(&ctx, "id") {
+            if let Err(rejection) = check_document_id(&doc_id) {
+                return rejection.into_response();
+            }
             let store = get_kv_data_store(&ctx);
-            let document_result = Document::from_remote(&store, index, doc_id.to_string()).await;
-            if document_result.is_err() {
-                return Response::error(
-                    ErrorResponse {
-                        error: "Document not found".into(),
-                    },
-                    404,
-                );
+            if let Some(response) =
+                check_writable_index(&store, index, allows_missing_index(&req)).await?
+            {
+                return Ok(response);
             }
 
-            let query = req.query::<AddDocumentQueryParams>()?;
-            let mut document = document_result.unwrap();
-            let document_body = req.text().await?;
+            // The document read here is the one rewritten, with the keywords it had
+            let mut document = match stored_document(&store, index, &doc_id).await {
+                Ok(document) => document,
+                Err(rejection) => return rejection.into_response(),
+            };
+
+            let max_bytes = get_max_document_bytes(&ctx.env);
+            if let Some(response) = reject_oversized(declared_content_length(&req), max_bytes)? {
+                return Ok(response);
+            }
+
+            let query = match parse_update_document(req.url()?.query()) {
+                Ok(query) => query,
+                Err(rejection) => return rejection.into_response(),
+            };
+            let format = match document_format(query.format, content_type(&req).as_deref()) {
+                Ok(format) => format,
+                Err(rejection) => return rejection.into_response(),
+            };
+            let binary = format.as_deref() == Some("binary");
+            let (document_body, sanitized) =
+                match check_document_body(req.bytes().await, max_bytes, binary, query.strict) {
+                    Ok(body) => body,
+                    Err(rejection) => return rejection.into_response(),
+                };
+            document.sanitized = sanitized;
             let env = &ctx.env;
-            let revision = document
-                .update(&store, env, document_body, query.format, false)
+            let outcome = match document
+                .update(
+                    &store,
+                    env,
+                    document_body,
+                    format,
+                    LangDetection::WhenMissing,
+                )
                 .await
-                .unwrap();
-
-            return Response::from_json(&UpdateDocumentResponse {
-                updated: true,
-                scores: document.keywords.unwrap(),
-                revision: revision,
-            });
-        }
-        return Response::error(
-            ErrorResponse {
-                error: "Missing document ID".into(),
-            },
-            400,
-        );
+            {
+                Ok(outcome) => outcome,
+                Err(err @ DataStoreError::InvalidFormat(_)) => {
+                    return json_error(
+                        400,
+                        ErrorCode::InvalidRequest,
+                        format!("Failed to update document: {}", err),
+                    )
+                }
+                Err(err @ DataStoreError::UnsupportedVersion(_)) => {
+                    return Rejection::from_store_error(err, ErrorCode::IndexNotFound)
+                        .into_response()
+                }
+                Err(err) => {
+                    return json_error(
+                        500,
+                        ErrorCode::InternalError,
+                        format!("Failed to update document: {}", err),
+                    )
+                }
+            };
+
+            // Rewriting a document as it's stored changes nothing worth recording
+            if !outcome.unchanged {
+                let updated = UsageDelta {
+                    docs_updated: 1,
+                    ..UsageDelta::default()
+                };
+                record_usage(&ctx, index, updated);
+                let event = ActivityEvent::written(&document.get_uuid(), false, &outcome, now_ms());
+                record_activity(&ctx, index, vec![event]);
+                record_keyword_trends(&ctx, index, outcome.keyword_deltas.clone());
+                record_shard_repairs(&ctx, index, shard_repairs(index, &document, &outcome));
+            }
+            let index_docs_count = count_documents(&ctx.env, index).await;
+            let response = AddDocumentResponse::new(&document, outcome, index_docs_count);
+            let status = response.status(200);
+            return Ok(Response::from_json(&response)?.with_status(status));
+        }
+        return json_error(400, ErrorCode::MissingParameter, "Missing document ID");
     }
 
-    return Response::error(
-        ErrorResponse {
-            error: "Missing index name".into(),
-        },
-        400,
-    );
+    json_error(400, ErrorCode::MissingParameter, "Missing index name")
 }
 
-pub async fn handle_add_document(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    if let Some(index) = ctx.param("index") {
-        let mut document: Document;
-        if let Some(id) = ctx.param("id") {
-            if !Document::is_valid_id(&id) {
-                return Response::error(
-                    ErrorResponse {
-                        error: "Invalid document ID format. Must match [a-zA-Z0-9-_]+".into(),
-                    },
-                    400,
-                );
+/// The keyword shards a write of `document` failed, for the journal to write again
+fn shard_repairs(index: &str, document: &Document, outcome: &UpdateOutcome) -> PendingShardRepairs {
+    let mut repairs = PendingShardRepairs::new(index);
+    repairs.record_outcome(&document.get_uuid(), outcome);
+    repairs
+}
+
+/// The stored document `doc_id`, or a 404 rejection when there is none
+async fn stored_document<S: Storage>(
+    store: &S,
+    index: &str,
+    doc_id: &str,
+) -> std::result::Result<Document, Rejection> {
+    match Document::from_remote(store, index, doc_id.to_string()).await {
+        Ok(document) => Ok(document),
+        Err(DataStoreError::NotFound(_)) => Err(Rejection::new(
+            404,
+            ErrorCode::DocumentNotFound,
+            "Document not found",
+        )),
+        Err(err) => Err(Rejection::from_store_error(
+            err,
+            ErrorCode::DocumentNotFound,
+        )),
+    }
+}
+
+/// A 409 rejection when a document with the custom `id` is already stored. A
+/// generated ID is a fresh UUID, so it isn't looked up.
+async fn check_new_document<S: Storage>(
+    store: &S,
+    index: &str,
+    id: Option<&String>,
+) -> std::result::Result<(), Rejection> {
+    let Some(id) = id else {
+        return Ok(());
+    };
+    match store.get(&document_kv_key(index, id)).await {
+        Ok(None) => Ok(()),
+        Ok(Some(_)) => Err(Rejection::new(
+            409,
+            ErrorCode::DocumentExists,
+            format!("Document '{}' already exists", id),
+        )),
+        Err(err) => Err(Rejection::from_store_error(
+            err,
+            ErrorCode::DocumentNotFound,
+        )),
+    }
+}
+
+/// The body formats keyword extraction understands. An `envelope` is a JSON object
+/// holding a text `body` and a `title`, see [`crate::data::document::DocumentEnvelope`].
+const DOCUMENT_FORMATS: [&str; 4] = ["text", "json", "binary", "envelope"];
+
+/// The validated inputs of an add-document request
+#[derive(Debug, PartialEq)]
+struct AddDocumentRequest {
+    index: String,
+    id: Option<String>,
+    /// Detected from the body when not given
+    lang: Option<IsoCode639_1>,
+    format: Option<String>,
+    /// `detect=false` gives documents without `lang` the index's default language
+    detection: LangDetection,
+    /// `strict=true` rejects a body that would need sanitizing
+    strict: bool,
+}
+
+/// Reject a route's document ID that doesn't match the ID grammar
+fn check_document_id(id: &str) -> std::result::Result<(), Rejection> {
+    match Document::is_valid_id(id) {
+        true => Ok(()),
+        false => Err(Rejection::new(
+            400,
+            ErrorCode::InvalidDocumentId,
+            Document::INVALID_ID_ERROR,
+        )),
+    }
+}
+
+/// Validate the route params and query string of an add-document request, before
+/// anything is read from the body or KV
+fn parse_add_document(
+    index: Option<&String>,
+    id: Option<&String>,
+    query: Option<&str>,
+) -> std::result::Result<AddDocumentRequest, Rejection> {
+    let index = index
+        .ok_or_else(|| Rejection::new(400, ErrorCode::MissingParameter, "Missing index name"))?;
+    if let Some(id) = id {
+        check_document_id(id)?;
+    }
+
+    let mut request = AddDocumentRequest {
+        index: index.clone(),
+        id: id.cloned(),
+        lang: None,
+        format: None,
+        detection: LangDetection::WhenMissing,
+        strict: false,
+    };
+    for (key, value) in form_urlencoded::parse(query.unwrap_or_default().as_bytes()) {
+        match key.as_ref() {
+            "lang" => {
+                let lang = IsoCode639_1::from_str(&value).map_err(|_| {
+                    Rejection::new(
+                        400,
+                        ErrorCode::InvalidRequest,
+                        format!("Unknown language '{}'", value),
+                    )
+                })?;
+                request.lang = Some(lang);
             }
-            document = Document::new_with_id(index, &id);
-        } else {
-            document = Document::new(index);
+            "detect" => {
+                request.detection = match value.parse::<bool>() {
+                    Ok(true) => LangDetection::WhenMissing,
+                    Ok(false) => LangDetection::Never,
+                    Err(_) => {
+                        return Err(Rejection::new(
+                            400,
+                            ErrorCode::InvalidRequest,
+                            format!("Invalid detect '{}', expected true or false", value),
+                        ))
+                    }
+                };
+            }
+            "strict" => request.strict = parse_strict(&value)?,
+            "format" => request.format = Some(parse_format(&value)?),
+            _ => {}
+        }
+    }
+    Ok(request)
+}
+
+/// Validate the query string of an update-document request, which takes the
+/// add-document `format` and `strict` params
+fn parse_update_document(
+    query: Option<&str>,
+) -> std::result::Result<UpdateDocumentQueryParams, Rejection> {
+    let mut params = UpdateDocumentQueryParams::default();
+    for (key, value) in form_urlencoded::parse(query.unwrap_or_default().as_bytes()) {
+        match key.as_ref() {
+            "strict" => params.strict = parse_strict(&value)?,
+            "format" => params.format = Some(parse_format(&value)?),
+            _ => {}
         }
+    }
+    Ok(params)
+}
 
-        if let Ok(document_body) = req.text().await {
-            let env = &ctx.env;
-            let store = get_kv_data_store(&ctx);
+fn parse_strict(value: &str) -> std::result::Result<bool, Rejection> {
+    value.parse::<bool>().map_err(|_| {
+        Rejection::new(
+            400,
+            ErrorCode::InvalidRequest,
+            format!("Invalid strict '{}', expected true or false", value),
+        )
+    })
+}
 
-            // See if the document exists already
-            let existing_doc = Document::from_remote(&store, index, document.get_uuid()).await;
-            if existing_doc.is_ok() {
-                return Response::error(
-                    ErrorResponse {
-                        error: "This document already exists".into(),
-                    },
-                    500,
-                );
-            }
+/// Reject a `format` that isn't one of [`DOCUMENT_FORMATS`]
+fn parse_format(value: &str) -> std::result::Result<String, Rejection> {
+    match DOCUMENT_FORMATS.contains(&value) {
+        true => Ok(value.to_string()),
+        false => Err(Rejection::new(
+            400,
+            ErrorCode::InvalidRequest,
+            format!(
+                "Unknown format '{}', expected one of {}",
+                value,
+                DOCUMENT_FORMATS.join(", ")
+            ),
+        )),
+    }
+}
 
-            let query = req.query::<AddDocumentQueryParams>()?;
-            document.set_language(query.lang.unwrap_or(IsoCode639_1::EN));
-            let document = document
-                .update(&store, env, document_body, query.format, false)
-                .await;
-
-            if document.is_err() {
-                return Response::error(
-                    ErrorResponse {
-                        error: format!("Failed to add document: {}", document.err().unwrap()),
-                    },
-                    500,
-                );
-            }
+/// The `Content-Type`s a document body can be sent as, and the format each implies
+const DOCUMENT_CONTENT_TYPES: [(&str, &str); 4] = [
+    ("text/plain", "text"),
+    ("text/html", "text"),
+    ("text/markdown", "text"),
+    ("application/json", "json"),
+];
 
-            return Response::from_json(&document.unwrap());
-        } else {
-            return Response::error(
-                ErrorResponse {
-                    error: "Invalid document format".into(),
-                },
-                400,
-            );
+/// The format of a document write: `format` when the request names one, otherwise
+/// the one its `Content-Type` implies. A `Content-Type` documents can't be sent as is
+/// refused with a 415 either way.
+fn document_format(
+    format: Option<String>,
+    content_type: Option<&str>,
+) -> std::result::Result<Option<String>, Rejection> {
+    let Some(media_type) = content_type
+        .and_then(|header| header.split(';').next())
+        .map(|media_type| media_type.trim().to_ascii_lowercase())
+        .filter(|media_type| !media_type.is_empty())
+    else {
+        return Ok(format);
+    };
+    match DOCUMENT_CONTENT_TYPES
+        .iter()
+        .find(|(name, _)| *name == media_type)
+    {
+        Some((_, implied)) => Ok(format.or_else(|| Some(implied.to_string()))),
+        None => {
+            let supported: Vec<&str> = DOCUMENT_CONTENT_TYPES
+                .iter()
+                .map(|(name, _)| *name)
+                .collect();
+            Err(Rejection::new(
+                415,
+                ErrorCode::UnsupportedMediaType,
+                format!(
+                    "Unsupported Content-Type '{}', expected one of {}",
+                    media_type,
+                    supported.join(", ")
+                ),
+            ))
         }
     }
-    return Response::from_bytes("Not implemented".into());
 }
 
-#[derive(serde::Serialize)]
+/// The `Content-Type` header of `req`, if it sent one
+fn content_type(req: &Request) -> Option<String> {
+    req.headers().get("Content-Type").ok().flatten()
+}
+
+/// The text of a body, sanitized, and whether sanitizing changed it. Rejects a body
+/// that couldn't be read or is over `limit` bytes, one that looks binary unless it's
+/// written as `binary`, and with `strict` one that would need sanitizing.
+fn check_document_body(
+    body: Result<Vec<u8>>,
+    limit: usize,
+    binary: bool,
+    strict: bool,
+) -> std::result::Result<(String, bool), Rejection> {
+    let body = body.map_err(|err| {
+        Rejection::new(
+            400,
+            ErrorCode::InvalidRequest,
+            format!("Unreadable document body: {}", err),
+        )
+    })?;
+    if let Some(error) = document_size_error(body.len(), limit) {
+        return Err(Rejection::new(413, ErrorCode::PayloadTooLarge, error));
+    }
+    if !binary && looks_binary(&body) {
+        return Err(Rejection::new(
+            415,
+            ErrorCode::UnsupportedMediaType,
+            "Document body looks like binary data, send it with format=binary to store it \
+             without keywords",
+        ));
+    }
+    match strict {
+        true => match check_body(&body) {
+            Ok(text) => Ok((text.to_string(), false)),
+            Err(error) => Err(Rejection::new(400, ErrorCode::InvalidRequest, error)),
+        },
+        false => Ok(sanitize_body(&body)),
+    }
+}
+
+pub async fn handle_add_document(mut req: Request, ctx: RouteContext<Context>) -> Result<Response> {
+    match add_document(&mut req, &ctx).await {
+        Ok(response) => Ok(response),
+        Err(rejection) => rejection.into_response(),
+    }
+}
+
+async fn add_document(
+    req: &mut Request,
+    ctx: &RouteContext<Context>,
+) -> std::result::Result<Response, Rejection> {
+    let url = req.url()?;
+    let params = parse_add_document(
+        ctx.param("index"),
+        decoded_param(ctx, "id").as_ref(),
+        url.query(),
+    )?;
+    let index = params.index.as_str();
+
+    let max_bytes = get_max_document_bytes(&ctx.env);
+    if let Some(error) =
+        declared_content_length(req).and_then(|len| document_size_error(len, max_bytes))
+    {
+        return Err(Rejection::new(413, ErrorCode::PayloadTooLarge, error));
+    }
+
+    let store = get_kv_data_store(ctx);
+    if let Some(response) = check_writable_index(&store, index, allows_missing_index(req)).await? {
+        return Ok(response);
+    }
+
+    let format = document_format(params.format, content_type(req).as_deref())?;
+    let binary = format.as_deref() == Some("binary");
+    let (document_body, sanitized) =
+        check_document_body(req.bytes().await, max_bytes, binary, params.strict)?;
+    let mut document = match &params.id {
+        Some(id) => Document::new_with_id(index, id),
+        None => Document::new(index),
+    };
+    document.sanitized = sanitized;
+
+    check_new_document(&store, index, params.id.as_ref()).await?;
+
+    if let Some(lang) = params.lang {
+        document.set_language(lang);
+    }
+    let outcome = match document
+        .update(&store, &ctx.env, document_body, format, params.detection)
+        .await
+    {
+        Ok(outcome) => outcome,
+        Err(err) => {
+            let (status, code) = match err {
+                DataStoreError::InvalidFormat(_) => (400, ErrorCode::InvalidRequest),
+                DataStoreError::UnsupportedVersion(_) => (426, ErrorCode::UnsupportedIndexVersion),
+                _ => (500, ErrorCode::InternalError),
+            };
+            return Err(Rejection::new(
+                status,
+                code,
+                format!("Failed to add document: {}", err),
+            ));
+        }
+    };
+
+    let added = UsageDelta {
+        docs_added: 1,
+        ..UsageDelta::default()
+    };
+    record_usage(ctx, index, added);
+    let event = ActivityEvent::written(&document.get_uuid(), true, &outcome, now_ms());
+    record_activity(ctx, index, vec![event]);
+    record_keyword_trends(ctx, index, outcome.keyword_deltas.clone());
+    record_shard_repairs(ctx, index, shard_repairs(index, &document, &outcome));
+    let index_docs_count = send_docs_delta(&ctx.env, index, 1).await;
+    let response = AddDocumentResponse::new(&document, outcome, index_docs_count);
+    let status = response.status(201);
+    let mut response = Response::from_json(&response)?.with_status(status);
+    response.headers_mut().set(
+        "Location",
+        &format!("/{}/doc/{}", index, document.get_uuid()),
+    )?;
+    Ok(response)
+}
+
+/// What deleting a document stripped from its keyword shards
+#[derive(serde::Serialize, Debug, PartialEq)]
 struct DeleteDocumentResponse {
+    /// False when a shard couldn't be written, leaving the document to be deleted
+    /// by repeating the request, or by fsck
     pub deleted: bool,
+    /// The revision the document had
+    pub revision: u32,
+    pub keywords_removed: usize,
+    pub shards_touched: usize,
+    pub failed_shards: Vec<FailedKeyword>,
 }
 
-pub async fn handle_delete_document(_req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    if let Some(index) = ctx.param("index") {
-        let mut document: Document;
-        if let Some(id) = ctx.param("id") {
-            if !Document::is_valid_id(&id) {
-                return Response::error(
-                    ErrorResponse {
-                        error: "Invalid document ID format. Must match [a-zA-Z0-9-_]+".into(),
-                    },
-                    400,
+impl DeleteDocumentResponse {
+    fn new(document: &Document, outcome: DeleteOutcome) -> DeleteDocumentResponse {
+        DeleteDocumentResponse {
+            deleted: outcome.is_complete(),
+            revision: document.revision,
+            keywords_removed: outcome.keywords_removed,
+            shards_touched: outcome.shards_touched,
+            failed_shards: outcome
+                .failed_shards
+                .into_iter()
+                .map(|(keyword, err)| FailedKeyword {
+                    keyword,
+                    error: err.to_string(),
+                })
+                .collect(),
+        }
+    }
+
+    /// 200 when the document is gone, or 207 when some of its shards weren't written
+    fn status(&self) -> u16 {
+        match self.deleted {
+            true => 200,
+            false => 207,
+        }
+    }
+}
+
+/// Delete the stored document `id` and strip its postings, or 404 when there's no
+/// such document
+async fn delete_stored_document<S: Storage>(
+    store: &S,
+    index: &str,
+    id: &str,
+    options: &DeleteOptions,
+) -> std::result::Result<(Document, DeleteDocumentResponse), Rejection> {
+    let existing = Document::from_remote(store, index, id.to_string())
+        .await
+        .map_err(|err| Rejection::from_store_error(err, ErrorCode::DocumentNotFound))?;
+    let outcome = delete_document(store, &existing, options)
+        .await
+        .map_err(|err| {
+            Rejection::new(
+                500,
+                ErrorCode::InternalError,
+                format!("Failed to delete document: {}", err),
+            )
+        })?;
+    let response = DeleteDocumentResponse::new(&existing, outcome);
+    Ok((existing, response))
+}
+
+pub async fn handle_delete_document(req: Request, ctx: RouteContext<Context>) -> Result<Response> {
+    let Some(index) = ctx.param("index") else {
+        return json_error(400, ErrorCode::MissingParameter, "Missing index name");
+    };
+    let Some(id) = decoded_param(&ctx, "id") else {
+        return json_error(400, ErrorCode::MissingParameter, "Missing document ID");
+    };
+    if let Err(rejection) = check_document_id(&id) {
+        return rejection.into_response();
+    }
+    let store = get_kv_data_store(&ctx);
+    if let Some(response) = check_writable_index(&store, index, allows_missing_index(&req)).await? {
+        return Ok(response);
+    }
+
+    let options = match DeleteOptions::for_index(&store, &ctx.env, index, now_ms()).await {
+        Ok(options) => options,
+        Err(err) => {
+            return json_error(
+                500,
+                ErrorCode::InternalError,
+                format!("Failed to delete document: {}", err),
+            )
+        }
+    };
+    let (existing, response) = match delete_stored_document(&store, index, &id, &options).await {
+        Ok(deleted) => deleted,
+        Err(rejection) => return rejection.into_response(),
+    };
+    // A delete that stopped part way is counted once it's finished
+    if response.deleted {
+        send_docs_delta(&ctx.env, index, -1).await;
+        let deleted = UsageDelta {
+            docs_deleted: 1,
+            ..UsageDelta::default()
+        };
+        record_usage(&ctx, index, deleted);
+        record_activity(
+            &ctx,
+            index,
+            vec![ActivityEvent::deleted(&existing, now_ms())],
+        );
+        record_keyword_trends(&ctx, index, deletion_deltas(&existing));
+        if let Some(bodies) = get_body_bucket(&ctx.env) {
+            if let Err(err) = existing.delete_body(&bodies).await {
+                edge_log!(
+                    console_warn,
+                    "Documents",
+                    index,
+                    "Failed to delete the offloaded body of document {}: {}",
+                    (existing.get_uuid()),
+                    err
                 );
             }
-            document = Document::new_with_id(index, &id);
-            let store = get_kv_data_store(&ctx);
-            if let Ok(_) = document.delete(&store).await {
-                return Response::from_json(&serde_json::json!({
-                    "deleted": true,
-                }));
-            } else {
-                return Response::error(
-                    ErrorResponse {
-                        error: "Failed to delete document".into(),
-                    },
-                    500,
-                );
+        }
+    }
+    let status = response.status();
+    Ok(Response::from_json(&response)?.with_status(status))
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+
+    use super::*;
+    use crate::{
+        data::{
+            bulk::BulkReader,
+            codec::CodecSet,
+            document::{testing::index_text, IndexingOptions},
+            keyword_shard::keyword_shard_prefix,
+            storage::memory::{MemoryStorage, OpCounts},
+            DEFAULT_N_SHARDS,
+        },
+        http::{
+            etag_listed,
+            search::{SearchFields, SearchResultRow},
+        },
+        util::http::decode_path_param,
+    };
+
+    fn parse(
+        index: Option<&str>,
+        id: Option<&str>,
+        query: Option<&str>,
+    ) -> std::result::Result<AddDocumentRequest, Rejection> {
+        parse_add_document(
+            index.map(String::from).as_ref(),
+            id.map(String::from).as_ref(),
+            query,
+        )
+    }
+
+    #[test]
+    fn test_parse_list_documents_params() {
+        assert_eq!(
+            parse_list_documents_params(&ListDocumentsParams::default()).unwrap(),
+            None
+        );
+        let params = ListDocumentsParams {
+            cursor: Some("2:idx:document:b".into()),
+            updated_since: Some(100),
+        };
+        let cursor = parse_list_documents_params(&params).unwrap().unwrap();
+        assert_eq!(cursor.offset, 2);
+
+        let params = ListDocumentsParams {
+            cursor: Some("bogus".into()),
+            updated_since: None,
+        };
+        let rejection = parse_list_documents_params(&params).unwrap_err();
+        assert_eq!(
+            (rejection.status, rejection.code),
+            (400, ErrorCode::InvalidRequest)
+        );
+    }
+
+    #[test]
+    fn test_add_document_defaults() {
+        let request = parse(Some("idx"), None, None).unwrap();
+        assert_eq!(
+            request,
+            AddDocumentRequest {
+                index: "idx".into(),
+                id: None,
+                lang: None,
+                format: None,
+                detection: LangDetection::WhenMissing,
+                strict: false,
             }
-        } else {
-            return Response::error(
-                ErrorResponse {
-                    error: "Missing document ID".into(),
-                },
+        );
+
+        let request = parse(
+            Some("idx"),
+            Some("doc-1"),
+            Some("lang=en&format=json&allow_missing=true"),
+        )
+        .unwrap();
+        assert_eq!(request.id.as_deref(), Some("doc-1"));
+        assert_eq!(request.lang, Some(IsoCode639_1::EN));
+        assert_eq!(request.format.as_deref(), Some("json"));
+
+        let request = parse(Some("idx"), None, Some("lang=en&detect=false")).unwrap();
+        assert_eq!(request.detection, LangDetection::Never);
+        assert!(
+            parse(Some("idx"), None, Some("strict=true"))
+                .unwrap()
+                .strict
+        );
+    }
+
+    #[test]
+    fn test_add_document_missing_index() {
+        let rejection = parse(None, None, None).unwrap_err();
+        assert_eq!(
+            (rejection.status, rejection.code),
+            (400, ErrorCode::MissingParameter)
+        );
+    }
+
+    #[test]
+    fn test_add_document_invalid_id() {
+        let rejection = parse(Some("idx"), Some("not/valid"), None).unwrap_err();
+        assert_eq!(rejection.status, 400);
+        assert!(rejection.error.contains("Invalid document ID"));
+    }
+
+    #[test]
+    fn test_document_id_grammar() {
+        let longest = "a".repeat(64);
+        for id in [
+            "a",
+            "doc-1",
+            "doc_1",
+            "-doc",
+            "doc_",
+            "A1-_b",
+            longest.as_str(),
+        ] {
+            assert!(Document::is_valid_id(id), "{}", id);
+        }
+        let too_long = "a".repeat(65);
+        for id in [
+            "",
+            "_",
+            "_rev",
+            "__doc",
+            "doc 1",
+            "doc.1",
+            "doc:1",
+            "doc/1",
+            "doc%1",
+            "café",
+            too_long.as_str(),
+        ] {
+            assert!(!Document::is_valid_id(id), "{}", id);
+        }
+        for _ in 0..200 {
+            let generated = Document::new("idx").get_uuid();
+            assert!(Document::is_valid_id(&generated), "{}", generated);
+        }
+    }
+
+    #[test]
+    fn test_read_routes_check_id() {
+        // GET, HEAD and the keywords route check the decoded ID like writes do
+        assert!(check_document_id(&decode_path_param("doc-1")).is_ok());
+        for id in ["%5Frev", "_rev", "doc%3A1", "doc%2F1"] {
+            let rejection = check_document_id(&decode_path_param(id)).unwrap_err();
+            assert_eq!(
+                (rejection.status, rejection.code),
+                (400, ErrorCode::InvalidDocumentId),
+                "{}",
+                id
+            );
+        }
+    }
+
+    #[test]
+    fn test_add_document_decoded_id() {
+        // The route decodes the ID once, so escapes are checked as the characters
+        let escaped = decode_path_param("doc%2D1");
+        assert_eq!(
+            parse(Some("idx"), Some(&escaped), None)
+                .unwrap()
+                .id
+                .as_deref(),
+            Some("doc-1")
+        );
+        for id in ["doc%201", "doc+1", "doc%251"] {
+            let rejection = parse(Some("idx"), Some(&decode_path_param(id)), None).unwrap_err();
+            assert_eq!(rejection.code, ErrorCode::InvalidDocumentId, "{}", id);
+        }
+    }
+
+    #[test]
+    fn test_add_document_invalid_params() {
+        let rejection = parse(Some("idx"), None, Some("lang=klingon")).unwrap_err();
+        assert_eq!(
+            rejection,
+            Rejection::new(400, ErrorCode::InvalidRequest, "Unknown language 'klingon'")
+        );
+
+        let rejection = parse(Some("idx"), None, Some("detect=maybe")).unwrap_err();
+        assert_eq!(rejection.status, 400);
+        let rejection = parse(Some("idx"), None, Some("strict=on")).unwrap_err();
+        assert_eq!(rejection.status, 400);
+
+        let rejection = parse(Some("idx"), None, Some("format=xml")).unwrap_err();
+        assert_eq!(rejection.status, 400);
+        assert!(rejection
+            .error
+            .contains("expected one of text, json, binary"));
+    }
+
+    #[test]
+    fn test_parse_update_document() {
+        assert_eq!(
+            parse_update_document(None).unwrap(),
+            UpdateDocumentQueryParams::default()
+        );
+        assert_eq!(
+            parse_update_document(Some("format=json&strict=true")).unwrap(),
+            UpdateDocumentQueryParams {
+                format: Some("json".into()),
+                strict: true,
+            }
+        );
+
+        let rejection = parse_update_document(Some("format=xml")).unwrap_err();
+        assert_eq!(
+            (rejection.status, rejection.code),
+            (400, ErrorCode::InvalidRequest)
+        );
+        assert!(rejection
+            .error
+            .contains("expected one of text, json, binary"));
+        let rejection = parse_update_document(Some("strict=on")).unwrap_err();
+        assert_eq!(
+            rejection,
+            Rejection::new(
                 400,
+                ErrorCode::InvalidRequest,
+                "Invalid strict 'on', expected true or false"
+            )
+        );
+    }
+
+    #[test]
+    fn test_add_document_body_checks() {
+        let check =
+            |body: &[u8], strict| check_document_body(Ok(body.to_vec()), 1024, false, strict);
+        let unreadable =
+            check_document_body(Err(worker::Error::BadEncoding), 1024, false, false).unwrap_err();
+        assert_eq!(unreadable.status, 400);
+
+        let oversized = check(&[b'x'; 2048], false).unwrap_err();
+        assert_eq!(oversized.status, 413);
+
+        assert_eq!(check(b"body", false).unwrap(), ("body".into(), false));
+        assert_eq!(check(b"body", true).unwrap(), ("body".into(), false));
+        assert_eq!(
+            check(b"Ocean\x00 tides\xff", false).unwrap(),
+            ("Ocean tides\u{fffd}".into(), true)
+        );
+        let rejection = check(b"bo\x00dy", true).unwrap_err();
+        assert_eq!(
+            (rejection.status, rejection.code),
+            (400, ErrorCode::InvalidRequest)
+        );
+        assert!(rejection.error.contains("U+0000 at byte 2"));
+
+        let gzip = b"\x1f\x8b\x08\x00\x00\x00\x00\x00\x00\x03\xcb\x4f\x4e\x4d\xcc\x03\x00";
+        let rejection = check(gzip, false).unwrap_err();
+        assert_eq!(
+            (rejection.status, rejection.code),
+            (415, ErrorCode::UnsupportedMediaType)
+        );
+        assert!(rejection.error.contains("format=binary"));
+        let (_, sanitized) = check_document_body(Ok(gzip.to_vec()), 1024, true, false).unwrap();
+        assert!(sanitized);
+    }
+
+    #[test]
+    fn test_document_format_from_content_type() {
+        let format = |explicit: Option<&str>, content_type: Option<&str>| {
+            document_format(explicit.map(String::from), content_type)
+        };
+        for (content_type, implied) in [
+            ("text/plain", "text"),
+            ("text/html; charset=utf-8", "text"),
+            ("text/markdown", "text"),
+            ("Application/JSON", "json"),
+        ] {
+            assert_eq!(
+                format(None, Some(content_type)).unwrap().as_deref(),
+                Some(implied),
+                "{}",
+                content_type
+            );
+        }
+        assert_eq!(format(None, None).unwrap(), None);
+        assert_eq!(format(None, Some("")).unwrap(), None);
+        // An explicit format wins over the one the header implies
+        assert_eq!(
+            format(Some("envelope"), Some("application/json"))
+                .unwrap()
+                .as_deref(),
+            Some("envelope")
+        );
+        assert_eq!(
+            format(Some("binary"), Some("text/plain"))
+                .unwrap()
+                .as_deref(),
+            Some("binary")
+        );
+
+        for content_type in [
+            "multipart/form-data; boundary=x",
+            "application/gzip",
+            "application/octet-stream",
+            "application/x-www-form-urlencoded",
+        ] {
+            let rejection = format(Some("text"), Some(content_type)).unwrap_err();
+            assert_eq!(
+                (rejection.status, rejection.code),
+                (415, ErrorCode::UnsupportedMediaType)
             );
+            assert!(rejection.error.contains("text/plain, text/html"));
         }
     }
 
-    return Response::error(
-        ErrorResponse {
-            error: "Missing index name".into(),
-        },
-        400,
-    );
+    #[test]
+    fn test_sanitized_bodies_are_stored_and_served_cleanly() {
+        let store = MemoryStorage::default();
+        // A NUL byte and a lone surrogate, as a broken scraper sends them
+        let fixture = b"Ocean\x00 tides \xed\xa0\x80rise at\x0b dawn.\n";
+        let (body, sanitized) =
+            check_document_body(Ok(fixture.to_vec()), 1024, false, false).unwrap();
+        let mut document = Document::new_with_id("idx", "doc1");
+        document.sanitized = sanitized;
+        block_on(document.update_with(
+            &store,
+            &IndexingOptions::default(),
+            body,
+            None,
+            LangDetection::WhenMissing,
+        ))
+        .unwrap();
+        let outcome = UpdateOutcome {
+            revision: document.revision,
+            keywords_added: 0,
+            keywords_removed: 0,
+            indexed_keywords: vec![],
+            failed_keywords: vec![],
+            unchanged: false,
+            keywords_dropped_below_floor: 0,
+            keyword_deltas: Default::default(),
+        };
+        let response = AddDocumentResponse::new(&document, outcome, None);
+        assert_eq!(serde_json::to_value(&response).unwrap()["sanitized"], true);
+
+        let stored = block_on(Document::from_remote(&store, "idx", "doc1".into())).unwrap();
+        let clean = "Ocean tides \u{fffd}\u{fffd}\u{fffd}rise at dawn.\n";
+        assert_eq!(stored.document_body.as_deref(), Some(clean));
+        assert!(stored.sanitized);
+
+        // Hydrated into a search result, the body serializes as it was stored
+        let key = document_kv_key("idx", &"doc1".to_string());
+        let bulk_reader = BulkReader::new(DEFAULT_N_SHARDS, &store, None);
+        let hydrated = block_on(bulk_reader.get_documents_kv_keys(vec![&key]));
+        let row: SearchResultRow = serde_json::from_value(serde_json::json!({
+            "doc_id": "doc1",
+            "score": 0.5,
+            "keywords": [],
+            "body": hydrated[0].as_ref().unwrap().document_body,
+        }))
+        .unwrap();
+        let fields = SearchFields::parse(Some("body")).unwrap();
+        let json = serde_json::to_string(&fields.shape(&row)).unwrap();
+        let served: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(served["body"], clean);
+    }
+
+    #[test]
+    fn test_document_size_error() {
+        assert_eq!(document_size_error(1024, 1024), None);
+        let error = document_size_error(30 * 1024 * 1024, 1024 * 1024).unwrap();
+        assert!(error.contains("31457280 bytes"));
+        assert!(error.contains("1048576 byte limit"));
+    }
+
+    #[test]
+    fn test_partial_update_response() {
+        let mut document = Document::new_with_id("idx", "doc1");
+        document.set_language(IsoCode639_1::EN);
+        document.keywords = Some(vec![
+            ("ocean".into(), 0.4.into()),
+            ("tide".into(), 0.2.into()),
+        ]);
+        let outcome = UpdateOutcome {
+            revision: 3,
+            keywords_added: 1,
+            keywords_removed: 2,
+            indexed_keywords: vec!["tide".into()],
+            failed_keywords: vec![(
+                "ocean".into(),
+                DataStoreError::NotFound("idx:kw:ocean:1".into()),
+            )],
+            unchanged: false,
+            keywords_dropped_below_floor: 4,
+            keyword_deltas: Default::default(),
+        };
+
+        let response = AddDocumentResponse::new(&document, outcome, Some(12));
+        assert_eq!(response.status(201), 207);
+        assert_eq!(
+            serde_json::to_value(&response).unwrap(),
+            serde_json::json!({
+                "id": "doc1",
+                "revision": 3,
+                "lang": "EN",
+                "keywords_added": 1,
+                "keywords_removed": 2,
+                "keywords_total": 2,
+                "keywords_dropped_below_floor": 4,
+                "index_docs_count": 12,
+                "indexed_keywords": ["tide"],
+                "failed_keywords": [{
+                    "keyword": "ocean",
+                    "error": "No KV key named 'idx:kw:ocean:1' was found"
+                }]
+            })
+        );
+
+        let complete = UpdateOutcome {
+            revision: 3,
+            keywords_added: 0,
+            keywords_removed: 0,
+            indexed_keywords: vec!["ocean".into(), "tide".into()],
+            failed_keywords: vec![],
+            unchanged: false,
+            keywords_dropped_below_floor: 0,
+            keyword_deltas: Default::default(),
+        };
+        let response = AddDocumentResponse::new(&document, complete, None);
+        assert_eq!(response.status(201), 201);
+        assert_eq!(response.status(200), 200);
+    }
+
+    #[test]
+    fn test_update_changes_etag() {
+        let store = MemoryStorage::default();
+        index_text(&store, "idx", "doc1", "Ocean tides.");
+        let mut document = block_on(Document::from_remote(&store, "idx", "doc1".into())).unwrap();
+        let before = document_etag(&document);
+        assert_eq!(before, "\"doc1-1\"");
+
+        block_on(document.update_with(
+            &store,
+            &IndexingOptions::default(),
+            "Mountain glaciers.".into(),
+            None,
+            LangDetection::WhenMissing,
+        ))
+        .unwrap();
+        let stored = block_on(Document::from_remote(&store, "idx", "doc1".into())).unwrap();
+        let after = document_etag(&stored);
+        assert_ne!(after, before);
+        // A cache holding the old revision no longer gets a 304
+        assert!(!etag_listed(&before, &after));
+    }
+
+    /// Rewrite `doc1` as the update handler does, returning the KV operations it took
+    fn rewrite(store: &MemoryStorage, body: &str) -> std::result::Result<OpCounts, Rejection> {
+        let before = store.counts();
+        let mut document = block_on(stored_document(store, "idx", "doc1"))?;
+        block_on(document.update_with(
+            store,
+            &IndexingOptions::default(),
+            body.into(),
+            Some("binary".into()),
+            LangDetection::Never,
+        ))
+        .unwrap();
+        let after = store.counts();
+        Ok(OpCounts {
+            gets: after.gets - before.gets,
+            puts: after.puts - before.puts,
+            ..OpCounts::default()
+        })
+    }
+
+    #[test]
+    fn test_update_reads_the_document_once() {
+        let store = MemoryStorage::default();
+        let missing = rewrite(&store, "bytes").unwrap_err();
+        assert_eq!(
+            (missing.status, missing.code),
+            (404, ErrorCode::DocumentNotFound)
+        );
+
+        let mut document = Document::new_with_id("idx", "doc1");
+        block_on(document.update_with(
+            &store,
+            &IndexingOptions::default(),
+            "bytes".into(),
+            Some("binary".into()),
+            LangDetection::Never,
+        ))
+        .unwrap();
+        // Binary bodies have no keywords, leaving only the document's own key
+        let counts = rewrite(&store, "other bytes").unwrap();
+        assert_eq!((counts.gets, counts.puts), (1, 1));
+        let counts = rewrite(&store, "other bytes").unwrap();
+        assert_eq!((counts.gets, counts.puts), (1, 0));
+    }
+
+    #[test]
+    fn test_only_custom_ids_are_checked_for_existence() {
+        let store = MemoryStorage::default();
+        index_text(&store, "idx", "doc1", "Ocean tides.");
+        let reads = store.counts().gets;
+
+        block_on(check_new_document(&store, "idx", None)).unwrap();
+        assert_eq!(store.counts().gets, reads);
+        block_on(check_new_document(&store, "idx", Some(&"doc2".into()))).unwrap();
+        assert_eq!(store.counts().gets, reads + 1);
+        let exists = block_on(check_new_document(&store, "idx", Some(&"doc1".into()))).unwrap_err();
+        assert_eq!(
+            (exists.status, exists.code),
+            (409, ErrorCode::DocumentExists)
+        );
+    }
+
+    #[test]
+    fn test_delete_stored_document() {
+        let store = MemoryStorage::default();
+        let options = DeleteOptions {
+            n_shards: DEFAULT_N_SHARDS,
+            codecs: CodecSet::V1,
+            now: 1,
+        };
+        let Err(missing) = block_on(delete_stored_document(&store, "idx", "doc1", &options)) else {
+            panic!("deleted a missing document");
+        };
+        assert_eq!(
+            (missing.status, missing.code),
+            (404, ErrorCode::DocumentNotFound)
+        );
+
+        let clean = index_text(&store, "idx", "doc1", "Ocean tides rise at dawn.");
+        let (_, response) =
+            block_on(delete_stored_document(&store, "idx", "doc1", &options)).unwrap();
+        let keywords = clean.keywords.as_ref().unwrap().len();
+        assert_eq!(
+            response,
+            DeleteDocumentResponse {
+                deleted: true,
+                revision: 1,
+                keywords_removed: keywords,
+                shards_touched: keywords,
+                failed_shards: vec![],
+            }
+        );
+        assert_eq!(response.status(), 200);
+        assert!(block_on(Document::from_remote(&store, "idx", "doc1".into())).is_err());
+
+        // A shard that fails even when retried keeps the document for another try
+        let failing = index_text(&store, "idx", "doc2", "Mountain trails climb steeply.");
+        let keyword = failing.keywords.as_ref().unwrap()[0].0.clone();
+        store.fail_puts(&keyword_shard_prefix("idx", &keyword), 2);
+        let (_, response) =
+            block_on(delete_stored_document(&store, "idx", "doc2", &options)).unwrap();
+        assert_eq!((response.deleted, response.status()), (false, 207));
+        assert_eq!(response.failed_shards.len(), 1);
+        assert_eq!(response.failed_shards[0].keyword, keyword);
+        assert_eq!(response.keywords_removed, response.shards_touched - 1);
+        assert!(block_on(Document::from_remote(&store, "idx", "doc2".into())).is_ok());
+
+        let (_, retried) =
+            block_on(delete_stored_document(&store, "idx", "doc2", &options)).unwrap();
+        assert!(retried.deleted && retried.failed_shards.is_empty());
+    }
 }
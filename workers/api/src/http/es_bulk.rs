@@ -0,0 +1,799 @@
+//! A shim for the happy subset of the Elasticsearch `_bulk` NDJSON protocol, so
+//! existing tooling can index into edgesearch. Each action line is translated onto
+//! the regular `Document` operations and reported back in an ES-style `items` array;
+//! a bad line only fails its own item, never the whole request.
+
+use std::collections::BTreeMap;
+
+use lingua::IsoCode639_1;
+use serde::Serialize;
+use serde_json::Value;
+use worker::{Context, Request, Response, Result, RouteContext};
+
+use crate::{
+    data::{
+        deletion::{delete_document, DeleteOptions},
+        document::{Document, LangDetection},
+        shard_repair::PendingShardRepairs,
+        storage::Storage,
+        trend::{add_deltas, deletion_deltas, KeywordDeltas},
+        usage::UsageDelta,
+    },
+    durable::{
+        activity::ActivityEvent,
+        journal::{
+            record_activity, record_keyword_trends, record_shard_repairs, record_usage,
+            send_docs_delta,
+        },
+    },
+    http::{allows_missing_index, check_index, frozen_rejection, json_error, ErrorCode},
+    util::{
+        kv::{get_body_bucket, get_kv_data_store},
+        time::now_ms,
+    },
+};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BulkAction {
+    Index,
+    Create,
+    Update,
+    Delete,
+}
+
+impl BulkAction {
+    fn from_name(name: &str) -> Option<BulkAction> {
+        match name {
+            "index" => Some(BulkAction::Index),
+            "create" => Some(BulkAction::Create),
+            "update" => Some(BulkAction::Update),
+            "delete" => Some(BulkAction::Delete),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            BulkAction::Index => "index",
+            BulkAction::Create => "create",
+            BulkAction::Update => "update",
+            BulkAction::Delete => "delete",
+        }
+    }
+
+    fn has_source(&self) -> bool {
+        *self != BulkAction::Delete
+    }
+}
+
+/// The document fields accepted on a source line (or inside `doc` for updates)
+#[derive(Debug, PartialEq)]
+pub struct BulkSource {
+    pub body: String,
+    pub format: Option<String>,
+    pub lang: Option<IsoCode639_1>,
+    /// `"detect": false` gives documents without `lang` the index's default language
+    pub detection: LangDetection,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct BulkOperation {
+    pub action: BulkAction,
+    pub id: Option<String>,
+    pub source: Option<BulkSource>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct BulkItemError {
+    #[serde(rename = "type")]
+    pub error_type: String,
+    pub reason: String,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct BulkItemResult {
+    #[serde(rename = "_index")]
+    pub index: String,
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    pub status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<BulkItemError>,
+    /// Set with the `noop` result of a write whose body matched the stored revision
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub unchanged: bool,
+}
+
+/// One entry of the `items` array, keyed by the action name like ES does
+#[derive(Serialize, Debug, PartialEq)]
+pub struct BulkItem(BTreeMap<String, BulkItemResult>);
+
+impl BulkItem {
+    fn ok(action: &str, index: &str, id: String, status: u16, result: &'static str) -> BulkItem {
+        BulkItem(BTreeMap::from([(
+            action.to_string(),
+            BulkItemResult {
+                index: index.to_string(),
+                id: Some(id),
+                status,
+                result: Some(result),
+                error: None,
+                unchanged: false,
+            },
+        )]))
+    }
+
+    fn failed(
+        action: &str,
+        index: &str,
+        id: Option<String>,
+        status: u16,
+        error_type: &str,
+        reason: String,
+    ) -> BulkItem {
+        BulkItem(BTreeMap::from([(
+            action.to_string(),
+            BulkItemResult {
+                index: index.to_string(),
+                id,
+                status,
+                result: None,
+                error: Some(BulkItemError {
+                    error_type: error_type.to_string(),
+                    reason,
+                }),
+                unchanged: false,
+            },
+        )]))
+    }
+
+    /// A write that found the document already stored as given, like ES's `noop`
+    fn unchanged(action: &str, index: &str, id: String) -> BulkItem {
+        let mut item = BulkItem::ok(action, index, id, 200, "noop");
+        item.0
+            .values_mut()
+            .for_each(|result| result.unchanged = true);
+        item
+    }
+
+    #[cfg(test)]
+    fn status(&self) -> u16 {
+        self.0.values().next().map_or(500, |item| item.status)
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.0.values().any(|item| item.error.is_some())
+    }
+}
+
+/// The documents the successful operations of a request added, updated and deleted
+fn bulk_usage(items: &[BulkItem]) -> UsageDelta {
+    let mut usage = UsageDelta::default();
+    for result in items.iter().flat_map(|item| item.0.values()) {
+        match result.result {
+            Some("created") => usage.docs_added += 1,
+            Some("updated") => usage.docs_updated += 1,
+            Some("deleted") => usage.docs_deleted += 1,
+            _ => {}
+        }
+    }
+    usage
+}
+
+#[derive(Serialize)]
+struct BulkResponse {
+    took: u64,
+    errors: bool,
+    items: Vec<BulkItem>,
+}
+
+/// Parse the `{"<action>": {...}}` line, returning the action name and its metadata
+fn parse_action_line(line: &str) -> std::result::Result<(String, Value), String> {
+    let value: Value =
+        serde_json::from_str(line).map_err(|err| format!("malformed action line: {}", err))?;
+    let Value::Object(map) = value else {
+        return Err("action line must be a JSON object".into());
+    };
+    if map.len() != 1 {
+        return Err("action line must contain exactly one action".into());
+    }
+    let (name, metadata) = map.into_iter().next().unwrap();
+    Ok((name, metadata))
+}
+
+/// Whether a line looks like the start of another known action, so a missing source
+/// line doesn't swallow the next operation
+fn is_action_line(line: &str) -> bool {
+    parse_action_line(line)
+        .ok()
+        .is_some_and(|(name, metadata)| {
+            BulkAction::from_name(&name).is_some() && metadata.is_object()
+        })
+}
+
+fn parse_source(action: BulkAction, line: &str) -> std::result::Result<BulkSource, String> {
+    let value: Value =
+        serde_json::from_str(line).map_err(|err| format!("malformed source line: {}", err))?;
+    // Updates wrap the partial document in `doc`, like ES does
+    let value = match (action, value) {
+        (BulkAction::Update, Value::Object(mut map)) if map.contains_key("doc") => {
+            map.remove("doc").unwrap()
+        }
+        (_, value) => value,
+    };
+    let Value::Object(map) = value else {
+        return Err("source line must be a JSON object".into());
+    };
+    let body = match map.get("body") {
+        Some(Value::String(body)) => body.clone(),
+        Some(_) => return Err("`body` must be a string".into()),
+        None => return Err("source is missing `body`".into()),
+    };
+    let format = match map.get("format") {
+        Some(Value::String(format)) => Some(format.clone()),
+        Some(_) => return Err("`format` must be a string".into()),
+        None => None,
+    };
+    let lang = match map.get("lang") {
+        Some(lang) => Some(
+            serde_json::from_value::<IsoCode639_1>(lang.clone())
+                .map_err(|_| format!("unknown language {}", lang))?,
+        ),
+        None => None,
+    };
+    let detection = match map.get("detect") {
+        Some(Value::Bool(false)) => LangDetection::Never,
+        Some(Value::Bool(true)) | None => LangDetection::WhenMissing,
+        Some(_) => return Err("`detect` must be a boolean".into()),
+    };
+    Ok(BulkSource {
+        body,
+        format,
+        lang,
+        detection,
+    })
+}
+
+/// Parse a `_bulk` NDJSON body into operations, with malformed or unsupported
+/// entries reported as failed items in place
+pub fn parse_bulk(index: &str, body: &str) -> Vec<std::result::Result<BulkOperation, BulkItem>> {
+    let mut lines = body
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty())
+        .peekable();
+    let mut operations = vec![];
+
+    while let Some(line) = lines.next() {
+        let (name, metadata) = match parse_action_line(line) {
+            Ok(parsed) => parsed,
+            Err(reason) => {
+                operations.push(Err(BulkItem::failed(
+                    "unknown",
+                    index,
+                    None,
+                    400,
+                    "parse_exception",
+                    reason,
+                )));
+                continue;
+            }
+        };
+
+        let id = metadata
+            .get("_id")
+            .and_then(Value::as_str)
+            .map(str::to_string);
+
+        let Some(action) = BulkAction::from_name(&name) else {
+            // Skip the unsupported action's source line, if it has one
+            if lines.peek().is_some_and(|next| !is_action_line(next)) {
+                lines.next();
+            }
+            operations.push(Err(BulkItem::failed(
+                &name,
+                index,
+                id,
+                400,
+                "illegal_argument_exception",
+                format!("unsupported bulk action '{}'", name),
+            )));
+            continue;
+        };
+
+        let source = if action.has_source() {
+            match lines.peek() {
+                Some(next) if !is_action_line(next) => {
+                    Some(parse_source(action, lines.next().unwrap()))
+                }
+                _ => {
+                    operations.push(Err(BulkItem::failed(
+                        action.name(),
+                        index,
+                        id,
+                        400,
+                        "action_request_validation_exception",
+                        format!("'{}' is missing its source line", action.name()),
+                    )));
+                    continue;
+                }
+            }
+        } else {
+            None
+        };
+
+        let failed = |status: u16, error_type: &str, reason: String| {
+            Err(BulkItem::failed(
+                action.name(),
+                index,
+                id.clone(),
+                status,
+                error_type,
+                reason,
+            ))
+        };
+
+        if !metadata.is_object() {
+            operations.push(failed(
+                400,
+                "parse_exception",
+                "action metadata must be a JSON object".into(),
+            ));
+            continue;
+        }
+        if let Some(target) = metadata.get("_index").and_then(Value::as_str) {
+            if target != index {
+                operations.push(failed(
+                    400,
+                    "illegal_argument_exception",
+                    format!("cross-index operation into '{}' is not supported", target),
+                ));
+                continue;
+            }
+        }
+        if let Some(id) = &id {
+            if !Document::is_valid_id(id) {
+                operations.push(failed(
+                    400,
+                    "illegal_argument_exception",
+                    Document::INVALID_ID_ERROR.into(),
+                ));
+                continue;
+            }
+        } else if action != BulkAction::Index && action != BulkAction::Create {
+            operations.push(failed(
+                400,
+                "action_request_validation_exception",
+                format!("'{}' requires an _id", action.name()),
+            ));
+            continue;
+        }
+
+        let source = match source.transpose() {
+            Ok(source) => source,
+            Err(reason) => {
+                operations.push(failed(400, "parse_exception", reason));
+                continue;
+            }
+        };
+
+        operations.push(Ok(BulkOperation { action, id, source }));
+    }
+    operations
+}
+
+/// A failed item for an operation on a frozen index
+async fn frozen_item<S: Storage>(
+    store: &S,
+    index: &str,
+    operation: &BulkOperation,
+) -> Option<BulkItem> {
+    let rejection = frozen_rejection(store, index).await?;
+    let error_type = match rejection.status {
+        423 => "cluster_block_exception",
+        _ => "exception",
+    };
+    Some(BulkItem::failed(
+        operation.action.name(),
+        index,
+        operation.id.clone(),
+        rejection.status,
+        error_type,
+        rejection.error,
+    ))
+}
+
+/// What a request's operations did, reported to the index's journal once they've run
+struct BulkEffects {
+    /// The documents created, less those deleted
+    docs_delta: i64,
+    activity: Vec<ActivityEvent>,
+    trends: KeywordDeltas,
+    /// The keyword shards the writes failed
+    repairs: PendingShardRepairs,
+}
+
+/// Run one operation, adding what it did to `effects`
+async fn execute_operation(
+    store: &worker::kv::KvStore,
+    env: &worker::Env,
+    index: &str,
+    operation: BulkOperation,
+    effects: &mut BulkEffects,
+) -> BulkItem {
+    let action = operation.action.name();
+    // Checked before every operation, so freezing an index stops an upload under way
+    if let Some(item) = frozen_item(store, index, &operation).await {
+        return item;
+    }
+    let existing = match &operation.id {
+        Some(id) => Document::from_remote(store, index, id.clone()).await.ok(),
+        None => None,
+    };
+
+    let (mut document, created) = match (operation.action, existing) {
+        (BulkAction::Create, Some(existing)) => {
+            return BulkItem::failed(
+                action,
+                index,
+                Some(existing.get_uuid()),
+                409,
+                "version_conflict_engine_exception",
+                "document already exists".into(),
+            );
+        }
+        (BulkAction::Update | BulkAction::Delete, None) => {
+            let (error_type, reason) = match operation.action {
+                BulkAction::Update => ("document_missing_exception", "document missing"),
+                _ => ("not_found", "document not found"),
+            };
+            return BulkItem::failed(action, index, operation.id, 404, error_type, reason.into());
+        }
+        (BulkAction::Delete, Some(existing)) => {
+            let deleted = match DeleteOptions::for_index(store, env, index, now_ms()).await {
+                Ok(options) => delete_document(store, &existing, &options).await,
+                Err(err) => Err(err),
+            };
+            let mut deleted = match deleted {
+                Ok(outcome) if !outcome.is_complete() => {
+                    let failed: Vec<&str> = outcome
+                        .failed_shards
+                        .iter()
+                        .map(|(keyword, _)| keyword.as_str())
+                        .collect();
+                    return BulkItem::failed(
+                        action,
+                        index,
+                        Some(existing.get_uuid()),
+                        500,
+                        "shard_write_exception",
+                        format!(
+                            "document is pending deletion but these keyword shards were not written: {}",
+                            failed.join(", ")
+                        ),
+                    );
+                }
+                Ok(_) => {
+                    effects.docs_delta -= 1;
+                    effects
+                        .activity
+                        .push(ActivityEvent::deleted(&existing, now_ms()));
+                    add_deltas(&mut effects.trends, &deletion_deltas(&existing));
+                    Ok(())
+                }
+                Err(err) => Err(err),
+            };
+            if let (Ok(()), true, Some(bodies)) =
+                (&deleted, existing.body_ref.is_some(), get_body_bucket(env))
+            {
+                deleted = existing.delete_body(&bodies).await;
+            }
+            return match deleted {
+                Ok(()) => BulkItem::ok(action, index, existing.get_uuid(), 200, "deleted"),
+                Err(err) => BulkItem::failed(
+                    action,
+                    index,
+                    Some(existing.get_uuid()),
+                    500,
+                    "exception",
+                    err.to_string(),
+                ),
+            };
+        }
+        (_, Some(existing)) => (existing, false),
+        (_, None) => match &operation.id {
+            Some(id) => (Document::new_with_id(index, id), true),
+            None => (Document::new(index), true),
+        },
+    };
+
+    let source = operation
+        .source
+        .expect("source is parsed for every write action");
+    // New documents without a language have it detected from their body
+    if let Some(lang) = source.lang {
+        document.set_language(lang);
+    }
+
+    let updated = document
+        .update(store, env, source.body, source.format, source.detection)
+        .await;
+    // The document is stored even when some of its keyword shards failed
+    if created && updated.is_ok() {
+        effects.docs_delta += 1;
+    }
+    if let Some(outcome) = updated.as_ref().ok().filter(|outcome| !outcome.unchanged) {
+        let event = ActivityEvent::written(&document.get_uuid(), created, outcome, now_ms());
+        effects.activity.push(event);
+        add_deltas(&mut effects.trends, &outcome.keyword_deltas);
+        effects
+            .repairs
+            .record_outcome(&document.get_uuid(), outcome);
+    }
+    match updated {
+        Ok(outcome) if !outcome.is_complete() => {
+            let failed: Vec<&str> = outcome
+                .failed_keywords
+                .iter()
+                .map(|(keyword, _)| keyword.as_str())
+                .collect();
+            BulkItem::failed(
+                action,
+                index,
+                Some(document.get_uuid()),
+                500,
+                "shard_write_exception",
+                format!(
+                    "document was written but these keyword shards were not: {}",
+                    failed.join(", ")
+                ),
+            )
+        }
+        Ok(outcome) if outcome.unchanged => BulkItem::unchanged(action, index, document.get_uuid()),
+        Ok(_) if created => BulkItem::ok(action, index, document.get_uuid(), 201, "created"),
+        Ok(_) => BulkItem::ok(action, index, document.get_uuid(), 200, "updated"),
+        Err(err) => BulkItem::failed(
+            action,
+            index,
+            Some(document.get_uuid()),
+            500,
+            "exception",
+            err.to_string(),
+        ),
+    }
+}
+
+pub async fn handle_bulk(mut req: Request, ctx: RouteContext<Context>) -> Result<Response> {
+    let Some(index) = ctx.param("index") else {
+        return json_error(400, ErrorCode::MissingParameter, "Missing index name");
+    };
+
+    let started = now_ms();
+    let store = get_kv_data_store(&ctx);
+    if let Some(response) = check_index(&store, index, allows_missing_index(&req)).await? {
+        return Ok(response);
+    }
+
+    let body = req.text().await?;
+    let mut items = vec![];
+    let mut effects = BulkEffects {
+        docs_delta: 0,
+        activity: vec![],
+        trends: KeywordDeltas::new(),
+        repairs: PendingShardRepairs::new(index),
+    };
+    // Operations run in order so later lines observe earlier ones, like ES
+    for operation in parse_bulk(index, &body) {
+        let item = match operation {
+            Ok(operation) => {
+                execute_operation(&store, &ctx.env, index, operation, &mut effects).await
+            }
+            Err(item) => item,
+        };
+        items.push(item);
+    }
+    if effects.docs_delta != 0 {
+        send_docs_delta(&ctx.env, index, effects.docs_delta).await;
+    }
+    record_usage(&ctx, index, bulk_usage(&items));
+    record_activity(&ctx, index, effects.activity);
+    record_keyword_trends(&ctx, index, effects.trends);
+    record_shard_repairs(&ctx, index, effects.repairs);
+
+    Response::from_json(&BulkResponse {
+        took: now_ms().saturating_sub(started),
+        errors: items.iter().any(BulkItem::is_error),
+        items,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+
+    use super::*;
+    use crate::data::{index_manager::IndexManager, storage::memory::MemoryStorage};
+
+    fn ok_ops(body: &str) -> Vec<BulkOperation> {
+        parse_bulk("idx", body)
+            .into_iter()
+            .map(|op| op.expect("operation should parse"))
+            .collect()
+    }
+
+    fn source(body: &str) -> Option<BulkSource> {
+        Some(BulkSource {
+            body: body.into(),
+            format: None,
+            lang: None,
+            detection: LangDetection::WhenMissing,
+        })
+    }
+
+    #[test]
+    fn test_parse_happy_path() {
+        let body = concat!(
+            "{\"index\":{\"_id\":\"a\"}}\n",
+            "{\"body\":\"first\"}\n",
+            "\n",
+            "{\"create\":{}}\n",
+            "{\"body\":\"second\",\"format\":\"text\",\"detect\":false}\n",
+            "{\"update\":{\"_id\":\"a\",\"_index\":\"idx\"}}\n",
+            "{\"doc\":{\"body\":\"third\"}}\n",
+            "{\"delete\":{\"_id\":\"a\"}}\n",
+        );
+        let ops = ok_ops(body);
+        assert_eq!(ops.len(), 4);
+        assert_eq!(
+            ops[0],
+            BulkOperation {
+                action: BulkAction::Index,
+                id: Some("a".into()),
+                source: source("first"),
+            }
+        );
+        assert_eq!(ops[1].id, None);
+        assert_eq!(
+            ops[1].source.as_ref().unwrap().format.as_deref(),
+            Some("text")
+        );
+        assert_eq!(
+            ops[1].source.as_ref().unwrap().detection,
+            LangDetection::Never
+        );
+        assert_eq!(ops[2].source, source("third"));
+        assert_eq!(
+            ops[3],
+            BulkOperation {
+                action: BulkAction::Delete,
+                id: Some("a".into()),
+                source: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_malformed_lines_fail_only_their_item() {
+        let body = concat!(
+            "{not json\n",
+            "{\"index\":{\"_id\":\"a\"}}\n",
+            "{\"body\":42}\n",
+            "{\"index\":{\"_id\":\"b\"}}\n",
+            "{\"body\":\"ok\"}\n",
+        );
+        let ops = parse_bulk("idx", body);
+        assert_eq!(ops.len(), 3);
+        assert_eq!(ops[0].as_ref().unwrap_err().status(), 400);
+        assert!(ops[1].as_ref().is_err_and(BulkItem::is_error));
+        assert_eq!(ops[2].as_ref().unwrap().id.as_deref(), Some("b"));
+    }
+
+    #[test]
+    fn test_missing_source_does_not_swallow_next_action() {
+        let body = concat!(
+            "{\"index\":{\"_id\":\"a\"}}\n",
+            "{\"delete\":{\"_id\":\"b\"}}\n",
+            "{\"create\":{\"_id\":\"c\"}}\n",
+        );
+        let ops = parse_bulk("idx", body);
+        assert_eq!(ops.len(), 3);
+        assert!(ops[0].is_err());
+        assert_eq!(ops[1].as_ref().unwrap().action, BulkAction::Delete);
+        assert!(ops[2].is_err());
+    }
+
+    #[test]
+    fn test_unsupported_action_is_a_per_item_error() {
+        let body = concat!(
+            "{\"upsert\":{\"_id\":\"a\"}}\n",
+            "{\"body\":\"skipped\"}\n",
+            "{\"delete\":{\"_id\":\"a\"}}\n",
+        );
+        let ops = parse_bulk("idx", body);
+        assert_eq!(ops.len(), 2);
+        let json = serde_json::to_value(ops[0].as_ref().unwrap_err()).unwrap();
+        assert_eq!(json["upsert"]["status"], 400);
+        assert_eq!(
+            json["upsert"]["error"]["type"],
+            "illegal_argument_exception"
+        );
+        assert!(ops[1].is_ok());
+    }
+
+    #[test]
+    fn test_rejects_invalid_metadata() {
+        let body = concat!(
+            "{\"index\":{\"_id\":\"a\",\"_index\":\"other\"}}\n",
+            "{\"body\":\"x\"}\n",
+            "{\"update\":{}}\n",
+            "{\"doc\":{\"body\":\"x\"}}\n",
+            "{\"delete\":{\"_id\":\"no spaces\"}}\n",
+            "{\"index\":{},\"create\":{}}\n",
+            "[1,2]\n",
+        );
+        let ops = parse_bulk("idx", body);
+        assert_eq!(ops.len(), 5);
+        assert!(ops
+            .iter()
+            .all(|op| op.as_ref().is_err_and(|i| i.status() == 400)));
+    }
+
+    #[test]
+    fn test_item_serialization() {
+        let item = BulkItem::ok("index", "idx", "a".into(), 201, "created");
+        assert_eq!(
+            serde_json::to_string(&item).unwrap(),
+            r#"{"index":{"_index":"idx","_id":"a","status":201,"result":"created"}}"#
+        );
+    }
+
+    #[test]
+    fn test_bulk_usage_counts_successes() {
+        let items = vec![
+            BulkItem::ok("index", "idx", "a".into(), 201, "created"),
+            BulkItem::ok("create", "idx", "b".into(), 201, "created"),
+            BulkItem::ok("index", "idx", "a".into(), 200, "updated"),
+            BulkItem::ok("delete", "idx", "b".into(), 200, "deleted"),
+            BulkItem::failed("delete", "idx", None, 404, "not_found", "x".into()),
+        ];
+        let usage = bulk_usage(&items);
+        assert_eq!(
+            (usage.docs_added, usage.docs_updated, usage.docs_deleted),
+            (2, 1, 1)
+        );
+        assert_eq!(usage.searches, 0);
+    }
+
+    #[test]
+    fn test_freezing_mid_upload_fails_later_items() {
+        let store = MemoryStorage::default();
+        let manager = IndexManager::new(&store);
+        block_on(manager.create_index("bulk-freeze", None, None)).unwrap();
+
+        let body = concat!(
+            "{\"index\":{\"_id\":\"a\"}}\n",
+            "{\"body\":\"first\"}\n",
+            "{\"index\":{\"_id\":\"b\"}}\n",
+            "{\"body\":\"second\"}\n",
+            "{\"delete\":{\"_id\":\"a\"}}\n",
+            "{\"create\":{}}\n",
+            "{\"body\":\"third\"}\n",
+        );
+        let mut statuses = vec![];
+        for (i, operation) in ok_ops(body).iter().enumerate() {
+            if i == 2 {
+                block_on(manager.set_frozen("bulk-freeze", true)).unwrap();
+            }
+            let item = block_on(frozen_item(&store, "bulk-freeze", operation));
+            statuses.push(item.as_ref().map(BulkItem::status));
+            if let Some(item) = item {
+                let json = serde_json::to_value(&item).unwrap();
+                let result = json.as_object().unwrap().values().next().unwrap();
+                assert_eq!(result["error"]["type"], "cluster_block_exception");
+            }
+        }
+        assert_eq!(statuses, vec![None, None, Some(423), Some(423)]);
+    }
+}
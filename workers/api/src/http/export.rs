@@ -0,0 +1,181 @@
+use worker::{Context, Request, Response, Result, RouteContext};
+
+use crate::{
+    data::{
+        export::{ExportCursor, ExportPage, DEFAULT_EXPORT_LIMIT, MAX_EXPORT_LIMIT},
+        index_manager::IndexManager,
+        keyword::KeywordManager,
+        keyword_shard::get_n_shards,
+    },
+    http::{check_index, index_codecs, json_error, require_api_key, ErrorCode, Rejection},
+    util::kv::get_kv_data_store,
+};
+
+pub const NDJSON_CONTENT_TYPE: &str = "application/x-ndjson";
+
+#[derive(serde::Deserialize, Default)]
+pub struct ExportParams {
+    limit: Option<usize>,
+    cursor: Option<String>,
+    after: Option<String>,
+}
+
+/// How many keywords to export, and where to resume: a cursor from an earlier
+/// call, or the keyword to start after
+pub fn parse_export_params(
+    params: &ExportParams,
+) -> std::result::Result<(ExportCursor, usize), Rejection> {
+    let invalid = |message: String| Rejection::new(400, ErrorCode::InvalidRequest, message);
+    let limit = params.limit.unwrap_or(DEFAULT_EXPORT_LIMIT);
+    if !(1..=MAX_EXPORT_LIMIT).contains(&limit) {
+        return Err(invalid(format!(
+            "limit must be between 1 and {}",
+            MAX_EXPORT_LIMIT
+        )));
+    }
+    let cursor = match (params.cursor.as_deref(), params.after.as_deref()) {
+        (Some(_), Some(_)) => return Err(invalid("Pass either cursor or after, not both".into())),
+        (Some(cursor), None) if !cursor.is_empty() => cursor.parse().map_err(invalid)?,
+        (None, Some(after)) if !after.is_empty() => ExportCursor::after(after),
+        _ => ExportCursor::default(),
+    };
+    Ok((cursor, limit))
+}
+
+/// One line per exported keyword, then a line holding the cursor to pass back, a
+/// `null` one once the export is done
+pub fn export_lines(page: &ExportPage) -> serde_json::Result<String> {
+    let mut lines = String::new();
+    for keyword in &page.keywords {
+        lines.push_str(&serde_json::to_string(keyword)?);
+        lines.push('\n');
+    }
+    let cursor = page.cursor.as_ref().map(ExportCursor::to_string);
+    lines.push_str(&serde_json::to_string(
+        &serde_json::json!({ "cursor": cursor }),
+    )?);
+    lines.push('\n');
+    Ok(lines)
+}
+
+/// `GET /:index/keywords/export`: the next keywords of an index with their shard and
+/// document counts, as NDJSON. Keep passing back the last line's `cursor` until it
+/// comes back `null` to export every keyword.
+pub async fn handle_export_keywords(req: Request, ctx: RouteContext<Context>) -> Result<Response> {
+    if let Some(response) = require_api_key(&req, &ctx.env, "Exporting an index's keywords") {
+        return response;
+    }
+    let Some(index) = ctx.param("index") else {
+        return json_error(400, ErrorCode::MissingParameter, "Missing index name");
+    };
+    let Ok(params) = req.query::<ExportParams>() else {
+        return json_error(400, ErrorCode::InvalidRequest, "limit must be a number");
+    };
+    let (cursor, limit) = match parse_export_params(&params) {
+        Ok(parsed) => parsed,
+        Err(rejection) => return rejection.into_response(),
+    };
+
+    let store = get_kv_data_store(&ctx);
+    if let Some(response) = check_index(&store, index, false).await? {
+        return Ok(response);
+    }
+    let n_shards = IndexManager::new(&store)
+        .shard_count(index, get_n_shards(&ctx.env))
+        .await;
+    let codecs = match index_codecs(&store, index).await {
+        Ok(codecs) => codecs,
+        Err(rejection) => return rejection.into_response(),
+    };
+    let manager = KeywordManager::new(index.into(), &ctx.env, &store)
+        .with_n_shards(n_shards)
+        .with_codecs(codecs);
+    let page = match manager.export(cursor, limit).await {
+        Ok(page) => page,
+        Err(err) => {
+            return Rejection::from_store_error(err, ErrorCode::IndexNotFound).into_response()
+        }
+    };
+    let mut response = Response::ok(export_lines(&page)?)?;
+    response
+        .headers_mut()
+        .set("Content-Type", NDJSON_CONTENT_TYPE)?;
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::export::ExportedKeyword;
+
+    #[test]
+    fn test_parse_export_params() {
+        let (cursor, limit) = parse_export_params(&ExportParams::default()).unwrap();
+        assert_eq!(
+            (cursor, limit),
+            (ExportCursor::default(), DEFAULT_EXPORT_LIMIT)
+        );
+
+        let after = ExportParams {
+            limit: Some(10),
+            after: Some("ocean".into()),
+            ..Default::default()
+        };
+        assert_eq!(
+            parse_export_params(&after).unwrap(),
+            (ExportCursor::after("ocean"), 10)
+        );
+        let resumed = ExportParams {
+            cursor: Some("5:oceanidx:kw:tide:0".into()),
+            ..Default::default()
+        };
+        let (cursor, _) = parse_export_params(&resumed).unwrap();
+        assert_eq!(cursor.page.as_deref(), Some("idx:kw:tide:0"));
+
+        for params in [
+            ExportParams {
+                limit: Some(0),
+                ..Default::default()
+            },
+            ExportParams {
+                limit: Some(MAX_EXPORT_LIMIT + 1),
+                ..Default::default()
+            },
+            ExportParams {
+                cursor: Some("bogus".into()),
+                ..Default::default()
+            },
+            ExportParams {
+                cursor: Some("0:".into()),
+                after: Some("ocean".into()),
+                ..Default::default()
+            },
+        ] {
+            let rejection = parse_export_params(&params).unwrap_err();
+            assert_eq!(
+                (rejection.status, rejection.code),
+                (400, ErrorCode::InvalidRequest)
+            );
+        }
+    }
+
+    #[test]
+    fn test_export_lines() {
+        let mut page = ExportPage {
+            keywords: vec![ExportedKeyword {
+                keyword: "ocean".into(),
+                shards: 2,
+                doc_count: 7,
+            }],
+            cursor: Some(ExportCursor::after("ocean")),
+        };
+        assert_eq!(
+            export_lines(&page).unwrap(),
+            "{\"keyword\":\"ocean\",\"shards\":2,\"doc_count\":7}\n{\"cursor\":\"5:ocean\"}\n"
+        );
+        page.cursor = None;
+        assert!(export_lines(&page)
+            .unwrap()
+            .ends_with("{\"cursor\":null}\n"));
+    }
+}
@@ -0,0 +1,122 @@
+use worker::{Context, Request, Response, Result, RouteContext};
+
+use crate::{
+    data::{
+        fsck::{FsckCursor, DEFAULT_FSCK_EXAMPLES, MAX_FSCK_EXAMPLES},
+        index_manager::IndexManager,
+        keyword::KeywordManager,
+        keyword_shard::get_n_shards,
+    },
+    http::{check_index, check_writable_index, index_codecs, json_error, ErrorCode, Rejection},
+    util::kv::get_kv_data_store,
+};
+
+#[derive(serde::Deserialize, Default)]
+pub struct FsckParams {
+    cursor: Option<String>,
+    repair: Option<bool>,
+    examples: Option<usize>,
+}
+
+/// Where to resume the check, and how many examples to report per kind of problem
+pub fn parse_fsck_params(
+    params: &FsckParams,
+) -> std::result::Result<(FsckCursor, usize), Rejection> {
+    let cursor = match params.cursor.as_deref() {
+        None | Some("") => FsckCursor::start(),
+        Some(cursor) => cursor
+            .parse()
+            .map_err(|err| Rejection::new(400, ErrorCode::InvalidRequest, err))?,
+    };
+    let examples = params
+        .examples
+        .unwrap_or(DEFAULT_FSCK_EXAMPLES)
+        .min(MAX_FSCK_EXAMPLES);
+    Ok((cursor, examples))
+}
+
+/// `POST /:index/fsck`: check the next batch of an index's documents and keyword
+/// shards for orphan, missing and empty postings, fixing them with `repair=true`.
+/// Keep passing back `cursor` until it comes back `null` to check the whole index.
+pub async fn handle_fsck(req: Request, ctx: RouteContext<Context>) -> Result<Response> {
+    let Some(index) = ctx.param("index") else {
+        return json_error(400, ErrorCode::MissingParameter, "Missing index name");
+    };
+    let Ok(params) = req.query::<FsckParams>() else {
+        return json_error(
+            400,
+            ErrorCode::InvalidRequest,
+            "repair must be true or false, and examples a number",
+        );
+    };
+    let (cursor, examples) = match parse_fsck_params(&params) {
+        Ok(parsed) => parsed,
+        Err(rejection) => return rejection.into_response(),
+    };
+
+    // Repairs write shards, so they wait for a frozen index to be unfrozen
+    let repair = params.repair.unwrap_or(false);
+    let store = get_kv_data_store(&ctx);
+    let rejected = match repair {
+        true => check_writable_index(&store, index, false).await?,
+        false => check_index(&store, index, false).await?,
+    };
+    if let Some(response) = rejected {
+        return Ok(response);
+    }
+
+    let n_shards = IndexManager::new(&store)
+        .shard_count(index, get_n_shards(&ctx.env))
+        .await;
+    let codecs = match index_codecs(&store, index).await {
+        Ok(codecs) => codecs,
+        Err(rejection) => return rejection.into_response(),
+    };
+    let manager = KeywordManager::new(index.into(), &ctx.env, &store)
+        .with_n_shards(n_shards)
+        .with_codecs(codecs);
+    match manager.fsck(cursor, repair, examples).await {
+        Ok(report) => Response::from_json(&report),
+        Err(err) => json_error(
+            500,
+            ErrorCode::InternalError,
+            format!("Failed to check the index: {}", err),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::fsck::FsckPhase;
+
+    #[test]
+    fn test_parse_fsck_params() {
+        let (cursor, examples) = parse_fsck_params(&FsckParams::default()).unwrap();
+        assert_eq!(cursor, FsckCursor::start());
+        assert_eq!(examples, DEFAULT_FSCK_EXAMPLES);
+
+        let params = FsckParams {
+            cursor: Some("s:4:idx:kw:ocean:3".into()),
+            examples: Some(10_000),
+            ..Default::default()
+        };
+        let (cursor, examples) = parse_fsck_params(&params).unwrap();
+        assert_eq!(cursor.phase, FsckPhase::Shards);
+        assert_eq!(cursor.page.as_deref(), Some("idx:kw:ocean:3"));
+        assert_eq!(examples, MAX_FSCK_EXAMPLES);
+    }
+
+    #[test]
+    fn test_parse_fsck_params_rejects_bad_cursor() {
+        let params = FsckParams {
+            cursor: Some("bogus".into()),
+            ..Default::default()
+        };
+        let rejection = parse_fsck_params(&params).unwrap_err();
+        assert_eq!(
+            (rejection.status, rejection.code),
+            (400, ErrorCode::InvalidRequest)
+        );
+    }
+}
@@ -1,13 +1,37 @@
-use worker::{Request, Response, Result, RouteContext};
+use worker::{Context, Request, Response, Result, RouteContext};
 
-use crate::http::StatusResponse;
+use crate::{
+    data::index::IndexDocument,
+    http::{
+        json_error,
+        render_html::{accepts_html, search_ui},
+        ErrorCode, StatusResponse, CAPABILITIES,
+    },
+};
 
-pub async fn handle_index(req: Request, _ctx: RouteContext<()>) -> Result<Response> {
-    if req.headers().get("Accept").map_or(false, |accept| {
-        accept.expect("unreadable").contains("text/html")
-    }) {
-        return Response::from_html(include_str!("../../index.html"));
+pub async fn handle_index(req: Request, _ctx: RouteContext<Context>) -> Result<Response> {
+    if accepts_html(&req) {
+        Response::from_html(include_str!("../../index.html"))
     } else {
-        return Response::from_json(&StatusResponse { ready: true });
+        Response::from_json(&StatusResponse {
+            ready: true,
+            capabilities: CAPABILITIES,
+        })
     }
 }
+
+/// `GET /:index/ui`: a search page for the index, which sends its searches with the
+/// API key pasted into it. The page itself reads nothing, so it needs no key.
+pub async fn handle_ui(_req: Request, ctx: RouteContext<Context>) -> Result<Response> {
+    let Some(index) = ctx.param("index") else {
+        return json_error(400, ErrorCode::MissingParameter, "Missing index name");
+    };
+    if !IndexDocument::is_valid_name(index) {
+        return json_error(
+            400,
+            ErrorCode::InvalidIndexName,
+            IndexDocument::INVALID_NAME_MESSAGE,
+        );
+    }
+    Response::from_html(search_ui(index))
+}
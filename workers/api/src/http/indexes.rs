@@ -1,8 +1,32 @@
-use worker::{Request, Response, Result, RouteContext};
+use std::{str::FromStr, sync::Arc};
+
+use lingua::IsoCode639_1;
+use worker::{kv::KvStore, Context, Env, Request, Response, Result, RouteContext};
 
 use crate::{
-    data::{index::IndexDocument, index_manager::IndexManager, KvPersistent},
-    http::ErrorResponse,
+    data::{
+        bulk::BulkReader,
+        index::{
+            parse_index_body, parse_label_filter, IndexDocument, IndexMetadata, IndexSettings,
+        },
+        index_manager::{IndexListing, IndexManager},
+        keyword_shard::get_n_shards,
+        stoplist::StopList,
+        storage::Storage,
+        DataStoreError,
+    },
+    durable::{
+        journal::read_exact_docs_count,
+        reader::{
+            get_durable_reader_namespace, get_index_detail_limit, ReaderPlacement,
+            INDEX_LISTING_READER,
+        },
+    },
+    edge_log,
+    http::{
+        check_index, etag, head_response, json_error, json_length, not_modified, with_etag,
+        ErrorCode, Rejection,
+    },
     util::kv::get_kv_data_store,
 };
 
@@ -11,77 +35,463 @@ struct DeletedResponse {
     deleted: bool,
 }
 
-pub async fn handle_list(_req: Request, ctx: RouteContext<()>) -> Result<Response> {
+fn index_store_error(err: DataStoreError) -> Rejection {
+    match err {
+        // Settings changing the version of an existing index
+        DataStoreError::InvalidFormat(_) => {
+            Rejection::new(400, ErrorCode::InvalidRequest, err.to_string())
+        }
+        _ => Rejection::from_store_error(err, ErrorCode::IndexNotFound),
+    }
+}
+
+/// An index and statistics that aren't stored on it
+#[derive(serde::Serialize)]
+struct IndexView {
+    #[serde(flatten)]
+    index: IndexDocument,
+    /// Stop-listed keywords that still have shards from before they were stop-listed
+    stoplisted_keywords: u32,
+}
+
+#[derive(serde::Deserialize)]
+struct ListIndexesParams {
+    detail: Option<bool>,
+    label: Option<String>,
+}
+
+/// `GET /indexes`: every index name, or with `detail=true` an [`IndexListing`] that
+/// also holds their index documents. `label=name:value` keeps only the indexes with
+/// that label, which reads every index document even without `detail`.
+pub async fn handle_list(req: Request, ctx: RouteContext<Context>) -> Result<Response> {
+    let Ok(params) = req.query::<ListIndexesParams>() else {
+        return json_error(
+            400,
+            ErrorCode::InvalidRequest,
+            "detail must be true or false",
+        );
+    };
+    let label = match params.label.as_deref().map(parse_label_filter) {
+        None => None,
+        Some(Ok(label)) => Some(label),
+        Some(Err(error)) => return json_error(400, ErrorCode::InvalidRequest, error),
+    };
+
     let store = &get_kv_data_store(&ctx);
     let indexer = IndexManager::new(store);
-    let known_indexes = indexer.list_indexes().await.unwrap();
-    return Response::from_json(&known_indexes);
-}
+    let detail = params.detail.unwrap_or(false);
+    if !detail && label.is_none() {
+        return match indexer.list_indexes().await {
+            Ok(known_indexes) => Response::from_json(&known_indexes),
+            Err(err) => index_store_error(err).into_response(),
+        };
+    }
 
-pub async fn handle_view(_req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let cache = get_kv_data_store(&ctx);
-    if let Some(index) = ctx.param("index") {
-        let indexer = IndexManager::new(&cache);
-        let count = indexer.count_index_documents(index).await.unwrap_or(0);
-        if let Ok(mut index_data) = indexer.read_index(index).await {
-            if index_data.docs_count != count {
-                // Update the count in KV if it has changed
-                index_data.docs_count = count;
-                index_data.write(&cache).await.unwrap();
-            }
-            return Response::from_json(&index_data);
-        } else {
-            return Response::error(
-                ErrorResponse {
-                    error: "Index not found".into(),
-                },
-                404,
+    let durable_reader_ns = get_durable_reader_namespace(&ctx.env)?;
+    let durable_obj =
+        ReaderPlacement::from_env(&ctx.env).reader_id(&durable_reader_ns, INDEX_LISTING_READER)?;
+    let reader = BulkReader::new(get_n_shards(&ctx.env), store, Some(durable_obj));
+    let limit = get_index_detail_limit() as usize;
+    let mut listing = match indexer.list_indexes_detailed(&reader, limit).await {
+        Ok(listing) => listing,
+        Err(err) => return index_store_error(err).into_response(),
+    };
+    if let Some((name, value)) = label {
+        if listing.detail_truncated {
+            return json_error(
+                400,
+                ErrorCode::InvalidRequest,
+                format!(
+                    "There are more than {} indexes, too many to filter by label",
+                    limit
+                ),
             );
         }
+        listing.retain_labelled(&name, &value);
     }
-    return Response::error(
-        ErrorResponse {
-            error: "Missing index name".into(),
-        },
-        400,
-    );
+    match detail {
+        true => Response::from_json::<IndexListing>(&listing),
+        false => Response::from_json(&listing.names),
+    }
+}
+
+impl IndexView {
+    /// Combines the index's generation with the stop-listed keyword count, the one
+    /// statistic not stored on the index document, and the document count, which an
+    /// exact view reads from the journal without a new generation
+    fn etag(&self) -> String {
+        let revision = format!(
+            "{}.{}.{}",
+            self.index.generation, self.index.docs_count, self.stoplisted_keywords
+        );
+        etag(&self.index.index, revision)
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct ViewParams {
+    exact: Option<bool>,
+}
+
+/// Read an index as stored. Its `docs_count` is what the index's journal last
+/// flushed, unless `exact` asks the journal for changes made since.
+async fn read_index_view(
+    cache: &Arc<KvStore>,
+    env: &Env,
+    index: &str,
+    exact: bool,
+) -> Option<IndexView> {
+    let mut index_data = IndexManager::new(cache).read_index(index).await.ok()?;
+    if exact {
+        match read_exact_docs_count(env, index).await {
+            Ok(count) => index_data.docs_count = count,
+            Err(err) => edge_log!(
+                console_warn,
+                "Indexes",
+                index,
+                "Failed to read the exact document count: {}",
+                err
+            ),
+        }
+    }
+    let stoplisted_keywords = match StopList::load(cache, index).await {
+        Ok(stoplist) => stoplist.count_stored(cache).await.unwrap_or(0),
+        Err(_) => 0,
+    };
+    Some(IndexView {
+        index: index_data,
+        stoplisted_keywords,
+    })
+}
+
+/// `GET /:index`: the index document, with `?exact=true` counting documents not yet
+/// flushed to it
+pub async fn handle_view(req: Request, ctx: RouteContext<Context>) -> Result<Response> {
+    let cache = get_kv_data_store(&ctx);
+    let Some(index) = ctx.param("index") else {
+        return json_error(400, ErrorCode::MissingParameter, "Missing index name");
+    };
+    let exact = req
+        .query::<ViewParams>()
+        .ok()
+        .and_then(|params| params.exact)
+        .unwrap_or(false);
+    let Some(view) = read_index_view(&cache, &ctx.env, index, exact).await else {
+        return json_error(404, ErrorCode::IndexNotFound, "Index not found");
+    };
+    let etag = view.etag();
+    if let Some(response) = not_modified(&req, &etag)? {
+        return Ok(response);
+    }
+    with_etag(Response::from_json(&view)?, &etag)
 }
 
-pub async fn handle_create(_req: Request, ctx: RouteContext<()>) -> Result<Response> {
+/// `HEAD /:index`: the status and headers `GET` would send
+pub async fn handle_head(req: Request, ctx: RouteContext<Context>) -> Result<Response> {
+    let cache = get_kv_data_store(&ctx);
+    let Some(index) = ctx.param("index") else {
+        return json_error(400, ErrorCode::MissingParameter, "Missing index name");
+    };
+    let Some(view) = read_index_view(&cache, &ctx.env, index, false).await else {
+        return json_error(404, ErrorCode::IndexNotFound, "Index not found");
+    };
+    let etag = view.etag();
+    if let Some(response) = not_modified(&req, &etag)? {
+        return Ok(response);
+    }
+    head_response(&etag, json_length(&view))
+}
+
+#[derive(serde::Deserialize)]
+struct CreateIndexParams {
+    lang: Option<String>,
+}
+
+/// `PUT /:index`: create an index, or return the existing one. A JSON
+/// [`IndexSettings`] body sets the index's search defaults, replacing those of an
+/// existing index, and its `description` and `labels` replace the index's own.
+pub async fn handle_create(mut req: Request, ctx: RouteContext<Context>) -> Result<Response> {
     let cache = get_kv_data_store(&ctx);
     if let Some(index) = ctx.param("index") {
-        let indexer = IndexManager::new(&cache);
-        if IndexDocument::is_reserved_index(index) {
-            return Response::error(
-                ErrorResponse {
-                    error: "Index name is reserved".into(),
-                },
+        let indexer = IndexManager::new(&cache).with_n_shards(get_n_shards(&ctx.env));
+        if !IndexDocument::is_valid_name(index) {
+            return json_error(
                 400,
+                ErrorCode::InvalidIndexName,
+                IndexDocument::INVALID_NAME_MESSAGE,
             );
         }
+        if IndexDocument::is_reserved_index(index) {
+            return json_error(400, ErrorCode::InvalidIndexName, "Index name is reserved");
+        }
 
-        let index_data = indexer.create_index(index).await.unwrap();
-        return Response::from_json(&index_data);
+        let params = req.query::<CreateIndexParams>()?;
+        let default_lang = match params.lang.as_deref().map(IsoCode639_1::from_str) {
+            None => None,
+            Some(Ok(lang)) => Some(lang),
+            Some(Err(_)) => {
+                return json_error(
+                    400,
+                    ErrorCode::InvalidRequest,
+                    format!("Unknown language '{}'", params.lang.unwrap_or_default()),
+                )
+            }
+        };
+
+        let body = req.text().await?;
+        let (settings, metadata) = match body.trim() {
+            "" => (None, IndexMetadata::default()),
+            body => match parse_index_body(body) {
+                Ok(parsed) => parsed,
+                Err(error) => return json_error(400, ErrorCode::InvalidRequest, error),
+            },
+        };
+
+        return match create_or_update(&indexer, index, default_lang, settings, metadata).await {
+            Ok(index_data) => Response::from_json(&index_data),
+            Err(rejection) => rejection.into_response(),
+        };
     }
-    Response::error(
-        ErrorResponse {
-            error: "Missing index name".into(),
-        },
-        400,
-    )
+    json_error(400, ErrorCode::MissingParameter, "Missing index name")
+}
+
+/// Create `index`, or update the existing index when `settings` or `metadata` are
+/// given. An index that exists but can't be read is never overwritten.
+async fn create_or_update<S: Storage>(
+    indexer: &IndexManager<'_, S>,
+    index: &str,
+    default_lang: Option<IsoCode639_1>,
+    settings: Option<IndexSettings>,
+    metadata: IndexMetadata,
+) -> std::result::Result<IndexDocument, Rejection> {
+    match indexer.read_index(index).await {
+        Ok(_) if settings.is_some() || !metadata.is_empty() => {
+            indexer.update_index(index, settings, metadata).await
+        }
+        Ok(_) | Err(DataStoreError::NotFound(_)) => {
+            indexer
+                .create_index_with_metadata(index, default_lang, settings, metadata)
+                .await
+        }
+        Err(err) => Err(err),
+    }
+    .map_err(index_store_error)
 }
 
-pub async fn handle_delete(_req: Request, ctx: RouteContext<()>) -> Result<Response> {
+/// `PATCH /:index`: change the description and labels of an existing index without
+/// touching its settings
+pub async fn handle_update_metadata(
+    mut req: Request,
+    ctx: RouteContext<Context>,
+) -> Result<Response> {
+    let Some(index) = ctx.param("index") else {
+        return json_error(400, ErrorCode::MissingParameter, "Missing index name");
+    };
+    let metadata = match IndexMetadata::parse(&req.text().await?) {
+        Ok(metadata) => metadata,
+        Err(error) => return json_error(400, ErrorCode::InvalidRequest, error),
+    };
+    let cache = get_kv_data_store(&ctx);
+    match IndexManager::new(&cache)
+        .update_metadata(index, metadata)
+        .await
+    {
+        Ok(index_data) => Response::from_json(&index_data),
+        Err(err) => index_store_error(err).into_response(),
+    }
+}
+
+/// Freeze an index, so document writes are rejected with 423 until it is unfrozen
+pub async fn handle_freeze(req: Request, ctx: RouteContext<Context>) -> Result<Response> {
+    set_frozen(req, ctx, true).await
+}
+
+pub async fn handle_unfreeze(req: Request, ctx: RouteContext<Context>) -> Result<Response> {
+    set_frozen(req, ctx, false).await
+}
+
+async fn set_frozen(req: Request, ctx: RouteContext<Context>, frozen: bool) -> Result<Response> {
+    // Freezing gates every writer, so AUTH_DISABLED alone doesn't allow it
+    if !crate::presents_api_key(&req, &ctx.env) {
+        return json_error(
+            403,
+            ErrorCode::Unauthorized,
+            "Freezing or unfreezing an index requires the API key",
+        );
+    }
+    let Some(index) = ctx.param("index") else {
+        return json_error(400, ErrorCode::MissingParameter, "Missing index name");
+    };
+    let cache = get_kv_data_store(&ctx);
+    if let Some(response) = check_index(&cache, index, false).await? {
+        return Ok(response);
+    }
+    let indexer = IndexManager::new(&cache);
+    // Writes during a reshard would land in shards it's about to replace
+    if !frozen {
+        if let Ok(IndexDocument {
+            reshard: Some(reshard),
+            ..
+        }) = indexer.read_index(index).await
+        {
+            return json_error(
+                409,
+                ErrorCode::ReshardInProgress,
+                format!(
+                    "A reshard to {} shards is in progress, and must finish before the index is unfrozen",
+                    reshard.target_shards
+                ),
+            );
+        }
+    }
+    match indexer.set_frozen(index, frozen).await {
+        Ok(index_data) => Response::from_json(&index_data),
+        Err(err) => index_store_error(err).into_response(),
+    }
+}
+
+pub async fn handle_delete(_req: Request, ctx: RouteContext<Context>) -> Result<Response> {
     let cache = get_kv_data_store(&ctx);
     if let Some(index) = ctx.param("index") {
         let indexer = IndexManager::new(&cache);
-        indexer.delete_index(index).await.unwrap();
-        return Response::from_json(&DeletedResponse { deleted: true });
+        return match indexer.delete_index(index).await {
+            Ok(()) => Response::from_json(&DeletedResponse { deleted: true }),
+            Err(err) => index_store_error(err).into_response(),
+        };
+    }
+    json_error(400, ErrorCode::MissingParameter, "Missing index name")
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+
+    use super::*;
+    use crate::data::storage::memory::MemoryStorage;
+
+    fn assert_retryable(rejection: Rejection) {
+        assert_eq!(
+            (rejection.status, rejection.code, rejection.retryable),
+            (502, ErrorCode::InternalError, true)
+        );
+    }
+
+    #[test]
+    fn test_list_errors_are_retryable() {
+        let store = MemoryStorage::default();
+        let indexer = IndexManager::new(&store);
+        store.fail_lists("index:", 2);
+        assert_retryable(index_store_error(
+            block_on(indexer.list_indexes()).err().unwrap(),
+        ));
+        let reader = BulkReader::new(48, &store, None);
+        let listed = block_on(indexer.list_indexes_detailed(&reader, 10));
+        assert_retryable(index_store_error(listed.err().unwrap()));
+        assert!(block_on(indexer.list_indexes()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_create_errors() {
+        let store = MemoryStorage::default();
+        let indexer = IndexManager::new(&store);
+        store.fail_puts("index:create-errors", 1);
+        let failed = block_on(create_or_update(
+            &indexer,
+            "create-errors",
+            None,
+            None,
+            IndexMetadata::default(),
+        ));
+        assert_retryable(failed.err().unwrap());
+        assert!(store.keys().is_empty());
+
+        // A transient read of an existing index doesn't overwrite it
+        let settings = IndexSettings {
+            limit: Some(5),
+            ..IndexSettings::default()
+        };
+        block_on(create_or_update(
+            &indexer,
+            "create-errors",
+            None,
+            Some(settings.clone()),
+            IndexMetadata::default(),
+        ))
+        .unwrap();
+        store.fail_gets("index:create-errors", 1);
+        let failed = block_on(create_or_update(
+            &indexer,
+            "create-errors",
+            None,
+            None,
+            IndexMetadata::default(),
+        ));
+        assert_retryable(failed.err().unwrap());
+        let stored = block_on(indexer.read_index("create-errors")).unwrap();
+        assert_eq!(stored.settings, settings);
+
+        block_on(store.put("index:create-broken", "not json".into())).unwrap();
+        let broken = block_on(create_or_update(
+            &indexer,
+            "create-broken",
+            None,
+            None,
+            IndexMetadata::default(),
+        ));
+        let broken = broken.err().unwrap();
+        assert_eq!((broken.status, broken.retryable), (500, false));
+    }
+
+    #[test]
+    fn test_put_body_metadata() {
+        let store = MemoryStorage::default();
+        let indexer = IndexManager::new(&store);
+        let put = |body: &str| {
+            let (settings, metadata) = parse_index_body(body).unwrap();
+            block_on(create_or_update(
+                &indexer,
+                "described",
+                None,
+                settings,
+                metadata,
+            ))
+            .unwrap()
+        };
+
+        let created =
+            put(r#"{"limit":5,"description":"Support articles","labels":{"env":"prod"}}"#);
+        assert_eq!(created.settings.limit, Some(5));
+        assert_eq!(created.description.as_deref(), Some("Support articles"));
+        assert!(created.has_label("env", "prod"));
+
+        // Metadata alone leaves the settings as they were
+        let relabelled = put(r#"{"labels":{"env":"staging"}}"#);
+        assert_eq!(relabelled.settings.limit, Some(5));
+        assert_eq!(relabelled.description.as_deref(), Some("Support articles"));
+        assert!(relabelled.has_label("env", "staging"));
+        assert_eq!(relabelled.generation, 1);
+
+        // Settings alone leave the metadata alone
+        let resettled = put(r#"{"limit":7}"#);
+        assert_eq!(resettled.labels, relabelled.labels);
+        let cleared = put(r#"{"description":""}"#);
+        assert_eq!(
+            (cleared.description, cleared.settings.limit),
+            (None, Some(7))
+        );
+    }
+
+    #[test]
+    fn test_delete_errors_are_retryable() {
+        let store = MemoryStorage::default();
+        let indexer = IndexManager::new(&store);
+        block_on(indexer.create_index("delete-errors", None, None)).unwrap();
+        store.fail_deletes("index:delete-errors", 1);
+        let failed = block_on(indexer.delete_index("delete-errors"));
+        assert_retryable(index_store_error(failed.err().unwrap()));
+        assert!(block_on(indexer.index_exists("delete-errors")).unwrap());
+
+        block_on(indexer.delete_index("delete-errors")).unwrap();
+        assert!(!block_on(indexer.index_exists("delete-errors")).unwrap());
     }
-    return Response::error(
-        ErrorResponse {
-            error: "Missing index name".into(),
-        },
-        400,
-    );
 }
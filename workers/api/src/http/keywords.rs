@@ -2,45 +2,485 @@ use std::collections::HashMap;
 
 use worker::{Request, Response};
 
-use crate::{data::keyword::KeywordManager, util::kv::get_kv_data_store};
+use crate::{
+    data::{
+        deletion::pending_deletions,
+        keyword::KeywordManager,
+        keyword_shard::get_n_shards,
+        related::{DEFAULT_RELATED_LIMIT, MAX_RELATED_LIMIT},
+        trend::{read_trend, TrendDay, DEFAULT_TREND_DAYS, MAX_TREND_DAYS},
+        DataStoreError,
+    },
+    durable::reader::get_batch_keyword_limit,
+    http::{
+        allows_missing_index, check_index, decoded_param, index_codecs, json_error, ErrorCode,
+        Rejection,
+    },
+    lexer::check_keyword,
+    util::{kv::get_kv_data_store, time::now_ms},
+};
 
 #[derive(serde::Serialize)]
 struct GetKeywordResponse {
     keyword: String,
+    /// Postings in this page
     document_count: u32,
-    scores: HashMap<String, f64>,
+    /// Postings of the keyword, estimated when `exact` is false
+    total: usize,
+    /// False when only some shards were read for a small page, so a better scored
+    /// posting may have been left out
+    exact: bool,
+    /// The newest timestamp of the keyword's shards, or null when a page was read
+    /// from their top-K summaries or the keyword has no shards
+    newest_ts: Option<u64>,
+    scores: PostingScores,
+}
+
+#[derive(Debug, PartialEq, serde::Serialize)]
+struct DocumentScore {
+    doc_id: String,
+    score: f64,
+}
+
+#[derive(Debug, PartialEq, serde::Serialize)]
+#[serde(untagged)]
+enum PostingScores {
+    /// Every posting, best scored first
+    Ranked(Vec<DocumentScore>),
+    /// Deprecated: the scores keyed by document, requested with `format=map`. It will
+    /// be removed in the next release.
+    Map(HashMap<String, f64>),
+}
+
+impl PostingScores {
+    fn new(merged: Vec<(String, f64)>, as_map: bool) -> PostingScores {
+        if as_map {
+            return PostingScores::Map(merged.into_iter().collect());
+        }
+        let scores = merged
+            .into_iter()
+            .map(|(doc_id, score)| DocumentScore { doc_id, score })
+            .collect();
+        PostingScores::Ranked(scores)
+    }
+}
+
+/// How a failed read of a keyword's shards is answered: a retryable 502, as the
+/// shards are usually only out of reach for a moment
+pub fn keyword_read_error(err: DataStoreError) -> Rejection {
+    Rejection {
+        retryable: true,
+        ..Rejection::new(
+            502,
+            ErrorCode::InternalError,
+            format!("Failed to read the keyword's shards: {}", err),
+        )
+    }
+}
+
+/// Why `keywords` can't be looked up, see [`check_keyword`]
+pub fn invalid_keyword<'k>(mut keywords: impl Iterator<Item = &'k String>) -> Option<String> {
+    keywords.find_map(|keyword| check_keyword(keyword).err().map(|err| err.to_string()))
+}
+
+#[derive(serde::Deserialize)]
+struct GetKeywordParams {
+    format: Option<String>,
+    offset: Option<usize>,
+    limit: Option<usize>,
+}
+
+/// Pages ending within this many postings are gathered from a sample of the shards
+pub const MAX_SAMPLED_PAGE_END: usize = 100;
+
+/// A page of postings, taken after they are sorted by score
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct KeywordPage {
+    offset: usize,
+    limit: Option<usize>,
+}
+
+impl KeywordPage {
+    /// The postings to gather when the page is small enough to sample shards for
+    fn sampled(&self) -> Option<usize> {
+        let end = self.offset.checked_add(self.limit?)?;
+        (end <= MAX_SAMPLED_PAGE_END).then_some(end)
+    }
+
+    fn apply(&self, postings: Vec<(String, f64)>) -> Vec<(String, f64)> {
+        let page = postings.into_iter().skip(self.offset);
+        match self.limit {
+            Some(limit) => page.take(limit).collect(),
+            None => page.collect(),
+        }
+    }
 }
+
+/// `GET /:index/keyword/:keyword`: the postings of the keyword, best scored first,
+/// paged with `offset` and `limit`
 pub async fn handle_get_keyword(
-    _req: Request,
-    ctx: worker::RouteContext<()>,
+    req: Request,
+    ctx: worker::RouteContext<worker::Context>,
 ) -> worker::Result<Response> {
     if let Some(index) = ctx.param("index") {
-        if let Some(keyword) = ctx.param("keyword") {
+        if let Some(keyword) = decoded_param(&ctx, "keyword") {
+            if let Some(error) = invalid_keyword([&keyword].into_iter()) {
+                return json_error(400, ErrorCode::InvalidRequest, error);
+            }
             let state = get_kv_data_store(&ctx);
-            let manager = KeywordManager::new(index.into(), &ctx.env, &state);
-            let merged = manager.merge_keyword_shards(keyword.into()).await.unwrap();
+            let Ok(params) = req.query::<GetKeywordParams>() else {
+                return json_error(
+                    400,
+                    ErrorCode::InvalidRequest,
+                    "offset and limit must be non-negative integers",
+                );
+            };
+            let as_map = match params.format.as_deref() {
+                None => false,
+                Some("map") => true,
+                Some(_) => {
+                    return json_error(
+                        400,
+                        ErrorCode::InvalidRequest,
+                        "format must be left out or 'map'",
+                    )
+                }
+            };
+            let page = KeywordPage {
+                offset: params.offset.unwrap_or(0),
+                limit: params.limit,
+            };
+            if let Some(response) = check_index(&state, index, allows_missing_index(&req)).await? {
+                return Ok(response);
+            }
+
+            let codecs = match index_codecs(&state, index).await {
+                Ok(codecs) => codecs,
+                Err(rejection) => return rejection.into_response(),
+            };
+            let manager = KeywordManager::new(index.into(), &ctx.env, &state).with_codecs(codecs);
+            let (mut merged, total, exact, newest_ts) = match page.sampled() {
+                Some(wanted) => match manager.top_keyword_postings(keyword.clone(), wanted).await {
+                    Ok(top) => (top.postings, top.total, top.exact, top.newest_ts),
+                    Err(err) => return keyword_read_error(err).into_response(),
+                },
+                None => match manager.merge_keyword_shards_stamped(keyword.clone()).await {
+                    Ok((stamped, _)) => {
+                        let total = stamped.postings.len();
+                        (stamped.postings, total, true, stamped.newest_ts)
+                    }
+                    Err(err) => return keyword_read_error(err).into_response(),
+                },
+            };
 
-            let document_count = merged.len() as u32;
-            let scores: HashMap<String, f64> = merged.into_iter().collect();
+            // Documents whose delete is under way are treated as already deleted
+            let pending = pending_deletions(&state, index).await.unwrap_or_default();
+            let listed = merged.len();
+            merged.retain(|(doc_id, _)| !pending.contains(doc_id));
+            let total = total.saturating_sub(listed - merged.len());
 
+            let postings = page.apply(merged);
             return Response::from_json(&GetKeywordResponse {
-                keyword: keyword.into(),
-                document_count,
-                scores,
+                keyword,
+                document_count: postings.len() as u32,
+                total,
+                exact,
+                newest_ts,
+                scores: PostingScores::new(postings, as_map),
             });
         } else {
-            return Response::error(
-                crate::http::ErrorResponse {
-                    error: "Missing keyword".into(),
-                },
+            return json_error(400, ErrorCode::MissingParameter, "Missing keyword");
+        }
+    }
+    json_error(400, ErrorCode::MissingParameter, "Missing index name")
+}
+
+#[derive(serde::Deserialize)]
+struct RelatedKeywordsParams {
+    limit: Option<usize>,
+}
+
+/// The number of related keywords to return, when `limit=` is left out or out of range
+pub fn related_limit(limit: Option<usize>) -> usize {
+    limit
+        .unwrap_or(DEFAULT_RELATED_LIMIT)
+        .clamp(1, MAX_RELATED_LIMIT)
+}
+
+/// `GET /:index/keyword/:keyword/related`: the keywords that most often appear in
+/// the same documents as `keyword`
+pub async fn handle_related_keywords(
+    req: Request,
+    ctx: worker::RouteContext<worker::Context>,
+) -> worker::Result<Response> {
+    let (Some(index), Some(keyword)) = (ctx.param("index"), decoded_param(&ctx, "keyword")) else {
+        return json_error(400, ErrorCode::MissingParameter, "Missing index or keyword");
+    };
+    if let Some(error) = invalid_keyword([&keyword].into_iter()) {
+        return json_error(400, ErrorCode::InvalidRequest, error);
+    }
+    let Ok(params) = req.query::<RelatedKeywordsParams>() else {
+        return json_error(400, ErrorCode::InvalidRequest, "limit must be a number");
+    };
+
+    let state = get_kv_data_store(&ctx);
+    if let Some(response) = check_index(&state, index, allows_missing_index(&req)).await? {
+        return Ok(response);
+    }
+
+    let codecs = match index_codecs(&state, index).await {
+        Ok(codecs) => codecs,
+        Err(rejection) => return rejection.into_response(),
+    };
+    let manager = KeywordManager::new(index.into(), &ctx.env, &state).with_codecs(codecs);
+    match manager
+        .related_keywords(keyword, related_limit(params.limit))
+        .await
+    {
+        Ok(related) => Response::from_json(&related),
+        Err(err) => json_error(
+            500,
+            ErrorCode::InternalError,
+            format!("Failed to load related keywords: {}", err),
+        ),
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct KeywordTrendParams {
+    days: Option<u32>,
+}
+
+/// The number of days a trend covers, when `days=` is left out or out of range
+pub fn trend_days(days: Option<u32>) -> u32 {
+    days.unwrap_or(DEFAULT_TREND_DAYS).clamp(1, MAX_TREND_DAYS)
+}
+
+#[derive(serde::Serialize)]
+struct KeywordTrendResponse {
+    keyword: String,
+    /// Oldest first, ending today
+    days: Vec<TrendDay>,
+}
+
+/// `GET /:index/keyword/:keyword/trend`: how many documents gained or lost the
+/// keyword on each of the last `days` days, see [`crate::data::trend`]
+pub async fn handle_keyword_trend(
+    req: Request,
+    ctx: worker::RouteContext<worker::Context>,
+) -> worker::Result<Response> {
+    let (Some(index), Some(keyword)) = (ctx.param("index"), decoded_param(&ctx, "keyword")) else {
+        return json_error(400, ErrorCode::MissingParameter, "Missing index or keyword");
+    };
+    if let Some(error) = invalid_keyword([&keyword].into_iter()) {
+        return json_error(400, ErrorCode::InvalidRequest, error);
+    }
+    let Ok(params) = req.query::<KeywordTrendParams>() else {
+        return json_error(400, ErrorCode::InvalidRequest, "days must be a number");
+    };
+
+    let state = get_kv_data_store(&ctx);
+    if let Some(response) = check_index(&state, index, allows_missing_index(&req)).await? {
+        return Ok(response);
+    }
+    match read_trend(&state, index, &keyword, now_ms(), trend_days(params.days)).await {
+        Ok(days) => Response::from_json(&KeywordTrendResponse { keyword, days }),
+        Err(err) => json_error(
+            500,
+            ErrorCode::InternalError,
+            format!("Failed to load the keyword's trend: {}", err),
+        ),
+    }
+}
+
+#[derive(serde::Serialize)]
+struct BatchKeywordEntry {
+    document_count: u32,
+    scores: HashMap<String, f64>,
+}
+
+/// Dispatches `/:index/keywords:<action>` routes, currently only `:batch`
+pub async fn handle_keywords_action(
+    req: Request,
+    ctx: worker::RouteContext<worker::Context>,
+) -> worker::Result<Response> {
+    match ctx.param("action").map(|action| action.as_str()) {
+        Some(":batch") => handle_batch_keywords(req, ctx).await,
+        _ => json_error(404, ErrorCode::NotFound, "Unknown keywords action"),
+    }
+}
+
+async fn handle_batch_keywords(
+    mut req: Request,
+    ctx: worker::RouteContext<worker::Context>,
+) -> worker::Result<Response> {
+    if let Some(index) = ctx.param("index") {
+        let state = get_kv_data_store(&ctx);
+        if let Some(response) = check_index(&state, index, allows_missing_index(&req)).await? {
+            return Ok(response);
+        }
+
+        let keywords = match req.json::<Vec<String>>().await {
+            Ok(keywords) => keywords,
+            Err(_) => {
+                return json_error(
+                    400,
+                    ErrorCode::InvalidRequest,
+                    "Request body must be a JSON array of keywords",
+                );
+            }
+        };
+
+        let limit = get_batch_keyword_limit(get_n_shards(&ctx.env));
+        if keywords.len() > limit as usize {
+            return json_error(
                 400,
+                ErrorCode::InvalidRequest,
+                format!("Too many keywords requested. Current limit: {}", limit),
             );
         }
+        if let Some(error) = invalid_keyword(keywords.iter()) {
+            return json_error(400, ErrorCode::InvalidRequest, error);
+        }
+
+        let codecs = match index_codecs(&state, index).await {
+            Ok(codecs) => codecs,
+            Err(rejection) => return rejection.into_response(),
+        };
+        let manager = KeywordManager::new(index.into(), &ctx.env, &state).with_codecs(codecs);
+        let merged = match manager.merge_many_keyword_shards(keywords).await {
+            Ok(merged) => merged,
+            Err(err) => {
+                return json_error(
+                    500,
+                    ErrorCode::InternalError,
+                    format!("Failed to load keywords: {}", err),
+                );
+            }
+        };
+
+        let response: HashMap<String, BatchKeywordEntry> = merged
+            .into_iter()
+            .map(|(keyword, docs)| {
+                (
+                    keyword,
+                    BatchKeywordEntry {
+                        document_count: docs.len() as u32,
+                        scores: docs.into_iter().collect(),
+                    },
+                )
+            })
+            .collect();
+        return Response::from_json(&response);
+    }
+    json_error(400, ErrorCode::MissingParameter, "Missing index name")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::MAX_KEYWORD_CHARS;
+
+    #[test]
+    fn test_invalid_keyword() {
+        let keywords =
+            |words: &[&str]| -> Vec<String> { words.iter().map(|w| w.to_string()).collect() };
+        assert_eq!(invalid_keyword(keywords(&["ocean", "tide"]).iter()), None);
+        assert_eq!(
+            invalid_keyword(keywords(&["ocean", " \t"]).iter()).as_deref(),
+            Some("Keywords can't be empty or only whitespace")
+        );
+        assert!(invalid_keyword(keywords(&[""]).iter()).is_some());
+        let long = "a".repeat(MAX_KEYWORD_CHARS + 1);
+        assert_eq!(
+            invalid_keyword([&long].into_iter()).as_deref(),
+            Some("Keyword is 129 characters, longer than the 128 character limit")
+        );
+    }
+
+    #[test]
+    fn test_keyword_read_errors_are_retryable() {
+        let err = DataStoreError::Worker(worker::Error::RustError("KV unavailable".into()));
+        let rejection = keyword_read_error(err);
+        assert_eq!(
+            (rejection.status, rejection.code, rejection.retryable),
+            (502, ErrorCode::InternalError, true)
+        );
+    }
+
+    #[test]
+    fn test_related_limit() {
+        assert_eq!(related_limit(None), DEFAULT_RELATED_LIMIT);
+        assert_eq!(related_limit(Some(0)), 1);
+        assert_eq!(related_limit(Some(25)), 25);
+        assert_eq!(related_limit(Some(10_000)), MAX_RELATED_LIMIT);
+    }
+
+    #[test]
+    fn test_trend_days() {
+        assert_eq!(trend_days(None), DEFAULT_TREND_DAYS);
+        assert_eq!(trend_days(Some(0)), 1);
+        assert_eq!(trend_days(Some(7)), 7);
+        assert_eq!(trend_days(Some(365)), MAX_TREND_DAYS);
+    }
+
+    #[test]
+    fn test_keyword_page() {
+        let postings: Vec<(String, f64)> = (0..5)
+            .map(|i| (format!("doc{}", i), 1.0 - i as f64 / 10.0))
+            .collect();
+        let page = KeywordPage {
+            offset: 1,
+            limit: Some(2),
+        };
+        let paged: Vec<String> = page
+            .apply(postings.clone())
+            .into_iter()
+            .map(|(doc_id, _)| doc_id)
+            .collect();
+        assert_eq!(paged, vec!["doc1", "doc2"]);
+        assert_eq!(page.sampled(), Some(3));
+
+        let everything = KeywordPage {
+            offset: 3,
+            limit: None,
+        };
+        assert_eq!(everything.apply(postings).len(), 2);
+        assert_eq!(everything.sampled(), None);
+
+        // Deep pages are read exactly
+        let deep = KeywordPage {
+            offset: MAX_SAMPLED_PAGE_END,
+            limit: Some(1),
+        };
+        assert_eq!(deep.sampled(), None);
+        let overflow = KeywordPage {
+            offset: usize::MAX,
+            limit: Some(1),
+        };
+        assert_eq!(overflow.sampled(), None);
+    }
+
+    #[test]
+    fn test_keyword_scores_formats() {
+        let merged = vec![
+            ("doc2".to_string(), 0.9),
+            ("doc1".to_string(), 0.4),
+            ("doc2".to_string(), 0.1),
+        ];
+
+        let ranked = serde_json::to_value(PostingScores::new(merged.clone(), false)).unwrap();
+        assert_eq!(
+            ranked,
+            serde_json::json!([
+                {"doc_id": "doc2", "score": 0.9},
+                {"doc_id": "doc1", "score": 0.4},
+                {"doc_id": "doc2", "score": 0.1},
+            ])
+        );
+
+        // The deprecated map keeps one score per document
+        let map = serde_json::to_value(PostingScores::new(merged, true)).unwrap();
+        assert_eq!(map, serde_json::json!({"doc2": 0.1, "doc1": 0.4}));
     }
-    return Response::error(
-        crate::http::ErrorResponse {
-            error: "Missing index name".into(),
-        },
-        400,
-    );
 }
@@ -1,21 +1,609 @@
+pub mod activity;
 pub mod documents;
+pub mod es_bulk;
+pub mod export;
+pub mod fsck;
 pub mod index;
 pub mod indexes;
 pub mod keywords;
+pub mod openapi;
+pub mod queries;
+pub mod render_html;
+pub mod reshard;
 pub mod search;
+pub mod snapshot;
+pub mod stoplist;
+pub mod templates;
+pub mod top;
+pub mod upgrade;
+pub mod usage;
+pub mod warm;
+
+use std::sync::Arc;
+
+use worker::{kv::KvStore, Context, Env, Request, Response, Result, RouteContext};
+
+use crate::{
+    data::{
+        codec::CodecSet, index::IndexDocument, index_manager::IndexManager, storage::Storage,
+        DataStoreError,
+    },
+    util::http::decode_path_param,
+};
+
+/// The request formats this worker accepts beyond those every version has, named
+/// in `GET /` so clients can use them only where they're understood
+pub const CAPABILITIES: &[&str] = &[CAPABILITY_QUERY_AST];
+
+/// `POST /:index/search` takes the query's AST as its body, see [`crate::lexer::ast`]
+pub const CAPABILITY_QUERY_AST: &str = "query_ast";
 
 #[derive(serde::Serialize)]
 pub struct StatusResponse {
     pub ready: bool,
+    pub capabilities: &'static [&'static str],
 }
 
-#[derive(serde::Serialize)]
+/// A stable, machine-readable reason for an error, sent as `code` alongside the message
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    MissingParameter,
+    InvalidRequest,
+    InvalidIndexName,
+    InvalidDocumentId,
+    InvalidQuery,
+    Unauthorized,
+    NotFound,
+    IndexNotFound,
+    DocumentNotFound,
+    MethodNotAllowed,
+    DocumentExists,
+    PayloadTooLarge,
+    InternalError,
+    Misconfigured,
+    IndexFrozen,
+    IndexNotFrozen,
+    ReshardInProgress,
+    SnapshotInProgress,
+    UnsupportedIndexVersion,
+    UnsupportedMediaType,
+}
+
+impl ErrorCode {
+    #[cfg(test)]
+    pub const ALL: [ErrorCode; 20] = [
+        ErrorCode::MissingParameter,
+        ErrorCode::InvalidRequest,
+        ErrorCode::InvalidIndexName,
+        ErrorCode::InvalidDocumentId,
+        ErrorCode::InvalidQuery,
+        ErrorCode::Unauthorized,
+        ErrorCode::NotFound,
+        ErrorCode::IndexNotFound,
+        ErrorCode::DocumentNotFound,
+        ErrorCode::MethodNotAllowed,
+        ErrorCode::DocumentExists,
+        ErrorCode::PayloadTooLarge,
+        ErrorCode::InternalError,
+        ErrorCode::Misconfigured,
+        ErrorCode::IndexFrozen,
+        ErrorCode::IndexNotFrozen,
+        ErrorCode::ReshardInProgress,
+        ErrorCode::SnapshotInProgress,
+        ErrorCode::UnsupportedIndexVersion,
+        ErrorCode::UnsupportedMediaType,
+    ];
+}
+
+/// The body of every error response, and what its HTML page is rendered from
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
 pub struct ErrorResponse {
     pub error: String,
+    pub code: ErrorCode,
+    /// Set when the same request may succeed if sent again, such as after a KV error
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub retryable: bool,
 }
 
-impl Into<String> for ErrorResponse {
-    fn into(self) -> String {
-        serde_json::to_string(&self).unwrap_or_else(|_| "{\"error\":\"internal error\"}".into())
+pub const ERROR_CONTENT_TYPE: &str = "application/json";
+
+/// Build an error response with the JSON [`ErrorResponse`] envelope and a JSON
+/// `Content-Type`, which `Response::error` leaves unset
+pub fn json_error(status: u16, code: ErrorCode, message: impl Into<String>) -> Result<Response> {
+    error_response(
+        status,
+        &ErrorResponse {
+            error: message.into(),
+            code,
+            retryable: false,
+        },
+    )
+}
+
+fn error_response(status: u16, body: &ErrorResponse) -> Result<Response> {
+    let json =
+        serde_json::to_string(body).map_err(|err| worker::Error::RustError(err.to_string()))?;
+    let mut response = Response::ok(json)?.with_status(status);
+    response
+        .headers_mut()
+        .set("Content-Type", ERROR_CONTENT_TYPE)?;
+    Ok(response)
+}
+
+/// Why a request was rejected, and the status code to reject it with
+#[derive(Debug, PartialEq)]
+pub struct Rejection {
+    pub status: u16,
+    pub code: ErrorCode,
+    pub error: String,
+    pub retryable: bool,
+}
+
+impl Rejection {
+    pub fn new(status: u16, code: ErrorCode, error: impl Into<String>) -> Rejection {
+        Rejection {
+            status,
+            code,
+            error: error.into(),
+            retryable: false,
+        }
+    }
+
+    /// How a failed store operation is answered: a missing key with 404 and
+    /// `not_found`, a KV or worker error, which is usually transient, with a
+    /// retryable 502, and anything else, like a record that doesn't deserialize,
+    /// with 500
+    pub fn from_store_error(err: DataStoreError, not_found: ErrorCode) -> Rejection {
+        match err {
+            DataStoreError::NotFound(_) => Rejection::new(404, not_found, err.to_string()),
+            DataStoreError::UnsupportedVersion(_) => {
+                Rejection::new(426, ErrorCode::UnsupportedIndexVersion, err.to_string())
+            }
+            DataStoreError::Kv(_) | DataStoreError::Worker(_) => Rejection {
+                retryable: true,
+                ..Rejection::new(502, ErrorCode::InternalError, err.to_string())
+            },
+            _ => Rejection::new(500, ErrorCode::InternalError, err.to_string()),
+        }
+    }
+
+    pub fn into_response(self) -> Result<Response> {
+        error_response(
+            self.status,
+            &ErrorResponse {
+                error: self.error,
+                code: self.code,
+                retryable: self.retryable,
+            },
+        )
+    }
+}
+
+/// A worker error from the runtime or KV, which is usually transient, is a retryable
+/// 502, and anything else, like a body that doesn't parse, a 500
+impl From<worker::Error> for Rejection {
+    fn from(err: worker::Error) -> Self {
+        match err {
+            worker::Error::JsError(_)
+            | worker::Error::Internal(_)
+            | worker::Error::Io(_)
+            | worker::Error::KvError(_)
+            | worker::Error::RustError(_) => Rejection {
+                retryable: true,
+                ..Rejection::new(502, ErrorCode::InternalError, err.to_string())
+            },
+            _ => Rejection::new(500, ErrorCode::InternalError, err.to_string()),
+        }
+    }
+}
+
+/// A 403 response unless the request presents the API key itself, for routes that
+/// walk every key of an index. Walking every key is expensive, so AUTH_DISABLED
+/// alone doesn't allow it. `what` names what the route does, as in "Exporting an
+/// index's keywords".
+pub fn require_api_key(req: &Request, env: &Env, what: &str) -> Option<Result<Response>> {
+    if crate::presents_api_key(req, env) {
+        return None;
+    }
+    Some(json_error(
+        403,
+        ErrorCode::Unauthorized,
+        format!("{} requires the API key", what),
+    ))
+}
+
+/// A route parameter holding user text, like a keyword or document ID, decoded once
+/// here so the layers below always receive the exact string
+pub fn decoded_param(ctx: &RouteContext<Context>, name: &str) -> Option<String> {
+    ctx.param(name).map(|value| decode_path_param(value))
+}
+
+#[derive(serde::Deserialize)]
+struct IndexLookupParams {
+    allow_missing: Option<bool>,
+}
+
+/// Whether the request opted out of index existence checks with `allow_missing=true`
+pub fn allows_missing_index(req: &Request) -> bool {
+    req.query::<IndexLookupParams>()
+        .ok()
+        .and_then(|params| params.allow_missing)
+        .unwrap_or(false)
+}
+
+/// Returns a 400 response when the index name is malformed, or a 404 response naming
+/// the index when it doesn't exist, so typos in an index name aren't mistaken for
+/// empty data.
+pub async fn check_index<S: Storage>(
+    store: &S,
+    index: &str,
+    allow_missing: bool,
+) -> Result<Option<Response>> {
+    match index_rejection(store, index, allow_missing).await {
+        Some(rejection) => rejection.into_response().map(Some),
+        None => Ok(None),
+    }
+}
+
+/// Like [`check_index`], and also returns a 423 response while the index is frozen,
+/// for handlers that write documents
+pub async fn check_writable_index(
+    store: &Arc<KvStore>,
+    index: &str,
+    allow_missing: bool,
+) -> Result<Option<Response>> {
+    let rejection = match index_rejection(store, index, allow_missing).await {
+        Some(rejection) => Some(rejection),
+        None => frozen_rejection(store, index).await,
+    };
+    match rejection {
+        Some(rejection) => rejection.into_response().map(Some),
+        None => Ok(None),
+    }
+}
+
+/// A 423 rejection while the index is frozen, checked against the isolate's cached
+/// index record
+pub async fn frozen_rejection<S: Storage>(store: &S, index: &str) -> Option<Rejection> {
+    match IndexManager::new(store).is_frozen(index).await {
+        Ok(false) => None,
+        Ok(true) => Some(Rejection::new(
+            423,
+            ErrorCode::IndexFrozen,
+            format!(
+                "Index '{}' is frozen and rejects document writes until it is unfrozen",
+                index
+            ),
+        )),
+        Err(err) => Some(index_lookup_error(index, err)),
+    }
+}
+
+/// How a failed read of the index record is answered, see
+/// [`Rejection::from_store_error`]
+fn index_lookup_error(index: &str, err: DataStoreError) -> Rejection {
+    let error = format!("Failed to look up index '{}': {}", index, err);
+    Rejection {
+        error,
+        ..Rejection::from_store_error(err, ErrorCode::IndexNotFound)
+    }
+}
+
+/// The codecs the index is read with, or a 426 rejection for a version this worker
+/// doesn't understand. A missing index has nothing to read, so any codecs do.
+pub async fn index_codecs<S: Storage>(
+    store: &S,
+    index: &str,
+) -> std::result::Result<CodecSet, Rejection> {
+    match IndexManager::new(store).codecs(index).await {
+        Ok(codecs) => Ok(codecs),
+        Err(DataStoreError::NotFound(_)) => Ok(CodecSet::LATEST),
+        Err(err) => Err(Rejection::from_store_error(err, ErrorCode::IndexNotFound)),
+    }
+}
+
+/// The index document, read fresh for handlers that need it anyway rather than
+/// trusting the isolate's cached record. Rejected as [`check_index`] would, and
+/// `None` when the index is missing and `allow_missing` is set.
+pub async fn read_checked_index<S: Storage>(
+    store: &S,
+    index: &str,
+    allow_missing: bool,
+) -> std::result::Result<Option<IndexDocument>, Rejection> {
+    if !IndexDocument::is_valid_name(index) {
+        return Err(Rejection::new(
+            400,
+            ErrorCode::InvalidIndexName,
+            IndexDocument::INVALID_NAME_MESSAGE,
+        ));
+    }
+    match IndexManager::new(store).read_index(index).await {
+        Ok(index_doc) => Ok(Some(index_doc)),
+        Err(DataStoreError::NotFound(_)) if allow_missing => Ok(None),
+        Err(DataStoreError::NotFound(_)) => Err(Rejection::new(
+            404,
+            ErrorCode::IndexNotFound,
+            format!("Index '{}' not found", index),
+        )),
+        Err(err) => Err(index_lookup_error(index, err)),
+    }
+}
+
+/// The storage-generic core of [`check_index`]
+async fn index_rejection<S: Storage>(
+    store: &S,
+    index: &str,
+    allow_missing: bool,
+) -> Option<Rejection> {
+    if !IndexDocument::is_valid_name(index) {
+        return Some(Rejection::new(
+            400,
+            ErrorCode::InvalidIndexName,
+            IndexDocument::INVALID_NAME_MESSAGE,
+        ));
+    }
+    if allow_missing {
+        return None;
+    }
+
+    match IndexManager::new(store).index_exists(index).await {
+        Ok(true) => None,
+        Ok(false) => Some(Rejection::new(
+            404,
+            ErrorCode::IndexNotFound,
+            format!("Index '{}' not found", index),
+        )),
+        Err(err) => Some(index_lookup_error(index, err)),
+    }
+}
+
+/// A strong ETag naming one revision of a resource
+pub fn etag(id: &str, revision: impl std::fmt::Display) -> String {
+    format!("\"{}-{}\"", id, revision)
+}
+
+/// Whether an `If-None-Match` header value lists `etag` or is `*`. If-None-Match
+/// compares tags weakly, so a `W/` prefix is ignored.
+pub fn etag_listed(if_none_match: &str, etag: &str) -> bool {
+    if_none_match
+        .split(',')
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+/// An empty 304 response when the request's `If-None-Match` already names `etag`,
+/// so the caller can skip building the body
+pub fn not_modified(req: &Request, etag: &str) -> Result<Option<Response>> {
+    match req.headers().get("If-None-Match")? {
+        Some(header) if etag_listed(&header, etag) => {
+            let mut response = Response::empty()?.with_status(304);
+            response.headers_mut().set("ETag", etag)?;
+            Ok(Some(response))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Counts the bytes written to it, to size a JSON body without building it
+#[derive(Default)]
+struct ByteCounter(usize);
+
+impl std::io::Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The length of `value` serialized as a JSON response body
+pub fn json_length<T: serde::Serialize>(value: &T) -> Option<usize> {
+    let mut counter = ByteCounter::default();
+    serde_json::to_writer(&mut counter, value).ok()?;
+    Some(counter.0)
+}
+
+/// The answer to a `HEAD` request for a JSON resource: the headers `GET` sends,
+/// with `Content-Length` set to the `GET` body's length when it is known
+pub fn head_response(etag: &str, content_length: Option<usize>) -> Result<Response> {
+    let mut response = Response::empty()?;
+    let headers = response.headers_mut();
+    headers.set("Content-Type", "application/json")?;
+    headers.set("ETag", etag)?;
+    if let Some(length) = content_length {
+        headers.set("Content-Length", &length.to_string())?;
+    }
+    Ok(response)
+}
+
+/// `response` with its `ETag` header set
+pub fn with_etag(mut response: Response, etag: &str) -> Result<Response> {
+    response.headers_mut().set("ETag", etag)?;
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+
+    use super::*;
+    use crate::{
+        data::{storage::memory::MemoryStorage, KvPersistent},
+        util::time::ManualClock,
+    };
+
+    #[test]
+    fn test_error_envelope() {
+        let body = ErrorResponse {
+            error: "Index 'sample' not found".into(),
+            code: ErrorCode::IndexNotFound,
+            retryable: false,
+        };
+        assert_eq!(
+            serde_json::to_string(&body).unwrap(),
+            r#"{"error":"Index 'sample' not found","code":"index_not_found"}"#
+        );
+        assert_eq!(ERROR_CONTENT_TYPE, "application/json");
+    }
+
+    #[test]
+    fn test_index_rejections() {
+        let store = MemoryStorage::default();
+        block_on(async {
+            let invalid = index_rejection(&store, "Not Valid!", false).await.unwrap();
+            assert_eq!(
+                (invalid.status, invalid.code),
+                (400, ErrorCode::InvalidIndexName)
+            );
+
+            let missing = index_rejection(&store, "missing", false).await.unwrap();
+            assert_eq!(
+                missing,
+                Rejection::new(404, ErrorCode::IndexNotFound, "Index 'missing' not found")
+            );
+            assert!(index_rejection(&store, "missing", true).await.is_none());
+            let missing = read_checked_index(&store, "missing", false).await;
+            assert_eq!(missing.err().map(|rejection| rejection.status), Some(404));
+            assert!(read_checked_index(&store, "missing", true)
+                .await
+                .unwrap()
+                .is_none());
+
+            store.put("index:broken", "not json".into()).await.unwrap();
+            let broken = index_rejection(&store, "broken", false).await.unwrap();
+            assert_eq!(
+                (broken.status, broken.code),
+                (500, ErrorCode::InternalError)
+            );
+
+            // A KV error looking the index up is answered as any other store error
+            store.fail_gets("index:flaky", 1);
+            let flaky = index_rejection(&store, "flaky", false).await.unwrap();
+            assert_eq!(
+                (flaky.status, flaky.code, flaky.retryable),
+                (502, ErrorCode::InternalError, true)
+            );
+            assert!(flaky.error.starts_with("Failed to look up index 'flaky'"));
+            store.fail_gets("index:flaky", 1);
+            let flaky = frozen_rejection(&store, "flaky").await.unwrap();
+            assert_eq!((flaky.status, flaky.retryable), (502, true));
+        });
+    }
+
+    #[test]
+    fn test_frozen_rejection() {
+        let store = MemoryStorage::default();
+        block_on(async {
+            let manager = IndexManager::new(&store);
+            manager
+                .create_index("frozen-docs", None, None)
+                .await
+                .unwrap();
+            assert!(frozen_rejection(&store, "frozen-docs").await.is_none());
+
+            manager.set_frozen("frozen-docs", true).await.unwrap();
+            let frozen = frozen_rejection(&store, "frozen-docs").await.unwrap();
+            assert_eq!((frozen.status, frozen.code), (423, ErrorCode::IndexFrozen));
+            assert!(frozen.error.contains("frozen-docs"));
+        });
+    }
+
+    #[test]
+    fn test_unknown_versions_are_refused() {
+        let store = MemoryStorage::default();
+        block_on(async {
+            let manager = IndexManager::new(&store);
+            let mut index_doc = manager
+                .create_index("newer-version", None, None)
+                .await
+                .unwrap();
+            assert_eq!(
+                index_codecs(&store, "newer-version").await.unwrap(),
+                CodecSet::LATEST
+            );
+            assert_eq!(
+                index_codecs(&store, "never-created").await.unwrap(),
+                CodecSet::LATEST
+            );
+
+            // Rewritten by a newer worker, and read again once the cached record expired
+            index_doc.version = crate::data::INDEX_VERSION_LATEST + 1;
+            index_doc.write(&store).await.unwrap();
+            let later = IndexManager::new(&store).with_clock(ManualClock::at(u64::MAX / 2));
+            assert!(later.codecs("newer-version").await.is_err());
+            let refused = index_codecs(&store, "newer-version").await.unwrap_err();
+            assert_eq!(
+                (refused.status, refused.code),
+                (426, ErrorCode::UnsupportedIndexVersion)
+            );
+        });
+    }
+
+    #[test]
+    fn test_etag_listed() {
+        let tag = etag("doc1", 3);
+        assert_eq!(tag, "\"doc1-3\"");
+        assert!(etag_listed("\"doc1-3\"", &tag));
+        assert!(etag_listed("\"doc1-2\", W/\"doc1-3\"", &tag));
+        assert!(etag_listed("*", &tag));
+        assert!(!etag_listed("\"doc1-2\"", &tag));
+        assert!(!etag_listed("\"doc1-30\"", &tag));
+    }
+
+    #[test]
+    fn test_json_length() {
+        let body = ErrorResponse {
+            error: "Document 'doc1' not found".into(),
+            code: ErrorCode::DocumentNotFound,
+            retryable: false,
+        };
+        assert_eq!(
+            json_length(&body),
+            Some(serde_json::to_string(&body).unwrap().len())
+        );
+    }
+
+    #[test]
+    fn test_store_error_rejections() {
+        let missing = Rejection::from_store_error(
+            DataStoreError::NotFound("index:idx".into()),
+            ErrorCode::IndexNotFound,
+        );
+        assert_eq!(
+            (missing.status, missing.code, missing.retryable),
+            (404, ErrorCode::IndexNotFound, false)
+        );
+
+        let transient = Rejection::from_store_error(
+            DataStoreError::Worker(worker::Error::RustError("KV timed out".into())),
+            ErrorCode::IndexNotFound,
+        );
+        assert_eq!(
+            (transient.status, transient.code, transient.retryable),
+            (502, ErrorCode::InternalError, true)
+        );
+
+        let broken = serde_json::from_str::<u32>("not json").unwrap_err();
+        let broken =
+            Rejection::from_store_error(DataStoreError::Serialization(broken), ErrorCode::NotFound);
+        assert_eq!((broken.status, broken.retryable), (500, false));
+    }
+
+    #[test]
+    fn test_worker_error_rejections() {
+        let rejection = Rejection::from(worker::Error::RustError("boom".into()));
+        assert_eq!(
+            (rejection.status, rejection.code, rejection.retryable),
+            (502, ErrorCode::InternalError, true)
+        );
+        let rejection = Rejection::from(worker::Error::BadEncoding);
+        assert_eq!(
+            (rejection.status, rejection.code, rejection.retryable),
+            (500, ErrorCode::InternalError, false)
+        );
     }
 }
@@ -0,0 +1,153 @@
+use worker::{Context, Request, Response, Result, RouteContext};
+
+/// The hand-maintained OpenAPI 3 description of every route registered in `lib.rs`.
+/// Tests below fail if a route is added to the router without being documented here.
+pub const OPENAPI_SPEC: &str = include_str!("../../openapi.json");
+
+pub async fn handle_openapi(_req: Request, _ctx: RouteContext<Context>) -> Result<Response> {
+    let mut response = Response::ok(OPENAPI_SPEC)?;
+    response
+        .headers_mut()
+        .set("Content-Type", "application/json")?;
+    Ok(response)
+}
+
+pub async fn handle_docs(_req: Request, _ctx: RouteContext<Context>) -> Result<Response> {
+    Response::from_html(include_str!("../../docs.html"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROUTER_SOURCE: &str = include_str!("../lib.rs");
+    const METHODS: [&str; 5] = ["get", "post", "put", "patch", "delete"];
+
+    /// Every `(method, path)` registered on the router in `lib.rs`
+    fn router_routes() -> Vec<(String, String)> {
+        let mut routes = vec![];
+        for method in METHODS {
+            let needle = format!(".{}_async(", method);
+            for (pos, _) in ROUTER_SOURCE.match_indices(&needle) {
+                let rest = &ROUTER_SOURCE[pos + needle.len()..];
+                let start = rest.find('"').unwrap() + 1;
+                let end = start + rest[start..].find('"').unwrap();
+                routes.push((method.to_string(), rest[start..end].to_string()));
+            }
+        }
+        routes
+    }
+
+    /// Whether a router pattern (`/:index/keywords:action`) matches a concrete path,
+    /// where a `:param` matches one or more characters other than `/`
+    fn route_matches(pattern: &str, path: &str) -> bool {
+        match pattern.find(':') {
+            None => pattern == path,
+            Some(param_start) => {
+                let (literal, param) = pattern.split_at(param_start);
+                let Some(path) = path.strip_prefix(literal) else {
+                    return false;
+                };
+                let param_len = param[1..]
+                    .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                    .map_or(param.len(), |len| len + 1);
+                let pattern_rest = &param[param_len..];
+                let segment_len = path.find('/').unwrap_or(path.len());
+                (1..=segment_len).any(|len| route_matches(pattern_rest, &path[len..]))
+            }
+        }
+    }
+
+    fn spec() -> serde_json::Value {
+        serde_json::from_str(OPENAPI_SPEC).expect("openapi.json is not valid JSON")
+    }
+
+    fn spec_operations() -> Vec<(String, String)> {
+        let spec = spec();
+        let mut operations = vec![];
+        for (path, item) in spec["paths"].as_object().unwrap() {
+            for method in METHODS {
+                if item.get(method).is_some() {
+                    operations.push((method.to_string(), path.clone()));
+                }
+            }
+        }
+        operations
+    }
+
+    fn collect_refs<'a>(value: &'a serde_json::Value, refs: &mut Vec<&'a str>) {
+        match value {
+            serde_json::Value::Object(map) => {
+                for (key, value) in map {
+                    match (key.as_str(), value) {
+                        ("$ref", serde_json::Value::String(target)) => refs.push(target),
+                        _ => collect_refs(value, refs),
+                    }
+                }
+            }
+            serde_json::Value::Array(items) => items.iter().for_each(|v| collect_refs(v, refs)),
+            _ => {}
+        }
+    }
+
+    #[test]
+    fn test_route_matches() {
+        assert!(route_matches("/:index/doc/:id", "/{index}/doc/{id}"));
+        assert!(route_matches(
+            "/:index/keywords:action",
+            "/{index}/keywords:batch"
+        ));
+        assert!(!route_matches("/:index/doc/:id", "/{index}/doc"));
+        assert!(!route_matches("/:index", "/{index}/search"));
+    }
+
+    #[test]
+    fn test_every_route_is_documented() {
+        let operations = spec_operations();
+        for (method, pattern) in router_routes() {
+            assert!(
+                operations
+                    .iter()
+                    .any(|(m, path)| *m == method && route_matches(&pattern, path)),
+                "{} {} is registered on the router but missing from openapi.json",
+                method.to_uppercase(),
+                pattern
+            );
+        }
+    }
+
+    #[test]
+    fn test_every_documented_route_exists() {
+        let routes = router_routes();
+        for (method, path) in spec_operations() {
+            assert!(
+                routes
+                    .iter()
+                    .any(|(m, pattern)| *m == method && route_matches(pattern, &path)),
+                "{} {} is in openapi.json but not registered on the router",
+                method.to_uppercase(),
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn test_error_codes_documented() {
+        let documented =
+            spec()["components"]["schemas"]["ErrorResponse"]["properties"]["code"]["enum"].clone();
+        let codes = serde_json::to_value(crate::http::ErrorCode::ALL).unwrap();
+        assert_eq!(documented, codes);
+    }
+
+    #[test]
+    fn test_refs_resolve() {
+        let spec = spec();
+        let mut refs = vec![];
+        collect_refs(&spec, &mut refs);
+        assert!(!refs.is_empty());
+        for target in refs {
+            let pointer = target.strip_prefix('#').unwrap();
+            assert!(spec.pointer(pointer).is_some(), "dangling $ref {}", target);
+        }
+    }
+}
@@ -0,0 +1,324 @@
+use std::collections::BTreeMap;
+
+use worker::{Context, Method, Request, RequestInit, Response, Result, RouteContext};
+
+use crate::{
+    data::{
+        index::IndexDocument,
+        saved_query::{list_saved_queries, saved_query_kv_key, SavedQuery},
+        storage::Storage,
+        DataStoreError, KvPersistent,
+    },
+    http::{check_index, json_error, search::handle_search, ErrorCode, Rejection},
+    util::kv::get_kv_data_store,
+};
+
+#[derive(serde::Serialize)]
+struct DeletedResponse {
+    deleted: bool,
+}
+
+fn saved_query_store_error(err: DataStoreError) -> Rejection {
+    Rejection::from_store_error(err, ErrorCode::NotFound)
+}
+
+/// Parse a `PUT /:index/queries/:name` body. Query names follow the rules of index
+/// names.
+pub fn parse_saved_query(
+    index: &str,
+    name: &str,
+    body: &str,
+) -> std::result::Result<SavedQuery, Rejection> {
+    if !IndexDocument::is_valid_name(name) {
+        return Err(Rejection::new(
+            400,
+            ErrorCode::InvalidRequest,
+            "Invalid query name. Must be 1-48 characters matching [a-z0-9-_]+",
+        ));
+    }
+    SavedQuery::parse(index, name, body)
+        .map_err(|error| Rejection::new(400, ErrorCode::InvalidQuery, error))
+}
+
+/// Store `saved`, replacing the query of the same name, unless it would be one
+/// query too many
+async fn save_query<S: Storage>(
+    store: &S,
+    mut saved: SavedQuery,
+) -> std::result::Result<SavedQuery, Rejection> {
+    let stored = list_saved_queries(store, &saved.index)
+        .await
+        .map_err(saved_query_store_error)?;
+    let replaces = stored.iter().any(|other| other.name == saved.name);
+    if !replaces && stored.len() >= SavedQuery::MAX_QUERIES {
+        return Err(Rejection::new(
+            400,
+            ErrorCode::InvalidRequest,
+            format!(
+                "Too many saved queries. Current limit: {}",
+                SavedQuery::MAX_QUERIES
+            ),
+        ));
+    }
+    saved.write(store).await.map_err(saved_query_store_error)?;
+    Ok(saved)
+}
+
+/// Parse the body of `POST /:index/queries/:name/run`: a JSON object of the value of
+/// each placeholder, or nothing for a query without any
+pub fn parse_run_values(body: &str) -> std::result::Result<BTreeMap<String, String>, Rejection> {
+    if body.trim().is_empty() {
+        return Ok(BTreeMap::new());
+    }
+    serde_json::from_str(body).map_err(|_| {
+        Rejection::new(
+            400,
+            ErrorCode::InvalidRequest,
+            "Request body must be a JSON object of a string for each placeholder",
+        )
+    })
+}
+
+/// The query parameters of the search a run makes: the saved options, less any the
+/// run's own parameters replace, then those, then the query with its placeholders
+/// filled in
+pub fn run_params(
+    saved: &SavedQuery,
+    values: &BTreeMap<String, String>,
+    overrides: Vec<(String, String)>,
+) -> std::result::Result<Vec<(String, String)>, Rejection> {
+    if let Some((name, _)) = overrides
+        .iter()
+        .find(|(name, _)| name == "query" || name == "text")
+    {
+        return Err(Rejection::new(
+            400,
+            ErrorCode::InvalidRequest,
+            format!("A saved query's run can't set '{}'", name),
+        ));
+    }
+    let query = saved
+        .substitute(values)
+        .map_err(|error| Rejection::new(400, ErrorCode::InvalidRequest, error))?;
+    let mut params: Vec<(String, String)> = saved
+        .query_pairs()
+        .into_iter()
+        .filter(|(name, _)| !overrides.iter().any(|(other, _)| other == name))
+        .collect();
+    params.extend(overrides);
+    params.push(("query".to_string(), query));
+    Ok(params)
+}
+
+/// `GET /:index/queries`: every saved query of the index, ordered by name
+pub async fn handle_list_queries(_req: Request, ctx: RouteContext<Context>) -> Result<Response> {
+    let Some(index) = ctx.param("index") else {
+        return json_error(400, ErrorCode::MissingParameter, "Missing index name");
+    };
+    let store = get_kv_data_store(&ctx);
+    if let Some(response) = check_index(&store, index, false).await? {
+        return Ok(response);
+    }
+    match list_saved_queries(&store, index).await {
+        Ok(queries) => Response::from_json(&queries),
+        Err(err) => saved_query_store_error(err).into_response(),
+    }
+}
+
+pub async fn handle_get_query(_req: Request, ctx: RouteContext<Context>) -> Result<Response> {
+    let (Some(index), Some(name)) = (ctx.param("index"), ctx.param("name")) else {
+        return json_error(
+            400,
+            ErrorCode::MissingParameter,
+            "Missing index or query name",
+        );
+    };
+    let store = get_kv_data_store(&ctx);
+    if let Some(response) = check_index(&store, index, false).await? {
+        return Ok(response);
+    }
+    match SavedQuery::load(&store, index, name).await {
+        Ok(saved) => Response::from_json(&saved),
+        Err(err) => saved_query_store_error(err).into_response(),
+    }
+}
+
+/// `PUT /:index/queries/:name`: create or replace a saved query, refused unless its
+/// query parses
+pub async fn handle_put_query(mut req: Request, ctx: RouteContext<Context>) -> Result<Response> {
+    let (Some(index), Some(name)) = (ctx.param("index"), ctx.param("name")) else {
+        return json_error(
+            400,
+            ErrorCode::MissingParameter,
+            "Missing index or query name",
+        );
+    };
+    let store = get_kv_data_store(&ctx);
+    if let Some(response) = check_index(&store, index, false).await? {
+        return Ok(response);
+    }
+    let saved = match parse_saved_query(index, name, &req.text().await?) {
+        Ok(saved) => save_query(&store, saved).await,
+        Err(rejection) => Err(rejection),
+    };
+    match saved {
+        Ok(saved) => Response::from_json(&saved),
+        Err(rejection) => rejection.into_response(),
+    }
+}
+
+pub async fn handle_delete_query(_req: Request, ctx: RouteContext<Context>) -> Result<Response> {
+    let (Some(index), Some(name)) = (ctx.param("index"), ctx.param("name")) else {
+        return json_error(
+            400,
+            ErrorCode::MissingParameter,
+            "Missing index or query name",
+        );
+    };
+    let store = get_kv_data_store(&ctx);
+    if let Some(response) = check_index(&store, index, false).await? {
+        return Ok(response);
+    }
+    if let Err(err) = SavedQuery::load(&store, index, name).await {
+        return saved_query_store_error(err).into_response();
+    }
+    match store.delete(&saved_query_kv_key(index, name)).await {
+        Ok(()) => Response::from_json(&DeletedResponse { deleted: true }),
+        Err(err) => saved_query_store_error(err).into_response(),
+    }
+}
+
+/// `POST /:index/queries/:name/run`: search with a saved query, its placeholders
+/// filled in from the body. The run's own query parameters replace the saved
+/// options of the same name, and the search is made by [`handle_search`] as if its
+/// parameters had been sent to `/:index/search`.
+pub async fn handle_run_query(mut req: Request, ctx: RouteContext<Context>) -> Result<Response> {
+    let (Some(index), Some(name)) = (ctx.param("index"), ctx.param("name")) else {
+        return json_error(
+            400,
+            ErrorCode::MissingParameter,
+            "Missing index or query name",
+        );
+    };
+    let index = index.to_string();
+    let store = get_kv_data_store(&ctx);
+    if let Some(response) = check_index(&store, &index, false).await? {
+        return Ok(response);
+    }
+    let saved = match SavedQuery::load(&store, &index, name).await {
+        Ok(saved) => saved,
+        Err(err) => return saved_query_store_error(err).into_response(),
+    };
+    let values = match parse_run_values(&req.text().await?) {
+        Ok(values) => values,
+        Err(rejection) => return rejection.into_response(),
+    };
+
+    let mut url = req.url()?;
+    let overrides = url.query_pairs().into_owned().collect();
+    let params = match run_params(&saved, &values, overrides) {
+        Ok(params) => params,
+        Err(rejection) => return rejection.into_response(),
+    };
+    url.set_path(&format!("/{}/search", index));
+    url.query_pairs_mut().clear().extend_pairs(params);
+    let search = Request::new_with_init(
+        url.as_str(),
+        &RequestInit {
+            method: Method::Post,
+            headers: req.headers().clone(),
+            ..Default::default()
+        },
+    )?;
+    handle_search(search, ctx).await
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+
+    use super::*;
+    use crate::{data::storage::memory::MemoryStorage, lexer::Expr};
+
+    fn saved(body: &str) -> SavedQuery {
+        parse_saved_query("idx", "canned", body).unwrap()
+    }
+
+    fn pairs(params: &[(&str, &str)]) -> Vec<(String, String)> {
+        params
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_saved_query_rejections() {
+        let bad_name = parse_saved_query("idx", "Canned", r#"{"query":"apple"}"#).unwrap_err();
+        assert_eq!(
+            (bad_name.status, bad_name.code),
+            (400, ErrorCode::InvalidRequest)
+        );
+        let bad_query = parse_saved_query("idx", "canned", r#"{"query":"(apple"}"#).unwrap_err();
+        assert_eq!(
+            (bad_query.status, bad_query.code),
+            (400, ErrorCode::InvalidQuery)
+        );
+    }
+
+    #[test]
+    fn test_save_query_limit() {
+        let store = MemoryStorage::default();
+        for i in 0..SavedQuery::MAX_QUERIES {
+            let query = parse_saved_query("idx", &format!("q{}", i), r#"{"query":"a"}"#).unwrap();
+            block_on(save_query(&store, query)).unwrap();
+        }
+        // Another index's queries count against its own limit
+        let other = parse_saved_query("other", "q0", r#"{"query":"a"}"#).unwrap();
+        block_on(save_query(&store, other)).unwrap();
+        let extra = parse_saved_query("idx", "extra", r#"{"query":"a"}"#).unwrap();
+        let rejected = block_on(save_query(&store, extra)).unwrap_err();
+        assert!(rejected.error.contains("limit"));
+
+        let replaced = parse_saved_query("idx", "q0", r#"{"query":"b"}"#).unwrap();
+        block_on(save_query(&store, replaced)).unwrap();
+        let stored = block_on(SavedQuery::load(&store, "idx", "q0")).unwrap();
+        assert_eq!(stored.query, "b");
+    }
+
+    #[test]
+    fn test_run_params() {
+        let canned = saved(
+            r#"{"query":"{{term}} && ~spam","options":{"limit":5,"filter":["lang=en","year>2000"],"full":true}}"#,
+        );
+        let values = parse_run_values(r#"{"term":"say \"cheese\" || id:x"}"#).unwrap();
+        let params = run_params(&canned, &values, pairs(&[("limit", "2")])).unwrap();
+        assert_eq!(
+            params,
+            pairs(&[
+                ("filter", "lang=en"),
+                ("filter", "year>2000"),
+                ("full", "true"),
+                ("limit", "2"),
+                ("query", r#""say \"cheese\" || id:x" && ~spam"#),
+            ])
+        );
+        // The value is one keyword however many operators it holds
+        assert_eq!(
+            Expr::parse(&params[4].1).unwrap(),
+            Expr::And(
+                Box::new(Expr::Word(r#"say "cheese" || id:x"#.into())),
+                Box::new(Expr::Not(Box::new(Expr::Word("spam".into())))),
+            )
+        );
+
+        let missing = run_params(&canned, &BTreeMap::new(), vec![]).unwrap_err();
+        assert_eq!(
+            (missing.status, missing.error.as_str()),
+            (400, "Missing a value for placeholder 'term'")
+        );
+        let replaced = run_params(&canned, &values, pairs(&[("query", "everything")]));
+        assert!(replaced.unwrap_err().error.contains("can't set 'query'"));
+        assert!(parse_run_values(r#"{"term":5}"#).is_err());
+        assert!(parse_run_values("  ").unwrap().is_empty());
+    }
+}
@@ -0,0 +1,269 @@
+//! The HTML served to browsers, rendered from the same structs as the JSON of each
+//! response so the two can't drift apart. Every string that may come from a user,
+//! like an index name, keyword, document ID or body, goes through [`escape`].
+
+use worker::{Request, Response, Result};
+
+use crate::http::{
+    search::{SearchResponse, SearchResultView},
+    ErrorResponse, ERROR_CONTENT_TYPE,
+};
+
+/// How many bytes of a body a search result shows
+pub const SNIPPET_BYTES: usize = 240;
+
+/// How many of a match's keywords a search result shows
+pub const SHOWN_KEYWORDS: usize = 5;
+
+const UI_TEMPLATE: &str = include_str!("../../ui.html");
+
+/// Whether a request prefers HTML, as a browser navigating to a URL does
+pub fn accepts_html(req: &Request) -> bool {
+    matches!(req.headers().get("Accept"), Ok(Some(accept)) if accept.contains("text/html"))
+}
+
+/// `text` with the characters that are markup in HTML text and attribute values
+/// replaced by their entities
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// The page shown for an error response
+pub fn error_page(status: u16, error: &ErrorResponse) -> String {
+    let code = serde_json::to_value(error.code)
+        .ok()
+        .and_then(|code| code.as_str().map(str::to_string))
+        .unwrap_or_default();
+    let retry = match error.retryable {
+        true => "<p>Sending the request again may succeed.</p>",
+        false => "",
+    };
+    format!(
+        "<!DOCTYPE html>\n<html lang=\"en\">\n<head><meta charset=\"UTF-8\"><title>{status} {code}</title></head>\n\
+         <body>\n<h1>{status} <code>{code}</code></h1>\n<p>{message}</p>\n{retry}</body>\n</html>\n",
+        status = status,
+        code = escape(&code),
+        message = escape(&error.error),
+        retry = retry,
+    )
+}
+
+/// The search page of `index`
+pub fn search_ui(index: &str) -> String {
+    UI_TEMPLATE.replace("{{index}}", &escape(index))
+}
+
+/// The matches of a search, as the search page shows them
+pub fn search_results(response: &SearchResponse) -> String {
+    let mut html = format!(
+        "<p class=\"summary\">{} match{}{}</p>\n",
+        response.document_count,
+        if response.document_count == 1 {
+            ""
+        } else {
+            "es"
+        },
+        if response.partial {
+            ", stopped early"
+        } else {
+            ""
+        }
+    );
+    for warning in response.warnings.iter() {
+        html.push_str(&format!(
+            "<p class=\"summary\">{}</p>\n",
+            escape(&warning.message)
+        ));
+    }
+    for row in &response.matches {
+        html.push_str(&search_result(row));
+    }
+    html
+}
+
+fn search_result(row: &SearchResultView) -> String {
+    let mut html = format!(
+        "<div class=\"result\"><strong>{}</strong>",
+        escape(row.doc_id)
+    );
+    if let Some(title) = row.title {
+        html.push_str(&format!(" <em>{}</em>", escape(title)));
+    }
+    if let Some(score) = row.score {
+        html.push_str(&format!(" <span class=\"score\">{:.3}</span>", score));
+    }
+    if row.missing {
+        html.push_str(" <span class=\"score\">missing</span>");
+    }
+    if let Some(Some(body)) = row.body {
+        html.push_str(&format!("<p>{}</p>", snippet(body, row.matched_spans)));
+    }
+    if let Some(keywords) = row.keywords {
+        let shown: Vec<String> = keywords
+            .iter()
+            .take(SHOWN_KEYWORDS)
+            .map(|(keyword, _)| escape(keyword))
+            .collect();
+        html.push_str(&format!("<p class=\"keywords\">{}</p>", shown.join(", ")));
+    }
+    html.push_str("</div>\n");
+    html
+}
+
+/// Up to [`SNIPPET_BYTES`] of `body` from shortly before its first span, with the
+/// spans marked
+pub fn snippet(body: &str, spans: &[[usize; 2]]) -> String {
+    let first = spans.first().map_or(0, |span| span[0]);
+    let start = char_boundary(body, first.saturating_sub(SNIPPET_BYTES / 4));
+    let end = char_boundary(body, start + SNIPPET_BYTES);
+    let mut html = String::new();
+    if start > 0 {
+        html.push('…');
+    }
+    let mut at = start;
+    for &[from, to] in spans {
+        let (from, to) = (from.max(at), to.min(end));
+        if from >= to || !body.is_char_boundary(from) || !body.is_char_boundary(to) {
+            continue;
+        }
+        html.push_str(&escape(&body[at..from]));
+        html.push_str(&format!("<mark>{}</mark>", escape(&body[from..to])));
+        at = to;
+    }
+    html.push_str(&escape(&body[at..end]));
+    if end < body.len() {
+        html.push('…');
+    }
+    html
+}
+
+/// The char boundary at or before `at`, within `text`
+fn char_boundary(text: &str, at: usize) -> usize {
+    let mut at = at.min(text.len());
+    while !text.is_char_boundary(at) {
+        at -= 1;
+    }
+    at
+}
+
+/// `response` rendered as an HTML page when it carries an [`ErrorResponse`], for a
+/// request that [`accepts_html`]
+pub async fn with_html_error(mut response: Response) -> Result<Response> {
+    let status = response.status_code();
+    let is_error = response
+        .headers()
+        .get("Content-Type")?
+        .is_some_and(|content_type| content_type == ERROR_CONTENT_TYPE);
+    if status < 400 || !is_error {
+        return Ok(response);
+    }
+    let headers = response.headers().clone();
+    let text = response.text().await?;
+    // The body was read, so even one that isn't an error's is sent again
+    let (mut rendered, content_type) = match serde_json::from_str::<ErrorResponse>(&text) {
+        Ok(error) => (Response::ok(error_page(status, &error))?, "text/html"),
+        Err(_) => (Response::ok(text)?, ERROR_CONTENT_TYPE),
+    };
+    for (name, value) in headers.entries() {
+        rendered.headers_mut().set(&name, &value)?;
+    }
+    rendered
+        .headers_mut()
+        .set("Content-Type", &format!("{}; charset=utf-8", content_type))?;
+    Ok(rendered.with_status(status))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::ErrorCode;
+
+    const HOSTILE: &str = "<script>alert('x')</script> & \"quoted\"";
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(
+            escape(HOSTILE),
+            "&lt;script&gt;alert(&#39;x&#39;)&lt;/script&gt; &amp; &quot;quoted&quot;"
+        );
+        assert_eq!(escape("ocean tide"), "ocean tide");
+    }
+
+    #[test]
+    fn test_error_page_escapes_message() {
+        let error = ErrorResponse {
+            error: format!("Index '{}' not found", HOSTILE),
+            code: ErrorCode::IndexNotFound,
+            retryable: true,
+        };
+        let page = error_page(404, &error);
+        assert!(page.contains("<h1>404 <code>index_not_found</code></h1>"));
+        assert!(page.contains("&lt;script&gt;"));
+        assert!(!page.contains("<script>"));
+        assert!(page.contains("again may succeed"));
+
+        // The page is rendered from the body the JSON response carries
+        let json = serde_json::to_string(&error).unwrap();
+        assert_eq!(serde_json::from_str::<ErrorResponse>(&json).unwrap(), error);
+    }
+
+    #[test]
+    fn test_search_ui_escapes_index() {
+        let page = search_ui("docs\"><script>");
+        assert!(!page.contains("{{index}}"));
+        assert!(page.contains("data-index=\"docs&quot;&gt;&lt;script&gt;\""));
+        assert_eq!(page.matches("<script>").count(), 1);
+    }
+
+    #[test]
+    fn test_search_result_escapes_keywords_and_body() {
+        let keywords = vec![("<b>bold</b>".to_string(), 0.9), ("tide".to_string(), 0.5)];
+        let body = Some("Ocean <tides> rise".to_string());
+        let row = SearchResultView {
+            doc_id: "doc\"1",
+            score: Some(0.91234),
+            raw_score: None,
+            keywords: Some(&keywords),
+            matched_terms: None,
+            total_terms: None,
+            body: Some(&body),
+            title: None,
+            missing: false,
+            matched_spans: &[[6, 13]],
+            recency_factor: None,
+            collapsed_count: None,
+            collapsed_hits: vec![],
+        };
+        assert_eq!(
+            search_result(&row),
+            "<div class=\"result\"><strong>doc&quot;1</strong> <span class=\"score\">0.912</span>\
+             <p>Ocean <mark>&lt;tides&gt;</mark> rise</p>\
+             <p class=\"keywords\">&lt;b&gt;bold&lt;/b&gt;, tide</p></div>\n"
+        );
+    }
+
+    #[test]
+    fn test_snippet_window() {
+        let body = format!("{}needle{}", "é".repeat(200), "z".repeat(400));
+        let start = "é".len() * 200;
+        let html = snippet(&body, &[[start, start + 6]]);
+        assert!(html.starts_with('…') && html.ends_with('…'));
+        assert!(html.contains("<mark>needle</mark>"));
+        assert!(html.len() < SNIPPET_BYTES + 40);
+
+        assert_eq!(snippet("short body", &[]), "short body");
+        // Spans past the shown text or off a char boundary are left unmarked
+        assert_eq!(snippet("éa", &[[1, 2], [9, 12]]), "éa");
+    }
+}
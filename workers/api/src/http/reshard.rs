@@ -0,0 +1,169 @@
+use worker::{Context, Request, Response, Result, RouteContext};
+
+use crate::{
+    data::{
+        index_manager::IndexManager,
+        keyword_shard::get_n_shards,
+        reshard::{reshard_batch, ReshardCursor, ReshardError},
+    },
+    http::{check_index, json_error, ErrorCode, Rejection},
+    util::{kv::get_kv_data_store, time::now_ms},
+};
+
+#[derive(serde::Deserialize, Default)]
+pub struct ReshardParams {
+    target_shards: Option<u32>,
+    cursor: Option<String>,
+}
+
+/// The shard count to move to, and where to resume
+pub fn parse_reshard_params(
+    params: &ReshardParams,
+) -> std::result::Result<(u32, Option<ReshardCursor>), Rejection> {
+    let Some(target) = params.target_shards else {
+        return Err(Rejection::new(
+            400,
+            ErrorCode::MissingParameter,
+            "Missing target_shards",
+        ));
+    };
+    let cursor = match params.cursor.as_deref() {
+        None | Some("") => None,
+        Some(cursor) => Some(
+            cursor
+                .parse()
+                .map_err(|err| Rejection::new(400, ErrorCode::InvalidRequest, err))?,
+        ),
+    };
+    Ok((target, cursor))
+}
+
+/// The response a reshard that can't run is rejected with
+pub fn reshard_rejection(err: ReshardError) -> Rejection {
+    let (status, code) = match err {
+        ReshardError::InvalidTarget(_) | ReshardError::AlreadySharded(_) => {
+            (400, ErrorCode::InvalidRequest)
+        }
+        ReshardError::NotFrozen | ReshardError::FreezeSettling(_) => {
+            (409, ErrorCode::IndexNotFrozen)
+        }
+        ReshardError::InProgress(_) => (409, ErrorCode::ReshardInProgress),
+        ReshardError::Store(_) => (500, ErrorCode::InternalError),
+    };
+    // A freeze that other isolates haven't seen yet settles on its own
+    Rejection {
+        retryable: matches!(err, ReshardError::FreezeSettling(_)),
+        ..Rejection::new(status, code, err.to_string())
+    }
+}
+
+/// `POST /:index/reshard?target_shards=`: run the next batch of moving a frozen
+/// index's keyword shards to `target_shards` shards. Keep passing back `cursor`
+/// until it comes back `null`, then unfreeze the index.
+pub async fn handle_reshard(req: Request, ctx: RouteContext<Context>) -> Result<Response> {
+    // Resharding rewrites every shard, so AUTH_DISABLED alone doesn't allow it
+    if !crate::presents_api_key(&req, &ctx.env) {
+        return json_error(
+            403,
+            ErrorCode::Unauthorized,
+            "Resharding an index requires the API key",
+        );
+    }
+    let Some(index) = ctx.param("index") else {
+        return json_error(400, ErrorCode::MissingParameter, "Missing index name");
+    };
+    let Ok(params) = req.query::<ReshardParams>() else {
+        return json_error(
+            400,
+            ErrorCode::InvalidRequest,
+            "target_shards must be a positive number",
+        );
+    };
+    let (target, cursor) = match parse_reshard_params(&params) {
+        Ok(parsed) => parsed,
+        Err(rejection) => return rejection.into_response(),
+    };
+
+    let store = get_kv_data_store(&ctx);
+    if let Some(response) = check_index(&store, index, false).await? {
+        return Ok(response);
+    }
+    let index_doc = match IndexManager::new(&store).read_index(index).await {
+        Ok(index_doc) => index_doc,
+        Err(err) => {
+            return json_error(
+                500,
+                ErrorCode::InternalError,
+                format!("Failed to read the index: {}", err),
+            )
+        }
+    };
+    let default_shards = get_n_shards(&ctx.env);
+    match reshard_batch(&store, index_doc, target, cursor, default_shards, now_ms()).await {
+        Ok(report) => Response::from_json(&report),
+        Err(err) => reshard_rejection(err).into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::{reshard::ReshardPhase, DataStoreError};
+
+    #[test]
+    fn test_parse_reshard_params() {
+        let rejection = parse_reshard_params(&ReshardParams::default()).unwrap_err();
+        assert_eq!(rejection.code, ErrorCode::MissingParameter);
+
+        let params = ReshardParams {
+            target_shards: Some(16),
+            cursor: Some("s:4:idx:kw:ocean:3".into()),
+        };
+        let (target, cursor) = parse_reshard_params(&params).unwrap();
+        let cursor = cursor.unwrap();
+        assert_eq!((target, cursor.phase), (16, ReshardPhase::Staging));
+        assert_eq!(cursor.page.as_deref(), Some("idx:kw:ocean:3"));
+
+        let params = ReshardParams {
+            target_shards: Some(16),
+            cursor: Some("bogus".into()),
+        };
+        let rejection = parse_reshard_params(&params).unwrap_err();
+        assert_eq!(
+            (rejection.status, rejection.code),
+            (400, ErrorCode::InvalidRequest)
+        );
+    }
+
+    #[test]
+    fn test_reshard_rejections() {
+        let cases = [
+            (ReshardError::NotFrozen, 409, ErrorCode::IndexNotFrozen),
+            (
+                ReshardError::InProgress(16),
+                409,
+                ErrorCode::ReshardInProgress,
+            ),
+            (
+                ReshardError::AlreadySharded(16),
+                400,
+                ErrorCode::InvalidRequest,
+            ),
+            (
+                ReshardError::Store(DataStoreError::NotFound("idx:kw:ocean:3".into())),
+                500,
+                ErrorCode::InternalError,
+            ),
+        ];
+        for (err, status, code) in cases {
+            let rejection = reshard_rejection(err);
+            assert_eq!((rejection.status, rejection.code), (status, code));
+            assert!(!rejection.retryable);
+        }
+        let settling = reshard_rejection(ReshardError::FreezeSettling(1_000));
+        assert_eq!(
+            (settling.status, settling.code, settling.retryable),
+            (409, ErrorCode::IndexNotFrozen, true)
+        );
+    }
+}
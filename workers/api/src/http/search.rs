@@ -1,85 +1,1073 @@
-use worker::{Request, Response, Result, RouteContext};
+use std::collections::{BTreeMap, HashMap};
+
+use worker::{Context, Env, Request, Response, Result, RouteContext};
 
 use crate::{
-    data::{bulk::BulkReader, keyword_shard::get_n_shards, PREFIX_DOCUMENT},
-    durable::reader::get_durable_reader_namespace,
-    lexer::lexer::QueryLexer,
-    util::kv::get_kv_data_store,
+    data::{
+        bulk::BulkReader,
+        codec::CodecSet,
+        deletion::pending_deletions,
+        document::Document,
+        index::{check_limit, IndexDocument, IndexSettings},
+        keyword_shard::get_n_shards,
+        op_budget::{CountedStorage, OpBudget, SUBREQUEST_CAP},
+        stoplist::StopList,
+        storage::Storage,
+        trace::{ReadTrace, SearchDiagnostics},
+        usage::UsageDelta,
+        DataStoreError, PREFIX_DOCUMENT,
+    },
+    durable::{
+        journal::record_usage,
+        reader::{get_durable_reader_namespace, DurableReader, ReaderPlacement},
+    },
+    edge_log,
+    http::{
+        json_error, read_checked_index,
+        render_html::{self, accepts_html},
+        ErrorCode, Rejection,
+    },
+    lexer::{
+        budget::{BudgetExceeded, BudgetTracker, QueryBudget},
+        collapse::Collapse,
+        contains::Contains,
+        facets::{count_facets, get_facet_max_docs, parse_facet_fields, Facet},
+        filter::{FilterError, Filters},
+        fuzzy::Correction,
+        lexer::{rank_order, QueryLexer},
+        normalize::Normalization,
+        recency::RecencyBoost,
+        scoring::ScoringMode,
+        simple::SimpleMode,
+        timings::{elapsed_ms, now_ms, Timings},
+        warnings::{SearchWarning, WarningCode, Warnings},
+        QueryError,
+    },
+    util::{
+        concurrency::join_bounded,
+        kv::{get_body_bucket, get_kv_data_store},
+    },
 };
 
-pub async fn handle_search(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+pub async fn handle_search(mut req: Request, ctx: RouteContext<Context>) -> Result<Response> {
     #[derive(serde::Deserialize)]
     struct SearchQuery {
-        pub query: String,
+        /// The query string, or `None` when the body is the query's AST
+        pub query: Option<String>,
+        /// A search box's free text, rewritten into a query by `mode`
+        pub text: Option<String>,
+        pub mode: Option<String>,
         pub full: Option<bool>,
+        pub allow_missing: Option<bool>,
+        pub fields: Option<String>,
+        pub timings: Option<bool>,
+        pub warnings: Option<bool>,
+        pub fuzzy: Option<bool>,
+        pub suggest_only: Option<bool>,
+        pub case_insensitive: Option<bool>,
+        pub limit: Option<u32>,
+        pub scoring: Option<String>,
+        pub budget_ms: Option<u64>,
+        pub budget_ops: Option<usize>,
+        pub debug: Option<bool>,
+        pub facets: Option<String>,
+        pub drop_missing: Option<bool>,
+        pub recency_boost: Option<f64>,
+        pub boost_field: Option<String>,
+        pub collapse: Option<String>,
+        pub collapse_hits: Option<usize>,
+        pub ids_only: Option<bool>,
+        pub normalize: Option<String>,
+        pub softmax_temperature: Option<f64>,
     }
+    let html = accepts_html(&req);
     if let Some(index) = ctx.param("index") {
         if let Ok(query) = req.query::<SearchQuery>() {
-            let store = get_kv_data_store(&ctx);
-            let lexer = QueryLexer::from_str(query.query.as_str(), &store, &ctx.env);
-            if !lexer.is_ok() {
-                return Response::error(
-                    crate::http::ErrorResponse {
-                        error: "Failed to parse query".into(),
-                    },
+            let fields = match SearchFields::parse(query.fields.as_deref()) {
+                Ok(fields) => fields,
+                Err(error) => {
+                    return json_error(400, ErrorCode::InvalidRequest, error);
+                }
+            };
+            // `filter`, `contains` and `contains_ci` repeat, which the query struct can't hold
+            let url = req.url()?;
+            let repeated = |name: &str| -> Vec<String> {
+                url.query_pairs()
+                    .filter(|(key, _)| key == name)
+                    .map(|(_, value)| value.into_owned())
+                    .collect()
+            };
+            let filter_params = repeated("filter");
+            let filters = match Filters::parse(filter_params.iter().map(String::as_str)) {
+                Ok(filters) => filters,
+                Err(error) => {
+                    return json_error(400, ErrorCode::InvalidRequest, error);
+                }
+            };
+            let (contains_params, contains_ci_params) =
+                (repeated("contains"), repeated("contains_ci"));
+            let contains = match Contains::parse(
+                contains_params.iter().map(String::as_str),
+                contains_ci_params.iter().map(String::as_str),
+            ) {
+                Ok(contains) => contains,
+                Err(error) => {
+                    return json_error(400, ErrorCode::InvalidRequest, error);
+                }
+            };
+            let recency =
+                match RecencyBoost::parse(query.recency_boost, query.boost_field.as_deref()) {
+                    Ok(recency) => recency,
+                    Err(error) => {
+                        return json_error(400, ErrorCode::InvalidRequest, error);
+                    }
+                };
+            let collapse = match Collapse::parse(query.collapse.as_deref(), query.collapse_hits) {
+                Ok(collapse) => collapse,
+                Err(error) => {
+                    return json_error(400, ErrorCode::InvalidRequest, error);
+                }
+            };
+            let normalization =
+                match Normalization::parse(query.normalize.as_deref(), query.softmax_temperature) {
+                    Ok(normalization) => normalization,
+                    Err(error) => {
+                        return json_error(400, ErrorCode::InvalidRequest, error);
+                    }
+                };
+            let ids_only = query.ids_only.unwrap_or(false);
+            let row_params = [
+                ("full", query.full == Some(true)),
+                ("fields", query.fields.is_some()),
+                ("filter", !filters.is_empty()),
+                ("contains", !contains.is_empty()),
+                ("facets", query.facets.is_some()),
+                ("recency_boost", recency.is_some()),
+                ("collapse", collapse.is_some()),
+                ("normalize", normalization.is_some()),
+                ("drop_missing", query.drop_missing.is_some()),
+                ("suggest_only", query.suggest_only == Some(true)),
+            ];
+            if let Some(error) = ids_only_conflict(ids_only, &row_params) {
+                return json_error(400, ErrorCode::InvalidRequest, error);
+            }
+            let facet_fields = parse_facet_fields(query.facets.as_deref());
+            let requested = match requested_options(query.full, query.limit, query.scoring) {
+                Ok(requested) => requested,
+                Err(error) => {
+                    return json_error(400, ErrorCode::InvalidRequest, error);
+                }
+            };
+
+            // Diagnostics list the index's KV keys, so they need the API key itself
+            let debug = query.debug.unwrap_or(false);
+            if debug && !crate::presents_api_key(&req, &ctx.env) {
+                return json_error(
+                    403,
+                    ErrorCode::Unauthorized,
+                    "debug=true requires the API key",
+                );
+            }
+            let trace = debug.then(ReadTrace::default);
+
+            // Everything the search reads goes through `store`, counting subrequests
+            let kv = get_kv_data_store(&ctx);
+            let subrequests = OpBudget::new(SUBREQUEST_CAP);
+            let store = CountedStorage::new(&kv, subrequests.clone());
+            // Read for its search defaults and its shard count, which saves a listing
+            // per keyword when it's recorded, so its existence and codecs are taken
+            // from the same read rather than the isolate's cached record
+            let allow_missing = query.allow_missing.unwrap_or(false);
+            let index_doc = match read_checked_index(&store, index, allow_missing).await {
+                Ok(index_doc) => index_doc,
+                Err(rejection) => return rejection.into_response(),
+            };
+            let defaults = match requested.full.is_some()
+                && requested.limit.is_some()
+                && requested.scoring.is_some()
+            {
+                true => IndexSettings::default(),
+                false => index_doc
+                    .as_ref()
+                    .map(|index| index.settings.clone())
+                    .unwrap_or_default(),
+            };
+            let known_n_shards = index_doc
+                .as_ref()
+                .and_then(IndexDocument::known_shard_count);
+            // Indexes whose shards are listed keep the merged postings of hot keywords,
+            // unless a reshard is moving them
+            let merge_cache = index_doc
+                .as_ref()
+                .is_some_and(|index| index.n_shards.is_none() && index.reshard.is_none());
+            let codecs = match index_doc.as_ref().map(CodecSet::for_index) {
+                None => CodecSet::LATEST,
+                Some(Ok(codecs)) => codecs,
+                Some(Err(err)) => {
+                    return Rejection::from_store_error(err, ErrorCode::IndexNotFound)
+                        .into_response()
+                }
+            };
+            let options = EffectiveOptions::resolve(&requested, &defaults);
+            let budget =
+                QueryBudget::from_env(&ctx.env).tightened(query.budget_ms, query.budget_ops);
+
+            let mode = match text_mode(query.query.is_some(), query.text.is_some(), query.mode) {
+                Ok(mode) => mode,
+                Err(error) => {
+                    return json_error(400, ErrorCode::InvalidRequest, error);
+                }
+            };
+            let lexer = match (query.query.as_deref(), query.text.as_deref()) {
+                (Some(query), _) => QueryLexer::from_str(query, &store, &ctx.env),
+                (None, Some(text)) => QueryLexer::from_text(text, mode, &store, &ctx.env),
+                (None, None) => {
+                    let body = req.text().await.unwrap_or_default();
+                    if body.trim().is_empty() {
+                        return json_error(400, ErrorCode::MissingParameter, "Missing query");
+                    }
+                    QueryLexer::from_ast_json(&body, &store, &ctx.env)
+                }
+            };
+            let lexer = match lexer {
+                Ok(lexer) => lexer,
+                Err(
+                    err @ (QueryError::InvalidAst(_)
+                    | QueryError::EmptyWord
+                    | QueryError::InvalidEscape(..)
+                    | QueryError::KeywordTooLong(_)),
+                ) => {
+                    return json_error(400, ErrorCode::InvalidQuery, err.to_string());
+                }
+                Err(QueryError::EmptyQuery) if query.text.is_some() => {
+                    return json_error(400, ErrorCode::InvalidQuery, "text has no words");
+                }
+                Err(_) => return json_error(400, ErrorCode::InvalidQuery, "Failed to parse query"),
+            };
+            // Substrings are only searched for in the bodies of the query's matches, so
+            // a query matching everything but some keywords would read the whole index
+            if !contains.is_empty() && !lexer.is_bounded() {
+                return json_error(
                     400,
+                    ErrorCode::InvalidQuery,
+                    "contains needs a query with a keyword or id: term that isn't negated",
                 );
             }
 
             // Execute the search query
-            let mut documents = lexer.unwrap().query(index).await;
-
-            // If full document bodies are requested, fetch them
-            if query.full.unwrap_or(false) {
-                let durable_reader_ns = get_durable_reader_namespace(&ctx.env).unwrap();
-                let durable_obj = durable_reader_ns.unique_id()?;
-                let bulk_reader = BulkReader::new(get_n_shards(&ctx.env), &store, durable_obj);
-
-                let doc_kv_keys: Vec<String> = documents
-                    .iter()
-                    .map(|key| format!("{}:{}{}", &index, PREFIX_DOCUMENT, &key.doc_id))
-                    .collect();
-
-                let full_doc_bodies = bulk_reader
-                    .get_documents_kv_keys(doc_kv_keys.iter().map(|s| s.as_str()).collect())
-                    .await;
-                for i in 0..documents.len() {
-                    let body = full_doc_bodies[i].document_body.clone();
-                    documents[i].body = body;
-                }
-                // Iterate over all the found doc_ids and merge document data in
-                return Response::from_json(&SearchResponse {
-                    document_count: documents.len() as u32,
-                    matches: documents,
+            let mut lexer = lexer
+                .with_fuzzy(query.fuzzy.unwrap_or(false))
+                .with_case_insensitive(query.case_insensitive.unwrap_or(false))
+                .with_scoring(options.scoring)
+                .with_budget(budget)
+                .with_subrequests(subrequests.clone())
+                .with_trace(trace.as_ref())
+                .with_codecs(codecs)
+                .with_known_n_shards(known_n_shards)
+                .with_merge_cache(merge_cache);
+            // Each stage adds what was imperfect about the results it produced
+            let mut warnings = Warnings::default();
+            if query.warnings.unwrap_or(false) {
+                match StopList::load(&store, index).await {
+                    Ok(stoplist) => {
+                        warnings.extend(stoplist_warnings(&stoplist, &lexer.keywords()))
+                    }
+                    Err(err) => warnings.push(
+                        WarningCode::StopListUnavailable,
+                        format!("Failed to load the stop-list: {}", err),
+                        None,
+                    ),
+                }
+            }
+
+            // Report the corrections a fuzzy search would make, without running it
+            if query.suggest_only.unwrap_or(false) {
+                let corrections = match lexer.suggest(index).await {
+                    Ok(corrections) => corrections,
+                    Err(err) => return search_read_error(err).into_response(),
+                };
+                warnings.extend(lexer.warnings().clone());
+                let budget_exceeded = lexer.budget_exceeded();
+                let mut timings = lexer.timings().clone();
+                timings.kv_ops_used = subrequests.used();
+                return respond(
+                    html,
+                    &SearchResponse {
+                        document_count: 0,
+                        matches: vec![],
+                        timings: query.timings.unwrap_or(false).then_some(timings),
+                        warnings,
+                        corrections,
+                        expanded_query: None,
+                        effective_options: options,
+                        partial: budget_exceeded.is_some(),
+                        budget_exceeded,
+                        diagnostics: trace.map(|trace| finish_trace(trace, &subrequests)),
+                        filter_errors: vec![],
+                        facets: BTreeMap::new(),
+                    },
+                );
+            }
+            // Only the ranked IDs, for joining against another store
+            if ids_only {
+                let mut ids = match lexer.query_ids(index).await {
+                    Ok(ids) => ids,
+                    Err(err) => return search_read_error(err).into_response(),
+                };
+                if let Some(limit) = options.limit {
+                    ids.truncate(limit as usize);
+                }
+                let budget_exceeded = lexer.budget_exceeded();
+                record_usage(&ctx, index, UsageDelta::search(ids.len()));
+                return Response::from_json(&SearchIdsResponse {
+                    document_count: ids.len() as u32,
+                    ids: ids.into_iter().map(|(id, _)| id).collect(),
+                    partial: budget_exceeded.is_some(),
+                    budget_exceeded,
                 });
             }
+            let mut documents = match lexer.query(index).await {
+                Ok(documents) => documents,
+                Err(err) => return search_read_error(err).into_response(),
+            };
+            warnings.extend(lexer.warnings().clone());
+            let mut timings = lexer.timings().clone();
+            let started = now_ms();
 
-            return Response::from_json(&SearchResponse {
-                document_count: documents.len() as u32,
-                matches: documents,
-            });
-        } else {
-            return Response::error(
-                crate::http::ErrorResponse {
-                    error: "Missing query".into(),
-                },
-                400,
+            // Facets fetch every match's document, so they're refused for large results
+            let facet_max_docs = get_facet_max_docs(&ctx.env);
+            if !facet_fields.is_empty() && documents.len() > facet_max_docs {
+                let matched = documents.len();
+                return json_error(
+                    400,
+                    ErrorCode::InvalidRequest,
+                    format!(
+                        "Facets count at most {} matches, but the query matched {}; narrow the query",
+                        facet_max_docs, matched
+                    ),
+                );
+            }
+
+            // Filters, substrings, facets and collapsing read every match's body, and a
+            // recency boost its timestamps, so those documents are fetched before the
+            // limit applies, and reused for the bodies below
+            let hydrates = !filters.is_empty()
+                || !contains.is_empty()
+                || !facet_fields.is_empty()
+                || recency.is_some()
+                || collapse.is_some();
+            let mut hydrated = None;
+            let mut dangling = 0;
+            let mut filter_errors = vec![];
+            let mut facets = BTreeMap::new();
+            if hydrates {
+                let hydrated_docs = hydrate(
+                    &ctx.env,
+                    &store,
+                    index,
+                    &documents,
+                    lexer.budget_mut(),
+                    trace.as_ref(),
+                )
+                .await;
+                let mut docs = match hydrated_docs {
+                    Ok(docs) => docs,
+                    Err(rejection) => return rejection.into_response(),
+                };
+                (documents, docs) = drop_unknown_ids(documents, docs);
+                dangling += flag_missing(&mut documents, &docs);
+                if !filters.is_empty() {
+                    let (kept, kept_docs, errors) = apply_filters(&filters, documents, docs);
+                    documents = kept;
+                    docs = kept_docs;
+                    filter_errors = errors;
+                }
+                if !contains.is_empty() {
+                    (documents, docs) = apply_contains(&contains, documents, docs);
+                }
+                if let Some(recency) = &recency {
+                    (documents, docs) = apply_recency(recency, documents, docs, now_ms());
+                }
+                if !facet_fields.is_empty() {
+                    let metadata: Vec<_> = docs
+                        .iter()
+                        .flatten()
+                        .filter_map(Document::metadata)
+                        .collect();
+                    facets = count_facets(&facet_fields, metadata.iter());
+                }
+                // Facets count every match, collapsed or not
+                if let Some(collapse) = &collapse {
+                    (documents, docs) =
+                        apply_collapse(collapse, documents, docs, options.full && fields.body);
+                }
+                hydrated = Some(docs);
+            }
+            if let Some(limit) = options.limit {
+                documents.truncate(limit as usize);
+            }
+
+            // If full document bodies are requested (and will be returned), fetch them
+            // in rounds until the budget runs out, leaving the rest without a body
+            if options.full && fields.body {
+                let mut docs = match hydrated {
+                    Some(docs) => docs,
+                    None => {
+                        let hydrated_docs = hydrate(
+                            &ctx.env,
+                            &store,
+                            index,
+                            &documents,
+                            lexer.budget_mut(),
+                            trace.as_ref(),
+                        )
+                        .await;
+                        match hydrated_docs {
+                            Ok(docs) => docs,
+                            Err(rejection) => return rejection.into_response(),
+                        }
+                    }
+                };
+                (documents, docs) = drop_unknown_ids(documents, docs);
+                dangling += flag_missing(&mut documents, &docs);
+                for (row, doc) in documents.iter_mut().zip(docs) {
+                    if let Some(doc) = doc {
+                        row.title = doc.title;
+                        row.body = doc.document_body;
+                    }
+                }
+            }
+            if dangling > 0 {
+                edge_log!(
+                    console_warn,
+                    "Search",
+                    index,
+                    "dangling_postings={}: matches whose document no longer exists, run fsck to repair",
+                    dangling
+                );
+                if query.drop_missing.unwrap_or(false) {
+                    documents.retain(|row| !row.missing);
+                }
+            }
+            if hydrates || (options.full && fields.body) {
+                timings.hydrate_ms = elapsed_ms(started, now_ms());
+            }
+            timings.kv_ops_used = subrequests.used();
+
+            edge_log!(
+                console_log,
+                "Search",
+                index,
+                "timings parse_ms={} preload_ms={} shard_reads={} evaluate_ms={} sort_ms={} hydrate_ms={}",
+                (timings.parse_ms),
+                (timings.preload_ms),
+                (timings.shard_reads),
+                (timings.evaluate_ms),
+                (timings.sort_ms),
+                (timings.hydrate_ms)
             );
+            let budget_exceeded = lexer.budget_exceeded();
+            if let Some(reason) = budget_exceeded {
+                let ops = lexer.budget_mut().ops();
+                edge_log!(
+                    console_warn,
+                    "Search",
+                    index,
+                    "budget exceeded reason={:?} ops={}, returning partial results",
+                    reason,
+                    ops
+                );
+            }
+
+            // Last, so the limit and collapsing ranked by the raw scores
+            if let Some(normalization) = &normalization {
+                apply_normalization(normalization, &mut documents);
+            }
+
+            // The boost behind each score is diagnostic output
+            if !(query.timings.unwrap_or(false) || debug) {
+                for row in documents.iter_mut() {
+                    row.recency_factor = None;
+                    for hit in row.collapsed_hits.iter_mut() {
+                        hit.recency_factor = None;
+                    }
+                }
+            }
+
+            record_usage(&ctx, index, UsageDelta::search(documents.len()));
+            respond(
+                html,
+                &SearchResponse {
+                    document_count: documents.len() as u32,
+                    matches: documents.iter().map(|row| fields.shape(row)).collect(),
+                    timings: query.timings.unwrap_or(false).then_some(timings),
+                    warnings,
+                    corrections: lexer.corrections().to_vec(),
+                    expanded_query: lexer.expanded_query(),
+                    effective_options: options,
+                    partial: budget_exceeded.is_some(),
+                    budget_exceeded,
+                    diagnostics: trace.map(|trace| finish_trace(trace, &subrequests)),
+                    filter_errors,
+                    facets,
+                },
+            )
+        } else {
+            json_error(400, ErrorCode::MissingParameter, "Missing query")
         }
     } else {
-        return Response::error(
-            crate::http::ErrorResponse {
-                error: "Missing index name".into(),
-            },
-            400,
+        json_error(400, ErrorCode::MissingParameter, "Missing index name")
+    }
+}
+
+/// The JSON body of a search, or with `Accept: text/html` what its HTML is rendered
+/// from, see [`render_html::search_results`]
+#[derive(serde::Serialize)]
+pub struct SearchResponse<'a> {
+    pub document_count: u32,
+    pub matches: Vec<SearchResultView<'a>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timings: Option<Timings>,
+    /// What is imperfect about the results, like keywords that matched nothing
+    #[serde(skip_serializing_if = "Warnings::is_empty")]
+    pub warnings: Warnings,
+    /// Query keywords that matched nothing and were replaced, with `fuzzy=true`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub corrections: Vec<Correction>,
+    /// The query with every keyword's casing variants, with `case_insensitive=true`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expanded_query: Option<String>,
+    /// The options the search ran with, after filling in the index's defaults
+    pub effective_options: EffectiveOptions,
+    /// Whether the search stopped reading early, so some matches or bodies may be
+    /// missing
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub partial: bool,
+    /// Which part of the search budget ran out, for partial results
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub budget_exceeded: Option<BudgetExceeded>,
+    /// The KV keys the search read, with `debug=true`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub diagnostics: Option<SearchDiagnostics>,
+    /// Filtered fields that none of the matched documents have
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub filter_errors: Vec<FilterError>,
+    /// Match counts per value of each field named by `facets=`
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub facets: BTreeMap<String, Facet>,
+}
+
+/// The body of an `ids_only=true` search
+#[derive(serde::Serialize)]
+pub struct SearchIdsResponse {
+    pub document_count: u32,
+    /// The matching document IDs, best first
+    pub ids: Vec<String>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub partial: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub budget_exceeded: Option<BudgetExceeded>,
+}
+
+/// Why `ids_only` can't be combined with the parameters set in `row_params`, which
+/// read each match's document or shape its row
+fn ids_only_conflict(ids_only: bool, row_params: &[(&str, bool)]) -> Option<String> {
+    let set: Vec<&str> = row_params
+        .iter()
+        .filter(|(_, set)| *set)
+        .map(|(name, _)| *name)
+        .collect();
+    match ids_only && !set.is_empty() {
+        true => Some(format!(
+            "ids_only can't be combined with {}",
+            set.join(", ")
+        )),
+        false => None,
+    }
+}
+
+/// How a search whose keyword shards couldn't be read is answered: a KV or durable
+/// reader failure with a retryable 502
+fn search_read_error(err: DataStoreError) -> Rejection {
+    Rejection::from_store_error(err, ErrorCode::IndexNotFound)
+}
+
+/// The search as JSON, or as the search page's results when the request accepts HTML
+fn respond(html: bool, response: &SearchResponse) -> Result<Response> {
+    match html {
+        true => Response::from_html(render_html::search_results(response)),
+        false => Response::from_json(response),
+    }
+}
+
+/// The diagnostics collected into `trace`, with the subrequests made in all
+fn finish_trace(trace: ReadTrace, subrequests: &OpBudget) -> SearchDiagnostics {
+    let mut diagnostics = trace.finish();
+    diagnostics.kv_ops_used = subrequests.used();
+    diagnostics
+}
+
+/// How many document bodies are fetched per round of hydration, between budget checks
+const HYDRATE_ROUND: usize = 50;
+
+/// Fetch the documents of `rows`, with their offloaded bodies, in rounds until the
+/// budget runs out. Rows past the last round have no entry. A missing durable reader
+/// binding is a 500 naming it.
+async fn hydrate<S: Storage>(
+    env: &Env,
+    store: &S,
+    index: &str,
+    rows: &[SearchResultRow],
+    budget: &mut BudgetTracker,
+    trace: Option<&ReadTrace>,
+) -> std::result::Result<Vec<Option<Document>>, Rejection> {
+    let durable_reader_ns = get_durable_reader_namespace(env).map_err(|err| {
+        Rejection::new(
+            500,
+            ErrorCode::InternalError,
+            format!(
+                "The durable reader binding '{}' is unavailable: {}",
+                DurableReader::BINDING_ID,
+                err
+            ),
+        )
+    })?;
+    let durable_obj = ReaderPlacement::from_env(env).reader_id(&durable_reader_ns, index)?;
+    let bulk_reader =
+        BulkReader::new(get_n_shards(env), store, Some(durable_obj)).with_trace(trace);
+
+    let doc_kv_keys: Vec<String> = rows
+        .iter()
+        .map(|key| format!("{}:{}{}", &index, PREFIX_DOCUMENT, &key.doc_id))
+        .collect();
+
+    let mut docs = vec![];
+    for round in doc_kv_keys.chunks(HYDRATE_ROUND) {
+        if !budget.has_room() {
+            break;
+        }
+        budget.spend(round.len());
+        docs.extend(
+            bulk_reader
+                .get_documents_kv_keys(round.iter().map(|s| s.as_str()).collect())
+                .await,
         );
     }
+    // Documents whose delete is under way are treated as already deleted
+    if docs.iter().any(Option::is_some) {
+        budget.spend(1);
+        match pending_deletions(store, index).await {
+            Ok(pending) if !pending.is_empty() => {
+                for doc in docs.iter_mut() {
+                    if doc
+                        .as_ref()
+                        .is_some_and(|doc| pending.contains(&doc.get_uuid()))
+                    {
+                        *doc = None;
+                    }
+                }
+            }
+            Ok(_) => {}
+            Err(err) => edge_log!(
+                console_warn,
+                "Search",
+                index,
+                "Failed to list the documents being deleted: {}",
+                err
+            ),
+        }
+    }
+    if let Some(bodies) = get_body_bucket(env) {
+        let offloaded = docs
+            .iter()
+            .flatten()
+            .filter(|doc| doc.document_body.is_none() && doc.body_ref.is_some())
+            .count();
+        store.count_subrequests(offloaded);
+        let loads = docs
+            .iter_mut()
+            .zip(doc_kv_keys.iter())
+            .filter_map(|(doc, doc_id)| Some((doc.as_mut()?, doc_id)))
+            .map(async |(doc, doc_id)| (doc_id, doc.load_body(&bodies).await));
+        for (doc_id, result) in join_bounded(loads).await {
+            if let Err(err) = result {
+                edge_log!(
+                    console_warn,
+                    "Search",
+                    index,
+                    "Failed to load the offloaded body of {}: {}",
+                    doc_id,
+                    err
+                );
+            }
+        }
+    }
+    Ok(docs)
+}
+
+/// Drop the rows matched only by an `id:` term whose fetched document doesn't exist,
+/// along with their entries in `docs`. Unlike a dangling posting, nothing stored
+/// names the document, so it isn't flagged. Rows past the end of `docs` are kept.
+fn drop_unknown_ids(
+    rows: Vec<SearchResultRow>,
+    docs: Vec<Option<Document>>,
+) -> (Vec<SearchResultRow>, Vec<Option<Document>>) {
+    let mut docs = docs.into_iter();
+    let (mut kept, mut kept_docs) = (Vec::with_capacity(rows.len()), vec![]);
+    for row in rows {
+        match docs.next() {
+            Some(None) if row.keywords.is_empty() => {}
+            Some(doc) => {
+                kept.push(row);
+                kept_docs.push(doc);
+            }
+            None => kept.push(row),
+        }
+    }
+    (kept, kept_docs)
+}
+
+/// Flag the rows whose document was fetched but doesn't exist, left behind in a
+/// shard by a deleted or expired document. Rows past the end of `docs` weren't
+/// fetched, so they aren't flagged. Returns how many rows were newly flagged.
+fn flag_missing(rows: &mut [SearchResultRow], docs: &[Option<Document>]) -> usize {
+    let mut flagged = 0;
+    for (row, doc) in rows.iter_mut().zip(docs) {
+        if doc.is_none() && !row.missing {
+            row.missing = true;
+            flagged += 1;
+        }
+    }
+    flagged
+}
+
+/// Keep the matches whose documents pass every filter, along with those documents,
+/// and report the filtered fields no document has. Matches the budget left
+/// unfetched can't be tested, so they're dropped.
+fn apply_filters(
+    filters: &Filters,
+    rows: Vec<SearchResultRow>,
+    docs: Vec<Option<Document>>,
+) -> (
+    Vec<SearchResultRow>,
+    Vec<Option<Document>>,
+    Vec<FilterError>,
+) {
+    let metadata: Vec<_> = docs
+        .iter()
+        .map(|doc| doc.as_ref().and_then(Document::metadata))
+        .collect();
+    let errors = filters.absent_fields(metadata.iter().flatten());
+
+    let (kept, kept_docs) = rows
+        .into_iter()
+        .zip(docs)
+        .zip(metadata)
+        .filter(|(_, metadata)| metadata.as_ref().is_some_and(|m| filters.matches(m)))
+        .map(|(matched, _)| matched)
+        .unzip();
+    (kept, kept_docs, errors)
+}
+
+/// Keep the matches whose bodies contain every substring, along with their
+/// documents, noting where in each body the substrings were found. Like filters,
+/// matches the budget left unfetched are dropped.
+fn apply_contains(
+    contains: &Contains,
+    rows: Vec<SearchResultRow>,
+    docs: Vec<Option<Document>>,
+) -> (Vec<SearchResultRow>, Vec<Option<Document>>) {
+    rows.into_iter()
+        .zip(docs)
+        .filter_map(|(mut row, doc)| {
+            let body = doc.as_ref()?.document_body.as_deref()?;
+            row.matched_spans = contains.spans(body)?;
+            Some((row, doc))
+        })
+        .unzip()
+}
+
+/// Multiply each match's score by its document's recency factor at `now` and rank
+/// them again, keeping `docs` in step. Matches without a document or timestamps
+/// keep their score.
+fn apply_recency(
+    recency: &RecencyBoost,
+    rows: Vec<SearchResultRow>,
+    docs: Vec<Option<Document>>,
+    now: u64,
+) -> (Vec<SearchResultRow>, Vec<Option<Document>>) {
+    let mut ranked: Vec<_> = rows
+        .into_iter()
+        .zip(docs)
+        .map(|(mut row, doc)| {
+            row.recency_factor = doc.as_ref().and_then(|doc| recency.factor(doc, now));
+            row.score *= row.recency_factor.unwrap_or(1.0);
+            (row, doc)
+        })
+        .collect();
+    ranked.sort_by(|(a, _), (b, _)| rank_order(a, b));
+    ranked.into_iter().unzip()
+}
+
+/// Replace each match's score with its normalized one, keeping the score it had as
+/// `raw_score`. Collapsed hits keep their raw scores.
+fn apply_normalization(normalization: &Normalization, rows: &mut [SearchResultRow]) {
+    let scores: Vec<f64> = rows.iter().map(|row| row.score).collect();
+    for (row, score) in rows.iter_mut().zip(normalization.apply(&scores)) {
+        row.raw_score = Some(row.score);
+        row.score = score;
+    }
+}
+
+/// Keep the best-ranked match of each group sharing a value of the collapsed field,
+/// along with its document, counting the others into it and moving the first
+/// `collapse.hits` of them under it, with their bodies when `bodies` is set. Matches
+/// without a document, or without the field, are groups of their own.
+fn apply_collapse(
+    collapse: &Collapse,
+    rows: Vec<SearchResultRow>,
+    docs: Vec<Option<Document>>,
+    bodies: bool,
+) -> (Vec<SearchResultRow>, Vec<Option<Document>>) {
+    let mut groups: HashMap<String, usize> = HashMap::new();
+    let mut docs = docs.into_iter();
+    let (mut kept, mut kept_docs): (Vec<SearchResultRow>, _) = (vec![], vec![]);
+    for mut row in rows {
+        // Rows past the end of `docs` weren't fetched, and keep no entry in it
+        let doc = docs.next();
+        let key = doc
+            .as_ref()
+            .and_then(|doc| collapse.group_key(doc.as_ref()?));
+        if let Some(&top) = key.as_ref().and_then(|key| groups.get(key)) {
+            let top = &mut kept[top];
+            top.collapsed_count = top.collapsed_count.map(|count| count + 1);
+            if top.collapsed_hits.len() < collapse.hits {
+                if let Some(doc) = doc.flatten().filter(|_| bodies) {
+                    row.title = doc.title;
+                    row.body = doc.document_body;
+                }
+                top.collapsed_hits.push(row);
+            }
+            continue;
+        }
+        if let Some(key) = key {
+            groups.insert(key, kept.len());
+        }
+        row.collapsed_count = Some(0);
+        kept.push(row);
+        kept_docs.extend(doc);
+    }
+    (kept, kept_docs)
+}
+
+/// How `text` is rewritten into a query, refusing `text` alongside `query` and a
+/// `mode` without `text`
+fn text_mode(
+    has_query: bool,
+    has_text: bool,
+    mode: Option<String>,
+) -> std::result::Result<SimpleMode, String> {
+    match (has_query, has_text, mode) {
+        (true, true, _) => Err("Pass either query or text, not both".into()),
+        (_, false, Some(_)) => Err("mode only applies to text".into()),
+        (_, _, None) => Ok(SimpleMode::default()),
+        (_, _, Some(name)) => SimpleMode::from_name(&name).ok_or_else(|| {
+            format!(
+                "Unknown mode '{}', expected one of {}",
+                name,
+                SimpleMode::NAMES.join(", ")
+            )
+        }),
+    }
+}
+
+/// Validate the search options given as query parameters
+fn requested_options(
+    full: Option<bool>,
+    limit: Option<u32>,
+    scoring: Option<String>,
+) -> std::result::Result<IndexSettings, String> {
+    let scoring = match scoring
+        .as_deref()
+        .map(|name| (name, ScoringMode::from_name(name)))
+    {
+        None => None,
+        Some((_, Some(mode))) => Some(mode),
+        Some((name, None)) => {
+            return Err(format!(
+                "Unknown scoring '{}', expected one of {}",
+                name,
+                ScoringMode::NAMES.join(", ")
+            ))
+        }
+    };
+    Ok(IndexSettings {
+        full,
+        limit: limit.map(check_limit).transpose()?,
+        scoring,
+        position_boost: None,
+        title_boost: None,
+        min_keyword_score: None,
+        extractor: None,
+        version: None,
+    })
+}
+
+/// The full, limit and scoring options a search ran with, the global defaults by
+/// default
+#[derive(serde::Serialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct EffectiveOptions {
+    pub full: bool,
+    /// The most matches returned, or `None` for all of them
+    pub limit: Option<u32>,
+    pub scoring: ScoringMode,
+}
+
+impl EffectiveOptions {
+    /// Each option as requested, else the index's default, else the global default
+    pub fn resolve(requested: &IndexSettings, defaults: &IndexSettings) -> EffectiveOptions {
+        EffectiveOptions {
+            full: requested.full.or(defaults.full).unwrap_or(false),
+            limit: requested.limit.or(defaults.limit),
+            scoring: requested.scoring.or(defaults.scoring).unwrap_or_default(),
+        }
+    }
+}
+
+/// A warning for every query keyword that is stop-listed, since documents indexed
+/// after it was stop-listed can't match it
+pub fn stoplist_warnings(stoplist: &StopList, keywords: &[String]) -> Vec<SearchWarning> {
+    let mut warned: Vec<&String> = keywords
+        .iter()
+        .filter(|keyword| stoplist.contains(keyword))
+        .collect();
+    warned.dedup();
+    warned
+        .into_iter()
+        .map(|keyword| SearchWarning {
+            code: WarningCode::StopListed,
+            message: format!(
+                "'{}' is stop-listed and is not indexed in new documents",
+                keyword
+            ),
+            detail: Some(keyword.clone()),
+        })
+        .collect()
+}
+
+/// The `SearchResultRow` fields a search response serializes, selected with the
+/// comma-separated `fields=` parameter. `doc_id` is always included.
+#[derive(Debug, PartialEq)]
+pub struct SearchFields {
+    pub score: bool,
+    pub keywords: bool,
+    /// `matched_terms` and `total_terms`
+    pub terms: bool,
+    pub body: bool,
+}
+
+impl Default for SearchFields {
+    fn default() -> Self {
+        SearchFields {
+            score: true,
+            keywords: true,
+            terms: true,
+            body: true,
+        }
+    }
+}
+
+impl SearchFields {
+    pub const ALLOWED: &'static str = "doc_id,score,keywords,terms,body";
+
+    /// Parse the `fields=` parameter, where a missing parameter selects every field
+    pub fn parse(fields: Option<&str>) -> std::result::Result<SearchFields, String> {
+        let Some(fields) = fields else {
+            return Ok(SearchFields::default());
+        };
+
+        let mut selected = SearchFields {
+            score: false,
+            keywords: false,
+            terms: false,
+            body: false,
+        };
+        for field in fields.split(',').map(str::trim).filter(|f| !f.is_empty()) {
+            match field {
+                "doc_id" => {}
+                "score" => selected.score = true,
+                "keywords" => selected.keywords = true,
+                "terms" => selected.terms = true,
+                "body" => selected.body = true,
+                _ => {
+                    return Err(format!(
+                        "Unknown field '{}', expected one of: {}",
+                        field,
+                        Self::ALLOWED
+                    ))
+                }
+            }
+        }
+        Ok(selected)
+    }
+
+    pub fn shape<'a>(&self, row: &'a SearchResultRow) -> SearchResultView<'a> {
+        SearchResultView {
+            doc_id: &row.doc_id,
+            score: self.score.then_some(row.score),
+            raw_score: row.raw_score.filter(|_| self.score),
+            keywords: self.keywords.then_some(row.keywords.as_slice()),
+            matched_terms: self.terms.then_some(row.matched_terms),
+            total_terms: self.terms.then_some(row.total_terms),
+            body: self.body.then_some(&row.body),
+            title: row.title.as_deref().filter(|_| self.body),
+            missing: row.missing,
+            matched_spans: &row.matched_spans,
+            recency_factor: row.recency_factor,
+            collapsed_count: row.collapsed_count,
+            collapsed_hits: row
+                .collapsed_hits
+                .iter()
+                .map(|hit| self.shape(hit))
+                .collect(),
+        }
+    }
 }
 
-#[derive(serde::Deserialize, serde::Serialize)]
-struct SearchResponse {
-    document_count: u32,
-    matches: Vec<SearchResultRow>,
+/// A `SearchResultRow` trimmed down to the selected fields for serialization
+#[derive(serde::Serialize)]
+pub struct SearchResultView<'a> {
+    pub doc_id: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub score: Option<f64>,
+    /// Sent with `score` when the search used `normalize`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub raw_score: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keywords: Option<&'a [(String, f64)]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub matched_terms: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total_terms: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub body: Option<&'a Option<String>>,
+    /// Sent with `body` for documents that have a title
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<&'a str>,
+    /// Set whatever the fields when the document no longer exists
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub missing: bool,
+    /// Set whatever the fields when the search used `contains`
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    pub matched_spans: &'a [[usize; 2]],
+    /// Set whatever the fields when a boosted search reports timings or diagnostics
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub recency_factor: Option<f64>,
+    /// Set whatever the fields when the search used `collapse`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub collapsed_count: Option<usize>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub collapsed_hits: Vec<SearchResultView<'a>>,
 }
 
 #[derive(serde::Serialize, serde::Deserialize)]
@@ -87,5 +1075,677 @@ pub struct SearchResultRow {
     pub doc_id: String,
     pub score: f64,
     pub keywords: Vec<(String, f64)>,
+    /// The query's distinct keywords this document matched
+    #[serde(default)]
+    pub matched_terms: usize,
+    /// The query's distinct keywords, negated ones included
+    #[serde(default)]
+    pub total_terms: usize,
     pub body: Option<String>,
+    /// The document's title, fetched along with its body
+    #[serde(default)]
+    pub title: Option<String>,
+    /// Whether the matched document was fetched and found not to exist
+    #[serde(default)]
+    pub missing: bool,
+    /// Byte ranges `[start, end)` of the body where a `contains` substring was found
+    #[serde(default)]
+    pub matched_spans: Vec<[usize; 2]>,
+    /// What `recency_boost` multiplied the score by
+    #[serde(default)]
+    pub recency_factor: Option<f64>,
+    /// The score before `normalize` rescaled it
+    #[serde(default)]
+    pub raw_score: Option<f64>,
+    /// How many other matches `collapse` folded into this one
+    #[serde(default)]
+    pub collapsed_count: Option<usize>,
+    /// The best-ranked of those, up to `collapse_hits`
+    #[serde(default)]
+    pub collapsed_hits: Vec<SearchResultRow>,
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+
+    use super::*;
+    use crate::data::{
+        document::{document_kv_key, testing::index_text},
+        storage::memory::MemoryStorage,
+        DEFAULT_N_SHARDS,
+    };
+
+    #[test]
+    fn test_option_precedence() {
+        let defaults = IndexSettings {
+            full: Some(true),
+            limit: Some(20),
+            scoring: Some(ScoringMode::Coverage),
+            position_boost: None,
+            title_boost: None,
+            min_keyword_score: None,
+            extractor: None,
+            version: None,
+        };
+
+        // Index defaults fill in what the request leaves out
+        let requested = requested_options(None, Some(5), None).unwrap();
+        assert_eq!(
+            EffectiveOptions::resolve(&requested, &defaults),
+            EffectiveOptions {
+                full: true,
+                limit: Some(5),
+                scoring: ScoringMode::Coverage,
+            }
+        );
+
+        // Explicit parameters win, even when they restate a global default
+        let requested = requested_options(Some(false), None, Some("mean".into())).unwrap();
+        let options = EffectiveOptions::resolve(&requested, &defaults);
+        assert_eq!((options.full, options.limit), (false, Some(20)));
+        assert_eq!(options.scoring, ScoringMode::Mean);
+
+        // Without index defaults, the global defaults apply
+        assert_eq!(
+            EffectiveOptions::resolve(
+                &requested_options(None, None, None).unwrap(),
+                &IndexSettings::default()
+            ),
+            EffectiveOptions {
+                full: false,
+                limit: None,
+                scoring: ScoringMode::Mean,
+            }
+        );
+    }
+
+    #[test]
+    fn test_invalid_requested_options() {
+        assert!(requested_options(None, Some(0), None).is_err());
+        assert!(requested_options(None, Some(IndexSettings::MAX_LIMIT + 1), None).is_err());
+        let unknown = requested_options(None, None, Some("bm25".into())).unwrap_err();
+        assert!(unknown.contains("bm25") && unknown.contains("coverage"));
+    }
+
+    #[test]
+    fn test_ids_only_conflict() {
+        let params = [("full", true), ("filter", false), ("collapse", true)];
+        assert_eq!(
+            ids_only_conflict(true, &params).as_deref(),
+            Some("ids_only can't be combined with full, collapse")
+        );
+        assert_eq!(ids_only_conflict(false, &params), None);
+        assert_eq!(ids_only_conflict(true, &[("filter", false)]), None);
+
+        let response = SearchIdsResponse {
+            document_count: 2,
+            ids: vec!["b".into(), "a".into()],
+            partial: false,
+            budget_exceeded: None,
+        };
+        assert_eq!(
+            serde_json::to_string(&response).unwrap(),
+            r#"{"document_count":2,"ids":["b","a"]}"#
+        );
+    }
+
+    #[test]
+    fn test_text_mode() {
+        assert_eq!(text_mode(false, true, None), Ok(SimpleMode::All));
+        assert_eq!(
+            text_mode(false, true, Some("phrase".into())),
+            Ok(SimpleMode::Phrase)
+        );
+        assert_eq!(text_mode(true, false, None), Ok(SimpleMode::All));
+        assert!(text_mode(true, true, None).is_err());
+        assert!(text_mode(true, false, Some("any".into())).is_err());
+        let unknown = text_mode(false, true, Some("exact".into())).unwrap_err();
+        assert!(unknown.contains("exact") && unknown.contains("phrase"));
+    }
+
+    fn row(n_keywords: usize) -> SearchResultRow {
+        SearchResultRow {
+            doc_id: "doc1".into(),
+            score: 0.75,
+            keywords: (0..n_keywords)
+                .map(|i| (format!("keyword{}", i), 0.5))
+                .collect(),
+            matched_terms: n_keywords,
+            total_terms: n_keywords,
+            body: None,
+            title: None,
+            missing: false,
+            matched_spans: vec![],
+            recency_factor: None,
+            raw_score: None,
+            collapsed_count: None,
+            collapsed_hits: vec![],
+        }
+    }
+
+    fn payload_len(fields: &SearchFields, row: &SearchResultRow) -> usize {
+        serde_json::to_string(&fields.shape(row)).unwrap().len()
+    }
+
+    #[test]
+    fn test_stoplist_warnings() {
+        let stoplist = StopList::new("idx", &["Acme Corp"]);
+        let keywords = vec!["ocean".to_string(), "ACME corp".to_string()];
+        let warnings = stoplist_warnings(&stoplist, &keywords);
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].code, WarningCode::StopListed);
+        assert_eq!(
+            warnings[0].message,
+            "'ACME corp' is stop-listed and is not indexed in new documents"
+        );
+        assert_eq!(warnings[0].detail.as_deref(), Some("ACME corp"));
+        assert!(stoplist_warnings(&StopList::default(), &keywords).is_empty());
+    }
+
+    #[test]
+    fn test_parse_fields() {
+        assert_eq!(SearchFields::parse(None).unwrap(), SearchFields::default());
+        assert_eq!(
+            SearchFields::parse(Some("doc_id, score")).unwrap(),
+            SearchFields {
+                score: true,
+                keywords: false,
+                terms: false,
+                body: false,
+            }
+        );
+        assert!(SearchFields::parse(Some("score,title")).is_err());
+    }
+
+    #[test]
+    fn test_doc_id_is_always_serialized() {
+        let fields = SearchFields::parse(Some("")).unwrap();
+        let json = serde_json::to_string(&fields.shape(&row(3))).unwrap();
+        assert_eq!(json, r#"{"doc_id":"doc1"}"#);
+    }
+
+    #[test]
+    fn test_normalized_scores_keep_raw_score() {
+        let mut rows: Vec<SearchResultRow> = [0.75, 0.5, 0.25]
+            .into_iter()
+            .map(|score| SearchResultRow { score, ..row(0) })
+            .collect();
+        apply_normalization(&Normalization::MinMax, &mut rows);
+        let scores: Vec<(f64, Option<f64>)> =
+            rows.iter().map(|row| (row.score, row.raw_score)).collect();
+        assert_eq!(
+            scores,
+            vec![(1.0, Some(0.75)), (0.5, Some(0.5)), (0.0, Some(0.25))]
+        );
+
+        let shaped = serde_json::to_value(SearchFields::default().shape(&rows[0])).unwrap();
+        assert_eq!(
+            (shaped["score"].as_f64(), shaped["raw_score"].as_f64()),
+            (Some(1.0), Some(0.75))
+        );
+        let unscored = SearchFields::parse(Some("keywords"))
+            .unwrap()
+            .shape(&rows[0]);
+        assert!(serde_json::to_value(unscored)
+            .unwrap()
+            .get("raw_score")
+            .is_none());
+    }
+
+    #[test]
+    fn test_title_is_sent_with_body() {
+        let mut titled = row(0);
+        titled.title = Some("Lighthouses".into());
+        titled.body = Some("Keepers tended the lamps".into());
+        let shaped = |fields: &str| {
+            let fields = SearchFields::parse(Some(fields)).unwrap();
+            serde_json::to_value(fields.shape(&titled)).unwrap()
+        };
+        assert_eq!(shaped("body")["title"], "Lighthouses");
+        assert!(shaped("score").get("title").is_none());
+        let untitled = serde_json::to_value(SearchFields::default().shape(&row(0))).unwrap();
+        assert!(untitled.get("title").is_none());
+    }
+
+    #[test]
+    fn test_dropping_keywords_trims_payload() {
+        let row = row(50);
+        let full = payload_len(&SearchFields::default(), &row);
+        let trimmed = payload_len(&SearchFields::parse(Some("doc_id,score")).unwrap(), &row);
+
+        assert!(full > 50 * "[\"keyword0\",0.5]".len());
+        assert!(trimmed < 40, "trimmed row is {} bytes", trimmed);
+    }
+
+    #[test]
+    fn test_timings_only_serialized_when_requested() {
+        let rows = [row(1)];
+        let response = |timings| SearchResponse {
+            document_count: 1,
+            matches: rows
+                .iter()
+                .map(|r| SearchFields::default().shape(r))
+                .collect(),
+            timings,
+            warnings: Warnings::default(),
+            corrections: vec![],
+            expanded_query: None,
+            effective_options: EffectiveOptions::default(),
+            partial: false,
+            budget_exceeded: None,
+            diagnostics: None,
+            filter_errors: vec![],
+            facets: BTreeMap::new(),
+        };
+
+        let json = serde_json::to_value(response(Some(Timings::default()))).unwrap();
+        let timings = json["timings"].as_object().unwrap();
+        assert_eq!(timings.len(), 7);
+        assert!(timings.values().all(|ms| ms.as_u64().is_some()));
+
+        let json = serde_json::to_value(response(None)).unwrap();
+        assert!(json.get("timings").is_none());
+    }
+
+    #[test]
+    fn test_corrections_serialization() {
+        let response = SearchResponse {
+            document_count: 0,
+            matches: vec![],
+            timings: None,
+            warnings: Warnings::default(),
+            corrections: vec![Correction {
+                original: "progamming".into(),
+                used: "programming".into(),
+            }],
+            expanded_query: None,
+            effective_options: EffectiveOptions::default(),
+            partial: false,
+            budget_exceeded: None,
+            diagnostics: None,
+            filter_errors: vec![],
+            facets: BTreeMap::new(),
+        };
+        assert_eq!(
+            serde_json::to_string(&response).unwrap(),
+            concat!(
+                r#"{"document_count":0,"matches":[],"corrections":[{"original":"progamming","used":"programming"}],"#,
+                r#""effective_options":{"full":false,"limit":null,"scoring":"mean"}}"#
+            )
+        );
+    }
+
+    #[test]
+    fn test_warnings_serialization() {
+        let store = MemoryStorage::default();
+        index_text(&store, "idx", "doc1", "Ocean tides rise at dawn.");
+        let response = |warnings| SearchResponse {
+            document_count: 1,
+            matches: vec![],
+            timings: None,
+            warnings,
+            corrections: vec![],
+            expanded_query: None,
+            effective_options: EffectiveOptions::default(),
+            partial: false,
+            budget_exceeded: None,
+            diagnostics: None,
+            filter_errors: vec![],
+            facets: BTreeMap::new(),
+        };
+
+        let ast = crate::lexer::Expr::parse("ocean && (volcano || tides)").unwrap();
+        let mut lexer = QueryLexer::direct(ast, &store, DEFAULT_N_SHARDS);
+        assert_eq!(block_on(lexer.query("idx")).unwrap().len(), 1);
+        let json = serde_json::to_value(response(lexer.warnings().clone())).unwrap();
+        assert_eq!(
+            json["warnings"],
+            serde_json::json!([{
+                "code": "keyword_no_matches",
+                "message": "'volcano' matched no documents",
+                "detail": "volcano",
+            }])
+        );
+
+        // Queries whose every keyword matched leave the field out
+        let ast = crate::lexer::Expr::parse("ocean && tides").unwrap();
+        let mut lexer = QueryLexer::direct(ast, &store, DEFAULT_N_SHARDS);
+        block_on(lexer.query("idx")).unwrap();
+        let json = serde_json::to_value(response(lexer.warnings().clone())).unwrap();
+        assert!(json.get("warnings").is_none());
+    }
+
+    #[test]
+    fn test_failed_shard_reads_are_retryable() {
+        let store = MemoryStorage::default();
+        index_text(&store, "idx", "doc1", "Ocean tides rise at dawn.");
+        store.fail_lists("idx:kw:", 1);
+        let ast = crate::lexer::Expr::parse("ocean").unwrap();
+        let mut lexer = QueryLexer::direct(ast, &store, DEFAULT_N_SHARDS);
+        let Err(err) = block_on(lexer.query("idx")) else {
+            panic!("the search should fail while its shards can't be listed");
+        };
+        let rejection = search_read_error(err);
+        assert_eq!(
+            (rejection.status, rejection.code, rejection.retryable),
+            (502, ErrorCode::InternalError, true)
+        );
+
+        // Sent again once the store is back, the search finds the document
+        assert_eq!(block_on(lexer.query("idx")).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_partial_serialization() {
+        let response = |budget_exceeded: Option<BudgetExceeded>| SearchResponse {
+            document_count: 0,
+            matches: vec![],
+            timings: None,
+            warnings: Warnings::default(),
+            corrections: vec![],
+            expanded_query: None,
+            effective_options: EffectiveOptions::default(),
+            partial: budget_exceeded.is_some(),
+            budget_exceeded,
+            diagnostics: None,
+            filter_errors: vec![],
+            facets: BTreeMap::new(),
+        };
+
+        let json = serde_json::to_value(response(Some(BudgetExceeded::Ops))).unwrap();
+        assert_eq!(json["partial"], true);
+        assert_eq!(json["budget_exceeded"], "ops");
+
+        let json = serde_json::to_value(response(None)).unwrap();
+        assert!(json.get("partial").is_none() && json.get("budget_exceeded").is_none());
+    }
+
+    #[test]
+    fn test_apply_filters() {
+        let bodies = [
+            Some(r#"{"price": 100, "year": 2019}"#),
+            Some(r#"{"price": "99", "year": 2023}"#),
+            Some("Plain text about oceans"),
+            Some(r#"{"price": 99.5}"#),
+            None,
+        ];
+        let rows = (0..bodies.len())
+            .map(|i| SearchResultRow {
+                doc_id: format!("doc{}", i),
+                ..row(0)
+            })
+            .collect();
+        let docs = bodies
+            .iter()
+            .enumerate()
+            .map(|(i, body)| {
+                let mut doc = Document::new_with_id("idx", &format!("doc{}", i));
+                doc.document_body = body.map(String::from);
+                Some(doc)
+            })
+            .collect();
+
+        let filters = Filters::parse(["price:<=100", "color:red"].into_iter()).unwrap();
+        let (kept, kept_docs, errors) = apply_filters(&filters, rows, docs);
+        assert!(kept.is_empty() && kept_docs.is_empty());
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].field, "color");
+
+        // Without a document, e.g. past the budget, a match can't pass
+        let rows: Vec<_> = (0..3)
+            .map(|i| SearchResultRow {
+                doc_id: format!("doc{}", i),
+                ..row(0)
+            })
+            .collect();
+        let mut docs: Vec<_> = bodies[..2]
+            .iter()
+            .map(|body| {
+                let mut doc = Document::new_with_id("idx", "doc");
+                doc.document_body = body.map(String::from);
+                Some(doc)
+            })
+            .collect();
+        docs.push(None);
+        let filters = Filters::parse(["price:<=100"].into_iter()).unwrap();
+        let (kept, kept_docs, errors) = apply_filters(&filters, rows, docs);
+        assert_eq!(kept.len(), 1);
+        assert_eq!(kept[0].doc_id, "doc0");
+        assert_eq!(kept_docs.len(), 1);
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn test_apply_contains() {
+        let bodies = [Some("The Pacific Ocean"), Some("the pacific ocean"), None];
+        let rows = || {
+            (0..bodies.len() + 1)
+                .map(|i| SearchResultRow {
+                    doc_id: format!("doc{}", i),
+                    ..row(0)
+                })
+                .collect()
+        };
+        let docs = || {
+            let mut docs: Vec<_> = bodies
+                .iter()
+                .map(|body| {
+                    let mut doc = Document::new_with_id("idx", "doc");
+                    doc.document_body = body.map(String::from);
+                    Some(doc)
+                })
+                .collect();
+            docs.push(None);
+            docs
+        };
+
+        // Without a body, or a document, a match can't contain anything
+        let contains = Contains::parse(["Ocean"].into_iter(), std::iter::empty()).unwrap();
+        let (kept, kept_docs) = apply_contains(&contains, rows(), docs());
+        assert_eq!(kept.len(), 1);
+        assert_eq!((kept[0].doc_id.as_str(), kept_docs.len()), ("doc0", 1));
+        assert_eq!(kept[0].matched_spans, vec![[12, 17]]);
+
+        let contains =
+            Contains::parse(std::iter::empty(), ["PACIFIC", "ocean"].into_iter()).unwrap();
+        let (kept, _) = apply_contains(&contains, rows(), docs());
+        let spans: Vec<_> = kept.iter().map(|row| row.matched_spans.clone()).collect();
+        assert_eq!(spans, vec![vec![[4, 11], [12, 17]]; 2]);
+        let json = serde_json::to_value(SearchFields::default().shape(&kept[0])).unwrap();
+        assert_eq!(
+            json["matched_spans"],
+            serde_json::json!([[4, 11], [12, 17]])
+        );
+    }
+
+    #[test]
+    fn test_apply_recency() {
+        const DAY: u64 = 86_400_000;
+        let now = 1000 * DAY;
+        let recency = RecencyBoost::parse(Some(10.0), None).unwrap().unwrap();
+        // An older document outranked by a newer one with a lower score, and a tie
+        // broken by age
+        let matches = [
+            ("old", 0.9, Some(now - 20 * DAY)),
+            ("new", 0.6, Some(now)),
+            ("legacy", 0.5, None),
+            ("tied", 0.6, Some(now - 10 * DAY)),
+        ];
+        let rows = matches
+            .iter()
+            .map(|(id, score, _)| SearchResultRow {
+                doc_id: id.to_string(),
+                score: *score,
+                ..row(0)
+            })
+            .collect();
+        let docs = matches
+            .iter()
+            .map(|(id, _, updated_at)| {
+                let mut doc = Document::new_with_id("idx", id);
+                doc.updated_at = *updated_at;
+                Some(doc)
+            })
+            .collect();
+
+        let (ranked, ranked_docs) = apply_recency(&recency, rows, docs, now);
+        let order: Vec<_> = ranked
+            .iter()
+            .map(|row| (row.doc_id.as_str(), row.score, row.recency_factor))
+            .collect();
+        assert_eq!(
+            order,
+            vec![
+                ("new", 0.6, Some(1.0)),
+                ("legacy", 0.5, None),
+                ("tied", 0.3, Some(0.5)),
+                ("old", 0.225, Some(0.25)),
+            ]
+        );
+        let doc_ids: Vec<_> = ranked_docs
+            .iter()
+            .flatten()
+            .map(Document::get_uuid)
+            .collect();
+        assert_eq!(doc_ids, vec!["new", "legacy", "tied", "old"]);
+    }
+
+    #[test]
+    fn test_apply_collapse() {
+        // Three chunks of one page outrank everything else, and the last match
+        // wasn't fetched
+        let matches = [
+            ("a1", Some(r#"{"url":"/a"}"#)),
+            ("a2", Some(r#"{"url":"/a"}"#)),
+            ("a3", Some(r#"{"url":"/a"}"#)),
+            ("b1", Some(r#"{"url":"/b"}"#)),
+            ("plain", Some("No metadata")),
+            ("gone", None),
+            ("a4", Some(r#"{"url":"/a"}"#)),
+        ];
+        let rows = || -> Vec<_> {
+            matches
+                .iter()
+                .chain([("unfetched", None)].iter())
+                .map(|(id, _)| SearchResultRow {
+                    doc_id: id.to_string(),
+                    ..row(0)
+                })
+                .collect()
+        };
+        let docs = || {
+            matches
+                .iter()
+                .map(|(id, body)| {
+                    let mut doc = Document::new_with_id("idx", id);
+                    doc.document_body = Some((*body)?.to_string());
+                    Some(doc)
+                })
+                .collect()
+        };
+
+        let collapse = Collapse::parse(Some("url"), Some(1)).unwrap().unwrap();
+        let (kept, kept_docs) = apply_collapse(&collapse, rows(), docs(), true);
+        let order: Vec<_> = kept
+            .iter()
+            .map(|row| (row.doc_id.as_str(), row.collapsed_count))
+            .collect();
+        assert_eq!(
+            order,
+            vec![
+                ("a1", Some(3)),
+                ("b1", Some(0)),
+                ("plain", Some(0)),
+                ("gone", Some(0)),
+                ("unfetched", Some(0)),
+            ]
+        );
+        let hits: Vec<_> = kept[0]
+            .collapsed_hits
+            .iter()
+            .map(|hit| (hit.doc_id.as_str(), hit.body.as_deref()))
+            .collect();
+        assert_eq!(hits, vec![("a2", Some(r#"{"url":"/a"}"#))]);
+        let doc_ids: Vec<_> = kept_docs.iter().flatten().map(Document::get_uuid).collect();
+        assert_eq!(doc_ids, vec!["a1", "b1", "plain"]);
+        assert_eq!(kept_docs.len(), 4);
+
+        // The limit applies to the collapsed results, so a second page survives it
+        let mut uncollapsed = rows();
+        uncollapsed.truncate(2);
+        let (mut collapsed, _) = apply_collapse(&collapse, rows(), docs(), false);
+        collapsed.truncate(2);
+        let ids = |rows: &[SearchResultRow]| -> Vec<String> {
+            rows.iter().map(|row| row.doc_id.clone()).collect()
+        };
+        assert_eq!(ids(&uncollapsed), vec!["a1", "a2"]);
+        assert_eq!(ids(&collapsed), vec!["a1", "b1"]);
+        assert!(collapsed[0].collapsed_hits[0].body.is_none());
+
+        let json = serde_json::to_value(SearchFields::default().shape(&collapsed[0])).unwrap();
+        assert_eq!(json["collapsed_count"], 3);
+        assert_eq!(json["collapsed_hits"][0]["doc_id"], "a2");
+        assert!(json["collapsed_hits"][0].get("collapsed_count").is_none());
+        let json = serde_json::to_value(SearchFields::default().shape(&row(0))).unwrap();
+        assert!(json.get("collapsed_count").is_none() && json.get("collapsed_hits").is_none());
+    }
+
+    #[test]
+    fn test_deleted_documents_are_flagged_missing() {
+        let store = MemoryStorage::default();
+        for id in ["doc0", "doc1", "doc2"] {
+            index_text(&store, "idx", id, "Ocean tides rise at dawn.");
+        }
+        // Deleted behind the index's back, leaving its postings in the shards
+        block_on(store.delete(&document_kv_key("idx", &"doc1".to_string()))).unwrap();
+
+        let mut rows: Vec<_> = (0..3)
+            .map(|i| SearchResultRow {
+                doc_id: format!("doc{}", i),
+                ..row(1)
+            })
+            .collect();
+        let keys: Vec<String> = rows
+            .iter()
+            .map(|row| document_kv_key("idx", &row.doc_id))
+            .collect();
+        let bulk_reader = BulkReader::new(DEFAULT_N_SHARDS, &store, None);
+        // Only the first two were fetched before the budget ran out
+        let docs = block_on(bulk_reader.get_documents_kv_keys(vec![&keys[0], &keys[1]]));
+
+        assert_eq!(flag_missing(&mut rows, &docs), 1);
+        assert_eq!(flag_missing(&mut rows, &docs), 0);
+        let missing: Vec<_> = rows.iter().map(|row| row.missing).collect();
+        assert_eq!(missing, vec![false, true, false]);
+
+        let fields = SearchFields::parse(Some("body")).unwrap();
+        let json = serde_json::to_string(&fields.shape(&rows[1])).unwrap();
+        assert_eq!(json, r#"{"doc_id":"doc1","body":null,"missing":true}"#);
+    }
+
+    #[test]
+    fn test_unknown_ids_are_dropped_on_hydration() {
+        let by_id = |doc_id: &str| SearchResultRow {
+            doc_id: doc_id.into(),
+            ..row(0)
+        };
+        let rows = vec![by_id("nope"), row(1), by_id("known"), by_id("unfetched")];
+        let docs = vec![None, None, Some(Document::new_with_id("idx", "known"))];
+
+        let (mut rows, docs) = drop_unknown_ids(rows, docs);
+        let doc_ids: Vec<_> = rows.iter().map(|row| row.doc_id.as_str()).collect();
+        assert_eq!(doc_ids, vec!["doc1", "known", "unfetched"]);
+        assert_eq!(docs.len(), 2);
+        // The posting that named a deleted document is still reported
+        assert_eq!(flag_missing(&mut rows, &docs), 1);
+        assert!(rows[0].missing);
+    }
+
+    #[test]
+    fn test_selected_body_serializes_null_when_not_fetched() {
+        let fields = SearchFields::parse(Some("body")).unwrap();
+        let json = serde_json::to_string(&fields.shape(&row(1))).unwrap();
+        assert_eq!(json, r#"{"doc_id":"doc1","body":null}"#);
+    }
 }
@@ -0,0 +1,212 @@
+use worker::{Context, Request, Response, Result, RouteContext};
+
+use crate::{
+    data::{
+        codec::CodecSet,
+        document::IndexingOptions,
+        index_manager::IndexManager,
+        snapshot::{list_snapshots, restore_batch, snapshot_batch, SnapshotError},
+    },
+    durable::journal::{read_exact_docs_count, send_docs_delta},
+    http::{check_index, json_error, ErrorCode, Rejection},
+    util::{
+        kv::{get_body_bucket, get_kv_data_store},
+        time::now_ms,
+    },
+};
+
+#[derive(serde::Deserialize, Default)]
+pub struct RestoreParams {
+    snapshot: Option<u64>,
+}
+
+/// The response a snapshot or restore that can't run is rejected with
+pub fn snapshot_rejection(err: SnapshotError) -> Rejection {
+    let (status, code) = match err {
+        SnapshotError::NotFrozen | SnapshotError::FreezeSettling(_) => {
+            (409, ErrorCode::IndexNotFrozen)
+        }
+        SnapshotError::NotFound(_) => (404, ErrorCode::NotFound),
+        SnapshotError::InProgress(_) => (409, ErrorCode::SnapshotInProgress),
+        SnapshotError::Resharding(_) => (409, ErrorCode::ReshardInProgress),
+        SnapshotError::Store(_) => (500, ErrorCode::InternalError),
+    };
+    // A freeze that other isolates haven't seen yet settles on its own
+    Rejection {
+        retryable: matches!(err, SnapshotError::FreezeSettling(_)),
+        ..Rejection::new(status, code, err.to_string())
+    }
+}
+
+/// Snapshots live in the R2 bucket, so without one there is nowhere to keep them
+fn missing_bucket() -> Result<Response> {
+    json_error(
+        503,
+        ErrorCode::Misconfigured,
+        "Snapshots need the R2_BUCKET binding",
+    )
+}
+
+/// `POST /:index/snapshot`: write the next batch of a snapshot of the frozen index
+/// to R2, starting one when none is in progress. Keep calling until `complete`.
+pub async fn handle_snapshot(req: Request, ctx: RouteContext<Context>) -> Result<Response> {
+    if !crate::presents_api_key(&req, &ctx.env) {
+        return json_error(
+            403,
+            ErrorCode::Unauthorized,
+            "Taking a snapshot requires the API key",
+        );
+    }
+    let Some(index) = ctx.param("index") else {
+        return json_error(400, ErrorCode::MissingParameter, "Missing index name");
+    };
+    let Some(bucket) = get_body_bucket(&ctx.env) else {
+        return missing_bucket();
+    };
+    let store = get_kv_data_store(&ctx);
+    if let Some(response) = check_index(&store, index, false).await? {
+        return Ok(response);
+    }
+    let index_doc = match IndexManager::new(&store).read_index(index).await {
+        Ok(index_doc) => index_doc,
+        Err(err) => {
+            return json_error(
+                500,
+                ErrorCode::InternalError,
+                format!("Failed to read the index: {}", err),
+            )
+        }
+    };
+    match snapshot_batch(&store, &bucket, &index_doc, now_ms()).await {
+        Ok(report) => Response::from_json(&report),
+        Err(err) => snapshot_rejection(err).into_response(),
+    }
+}
+
+/// `GET /:index/snapshots`: every complete snapshot of the index, oldest first
+pub async fn handle_list_snapshots(_req: Request, ctx: RouteContext<Context>) -> Result<Response> {
+    let Some(index) = ctx.param("index") else {
+        return json_error(400, ErrorCode::MissingParameter, "Missing index name");
+    };
+    let Some(bucket) = get_body_bucket(&ctx.env) else {
+        return missing_bucket();
+    };
+    let store = get_kv_data_store(&ctx);
+    if let Some(response) = check_index(&store, index, false).await? {
+        return Ok(response);
+    }
+    match list_snapshots(&bucket, index).await {
+        Ok(snapshots) => Response::from_json(&serde_json::json!({ "snapshots": snapshots })),
+        Err(err) => json_error(
+            500,
+            ErrorCode::InternalError,
+            format!("Failed to list snapshots: {}", err),
+        ),
+    }
+}
+
+/// `POST /:index/restore?snapshot=`: run the next batch of replacing the frozen
+/// index's contents with a snapshot. Keep calling until `complete`, then unfreeze
+/// the index.
+pub async fn handle_restore(req: Request, ctx: RouteContext<Context>) -> Result<Response> {
+    // Restoring deletes every document first, so AUTH_DISABLED alone doesn't allow it
+    if !crate::presents_api_key(&req, &ctx.env) {
+        return json_error(
+            403,
+            ErrorCode::Unauthorized,
+            "Restoring a snapshot requires the API key",
+        );
+    }
+    let Some(index) = ctx.param("index") else {
+        return json_error(400, ErrorCode::MissingParameter, "Missing index name");
+    };
+    let Ok(params) = req.query::<RestoreParams>() else {
+        return json_error(
+            400,
+            ErrorCode::InvalidRequest,
+            "snapshot must be a snapshot's timestamp",
+        );
+    };
+    let Some(snapshot) = params.snapshot else {
+        return json_error(400, ErrorCode::MissingParameter, "Missing snapshot");
+    };
+    let Some(bucket) = get_body_bucket(&ctx.env) else {
+        return missing_bucket();
+    };
+    let store = get_kv_data_store(&ctx);
+    if let Some(response) = check_index(&store, index, false).await? {
+        return Ok(response);
+    }
+    let index_doc = match IndexManager::new(&store).read_index(index).await {
+        Ok(index_doc) => index_doc,
+        Err(err) => {
+            return json_error(
+                500,
+                ErrorCode::InternalError,
+                format!("Failed to read the index: {}", err),
+            )
+        }
+    };
+    let mut options = IndexingOptions::from_env(&ctx.env);
+    options.n_shards = index_doc.shard_count(options.n_shards);
+    options.codecs = match CodecSet::for_index(&index_doc) {
+        Ok(codecs) => codecs,
+        Err(err) => {
+            return Rejection::from_store_error(err, ErrorCode::IndexNotFound).into_response()
+        }
+    };
+    let report = match restore_batch(&store, &bucket, index_doc, snapshot, &options, now_ms()).await
+    {
+        Ok(report) => report,
+        Err(err) => return snapshot_rejection(err).into_response(),
+    };
+    // The journal's count would otherwise overwrite the restored one once unfrozen
+    if report.complete {
+        if let Ok(counted) = read_exact_docs_count(&ctx.env, index).await {
+            let delta = report.documents as i64 - counted as i64;
+            if delta != 0 {
+                send_docs_delta(&ctx.env, index, delta).await;
+            }
+        }
+    }
+    Response::from_json(&report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::DataStoreError;
+
+    #[test]
+    fn test_snapshot_rejections() {
+        let cases = [
+            (SnapshotError::NotFrozen, 409, ErrorCode::IndexNotFrozen),
+            (SnapshotError::NotFound(1), 404, ErrorCode::NotFound),
+            (
+                SnapshotError::InProgress("A restore of snapshot 1".into()),
+                409,
+                ErrorCode::SnapshotInProgress,
+            ),
+            (
+                SnapshotError::Resharding(16),
+                409,
+                ErrorCode::ReshardInProgress,
+            ),
+            (
+                SnapshotError::Store(DataStoreError::NotFound("snapshots/idx/1.ndjson".into())),
+                500,
+                ErrorCode::InternalError,
+            ),
+        ];
+        for (err, status, code) in cases {
+            let rejection = snapshot_rejection(err);
+            assert_eq!((rejection.status, rejection.code), (status, code));
+            assert!(!rejection.retryable);
+        }
+        let settling = snapshot_rejection(SnapshotError::FreezeSettling(1_000));
+        assert_eq!(
+            (settling.status, settling.code, settling.retryable),
+            (409, ErrorCode::IndexNotFrozen, true)
+        );
+    }
+}
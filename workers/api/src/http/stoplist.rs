@@ -0,0 +1,106 @@
+use worker::{Context, Request, Response, Result, RouteContext};
+
+use crate::{
+    data::{stoplist::StopList, KvPersistent},
+    http::{check_index, json_error, ErrorCode, Rejection},
+    util::kv::get_kv_data_store,
+};
+
+/// Parse a `PUT /:index/stoplist` body: a JSON array of keywords, normalized the
+/// same way as indexed keywords
+pub fn parse_stoplist(index: &str, body: &str) -> std::result::Result<StopList, Rejection> {
+    let keywords = serde_json::from_str::<Vec<String>>(body).map_err(|_| {
+        Rejection::new(
+            400,
+            ErrorCode::InvalidRequest,
+            "Request body must be a JSON array of keywords",
+        )
+    })?;
+
+    let stoplist = StopList::new(index, &keywords);
+    if stoplist.len() > StopList::MAX_ENTRIES {
+        return Err(Rejection::new(
+            400,
+            ErrorCode::InvalidRequest,
+            format!(
+                "Too many stop-listed keywords. Current limit: {}",
+                StopList::MAX_ENTRIES
+            ),
+        ));
+    }
+    Ok(stoplist)
+}
+
+pub async fn handle_get_stoplist(_req: Request, ctx: RouteContext<Context>) -> Result<Response> {
+    if let Some(index) = ctx.param("index") {
+        let store = get_kv_data_store(&ctx);
+        if let Some(response) = check_index(&store, index, false).await? {
+            return Ok(response);
+        }
+
+        return match StopList::load(&store, index).await {
+            Ok(stoplist) => Response::from_json(&stoplist),
+            Err(err) => json_error(
+                500,
+                ErrorCode::InternalError,
+                format!("Failed to load the stop-list: {}", err),
+            ),
+        };
+    }
+    json_error(400, ErrorCode::MissingParameter, "Missing index name")
+}
+
+/// Replace the stop-list of an index. Keyword shards that were already written are
+/// left alone until their documents are reindexed.
+pub async fn handle_set_stoplist(mut req: Request, ctx: RouteContext<Context>) -> Result<Response> {
+    if let Some(index) = ctx.param("index") {
+        let store = get_kv_data_store(&ctx);
+        if let Some(response) = check_index(&store, index, false).await? {
+            return Ok(response);
+        }
+
+        let mut stoplist = match parse_stoplist(index, &req.text().await?) {
+            Ok(stoplist) => stoplist,
+            Err(rejection) => return rejection.into_response(),
+        };
+        if let Err(err) = stoplist.write(&store).await {
+            return json_error(
+                500,
+                ErrorCode::InternalError,
+                format!("Failed to save the stop-list: {}", err),
+            );
+        }
+        return Response::from_json(&stoplist);
+    }
+    json_error(400, ErrorCode::MissingParameter, "Missing index name")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_stoplist_normalizes() {
+        let stoplist = parse_stoplist("idx", r#"["Acme Corp", "acme  corp", "Footer"]"#).unwrap();
+        assert_eq!(stoplist.keywords(), vec!["acme corp", "footer"]);
+        assert_eq!(
+            serde_json::to_string(&stoplist).unwrap(),
+            r#"{"keywords":["acme corp","footer"]}"#
+        );
+    }
+
+    #[test]
+    fn test_parse_stoplist_rejections() {
+        let not_array = parse_stoplist("idx", r#"{"keywords":[]}"#).unwrap_err();
+        assert_eq!(
+            (not_array.status, not_array.code),
+            (400, ErrorCode::InvalidRequest)
+        );
+
+        let too_many: Vec<String> = (0..=StopList::MAX_ENTRIES)
+            .map(|i| format!("kw{}", i))
+            .collect();
+        let too_many = parse_stoplist("idx", &serde_json::to_string(&too_many).unwrap());
+        assert!(too_many.unwrap_err().error.contains("limit"));
+    }
+}
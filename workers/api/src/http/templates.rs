@@ -0,0 +1,148 @@
+use worker::{Context, Request, Response, Result, RouteContext};
+
+use crate::{
+    data::{
+        index::IndexDocument,
+        storage::Storage,
+        template::{list_templates, template_kv_key, IndexTemplate},
+        DataStoreError, KvPersistent,
+    },
+    http::{json_error, ErrorCode, Rejection},
+    util::kv::get_kv_data_store,
+};
+
+#[derive(serde::Serialize)]
+struct DeletedResponse {
+    deleted: bool,
+}
+
+fn template_store_error(err: DataStoreError) -> Rejection {
+    Rejection::from_store_error(err, ErrorCode::NotFound)
+}
+
+/// Parse a `PUT /_templates/:name` body. Template names follow the rules of index
+/// names.
+pub fn parse_template(name: &str, body: &str) -> std::result::Result<IndexTemplate, Rejection> {
+    if !IndexDocument::is_valid_name(name) {
+        return Err(Rejection::new(
+            400,
+            ErrorCode::InvalidRequest,
+            "Invalid template name. Must be 1-48 characters matching [a-z0-9-_]+",
+        ));
+    }
+    IndexTemplate::parse(name, body)
+        .map_err(|error| Rejection::new(400, ErrorCode::InvalidRequest, error))
+}
+
+/// Store `template`, replacing the one of the same name, unless it would be one
+/// template too many
+async fn save_template<S: Storage>(
+    store: &S,
+    mut template: IndexTemplate,
+) -> std::result::Result<IndexTemplate, Rejection> {
+    let stored = list_templates(store).await.map_err(template_store_error)?;
+    let replaces = stored.iter().any(|other| other.name == template.name);
+    if !replaces && stored.len() >= IndexTemplate::MAX_TEMPLATES {
+        return Err(Rejection::new(
+            400,
+            ErrorCode::InvalidRequest,
+            format!(
+                "Too many index templates. Current limit: {}",
+                IndexTemplate::MAX_TEMPLATES
+            ),
+        ));
+    }
+    template.write(store).await.map_err(template_store_error)?;
+    Ok(template)
+}
+
+/// `GET /_templates`: every template, ordered by name
+pub async fn handle_list_templates(_req: Request, ctx: RouteContext<Context>) -> Result<Response> {
+    let store = get_kv_data_store(&ctx);
+    match list_templates(&store).await {
+        Ok(templates) => Response::from_json(&templates),
+        Err(err) => template_store_error(err).into_response(),
+    }
+}
+
+pub async fn handle_get_template(_req: Request, ctx: RouteContext<Context>) -> Result<Response> {
+    let Some(name) = ctx.param("name") else {
+        return json_error(400, ErrorCode::MissingParameter, "Missing template name");
+    };
+    let store = get_kv_data_store(&ctx);
+    match IndexTemplate::load(&store, name).await {
+        Ok(template) => Response::from_json(&template),
+        Err(err) => template_store_error(err).into_response(),
+    }
+}
+
+/// `PUT /_templates/:name`: create or replace a template. Indexes already created
+/// from it keep the settings and stop-list it had then.
+pub async fn handle_put_template(mut req: Request, ctx: RouteContext<Context>) -> Result<Response> {
+    let Some(name) = ctx.param("name") else {
+        return json_error(400, ErrorCode::MissingParameter, "Missing template name");
+    };
+    let store = get_kv_data_store(&ctx);
+    let saved = match parse_template(name, &req.text().await?) {
+        Ok(template) => save_template(&store, template).await,
+        Err(rejection) => Err(rejection),
+    };
+    match saved {
+        Ok(template) => Response::from_json(&template),
+        Err(rejection) => rejection.into_response(),
+    }
+}
+
+pub async fn handle_delete_template(_req: Request, ctx: RouteContext<Context>) -> Result<Response> {
+    let Some(name) = ctx.param("name") else {
+        return json_error(400, ErrorCode::MissingParameter, "Missing template name");
+    };
+    let store = get_kv_data_store(&ctx);
+    if let Err(err) = IndexTemplate::load(&store, name).await {
+        return template_store_error(err).into_response();
+    }
+    match store.delete(&template_kv_key(name)).await {
+        Ok(()) => Response::from_json(&DeletedResponse { deleted: true }),
+        Err(err) => template_store_error(err).into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+
+    use super::*;
+    use crate::data::storage::memory::MemoryStorage;
+
+    #[test]
+    fn test_parse_template_rejections() {
+        let parsed = parse_template("tenants", r#"{"pattern":"tenant-*"}"#).unwrap();
+        assert_eq!(parsed.pattern, "tenant-*");
+
+        let bad_name = parse_template("Tenants", r#"{"pattern":"tenant-*"}"#).unwrap_err();
+        assert_eq!(
+            (bad_name.status, bad_name.code),
+            (400, ErrorCode::InvalidRequest)
+        );
+        let bad_body = parse_template("tenants", r#"{"pattern":"tenant-*","limit":5}"#);
+        assert!(bad_body.unwrap_err().error.contains("unknown field"));
+    }
+
+    #[test]
+    fn test_save_template_limit() {
+        let store = MemoryStorage::default();
+        for i in 0..IndexTemplate::MAX_TEMPLATES {
+            let template = parse_template(&format!("t{}", i), r#"{"pattern":"a-*"}"#).unwrap();
+            block_on(save_template(&store, template)).unwrap();
+        }
+        let extra = parse_template("extra", r#"{"pattern":"a-*"}"#).unwrap();
+        let rejected = block_on(save_template(&store, extra)).unwrap_err();
+        assert!(rejected.error.contains("limit"));
+
+        // Replacing a stored template doesn't count against the limit
+        let replaced = parse_template("t0", r#"{"pattern":"b-*"}"#).unwrap();
+        block_on(save_template(&store, replaced)).unwrap();
+        let stored = block_on(IndexTemplate::load(&store, "t0")).unwrap();
+        assert_eq!(stored.pattern, "b-*");
+    }
+}
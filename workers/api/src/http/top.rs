@@ -0,0 +1,174 @@
+use worker::{Context, Request, Response, Result, RouteContext};
+
+use crate::{
+    data::{
+        index_manager::IndexManager,
+        keyword::KeywordManager,
+        keyword_shard::get_n_shards,
+        storage::PageCursor,
+        top::{TopBy, TopOptions, DEFAULT_TOP_LIMIT, MAX_TOP_LIMIT},
+    },
+    http::{check_index, index_codecs, json_error, require_api_key, ErrorCode, Rejection},
+    util::kv::get_kv_data_store,
+};
+
+#[derive(serde::Deserialize, Default)]
+pub struct TopParams {
+    by: Option<String>,
+    limit: Option<usize>,
+    sample: Option<f64>,
+    cursor: Option<String>,
+}
+
+/// What to rank by, how many entries and which share of documents, and where to
+/// resume
+pub fn parse_top_params(
+    params: &TopParams,
+) -> std::result::Result<(TopOptions, Option<PageCursor>), Rejection> {
+    let invalid = |message: String| Rejection::new(400, ErrorCode::InvalidRequest, message);
+    let Some(by) = params.by.as_deref() else {
+        return Err(Rejection::new(
+            400,
+            ErrorCode::MissingParameter,
+            "Missing by",
+        ));
+    };
+    let by = TopBy::from_name(by).ok_or_else(|| {
+        invalid(format!(
+            "by must be one of {}, not '{}'",
+            TopBy::NAMES.join(", "),
+            by
+        ))
+    })?;
+    let limit = params.limit.unwrap_or(DEFAULT_TOP_LIMIT);
+    if !(1..=MAX_TOP_LIMIT).contains(&limit) {
+        return Err(invalid(format!(
+            "limit must be between 1 and {}",
+            MAX_TOP_LIMIT
+        )));
+    }
+    let sample = match params.sample {
+        Some(_) if by != TopBy::DocSize => {
+            return Err(invalid("sample only applies to by=doc_size".into()))
+        }
+        Some(sample) if !(sample > 0.0 && sample <= 1.0) => {
+            return Err(invalid("sample must be above 0 and at most 1".into()))
+        }
+        sample => sample.unwrap_or(1.0),
+    };
+    let cursor = match params.cursor.as_deref() {
+        None | Some("") => None,
+        Some(cursor) => Some(cursor.parse().map_err(invalid)?),
+    };
+    Ok((TopOptions { by, limit, sample }, cursor))
+}
+
+/// `GET /:index/top?by=doc_size|keyword_postings`: rank the next batch of an index's
+/// documents by stored size, or its keywords by postings. Keep passing back `cursor`
+/// until it comes back `null` to cover the whole index.
+pub async fn handle_top(req: Request, ctx: RouteContext<Context>) -> Result<Response> {
+    if let Some(response) = require_api_key(&req, &ctx.env, "Ranking an index's keys") {
+        return response;
+    }
+    let Some(index) = ctx.param("index") else {
+        return json_error(400, ErrorCode::MissingParameter, "Missing index name");
+    };
+    let Ok(params) = req.query::<TopParams>() else {
+        return json_error(
+            400,
+            ErrorCode::InvalidRequest,
+            "limit must be a number, and sample a number in (0, 1]",
+        );
+    };
+    let (options, cursor) = match parse_top_params(&params) {
+        Ok(parsed) => parsed,
+        Err(rejection) => return rejection.into_response(),
+    };
+
+    let store = get_kv_data_store(&ctx);
+    if let Some(response) = check_index(&store, index, false).await? {
+        return Ok(response);
+    }
+    let n_shards = IndexManager::new(&store)
+        .shard_count(index, get_n_shards(&ctx.env))
+        .await;
+    let codecs = match index_codecs(&store, index).await {
+        Ok(codecs) => codecs,
+        Err(rejection) => return rejection.into_response(),
+    };
+    let manager = KeywordManager::new(index.into(), &ctx.env, &store)
+        .with_n_shards(n_shards)
+        .with_codecs(codecs);
+    match manager.top(cursor, &options).await {
+        Ok(report) => Response::from_json(&report),
+        Err(err) => json_error(
+            500,
+            ErrorCode::InternalError,
+            format!("Failed to rank the index: {}", err),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(by: &str) -> TopParams {
+        TopParams {
+            by: Some(by.into()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_parse_top_params() {
+        let (options, cursor) = parse_top_params(&params("doc_size")).unwrap();
+        assert_eq!(
+            (options.by, options.limit, options.sample),
+            (TopBy::DocSize, DEFAULT_TOP_LIMIT, 1.0)
+        );
+        assert!(cursor.is_none());
+
+        let sampled = TopParams {
+            limit: Some(5),
+            sample: Some(0.1),
+            cursor: Some("4:idx:document:b".into()),
+            ..params("doc_size")
+        };
+        let (options, cursor) = parse_top_params(&sampled).unwrap();
+        assert_eq!((options.limit, options.sample), (5, 0.1));
+        assert_eq!(cursor.unwrap().offset, 4);
+    }
+
+    #[test]
+    fn test_parse_top_params_rejections() {
+        let missing = parse_top_params(&TopParams::default()).unwrap_err();
+        assert_eq!(missing.code, ErrorCode::MissingParameter);
+        let rejected = [
+            params("bytes"),
+            TopParams {
+                limit: Some(MAX_TOP_LIMIT + 1),
+                ..params("doc_size")
+            },
+            TopParams {
+                sample: Some(0.0),
+                ..params("doc_size")
+            },
+            TopParams {
+                sample: Some(0.5),
+                ..params("keyword_postings")
+            },
+            TopParams {
+                cursor: Some("bogus".into()),
+                ..params("keyword_postings")
+            },
+        ];
+        for params in rejected {
+            let rejection = parse_top_params(&params).unwrap_err();
+            assert_eq!(
+                (rejection.status, rejection.code),
+                (400, ErrorCode::InvalidRequest)
+            );
+        }
+    }
+}
@@ -0,0 +1,140 @@
+use worker::{Context, Request, Response, Result, RouteContext};
+
+use crate::{
+    data::{
+        index_manager::IndexManager,
+        storage::PageCursor,
+        upgrade::{upgrade_batch, UpgradeError},
+    },
+    http::{check_index, json_error, ErrorCode, Rejection},
+    util::kv::get_kv_data_store,
+};
+
+#[derive(serde::Deserialize, Default)]
+pub struct UpgradeParams {
+    cursor: Option<String>,
+}
+
+/// Where to resume the upgrade
+pub fn parse_upgrade_cursor(
+    params: &UpgradeParams,
+) -> std::result::Result<Option<PageCursor>, Rejection> {
+    match params.cursor.as_deref() {
+        None | Some("") => Ok(None),
+        Some(cursor) => cursor
+            .parse()
+            .map(Some)
+            .map_err(|err| Rejection::new(400, ErrorCode::InvalidRequest, err)),
+    }
+}
+
+/// The response an upgrade that can't run is rejected with
+pub fn upgrade_rejection(err: UpgradeError) -> Rejection {
+    let (status, code) = match err {
+        UpgradeError::AlreadyUpgraded(_) => (400, ErrorCode::InvalidRequest),
+        UpgradeError::NotFrozen => (409, ErrorCode::IndexNotFrozen),
+        UpgradeError::Resharding(_) => (409, ErrorCode::ReshardInProgress),
+        UpgradeError::Store(err) => {
+            return Rejection::from_store_error(err, ErrorCode::IndexNotFound)
+        }
+    };
+    Rejection::new(status, code, err.to_string())
+}
+
+/// `POST /:index/upgrade`: run the next batch of rewriting a frozen index's keyword
+/// shards for the latest index version. Keep passing back `cursor` until it comes
+/// back `null`, then unfreeze the index.
+pub async fn handle_upgrade(req: Request, ctx: RouteContext<Context>) -> Result<Response> {
+    // Upgrading rewrites every shard, so AUTH_DISABLED alone doesn't allow it
+    if !crate::presents_api_key(&req, &ctx.env) {
+        return json_error(
+            403,
+            ErrorCode::Unauthorized,
+            "Upgrading an index requires the API key",
+        );
+    }
+    let Some(index) = ctx.param("index") else {
+        return json_error(400, ErrorCode::MissingParameter, "Missing index name");
+    };
+    let Ok(params) = req.query::<UpgradeParams>() else {
+        return json_error(400, ErrorCode::InvalidRequest, "Invalid query parameters");
+    };
+    let cursor = match parse_upgrade_cursor(&params) {
+        Ok(cursor) => cursor,
+        Err(rejection) => return rejection.into_response(),
+    };
+
+    let store = get_kv_data_store(&ctx);
+    if let Some(response) = check_index(&store, index, false).await? {
+        return Ok(response);
+    }
+    let index_doc = match IndexManager::new(&store).read_index(index).await {
+        Ok(index_doc) => index_doc,
+        Err(err) => {
+            return json_error(
+                500,
+                ErrorCode::InternalError,
+                format!("Failed to read the index: {}", err),
+            )
+        }
+    };
+    match upgrade_batch(&store, index_doc, cursor).await {
+        Ok(report) => Response::from_json(&report),
+        Err(err) => upgrade_rejection(err).into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::DataStoreError;
+
+    #[test]
+    fn test_parse_upgrade_cursor() {
+        assert_eq!(
+            parse_upgrade_cursor(&UpgradeParams::default()).unwrap(),
+            None
+        );
+        let params = UpgradeParams {
+            cursor: Some("4:idx:kw:ocean:3".into()),
+        };
+        let cursor = parse_upgrade_cursor(&params).unwrap().unwrap();
+        assert_eq!(cursor.offset, 4);
+        assert_eq!(cursor.page.as_deref(), Some("idx:kw:ocean:3"));
+
+        let params = UpgradeParams {
+            cursor: Some("bogus".into()),
+        };
+        let rejection = parse_upgrade_cursor(&params).unwrap_err();
+        assert_eq!(
+            (rejection.status, rejection.code),
+            (400, ErrorCode::InvalidRequest)
+        );
+    }
+
+    #[test]
+    fn test_upgrade_rejections() {
+        let cases = [
+            (UpgradeError::NotFrozen, 409, ErrorCode::IndexNotFrozen),
+            (
+                UpgradeError::Resharding(16),
+                409,
+                ErrorCode::ReshardInProgress,
+            ),
+            (
+                UpgradeError::AlreadyUpgraded(2),
+                400,
+                ErrorCode::InvalidRequest,
+            ),
+            (
+                UpgradeError::Store(DataStoreError::UnsupportedVersion(3)),
+                426,
+                ErrorCode::UnsupportedIndexVersion,
+            ),
+        ];
+        for (err, status, code) in cases {
+            let rejection = upgrade_rejection(err);
+            assert_eq!((rejection.status, rejection.code), (status, code));
+        }
+    }
+}
@@ -0,0 +1,101 @@
+use serde::Serialize;
+use worker::{Context, Request, Response, Result, RouteContext};
+
+use crate::{
+    data::{
+        index_manager::IndexManager,
+        usage::{read_usage, UsageDay, DEFAULT_USAGE_DAYS, MAX_USAGE_DAYS},
+    },
+    http::{check_index, json_error, ErrorCode, Rejection},
+    lexer::extractor::Extractor,
+    util::{kv::get_kv_data_store, time::now_ms},
+};
+
+#[derive(serde::Deserialize, Default)]
+pub struct UsageParams {
+    days: Option<u32>,
+}
+
+#[derive(Serialize)]
+pub struct UsageSeries {
+    pub index: String,
+    /// What picks the keywords of documents written to the index
+    pub extractor: Extractor,
+    /// What the extractor costs each document written
+    pub extractor_note: &'static str,
+    /// The `min_keyword_score` documents written now are indexed with
+    pub min_keyword_score: f64,
+    /// One entry per day, oldest first and ending today
+    pub days: Vec<UsageDay>,
+}
+
+/// How many days of usage to return
+pub fn parse_usage_days(params: &UsageParams) -> std::result::Result<u32, Rejection> {
+    match params.days.unwrap_or(DEFAULT_USAGE_DAYS) {
+        days @ 1..=MAX_USAGE_DAYS => Ok(days),
+        _ => Err(Rejection::new(
+            400,
+            ErrorCode::InvalidRequest,
+            format!("days must be between 1 and {}", MAX_USAGE_DAYS),
+        )),
+    }
+}
+
+/// `GET /:index/usage`: the index's daily search and document counts, and which
+/// extractor and keyword floor its documents are written with
+pub async fn handle_usage(req: Request, ctx: RouteContext<Context>) -> Result<Response> {
+    let Some(index) = ctx.param("index") else {
+        return json_error(400, ErrorCode::MissingParameter, "Missing index name");
+    };
+    let Ok(params) = req.query::<UsageParams>() else {
+        return json_error(400, ErrorCode::InvalidRequest, "Invalid query parameters");
+    };
+    let days = match parse_usage_days(&params) {
+        Ok(days) => days,
+        Err(rejection) => return rejection.into_response(),
+    };
+
+    let store = get_kv_data_store(&ctx);
+    if let Some(response) = check_index(&store, index, false).await? {
+        return Ok(response);
+    }
+    let series = async {
+        let index_doc = IndexManager::new(&store).read_index(index).await?;
+        let extractor = index_doc.settings.extractor.unwrap_or_default();
+        Ok(UsageSeries {
+            index: index.to_string(),
+            extractor,
+            extractor_note: extractor.note(),
+            min_keyword_score: index_doc.settings.min_keyword_score.unwrap_or(0.0),
+            days: read_usage(&store, index, now_ms(), days).await?,
+        })
+    };
+    match series.await {
+        Ok(series) => Response::from_json(&series),
+        Err(err) => Rejection::from_store_error(err, ErrorCode::IndexNotFound).into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_usage_days() {
+        assert_eq!(
+            parse_usage_days(&UsageParams::default()).unwrap(),
+            DEFAULT_USAGE_DAYS
+        );
+        assert_eq!(
+            parse_usage_days(&UsageParams { days: Some(30) }).unwrap(),
+            30
+        );
+        for days in [0, MAX_USAGE_DAYS + 1] {
+            let rejection = parse_usage_days(&UsageParams { days: Some(days) }).unwrap_err();
+            assert_eq!(
+                (rejection.status, rejection.code),
+                (400, ErrorCode::InvalidRequest)
+            );
+        }
+    }
+}
@@ -0,0 +1,204 @@
+use worker::{Context, Request, Response, Result, RouteContext};
+
+use crate::{
+    data::{
+        index_manager::IndexManager,
+        keyword::KeywordManager,
+        merge_cache::WarmReport,
+        storage::PageCursor,
+        top::{TopBy, TopOptions, MAX_TOP_LIMIT},
+    },
+    http::{
+        check_index, index_codecs, json_error, keywords::invalid_keyword, require_api_key,
+        ErrorCode, Rejection,
+    },
+    util::kv::get_kv_data_store,
+};
+
+/// The most keywords one warm-up merges, each a listing and a write besides its reads
+pub const MAX_WARM_KEYWORDS: usize = 100;
+
+#[derive(serde::Deserialize, Default)]
+pub struct WarmParams {
+    top: Option<usize>,
+    cursor: Option<String>,
+}
+
+#[derive(serde::Serialize)]
+struct WarmResponse {
+    #[serde(flatten)]
+    report: WarmReport,
+    /// With `top`, where the next warm-up continues the walk, `None` once it's done
+    /// and without `top`
+    cursor: Option<String>,
+}
+
+/// Parse the body of `POST /:index/warm`: a JSON array of keywords
+pub fn parse_warm_keywords(body: &str) -> std::result::Result<Vec<String>, Rejection> {
+    let invalid = |message: String| Rejection::new(400, ErrorCode::InvalidRequest, message);
+    let keywords: Vec<String> = serde_json::from_str(body).map_err(|_| {
+        invalid("Request body must be a JSON array of keywords, or pass top".into())
+    })?;
+    if keywords.is_empty() || keywords.len() > MAX_WARM_KEYWORDS {
+        return Err(invalid(format!(
+            "Warm between 1 and {} keywords at a time",
+            MAX_WARM_KEYWORDS
+        )));
+    }
+    match invalid_keyword(keywords.iter()) {
+        Some(error) => Err(invalid(error)),
+        None => Ok(keywords),
+    }
+}
+
+/// With `top`, how many keywords to take from the batch and where the walk resumes
+pub fn parse_warm_top(
+    params: &WarmParams,
+) -> std::result::Result<Option<(usize, Option<PageCursor>)>, Rejection> {
+    let invalid = |message: String| Rejection::new(400, ErrorCode::InvalidRequest, message);
+    let Some(top) = params.top else {
+        return match params.cursor {
+            Some(_) => Err(invalid("cursor only applies with top".into())),
+            None => Ok(None),
+        };
+    };
+    if !(1..=MAX_TOP_LIMIT).contains(&top) {
+        return Err(invalid(format!(
+            "top must be between 1 and {}",
+            MAX_TOP_LIMIT
+        )));
+    }
+    let cursor = match params.cursor.as_deref() {
+        None | Some("") => None,
+        Some(cursor) => Some(cursor.parse().map_err(invalid)?),
+    };
+    Ok(Some((top, cursor)))
+}
+
+/// `POST /:index/warm`: merge keywords ahead of the searches for them, keeping the
+/// postings of those stored in every shard in the merge cache, see
+/// [`crate::data::merge_cache`]. The keywords are the body's JSON array, or with
+/// `top=N` the N with the most postings in the next batch of the
+/// `/:index/top?by=keyword_postings` walk, returning its `cursor` to continue with.
+pub async fn handle_warm(mut req: Request, ctx: RouteContext<Context>) -> Result<Response> {
+    let Some(index) = ctx.param("index") else {
+        return json_error(400, ErrorCode::MissingParameter, "Missing index name");
+    };
+    let Ok(params) = req.query::<WarmParams>() else {
+        return json_error(400, ErrorCode::InvalidRequest, "top must be a number");
+    };
+    let top = match parse_warm_top(&params) {
+        Ok(top) => top,
+        Err(rejection) => return rejection.into_response(),
+    };
+    if top.is_some() {
+        let what = "Warming an index's top keywords";
+        if let Some(response) = require_api_key(&req, &ctx.env, what) {
+            return response;
+        }
+    }
+
+    let store = get_kv_data_store(&ctx);
+    if let Some(response) = check_index(&store, index, false).await? {
+        return Ok(response);
+    }
+    let index_doc = match IndexManager::new(&store).read_index(index).await {
+        Ok(index_doc) => index_doc,
+        Err(err) => {
+            return Rejection::from_store_error(err, ErrorCode::IndexNotFound).into_response()
+        }
+    };
+    if index_doc.reshard.is_some() {
+        return json_error(
+            409,
+            ErrorCode::ReshardInProgress,
+            "The index is resharding, so its shards can't be warmed",
+        );
+    }
+    if index_doc.n_shards.is_some() {
+        return json_error(
+            400,
+            ErrorCode::InvalidRequest,
+            "The index's shard count is recorded, so there's nothing to warm",
+        );
+    }
+    let codecs = match index_codecs(&store, index).await {
+        Ok(codecs) => codecs,
+        Err(rejection) => return rejection.into_response(),
+    };
+    let manager = KeywordManager::new(index.into(), &ctx.env, &store).with_codecs(codecs);
+
+    let (keywords, cursor) = match top {
+        Some((limit, cursor)) => {
+            let options = TopOptions {
+                by: TopBy::KeywordPostings,
+                limit,
+                sample: 1.0,
+            };
+            match manager.top(cursor, &options).await {
+                Ok(report) => {
+                    let keywords = report.entries.into_iter().map(|entry| entry.name).collect();
+                    (keywords, report.cursor)
+                }
+                Err(err) => {
+                    return json_error(
+                        500,
+                        ErrorCode::InternalError,
+                        format!("Failed to rank the index's keywords: {}", err),
+                    )
+                }
+            }
+        }
+        None => match parse_warm_keywords(&req.text().await?) {
+            Ok(keywords) => (keywords, None),
+            Err(rejection) => return rejection.into_response(),
+        },
+    };
+
+    match manager.warm(keywords).await {
+        Ok(report) => Response::from_json(&WarmResponse { report, cursor }),
+        Err(err) => json_error(
+            500,
+            ErrorCode::InternalError,
+            format!("Failed to warm keywords: {}", err),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_warm_request() {
+        assert_eq!(
+            parse_warm_keywords(r#"["ocean", "tide"]"#).unwrap(),
+            vec!["ocean", "tide"]
+        );
+        let too_many = serde_json::to_string(&vec!["a"; MAX_WARM_KEYWORDS + 1]).unwrap();
+        for rejected in ["", "{}", "[]", r#"[""]"#, &too_many] {
+            let rejection = parse_warm_keywords(rejected).unwrap_err();
+            assert_eq!(rejection.code, ErrorCode::InvalidRequest, "{}", rejected);
+        }
+
+        assert!(parse_warm_top(&WarmParams::default()).unwrap().is_none());
+        let params = WarmParams {
+            top: Some(5),
+            cursor: Some("4:idx:kw:b:0".into()),
+        };
+        let (top, cursor) = parse_warm_top(&params).unwrap().unwrap();
+        assert_eq!((top, cursor.unwrap().offset), (5, 4));
+        for rejected in [
+            WarmParams {
+                top: Some(0),
+                cursor: None,
+            },
+            WarmParams {
+                top: None,
+                cursor: Some("4:idx:kw:b:0".into()),
+            },
+        ] {
+            assert!(parse_warm_top(&rejected).is_err());
+        }
+    }
+}
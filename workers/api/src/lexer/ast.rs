@@ -0,0 +1,133 @@
+//! The JSON form of a query's AST, which a search can send as its body in place of a
+//! query string. Each node is an object with one field naming its kind:
+//!
+//! ```json
+//! {"and": [{"word": "ocean"}, {"not": {"or": [{"word": "storm"}, {"doc_id": "a1"}]}}]}
+//! ```
+//!
+//! `and` and `or` take two or more operands, folded from the left, so a chain of
+//! either reads as the same tree the query string would parse into. Words are used
+//! exactly as given, with no quoting or `id:` prefix to escape, though like query
+//! string words they can't be blank or over [`crate::lexer::MAX_KEYWORD_CHARS`].
+
+use serde::{Deserialize, Serialize};
+
+use crate::lexer::{Expr, QueryError};
+
+/// One node of the wire format, which [`Expr`] converts to and from
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum AstNode {
+    Word(String),
+    DocId(String),
+    Not(Box<AstNode>),
+    And(Vec<AstNode>),
+    Or(Vec<AstNode>),
+}
+
+impl From<Expr> for AstNode {
+    fn from(expr: Expr) -> AstNode {
+        match expr {
+            Expr::Word(word) => AstNode::Word(word),
+            Expr::DocId(id) => AstNode::DocId(id),
+            Expr::Not(inner) => AstNode::Not(Box::new((*inner).into())),
+            Expr::And(left, right) => AstNode::And(vec![(*left).into(), (*right).into()]),
+            Expr::Or(left, right) => AstNode::Or(vec![(*left).into(), (*right).into()]),
+        }
+    }
+}
+
+impl TryFrom<AstNode> for Expr {
+    type Error = String;
+
+    fn try_from(node: AstNode) -> Result<Expr, String> {
+        match node {
+            AstNode::Word(word) => Ok(Expr::Word(word)),
+            AstNode::DocId(id) => Ok(Expr::DocId(id)),
+            AstNode::Not(inner) => Ok(Expr::Not(Box::new((*inner).try_into()?))),
+            AstNode::And(operands) => fold_operands("and", operands, Expr::And),
+            AstNode::Or(operands) => fold_operands("or", operands, Expr::Or),
+        }
+    }
+}
+
+fn fold_operands(
+    kind: &str,
+    operands: Vec<AstNode>,
+    join: fn(Box<Expr>, Box<Expr>) -> Expr,
+) -> Result<Expr, String> {
+    if operands.len() < 2 {
+        return Err(format!("{} needs at least 2 operands", kind));
+    }
+    let mut operands = operands.into_iter().map(Expr::try_from);
+    let first = operands.next().unwrap()?;
+    operands.try_fold(first, |left, right| {
+        Ok(join(Box::new(left), Box::new(right?)))
+    })
+}
+
+impl Serialize for Expr {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        AstNode::from(self.clone()).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Expr {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Expr, D::Error> {
+        Expr::try_from(AstNode::deserialize(deserializer)?).map_err(serde::de::Error::custom)
+    }
+}
+
+impl Expr {
+    /// Parse a query AST sent as JSON
+    pub fn from_ast_json(json: &str) -> Result<Expr, QueryError> {
+        serde_json::from_str(json).map_err(|err| QueryError::InvalidAst(err.to_string()))
+    }
+
+    pub fn to_ast_json(&self) -> String {
+        serde_json::to_string(self).expect("a query AST always serializes")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::tokenizer::{StringTokenizer, Tokenable};
+
+    fn parse(query: &str) -> Expr {
+        StringTokenizer::parse(StringTokenizer::tokenize(query).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_ast_json_format() {
+        let expr = parse("ocean && ~(storm || id:a1)");
+        assert_eq!(
+            expr.to_ast_json(),
+            r#"{"and":[{"word":"ocean"},{"not":{"or":[{"word":"storm"},{"doc_id":"a1"}]}}]}"#
+        );
+
+        // Chains fold from the left, as the query string's operators do
+        let chain = Expr::from_ast_json(r#"{"or":[{"word":"a"},{"word":"b"},{"word":"c"}]}"#);
+        assert_eq!(chain.unwrap().to_string(), parse("a || b || c").to_string());
+        let words = Expr::from_ast_json(r#"{"word":"id:a1 && \"b\""}"#).unwrap();
+        assert!(matches!(words, Expr::Word(word) if word == "id:a1 && \"b\""));
+    }
+
+    #[test]
+    fn test_invalid_ast_json() {
+        for json in [
+            r#"{"and":[{"word":"a"}]}"#,
+            r#"{"or":[]}"#,
+            r#"{"near":[{"word":"a"},{"word":"b"}]}"#,
+            r#"{"word":"a","doc_id":"b"}"#,
+            r#""ocean""#,
+            "",
+        ] {
+            assert!(
+                matches!(Expr::from_ast_json(json), Err(QueryError::InvalidAst(_))),
+                "{}",
+                json
+            );
+        }
+    }
+}
@@ -0,0 +1,192 @@
+//! A cap on the time and KV operations a single search may spend, so that a slow
+//! store or a very wide query yields partial results instead of a failed request.
+
+use std::rc::Rc;
+
+use serde::Serialize;
+use worker::Env;
+
+use crate::{
+    data::{
+        op_budget::OpBudget, DEFAULT_SEARCH_BUDGET_MS, DEFAULT_SEARCH_BUDGET_OPS,
+        ENV_VAR_SEARCH_BUDGET_MS, ENV_VAR_SEARCH_BUDGET_OPS,
+    },
+    lexer::timings::{elapsed_ms, now_ms},
+    util::env::{parse_env_u64, parse_env_usize},
+};
+
+/// The most time and KV operations one search may spend before it stops reading and
+/// answers from the keyword data it has already loaded
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct QueryBudget {
+    pub max_ms: u64,
+    pub max_ops: usize,
+}
+
+impl Default for QueryBudget {
+    fn default() -> Self {
+        QueryBudget::UNLIMITED
+    }
+}
+
+impl QueryBudget {
+    pub const UNLIMITED: QueryBudget = QueryBudget {
+        max_ms: u64::MAX,
+        max_ops: usize::MAX,
+    };
+
+    /// The budget set by `SEARCH_BUDGET_MS` and `SEARCH_BUDGET_OPS`
+    pub fn from_env(env: &Env) -> QueryBudget {
+        QueryBudget {
+            max_ms: parse_env_u64(
+                env,
+                ENV_VAR_SEARCH_BUDGET_MS,
+                DEFAULT_SEARCH_BUDGET_MS,
+                1..=u64::MAX,
+            ),
+            max_ops: parse_env_usize(
+                env,
+                ENV_VAR_SEARCH_BUDGET_OPS,
+                DEFAULT_SEARCH_BUDGET_OPS,
+                1..=usize::MAX,
+            ),
+        }
+    }
+
+    /// This budget lowered to `max_ms` and `max_ops` where given. A request can only
+    /// tighten the budget, never raise it.
+    pub fn tightened(self, max_ms: Option<u64>, max_ops: Option<usize>) -> QueryBudget {
+        QueryBudget {
+            max_ms: max_ms.map_or(self.max_ms, |ms| ms.min(self.max_ms)),
+            max_ops: max_ops.map_or(self.max_ops, |ops| ops.min(self.max_ops)),
+        }
+    }
+}
+
+/// Which part of a [`QueryBudget`] ran out
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum BudgetExceeded {
+    Time,
+    Ops,
+    /// The request came within a few percent of the Workers subrequest cap
+    Subrequests,
+}
+
+/// The time and operations a search has spent against its [`QueryBudget`]
+#[derive(Debug)]
+pub struct BudgetTracker {
+    budget: QueryBudget,
+    started: u64,
+    ops: usize,
+    exceeded: Option<BudgetExceeded>,
+    /// The subrequests the whole request made, counted by its store
+    subrequests: Option<Rc<OpBudget>>,
+}
+
+impl BudgetTracker {
+    /// Start spending `budget` now
+    pub fn start(budget: QueryBudget) -> BudgetTracker {
+        BudgetTracker {
+            budget,
+            started: now_ms(),
+            ops: 0,
+            exceeded: None,
+            subrequests: None,
+        }
+    }
+
+    /// Also stop once the request's subrequests come near the cap
+    pub fn watch_subrequests(&mut self, subrequests: Rc<OpBudget>) {
+        self.subrequests = Some(subrequests);
+    }
+
+    /// The subrequest count being watched, if any
+    pub fn subrequests(&self) -> Option<Rc<OpBudget>> {
+        self.subrequests.clone()
+    }
+
+    /// Count `ops` KV operations against the budget
+    pub fn spend(&mut self, ops: usize) {
+        self.ops = self.ops.saturating_add(ops);
+    }
+
+    /// Whether another read may be issued. Once this is false it stays false, and
+    /// [`Self::exceeded`] says why.
+    pub fn has_room(&mut self) -> bool {
+        if self.exceeded.is_none() {
+            if self.ops >= self.budget.max_ops {
+                self.exceeded = Some(BudgetExceeded::Ops);
+            } else if self.subrequests.as_ref().is_some_and(|ops| ops.near_cap()) {
+                self.exceeded = Some(BudgetExceeded::Subrequests);
+            } else if elapsed_ms(self.started, now_ms()) >= self.budget.max_ms {
+                self.exceeded = Some(BudgetExceeded::Time);
+            }
+        }
+        self.exceeded.is_none()
+    }
+
+    /// Why reads were stopped, if they were
+    pub fn exceeded(&self) -> Option<BudgetExceeded> {
+        self.exceeded
+    }
+
+    /// The KV operations spent so far
+    pub fn ops(&self) -> usize {
+        self.ops
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tightened_never_raises() {
+        let budget = QueryBudget {
+            max_ms: 100,
+            max_ops: 50,
+        };
+        assert_eq!(budget.tightened(None, None), budget);
+        assert_eq!(
+            budget.tightened(Some(20), Some(500)),
+            QueryBudget {
+                max_ms: 20,
+                max_ops: 50,
+            }
+        );
+    }
+
+    #[test]
+    fn test_exhaustion_sticks() {
+        let mut tracker = BudgetTracker::start(QueryBudget {
+            max_ms: u64::MAX,
+            max_ops: 3,
+        });
+        tracker.spend(2);
+        assert!(tracker.has_room());
+        tracker.spend(1);
+        assert!(!tracker.has_room());
+        assert_eq!(tracker.exceeded(), Some(BudgetExceeded::Ops));
+
+        let mut tracker = BudgetTracker::start(QueryBudget {
+            max_ms: 0,
+            max_ops: usize::MAX,
+        });
+        assert!(!tracker.has_room());
+        assert_eq!(tracker.exceeded(), Some(BudgetExceeded::Time));
+        assert_eq!(tracker.ops(), 0);
+    }
+
+    #[test]
+    fn test_subrequests_near_the_cap_stop_reads() {
+        let subrequests = OpBudget::new(100);
+        let mut tracker = BudgetTracker::start(QueryBudget::UNLIMITED);
+        tracker.watch_subrequests(subrequests.clone());
+        subrequests.count(94);
+        assert!(tracker.has_room());
+        subrequests.count(1);
+        assert!(!tracker.has_room());
+        assert_eq!(tracker.exceeded(), Some(BudgetExceeded::Subrequests));
+    }
+}
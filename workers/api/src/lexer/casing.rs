@@ -0,0 +1,160 @@
+//! Case-insensitive matching for indexes whose keywords kept whatever casing YAKE
+//! produced, by expanding each query keyword into its stored casing variants.
+
+use std::collections::HashMap;
+
+use crate::lexer::Expr;
+
+/// The most casing variants a single query keyword expands into
+pub const MAX_CASE_VARIANTS: usize = 8;
+
+/// The spellings of `keyword` whose shard keys are listed to find its variants:
+/// lowercased, as given, capitalized, and uppercased
+pub fn variant_prefixes(keyword: &str) -> Vec<String> {
+    let lower = keyword.to_lowercase();
+    let mut chars = lower.chars();
+    let capitalized = match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    };
+    let mut prefixes = vec![
+        lower,
+        keyword.to_string(),
+        capitalized,
+        keyword.to_uppercase(),
+    ];
+    let mut seen = vec![];
+    prefixes.retain(|prefix| {
+        let new = !seen.contains(prefix);
+        seen.push(prefix.clone());
+        new
+    });
+    prefixes
+}
+
+/// The listed keywords that are `keyword` in another casing, with the exact
+/// spelling first and the rest sorted, bounded by [`MAX_CASE_VARIANTS`]. The query
+/// keyword itself is always a variant, even when nothing is stored under it.
+pub fn case_variants(keyword: &str, listed: &[String]) -> Vec<String> {
+    let lower = keyword.to_lowercase();
+    let mut others: Vec<&String> = listed
+        .iter()
+        .filter(|candidate| candidate.as_str() != keyword && candidate.to_lowercase() == lower)
+        .collect();
+    others.sort();
+    others.dedup();
+
+    let mut variants = vec![keyword.to_string()];
+    variants.extend(others.into_iter().take(MAX_CASE_VARIANTS - 1).cloned());
+    variants
+}
+
+/// Merge the postings of every variant into one list, keeping each document's best
+/// score across the variants it appears under
+pub fn merge_variant_postings(
+    variants: &[String],
+    postings: &HashMap<String, Vec<(String, f64)>>,
+) -> Vec<(String, f64)> {
+    let mut merged: Vec<(String, f64)> = vec![];
+    let mut positions: HashMap<String, usize> = HashMap::new();
+    for variant in variants {
+        for (doc_id, score) in postings.get(variant).into_iter().flatten() {
+            match positions.get(doc_id) {
+                Some(&at) => merged[at].1 = merged[at].1.max(*score),
+                None => {
+                    positions.insert(doc_id.clone(), merged.len());
+                    merged.push((doc_id.clone(), *score));
+                }
+            }
+        }
+    }
+    merged
+}
+
+/// The query with every expanded keyword replaced by an `OR` of its variants
+pub fn expand_query(expr: &Expr, variants: &HashMap<String, Vec<String>>) -> Expr {
+    match expr {
+        Expr::Word(word) => match variants.get(word) {
+            Some(variants) if variants.len() > 1 => variants
+                .iter()
+                .map(|variant| Expr::Word(variant.clone()))
+                .reduce(|left, right| Expr::Or(Box::new(left), Box::new(right)))
+                .unwrap(),
+            _ => expr.clone(),
+        },
+        Expr::DocId(_) => expr.clone(),
+        Expr::Not(inner) => Expr::Not(Box::new(expand_query(inner, variants))),
+        Expr::And(left, right) => Expr::And(
+            Box::new(expand_query(left, variants)),
+            Box::new(expand_query(right, variants)),
+        ),
+        Expr::Or(left, right) => Expr::Or(
+            Box::new(expand_query(left, variants)),
+            Box::new(expand_query(right, variants)),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(items: &[&str]) -> Vec<String> {
+        items.iter().map(|item| item.to_string()).collect()
+    }
+
+    #[test]
+    fn test_variant_prefixes() {
+        assert_eq!(
+            variant_prefixes("ocean"),
+            strings(&["ocean", "Ocean", "OCEAN"])
+        );
+        assert_eq!(
+            variant_prefixes("iPhone"),
+            strings(&["iphone", "iPhone", "Iphone", "IPHONE"])
+        );
+        assert_eq!(variant_prefixes("42"), strings(&["42"]));
+    }
+
+    #[test]
+    fn test_case_variants_are_exact_matches_only() {
+        let listed = strings(&["Ocean", "oceanic", "OCEAN", "ocean", "Oceans"]);
+        assert_eq!(
+            case_variants("ocean", &listed),
+            strings(&["ocean", "OCEAN", "Ocean"])
+        );
+        assert_eq!(case_variants("Storm", &listed), strings(&["Storm"]));
+    }
+
+    #[test]
+    fn test_case_variants_are_bounded() {
+        let listed: Vec<String> = (0..32u32)
+            .map(|bits| {
+                "abcde"
+                    .chars()
+                    .enumerate()
+                    .map(|(i, c)| match bits & (1 << i) {
+                        0 => c,
+                        _ => c.to_ascii_uppercase(),
+                    })
+                    .collect()
+            })
+            .collect();
+        let variants = case_variants("abcde", &listed);
+        assert_eq!(variants.len(), MAX_CASE_VARIANTS);
+        assert_eq!(variants[0], "abcde");
+    }
+
+    #[test]
+    fn test_merge_keeps_best_score() {
+        let postings = HashMap::from([
+            ("ocean".to_string(), vec![("a".to_string(), 0.2)]),
+            (
+                "Ocean".to_string(),
+                vec![("a".to_string(), 0.7), ("b".to_string(), 0.4)],
+            ),
+        ]);
+        let merged = merge_variant_postings(&strings(&["ocean", "Ocean", "OCEAN"]), &postings);
+        assert_eq!(merged, vec![("a".to_string(), 0.7), ("b".to_string(), 0.4)]);
+    }
+}
@@ -0,0 +1,94 @@
+//! Collapsing matches that share a metadata value into one result, such as the
+//! chunks of one page indexed as separate documents.
+//!
+//! `collapse=url` keeps the best-ranked match per value of the `url` field and counts
+//! the rest as collapsed into it, and `collapse_hits=N` returns the next `N` of those
+//! alongside it. Values group by their JSON form, so `"1"` and `1` are different
+//! groups. A match without the field, or with it `null`, is a group of its own.
+
+use serde_json::Value;
+
+use crate::data::document::Document;
+
+/// The most collapsed matches `collapse_hits` may return under each result
+pub const MAX_COLLAPSE_HITS: usize = 10;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Collapse {
+    pub field: String,
+    /// How many of the collapsed matches are returned under the one kept
+    pub hits: usize,
+}
+
+impl Collapse {
+    /// Parse the `collapse` and `collapse_hits` parameters of a search, `None` when
+    /// no collapsing was asked for
+    pub fn parse(field: Option<&str>, hits: Option<usize>) -> Result<Option<Collapse>, String> {
+        let Some(field) = field.map(str::trim) else {
+            return match hits {
+                Some(_) => Err("collapse_hits needs collapse".to_string()),
+                None => Ok(None),
+            };
+        };
+        if field.is_empty() {
+            return Err("collapse needs a metadata field name".to_string());
+        }
+        let hits = hits.unwrap_or(0);
+        if hits > MAX_COLLAPSE_HITS {
+            return Err(format!(
+                "collapse_hits must be at most {}",
+                MAX_COLLAPSE_HITS
+            ));
+        }
+        Ok(Some(Collapse {
+            field: field.to_string(),
+            hits,
+        }))
+    }
+
+    /// The group `document` collapses into, or `None` when it has no value for the
+    /// field and so is a group of its own
+    pub fn group_key(&self, document: &Document) -> Option<String> {
+        match document.metadata()?.get(&self.field)? {
+            Value::Null => None,
+            value => Some(value.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_collapse() {
+        assert_eq!(Collapse::parse(None, None), Ok(None));
+        assert_eq!(
+            Collapse::parse(Some(" url "), Some(3)),
+            Ok(Some(Collapse {
+                field: "url".to_string(),
+                hits: 3
+            }))
+        );
+        assert_eq!(Collapse::parse(Some("url"), None).unwrap().unwrap().hits, 0);
+
+        assert!(Collapse::parse(None, Some(2)).is_err());
+        assert!(Collapse::parse(Some(""), None).is_err());
+        assert!(Collapse::parse(Some("url"), Some(MAX_COLLAPSE_HITS + 1)).is_err());
+    }
+
+    #[test]
+    fn test_group_key() {
+        let collapse = Collapse::parse(Some("url"), None).unwrap().unwrap();
+        let key = |body: &str| {
+            let mut document = Document::new_with_id("idx", "doc1");
+            document.document_body = Some(body.to_string());
+            collapse.group_key(&document)
+        };
+        assert_eq!(key(r#"{"url":"/a"}"#), Some(r#""/a""#.to_string()));
+        assert_ne!(key(r#"{"url":"1"}"#), key(r#"{"url":1}"#));
+        assert_eq!(key(r#"{"url":null}"#), None);
+        assert_eq!(key(r#"{"title":"no url"}"#), None);
+        assert_eq!(key("Plain text"), None);
+    }
+}
@@ -0,0 +1,155 @@
+//! Substring filters applied to search matches once their documents are hydrated.
+//!
+//! Each `contains=` parameter is a literal substring a match's body must contain,
+//! and each `contains_ci=` one is matched ignoring case. A match must contain every
+//! substring, and reports where in its body they were found:
+//!
+//! - `contains=Pacific Ocean` keeps bodies with exactly that text
+//! - `contains_ci=straße` also keeps bodies with `STRASSE` or `Straße`
+
+/// The most substrings one search may filter by
+pub const MAX_NEEDLES: usize = 8;
+
+/// The most spans reported for one match, enough to highlight a page of text
+pub const MAX_SPANS: usize = 100;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Needle {
+    pub text: String,
+    pub case_insensitive: bool,
+}
+
+/// The text `haystack` folds to for a case-insensitive comparison, along with the
+/// byte range of `haystack` each byte of the folded text came from
+fn fold(haystack: &str) -> (String, Vec<(usize, usize)>) {
+    let mut folded = String::with_capacity(haystack.len());
+    let mut origins = Vec::with_capacity(haystack.len());
+    for (start, c) in haystack.char_indices() {
+        let origin = (start, start + c.len_utf8());
+        let before = folded.len();
+        // Lowercasing leaves these apart from the letters they're written as in caps
+        match c {
+            'ß' | 'ẞ' => folded.push_str("ss"),
+            'ς' => folded.push('σ'),
+            _ => folded.extend(c.to_lowercase()),
+        }
+        origins.resize(origins.len() + folded.len() - before, origin);
+    }
+    (folded, origins)
+}
+
+impl Needle {
+    /// Byte ranges `[start, end)` of every place in `body` the needle occurs
+    pub fn spans(&self, body: &str) -> Vec<[usize; 2]> {
+        if !self.case_insensitive {
+            return body
+                .match_indices(self.text.as_str())
+                .map(|(start, found)| [start, start + found.len()])
+                .collect();
+        }
+        let (needle, _) = fold(&self.text);
+        let (folded, origins) = fold(body);
+        folded
+            .match_indices(needle.as_str())
+            .map(|(start, found)| [origins[start].0, origins[start + found.len() - 1].1])
+            .collect()
+    }
+}
+
+/// Every substring filter of a search, all of which a match must contain
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Contains(pub Vec<Needle>);
+
+impl Contains {
+    /// Parse the `contains=` and `contains_ci=` parameters of a search
+    pub fn parse<'p>(
+        sensitive: impl Iterator<Item = &'p str>,
+        insensitive: impl Iterator<Item = &'p str>,
+    ) -> Result<Contains, String> {
+        let needles: Vec<Needle> = sensitive
+            .map(|text| (text, false))
+            .chain(insensitive.map(|text| (text, true)))
+            .map(|(text, case_insensitive)| Needle {
+                text: text.to_string(),
+                case_insensitive,
+            })
+            .collect();
+        if needles.iter().any(|needle| needle.text.is_empty()) {
+            return Err("contains needs a substring to search for".to_string());
+        }
+        if needles.len() > MAX_NEEDLES {
+            return Err(format!(
+                "At most {} contains substrings may be given, but got {}",
+                MAX_NEEDLES,
+                needles.len()
+            ));
+        }
+        Ok(Contains(needles))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Where in `body` each substring was found, in order, or `None` unless every
+    /// substring was. At most [`MAX_SPANS`] spans are kept.
+    pub fn spans(&self, body: &str) -> Option<Vec<[usize; 2]>> {
+        let mut spans = vec![];
+        for needle in &self.0 {
+            let found = needle.spans(body);
+            if found.is_empty() {
+                return None;
+            }
+            spans.extend(found);
+        }
+        spans.sort_unstable();
+        spans.dedup();
+        spans.truncate(MAX_SPANS);
+        Some(spans)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(sensitive: &[&str], insensitive: &[&str]) -> Result<Contains, String> {
+        Contains::parse(sensitive.iter().copied(), insensitive.iter().copied())
+    }
+
+    #[test]
+    fn test_parse_contains() {
+        let contains = parse(&["Ocean"], &["tide"]).unwrap();
+        assert_eq!(contains.0.len(), 2);
+        assert!(!contains.0[0].case_insensitive && contains.0[1].case_insensitive);
+        assert!(parse(&[], &[]).unwrap().is_empty());
+
+        assert!(parse(&[""], &[]).is_err());
+        assert!(parse(&["a"; MAX_NEEDLES], &["b"]).is_err());
+    }
+
+    #[test]
+    fn test_every_substring_must_occur() {
+        let contains = parse(&["Ocean", "tide"], &[]).unwrap();
+        let body = "Ocean tides rise. The Ocean sleeps.";
+        assert_eq!(contains.spans(body), Some(vec![[0, 5], [6, 10], [22, 27]]));
+        // Case-sensitive needles don't match other casings
+        assert_eq!(contains.spans("ocean tides rise"), None);
+        assert_eq!(contains.spans("Ocean waves"), None);
+    }
+
+    #[test]
+    fn test_unicode_case_folding() {
+        let spans = |needle: &str, body: &str| parse(&[], &[needle]).unwrap().spans(body);
+
+        // Offsets point into the body as stored, whatever its letters fold to
+        assert_eq!(spans("café", "Grand CAFÉ"), Some(vec![[6, 11]]));
+        assert_eq!(spans("STRASSE", "Hauptstraße 5"), Some(vec![[5, 12]]));
+        assert_eq!(spans("straße", "HAUPTSTRASSE"), Some(vec![[5, 12]]));
+        assert_eq!(spans("ΟΔΟΣ", "μια οδος"), Some(vec![[7, 15]]));
+        assert_eq!(spans("ὈΔΥΣΣΕΎΣ", "ὀδυσσεύς"), Some(vec![[0, 17]]));
+        // İ lowercases to two characters, both mapped back to it
+        assert_eq!(spans("i\u{307}stanbul", "İSTANBUL"), Some(vec![[0, 9]]));
+        assert_eq!(spans("ocean", "Pacific"), None);
+    }
+}
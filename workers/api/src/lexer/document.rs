@@ -1,25 +1,33 @@
-use lingua::IsoCode639_1;
-use once_cell::sync::Lazy;
 use worker::Env;
-use yake_rust::{Config, StopWords};
+use yake_rust::Config;
 
 use crate::{
-    data::{DocumentScore, DEFAULT_YAKE_MIN_CHARS, DEFAULT_YAKE_NGRAMS},
-    edge_log,
+    data::{
+        document::KeywordScore, DocumentScore, DEFAULT_YAKE_MIN_CHARS, DEFAULT_YAKE_NGRAMS,
+        ENV_VAR_YAKE_MIN_CHARS, ENV_VAR_YAKE_NGRAMS,
+    },
+    lexer::extractor::Extractor,
+    util::env::parse_env_u32,
 };
 
-fn get_yake_config_from_env(env: &Env) -> Config {
-    let ngrams = env
-        .var("YAKE_NGRAMS")
-        .ok()
-        .map(|v| v.to_string().parse::<u8>().unwrap_or(3))
-        .unwrap_or(DEFAULT_YAKE_NGRAMS);
-    let min_chars = env
-        .var("YAKE_MINIMUM_CHARS")
-        .ok()
-        .map(|v| v.to_string().parse::<u8>().unwrap_or(2))
-        .unwrap_or(DEFAULT_YAKE_MIN_CHARS);
+pub fn get_yake_config_from_env(env: &Env) -> Config {
+    let ngrams = parse_env_u32(env, ENV_VAR_YAKE_NGRAMS, DEFAULT_YAKE_NGRAMS.into(), 1..=10);
+    let min_chars = parse_env_u32(
+        env,
+        ENV_VAR_YAKE_MIN_CHARS,
+        DEFAULT_YAKE_MIN_CHARS.into(),
+        1..=64,
+    );
 
+    yake_config(ngrams as u8, min_chars as u8)
+}
+
+/// The YAKE settings used when the env doesn't override them
+pub fn default_yake_config() -> Config {
+    yake_config(DEFAULT_YAKE_NGRAMS, DEFAULT_YAKE_MIN_CHARS)
+}
+
+fn yake_config(ngrams: u8, min_chars: u8) -> Config {
     Config {
         ngrams: ngrams as usize,
         minimum_chars: min_chars as usize,
@@ -28,52 +36,103 @@ fn get_yake_config_from_env(env: &Env) -> Config {
     }
 }
 
-static STOPWORDS_CACHE: Lazy<std::collections::HashMap<String, StopWords>> = Lazy::new(|| {
-    let mut map = std::collections::HashMap::new();
-    // Iterate over certain IsoCode639_1 variants and pre-load their stopwords
-    let iso_codes = vec![IsoCode639_1::EN];
-    for code in iso_codes {
-        let lang_str = code.to_string();
-        map.insert(
-            lang_str.clone(),
-            StopWords::predefined(&lang_str.as_str()).unwrap(),
-        );
+/// The factor a keyword's score is multiplied by when it first appears
+/// `offset_ratio` of the way into the body: 1 at the start, falling to `1 / (1 + k)`
+/// at the end
+pub fn position_decay(offset_ratio: f64, k: f64) -> f64 {
+    1.0 / (1.0 + k * offset_ratio)
+}
+
+/// What an index without a `title_boost` of its own multiplies title keywords by
+pub const DEFAULT_TITLE_BOOST: f64 = 1.5;
+
+/// Multiply the scores of the keywords picked from a document's title by `boost`,
+/// keeping the unboosted score as `raw`. Scores are capped at 1, the top of both
+/// extractors' scale.
+pub fn boost_title(keywords: Vec<DocumentScore>, boost: f64) -> Vec<(String, KeywordScore)> {
+    keywords
+        .into_iter()
+        .map(|(keyword, raw)| {
+            let score = (raw * boost).min(1.0);
+            (keyword, KeywordScore { score, raw })
+        })
+        .collect()
+}
+
+/// The body's keywords followed by the title's that it lacks. A keyword in both
+/// keeps whichever of its scores is higher.
+pub fn merge_title_keywords(
+    mut keywords: Vec<(String, KeywordScore)>,
+    title: Vec<(String, KeywordScore)>,
+) -> Vec<(String, KeywordScore)> {
+    for (keyword, score) in title {
+        match keywords
+            .iter_mut()
+            .find(|(existing, _)| *existing == keyword)
+        {
+            Some((_, existing)) if existing.score < score.score => *existing = score,
+            Some(_) => {}
+            None => keywords.push((keyword, score)),
+        }
     }
-    map
-});
+    keywords
+}
 
 pub struct DocumentLexer<'a> {
-    env: &'a Env,
+    config: Config,
+    extractor: Extractor,
     body: &'a str,
 }
 
 impl<'a> DocumentLexer<'a> {
-    pub fn new(env: &'a Env, body: &'a str) -> Self {
-        DocumentLexer { env, body: body }
+    pub fn new(env: &Env, body: &'a str) -> Self {
+        Self::with_config(get_yake_config_from_env(env), body)
     }
 
-    pub fn try_string(&self, lang: &str) -> Option<Vec<DocumentScore>> {
-        let stopwords = if let Some(cached) = STOPWORDS_CACHE.get(lang) {
-            cached.clone()
-        } else {
-            edge_log!(
-                console_warn,
-                "Document",
-                "",
-                "No cached stopwords for language {}",
-                lang
-            );
-            let sw = StopWords::predefined(&lang);
-            sw.unwrap()
-        };
-        let yake_config = get_yake_config_from_env(self.env);
-        let _keywords: Vec<(String, f64)> =
-            yake_rust::get_n_best(50, &self.body, &stopwords, &yake_config)
-                .iter()
-                .map(|item| (item.keyword.clone(), 1.0f64 - item.score))
-                .collect();
+    pub fn with_config(config: Config, body: &'a str) -> Self {
+        DocumentLexer {
+            config,
+            extractor: Extractor::default(),
+            body,
+        }
+    }
+
+    /// Pick keywords with `extractor` rather than YAKE
+    pub fn with_extractor(mut self, extractor: Extractor) -> Self {
+        self.extractor = extractor;
+        self
+    }
 
-        Some(_keywords)
+    pub fn try_string(&self, lang: &str) -> Option<Vec<DocumentScore<'_>>> {
+        let backend = self.extractor.backend();
+        Some(backend.extract(self.body, lang, &self.config))
+    }
+
+    /// Scale each keyword's score by the [`position_decay`] of where it first appears
+    /// in the body, keeping the unboosted score as `raw`. Keywords that can't be
+    /// found as written, e.g. ones YAKE rejoined across punctuation, count as
+    /// appearing at the very end. A `k` of 0 leaves every score as it is.
+    pub fn boost_by_position(
+        &self,
+        keywords: Vec<DocumentScore>,
+        k: f64,
+    ) -> Vec<(String, KeywordScore)> {
+        if k == 0.0 {
+            return keywords
+                .into_iter()
+                .map(|(keyword, raw)| (keyword, raw.into()))
+                .collect();
+        }
+        let body = self.body.to_lowercase();
+        let len = body.len().max(1) as f64;
+        keywords
+            .into_iter()
+            .map(|(keyword, raw)| {
+                let offset = body.find(&keyword.to_lowercase()).unwrap_or(body.len());
+                let score = raw * position_decay(offset as f64 / len, k);
+                (keyword, KeywordScore { score, raw })
+            })
+            .collect()
     }
 
     pub fn try_json<'j>(&self, lang: &str) -> Option<Vec<DocumentScore<'j>>> {
@@ -84,7 +143,8 @@ impl<'a> DocumentLexer<'a> {
 
         // Create a temporary DocumentLexer with the cleaned string
         let temp_lexer = DocumentLexer {
-            env: self.env,
+            config: self.config.clone(),
+            extractor: self.extractor,
             body: &cleaned_str,
         };
         temp_lexer.try_string(lang)
@@ -111,3 +171,66 @@ impl<'a> DocumentLexer<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_position_decay() {
+        assert_eq!(position_decay(0.0, 3.0), 1.0);
+        assert_eq!(position_decay(0.5, 2.0), 0.5);
+        assert_eq!(position_decay(1.0, 1.0), 0.5);
+        assert_eq!(position_decay(0.7, 0.0), 1.0);
+    }
+
+    #[test]
+    fn test_boost_by_position() {
+        let body = "Ocean tides rise. Much later comes the storm";
+        let lexer = DocumentLexer::with_config(default_yake_config(), body);
+        let keywords = vec![
+            ("Ocean".to_string(), 0.8),
+            ("storm".to_string(), 0.8),
+            ("missing".to_string(), 0.8),
+        ];
+
+        let boosted = lexer.boost_by_position(keywords.clone(), 1.0);
+        assert_eq!(boosted[0].1, KeywordScore::from(0.8));
+        let storm_ratio = body.find("storm").unwrap() as f64 / body.len() as f64;
+        assert!((boosted[1].1.score - 0.8 / (1.0 + storm_ratio)).abs() < 1e-9);
+        assert_eq!(boosted[1].1.raw, 0.8);
+        // Keywords that can't be found count as appearing at the end
+        assert!((boosted[2].1.score - 0.4).abs() < 1e-9);
+
+        let unboosted = lexer.boost_by_position(keywords, 0.0);
+        assert!(unboosted.iter().all(|(_, score)| score.score == score.raw));
+    }
+
+    #[test]
+    fn test_title_keywords_are_boosted_and_merged() {
+        let title = boost_title(
+            vec![("ocean".to_string(), 0.4), ("storm".to_string(), 0.8)],
+            DEFAULT_TITLE_BOOST,
+        );
+        assert!((title[0].1.score - 0.6).abs() < 1e-9);
+        assert_eq!(title[0].1.raw, 0.4);
+        // Capped at the top of the scale
+        assert_eq!(
+            title[1].1,
+            KeywordScore {
+                score: 1.0,
+                raw: 0.8
+            }
+        );
+
+        let body = vec![
+            ("tide".to_string(), KeywordScore::from(0.5)),
+            ("ocean".to_string(), KeywordScore::from(0.9)),
+            ("storm".to_string(), KeywordScore::from(0.3)),
+        ];
+        let merged = merge_title_keywords(body, title.clone());
+        let scores: Vec<(&str, f64)> = merged.iter().map(|(k, s)| (k.as_str(), s.score)).collect();
+        assert_eq!(scores, vec![("tide", 0.5), ("ocean", 0.9), ("storm", 1.0)]);
+        assert_eq!(merge_title_keywords(vec![], title.clone()), title);
+    }
+}
@@ -0,0 +1,238 @@
+//! The backends that pick a document's keywords, chosen per index with the
+//! `extractor` setting. YAKE weighs each phrase by its context and is the default;
+//! `tf` counts single words, which is far cheaper and suits short texts like product
+//! titles. Both score keywords in (0, 1], so documents indexed by either compare
+//! alike in searches.
+
+use std::collections::HashMap;
+
+use lingua::IsoCode639_1;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use yake_rust::{Config, StopWords};
+
+use crate::edge_log;
+
+/// The most keywords kept from one document
+pub const MAX_KEYWORDS: usize = 50;
+
+/// The score given to YAKE's weakest keywords, whose `1 - score` isn't positive
+const MIN_SCORE: f64 = 1e-6;
+
+static STOPWORDS_CACHE: Lazy<HashMap<String, StopWords>> = Lazy::new(|| {
+    let mut map = HashMap::new();
+    // Iterate over certain IsoCode639_1 variants and pre-load their stopwords
+    let iso_codes = vec![IsoCode639_1::EN];
+    for code in iso_codes {
+        let lang_str = code.to_string();
+        map.insert(
+            lang_str.clone(),
+            StopWords::predefined(lang_str.as_str()).unwrap(),
+        );
+    }
+    map
+});
+
+/// The name yake gives the stopword list of `lang`, which spells Czech its own way
+fn yake_language(lang: &str) -> &str {
+    match lang {
+        "cs" => "cz",
+        lang => lang,
+    }
+}
+
+/// The stopwords of `lang`, or English's for the languages yake has no list for,
+/// such as Korean or Hebrew, which an index may still be created with
+fn stopwords(lang: &str) -> StopWords {
+    if let Some(cached) = STOPWORDS_CACHE.get(lang) {
+        return cached.clone();
+    }
+    if let Some(predefined) = StopWords::predefined(yake_language(lang)) {
+        return predefined;
+    }
+    edge_log!(
+        console_warn,
+        "Document",
+        "",
+        "No stopwords for language {}, using English's",
+        lang
+    );
+    STOPWORDS_CACHE[&IsoCode639_1::EN.to_string()].clone()
+}
+
+/// Picks the keywords of a plain text body in `lang`, scored in (0, 1]
+pub trait KeywordExtractor {
+    fn extract(&self, body: &str, lang: &str, config: &Config) -> Vec<(String, f64)>;
+}
+
+/// YAKE's best keywords, scored `1 - score` since YAKE ranks the best lowest
+pub struct YakeExtractor;
+
+impl KeywordExtractor for YakeExtractor {
+    fn extract(&self, body: &str, lang: &str, config: &Config) -> Vec<(String, f64)> {
+        yake_rust::get_n_best(MAX_KEYWORDS, body, &stopwords(lang), config)
+            .iter()
+            .map(|item| {
+                let score = (1.0f64 - item.score).clamp(MIN_SCORE, 1.0);
+                (item.keyword.clone(), score)
+            })
+            .collect()
+    }
+}
+
+/// The most frequent words that aren't stopwords, numbers or shorter than
+/// `config.minimum_chars`, each scored by its count over the top word's. Ties go to
+/// the word appearing first. Phrases aren't formed, so `config.ngrams` is ignored.
+pub struct TfExtractor;
+
+impl KeywordExtractor for TfExtractor {
+    fn extract(&self, body: &str, lang: &str, config: &Config) -> Vec<(String, f64)> {
+        let stopwords = stopwords(lang);
+        // Each word's count and first position
+        let mut counts: HashMap<String, (usize, usize)> = HashMap::new();
+        let words = body
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|word| word.chars().count() >= config.minimum_chars)
+            .filter(|word| !word.chars().all(|c| c.is_numeric()))
+            .map(str::to_lowercase)
+            .filter(|word| !stopwords.contains(word));
+        for (position, word) in words.enumerate() {
+            counts.entry(word).or_insert((0, position)).0 += 1;
+        }
+
+        let mut ranked: Vec<(String, (usize, usize))> = counts.into_iter().collect();
+        ranked.sort_by(|(_, (a_count, a_first)), (_, (b_count, b_first))| {
+            b_count.cmp(a_count).then(a_first.cmp(b_first))
+        });
+        let top = ranked.first().map_or(1, |(_, (count, _))| *count) as f64;
+        ranked
+            .into_iter()
+            .take(MAX_KEYWORDS)
+            .map(|(word, (count, _))| (word, count as f64 / top))
+            .collect()
+    }
+}
+
+/// Which [`KeywordExtractor`] an index's documents are written with
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Extractor {
+    #[default]
+    Yake,
+    Tf,
+}
+
+impl Extractor {
+    pub const NAMES: [&'static str; 2] = ["yake", "tf"];
+
+    pub fn backend(&self) -> &'static dyn KeywordExtractor {
+        match self {
+            Extractor::Yake => &YakeExtractor,
+            Extractor::Tf => &TfExtractor,
+        }
+    }
+
+    /// What the extractor costs, for the index's usage
+    pub fn note(&self) -> &'static str {
+        match self {
+            Extractor::Yake => {
+                "yake: scores phrases by their context, the most CPU time per document written"
+            }
+            Extractor::Tf => {
+                "tf: counts single words, a fraction of YAKE's CPU time on each document written"
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::document::default_yake_config;
+
+    const ARTICLE: &str = "The ocean tide rises every evening. Fishermen watch the ocean \
+        tide from the harbor, waiting for the tide to turn before the boats leave the harbor.";
+
+    const TITLE: &str = "Stainless Steel Water Bottle, 750ml, Steel Cap";
+
+    /// The top keywords, with scores rounded to 3 places
+    fn snapshot(extractor: Extractor, body: &str, n: usize) -> Vec<(String, String)> {
+        let keywords = extractor
+            .backend()
+            .extract(body, "en", &default_yake_config());
+        assert!(keywords
+            .iter()
+            .all(|(_, score)| *score > 0.0 && *score <= 1.0));
+        keywords
+            .into_iter()
+            .take(n)
+            .map(|(keyword, score)| (keyword, format!("{:.3}", score)))
+            .collect()
+    }
+
+    #[test]
+    fn test_languages_without_stopwords_fall_back() {
+        let config = default_yake_config();
+        for extractor in [Extractor::Yake, Extractor::Tf] {
+            for lang in ["ko", "he", "vi", "cs"] {
+                let keywords = extractor.backend().extract(ARTICLE, lang, &config);
+                assert!(!keywords.is_empty(), "{}", lang);
+            }
+        }
+        // English's stopwords stand in for the missing list, and Czech has its own
+        assert!(stopwords("ko").contains("the"));
+        assert!(!stopwords("cs").contains("the"));
+    }
+
+    fn pairs(expected: &[(&str, &str)]) -> Vec<(String, String)> {
+        expected
+            .iter()
+            .map(|(keyword, score)| (keyword.to_string(), score.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_yake_snapshot() {
+        assert_eq!(
+            snapshot(Extractor::Yake, ARTICLE, 4),
+            pairs(&[
+                ("ocean tide rises", "0.994"),
+                ("rises every evening", "0.983"),
+                ("ocean tide", "0.980"),
+                ("tide rises", "0.965"),
+            ])
+        );
+        assert_eq!(
+            snapshot(Extractor::Yake, TITLE, 4),
+            pairs(&[
+                ("steel water bottle", "0.999"),
+                ("stainless steel water", "0.999"),
+                ("water bottle", "0.994"),
+                ("steel cap", "0.992"),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_tf_snapshot() {
+        assert_eq!(
+            snapshot(Extractor::Tf, ARTICLE, 4),
+            pairs(&[
+                ("tide", "1.000"),
+                ("ocean", "0.667"),
+                ("harbor", "0.667"),
+                ("rises", "0.333"),
+            ])
+        );
+        assert_eq!(
+            snapshot(Extractor::Tf, TITLE, 4),
+            pairs(&[
+                ("steel", "1.000"),
+                ("stainless", "0.500"),
+                ("water", "0.500"),
+                ("bottle", "0.500"),
+            ])
+        );
+        assert!(snapshot(Extractor::Tf, "the and of 42", 4).is_empty());
+    }
+}
@@ -0,0 +1,177 @@
+//! Per-value match counts over metadata fields, for building filter UIs next to the
+//! results. Counting needs every match's document, so faceted searches are capped
+//! at `SEARCH_FACET_MAX_DOCS` matches.
+
+use std::collections::{BTreeMap, HashMap};
+
+use serde::Serialize;
+use serde_json::{Map, Value};
+use worker::Env;
+
+use crate::{
+    data::{DEFAULT_SEARCH_FACET_MAX_DOCS, ENV_VAR_SEARCH_FACET_MAX_DOCS},
+    util::env::parse_env_usize,
+};
+
+/// The most distinct values reported per facet, the rest are summed into `other`
+pub const MAX_FACET_VALUES: usize = 50;
+
+/// The most matches a faceted search will count
+pub fn get_facet_max_docs(env: &Env) -> usize {
+    parse_env_usize(
+        env,
+        ENV_VAR_SEARCH_FACET_MAX_DOCS,
+        DEFAULT_SEARCH_FACET_MAX_DOCS,
+        0..=usize::MAX,
+    )
+}
+
+/// Parse the comma-separated `facets=` parameter
+pub fn parse_facet_fields(fields: Option<&str>) -> Vec<String> {
+    let mut parsed: Vec<String> = vec![];
+    for field in fields.unwrap_or("").split(',').map(str::trim) {
+        if !field.is_empty() && !parsed.iter().any(|f| f == field) {
+            parsed.push(field.to_string());
+        }
+    }
+    parsed
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct FacetValue {
+    pub value: String,
+    pub count: u32,
+}
+
+/// A field's most common values, most matches first
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct Facet {
+    pub values: Vec<FacetValue>,
+    /// Matches counted under values beyond the first `MAX_FACET_VALUES`
+    pub other: u32,
+}
+
+/// How a metadata value is counted: strings as themselves, numbers and booleans as
+/// written, and each element of an array separately. Nulls and objects aren't counted.
+fn facet_values(value: &Value) -> Vec<String> {
+    match value {
+        Value::String(s) => vec![s.clone()],
+        Value::Number(n) => vec![n.to_string()],
+        Value::Bool(b) => vec![b.to_string()],
+        Value::Array(items) => {
+            let mut values: Vec<String> = items.iter().flat_map(facet_values).collect();
+            // A document counts once per value, even if its array repeats it
+            values.sort();
+            values.dedup();
+            values
+        }
+        Value::Null | Value::Object(_) => vec![],
+    }
+}
+
+/// Count the values of each of `fields` across the metadata of every match
+pub fn count_facets<'m>(
+    fields: &[String],
+    documents: impl Iterator<Item = &'m Map<String, Value>>,
+) -> BTreeMap<String, Facet> {
+    let mut counts: Vec<HashMap<String, u32>> = vec![HashMap::new(); fields.len()];
+    for metadata in documents {
+        for (field, counts) in fields.iter().zip(counts.iter_mut()) {
+            for value in metadata.get(field).map(facet_values).unwrap_or_default() {
+                *counts.entry(value).or_default() += 1;
+            }
+        }
+    }
+
+    fields
+        .iter()
+        .cloned()
+        .zip(counts)
+        .map(|(field, counts)| {
+            let mut values: Vec<FacetValue> = counts
+                .into_iter()
+                .map(|(value, count)| FacetValue { value, count })
+                .collect();
+            values.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.value.cmp(&b.value)));
+            let other = values
+                .iter()
+                .skip(MAX_FACET_VALUES)
+                .map(|value| value.count)
+                .sum();
+            values.truncate(MAX_FACET_VALUES);
+            (field, Facet { values, other })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn metadata(values: Vec<Value>) -> Vec<Map<String, Value>> {
+        values
+            .into_iter()
+            .map(|value| value.as_object().unwrap().clone())
+            .collect()
+    }
+
+    fn counts(facet: &Facet) -> Vec<(&str, u32)> {
+        facet
+            .values
+            .iter()
+            .map(|v| (v.value.as_str(), v.count))
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_facet_fields() {
+        assert!(parse_facet_fields(None).is_empty());
+        assert_eq!(
+            parse_facet_fields(Some("category, tags,,category")),
+            vec!["category", "tags"]
+        );
+    }
+
+    #[test]
+    fn test_array_values_count_each_element() {
+        let documents = metadata(vec![
+            json!({"category": "books", "tags": ["sea", "travel", "sea"]}),
+            json!({"category": "books", "tags": ["sea"]}),
+            json!({"category": "maps", "tags": "travel", "year": 2020}),
+            json!({"category": null, "tags": [{"nested": true}, 7]}),
+        ]);
+        let fields = parse_facet_fields(Some("category,tags,year,missing"));
+        let facets = count_facets(&fields, documents.iter());
+
+        assert_eq!(counts(&facets["category"]), vec![("books", 2), ("maps", 1)]);
+        assert_eq!(
+            counts(&facets["tags"]),
+            vec![("sea", 2), ("travel", 2), ("7", 1)]
+        );
+        assert_eq!(counts(&facets["year"]), vec![("2020", 1)]);
+        assert_eq!(facets["missing"], Facet::default());
+    }
+
+    #[test]
+    fn test_rare_values_fall_into_other() {
+        let mut values: Vec<Value> = (0..60).map(|i| json!({"n": i})).collect();
+        values.extend((0..5).map(|_| json!({"n": 7})));
+        let documents = metadata(values);
+        let facets = count_facets(&["n".to_string()], documents.iter());
+
+        let facet = &facets["n"];
+        assert_eq!(facet.values.len(), MAX_FACET_VALUES);
+        assert_eq!(
+            facet.values[0],
+            FacetValue {
+                value: "7".into(),
+                count: 6
+            }
+        );
+        assert_eq!(facet.other, 10);
+        let counted: u32 = facet.values.iter().map(|v| v.count).sum();
+        assert_eq!(counted + facet.other, 65);
+    }
+}
@@ -0,0 +1,223 @@
+//! Metadata filters applied to search matches once their documents are hydrated.
+//!
+//! A document's metadata is the top-level fields of its body, when the body is a JSON
+//! object. Each `filter=` parameter is a `field:condition` pair, and a match must
+//! pass every filter:
+//!
+//! - `category:books` keeps documents whose field equals the value
+//! - `price:<100`, `price:<=100`, `price:>5` and `price:>=5` compare numbers
+//! - `year:2019..2023` keeps numbers inside the range, both ends included
+
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+/// How a filter tests a metadata value
+#[derive(Debug, Clone, PartialEq)]
+pub enum Condition {
+    /// The value is this string, or a number or boolean written this way
+    Equals(String),
+    Lt(f64),
+    Le(f64),
+    Gt(f64),
+    Ge(f64),
+    /// Between the two numbers, inclusive
+    Range(f64, f64),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Filter {
+    pub field: String,
+    pub condition: Condition,
+}
+
+/// A filtered field that no matched document has, so its filter removed everything
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct FilterError {
+    pub field: String,
+    pub error: String,
+}
+
+fn parse_number(raw: &str, filter: &str) -> Result<f64, String> {
+    match raw.trim().parse::<f64>() {
+        Ok(number) if number.is_finite() => Ok(number),
+        _ => Err(format!("Invalid number '{}' in filter '{}'", raw, filter)),
+    }
+}
+
+impl Filter {
+    /// Parse a `field:condition` filter parameter
+    pub fn parse(raw: &str) -> Result<Filter, String> {
+        let Some((field, condition)) = raw.split_once(':') else {
+            return Err(format!("Filter '{}' must be written field:condition", raw));
+        };
+        let field = field.trim();
+        if field.is_empty() {
+            return Err(format!("Filter '{}' has no field name", raw));
+        }
+
+        let condition = if let Some(number) = condition.strip_prefix("<=") {
+            Condition::Le(parse_number(number, raw)?)
+        } else if let Some(number) = condition.strip_prefix(">=") {
+            Condition::Ge(parse_number(number, raw)?)
+        } else if let Some(number) = condition.strip_prefix('<') {
+            Condition::Lt(parse_number(number, raw)?)
+        } else if let Some(number) = condition.strip_prefix('>') {
+            Condition::Gt(parse_number(number, raw)?)
+        } else if let Some((low, high)) = condition.split_once("..") {
+            let (low, high) = (parse_number(low, raw)?, parse_number(high, raw)?);
+            if low > high {
+                return Err(format!("Filter '{}' has an empty range", raw));
+            }
+            Condition::Range(low, high)
+        } else {
+            Condition::Equals(condition.to_string())
+        };
+        Ok(Filter {
+            field: field.to_string(),
+            condition,
+        })
+    }
+
+    /// Whether `metadata` passes this filter. Numeric conditions only match numbers,
+    /// so a field stored as a string or boolean never passes one.
+    pub fn matches(&self, metadata: &Map<String, Value>) -> bool {
+        let Some(value) = metadata.get(&self.field) else {
+            return false;
+        };
+        let number = value.as_f64();
+        match &self.condition {
+            Condition::Equals(expected) => match value {
+                Value::String(s) => s == expected,
+                Value::Number(_) => expected.parse::<f64>().ok() == number,
+                Value::Bool(b) => expected.parse::<bool>().ok() == Some(*b),
+                _ => false,
+            },
+            Condition::Lt(bound) => number.is_some_and(|n| n < *bound),
+            Condition::Le(bound) => number.is_some_and(|n| n <= *bound),
+            Condition::Gt(bound) => number.is_some_and(|n| n > *bound),
+            Condition::Ge(bound) => number.is_some_and(|n| n >= *bound),
+            Condition::Range(low, high) => number.is_some_and(|n| *low <= n && n <= *high),
+        }
+    }
+}
+
+/// Every filter of a search, all of which a match must pass
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Filters(pub Vec<Filter>);
+
+impl Filters {
+    pub fn parse<'p>(raw: impl Iterator<Item = &'p str>) -> Result<Filters, String> {
+        raw.map(Filter::parse)
+            .collect::<Result<_, _>>()
+            .map(Filters)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn matches(&self, metadata: &Map<String, Value>) -> bool {
+        self.0.iter().all(|filter| filter.matches(metadata))
+    }
+
+    /// An error for each filtered field that none of `documents` has
+    pub fn absent_fields<'m>(
+        &self,
+        documents: impl Iterator<Item = &'m Map<String, Value>> + Clone,
+    ) -> Vec<FilterError> {
+        let mut errors: Vec<FilterError> = vec![];
+        for filter in &self.0 {
+            let present = documents
+                .clone()
+                .any(|metadata| metadata.contains_key(&filter.field));
+            if !present && !errors.iter().any(|e| e.field == filter.field) {
+                errors.push(FilterError {
+                    field: filter.field.clone(),
+                    error: "No matched document has this field".to_string(),
+                });
+            }
+        }
+        errors
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn metadata(value: Value) -> Map<String, Value> {
+        value.as_object().unwrap().clone()
+    }
+
+    fn passes(filter: &str, value: Value) -> bool {
+        Filter::parse(filter).unwrap().matches(&metadata(value))
+    }
+
+    #[test]
+    fn test_parse_filters() {
+        assert_eq!(
+            Filter::parse("price:<100").unwrap().condition,
+            Condition::Lt(100.0)
+        );
+        assert_eq!(
+            Filter::parse("price:>=9.5").unwrap().condition,
+            Condition::Ge(9.5)
+        );
+        assert_eq!(
+            Filter::parse("year:2019..2023").unwrap().condition,
+            Condition::Range(2019.0, 2023.0)
+        );
+        assert_eq!(
+            Filter::parse("url:https://example.com").unwrap(),
+            Filter {
+                field: "url".into(),
+                condition: Condition::Equals("https://example.com".into()),
+            }
+        );
+        for invalid in ["price", ":5", "price:<cheap", "year:2023..2019", "n:>inf"] {
+            assert!(Filter::parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_numeric_boundaries() {
+        assert!(passes("price:<100", json!({"price": 99.99})));
+        assert!(!passes("price:<100", json!({"price": 100})));
+        assert!(passes("price:<=100", json!({"price": 100})));
+        assert!(!passes("price:>5", json!({"price": 5})));
+        assert!(passes("price:>=5", json!({"price": 5})));
+        assert!(passes("year:2019..2023", json!({"year": 2019})));
+        assert!(passes("year:2019..2023", json!({"year": 2023})));
+        assert!(!passes("year:2019..2023", json!({"year": 2023.5})));
+        assert!(passes("temp:-10..-1", json!({"temp": -3})));
+    }
+
+    #[test]
+    fn test_mixed_type_metadata() {
+        // Numeric conditions never match values that aren't numbers
+        assert!(!passes("price:<100", json!({"price": "50"})));
+        assert!(!passes("price:<100", json!({"price": true})));
+        assert!(!passes("price:<100", json!({"price": null})));
+        assert!(!passes("price:<100", json!({"cost": 50})));
+
+        assert!(passes("price:50", json!({"price": 50.0})));
+        assert!(passes("price:50", json!({"price": "50"})));
+        assert!(passes("published:true", json!({"published": true})));
+        assert!(!passes("category:books", json!({"category": ["books"]})));
+    }
+
+    #[test]
+    fn test_absent_fields() {
+        let filters = Filters::parse(["price:<100", "year:2020", "price:>1"].into_iter()).unwrap();
+        let documents = [
+            metadata(json!({"price": 5})),
+            metadata(json!({"title": "x"})),
+        ];
+        let errors = filters.absent_fields(documents.iter());
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].field, "year");
+        assert!(filters.absent_fields([].iter()).len() == 2);
+    }
+}
@@ -0,0 +1,150 @@
+//! Spelling correction for query keywords that match nothing, by picking the
+//! closest stored keyword within a small Damerau-Levenshtein distance.
+
+use serde::Serialize;
+
+/// The largest edit distance a correction may be from the original keyword
+pub const MAX_CORRECTION_DISTANCE: usize = 2;
+
+/// The number of leading characters a correction must share with the original,
+/// which is also the prefix listed to find candidates
+pub const CORRECTION_PREFIX_CHARS: usize = 2;
+
+/// A query keyword that matched nothing, and the stored keyword used in its place
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Correction {
+    pub original: String,
+    pub used: String,
+}
+
+/// The prefix candidates for `keyword` are listed under, or `None` when the keyword
+/// is too short to correct
+pub fn correction_prefix(keyword: &str) -> Option<String> {
+    let prefix: String = keyword.chars().take(CORRECTION_PREFIX_CHARS).collect();
+    (prefix.chars().count() == CORRECTION_PREFIX_CHARS).then_some(prefix)
+}
+
+/// The optimal string alignment variant of the Damerau-Levenshtein distance: the
+/// number of insertions, deletions, substitutions and adjacent transpositions
+/// between `a` and `b`, counted in characters rather than bytes
+pub fn damerau_levenshtein(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+
+    // Three rows of the distance matrix: two rows back, the previous, and the current
+    let mut before: Vec<usize> = vec![0; b.len() + 1];
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    let mut current: Vec<usize> = vec![0; b.len() + 1];
+    for i in 1..=a.len() {
+        current[0] = i;
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            current[j] = (previous[j] + 1)
+                .min(current[j - 1] + 1)
+                .min(previous[j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                current[j] = current[j].min(before[j - 2] + 1);
+            }
+        }
+        std::mem::swap(&mut before, &mut previous);
+        std::mem::swap(&mut previous, &mut current);
+    }
+    previous[b.len()]
+}
+
+/// Every candidate at the smallest distance from `original`, provided that distance
+/// is within [`MAX_CORRECTION_DISTANCE`]. The original itself is never a candidate.
+pub fn closest_keywords<'c>(original: &str, candidates: &'c [String]) -> Vec<&'c String> {
+    let mut best = MAX_CORRECTION_DISTANCE + 1;
+    let mut closest = vec![];
+    for candidate in candidates.iter().filter(|c| c.as_str() != original) {
+        let distance = damerau_levenshtein(original, candidate);
+        if distance < best {
+            best = distance;
+            closest.clear();
+        }
+        if distance == best {
+            closest.push(candidate);
+        }
+    }
+    closest
+}
+
+/// Break a tie between equally close keywords, preferring the one found in the
+/// most documents and then the alphabetically first, so corrections are stable
+pub fn most_frequent(tied: Vec<(String, usize)>) -> Option<String> {
+    tied.into_iter()
+        .max_by(|(a, a_docs), (b, b_docs)| a_docs.cmp(b_docs).then_with(|| b.cmp(a)))
+        .map(|(keyword, _)| keyword)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(items: &[&str]) -> Vec<String> {
+        items.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_damerau_levenshtein() {
+        assert_eq!(damerau_levenshtein("programming", "programming"), 0);
+        assert_eq!(damerau_levenshtein("progamming", "programming"), 1);
+        assert_eq!(damerau_levenshtein("porgramming", "programming"), 1);
+        assert_eq!(damerau_levenshtein("ocean", "oceans"), 1);
+        assert_eq!(damerau_levenshtein("kitten", "sitting"), 3);
+        assert_eq!(damerau_levenshtein("", "abc"), 3);
+        assert_eq!(damerau_levenshtein("abc", ""), 3);
+    }
+
+    #[test]
+    fn test_distance_counts_characters_not_bytes() {
+        assert_eq!(damerau_levenshtein("café", "cafe"), 1);
+        assert_eq!(damerau_levenshtein("über", "übre"), 1);
+        assert_eq!(damerau_levenshtein("東京都", "東京"), 1);
+    }
+
+    #[test]
+    fn test_correction_prefix() {
+        assert_eq!(correction_prefix("programming").as_deref(), Some("pr"));
+        assert_eq!(correction_prefix("über").as_deref(), Some("üb"));
+        assert_eq!(correction_prefix("東京").as_deref(), Some("東京"));
+        assert_eq!(correction_prefix("a"), None);
+    }
+
+    #[test]
+    fn test_closest_keywords() {
+        let candidates = strings(&["programming", "program", "progress", "programmer"]);
+        assert_eq!(
+            closest_keywords("progamming", &candidates),
+            vec!["programming"]
+        );
+        assert!(closest_keywords("prxxxxxxxx", &candidates).is_empty());
+        assert!(closest_keywords("program", &strings(&["program"])).is_empty());
+    }
+
+    #[test]
+    fn test_closest_keywords_non_ascii() {
+        let candidates = strings(&["café crème", "cafés", "über"]);
+        assert_eq!(
+            closest_keywords("cafe creme", &candidates),
+            vec!["café crème"]
+        );
+    }
+
+    #[test]
+    fn test_ties_prefer_document_frequency() {
+        let candidates = strings(&["cart", "care", "card"]);
+        let tied = closest_keywords("carx", &candidates);
+        assert_eq!(tied.len(), 3);
+
+        let frequencies = tied
+            .into_iter()
+            .zip([3, 7, 7])
+            .map(|(keyword, docs)| (keyword.clone(), docs))
+            .collect();
+        // "care" and "card" are both in 7 documents; the alphabetically first wins
+        assert_eq!(most_frequent(frequencies).as_deref(), Some("card"));
+        assert_eq!(most_frequent(vec![]), None);
+    }
+}
@@ -1,19 +1,38 @@
-use std::{collections::HashMap, sync::Arc};
-
-use futures::future::join_all;
-use worker::kv::KvStore;
+use std::{
+    collections::{HashMap, HashSet},
+    rc::Rc,
+};
 
 use crate::{
-    data::keyword::KeywordManager,
+    data::{
+        codec::CodecSet, keyword::KeywordManager, op_budget::OpBudget, storage::Storage,
+        trace::ReadTrace, DataStoreError,
+    },
     edge_log,
     http::search::SearchResultRow,
     lexer::{
-        scoring::score_collective_keywords,
+        budget::{BudgetExceeded, BudgetTracker, QueryBudget},
+        casing::{case_variants, expand_query, merge_variant_postings, variant_prefixes},
+        check_ast,
+        fuzzy::{closest_keywords, correction_prefix, most_frequent, Correction},
+        plan::{Evaluator, Plan},
+        scoring::{ScoringMode, TermCoverage},
+        simple::{rewrite, SimpleMode},
+        timings::{elapsed_ms, now_ms, Timings},
         tokenizer::{StringTokenizer, Tokenable},
+        warnings::Warnings,
         DocumentMatches, Expr, KeywordCache, QueryError,
     },
 };
 
+/// Order rows best score first, breaking ties by document ID so results are stable
+pub fn rank_order(a: &SearchResultRow, b: &SearchResultRow) -> std::cmp::Ordering {
+    b.score
+        .partial_cmp(&a.score)
+        .unwrap_or(std::cmp::Ordering::Equal)
+        .then_with(|| a.doc_id.cmp(&b.doc_id))
+}
+
 ///
 /// QueryLexer is responsible for parsing search queries, but also executes search
 /// queries for documents matching the keywords in the query.
@@ -21,44 +40,185 @@ use crate::{
 /// This struct handles the complete pipeline from raw inputs into search results by:
 /// 1. Parses the input query string into AST
 /// 2. Iterate through the AST and collect matching documents for each keyword
-/// 3. `AND` / `OR` / `NOT` merges the document sets recursively
+/// 3. `AND` / `OR` / `NOT` merges the document sets recursively, see [`Plan`]
 /// 4. Returns the final set of matching documents with individual keyword scores
 ///
-pub struct QueryLexer<'a> {
+pub struct QueryLexer<'a, S: Storage> {
     /// The parsed Abstract Syntax Tree representation of the query
     ast: Expr,
-    /// Reference to the Workers environment for accessing Durable Objects
-    env: &'a worker::Env,
+    /// How keyword shards are read: through the Workers environment's Durable
+    /// Objects, or directly from the store
+    shards: ShardAccess<'a>,
     /// Reference to the KV store for retrieving keyword data
-    store: &'a Arc<KvStore>,
-    /// Current query execution results
-    result: DocumentMatches,
+    store: &'a S,
     /// Cache of keyword data to avoid repeated KV store lookups
     kw_cache: KeywordCache,
+    /// Time spent in each stage of the query so far
+    timings: Timings,
+    /// Whether keywords matching nothing are replaced by the closest stored keyword
+    fuzzy: bool,
+    /// The keyword substitutions made by the most recent fuzzy preload
+    corrections: Vec<Correction>,
+    /// Whether keywords also match their stored variants in other casings
+    case_insensitive: bool,
+    /// The stored casing variants each query keyword was expanded into, for keywords
+    /// with more than one
+    case_variants: HashMap<String, Vec<String>>,
+    /// How each document's keyword matches are combined into its score
+    scoring: ScoringMode,
+    /// The time and KV operations spent so far against the search's budget
+    budget: BudgetTracker,
+    /// Where the keyword shards the query reads are recorded, with `debug=true`
+    trace: Option<&'a ReadTrace>,
+    /// What the index's keyword shards are read with
+    codecs: CodecSet,
+    /// What the scores of some keywords are multiplied by, when the query was
+    /// rewritten from a search box's text rather than sent as written
+    boosts: Option<HashMap<String, f64>>,
+    /// The index's recorded shard count, so its shard keys are named rather than listed
+    known_n_shards: Option<u32>,
+    /// Whether keywords are read through the merge cache, see [`crate::data::merge_cache`]
+    merge_cache: bool,
+    /// What was imperfect about the most recent preload, like keywords matching nothing
+    warnings: Warnings,
+}
+
+/// How many keywords are read per round of preloading, between budget checks
+pub const PRELOAD_ROUND: usize = 8;
+
+/// Where a [`QueryLexer`] reads keyword shards from
+enum ShardAccess<'a> {
+    /// Use the env's shard count and Durable Object reader
+    Env(&'a worker::Env),
+    /// Read straight from the store with the given shard count
+    Direct(u32),
 }
 
-impl<'a> QueryLexer<'a> {
+impl<'a, S: Storage> QueryLexer<'a, S> {
     /// Create a new QueryLexer a precompiled query
     pub fn new(
         ast: Expr,
-        store: &'a Arc<KvStore>,
+        store: &'a S,
         env: &'a worker::Env,
-    ) -> Result<QueryLexer<'a>, QueryError> {
-        Ok(QueryLexer {
+    ) -> Result<QueryLexer<'a, S>, QueryError> {
+        check_ast(&ast)?;
+        Ok(Self::with_access(ast, store, ShardAccess::Env(env)))
+    }
+
+    /// Create a QueryLexer that reads keyword shards straight from `store`, without
+    /// the env or its Durable Object reader
+    pub fn direct(ast: Expr, store: &'a S, n_shards: u32) -> QueryLexer<'a, S> {
+        Self::with_access(ast, store, ShardAccess::Direct(n_shards))
+    }
+
+    fn with_access(ast: Expr, store: &'a S, shards: ShardAccess<'a>) -> QueryLexer<'a, S> {
+        QueryLexer {
             ast,
-            env,
+            shards,
             store,
-            result: HashMap::new(),
             kw_cache: HashMap::new(),
-        })
+            timings: Timings::default(),
+            fuzzy: false,
+            corrections: vec![],
+            case_insensitive: false,
+            case_variants: HashMap::new(),
+            scoring: ScoringMode::default(),
+            budget: BudgetTracker::start(QueryBudget::UNLIMITED),
+            trace: None,
+            codecs: CodecSet::V1,
+            boosts: None,
+            known_n_shards: None,
+            merge_cache: false,
+            warnings: Warnings::default(),
+        }
+    }
+
+    /// Replace keywords that match no documents with the closest stored keyword
+    pub fn with_fuzzy(mut self, fuzzy: bool) -> Self {
+        self.fuzzy = fuzzy;
+        self
+    }
+
+    /// Match keywords regardless of the casing they were stored with, for indexes
+    /// whose keywords weren't normalized when they were written
+    pub fn with_case_insensitive(mut self, case_insensitive: bool) -> Self {
+        self.case_insensitive = case_insensitive;
+        self
+    }
+
+    /// Combine each document's keyword matches into its score with `scoring`
+    pub fn with_scoring(mut self, scoring: ScoringMode) -> Self {
+        self.scoring = scoring;
+        self
+    }
+
+    /// Stop reading once `budget` is spent, counted from now, and evaluate the query
+    /// with whatever keyword data was loaded by then
+    pub fn with_budget(mut self, budget: QueryBudget) -> Self {
+        let subrequests = self.budget.subrequests();
+        self.budget = BudgetTracker::start(budget);
+        if let Some(subrequests) = subrequests {
+            self.budget.watch_subrequests(subrequests);
+        }
+        self
+    }
+
+    /// Also stop reading once the request's `subrequests` come near the Workers cap
+    pub fn with_subrequests(mut self, subrequests: Rc<OpBudget>) -> Self {
+        self.budget.watch_subrequests(subrequests);
+        self
+    }
+
+    /// Record every keyword shard listed and read into `trace`
+    pub fn with_trace(mut self, trace: Option<&'a ReadTrace>) -> Self {
+        self.trace = trace;
+        self
+    }
+
+    /// Mark the query as rewritten from text, multiplying the scores of the keywords
+    /// in `boosts` by theirs, so [`Self::expanded_query`] reports what ran
+    pub fn with_rewrite(mut self, boosts: HashMap<String, f64>) -> Self {
+        self.boosts = Some(boosts);
+        self
+    }
+
+    /// Read keyword shards with the codecs of the index's version
+    pub fn with_codecs(mut self, codecs: CodecSet) -> Self {
+        self.codecs = codecs;
+        self
+    }
+
+    /// Name the shard keys of an index with a recorded shard count instead of listing
+    /// them, see [`crate::data::index::IndexDocument::known_shard_count`]
+    pub fn with_known_n_shards(mut self, n_shards: Option<u32>) -> Self {
+        self.known_n_shards = n_shards;
+        self
+    }
+
+    /// Answer keywords stored in every shard from their merged postings kept in KV,
+    /// and keep those of the keywords merged
+    pub fn with_merge_cache(mut self, merge_cache: bool) -> Self {
+        self.merge_cache = merge_cache;
+        self
+    }
+
+    /// The search's budget, for reads made after [`Self::query`] such as hydration
+    pub fn budget_mut(&mut self) -> &mut BudgetTracker {
+        &mut self.budget
+    }
+
+    /// Why reads stopped early, if the budget ran out
+    pub fn budget_exceeded(&self) -> Option<BudgetExceeded> {
+        self.budget.exceeded()
     }
 
     /// Create a new [`QueryLexer`] through tokenization of a raw query string
     pub fn from_str(
         query: &str,
-        store: &'a Arc<KvStore>,
+        store: &'a S,
         env: &'a worker::Env,
-    ) -> Result<QueryLexer<'a>, QueryError> {
+    ) -> Result<QueryLexer<'a, S>, QueryError> {
+        let started = now_ms();
         let tokens = StringTokenizer::tokenize(query)?;
         let ast = StringTokenizer::parse(tokens);
         if ast.is_none() {
@@ -71,13 +231,49 @@ impl<'a> QueryLexer<'a> {
             );
             return Err(QueryError::InvalidQuery(query.to_string(), ast));
         }
-        Self::new(ast.unwrap(), store, env)
+        let mut lexer = Self::new(ast.unwrap(), store, env)?;
+        lexer.timings.parse_ms = elapsed_ms(started, now_ms());
+        Ok(lexer)
+    }
+
+    /// Create a new [`QueryLexer`] from a query AST sent as JSON, see [`crate::lexer::ast`]
+    pub fn from_ast_json(
+        json: &str,
+        store: &'a S,
+        env: &'a worker::Env,
+    ) -> Result<QueryLexer<'a, S>, QueryError> {
+        let started = now_ms();
+        let ast = Expr::from_ast_json(json)?;
+        let mut lexer = Self::new(ast, store, env)?;
+        lexer.timings.parse_ms = elapsed_ms(started, now_ms());
+        Ok(lexer)
+    }
+
+    /// Create a new [`QueryLexer`] from the free text of a search box, combining its
+    /// words by `mode`, see [`crate::lexer::simple`]
+    pub fn from_text(
+        text: &str,
+        mode: SimpleMode,
+        store: &'a S,
+        env: &'a worker::Env,
+    ) -> Result<QueryLexer<'a, S>, QueryError> {
+        let started = now_ms();
+        let query = rewrite(text, mode).ok_or(QueryError::EmptyQuery)?;
+        let mut lexer = Self::new(query.expr, store, env)?.with_rewrite(query.boosts);
+        lexer.timings.parse_ms = elapsed_ms(started, now_ms());
+        Ok(lexer)
+    }
+
+    /// Time spent in each stage of the most recent [`Self::query`]
+    pub fn timings(&self) -> &Timings {
+        &self.timings
     }
 
     /// Collect all [`Expr::Word`] keywords from the AST and turn them into a list of keyword strings
     pub fn collect_keywords(expr: &Expr) -> Vec<&str> {
         match expr {
             Expr::Word(word) => vec![word.as_str()],
+            Expr::DocId(_) => vec![],
             Expr::Not(inner) => Self::collect_keywords(inner),
             Expr::And(left, right) | Expr::Or(left, right) => {
                 let mut keywords = Self::collect_keywords(left);
@@ -87,140 +283,1147 @@ impl<'a> QueryLexer<'a> {
         }
     }
 
+    /// Whether every match must hold a keyword or be named by an `id:` term, rather
+    /// than only being excluded from some, so the matches are bounded by the postings
+    fn anchored(expr: &Expr) -> bool {
+        match expr {
+            Expr::Word(_) | Expr::DocId(_) => true,
+            Expr::Not(_) => false,
+            Expr::And(left, right) => Self::anchored(left) || Self::anchored(right),
+            Expr::Or(left, right) => Self::anchored(left) && Self::anchored(right),
+        }
+    }
+
+    /// Whether the query's matches are drawn from its keywords' postings or `id:`
+    /// terms, as opposed to being only negated
+    pub fn is_bounded(&self) -> bool {
+        Self::anchored(&self.ast)
+    }
+
+    /// The keyword substitutions made by the most recent [`Self::query`] or [`Self::suggest`]
+    pub fn corrections(&self) -> &[Correction] {
+        &self.corrections
+    }
+
+    /// The warnings of the most recent [`Self::query`] or [`Self::suggest`]
+    pub fn warnings(&self) -> &Warnings {
+        &self.warnings
+    }
+
+    /// The query as evaluated by the most recent case-insensitive [`Self::query`], with
+    /// each keyword that has stored casing variants replaced by an `OR` of them, or
+    /// as rewritten from text
+    pub fn expanded_query(&self) -> Option<String> {
+        (self.case_insensitive || self.boosts.is_some())
+            .then(|| format!("{}", expand_query(&self.ast, &self.case_variants)))
+    }
+
+    /// Every keyword in the query, exactly as written
+    pub fn keywords(&self) -> Vec<String> {
+        Self::collect_keywords(&self.ast)
+            .into_iter()
+            .map(str::to_string)
+            .collect()
+    }
+
     /// Using the query AST provided during construction, execute the query recursively
     /// against the provided index and keyword shards in the KV store.
-    pub async fn query(&mut self, index: &str) -> Vec<SearchResultRow> {
+    pub async fn query(&mut self, index: &str) -> Result<Vec<SearchResultRow>, DataStoreError> {
+        let matches = self.evaluate(index).await?;
+        let started = now_ms();
+        let mut rows = self.rows(&matches);
+        Self::sort_rows(&mut rows);
+        self.timings.sort_ms = elapsed_ms(started, now_ms());
+        Ok(rows)
+    }
+
+    /// The IDs and scores of [`Self::query`]'s matches in the same order, without
+    /// building their rows: no keyword is copied out of the matches
+    pub async fn query_ids(&mut self, index: &str) -> Result<Vec<(String, f64)>, DataStoreError> {
+        let matches = self.evaluate(index).await?;
+        let started = now_ms();
+        let ids = self.ranked_ids(matches);
+        self.timings.sort_ms = elapsed_ms(started, now_ms());
+        Ok(ids)
+    }
+
+    /// Preload the query's keywords and match documents against them
+    async fn evaluate(&mut self, index: &str) -> Result<DocumentMatches, DataStoreError> {
         // Cleanup and preload keyword data
         self.kw_cache.clear();
-        self.result.clear();
-        self.preload_keyword_data(index).await;
+        self.corrections.clear();
+        self.case_variants.clear();
+        self.warnings.clear();
+        let started = now_ms();
+        self.timings.shard_reads = self.preload_keyword_data(index).await?;
+        self.timings.preload_ms = elapsed_ms(started, now_ms());
 
         let ast_str = format!("{}", &self.ast);
         edge_log!(console_debug, "QueryLexer", index, "AST={}", ast_str);
 
-        self.filter_documents_on_query(index, self.ast.clone())
+        let started = now_ms();
+        let plan = Plan::build(&self.ast, &self.kw_cache);
+        let matches = Evaluator::new(&self.kw_cache, &plan).evaluate(&plan);
+        self.timings.evaluate_ms = elapsed_ms(started, now_ms());
+        Ok(matches)
+    }
+
+    /// A row for every match, scored but unsorted
+    fn rows(&self, matches: &DocumentMatches) -> Vec<SearchResultRow> {
+        let query_keywords = Self::collect_keywords(&self.ast)
+            .into_iter()
+            .collect::<HashSet<_>>();
+        let scoring = self.scoring;
+        let boosts = self.boosts.as_ref();
+        matches
             .iter()
-            .map(move |(doc_id, kw_matches)| SearchResultRow {
-                doc_id: doc_id.to_string(),
-                score: score_collective_keywords(kw_matches),
-                keywords: kw_matches
+            .map(|(doc_id, kw_matches)| {
+                let kw_matches: Vec<(String, f64)> = kw_matches
                     .iter()
-                    .map(|(kw, score)| (kw.clone(), *score))
-                    .collect(),
-                body: None, // document body is not fetched in the QueryLexer
+                    .map(|(kw, score)| {
+                        let boost = boosts.and_then(|boosts| boosts.get(kw)).unwrap_or(&1.0);
+                        (kw.clone(), score * boost)
+                    })
+                    .collect();
+                let coverage = TermCoverage::of(&kw_matches, &query_keywords);
+                SearchResultRow {
+                    doc_id: doc_id.to_string(),
+                    score: scoring.score(&kw_matches, coverage),
+                    keywords: kw_matches,
+                    matched_terms: coverage.matched,
+                    total_terms: coverage.total,
+                    body: None, // document body is not fetched in the QueryLexer
+                    title: None,
+                    missing: false,
+                    matched_spans: vec![],
+                    recency_factor: None,
+                    raw_score: None,
+                    collapsed_count: None,
+                    collapsed_hits: vec![],
+                }
+            })
+            .collect()
+    }
+
+    /// Every match's ID and score, in the order of [`rank_order`]. Matches are only
+    /// copied to apply a rewritten query's boosts, and then without their keywords.
+    fn ranked_ids(&self, matches: DocumentMatches) -> Vec<(String, f64)> {
+        let query_keywords = Self::collect_keywords(&self.ast)
+            .into_iter()
+            .collect::<HashSet<_>>();
+        let scoring = self.scoring;
+        let mut ids: Vec<(String, f64)> = matches
+            .into_iter()
+            .map(|(doc_id, kw_matches)| {
+                let coverage = TermCoverage::of(&kw_matches, &query_keywords);
+                let score = match &self.boosts {
+                    None => scoring.score(&kw_matches, coverage),
+                    Some(boosts) => {
+                        let boosted: Vec<(&str, f64)> = kw_matches
+                            .iter()
+                            .map(|(kw, score)| {
+                                (kw.as_str(), score * boosts.get(kw).unwrap_or(&1.0))
+                            })
+                            .collect();
+                        scoring.score(&boosted, coverage)
+                    }
+                };
+                (doc_id, score)
             })
-            .collect::<Vec<SearchResultRow>>()
+            .collect();
+        ids.sort_by(|a, b| {
+            b.1.partial_cmp(&a.1)
+                .unwrap_or(std::cmp::Ordering::Equal)
+                .then_with(|| a.0.cmp(&b.0))
+        });
+        ids
+    }
+
+    /// Find the corrections a fuzzy [`Self::query`] would make, without evaluating it
+    pub async fn suggest(&mut self, index: &str) -> Result<Vec<Correction>, DataStoreError> {
+        self.fuzzy = true;
+        self.kw_cache.clear();
+        self.corrections.clear();
+        self.case_variants.clear();
+        self.warnings.clear();
+        let started = now_ms();
+        self.timings.shard_reads = self.preload_keyword_data(index).await?;
+        self.timings.preload_ms = elapsed_ms(started, now_ms());
+        Ok(self.corrections.clone())
+    }
+
+    fn sort_rows(rows: &mut [SearchResultRow]) {
+        rows.sort_by(rank_order);
     }
 
     /// Retrieves the keywords for all possible keywords in the query, generating a cache
-    /// and invoking a maximum of (N * N_SHARDS) KV reads, with a single LIST request.
-    async fn preload_keyword_data(&mut self, index: &str) -> () {
-        let manager = KeywordManager::new(index.to_string(), &self.env, &self.store);
+    /// and invoking a maximum of (N * N_SHARDS) KV reads, with a LIST request per
+    /// keyword unless the index's shard count is known. Keywords are read in rounds of [`PRELOAD_ROUND`], and once the budget
+    /// runs out no further rounds are issued, leaving the rest without postings.
+    /// Returns the number of keyword shards read, or the error of the first round whose
+    /// shards couldn't be read.
+    async fn preload_keyword_data(&mut self, index: &str) -> Result<usize, DataStoreError> {
+        let manager = match self.shards {
+            ShardAccess::Env(env) => KeywordManager::new(index.to_string(), env, self.store),
+            ShardAccess::Direct(n_shards) => {
+                KeywordManager::direct(index.to_string(), n_shards, self.store)
+            }
+        }
+        .with_trace(self.trace)
+        .with_codecs(self.codecs)
+        .with_merge_cache(self.merge_cache);
+        let manager = match self.known_n_shards {
+            Some(n_shards) => manager.with_known_n_shards(n_shards),
+            None => manager,
+        };
 
-        // preload all keyword data in the cache
-        let all_keywords = Self::collect_keywords(&self.ast);
-        let keyword_futures: Vec<_> = all_keywords
-            .iter()
-            .filter(|kw| !self.kw_cache.contains_key(**kw))
-            .map(async |kw| {
-                (
-                    *kw,
-                    manager.merge_keyword_shards(kw.to_string()).await.unwrap(),
-                )
-            })
+        // preload all keyword data in the cache, merging every keyword in one batch
+        let keywords: Vec<&str> = Self::collect_keywords(&self.ast)
+            .into_iter()
+            .filter(|kw| !self.kw_cache.contains_key(*kw))
             .collect();
+        let exact: Vec<String> = keywords.iter().map(|kw| kw.to_string()).collect();
+        let variants: Vec<Vec<String>> = match self.case_insensitive {
+            true => Self::list_case_variants(&manager, &exact, &mut self.budget).await,
+            false => exact.iter().map(|kw| vec![kw.clone()]).collect(),
+        };
+        let mut to_read: Vec<String> = vec![];
+        for variant in variants.iter().flatten() {
+            if !to_read.contains(variant) {
+                to_read.push(variant.clone());
+            }
+        }
+        let mut merged = HashMap::new();
+        let mut shard_reads = 0;
+        for round in to_read.chunks(PRELOAD_ROUND) {
+            if !self.budget.has_room() {
+                break;
+            }
+            let (read, reads) = if manager.caches_merges() {
+                let cached = manager
+                    .merge_many_keyword_shards_cached(round.to_vec())
+                    .await?;
+                let postings = cached
+                    .merged
+                    .into_iter()
+                    .map(|(keyword, stamped)| (keyword, stamped.postings))
+                    .collect::<HashMap<_, _>>();
+                self.budget
+                    .spend(cached.listings + cached.cache_ops + cached.shard_reads);
+                (postings, cached.shard_reads)
+            } else {
+                let (read, reads) = manager
+                    .merge_many_keyword_shards_counted(round.to_vec())
+                    .await?;
+                // One listing per keyword that needs one, plus every shard read
+                let listings = round.iter().filter(|kw| manager.lists_shards(kw)).count();
+                self.budget.spend(listings + reads);
+                (read, reads)
+            };
+            merged.extend(read);
+            shard_reads += reads;
+        }
 
-        let keyword_shard_data = join_all(keyword_futures).await;
-        for (keyword, doc_matches) in keyword_shard_data.into_iter() {
+        for (keyword, variants) in keywords.into_iter().zip(variants) {
+            let doc_matches = merge_variant_postings(&variants, &merged);
             self.kw_cache.insert(keyword.to_string(), doc_matches);
+            if variants.len() > 1 {
+                self.case_variants.insert(keyword.to_string(), variants);
+            }
         }
+
+        if self.fuzzy && self.budget.has_room() {
+            shard_reads += self.correct_unmatched(&manager).await;
+        }
+
+        // Keywords left unread by the budget are covered by `partial` instead
+        let mut warned = HashSet::new();
+        for keyword in Self::collect_keywords(&self.ast) {
+            let read = match self.case_variants.get(keyword) {
+                Some(variants) => variants.iter().all(|kw| merged.contains_key(kw)),
+                None => merged.contains_key(keyword),
+            };
+            let unmatched = self.kw_cache.get(keyword).is_some_and(Vec::is_empty);
+            if read && unmatched && warned.insert(keyword) {
+                self.warnings.keyword_no_matches(keyword);
+            }
+        }
+        Ok(shard_reads)
     }
 
-    /// Steps through the AST tree and recursively merges keyword score sets into document IDs.
-    fn filter_documents_on_query(
-        &mut self,
-        index: &str,
-        expr: Expr,
-    ) -> HashMap<String, Vec<(String, f64)>> {
-        match expr {
-            Expr::Not(inner) => {
-                let inner_matches = self.filter_documents_on_query(index, *inner);
-                let mut negated_result = HashMap::new();
-                for (doc_id, kws) in self.result.iter() {
-                    if !inner_matches.contains_key(doc_id) {
-                        negated_result.insert(doc_id.clone(), kws.clone());
-                    }
+    /// The stored casing variants of each keyword, found by listing the shard keys of
+    /// a few spellings of it. A keyword whose listing fails, or that is reached after
+    /// the budget ran out, only matches itself.
+    async fn list_case_variants(
+        manager: &KeywordManager<'_, S>,
+        keywords: &[String],
+        budget: &mut BudgetTracker,
+    ) -> Vec<Vec<String>> {
+        let mut variants = vec![];
+        for keyword in keywords {
+            let mut listed = vec![];
+            if !budget.has_room() {
+                variants.push(case_variants(keyword, &listed));
+                continue;
+            }
+            let prefixes = variant_prefixes(keyword);
+            budget.spend(prefixes.len());
+            for prefix in prefixes {
+                match manager.list_keywords_with_prefix(&prefix).await {
+                    Ok(found) => listed.extend(found),
+                    Err(err) => edge_log!(
+                        console_warn,
+                        "QueryLexer",
+                        "case_insensitive",
+                        "Failed to list casing variants of '{}': {}",
+                        keyword,
+                        err
+                    ),
                 }
-                self.result = negated_result;
-                self.result.clone()
             }
-            Expr::And(left, right) => {
-                let left_result = self.filter_documents_on_query(index, *left);
-                let right_result = self.filter_documents_on_query(index, *right);
-                let and_result = left_result
-                    .iter()
-                    .filter(|(doc_id, _)| right_result.contains_key(*doc_id))
-                    .map(|(doc_id, kws)| {
-                        let merged_kws = {
-                            let mut kws = kws.clone();
-                            let right_kws = right_result.get(doc_id).unwrap();
-                            for (kw, score) in right_kws {
-                                if !kws.iter().any(|(k, _)| k == kw) {
-                                    kws.push((kw.clone(), *score));
-                                }
-                            }
-                            kws
-                        };
-                        (doc_id.to_string(), merged_kws)
-                    })
-                    .collect::<HashMap<String, Vec<(String, f64)>>>();
-                self.result = and_result;
-                self.result.clone()
+            variants.push(case_variants(keyword, &listed));
+        }
+        variants
+    }
+
+    /// Substitute the closest stored keyword for every query keyword that matched no
+    /// documents, recording each substitution, until the budget runs out. Returns the
+    /// number of shards read.
+    async fn correct_unmatched(&mut self, manager: &KeywordManager<'_, S>) -> usize {
+        let mut unmatched: Vec<String> = self
+            .kw_cache
+            .iter()
+            .filter(|(_, docs)| docs.is_empty())
+            .map(|(keyword, _)| keyword.clone())
+            .collect();
+        unmatched.sort();
+
+        let mut shard_reads = 0;
+        for original in unmatched {
+            if !self.budget.has_room() {
+                break;
             }
-            Expr::Or(left, right) => {
-                let mut left_branch = self.filter_documents_on_query(index, *left);
-                let right_branch = self.filter_documents_on_query(index, *right);
-                Self::set_merge(&mut left_branch, right_branch);
-                self.result = left_branch;
-                self.result.clone()
+            let Some(prefix) = correction_prefix(&original) else {
+                continue;
+            };
+            self.budget.spend(1);
+            let candidates = match manager.list_keywords_with_prefix(&prefix).await {
+                Ok(candidates) => candidates,
+                Err(err) => {
+                    edge_log!(
+                        console_warn,
+                        "QueryLexer",
+                        "fuzzy",
+                        "Failed to list correction candidates for '{}': {}",
+                        original,
+                        err
+                    );
+                    continue;
+                }
+            };
+            let tied: Vec<String> = closest_keywords(&original, &candidates)
+                .into_iter()
+                .cloned()
+                .collect();
+            if tied.is_empty() {
+                continue;
             }
-            Expr::Word(word) => {
-                // Vec<(doc_id, score)>
-                let kw_data = self.kw_cache.get(&word).unwrap();
-                let to_add: HashMap<String, Vec<(String, f64)>> = kw_data
-                    .iter()
-                    .map(|(doc_id, score)| (doc_id.clone(), vec![(word.clone(), *score)]))
-                    .collect();
-                self.result = to_add;
-                self.result.clone()
+
+            // Equally close candidates are told apart by how many documents use them
+            let (mut merged, reads) = match manager
+                .merge_many_keyword_shards_counted(tied.clone())
+                .await
+            {
+                Ok(result) => result,
+                Err(err) => {
+                    edge_log!(
+                        console_warn,
+                        "QueryLexer",
+                        "fuzzy",
+                        "Failed to read correction candidates for '{}': {}",
+                        original,
+                        err
+                    );
+                    continue;
+                }
+            };
+            self.budget.spend(tied.len() + reads);
+            shard_reads += reads;
+            let frequencies = tied
+                .into_iter()
+                .map(|keyword| {
+                    let docs = merged.get(&keyword).map_or(0, Vec::len);
+                    (keyword, docs)
+                })
+                .collect();
+            let Some(used) = most_frequent(frequencies) else {
+                continue;
+            };
+            let postings = merged.remove(&used).unwrap_or_default();
+            if postings.is_empty() {
+                continue;
             }
+
+            Self::substitute(&mut self.ast, &original, &used);
+            self.kw_cache.insert(used.clone(), postings);
+            self.corrections.push(Correction { original, used });
         }
+        shard_reads
     }
 
-    /// Merge two keyword result sets together, avoiding duplicates
-    fn set_merge<T>(
-        into: &mut HashMap<String, Vec<(String, T)>>,
-        from: HashMap<String, Vec<(String, T)>>,
-    ) where
-        T: PartialEq + Copy,
-    {
-        // Merge items that are in `from` into `into`
-        for (doc_key, item) in from {
-            match into.get_mut(&doc_key) {
-                Some(existing) => {
-                    // Collect keywords to add to avoid borrowing conflicts
-                    let mut to_add = Vec::new();
-                    for (keyword, score) in item {
-                        if !existing.iter().any(|(k, _)| k == &keyword) {
-                            to_add.push((keyword, score));
-                        }
-                    }
-                    existing.extend(to_add);
-                }
-                None => {
-                    into.insert(doc_key, item);
-                }
+    /// Replace every [`Expr::Word`] matching `from` with `to`
+    fn substitute(expr: &mut Expr, from: &str, to: &str) {
+        match expr {
+            Expr::Word(word) if word == from => *word = to.to_string(),
+            Expr::Word(_) | Expr::DocId(_) => {}
+            Expr::Not(inner) => Self::substitute(inner, from, to),
+            Expr::And(left, right) | Expr::Or(left, right) => {
+                Self::substitute(left, from, to);
+                Self::substitute(right, from, to);
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+
+    use super::*;
+    use crate::{
+        data::{
+            document::{
+                testing::{index_envelope, index_text},
+                IndexingOptions,
+            },
+            keyword_shard::testing::seed_postings,
+            op_budget::{CountedStorage, SUBREQUEST_CAP},
+            storage::memory::MemoryStorage,
+            DEFAULT_N_SHARDS,
+        },
+        lexer::{document::DEFAULT_TITLE_BOOST, simple::BIGRAM_BOOST, Token, MAX_KEYWORD_CHARS},
+    };
+
+    fn run_query(store: &MemoryStorage, index: &str, query: &str) -> Vec<SearchResultRow> {
+        let tokens: Vec<Token> = StringTokenizer::tokenize(query).unwrap();
+        let ast = StringTokenizer::parse(tokens).unwrap();
+        let mut lexer = QueryLexer::direct(ast, store, DEFAULT_N_SHARDS);
+        block_on(lexer.query(index)).unwrap()
+    }
+
+    fn doc_ids(rows: &[SearchResultRow]) -> Vec<&str> {
+        rows.iter().map(|row| row.doc_id.as_str()).collect()
+    }
+
+    fn seeded_store() -> MemoryStorage {
+        let store = MemoryStorage::default();
+        let n = DEFAULT_N_SHARDS;
+        seed_postings(
+            &store,
+            "idx",
+            n,
+            "ocean",
+            &[("a", 0.9), ("b", 0.5), ("c", 0.3)],
+        );
+        seed_postings(&store, "idx", n, "storm", &[("b", 0.7), ("d", 0.6)]);
+        seed_postings(&store, "idx", n, "tropical", &[("c", 0.8)]);
+        store
+    }
+
+    /// A store with postings for `n` keywords, and the query matching any of them
+    fn wide_query(n: usize) -> (MemoryStorage, Expr) {
+        let store = MemoryStorage::default();
+        let keywords: Vec<String> = (0..n).map(|i| format!("term{}", i)).collect();
+        for keyword in &keywords {
+            seed_postings(&store, "idx", DEFAULT_N_SHARDS, keyword, &[("a", 0.5)]);
+        }
+        let tokens = StringTokenizer::tokenize(&keywords.join(" || ")).unwrap();
+        (store, StringTokenizer::parse(tokens).unwrap())
+    }
+
+    /// The gets and lists made by a query, with `budget`, of `n` keywords
+    fn budgeted_reads(
+        n: usize,
+        budget: QueryBudget,
+        slow: bool,
+    ) -> (usize, Vec<SearchResultRow>, Option<BudgetExceeded>) {
+        let (store, ast) = wide_query(n);
+        if slow {
+            store.slow_reads(std::time::Duration::from_millis(2));
+        }
+        let before = store.counts();
+        let mut lexer = QueryLexer::direct(ast, &store, DEFAULT_N_SHARDS)
+            .with_fuzzy(true)
+            .with_budget(budget);
+        let rows = block_on(lexer.query("idx")).unwrap();
+        let after = store.counts();
+        let reads = after.gets + after.lists - before.gets - before.lists;
+        (reads, rows, lexer.budget_exceeded())
+    }
+
+    #[test]
+    fn test_budget_stops_preload_between_rounds() {
+        let (round_cost, _, exceeded) =
+            budgeted_reads(PRELOAD_ROUND, QueryBudget::UNLIMITED, false);
+        assert!(exceeded.is_none());
+
+        // Once the first round spends the budget, later keywords are never read
+        let budget = QueryBudget {
+            max_ms: u64::MAX,
+            max_ops: 1,
+        };
+        let (reads, rows, exceeded) = budgeted_reads(PRELOAD_ROUND * 2, budget, false);
+        assert_eq!(exceeded, Some(BudgetExceeded::Ops));
+        assert_eq!(reads, round_cost);
+        // The first round's keywords still match
+        assert_eq!(doc_ids(&rows), vec!["a"]);
+        assert_eq!(rows[0].keywords.len(), PRELOAD_ROUND);
+    }
+
+    #[test]
+    fn test_slow_store_exceeds_time_budget() {
+        let (round_cost, _, _) = budgeted_reads(PRELOAD_ROUND, QueryBudget::UNLIMITED, false);
+
+        let budget = QueryBudget {
+            max_ms: 1,
+            max_ops: usize::MAX,
+        };
+        let (reads, rows, exceeded) = budgeted_reads(PRELOAD_ROUND * 3, budget, true);
+        assert_eq!(exceeded, Some(BudgetExceeded::Time));
+        assert_eq!(reads, round_cost);
+        assert_eq!(rows[0].keywords.len(), PRELOAD_ROUND);
+    }
+
+    #[test]
+    fn test_subrequests_count_every_store_operation() {
+        let (store, ast) = wide_query(PRELOAD_ROUND * 4);
+        let subrequests = OpBudget::new(SUBREQUEST_CAP);
+        let counted = CountedStorage::new(&store, subrequests.clone());
+        let mut lexer = QueryLexer::direct(ast, &counted, DEFAULT_N_SHARDS)
+            .with_case_insensitive(true)
+            .with_subrequests(subrequests.clone());
+        let before = store.counts();
+        let rows = block_on(lexer.query("idx")).unwrap();
+        assert_eq!(rows[0].keywords.len(), PRELOAD_ROUND * 4);
+        assert!(lexer.budget_exceeded().is_none());
+
+        let after = store.counts();
+        let made = after.gets + after.puts + after.deletes + after.lists
+            - (before.gets + before.puts + before.deletes + before.lists);
+        assert!(made > PRELOAD_ROUND * 4);
+        assert_eq!(subrequests.used(), made);
+    }
+
+    #[test]
+    fn test_subrequests_near_the_cap_return_partial_results() {
+        let (round_cost, _, _) = budgeted_reads(PRELOAD_ROUND, QueryBudget::UNLIMITED, false);
+
+        // Every round costs the same, so 19 rounds come within 5% of 20 rounds' cost
+        let (store, ast) = wide_query(PRELOAD_ROUND * 25);
+        let subrequests = OpBudget::new(round_cost * 20);
+        let counted = CountedStorage::new(&store, subrequests.clone());
+        let mut lexer = QueryLexer::direct(ast, &counted, DEFAULT_N_SHARDS)
+            .with_subrequests(subrequests.clone())
+            .with_budget(QueryBudget::UNLIMITED);
+        let rows = block_on(lexer.query("idx")).unwrap();
+        assert_eq!(lexer.budget_exceeded(), Some(BudgetExceeded::Subrequests));
+        assert_eq!(rows[0].keywords.len(), PRELOAD_ROUND * 19);
+        assert_eq!(subrequests.used(), round_cost * 19);
+        assert!(!subrequests.exceeded());
+    }
+
+    #[test]
+    fn test_trace_records_shard_layout() {
+        let store = seeded_store();
+        let trace = ReadTrace::default();
+        let tokens = StringTokenizer::tokenize("ocean && storm").unwrap();
+        let ast = StringTokenizer::parse(tokens).unwrap();
+        let mut lexer = QueryLexer::direct(ast, &store, DEFAULT_N_SHARDS).with_trace(Some(&trace));
+        block_on(lexer.query("idx")).unwrap();
+        let diagnostics = trace.finish();
+
+        let traced: Vec<(&str, usize)> = diagnostics
+            .keywords
+            .iter()
+            .map(|kw| {
+                (
+                    kw.keyword.as_str(),
+                    kw.shards.iter().map(|s| s.postings).sum(),
+                )
+            })
+            .collect();
+        assert_eq!(traced, vec![("ocean", 3), ("storm", 2)]);
+
+        let mut bytes = 0;
+        for keyword in &diagnostics.keywords {
+            assert_eq!(keyword.prefix, format!("idx:kw:{}:", keyword.keyword));
+            let stored: Vec<String> = store
+                .keys()
+                .into_iter()
+                .filter(|key| key.starts_with(&keyword.prefix))
+                .collect();
+            let listed: Vec<String> = keyword.shards.iter().map(|s| s.key.clone()).collect();
+            assert_eq!(listed, stored);
+            assert!(keyword.shards.iter().all(|s| s.ts == Some(1)));
+            assert_eq!(keyword.newest_ts, Some(1));
+            for key in stored {
+                bytes += block_on(store.get(&key)).unwrap().unwrap().len();
+            }
+        }
+        assert_eq!(diagnostics.bytes_read, bytes);
+        assert_eq!(diagnostics.durable_requests, 0);
+    }
+
+    #[test]
+    fn test_query_keywords_are_not_decoded() {
+        let store = MemoryStorage::default();
+        let n = DEFAULT_N_SHARDS;
+        seed_postings(&store, "idx", n, "c%20sharp", &[("a", 0.9)]);
+        seed_postings(&store, "idx", n, "c sharp", &[("b", 0.8)]);
+        seed_postings(&store, "idx", n, "c++", &[("c", 0.7)]);
+
+        // The query string was already decoded once by the HTTP layer
+        assert_eq!(doc_ids(&run_query(&store, "idx", "c%20sharp")), vec!["a"]);
+        assert_eq!(doc_ids(&run_query(&store, "idx", "c++")), vec!["c"]);
+        assert!(run_query(&store, "idx", "c%2B%2B").is_empty());
+
+        let ast = StringTokenizer::parse(StringTokenizer::tokenize("c%20sharp || c++").unwrap());
+        let lexer = QueryLexer::direct(ast.unwrap(), &store, n);
+        assert_eq!(lexer.keywords(), vec!["c%20sharp", "c++"]);
+    }
+
+    #[test]
+    fn test_query_and_or_not() {
+        let store = seeded_store();
+        assert_eq!(
+            doc_ids(&run_query(&store, "idx", "ocean && storm")),
+            vec!["b"]
+        );
+        assert_eq!(
+            doc_ids(&run_query(&store, "idx", "storm || tropical")),
+            vec!["c", "b", "d"]
+        );
+        assert_eq!(
+            doc_ids(&run_query(&store, "idx", "ocean && ~(storm || tropical)")),
+            vec!["a"]
+        );
+    }
+
+    #[test]
+    fn test_parse_doc_id_terms() {
+        let parse = |query| {
+            let ast = StringTokenizer::parse(StringTokenizer::tokenize(query).unwrap()).unwrap();
+            format!("{}", ast)
+        };
+        assert_eq!(
+            parse("id:a || id:\"b c\" && storm"),
+            "(id:a || (id:\"b c\" && storm))"
+        );
+        assert!(matches!(
+            StringTokenizer::tokenize("id:\"a").map(|_| ()),
+            Err(QueryError::UnclosedQuote)
+        ));
+        // Quoting the whole term, or leaving out the ID, keeps it a keyword
+        let tokens = StringTokenizer::tokenize("\"id:a\" id:").unwrap();
+        assert!(
+            matches!(&tokens[..], [Token::Word(a), Token::Word(b)] if a == "id:a" && b == "id:")
+        );
+        let ast = StringTokenizer::parse(StringTokenizer::tokenize("\"id:a\"").unwrap()).unwrap();
+        assert_eq!(
+            QueryLexer::<MemoryStorage>::collect_keywords(&ast),
+            vec!["id:a"]
+        );
+    }
+
+    #[test]
+    fn test_empty_and_overlong_words_are_rejected() {
+        for query in ["\"\"", "ocean || \"   \"", "(\"\t\")"] {
+            assert!(
+                matches!(StringTokenizer::tokenize(query), Err(QueryError::EmptyWord)),
+                "{}",
+                query
+            );
+        }
+        let long = "a".repeat(MAX_KEYWORD_CHARS + 1);
+        assert!(matches!(
+            StringTokenizer::tokenize(&format!("ocean && \"{}\"", long)),
+            Err(QueryError::KeywordTooLong(chars)) if chars == MAX_KEYWORD_CHARS + 1
+        ));
+        assert!(StringTokenizer::tokenize(&"é".repeat(MAX_KEYWORD_CHARS)).is_ok());
+
+        // Quoted words are trimmed
+        let ast =
+            StringTokenizer::parse(StringTokenizer::tokenize("\" ocean \"").unwrap()).unwrap();
+        assert_eq!(ast.to_string(), "ocean");
+
+        // Words that skip the tokenizer are checked as the lexer is built
+        let empty = Expr::from_ast_json(r#"{"or":[{"word":"ocean"},{"word":" "}]}"#).unwrap();
+        assert!(matches!(check_ast(&empty), Err(QueryError::EmptyWord)));
+        let phrase = rewrite(&vec!["ocean"; 30].join(" "), SimpleMode::Phrase).unwrap();
+        assert!(matches!(
+            check_ast(&phrase.expr),
+            Err(QueryError::KeywordTooLong(_))
+        ));
+        assert!(check_ast(&Expr::DocId("".into())).is_ok());
+    }
+
+    #[test]
+    fn test_negated_queries_are_unbounded() {
+        let bounded = |query| {
+            let ast = StringTokenizer::parse(StringTokenizer::tokenize(query).unwrap()).unwrap();
+            QueryLexer::<MemoryStorage>::anchored(&ast)
+        };
+        assert!(bounded("ocean && ~storm"));
+        assert!(bounded("id:a || ocean"));
+        assert!(!bounded("~storm"));
+        assert!(!bounded("ocean || ~storm"));
+    }
+
+    #[test]
+    fn test_doc_id_terms_restrict_and_exclude() {
+        let store = seeded_store();
+        // AND binds tighter than OR, and an ID alone matches with a score of 1.0
+        let rows = run_query(&store, "idx", "id:a || id:b && storm");
+        assert_eq!(doc_ids(&rows), vec!["a", "b"]);
+        assert_eq!(rows[0].score, 1.0);
+        assert!(rows[0].keywords.is_empty());
+        assert!((rows[1].score - 0.7).abs() < 1e-9);
+
+        // Combined with keywords, IDs only restrict, leaving scores to the keywords
+        let rows = run_query(&store, "idx", "(id:a || id:c) && ocean");
+        assert_eq!(doc_ids(&rows), vec!["a", "c"]);
+        assert!((rows[1].score - 0.3).abs() < 1e-9);
+        assert_eq!((rows[1].matched_terms, rows[1].total_terms), (1, 1));
+
+        assert_eq!(
+            doc_ids(&run_query(&store, "idx", "ocean && ~(id:a || id:b)")),
+            vec!["c"]
+        );
+        assert_eq!(
+            doc_ids(&run_query(&store, "idx", "ocean && ~id:nope")),
+            vec!["a", "b", "c"]
+        );
+
+        // An unknown ID matches until hydration finds its document doesn't exist
+        let rows = run_query(&store, "idx", "id:nope || storm");
+        assert_eq!(doc_ids(&rows), vec!["nope", "b", "d"]);
+        assert!(doc_ids(&run_query(&store, "idx", "id:nope && storm")).is_empty());
+    }
+
+    #[test]
+    fn test_query_scores_and_orders_rows() {
+        let store = seeded_store();
+        let rows = run_query(&store, "idx", "ocean || storm");
+        assert_eq!(doc_ids(&rows), vec!["a", "b", "d", "c"]);
+
+        let b = rows.iter().find(|row| row.doc_id == "b").unwrap();
+        assert!((b.score - 0.6).abs() < 1e-9);
+        assert_eq!(b.keywords.len(), 2);
+    }
+
+    #[test]
+    fn test_overlapping_or_branches_count_terms_once() {
+        let store = seeded_store();
+        let query = "(ocean && storm) || (ocean && tropical) || missing";
+        let tokens: Vec<Token> = StringTokenizer::tokenize(query).unwrap();
+        let ast = StringTokenizer::parse(tokens).unwrap();
+        let mut lexer =
+            QueryLexer::direct(ast, &store, DEFAULT_N_SHARDS).with_scoring(ScoringMode::Coverage);
+        let rows = block_on(lexer.query("idx")).unwrap();
+        assert_eq!(doc_ids(&rows), vec!["b", "c"]);
+
+        // ocean is in both branches, but counts once in the query and the row
+        let terms: Vec<_> = rows
+            .iter()
+            .map(|row| (row.matched_terms, row.total_terms))
+            .collect();
+        assert_eq!(terms, vec![(2, 4), (2, 4)]);
+
+        // Coverage scoring divides by the same counts the row reports
+        assert!((rows[1].score - 0.55 * 2.0 / 4.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_query_unknown_keyword_matches_nothing() {
+        let store = seeded_store();
+        assert!(run_query(&store, "idx", "volcano").is_empty());
+        assert!(run_query(&store, "other", "ocean").is_empty());
+    }
+
+    #[test]
+    fn test_unmatched_keywords_are_warned() {
+        use crate::lexer::warnings::{SearchWarning, WarningCode};
+
+        let store = seeded_store();
+        let query = "ocean && (volcano || storm) && ~volcano && ~lava";
+        let ast = StringTokenizer::parse(StringTokenizer::tokenize(query).unwrap()).unwrap();
+        let mut lexer = QueryLexer::direct(ast, &store, DEFAULT_N_SHARDS);
+        assert_eq!(doc_ids(&block_on(lexer.query("idx")).unwrap()), vec!["b"]);
+
+        // Each unknown keyword is warned about once, however often it appears
+        let warned: Vec<&SearchWarning> = lexer.warnings().iter().collect();
+        assert_eq!(warned.len(), 2);
+        assert_eq!(warned[0].code, WarningCode::KeywordNoMatches);
+        assert_eq!(warned[0].detail.as_deref(), Some("volcano"));
+        assert_eq!(warned[1].detail.as_deref(), Some("lava"));
+        assert_eq!(warned[0].message, "'volcano' matched no documents");
+
+        // A fuzzy correction replaces the keyword instead
+        let mut lexer = fuzzy_lexer(&store, "ocaen");
+        block_on(lexer.query("idx")).unwrap();
+        assert!(lexer.warnings().is_empty());
+
+        // Keywords the budget left unread aren't known to match nothing
+        let (store, ast) = wide_query(PRELOAD_ROUND * 3);
+        let mut lexer =
+            QueryLexer::direct(ast, &store, DEFAULT_N_SHARDS).with_budget(QueryBudget {
+                max_ms: u64::MAX,
+                max_ops: 1,
+            });
+        block_on(lexer.query("idx")).unwrap();
+        assert!(lexer.budget_exceeded().is_some());
+        assert!(lexer.warnings().is_empty());
+    }
+
+    fn fuzzy_lexer<'s>(store: &'s MemoryStorage, query: &str) -> QueryLexer<'s, MemoryStorage> {
+        let ast = StringTokenizer::parse(StringTokenizer::tokenize(query).unwrap()).unwrap();
+        QueryLexer::direct(ast, store, DEFAULT_N_SHARDS).with_fuzzy(true)
+    }
+
+    #[test]
+    fn test_fuzzy_query_substitutes_closest_keyword() {
+        let store = seeded_store();
+        let mut lexer = fuzzy_lexer(&store, "ocaen && storm");
+        let rows = block_on(lexer.query("idx")).unwrap();
+        assert_eq!(doc_ids(&rows), vec!["b"]);
+        assert_eq!(rows[0].keywords[0].0, "ocean");
+        assert_eq!(
+            lexer.corrections(),
+            &[Correction {
+                original: "ocaen".into(),
+                used: "ocean".into()
+            }]
+        );
+
+        // Keywords that match something, or are too far from anything, are left alone
+        let mut lexer = fuzzy_lexer(&store, "storm || volcano");
+        assert_eq!(
+            doc_ids(&block_on(lexer.query("idx")).unwrap()),
+            vec!["b", "d"]
+        );
+        assert!(lexer.corrections().is_empty());
+    }
+
+    #[test]
+    fn test_fuzzy_ties_prefer_document_frequency() {
+        let store = seeded_store();
+        seed_postings(&store, "idx", DEFAULT_N_SHARDS, "card", &[("a", 0.5)]);
+        seed_postings(
+            &store,
+            "idx",
+            DEFAULT_N_SHARDS,
+            "cart",
+            &[("b", 0.5), ("c", 0.5)],
+        );
+        let mut lexer = fuzzy_lexer(&store, "carx");
+        assert_eq!(
+            doc_ids(&block_on(lexer.query("idx")).unwrap()),
+            vec!["b", "c"]
+        );
+        assert_eq!(lexer.corrections()[0].used, "cart");
+    }
+
+    #[test]
+    fn test_suggest_does_not_evaluate() {
+        let store = seeded_store();
+        seed_postings(&store, "idx", DEFAULT_N_SHARDS, "café", &[("e", 0.5)]);
+        let mut lexer = fuzzy_lexer(&store, "cafe || storm");
+        let corrections = block_on(lexer.suggest("idx")).unwrap();
+        assert_eq!(corrections.len(), 1);
+        assert_eq!(corrections[0].used, "café");
+    }
+
+    /// Postings stored under the casings YAKE may have produced for one keyword
+    fn mixed_case_store() -> MemoryStorage {
+        let store = seeded_store();
+        let n = DEFAULT_N_SHARDS;
+        seed_postings(&store, "idx", n, "Ocean", &[("a", 0.4), ("e", 0.6)]);
+        seed_postings(&store, "idx", n, "OCEAN", &[("f", 0.2)]);
+        seed_postings(&store, "idx", n, "Oceanic", &[("g", 0.9)]);
+        seed_postings(&store, "idx", n, "Storm", &[("e", 0.5)]);
+        store
+    }
+
+    fn case_insensitive_lexer<'s>(
+        store: &'s MemoryStorage,
+        query: &str,
+    ) -> QueryLexer<'s, MemoryStorage> {
+        let ast = StringTokenizer::parse(StringTokenizer::tokenize(query).unwrap()).unwrap();
+        QueryLexer::direct(ast, store, DEFAULT_N_SHARDS).with_case_insensitive(true)
+    }
+
+    #[test]
+    fn test_case_insensitive_merges_casing_variants() {
+        let store = mixed_case_store();
+        assert_eq!(
+            doc_ids(&run_query(&store, "idx", "ocean")),
+            vec!["a", "b", "c"]
+        );
+
+        let mut lexer = case_insensitive_lexer(&store, "ocean");
+        let rows = block_on(lexer.query("idx")).unwrap();
+        assert_eq!(doc_ids(&rows), vec!["a", "e", "b", "c", "f"]);
+        // A document under several casings keeps its best score
+        assert_eq!(rows[0].score, 0.9);
+        assert_eq!(
+            lexer.expanded_query().as_deref(),
+            Some("((ocean || OCEAN) || Ocean)")
+        );
+
+        let mut lexer = case_insensitive_lexer(&store, "STORM && ocean");
+        assert_eq!(
+            doc_ids(&block_on(lexer.query("idx")).unwrap()),
+            vec!["b", "e"]
+        );
+        assert_eq!(
+            lexer.expanded_query().as_deref(),
+            Some("(((STORM || Storm) || storm) && ((ocean || OCEAN) || Ocean))")
+        );
+    }
+
+    #[test]
+    fn test_case_insensitive_without_variants() {
+        let store = seeded_store();
+        let mut lexer = case_insensitive_lexer(&store, "tropical && ~nothing");
+        assert_eq!(doc_ids(&block_on(lexer.query("idx")).unwrap()), vec!["c"]);
+        assert_eq!(
+            lexer.expanded_query().as_deref(),
+            Some("(tropical && ~(nothing))")
+        );
+        assert_eq!(fuzzy_lexer(&store, "ocean").expanded_query(), None);
+    }
+
+    #[test]
+    fn test_text_matches_bigrams_with_a_boost() {
+        let store = MemoryStorage::default();
+        let n = DEFAULT_N_SHARDS;
+        seed_postings(
+            &store,
+            "idx",
+            n,
+            "rust",
+            &[("a", 0.5), ("b", 0.5), ("c", 0.5)],
+        );
+        seed_postings(&store, "idx", n, "async runtime", &[("a", 0.5)]);
+        seed_postings(&store, "idx", n, "async", &[("b", 0.5)]);
+        seed_postings(&store, "idx", n, "runtime", &[("b", 0.5)]);
+        let text = |mode: SimpleMode| {
+            let query = rewrite("rust async runtime", mode).unwrap();
+            let mut lexer = QueryLexer::direct(query.expr, &store, n).with_rewrite(query.boosts);
+            (
+                block_on(lexer.query("idx")).unwrap(),
+                lexer.expanded_query(),
+            )
+        };
+
+        let (rows, expanded) = text(SimpleMode::All);
+        assert_eq!(doc_ids(&rows), vec!["a", "b"]);
+        assert!((rows[0].score - 0.5 * (1.0 + BIGRAM_BOOST) / 2.0).abs() < 1e-9);
+        assert_eq!(rows[1].score, 0.5);
+        assert_eq!(
+            expanded.as_deref(),
+            Some("((((rust && async) && runtime) || (\"rust async\" && runtime)) || (rust && \"async runtime\"))")
+        );
+        assert_eq!(doc_ids(&text(SimpleMode::Any).0), vec!["a", "b", "c"]);
+        assert!(text(SimpleMode::Phrase).0.is_empty());
+    }
+
+    #[test]
+    fn test_query_indexed_documents() {
+        let store = MemoryStorage::default();
+        let ocean = index_text(
+            &store,
+            "idx",
+            "ocean",
+            "Ocean waves crash onto the sandy beach.",
+        );
+        let forest = index_text(
+            &store,
+            "idx",
+            "forest",
+            "Tall pine trees fill the quiet forest.",
+        );
+
+        let keyword = &ocean.keywords.as_ref().unwrap()[0].0;
+        let rows = run_query(&store, "idx", &format!("\"{}\"", keyword));
+        assert_eq!(doc_ids(&rows), vec!["ocean"]);
+
+        let forest_keyword = &forest.keywords.as_ref().unwrap()[0].0;
+        let rows = run_query(
+            &store,
+            "idx",
+            &format!("\"{}\" || \"{}\"", keyword, forest_keyword),
+        );
+        assert_eq!(rows.len(), 2);
+    }
+
+    #[test]
+    fn test_title_boost_ranks_title_matches_higher() {
+        let corpus = [
+            (
+                "keeper",
+                serde_json::json!({
+                    "title": "Lighthouses",
+                    "body": "Islands keepers tended the lamps through long winter storms on remote islands."
+                }),
+            ),
+            (
+                "ferries",
+                serde_json::json!({
+                    "title": "Remote islands",
+                    "body": "Ferries run twice a week, weather permitting, carrying supplies out to the islands."
+                }),
+            ),
+        ];
+        let ranking = |title_boost: f64| {
+            let store = MemoryStorage::default();
+            let options = IndexingOptions {
+                title_boost,
+                ..IndexingOptions::default()
+            };
+            for (id, envelope) in corpus.clone() {
+                index_envelope(&store, "idx", id, envelope, &options);
+            }
+            run_query(&store, "idx", "islands")
+        };
+
+        // Scored as they were picked, the body that dwells on islands ranks first
+        let unboosted = ranking(1.0);
+        assert_eq!(doc_ids(&unboosted), vec!["keeper", "ferries"]);
+        let boosted = ranking(DEFAULT_TITLE_BOOST);
+        assert_eq!(doc_ids(&boosted), vec!["ferries", "keeper"]);
+        assert_eq!(boosted[0].score, 1.0);
+        assert_eq!(boosted[1].score, unboosted[0].score);
+    }
+
+    /// A pseudo-random query of the seeded store's keywords and documents, and of
+    /// ones it doesn't hold, at most `depth` operators deep
+    fn random_expr(seed: &mut u64, depth: u32) -> Expr {
+        *seed ^= *seed << 13;
+        *seed ^= *seed >> 7;
+        *seed ^= *seed << 17;
+        let pick = *seed % if depth == 0 { 2 } else { 5 };
+        let leaf = (*seed >> 8) as usize;
+        match pick {
+            0 => Expr::Word(["ocean", "storm", "tropical", "absent"][leaf % 4].to_string()),
+            1 => Expr::DocId(["a", "b", "d", "zz"][leaf % 4].to_string()),
+            2 => Expr::Not(Box::new(random_expr(seed, depth - 1))),
+            3 => Expr::And(
+                Box::new(random_expr(seed, depth - 1)),
+                Box::new(random_expr(seed, depth - 1)),
+            ),
+            _ => Expr::Or(
+                Box::new(random_expr(seed, depth - 1)),
+                Box::new(random_expr(seed, depth - 1)),
+            ),
+        }
+    }
+
+    #[test]
+    fn test_ast_json_evaluates_as_query_string() {
+        let store = seeded_store();
+        let mut seed = 0x9e37_79b9_7f4a_7c15;
+        for _ in 0..200 {
+            let query = random_expr(&mut seed, 3).to_string();
+            let parsed =
+                StringTokenizer::parse(StringTokenizer::tokenize(&query).unwrap()).unwrap();
+            let ast = Expr::from_ast_json(&parsed.to_ast_json()).unwrap();
+            assert_eq!(ast.to_string(), parsed.to_string());
+
+            let mut lexer = QueryLexer::direct(ast, &store, DEFAULT_N_SHARDS);
+            let from_ast = block_on(lexer.query("idx")).unwrap();
+            let from_string = run_query(&store, "idx", &query);
+            let scored = |rows: &[SearchResultRow]| -> Vec<(String, f64)> {
+                rows.iter()
+                    .map(|row| (row.doc_id.clone(), row.score))
+                    .collect()
+            };
+            assert_eq!(scored(&from_ast), scored(&from_string), "{}", query);
+        }
+    }
+
+    #[test]
+    fn test_query_ids_rank_as_query() {
+        let store = seeded_store();
+        for query in [
+            "ocean || storm || tropical",
+            "ocean && ~storm",
+            "id:d || ocean",
+        ] {
+            let ranked: Vec<(String, f64)> = run_query(&store, "idx", query)
+                .into_iter()
+                .map(|row| (row.doc_id, row.score))
+                .collect();
+            let ast = StringTokenizer::parse(StringTokenizer::tokenize(query).unwrap()).unwrap();
+            let mut lexer = QueryLexer::direct(ast, &store, DEFAULT_N_SHARDS);
+            assert_eq!(
+                block_on(lexer.query_ids("idx")).unwrap(),
+                ranked,
+                "query {}",
+                query
+            );
+        }
+
+        // A rewritten query's boosts apply as they do to rows
+        let query = rewrite("ocean storm", SimpleMode::Any).unwrap();
+        let mut lexer = QueryLexer::direct(query.expr.clone(), &store, DEFAULT_N_SHARDS)
+            .with_rewrite(query.boosts.clone());
+        let rows = block_on(lexer.query("idx")).unwrap();
+        let mut lexer =
+            QueryLexer::direct(query.expr, &store, DEFAULT_N_SHARDS).with_rewrite(query.boosts);
+        let ids = block_on(lexer.query_ids("idx")).unwrap();
+        assert_eq!(ids.len(), rows.len());
+        for ((id, score), row) in ids.iter().zip(&rows) {
+            assert_eq!((id, *score), (&row.doc_id, row.score));
+        }
+    }
+
+    /// `cargo test -p edgesearch-api --lib ids_only -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn bench_ids_only() {
+        use std::time::{Duration, Instant};
+
+        let store = MemoryStorage::default();
+        let ast = StringTokenizer::parse(StringTokenizer::tokenize("k0 || k1").unwrap()).unwrap();
+        let lexer = QueryLexer::direct(ast, &store, DEFAULT_N_SHARDS);
+        let synthetic = || -> DocumentMatches {
+            (0..100_000)
+                .map(|d| {
+                    let keywords = (0..8)
+                        .map(|k| (format!("keyword-{}", k), (d % 97) as f64 / 97.0))
+                        .collect();
+                    (format!("d{}", d), keywords)
+                })
+                .collect()
+        };
+
+        let (mut rows_time, mut ids_time) = (Duration::ZERO, Duration::ZERO);
+        for _ in 0..5 {
+            let matches = synthetic();
+            let started = Instant::now();
+            let mut rows = lexer.rows(&matches);
+            QueryLexer::<MemoryStorage>::sort_rows(&mut rows);
+            rows_time += started.elapsed();
+            assert_eq!(rows.len(), 100_000);
+
+            let matches = synthetic();
+            let started = Instant::now();
+            let ids = lexer.ranked_ids(matches);
+            ids_time += started.elapsed();
+            assert_eq!(ids.len(), 100_000);
+        }
+        println!("rows: {:?}, ids: {:?}", rows_time, ids_time);
+        assert!(ids_time < rows_time);
+    }
+}
@@ -6,12 +6,48 @@
 
 use std::{collections::HashMap, fmt::Display};
 
+use crate::lexer::tokenizer::{StringTokenizer, Tokenable};
+
 /// Type alias for document matches: [`HashMap<doc_id, Vec<(keyword, score)>>`]
 type DocumentMatches = HashMap<String, Vec<(String, f64)>>;
 
 /// Type alias for keyword cache: HashMap<keyword, Vec<(doc_id, score)>>
 type KeywordCache = HashMap<String, Vec<(String, f64)>>;
 
+/// The prefix of a bare or quoted term naming a document ID, e.g. `id:abc123`. A
+/// fully quoted `"id:abc123"` is still a keyword.
+pub const DOC_ID_PREFIX: &str = "id:";
+
+/// The longest keyword a query may name. Indexing extracts keywords of a few words,
+/// well within this, so a longer one can't match anything.
+pub const MAX_KEYWORD_CHARS: usize = 128;
+
+/// Reject a keyword that is empty or only whitespace, whose shard prefix would list
+/// every keyword of the index, or that is longer than [`MAX_KEYWORD_CHARS`]
+pub fn check_keyword(keyword: &str) -> Result<(), QueryError> {
+    if keyword.trim().is_empty() {
+        return Err(QueryError::EmptyWord);
+    }
+    match keyword.chars().count() {
+        chars if chars > MAX_KEYWORD_CHARS => Err(QueryError::KeywordTooLong(chars)),
+        _ => Ok(()),
+    }
+}
+
+/// [`check_keyword`] every word of `ast`, for the words of an AST or a search box's
+/// phrase, which don't pass through the tokenizer
+pub fn check_ast(ast: &Expr) -> Result<(), QueryError> {
+    match ast {
+        Expr::Word(word) => check_keyword(word),
+        Expr::DocId(_) => Ok(()),
+        Expr::Not(inner) => check_ast(inner),
+        Expr::And(left, right) | Expr::Or(left, right) => {
+            check_ast(left)?;
+            check_ast(right)
+        }
+    }
+}
+
 /// Describes an error that occurred during query parsing or execution
 #[derive(thiserror::Error, Debug)]
 pub enum QueryError {
@@ -21,18 +57,30 @@ pub enum QueryError {
     UnexpectedEof,
     #[error("Unclosed quoted string")]
     UnclosedQuote,
+    /// A backslash in a quoted word before something other than `"` or `\`, with the
+    /// byte offset of the backslash
+    #[error("Invalid escape '\\{0}' at offset {1}, only \\\" and \\\\ are escapes in quotes")]
+    InvalidEscape(char, usize),
     #[error("Empty query")]
     EmptyQuery,
     #[error("Parse error: {0:?}")]
     InvalidQuery(String, Option<Expr>),
     #[error("Missing closing parenthesis")]
     MissingClosingParen,
+    #[error("Invalid query AST: {0}")]
+    InvalidAst(String),
+    #[error("Keywords can't be empty or only whitespace")]
+    EmptyWord,
+    #[error("Keyword is {0} characters, longer than the {MAX_KEYWORD_CHARS} character limit")]
+    KeywordTooLong(usize),
 }
 
 /// Describes an AST token in the search language
 #[derive(Clone)]
 pub enum Token {
     Word(String),
+    /// An `id:` term, naming a document rather than a keyword
+    DocId(String),
     And,
     Or,
     Not,
@@ -41,18 +89,38 @@ pub enum Token {
 }
 
 /// Describes an expression node in the query AST
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Expr {
     Word(String),
+    /// Exactly the named document, if it exists
+    DocId(String),
     Not(Box<Expr>),
     And(Box<Expr>, Box<Expr>),
     Or(Box<Expr>, Box<Expr>),
 }
 
+impl Expr {
+    /// Parse a query string, as [`Display`] writes one
+    pub fn parse(query: &str) -> Result<Expr, QueryError> {
+        StringTokenizer::parse(StringTokenizer::tokenize(query)?)
+            .ok_or_else(|| QueryError::InvalidQuery(query.to_string(), None))
+    }
+}
+
+/// The query string of the expression, quoting and escaping words as the tokenizer
+/// reads them, so [`Expr::parse`] gives back the same tree. Only whitespace around a
+/// word is lost, since the tokenizer trims it.
 impl Display for Expr {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Expr::Word(word) => write!(f, "{}", word),
+            Expr::Word(word) if StringTokenizer::is_bare_word(word) => write!(f, "{}", word),
+            Expr::Word(word) => write!(f, "{}", StringTokenizer::quote(word)),
+            Expr::DocId(id)
+                if id.is_empty() || id.chars().any(StringTokenizer::is_reserved_char) =>
+            {
+                write!(f, "{}{}", DOC_ID_PREFIX, StringTokenizer::quote(id))
+            }
+            Expr::DocId(id) => write!(f, "{}{}", DOC_ID_PREFIX, id),
             Expr::Not(inner) => write!(f, "~({})", inner),
             Expr::And(left, right) => write!(f, "({} && {})", left, right),
             Expr::Or(left, right) => write!(f, "({} || {})", left, right),
@@ -60,7 +128,23 @@ impl Display for Expr {
     }
 }
 
+pub mod ast;
+pub mod budget;
+pub mod casing;
+pub mod collapse;
+pub mod contains;
 pub mod document;
+pub mod extractor;
+pub mod facets;
+pub mod filter;
+pub mod fuzzy;
+#[allow(clippy::module_inception)]
 pub mod lexer;
+pub mod normalize;
+pub mod plan;
+pub mod recency;
 pub mod scoring;
+pub mod simple;
+pub mod timings;
 pub mod tokenizer;
+pub mod warnings;
@@ -0,0 +1,146 @@
+//! Rescaling a search's final scores into [0, 1], so that a threshold like "only
+//! show matches above 0.5" means the same from one query to the next.
+//!
+//! `normalize=` picks how, relative to the matches being returned:
+//!
+//! - `minmax` maps the lowest score to 0 and the highest to 1
+//! - `softmax` gives each match its share of `exp(score / softmax_temperature)`, so
+//!   the scores sum to 1, and a lower temperature favours the best matches more
+//!
+//! Both keep the order of the matches. Scores are normalized last, after the limit,
+//! collapsing and any recency boost have used the raw scores, and each match keeps
+//! its raw score as `raw_score`.
+
+/// The temperature of `normalize=softmax` without `softmax_temperature=`
+pub const DEFAULT_SOFTMAX_TEMPERATURE: f64 = 1.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Normalization {
+    MinMax,
+    Softmax { temperature: f64 },
+}
+
+impl Normalization {
+    /// Parse the `normalize` and `softmax_temperature` parameters of a search,
+    /// `None` when scores are left as they are
+    pub fn parse(
+        method: Option<&str>,
+        temperature: Option<f64>,
+    ) -> Result<Option<Normalization>, String> {
+        match (method, temperature) {
+            (None, None) => Ok(None),
+            (Some("minmax"), None) => Ok(Some(Normalization::MinMax)),
+            (Some("softmax"), temperature) => match temperature {
+                Some(t) if !t.is_finite() || t <= 0.0 => {
+                    Err("softmax_temperature must be a positive number".to_string())
+                }
+                t => Ok(Some(Normalization::Softmax {
+                    temperature: t.unwrap_or(DEFAULT_SOFTMAX_TEMPERATURE),
+                })),
+            },
+            (None | Some("minmax"), Some(_)) => {
+                Err("softmax_temperature needs normalize=softmax".to_string())
+            }
+            (Some(other), _) => Err(format!(
+                "Unknown normalize '{}', expected minmax or softmax",
+                other
+            )),
+        }
+    }
+
+    /// `scores` rescaled into [0, 1], in the same order. When every score is the
+    /// same, min-max has no range to scale over and gives each a 1.
+    pub fn apply(&self, scores: &[f64]) -> Vec<f64> {
+        let max = scores.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+        match *self {
+            Normalization::MinMax => {
+                let min = scores.iter().copied().fold(f64::INFINITY, f64::min);
+                let range = max - min;
+                scores
+                    .iter()
+                    .map(|score| match range > 0.0 {
+                        true => (score - min) / range,
+                        false => 1.0,
+                    })
+                    .collect()
+            }
+            Normalization::Softmax { temperature } => {
+                // Shifted by the highest score, so no exponent overflows
+                let weights: Vec<f64> = scores
+                    .iter()
+                    .map(|score| ((score - max) / temperature).exp())
+                    .collect();
+                let total: f64 = weights.iter().sum();
+                weights.iter().map(|weight| weight / total).collect()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: &[f64], expected: &[f64]) {
+        assert_eq!(actual.len(), expected.len());
+        for (a, e) in actual.iter().zip(expected) {
+            assert!((a - e).abs() < 1e-9, "{:?} != {:?}", actual, expected);
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Normalization::parse(None, None), Ok(None));
+        assert_eq!(
+            Normalization::parse(Some("minmax"), None),
+            Ok(Some(Normalization::MinMax))
+        );
+        assert_eq!(
+            Normalization::parse(Some("softmax"), None),
+            Ok(Some(Normalization::Softmax { temperature: 1.0 }))
+        );
+        assert_eq!(
+            Normalization::parse(Some("softmax"), Some(0.1)),
+            Ok(Some(Normalization::Softmax { temperature: 0.1 }))
+        );
+        for (method, temperature) in [
+            (Some("zscore"), None),
+            (Some("softmax"), Some(0.0)),
+            (Some("softmax"), Some(f64::NAN)),
+            (Some("minmax"), Some(2.0)),
+            (None, Some(2.0)),
+        ] {
+            assert!(Normalization::parse(method, temperature).is_err());
+        }
+    }
+
+    #[test]
+    fn test_minmax() {
+        let minmax = Normalization::MinMax;
+        assert_close(&minmax.apply(&[0.9, 0.6, 0.3]), &[1.0, 0.5, 0.0]);
+        assert_close(&minmax.apply(&[0.42]), &[1.0]);
+        assert_close(&minmax.apply(&[0.5, 0.5, 0.5]), &[1.0, 1.0, 1.0]);
+        assert!(minmax.apply(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_softmax() {
+        let softmax = Normalization::Softmax { temperature: 1.0 };
+        let e = std::f64::consts::E;
+        let total = 1.0 + 1.0 / e + 1.0 / (e * e);
+        assert_close(
+            &softmax.apply(&[3.0, 2.0, 1.0]),
+            &[1.0 / total, 1.0 / e / total, 1.0 / (e * e) / total],
+        );
+        assert_close(&softmax.apply(&[0.42]), &[1.0]);
+        assert_close(&softmax.apply(&[0.5, 0.5, 0.5, 0.5]), &[0.25; 4]);
+        assert!(softmax.apply(&[]).is_empty());
+
+        // A lower temperature gives the best match more of the total
+        let sharp = Normalization::Softmax { temperature: 0.1 }.apply(&[0.9, 0.6]);
+        let flat = softmax.apply(&[0.9, 0.6]);
+        assert!(sharp[0] > flat[0] && sharp[0] > sharp[1]);
+        // Large scores don't overflow
+        assert_close(&softmax.apply(&[1000.0, 1000.0]), &[0.5, 0.5]);
+    }
+}
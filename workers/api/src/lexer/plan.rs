@@ -0,0 +1,502 @@
+//! Planning and evaluating a query AST once its keywords are preloaded. Each keyword
+//! is annotated with its posting count, so `AND` can evaluate its smaller side first
+//! and probe the other side's postings instead of materializing them.
+
+use std::collections::HashMap;
+
+use crate::lexer::{DocumentMatches, Expr, KeywordCache};
+
+/// A query AST annotated with what's known about each keyword from the cache
+#[derive(Debug, PartialEq)]
+pub enum Plan<'e> {
+    Word {
+        keyword: &'e str,
+        /// The number of documents the keyword matches
+        postings: usize,
+    },
+    /// Exactly the named document, whose existence is only checked on hydration
+    DocId(&'e str),
+    Not(Box<Plan<'e>>),
+    /// `positive && ~inner`, excluding `inner`'s documents from `positive`'s
+    AndNot {
+        positive: Box<Plan<'e>>,
+        inner: Box<Plan<'e>>,
+    },
+    And {
+        left: Box<Plan<'e>>,
+        right: Box<Plan<'e>>,
+        /// Whether the right side is expected to be smaller, and may be evaluated first
+        right_first: bool,
+    },
+    Or(Box<Plan<'e>>, Box<Plan<'e>>),
+}
+
+impl<'e> Plan<'e> {
+    pub fn build(expr: &'e Expr, cache: &KeywordCache) -> Plan<'e> {
+        let build = |expr| Box::new(Plan::build(expr, cache));
+        match expr {
+            Expr::Word(keyword) => Plan::Word {
+                keyword,
+                postings: cache.get(keyword).map_or(0, Vec::len),
+            },
+            Expr::DocId(doc_id) => Plan::DocId(doc_id),
+            Expr::Not(inner) => Plan::Not(build(inner)),
+            Expr::And(positive, negated) if matches!(**negated, Expr::Not(_)) => {
+                let Expr::Not(inner) = &**negated else {
+                    unreachable!()
+                };
+                Plan::AndNot {
+                    positive: build(positive),
+                    inner: build(inner),
+                }
+            }
+            Expr::And(negated, positive) if matches!(**negated, Expr::Not(_)) => {
+                let Expr::Not(inner) = &**negated else {
+                    unreachable!()
+                };
+                Plan::AndNot {
+                    positive: build(positive),
+                    inner: build(inner),
+                }
+            }
+            Expr::And(left, right) => {
+                let (left, right) = (build(left), build(right));
+                let right_first =
+                    Plan::reorderable(&left, &right) && right.estimate() < left.estimate();
+                Plan::And {
+                    left,
+                    right,
+                    right_first,
+                }
+            }
+            Expr::Or(left, right) => Plan::Or(build(left), build(right)),
+        }
+    }
+
+    /// An upper bound on the number of documents the plan matches
+    pub fn estimate(&self) -> usize {
+        match self {
+            Plan::Word { postings, .. } => *postings,
+            Plan::DocId(_) => 1,
+            // Negation excludes from whatever was evaluated before it
+            Plan::Not(_) => usize::MAX,
+            Plan::AndNot { positive, .. } => positive.estimate(),
+            Plan::And { left, right, .. } => left.estimate().min(right.estimate()),
+            Plan::Or(left, right) => left.estimate().saturating_add(right.estimate()),
+        }
+    }
+
+    /// Whether the plan holds a bare `~`, which negates the documents of the node
+    /// evaluated just before it, making evaluation order observable
+    pub fn reads_previous(&self) -> bool {
+        match self {
+            Plan::Word { .. } | Plan::DocId(_) => false,
+            Plan::Not(_) => true,
+            Plan::AndNot { positive, inner } => positive.reads_previous() || inner.reads_previous(),
+            Plan::And { left, right, .. } | Plan::Or(left, right) => {
+                left.reads_previous() || right.reads_previous()
+            }
+        }
+    }
+
+    /// Whether the sides of an `AND` may be evaluated in either order, or skipped
+    /// in favour of probing their postings
+    fn reorderable(left: &Plan, right: &Plan) -> bool {
+        !left.reads_previous() && !right.reads_previous()
+    }
+}
+
+/// Evaluates a [`Plan`] against preloaded keyword postings
+pub struct Evaluator<'c> {
+    cache: &'c KeywordCache,
+    /// The documents matched by the most recently evaluated node, which a bare `~`
+    /// negates; only kept when the plan has one
+    previous: Option<DocumentMatches>,
+}
+
+impl<'c> Evaluator<'c> {
+    pub fn new(cache: &'c KeywordCache, plan: &Plan) -> Evaluator<'c> {
+        Evaluator {
+            cache,
+            previous: plan.reads_previous().then(HashMap::new),
+        }
+    }
+
+    pub fn evaluate(&mut self, plan: &Plan) -> DocumentMatches {
+        let matches = match plan {
+            Plan::Word { keyword, postings } => self.word(keyword, *postings),
+            // Matched by ID rather than by any keyword, so it lists none
+            Plan::DocId(doc_id) => HashMap::from([(doc_id.to_string(), vec![])]),
+            Plan::Not(inner) => {
+                let base = self
+                    .previous
+                    .as_mut()
+                    .map(std::mem::take)
+                    .unwrap_or_default();
+                let excluded = self.evaluate(inner);
+                Self::exclude(base, &excluded)
+            }
+            Plan::AndNot { positive, inner } => {
+                let base = self.evaluate(positive);
+                let excluded = self.evaluate(inner);
+                Self::exclude(base, &excluded)
+            }
+            Plan::And {
+                left,
+                right,
+                right_first,
+            } => self.and(left, right, *right_first),
+            Plan::Or(left, right) => {
+                let mut left = self.evaluate(left);
+                let right = self.evaluate(right);
+                Self::merge(&mut left, right);
+                left
+            }
+        };
+        if self.previous.is_some() {
+            self.previous = Some(matches.clone());
+        }
+        matches
+    }
+
+    fn word(&self, keyword: &str, postings: usize) -> DocumentMatches {
+        let mut matches = HashMap::with_capacity(postings);
+        for (doc_id, score) in self.cache.get(keyword).into_iter().flatten() {
+            matches.insert(doc_id.clone(), vec![(keyword.to_string(), *score)]);
+        }
+        matches
+    }
+
+    /// Intersect both sides, keeping each document's keywords in query order: the
+    /// left side's, then the right side's that aren't already listed
+    fn and(&mut self, left: &Plan, right: &Plan, right_first: bool) -> DocumentMatches {
+        if Plan::reorderable(left, right) {
+            let (smaller, larger, smaller_is_left) = match right_first {
+                true => (right, left, false),
+                false => (left, right, true),
+            };
+            let smaller = self.evaluate(smaller);
+            if let Plan::Word { keyword, .. } = larger {
+                return self.probe_word(smaller, keyword, smaller_is_left);
+            }
+            let larger = self.evaluate(larger);
+            return match smaller_is_left {
+                true => Self::intersect(smaller, larger),
+                false => Self::intersect(larger, smaller),
+            };
+        }
+
+        let left = self.evaluate(left);
+        let right = self.evaluate(right);
+        Self::intersect(left, right)
+    }
+
+    /// Intersect `matches` with a keyword's postings, without materializing them
+    fn probe_word(
+        &self,
+        matches: DocumentMatches,
+        keyword: &str,
+        matches_is_left: bool,
+    ) -> DocumentMatches {
+        let mut found: HashMap<&str, f64> = HashMap::new();
+        for (doc_id, score) in self.cache.get(keyword).into_iter().flatten() {
+            if matches.contains_key(doc_id) {
+                found.insert(doc_id, *score);
+            }
+        }
+
+        let mut intersection = HashMap::with_capacity(found.len());
+        for (doc_id, keywords) in matches {
+            let Some(score) = found.get(doc_id.as_str()) else {
+                continue;
+            };
+            let word = vec![(keyword.to_string(), *score)];
+            let merged = match matches_is_left {
+                true => Self::merge_keywords(keywords, word),
+                false => Self::merge_keywords(word, keywords),
+            };
+            intersection.insert(doc_id, merged);
+        }
+        intersection
+    }
+
+    /// Probe the smaller side against the larger, moving the keyword lists rather
+    /// than cloning them
+    fn intersect(left: DocumentMatches, right: DocumentMatches) -> DocumentMatches {
+        let smaller_is_left = left.len() <= right.len();
+        let (smaller, mut larger) = match smaller_is_left {
+            true => (left, right),
+            false => (right, left),
+        };
+
+        let mut intersection = HashMap::with_capacity(smaller.len());
+        for (doc_id, keywords) in smaller {
+            let Some(other) = larger.remove(&doc_id) else {
+                continue;
+            };
+            let merged = match smaller_is_left {
+                true => Self::merge_keywords(keywords, other),
+                false => Self::merge_keywords(other, keywords),
+            };
+            intersection.insert(doc_id, merged);
+        }
+        intersection
+    }
+
+    /// `left`'s keywords followed by `right`'s that aren't already listed
+    fn merge_keywords(
+        mut left: Vec<(String, f64)>,
+        right: Vec<(String, f64)>,
+    ) -> Vec<(String, f64)> {
+        for (keyword, score) in right {
+            if !left.iter().any(|(k, _)| *k == keyword) {
+                left.push((keyword, score));
+            }
+        }
+        left
+    }
+
+    /// Remove every document in `excluded` from `base`
+    fn exclude(base: DocumentMatches, excluded: &DocumentMatches) -> DocumentMatches {
+        base.into_iter()
+            .filter(|(doc_id, _)| !excluded.contains_key(doc_id))
+            .collect()
+    }
+
+    /// Merge `from` into `into`, adding the keywords each document doesn't list yet
+    fn merge(into: &mut DocumentMatches, from: DocumentMatches) {
+        into.reserve(from.len());
+        for (doc_id, keywords) in from {
+            match into.get_mut(&doc_id) {
+                Some(existing) => {
+                    *existing = Self::merge_keywords(std::mem::take(existing), keywords);
+                }
+                None => {
+                    into.insert(doc_id, keywords);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+    use crate::lexer::tokenizer::{StringTokenizer, Tokenable};
+
+    /// The evaluator before planning: every node is fully materialized in query order,
+    /// and its result kept for a following `~` to negate
+    struct Naive<'c> {
+        cache: &'c KeywordCache,
+        result: DocumentMatches,
+    }
+
+    impl Naive<'_> {
+        fn evaluate(&mut self, expr: &Expr) -> DocumentMatches {
+            match expr {
+                Expr::Not(inner) => {
+                    let base = std::mem::take(&mut self.result);
+                    let inner = self.evaluate(inner);
+                    self.result = Evaluator::exclude(base, &inner);
+                }
+                Expr::And(positive, negated) if matches!(**negated, Expr::Not(_)) => {
+                    self.and_not(positive, negated)
+                }
+                Expr::And(negated, positive) if matches!(**negated, Expr::Not(_)) => {
+                    self.and_not(positive, negated)
+                }
+                Expr::And(left, right) => {
+                    let left = self.evaluate(left);
+                    let right = self.evaluate(right);
+                    self.result = left
+                        .iter()
+                        .filter(|(doc_id, _)| right.contains_key(*doc_id))
+                        .map(|(doc_id, kws)| {
+                            let merged =
+                                Evaluator::merge_keywords(kws.clone(), right[doc_id].clone());
+                            (doc_id.clone(), merged)
+                        })
+                        .collect();
+                }
+                Expr::Or(left, right) => {
+                    let mut left = self.evaluate(left);
+                    let right = self.evaluate(right);
+                    for (doc_id, kws) in right {
+                        match left.get_mut(&doc_id) {
+                            Some(existing) => {
+                                *existing = Evaluator::merge_keywords(existing.clone(), kws)
+                            }
+                            None => {
+                                left.insert(doc_id, kws);
+                            }
+                        }
+                    }
+                    self.result = left;
+                }
+                Expr::Word(word) => {
+                    self.result = self.cache[word]
+                        .iter()
+                        .map(|(doc_id, score)| (doc_id.clone(), vec![(word.clone(), *score)]))
+                        .collect();
+                }
+                Expr::DocId(doc_id) => {
+                    self.result = HashMap::from([(doc_id.clone(), vec![])]);
+                }
+            }
+            self.result.clone()
+        }
+
+        fn and_not(&mut self, positive: &Expr, negated: &Expr) {
+            let Expr::Not(inner) = negated else {
+                unreachable!()
+            };
+            let base = self.evaluate(positive);
+            let excluded = self.evaluate(inner);
+            self.result = Evaluator::exclude(base, &excluded);
+        }
+    }
+
+    fn naive(expr: &Expr, cache: &KeywordCache) -> DocumentMatches {
+        Naive {
+            cache,
+            result: HashMap::new(),
+        }
+        .evaluate(expr)
+    }
+
+    fn planned(expr: &Expr, cache: &KeywordCache) -> DocumentMatches {
+        let plan = Plan::build(expr, cache);
+        Evaluator::new(cache, &plan).evaluate(&plan)
+    }
+
+    fn parse(query: &str) -> Expr {
+        StringTokenizer::parse(StringTokenizer::tokenize(query).unwrap()).unwrap()
+    }
+
+    /// A small deterministic generator, so failures reproduce
+    struct Lcg(u64);
+
+    impl Lcg {
+        fn next(&mut self, below: usize) -> usize {
+            self.0 = self
+                .0
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            ((self.0 >> 33) as usize) % below
+        }
+    }
+
+    fn random_cache(rng: &mut Lcg, keywords: usize, docs: usize) -> KeywordCache {
+        (0..keywords)
+            .map(|k| {
+                let mut postings = vec![];
+                for d in 0..docs {
+                    if rng.next(3) == 0 {
+                        postings.push((format!("d{}", d), rng.next(1000) as f64 / 1000.0));
+                    }
+                }
+                (format!("k{}", k), postings)
+            })
+            .collect()
+    }
+
+    fn random_expr(rng: &mut Lcg, keywords: usize, depth: usize) -> Expr {
+        let word = |rng: &mut Lcg| Expr::Word(format!("k{}", rng.next(keywords)));
+        if depth == 0 {
+            return word(rng);
+        }
+        let child = |rng: &mut Lcg| Box::new(random_expr(rng, keywords, depth - 1));
+        match rng.next(5) {
+            0 => word(rng),
+            1 => Expr::Not(child(rng)),
+            2 | 3 => Expr::And(child(rng), child(rng)),
+            _ => Expr::Or(child(rng), child(rng)),
+        }
+    }
+
+    fn cache(items: &[(&str, &[(&str, f64)])]) -> KeywordCache {
+        items
+            .iter()
+            .map(|(kw, postings)| {
+                let postings = postings.iter().map(|(d, s)| (d.to_string(), *s)).collect();
+                (kw.to_string(), postings)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_plan_orders_and_by_postings() {
+        let cache = cache(&[
+            ("common", &[("a", 0.1), ("b", 0.2), ("c", 0.3)]),
+            ("rare", &[("b", 0.9)]),
+        ]);
+        let expr = parse("common && rare");
+        let Plan::And { right_first, .. } = Plan::build(&expr, &cache) else {
+            panic!("expected an AND plan");
+        };
+        assert!(right_first);
+
+        // A bare negation reads the side evaluated before it, so order is kept
+        let expr = parse("common && (rare || ~common)");
+        let Plan::And { right_first, .. } = Plan::build(&expr, &cache) else {
+            panic!("expected an AND plan");
+        };
+        assert!(!right_first);
+    }
+
+    #[test]
+    fn test_and_keeps_query_keyword_order() {
+        let cache = cache(&[
+            ("common", &[("a", 0.1), ("b", 0.2), ("c", 0.3)]),
+            ("rare", &[("b", 0.9)]),
+        ]);
+        let matches = planned(&parse("common && rare"), &cache);
+        assert_eq!(
+            matches["b"],
+            vec![("common".to_string(), 0.2), ("rare".to_string(), 0.9)]
+        );
+        assert_eq!(matches.len(), 1);
+    }
+
+    #[test]
+    fn test_planned_matches_naive_on_random_queries() {
+        let mut rng = Lcg(0x5eed);
+        for _ in 0..50 {
+            let cache = random_cache(&mut rng, 6, 40);
+            for _ in 0..40 {
+                let expr = random_expr(&mut rng, 6, 4);
+                assert_eq!(
+                    planned(&expr, &cache),
+                    naive(&expr, &cache),
+                    "query {}",
+                    expr
+                );
+            }
+        }
+    }
+
+    /// `cargo test -p edgesearch-api --lib skewed -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn bench_skewed_and() {
+        let common: Vec<(String, f64)> = (0..80_000).map(|d| (format!("d{}", d), 0.5)).collect();
+        let rare: Vec<(String, f64)> = (0..12).map(|d| (format!("d{}", d * 997), 0.9)).collect();
+        let cache: KeywordCache =
+            HashMap::from([("common".to_string(), common), ("rare".to_string(), rare)]);
+        let expr = parse("common && rare");
+
+        let time = |evaluate: &dyn Fn() -> DocumentMatches| {
+            let started = Instant::now();
+            for _ in 0..20 {
+                assert_eq!(evaluate().len(), 12);
+            }
+            started.elapsed()
+        };
+        let naive_time = time(&|| naive(&expr, &cache));
+        let planned_time = time(&|| planned(&expr, &cache));
+        println!("naive: {:?}, planned: {:?}", naive_time, planned_time);
+        assert!(planned_time < naive_time);
+    }
+}
@@ -0,0 +1,128 @@
+//! Boosting newer documents at query time, once matches are hydrated.
+//!
+//! `recency_boost=` is a half-life in days: a match's score is multiplied by
+//! `0.5^(age_days / half_life)`, so a document one half-life old keeps half its score.
+//! `boost_field=` picks the timestamp its age is measured from:
+//!
+//! - `updated` (the default) measures from the document's latest revision
+//! - `created` measures from when it was added
+
+use crate::data::document::Document;
+
+const MS_PER_DAY: f64 = 86_400_000.0;
+
+/// The timestamp of a document a recency boost measures its age from
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum BoostField {
+    Created,
+    #[default]
+    Updated,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RecencyBoost {
+    pub half_life_days: f64,
+    pub field: BoostField,
+}
+
+impl RecencyBoost {
+    /// Parse the `recency_boost` and `boost_field` parameters of a search, `None`
+    /// when no boost was asked for
+    pub fn parse(
+        half_life_days: Option<f64>,
+        field: Option<&str>,
+    ) -> Result<Option<RecencyBoost>, String> {
+        let field = match field {
+            None => BoostField::default(),
+            Some("updated") => BoostField::Updated,
+            Some("created") => BoostField::Created,
+            Some(other) => {
+                return Err(format!(
+                    "Unknown boost_field '{}', expected created or updated",
+                    other
+                ))
+            }
+        };
+        match half_life_days {
+            None if field != BoostField::default() => {
+                Err("boost_field needs recency_boost".to_string())
+            }
+            None => Ok(None),
+            Some(days) if days.is_finite() && days > 0.0 => Ok(Some(RecencyBoost {
+                half_life_days: days,
+                field,
+            })),
+            Some(_) => Err("recency_boost must be a positive number of days".to_string()),
+        }
+    }
+
+    /// What a score is multiplied by for a document whose timestamp is `at`, at `now`.
+    /// Timestamps in the future count as new.
+    pub fn factor_at(&self, at: u64, now: u64) -> f64 {
+        let age_days = now.saturating_sub(at) as f64 / MS_PER_DAY;
+        0.5f64.powf(age_days / self.half_life_days)
+    }
+
+    /// The factor for `document`, or `None` when it has no timestamps, having been
+    /// written before they were kept
+    pub fn factor(&self, document: &Document, now: u64) -> Option<f64> {
+        let at = match self.field {
+            BoostField::Created => document.created_at,
+            BoostField::Updated => document.updated_at,
+        }?;
+        Some(self.factor_at(at, now))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_recency_boost() {
+        assert_eq!(RecencyBoost::parse(None, None), Ok(None));
+        let boost = RecencyBoost::parse(Some(30.0), Some("created")).unwrap();
+        assert_eq!(
+            boost,
+            Some(RecencyBoost {
+                half_life_days: 30.0,
+                field: BoostField::Created
+            })
+        );
+        assert_eq!(
+            RecencyBoost::parse(Some(7.0), None).unwrap().unwrap().field,
+            BoostField::Updated
+        );
+
+        assert!(RecencyBoost::parse(None, Some("created")).is_err());
+        assert!(RecencyBoost::parse(Some(7.0), Some("modified")).is_err());
+        for days in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+            assert!(RecencyBoost::parse(Some(days), None).is_err(), "{}", days);
+        }
+    }
+
+    #[test]
+    fn test_factor_halves_every_half_life() {
+        let boost = RecencyBoost {
+            half_life_days: 10.0,
+            field: BoostField::Updated,
+        };
+        let now = 100 * MS_PER_DAY as u64;
+        assert_eq!(boost.factor_at(now, now), 1.0);
+        assert_eq!(boost.factor_at(now - 10 * MS_PER_DAY as u64, now), 0.5);
+        assert_eq!(boost.factor_at(now - 20 * MS_PER_DAY as u64, now), 0.25);
+        assert_eq!(boost.factor_at(now + 1000, now), 1.0);
+
+        let mut document = Document::new_with_id("idx", "doc1");
+        document.created_at = Some(now - 20 * MS_PER_DAY as u64);
+        document.updated_at = Some(now - 10 * MS_PER_DAY as u64);
+        assert_eq!(boost.factor(&document, now), Some(0.5));
+        let created = RecencyBoost {
+            field: BoostField::Created,
+            ..boost
+        };
+        assert_eq!(created.factor(&document, now), Some(0.25));
+        document.updated_at = None;
+        assert_eq!(boost.factor(&document, now), None);
+    }
+}
@@ -1,5 +1,9 @@
+use std::collections::HashSet;
+
+use serde::{Deserialize, Serialize};
+
 /// Score a list of keyword matches for a single document into a single score.
-pub fn score_collective_keywords(data: &Vec<(String, f64)>) -> f64 {
+pub fn score_collective_keywords<K>(data: &[(K, f64)]) -> f64 {
     let total_matches = data.len() as u32;
     if total_matches == 1u32 {
         data[0].1
@@ -7,3 +11,121 @@ pub fn score_collective_keywords(data: &Vec<(String, f64)>) -> f64 {
         data.iter().map(|(_, score)| *score).sum::<f64>() / (total_matches as f64)
     }
 }
+
+/// How many of a query's distinct keywords a document matched, reported on its
+/// search result and used by [`ScoringMode::Coverage`]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TermCoverage {
+    pub matched: usize,
+    pub total: usize,
+}
+
+impl TermCoverage {
+    /// Count the distinct `query_keywords` among a document's keyword matches
+    pub fn of<K: AsRef<str>>(data: &[(K, f64)], query_keywords: &HashSet<&str>) -> TermCoverage {
+        let matched: HashSet<&str> = data
+            .iter()
+            .map(|(keyword, _)| keyword.as_ref())
+            .filter(|keyword| query_keywords.contains(keyword))
+            .collect();
+        TermCoverage {
+            matched: matched.len(),
+            total: query_keywords.len(),
+        }
+    }
+
+    /// The share of the query's keywords matched
+    pub fn ratio(&self) -> f64 {
+        self.matched as f64 / self.total.max(self.matched).max(1) as f64
+    }
+}
+
+/// How a document's keyword matches are combined into its search score, chosen with
+/// the `scoring` search parameter
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ScoringMode {
+    /// The mean score of the matched keywords
+    #[default]
+    Mean,
+    /// The sum of the matched keywords' scores, favoring documents matching more
+    Sum,
+    /// The best matched keyword's score
+    Max,
+    /// The mean score, scaled by the share of the query's keywords matched
+    Coverage,
+}
+
+impl ScoringMode {
+    pub const NAMES: [&'static str; 4] = ["mean", "sum", "max", "coverage"];
+
+    pub fn from_name(name: &str) -> Option<ScoringMode> {
+        match name {
+            "mean" => Some(ScoringMode::Mean),
+            "sum" => Some(ScoringMode::Sum),
+            "max" => Some(ScoringMode::Max),
+            "coverage" => Some(ScoringMode::Coverage),
+            _ => None,
+        }
+    }
+
+    /// Score a document's keyword matches, which cover `coverage` of the query. A
+    /// document matched only by its `id:` scores 1.0.
+    pub fn score<K>(&self, data: &[(K, f64)], coverage: TermCoverage) -> f64 {
+        if data.is_empty() {
+            return 1.0;
+        }
+        match self {
+            ScoringMode::Mean => score_collective_keywords(data),
+            ScoringMode::Sum => data.iter().map(|(_, score)| *score).sum(),
+            ScoringMode::Max => data.iter().map(|(_, score)| *score).fold(0.0, f64::max),
+            ScoringMode::Coverage => score_collective_keywords(data) * coverage.ratio(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scoring_modes() {
+        let matches = vec![("ocean".to_string(), 0.8), ("tide".to_string(), 0.4)];
+        let close = |a: f64, b: f64| (a - b).abs() < 1e-9;
+        let of = |total| TermCoverage { matched: 2, total };
+        assert!(close(ScoringMode::Mean.score(&matches, of(4)), 0.6));
+        assert!(close(ScoringMode::Sum.score(&matches, of(4)), 1.2));
+        assert!(close(ScoringMode::Max.score(&matches, of(4)), 0.8));
+        assert!(close(ScoringMode::Coverage.score(&matches, of(4)), 0.3));
+        assert!(close(ScoringMode::Coverage.score(&matches, of(2)), 0.6));
+
+        for name in ScoringMode::NAMES {
+            let mode = ScoringMode::from_name(name).unwrap();
+            assert_eq!(
+                serde_json::to_string(&mode).unwrap(),
+                format!("\"{}\"", name)
+            );
+        }
+        assert_eq!(ScoringMode::from_name("bm25"), None);
+    }
+
+    #[test]
+    fn test_term_coverage() {
+        let query: HashSet<&str> = ["ocean", "tide", "storm"].into_iter().collect();
+        let matches = vec![
+            ("ocean".to_string(), 0.8),
+            ("tide".to_string(), 0.4),
+            ("ocean".to_string(), 0.8),
+        ];
+        let coverage = TermCoverage::of(&matches, &query);
+        assert_eq!(
+            coverage,
+            TermCoverage {
+                matched: 2,
+                total: 3
+            }
+        );
+        assert!((coverage.ratio() - 2.0 / 3.0).abs() < 1e-9);
+        assert_eq!(TermCoverage::of::<&str>(&[], &HashSet::new()).ratio(), 0.0);
+    }
+}
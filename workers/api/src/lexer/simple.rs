@@ -0,0 +1,176 @@
+//! Rewriting the free text of a search box, sent as `text` with a `mode`, into a
+//! query. YAKE stores phrases as well as single words, so `rust async runtime` may
+//! only be found as `rust` and `async runtime`: besides the words themselves, each
+//! adjacent pair is also tried as one keyword, whose matches are scored a little
+//! higher by [`BIGRAM_BOOST`].
+
+use std::collections::HashMap;
+
+use crate::lexer::Expr;
+
+/// What a bigram's keyword scores are multiplied by
+pub const BIGRAM_BOOST: f64 = 1.1;
+
+/// How the words of `text` are combined
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum SimpleMode {
+    /// Any of the words or their bigrams
+    Any,
+    /// Every word, each of which may be matched as part of a bigram with its neighbour
+    #[default]
+    All,
+    /// The whole text as one keyword
+    Phrase,
+}
+
+impl SimpleMode {
+    pub const NAMES: [&'static str; 3] = ["any", "all", "phrase"];
+
+    pub fn from_name(name: &str) -> Option<SimpleMode> {
+        match name {
+            "any" => Some(SimpleMode::Any),
+            "all" => Some(SimpleMode::All),
+            "phrase" => Some(SimpleMode::Phrase),
+            _ => None,
+        }
+    }
+}
+
+/// The query `text` is rewritten into, and the boost given to each keyword scored
+/// differently than it's stored
+#[derive(Debug, Clone)]
+pub struct SimpleQuery {
+    pub expr: Expr,
+    pub boosts: HashMap<String, f64>,
+}
+
+/// Rewrite `text` into a query, or `None` when it has no words
+pub fn rewrite(text: &str, mode: SimpleMode) -> Option<SimpleQuery> {
+    let words: Vec<&str> = text.split_whitespace().collect();
+    if words.is_empty() {
+        return None;
+    }
+    let word = |word: &str| Expr::Word(word.to_string());
+    let bigrams: Vec<String> = match mode {
+        SimpleMode::Phrase => vec![],
+        _ => words.windows(2).map(|pair| pair.join(" ")).collect(),
+    };
+    let expr = match mode {
+        SimpleMode::Phrase => word(&words.join(" ")),
+        SimpleMode::Any => {
+            let terms = words
+                .iter()
+                .copied()
+                .chain(bigrams.iter().map(String::as_str));
+            join(terms.map(word), Expr::Or)
+        }
+        // Every word on its own, or with one adjacent pair replaced by its bigram
+        SimpleMode::All => {
+            let split = join(words.iter().map(|w| word(w)), Expr::And);
+            let paired = bigrams.iter().enumerate().map(|(i, bigram)| {
+                let terms = words[..i]
+                    .iter()
+                    .copied()
+                    .chain([bigram.as_str()])
+                    .chain(words[i + 2..].iter().copied());
+                join(terms.map(word), Expr::And)
+            });
+            join([split].into_iter().chain(paired), Expr::Or)
+        }
+    };
+    let boosts = bigrams
+        .into_iter()
+        .map(|bigram| (bigram, BIGRAM_BOOST))
+        .collect();
+    Some(SimpleQuery { expr, boosts })
+}
+
+/// Fold `terms` from the left, as a chain of the query string's operators would be
+fn join(terms: impl IntoIterator<Item = Expr>, op: fn(Box<Expr>, Box<Expr>) -> Expr) -> Expr {
+    let mut terms = terms.into_iter();
+    let first = terms.next().expect("at least one term");
+    terms.fold(first, |left, right| op(Box::new(left), Box::new(right)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rewritten(text: &str, mode: SimpleMode) -> String {
+        rewrite(text, mode).unwrap().expr.to_string()
+    }
+
+    #[test]
+    fn test_one_word() {
+        for mode in [SimpleMode::Any, SimpleMode::All, SimpleMode::Phrase] {
+            let query = rewrite("  rust ", mode).unwrap();
+            assert_eq!(query.expr.to_string(), "rust");
+            assert!(query.boosts.is_empty());
+        }
+        assert!(rewrite(" \t", SimpleMode::All).is_none());
+    }
+
+    #[test]
+    fn test_two_words() {
+        assert_eq!(
+            rewritten("async runtime", SimpleMode::All),
+            "((async && runtime) || \"async runtime\")"
+        );
+        assert_eq!(
+            rewritten("async runtime", SimpleMode::Any),
+            "((async || runtime) || \"async runtime\")"
+        );
+        assert_eq!(
+            rewritten("async   runtime", SimpleMode::Phrase),
+            "\"async runtime\""
+        );
+        let boosts = rewrite("async runtime", SimpleMode::All).unwrap().boosts;
+        assert_eq!(
+            boosts,
+            HashMap::from([("async runtime".into(), BIGRAM_BOOST)])
+        );
+    }
+
+    #[test]
+    fn test_three_words() {
+        assert_eq!(
+            rewritten("rust async runtime", SimpleMode::All),
+            "((((rust && async) && runtime) || (\"rust async\" && runtime)) || (rust && \"async runtime\"))"
+        );
+        assert_eq!(
+            rewritten("rust async runtime", SimpleMode::Any),
+            "((((rust || async) || runtime) || \"rust async\") || \"async runtime\")"
+        );
+        assert!(rewrite("rust async runtime", SimpleMode::Phrase)
+            .unwrap()
+            .boosts
+            .is_empty());
+    }
+
+    #[test]
+    fn test_four_words() {
+        let all = rewrite("fast rust async runtime", SimpleMode::All).unwrap();
+        assert_eq!(
+            all.expr.to_string(),
+            "((((((fast && rust) && async) && runtime) \
+             || ((\"fast rust\" && async) && runtime)) \
+             || ((fast && \"rust async\") && runtime)) \
+             || ((fast && rust) && \"async runtime\"))"
+        );
+        let mut bigrams: Vec<&str> = all.boosts.keys().map(String::as_str).collect();
+        bigrams.sort();
+        assert_eq!(bigrams, vec!["async runtime", "fast rust", "rust async"]);
+        assert_eq!(
+            rewritten("fast rust async runtime", SimpleMode::Any),
+            "((((((fast || rust) || async) || runtime) || \"fast rust\") || \"rust async\") || \"async runtime\")"
+        );
+    }
+
+    #[test]
+    fn test_mode_names() {
+        for name in SimpleMode::NAMES {
+            assert!(SimpleMode::from_name(name).is_some());
+        }
+        assert_eq!(SimpleMode::from_name("exact"), None);
+    }
+}
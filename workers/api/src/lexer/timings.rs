@@ -0,0 +1,61 @@
+use serde::Serialize;
+
+pub use crate::util::time::now_ms;
+
+/// Milliseconds spent in each stage of a search, collected with `worker::Date::now()`
+/// deltas as the query moves through [`crate::lexer::lexer::QueryLexer`] and the
+/// search handler.
+#[derive(Serialize, Default, Debug, Clone, PartialEq)]
+pub struct Timings {
+    /// Tokenizing and parsing the query string into an AST
+    pub parse_ms: u64,
+    /// Listing and reading the keyword shards for every keyword in the query
+    pub preload_ms: u64,
+    /// Number of keyword shards read during preload
+    pub shard_reads: usize,
+    /// Evaluating the AST against the preloaded keyword data
+    pub evaluate_ms: u64,
+    /// Scoring and sorting the matched documents
+    pub sort_ms: u64,
+    /// Fetching full document bodies, when requested
+    pub hydrate_ms: u64,
+    /// Subrequests the search made: KV operations and durable reader requests
+    pub kv_ops_used: usize,
+}
+
+/// Milliseconds elapsed since `start`, clamped at zero against clock skew
+pub fn elapsed_ms(start: u64, now: u64) -> u64 {
+    now.saturating_sub(start)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_elapsed_is_never_negative() {
+        assert_eq!(elapsed_ms(10, 25), 15);
+        assert_eq!(elapsed_ms(25, 10), 0);
+    }
+
+    #[test]
+    fn test_serializes_every_stage() {
+        let timings = Timings {
+            shard_reads: 3,
+            ..Default::default()
+        };
+        let json = serde_json::to_value(&timings).unwrap();
+        for field in [
+            "parse_ms",
+            "preload_ms",
+            "shard_reads",
+            "evaluate_ms",
+            "sort_ms",
+            "hydrate_ms",
+            "kv_ops_used",
+        ] {
+            assert!(json[field].as_u64().is_some(), "missing {}", field);
+        }
+        assert_eq!(json["shard_reads"], 3);
+    }
+}
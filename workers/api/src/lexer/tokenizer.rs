@@ -1,4 +1,4 @@
-use crate::lexer::{Expr, QueryError, Token};
+use crate::lexer::{check_keyword, Expr, QueryError, Token, DOC_ID_PREFIX};
 
 /// Describes the input medium tokenizer
 pub trait Tokenable<'a> {
@@ -11,10 +11,67 @@ pub trait Tokenable<'a> {
 ///
 /// Some examples of valid inputs:
 ///  - `"apple"`
-///  - `"apple" && "banana"`
+///  - `apple && "banana split"`
 ///  - `("apple" || "banana") && ~"grape"`
+///  - `(id:abc123 || id:"def456") && apple`
+///  - `"say \"cheese\""`, escaping a quote or backslash inside quotes
 pub struct StringTokenizer {}
+
+/// The characters of a query string with their byte offsets
+type Chars<'s> = std::iter::Peekable<std::str::CharIndices<'s>>;
+
 impl StringTokenizer {
+    /// Characters which terminate a bare (unquoted) word
+    pub fn is_reserved_char(c: char) -> bool {
+        c.is_whitespace() || matches!(c, '(' | ')' | '&' | '|' | '~' | '"')
+    }
+
+    /// Whether `word` is read back as the same keyword without quotes
+    pub fn is_bare_word(word: &str) -> bool {
+        !word.is_empty()
+            && !word.starts_with(DOC_ID_PREFIX)
+            && !word.chars().any(Self::is_reserved_char)
+    }
+
+    /// `word` in quotes, with each `"` and `\` in it escaped by a backslash
+    pub fn quote(word: &str) -> String {
+        let mut quoted = String::with_capacity(word.len() + 2);
+        quoted.push('"');
+        for c in word.chars() {
+            if matches!(c, '"' | '\\') {
+                quoted.push('\\');
+            }
+            quoted.push(c);
+        }
+        quoted.push('"');
+        quoted
+    }
+
+    /// The rest of a quoted string whose opening quote was just read. A backslash
+    /// escapes a following `"` or `\`, and is refused before anything else.
+    fn quoted(chars: &mut Chars) -> Result<String, QueryError> {
+        let mut word = String::new();
+        while let Some((offset, c)) = chars.next() {
+            match c {
+                '"' => return Ok(word),
+                '\\' => match chars.next() {
+                    Some((_, escaped @ ('"' | '\\'))) => word.push(escaped),
+                    Some((_, other)) => return Err(QueryError::InvalidEscape(other, offset)),
+                    None => break,
+                },
+                _ => word.push(c),
+            }
+        }
+        Err(QueryError::UnclosedQuote)
+    }
+
+    /// A keyword token of `word` without its surrounding whitespace
+    fn word(word: &str) -> Result<Token, QueryError> {
+        let word = word.trim();
+        check_keyword(word)?;
+        Ok(Token::Word(word.to_string()))
+    }
+
     fn parse_or(iter: &mut std::iter::Peekable<std::slice::Iter<Token>>) -> Option<Expr> {
         let mut left = Self::parse_and(iter)?;
         while let Some(Token::Or) = iter.peek() {
@@ -47,6 +104,7 @@ impl StringTokenizer {
     fn parse_primary(iter: &mut std::iter::Peekable<std::slice::Iter<Token>>) -> Option<Expr> {
         match iter.next() {
             Some(Token::Word(word)) => Some(Expr::Word(word.clone())),
+            Some(Token::DocId(id)) => Some(Expr::DocId(id.clone())),
             Some(Token::LParen) => {
                 let expr = Self::parse_or(iter)?;
                 if let Some(Token::RParen) = iter.next() {
@@ -69,48 +127,180 @@ impl<'a> Tokenable<'a> for StringTokenizer {
     }
 
     fn tokenize(input: Self::Type) -> Result<Vec<Token>, QueryError> {
-        let mut chars = input.chars().peekable();
+        let mut chars = input.char_indices().peekable();
         let mut tokens = Vec::new();
-        while let Some(ch) = chars.next() {
+        while let Some((_, ch)) = chars.next() {
             match ch {
                 ' ' | '\t' | '\n' => continue,
                 '(' => tokens.push(Token::LParen),
                 ')' => tokens.push(Token::RParen),
                 '&' => {
-                    if chars.peek() != Some(&'&') {
+                    if !matches!(chars.peek(), Some((_, '&'))) {
                         Err(QueryError::InvalidToken(ch))?
                     }
                     chars.next();
                     tokens.push(Token::And);
                 }
                 '|' => {
-                    if chars.peek() != Some(&'|') {
+                    if !matches!(chars.peek(), Some((_, '|'))) {
                         Err(QueryError::InvalidToken(ch))?
                     }
                     chars.next();
                     tokens.push(Token::Or);
                 }
                 '~' => tokens.push(Token::Not),
-                '"' => {
-                    let mut word = String::new();
-                    let mut found_closing_quote = false;
-                    while let Some(c) = chars.next() {
-                        if c == '"' {
-                            found_closing_quote = true;
+                '"' => tokens.push(Self::word(&Self::quoted(&mut chars)?)?),
+                _ => {
+                    // Bare words continue until whitespace or an operator character
+                    let mut word = String::from(ch);
+                    while let Some(&(_, c)) = chars.peek() {
+                        if Self::is_reserved_char(c) {
                             break;
                         }
                         word.push(c);
+                        chars.next();
                     }
-                    if !found_closing_quote {
-                        return Err(QueryError::UnclosedQuote);
+                    match word.strip_prefix(DOC_ID_PREFIX) {
+                        Some("") if matches!(chars.peek(), Some((_, '"'))) => {
+                            chars.next();
+                            tokens.push(Token::DocId(Self::quoted(&mut chars)?));
+                        }
+                        Some(id) if !id.is_empty() => tokens.push(Token::DocId(id.to_string())),
+                        _ => tokens.push(Self::word(&word)?),
                     }
-                    tokens.push(Token::Word(word));
-                }
-                _ => {
-                    return Err(QueryError::InvalidToken(ch));
                 }
             }
         }
         Ok(tokens)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Characters that are operators, quotes or escapes in a query string, and some
+    /// that aren't
+    const ALPHABET: [&str; 14] = [
+        "a", "é", " ", "\"", "\\", "&", "|", "~", "(", ")", "id:", ":", "&&", "\t",
+    ];
+
+    fn next(seed: &mut u64) -> u64 {
+        *seed ^= *seed << 13;
+        *seed ^= *seed >> 7;
+        *seed ^= *seed << 17;
+        *seed
+    }
+
+    /// A word of up to 6 pieces of [`ALPHABET`], starting and ending with a letter so
+    /// the tokenizer has no whitespace to trim
+    fn random_word(seed: &mut u64) -> String {
+        let pieces = next(seed) % 7;
+        let mut word = String::from("w");
+        for _ in 0..pieces {
+            word.push_str(ALPHABET[next(seed) as usize % ALPHABET.len()]);
+        }
+        word.push('z');
+        word
+    }
+
+    fn random_expr(seed: &mut u64, depth: u32) -> Expr {
+        match next(seed) % if depth == 0 { 3 } else { 6 } {
+            0 => Expr::Word(random_word(seed)),
+            // Words that read as bare IDs or operators unless they're quoted
+            1 => Expr::Word(["id:a", "id:", "&&", "a\\", "\\\""][next(seed) as usize % 5].into()),
+            // IDs are used untrimmed, so they may be blank or padded
+            2 => Expr::DocId(match next(seed) % 3 {
+                0 => String::new(),
+                1 => format!(" {} ", random_word(seed)),
+                _ => random_word(seed),
+            }),
+            3 => Expr::Not(Box::new(random_expr(seed, depth - 1))),
+            4 => Expr::And(
+                Box::new(random_expr(seed, depth - 1)),
+                Box::new(random_expr(seed, depth - 1)),
+            ),
+            _ => Expr::Or(
+                Box::new(random_expr(seed, depth - 1)),
+                Box::new(random_expr(seed, depth - 1)),
+            ),
+        }
+    }
+
+    #[test]
+    fn test_display_round_trips() {
+        let mut seed = 0x2545_f491_4f6c_dd1d;
+        for _ in 0..2_000 {
+            let expr = random_expr(&mut seed, 4);
+            let query = expr.to_string();
+            assert_eq!(Expr::parse(&query).unwrap(), expr, "{}", query);
+        }
+    }
+
+    /// Words with quotes, backslashes and operators, and how they're written in a
+    /// query string. The client's `QueryExpr::to_query_string` is tested against the
+    /// same strings.
+    const ESCAPED_WORDS: [(&str, &str); 6] = [
+        (r#"say "cheese""#, r#""say \"cheese\"""#),
+        (r"C:\My Files\", r#""C:\\My Files\\""#),
+        (r#"\""#, r#""\\\"""#),
+        (r#"a" || "b"#, r#""a\" || \"b""#),
+        ("fn(x) && ~y", r#""fn(x) && ~y""#),
+        (r"a\b", r"a\b"),
+    ];
+
+    #[test]
+    fn test_escaped_words_round_trip() {
+        for (word, query) in ESCAPED_WORDS {
+            let expr = Expr::Word(word.into());
+            assert_eq!(expr.to_string(), query);
+            assert_eq!(Expr::parse(query).unwrap(), expr, "{}", query);
+            // Escapes read the same inside an ID and around operators
+            let combined = format!("~{} && id:{}", query, StringTokenizer::quote(word));
+            assert_eq!(
+                Expr::parse(&combined).unwrap(),
+                Expr::And(
+                    Box::new(Expr::Not(Box::new(expr))),
+                    Box::new(Expr::DocId(word.into()))
+                ),
+                "{}",
+                combined
+            );
+        }
+    }
+
+    #[test]
+    fn test_quoted_escapes() {
+        let expr = Expr::Word("say \"cheese\" \\o/".into());
+        assert_eq!(expr.to_string(), r#""say \"cheese\" \\o/""#);
+        assert_eq!(Expr::DocId("a b".into()).to_string(), r#"id:"a b""#);
+        assert_eq!(Expr::DocId("".into()).to_string(), r#"id:"""#);
+        // Only quotes and backslashes are escaped in quoted words, and bare words take
+        // no escapes
+        assert_eq!(
+            Expr::parse(r#""C:\\dir""#).unwrap(),
+            Expr::Word(r"C:\dir".into())
+        );
+        assert!(matches!(
+            Expr::parse(r#"a && "C:\dir""#),
+            Err(QueryError::InvalidEscape('d', 8))
+        ));
+        assert!(matches!(
+            Expr::parse(r#""tab\t""#),
+            Err(QueryError::InvalidEscape('t', 4))
+        ));
+        assert!(matches!(
+            Expr::parse(r#"id:"a\b""#),
+            Err(QueryError::InvalidEscape('b', 5))
+        ));
+        assert_eq!(Expr::parse(r"a\b").unwrap(), Expr::Word(r"a\b".into()));
+        assert!(matches!(
+            Expr::parse(r#""a\""#),
+            Err(QueryError::UnclosedQuote)
+        ));
+        assert!(matches!(
+            Expr::parse("a &&"),
+            Err(QueryError::InvalidQuery(..))
+        ));
+    }
+}
@@ -0,0 +1,74 @@
+//! Notes a search sends beside its results when they are imperfect, like a keyword
+//! that no document holds, each with a stable code for callers to act on.
+
+use serde::Serialize;
+
+/// A stable, machine-readable reason for a warning, sent as `code` beside the message
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum WarningCode {
+    /// A query keyword that matched no documents
+    KeywordNoMatches,
+    /// A query keyword on the index's stop-list, with `warnings=true`
+    StopListed,
+    /// The stop-list couldn't be read to check the query against it
+    StopListUnavailable,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct SearchWarning {
+    pub code: WarningCode,
+    pub message: String,
+    /// What the warning is about, like the keyword that matched nothing
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+/// The warnings of one search, collected as it runs
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+#[serde(transparent)]
+pub struct Warnings(Vec<SearchWarning>);
+
+impl Warnings {
+    pub fn push(&mut self, code: WarningCode, message: String, detail: Option<String>) {
+        self.0.push(SearchWarning {
+            code,
+            message,
+            detail,
+        });
+    }
+
+    /// Warn that `keyword` matched no documents
+    pub fn keyword_no_matches(&mut self, keyword: &str) {
+        self.push(
+            WarningCode::KeywordNoMatches,
+            format!("'{}' matched no documents", keyword),
+            Some(keyword.to_string()),
+        );
+    }
+
+    pub fn extend(&mut self, warnings: impl IntoIterator<Item = SearchWarning>) {
+        self.0.extend(warnings);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &SearchWarning> {
+        self.0.iter()
+    }
+
+    pub fn clear(&mut self) {
+        self.0.clear();
+    }
+}
+
+impl IntoIterator for Warnings {
+    type Item = SearchWarning;
+    type IntoIter = std::vec::IntoIter<SearchWarning>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter()
+    }
+}
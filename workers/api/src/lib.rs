@@ -7,51 +7,156 @@ mod durable;
 mod http;
 pub mod lexer;
 
+use std::sync::Once;
+
 use worker::{event, Context, Env, Request, Response, Result, RouteContext, Router};
 
-use crate::data::{DataStoreError, ENV_VAR_API_KEY};
+use crate::{
+    data::{ENV_VAR_API_KEY, ENV_VAR_AUTH_DISABLED, ENV_VAR_PRELOAD_DETECTOR},
+    util::auth::{authorize, is_truthy, AuthOutcome},
+};
+
+static AUTH_DISABLED_WARNING: Once = Once::new();
+static DETECTOR_PRELOAD: Once = Once::new();
 
-/// Compare a request's API key header to the API_KEY env var, if one exists.
-fn check_auth(req: &Request, ctx: &RouteContext<()>) -> bool {
-    // Check if API_KEY env var is set, if not ignore
-    ctx.env
-        .var(ENV_VAR_API_KEY)
-        .map_err(DataStoreError::Worker)
-        .map(|v| {
-            let api_key = req.headers().get("X-API-Key").unwrap_or(None);
-            api_key.as_ref() == Some(&v.to_string())
-        })
-        .unwrap_or_else(|_| false)
+fn is_auth_disabled(env: &Env) -> bool {
+    env.var(ENV_VAR_AUTH_DISABLED)
+        .map(|v| is_truthy(&v.to_string()))
+        .unwrap_or(false)
+}
+
+/// Compare a request's API key header to the API_KEY env var in constant time.
+fn check_auth(req: &Request, ctx: &RouteContext<Context>) -> AuthOutcome {
+    let api_key = ctx.env.var(ENV_VAR_API_KEY).ok().map(|v| v.to_string());
+    let presented = req.headers().get("X-API-Key").unwrap_or(None);
+    authorize(
+        api_key.as_deref(),
+        is_auth_disabled(&ctx.env),
+        presented.as_deref(),
+    )
+}
+
+/// Whether a request presents the configured API key itself, rather than being let
+/// through because `AUTH_DISABLED=true`
+fn presents_api_key(req: &Request, env: &Env) -> bool {
+    let api_key = env.var(ENV_VAR_API_KEY).ok().map(|v| v.to_string());
+    let presented = req.headers().get("X-API-Key").unwrap_or(None);
+    authorize(api_key.as_deref(), false, presented.as_deref()) == AuthOutcome::Allowed
 }
 
 macro_rules! with_auth {
     ($handler:expr) => {
-        |req: Request, ctx: RouteContext<()>| async move {
-            if crate::check_auth(&req, &ctx) {
-                $handler(req, ctx).await
-            } else {
-                worker::Response::error("Unauthorized", 401)
+        |req: Request, ctx: RouteContext<Context>| async move {
+            match crate::check_auth(&req, &ctx) {
+                crate::util::auth::AuthOutcome::Allowed => $handler(req, ctx).await,
+                crate::util::auth::AuthOutcome::Unauthorized => crate::http::json_error(
+                    401,
+                    crate::http::ErrorCode::Unauthorized,
+                    "Unauthorized",
+                ),
+                crate::util::auth::AuthOutcome::Misconfigured => crate::http::json_error(
+                    503,
+                    crate::http::ErrorCode::Misconfigured,
+                    "Server misconfigured: API_KEY is not set and AUTH_DISABLED is not enabled",
+                ),
             }
         }
     };
 }
 
 #[event(fetch)]
-pub async fn main(req: Request, env: Env, _ctx: Context) -> Result<Response> {
-    return Router::new()
+pub async fn main(req: Request, env: Env, ctx: Context) -> Result<Response> {
+    util::log::set_level_from_env(&env);
+    util::concurrency::set_limit_from_env(&env);
+    if is_auth_disabled(&env) {
+        AUTH_DISABLED_WARNING.call_once(|| {
+            edge_log!(
+                console_warn,
+                "Auth",
+                "",
+                "AUTH_DISABLED=true: every protected route is open to anyone! Set API_KEY to require authentication."
+            );
+        });
+    }
+    // Pay for loading the language models once per isolate, before the first
+    // document needs them
+    let preload = env
+        .var(ENV_VAR_PRELOAD_DETECTOR)
+        .map(|v| is_truthy(&v.to_string()));
+    if preload.unwrap_or(false) {
+        DETECTOR_PRELOAD.call_once(|| data::document::preload_detector(&env));
+    }
+
+    // Browsers are shown error pages rather than the JSON envelope
+    let html = http::render_html::accepts_html(&req);
+
+    // Handlers hand work that can finish after the response to `ctx.data.wait_until`
+    let response = Router::with_data(ctx)
         .get_async("/", http::index::handle_index)
+        .get_async("/openapi.json", http::openapi::handle_openapi)
+        .get_async("/docs", http::openapi::handle_docs)
         // Search endpoints
         .post_async("/:index/search", with_auth!(http::search::handle_search))
+        // Saved queries
+        .get_async(
+            "/:index/queries",
+            with_auth!(http::queries::handle_list_queries),
+        )
+        .get_async(
+            "/:index/queries/:name",
+            with_auth!(http::queries::handle_get_query),
+        )
+        .put_async(
+            "/:index/queries/:name",
+            with_auth!(http::queries::handle_put_query),
+        )
+        .delete_async(
+            "/:index/queries/:name",
+            with_auth!(http::queries::handle_delete_query),
+        )
+        .post_async(
+            "/:index/queries/:name/run",
+            with_auth!(http::queries::handle_run_query),
+        )
         // Keyword endpoints
         .get_async(
             "/:index/keyword/:keyword",
             with_auth!(http::keywords::handle_get_keyword),
         )
+        .get_async(
+            "/:index/keyword/:keyword/related",
+            with_auth!(http::keywords::handle_related_keywords),
+        )
+        .get_async(
+            "/:index/keyword/:keyword/trend",
+            with_auth!(http::keywords::handle_keyword_trend),
+        )
+        .post_async(
+            "/:index/keywords:action",
+            with_auth!(http::keywords::handle_keywords_action),
+        )
+        .get_async(
+            "/:index/keywords/export",
+            with_auth!(http::export::handle_export_keywords),
+        )
         // Document endpoints
+        .get_async(
+            "/:index/docs",
+            with_auth!(http::documents::handle_list_documents),
+        )
         .get_async(
             "/:index/doc/:id",
             with_auth!(http::documents::handle_get_document),
         )
+        // The router doesn't answer HEAD with the GET handlers, so it is routed explicitly
+        .head_async(
+            "/:index/doc/:id",
+            with_auth!(http::documents::handle_head_document),
+        )
+        .get_async(
+            "/:index/doc/:id/keywords",
+            with_auth!(http::documents::handle_document_keywords),
+        )
         .post_async(
             "/:index/doc",
             with_auth!(http::documents::handle_add_document),
@@ -68,21 +173,118 @@ pub async fn main(req: Request, env: Env, _ctx: Context) -> Result<Response> {
             "/:index/doc/:id",
             with_auth!(http::documents::handle_delete_document),
         )
+        // Stop-list endpoints
+        .get_async(
+            "/:index/stoplist",
+            with_auth!(http::stoplist::handle_get_stoplist),
+        )
+        .put_async(
+            "/:index/stoplist",
+            with_auth!(http::stoplist::handle_set_stoplist),
+        )
+        // Integrity check
+        .post_async("/:index/fsck", with_auth!(http::fsck::handle_fsck))
+        // Shard count migration
+        .post_async("/:index/reshard", with_auth!(http::reshard::handle_reshard))
+        // Index format migration
+        .post_async("/:index/upgrade", with_auth!(http::upgrade::handle_upgrade))
+        // Daily usage counters
+        .get_async("/:index/usage", with_auth!(http::usage::handle_usage))
+        // Recent document changes
+        .get_async(
+            "/:index/activity",
+            with_auth!(http::activity::handle_activity),
+        )
+        // Search page for browsers
+        .get_async("/:index/ui", http::index::handle_ui)
+        // Merging hot keywords ahead of their searches
+        .post_async("/:index/warm", with_auth!(http::warm::handle_warm))
+        // Largest documents and keywords
+        .get_async("/:index/top", with_auth!(http::top::handle_top))
+        // Snapshots to R2
+        .post_async(
+            "/:index/snapshot",
+            with_auth!(http::snapshot::handle_snapshot),
+        )
+        .get_async(
+            "/:index/snapshots",
+            with_auth!(http::snapshot::handle_list_snapshots),
+        )
+        .post_async(
+            "/:index/restore",
+            with_auth!(http::snapshot::handle_restore),
+        )
+        // Elasticsearch-compatible bulk endpoint
+        .post_async("/:index/_bulk", with_auth!(http::es_bulk::handle_bulk))
+        // Index templates
+        .get_async(
+            "/_templates",
+            with_auth!(http::templates::handle_list_templates),
+        )
+        .get_async(
+            "/_templates/:name",
+            with_auth!(http::templates::handle_get_template),
+        )
+        .put_async(
+            "/_templates/:name",
+            with_auth!(http::templates::handle_put_template),
+        )
+        .delete_async(
+            "/_templates/:name",
+            with_auth!(http::templates::handle_delete_template),
+        )
         // Index endpoints (protected)
         .get_async("/indexes", with_auth!(http::indexes::handle_list))
         .get_async("/:index", with_auth!(http::indexes::handle_view))
+        .head_async("/:index", with_auth!(http::indexes::handle_head))
         .put_async("/:index", with_auth!(http::indexes::handle_create))
         .delete_async("/:index", with_auth!(http::indexes::handle_delete))
+        .patch_async("/:index", with_auth!(http::indexes::handle_update_metadata))
+        .post_async("/:index/freeze", with_auth!(http::indexes::handle_freeze))
+        .post_async(
+            "/:index/unfreeze",
+            with_auth!(http::indexes::handle_unfreeze),
+        )
         // Run router
         .run(req, env)
-        .await;
+        .await?;
+    match html {
+        true => http::render_html::with_html_error(response).await,
+        false => Ok(response),
+    }
 }
 
+/// Log a message when its level is at or above the request's `LOG_LEVEL`, formatting
+/// it only then
 #[macro_export]
 macro_rules! edge_log {
-    ($level:ident, $module:expr, $index:expr, $msg:expr $(, $args:tt)* ) => {
-        worker::$level!(
-            "[{}][{}] {}", $module, $index, format!($msg $(, $args)*)
-        );
-    }
+    ($level:ident, $module:expr, $index:expr, $($fmt:tt)+) => {
+        if $crate::util::log::enabled($crate::edge_log_level!($level)) {
+            let message = format!($($fmt)+);
+            // The console is only reachable inside the Workers runtime, not in native tests
+            if cfg!(target_arch = "wasm32") {
+                worker::$level!("[{}][{}] {}", $module, $index, message);
+            } else {
+                let _ = ($module, $index, message);
+            }
+        }
+    };
+}
+
+/// The [`LogLevel`](crate::util::log::LogLevel) of a `worker` console macro
+#[doc(hidden)]
+#[macro_export]
+macro_rules! edge_log_level {
+    (console_debug) => {
+        $crate::util::log::LogLevel::Debug
+    };
+    (console_log) => {
+        $crate::util::log::LogLevel::Info
+    };
+    (console_warn) => {
+        $crate::util::log::LogLevel::Warn
+    };
+    (console_error) => {
+        $crate::util::log::LogLevel::Error
+    };
 }
@@ -0,0 +1,121 @@
+use sha2::{Digest, Sha256};
+
+/// The outcome of checking a request's credentials against the worker configuration
+#[derive(Debug, PartialEq, Eq)]
+pub enum AuthOutcome {
+    /// The request may proceed
+    Allowed,
+    /// An API key is configured and the request did not present it
+    Unauthorized,
+    /// No API key is configured and auth has not been explicitly disabled
+    Misconfigured,
+}
+
+/// Compare two secrets without leaking where they differ through timing.
+///
+/// Both values are hashed first so the comparison always runs over
+/// 32 bytes regardless of the input lengths.
+pub fn constant_time_eq(a: &str, b: &str) -> bool {
+    let a_digest = Sha256::digest(a.as_bytes());
+    let b_digest = Sha256::digest(b.as_bytes());
+    a_digest
+        .iter()
+        .zip(b_digest.iter())
+        .fold(0u8, |acc, (x, y)| acc | (x ^ y))
+        == 0
+}
+
+/// Decide whether a request is authorized.
+///
+/// - `api_key` is the configured `API_KEY` value, if any
+/// - `auth_disabled` is true when `AUTH_DISABLED=true` is set
+/// - `presented` is the request's `X-API-Key` header, if any
+pub fn authorize(
+    api_key: Option<&str>,
+    auth_disabled: bool,
+    presented: Option<&str>,
+) -> AuthOutcome {
+    match api_key.map(str::trim).filter(|key| !key.is_empty()) {
+        Some(expected) => match presented {
+            Some(presented) if constant_time_eq(expected, presented) => AuthOutcome::Allowed,
+            _ => AuthOutcome::Unauthorized,
+        },
+        None if auth_disabled => AuthOutcome::Allowed,
+        None => AuthOutcome::Misconfigured,
+    }
+}
+
+/// Parse a boolean-ish env var value, only `true`/`1`/`yes` are truthy
+pub fn is_truthy(value: &str) -> bool {
+    matches!(
+        value.trim().to_ascii_lowercase().as_str(),
+        "true" | "1" | "yes"
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq("secret", "secret"));
+        assert!(!constant_time_eq("secret", "secreT"));
+        assert!(!constant_time_eq("secret", "secret-but-longer"));
+        assert!(!constant_time_eq("", "secret"));
+    }
+
+    #[test]
+    fn test_authorize_with_api_key() {
+        assert_eq!(
+            authorize(Some("abc"), false, Some("abc")),
+            AuthOutcome::Allowed
+        );
+        assert_eq!(
+            authorize(Some("abc"), false, Some("abd")),
+            AuthOutcome::Unauthorized
+        );
+        assert_eq!(
+            authorize(Some("abc"), false, None),
+            AuthOutcome::Unauthorized
+        );
+        // A configured key is always enforced, even when auth is disabled
+        assert_eq!(
+            authorize(Some("abc"), true, None),
+            AuthOutcome::Unauthorized
+        );
+    }
+
+    #[test]
+    fn test_authorize_auth_disabled() {
+        assert_eq!(authorize(None, true, None), AuthOutcome::Allowed);
+        assert_eq!(
+            authorize(None, true, Some("anything")),
+            AuthOutcome::Allowed
+        );
+        assert_eq!(authorize(Some("  "), true, None), AuthOutcome::Allowed);
+    }
+
+    #[test]
+    fn test_authorize_missing_api_key() {
+        assert_eq!(authorize(None, false, None), AuthOutcome::Misconfigured);
+        assert_eq!(
+            authorize(None, false, Some("guess")),
+            AuthOutcome::Misconfigured
+        );
+        // An empty key must never match an empty header
+        assert_eq!(
+            authorize(Some(""), false, Some("")),
+            AuthOutcome::Misconfigured
+        );
+    }
+
+    #[test]
+    fn test_is_truthy() {
+        assert!(is_truthy("true"));
+        assert!(is_truthy(" TRUE "));
+        assert!(is_truthy("1"));
+        assert!(!is_truthy("false"));
+        assert!(!is_truthy(""));
+    }
+}
@@ -0,0 +1,97 @@
+//! How many KV operations, or requests to the durable reader, a fan-out has in flight
+//! at once. Awaiting hundreds of reads together trips the runtime's limit on
+//! simultaneous connections, so fan-outs go through [`join_bounded`], which runs at
+//! most `KV_CONCURRENCY` of them at a time. Like the log level, the limit is set as
+//! each request arrives and lives in a thread-local, rather than being passed to
+//! every function that fans out.
+//!
+//! The limit bounds each fan-out on its own, so one nested inside another, like the
+//! shard reads of each keyword of a search, can have up to its square in flight.
+
+use std::{cell::Cell, future::Future};
+
+use futures::{stream, StreamExt};
+use worker::Env;
+
+use crate::{data::ENV_VAR_KV_CONCURRENCY, util::env::parse_env_usize};
+
+pub const DEFAULT_KV_CONCURRENCY: usize = 20;
+pub const MAX_KV_CONCURRENCY: usize = 1_000;
+
+thread_local! {
+    static LIMIT: Cell<usize> = const { Cell::new(DEFAULT_KV_CONCURRENCY) };
+}
+
+/// The most futures [`join_bounded`] runs at once
+pub fn limit() -> usize {
+    LIMIT.with(Cell::get)
+}
+
+pub fn set_limit(limit: usize) {
+    LIMIT.with(|current| current.set(limit.max(1)));
+}
+
+/// Set the limit of the request about to be handled from `KV_CONCURRENCY`
+pub fn set_limit_from_env(env: &Env) {
+    set_limit(parse_env_usize(
+        env,
+        ENV_VAR_KV_CONCURRENCY,
+        DEFAULT_KV_CONCURRENCY,
+        1..=MAX_KV_CONCURRENCY,
+    ));
+}
+
+/// Await every one of `futures`, at most [`limit`] of them at once, starting the next
+/// as soon as any finishes. Outputs are returned in the order of `futures`, so each
+/// stays beside the input it came from, as with `join_all`.
+pub async fn join_bounded<F: Future>(futures: impl IntoIterator<Item = F>) -> Vec<F::Output> {
+    let futures: Vec<F> = futures.into_iter().collect();
+    let mut outputs: Vec<Option<F::Output>> = futures.iter().map(|_| None).collect();
+    let mut running = stream::iter(
+        futures
+            .into_iter()
+            .enumerate()
+            .map(|(i, future)| async move { (i, future.await) }),
+    )
+    .buffer_unordered(limit());
+    while let Some((i, output)) = running.next().await {
+        outputs[i] = Some(output);
+    }
+    outputs
+        .into_iter()
+        .map(|output| output.expect("every future was awaited"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+
+    use super::*;
+    use crate::data::storage::{memory::MemoryStorage, Storage};
+
+    #[test]
+    fn test_in_flight_reads_are_bounded() {
+        let store = MemoryStorage::default();
+        for i in 0..50 {
+            block_on(store.put(&format!("k{:02}", i), i.to_string())).unwrap();
+        }
+        let keys: Vec<String> = (0..50).map(|i| format!("k{:02}", i)).collect();
+
+        store.track_in_flight();
+        let unbounded = block_on(futures::future::join_all(keys.iter().map(|k| store.get(k))));
+        assert_eq!((unbounded.len(), store.max_in_flight()), (50, 50));
+
+        for bound in [1, 7, 20] {
+            set_limit(bound);
+            store.track_in_flight();
+            let read = block_on(join_bounded(keys.iter().map(|key| store.get(key))));
+            assert_eq!(store.max_in_flight(), bound);
+            // Each output is the read of the key at the same position
+            let values: Vec<String> = read.into_iter().map(|v| v.unwrap().unwrap()).collect();
+            assert_eq!(values, (0..50).map(|i| i.to_string()).collect::<Vec<_>>());
+        }
+        set_limit(DEFAULT_KV_CONCURRENCY);
+        assert!(block_on(join_bounded(Vec::<futures::future::Ready<()>>::new())).is_empty());
+    }
+}
@@ -0,0 +1,128 @@
+//! Reading numeric settings from the Worker's env vars. A value that doesn't parse
+//! falls back to the setting's default and one out of range is clamped into it,
+//! each logged once per isolate, so a typo in `wrangler.jsonc` can't fail every
+//! request reading it.
+
+use std::{collections::HashSet, fmt::Display, ops::RangeInclusive, str::FromStr, sync::Mutex};
+
+use once_cell::sync::Lazy;
+use worker::Env;
+
+use crate::edge_log;
+
+/// The problems already logged by this isolate, so each is logged once
+static LOGGED: Lazy<Mutex<HashSet<String>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+
+/// The env var `name` as a number in `range`, or `default` when it's unset
+pub fn parse_env_u32(env: &Env, name: &str, default: u32, range: RangeInclusive<u32>) -> u32 {
+    parse_env(env, name, default, range)
+}
+
+pub fn parse_env_u64(env: &Env, name: &str, default: u64, range: RangeInclusive<u64>) -> u64 {
+    parse_env(env, name, default, range)
+}
+
+pub fn parse_env_usize(
+    env: &Env,
+    name: &str,
+    default: usize,
+    range: RangeInclusive<usize>,
+) -> usize {
+    parse_env(env, name, default, range)
+}
+
+fn parse_env<T: FromStr + Ord + Copy + Display>(
+    env: &Env,
+    name: &str,
+    default: T,
+    range: RangeInclusive<T>,
+) -> T {
+    let raw = env.var(name).ok().map(|v| v.to_string());
+    let (value, problem) = parse_setting(name, raw.as_deref(), default, range);
+    if let Some(problem) = problem {
+        let first = LOGGED
+            .lock()
+            .map(|mut logged| logged.insert(problem.clone()))
+            .unwrap_or(true);
+        if first {
+            edge_log!(console_error, "Config", name, "{}", problem);
+        }
+    }
+    value
+}
+
+/// The value of a setting set to `raw`, and what was wrong with `raw` if it wasn't
+/// used as it is
+pub fn parse_setting<T: FromStr + Ord + Copy + Display>(
+    name: &str,
+    raw: Option<&str>,
+    default: T,
+    range: RangeInclusive<T>,
+) -> (T, Option<String>) {
+    let Some(raw) = raw else {
+        return (default, None);
+    };
+    match raw.trim().parse::<T>() {
+        Ok(value) if range.contains(&value) => (value, None),
+        Ok(value) => {
+            let clamped = value.clamp(*range.start(), *range.end());
+            let problem = format!(
+                "{}={} is outside {} to {}, using {}",
+                name,
+                value,
+                range.start(),
+                range.end(),
+                clamped
+            );
+            (clamped, Some(problem))
+        }
+        Err(_) => {
+            let problem = format!(
+                "{}='{}' is not a whole number, using the default of {}",
+                name, raw, default
+            );
+            (default, Some(problem))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::{DEFAULT_N_SHARDS, MAX_N_SHARDS};
+
+    fn n_shards(raw: Option<&str>) -> (u32, Option<String>) {
+        parse_setting("N_SHARDS", raw, DEFAULT_N_SHARDS, 1..=MAX_N_SHARDS)
+    }
+
+    #[test]
+    fn test_unset_and_valid() {
+        assert_eq!(n_shards(None), (48, None));
+        assert_eq!(n_shards(Some("16")), (16, None));
+        assert_eq!(n_shards(Some(" 1024 ")), (1024, None));
+    }
+
+    #[test]
+    fn test_malformed_falls_back_to_default() {
+        for raw in ["forty-eight", "", "-3", "4.5", "99999999999"] {
+            let (value, problem) = n_shards(Some(raw));
+            assert_eq!(value, 48, "{}", raw);
+            assert!(problem.unwrap().contains(&format!("'{}'", raw)));
+        }
+    }
+
+    #[test]
+    fn test_out_of_range_is_clamped() {
+        let (value, problem) = n_shards(Some("0"));
+        assert_eq!(value, 1);
+        assert_eq!(
+            problem.as_deref(),
+            Some("N_SHARDS=0 is outside 1 to 1024, using 1")
+        );
+        assert_eq!(n_shards(Some("5000")).0, 1024);
+        assert_eq!(
+            parse_setting("SEARCH_BUDGET_MS", Some("0"), 10_000u64, 1..=u64::MAX).0,
+            1
+        );
+    }
+}
@@ -1,7 +1,41 @@
-use url::form_urlencoded;
+/// Percent-decode a URL path segment, such as a keyword or document ID route
+/// parameter. Unlike query strings, `+` stays a literal plus, and a `%` not followed
+/// by two hex digits is kept as written.
+pub fn decode_path_param(input: &str) -> String {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3).and_then(|pair| {
+            let pair = std::str::from_utf8(pair).ok()?;
+            u8::from_str_radix(pair, 16).ok()
+        });
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-pub fn url_decode(input: &str) -> String {
-    form_urlencoded::parse(input.as_bytes())
-        .map(|(k, _)| k.into_owned())
-        .collect()
+    #[test]
+    fn test_decode_path_param() {
+        assert_eq!(decode_path_param("c%20sharp"), "c sharp");
+        assert_eq!(decode_path_param("c%2520sharp"), "c%20sharp");
+        assert_eq!(decode_path_param("c++"), "c++");
+        assert_eq!(decode_path_param("c%2B%2b"), "c++");
+        assert_eq!(decode_path_param("100%"), "100%");
+        assert_eq!(decode_path_param("%zz%4"), "%zz%4");
+        assert_eq!(decode_path_param("caf%C3%A9"), "café");
+    }
 }
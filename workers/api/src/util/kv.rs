@@ -1,13 +1,19 @@
 use std::sync::Arc;
 
-use worker::{kv::KvStore, RouteContext};
+use worker::{kv::KvStore, Bucket, Context, RouteContext};
 
-const KV_BINDING_NAME: &'static str = "INDEX";
+const KV_BINDING_NAME: &str = "INDEX";
+const R2_BINDING_NAME: &str = "R2_BUCKET";
 
-pub fn get_kv_data_store(ctx: &RouteContext<()>) -> Arc<KvStore> {
+pub fn get_kv_data_store(ctx: &RouteContext<Context>) -> Arc<KvStore> {
     Arc::new(ctx.kv(KV_BINDING_NAME).unwrap())
 }
 
 pub fn get_kv_data_store_from_env(env: &worker::Env) -> Arc<KvStore> {
     Arc::new(env.kv(KV_BINDING_NAME).unwrap())
 }
+
+/// The optional R2 bucket that large document bodies are offloaded to
+pub fn get_body_bucket(env: &worker::Env) -> Option<Bucket> {
+    env.bucket(R2_BINDING_NAME).ok()
+}
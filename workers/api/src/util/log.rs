@@ -0,0 +1,120 @@
+//! The threshold `edge_log!` checks before formatting a message, set from the
+//! `LOG_LEVEL` env var as each request arrives. A Worker's requests all see the same
+//! env, so the requests an isolate interleaves agree on the level, which lives in a
+//! thread-local rather than being passed to every function that logs.
+
+use std::{cell::Cell, sync::Once};
+
+use worker::Env;
+
+use crate::{data::ENV_VAR_LOG_LEVEL, edge_log};
+
+/// How severe a message is, from `console_debug` up to `console_error`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Debug,
+    Info,
+    Warn,
+    Error,
+    /// Above every message, so nothing is logged
+    Off,
+}
+
+/// The level used without `LOG_LEVEL`, leaving out the per-shard debug lines
+pub const DEFAULT_LOG_LEVEL: LogLevel = LogLevel::Info;
+
+static INVALID_LEVEL_WARNING: Once = Once::new();
+
+thread_local! {
+    static LEVEL: Cell<LogLevel> = const { Cell::new(DEFAULT_LOG_LEVEL) };
+}
+
+impl LogLevel {
+    pub const NAMES: [&'static str; 5] = ["debug", "info", "warn", "error", "off"];
+
+    pub fn from_name(name: &str) -> Option<LogLevel> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "debug" => Some(LogLevel::Debug),
+            "info" | "log" => Some(LogLevel::Info),
+            "warn" | "warning" => Some(LogLevel::Warn),
+            "error" => Some(LogLevel::Error),
+            "off" | "none" => Some(LogLevel::Off),
+            _ => None,
+        }
+    }
+}
+
+/// Whether a message at `level` is logged
+pub fn enabled(level: LogLevel) -> bool {
+    LEVEL.with(|threshold| level >= threshold.get())
+}
+
+pub fn set_level(level: LogLevel) {
+    LEVEL.with(|threshold| threshold.set(level));
+}
+
+/// The `LOG_LEVEL` env var, or [`DEFAULT_LOG_LEVEL`] when it's unset or unknown
+pub fn level_from_env(env: &Env) -> LogLevel {
+    let Ok(raw) = env.var(ENV_VAR_LOG_LEVEL).map(|v| v.to_string()) else {
+        return DEFAULT_LOG_LEVEL;
+    };
+    LogLevel::from_name(&raw).unwrap_or_else(|| {
+        INVALID_LEVEL_WARNING.call_once(|| {
+            edge_log!(
+                console_error,
+                "Config",
+                ENV_VAR_LOG_LEVEL,
+                "{}='{}' is not one of {}, using info",
+                ENV_VAR_LOG_LEVEL,
+                raw,
+                LogLevel::NAMES.join(", ")
+            );
+        });
+        DEFAULT_LOG_LEVEL
+    })
+}
+
+/// Set the level of the request about to be handled from `LOG_LEVEL`
+pub fn set_level_from_env(env: &Env) {
+    set_level(level_from_env(env));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+
+    #[test]
+    fn test_below_threshold_is_not_formatted() {
+        let formatted = Cell::new(0);
+        let argument = || {
+            formatted.set(formatted.get() + 1);
+            "shard"
+        };
+
+        set_level(LogLevel::Warn);
+        edge_log!(console_debug, "Test", "", "skipped {}", argument());
+        edge_log!(console_log, "Test", "", "skipped {}", argument());
+        assert_eq!(formatted.get(), 0);
+        edge_log!(console_warn, "Test", "", "logged {}", argument());
+        edge_log!(console_error, "Test", "", "logged {}", argument());
+        assert_eq!(formatted.get(), 2);
+
+        set_level(LogLevel::Off);
+        edge_log!(console_error, "Test", "", "skipped {}", argument());
+        assert_eq!(formatted.get(), 2);
+        set_level(DEFAULT_LOG_LEVEL);
+    }
+
+    #[test]
+    fn test_level_names() {
+        for name in LogLevel::NAMES {
+            assert!(LogLevel::from_name(name).is_some());
+        }
+        assert_eq!(LogLevel::from_name(" WARN "), Some(LogLevel::Warn));
+        assert_eq!(LogLevel::from_name("verbose"), None);
+        assert!(!enabled(LogLevel::Debug));
+        assert!(enabled(LogLevel::Info));
+    }
+}
@@ -1,2 +1,8 @@
+pub mod auth;
+pub mod concurrency;
+pub mod env;
 pub mod http;
 pub mod kv;
+pub mod log;
+pub mod sanitize;
+pub mod time;
@@ -0,0 +1,125 @@
+//! Cleaning up document bodies as they arrive. Scrapers upstream sometimes send NUL
+//! bytes, stray control characters or broken UTF-8, which would be stored as they
+//! came and only surface when a search serves the body back. Bodies are cleaned on
+//! ingest instead: invalid UTF-8 sequences become U+FFFD and the C0 control
+//! characters besides `\n`, `\t` and `\r` are dropped. With `strict=true` a write
+//! is rejected rather than cleaned.
+//!
+//! A body that is mostly either, like a gzipped or image upload, isn't text at all,
+//! and would only give keywords made of noise. [`looks_binary`] tells them apart.
+
+use std::borrow::Cow;
+
+/// Whether `c` is a C0 control character a body shouldn't hold
+fn is_unwanted_control(c: char) -> bool {
+    c < '\u{20}' && !matches!(c, '\n' | '\t' | '\r')
+}
+
+/// How much of the start of a body [`looks_binary`] reads
+const BINARY_SAMPLE_BYTES: usize = 4096;
+
+/// The share of a body's characters that can be control characters or invalid UTF-8
+/// before it's taken for binary data
+const BINARY_RATIO: f64 = 0.3;
+
+/// Whether the start of `bytes` is so full of control characters and invalid UTF-8
+/// that it can't be text
+pub fn looks_binary(bytes: &[u8]) -> bool {
+    let sample = String::from_utf8_lossy(&bytes[..bytes.len().min(BINARY_SAMPLE_BYTES)]);
+    let (mut chars, mut unprintable) = (0usize, 0usize);
+    for c in sample.chars() {
+        chars += 1;
+        if c == char::REPLACEMENT_CHARACTER || c == '\u{7f}' || is_unwanted_control(c) {
+            unprintable += 1;
+        }
+    }
+    unprintable as f64 > chars as f64 * BINARY_RATIO
+}
+
+/// `bytes` as text, with invalid UTF-8 replaced and unwanted control characters
+/// dropped, and whether anything had to change
+pub fn sanitize_body(bytes: &[u8]) -> (String, bool) {
+    let text = String::from_utf8_lossy(bytes);
+    if text.contains(is_unwanted_control) {
+        return (
+            text.chars().filter(|&c| !is_unwanted_control(c)).collect(),
+            true,
+        );
+    }
+    let replaced = matches!(text, Cow::Owned(_));
+    (text.into_owned(), replaced)
+}
+
+/// `bytes` as text when there's nothing to clean, otherwise what's wrong with them
+/// and at which byte offset, for `strict=true` writes
+pub fn check_body(bytes: &[u8]) -> Result<&str, String> {
+    let text = std::str::from_utf8(bytes).map_err(|err| {
+        format!(
+            "Document body has invalid UTF-8 at byte {}",
+            err.valid_up_to()
+        )
+    })?;
+    match text.find(is_unwanted_control) {
+        Some(offset) => Err(format!(
+            "Document body has control character U+{:04X} at byte {}",
+            bytes[offset], offset
+        )),
+        None => Ok(text),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sanitize_body() {
+        assert_eq!(
+            sanitize_body(b"Tides\n\trise.\r\n"),
+            ("Tides\n\trise.\r\n".to_string(), false)
+        );
+        assert_eq!(
+            sanitize_body(b"Ti\x00des\x1b rise\x7f"),
+            ("Tides rise\x7f".to_string(), true)
+        );
+        // A lone surrogate encoded as UTF-8, and a truncated sequence
+        assert_eq!(
+            sanitize_body(b"Ocean \xed\xa0\x80 tides \xe2\x82"),
+            (
+                "Ocean \u{fffd}\u{fffd}\u{fffd} tides \u{fffd}".to_string(),
+                true
+            )
+        );
+        assert_eq!(sanitize_body(b""), (String::new(), false));
+    }
+
+    #[test]
+    fn test_looks_binary() {
+        let gzip = b"\x1f\x8b\x08\x00\x00\x00\x00\x00\x00\x03\xcb\x4f\x4e\x4d\xcc\x03\x00";
+        let png = b"\x89PNG\r\n\x1a\n\x00\x00\x00\rIHDR\x00\x00\x01\x00";
+        assert!(looks_binary(gzip));
+        assert!(looks_binary(png));
+        assert!(!looks_binary(
+            b"Ocean tides rise at dawn.\r\n\tAnd fall at dusk."
+        ));
+        assert!(!looks_binary("Stra\u{df}e, caf\u{e9}, \u{6d77}".as_bytes()));
+        assert!(!looks_binary(b""));
+        // A stray NUL in a long text body is cleaned, not refused
+        let mut text = "Ocean tides rise at dawn. ".repeat(8).into_bytes();
+        text.push(0);
+        assert!(!looks_binary(&text));
+    }
+
+    #[test]
+    fn test_check_body() {
+        assert_eq!(check_body("Tides\n rise".as_bytes()), Ok("Tides\n rise"));
+        assert_eq!(
+            check_body(b"Ti\x00des"),
+            Err("Document body has control character U+0000 at byte 2".to_string())
+        );
+        assert_eq!(
+            check_body(b"Ocean \xed\xa0\x80"),
+            Err("Document body has invalid UTF-8 at byte 6".to_string())
+        );
+    }
+}
@@ -0,0 +1,60 @@
+use std::rc::Rc;
+
+/// Milliseconds since the epoch. Uses the Workers clock on wasm and the system clock
+/// natively, so code paths that timestamp data can run under `cargo test`.
+pub fn now_ms() -> u64 {
+    if cfg!(target_arch = "wasm32") {
+        worker::Date::now().as_millis()
+    } else {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_millis() as u64)
+            .unwrap_or(0)
+    }
+}
+
+/// Where the data layer reads the time it stamps records with, so tests can pin it
+pub trait Clock {
+    /// Milliseconds since the epoch
+    fn now_millis(&self) -> u64;
+}
+
+/// A clock shared by everything stamped during one operation
+pub type SharedClock = Rc<dyn Clock>;
+
+/// The real time, see [`now_ms`]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct WorkerClock;
+
+impl Clock for WorkerClock {
+    fn now_millis(&self) -> u64 {
+        now_ms()
+    }
+}
+
+pub fn worker_clock() -> SharedClock {
+    Rc::new(WorkerClock)
+}
+
+/// A clock that only moves when told to
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub struct ManualClock(std::cell::Cell<u64>);
+
+#[cfg(test)]
+impl ManualClock {
+    pub fn at(now: u64) -> Rc<ManualClock> {
+        Rc::new(ManualClock(std::cell::Cell::new(now)))
+    }
+
+    pub fn advance(&self, ms: u64) {
+        self.0.set(self.0.get() + ms);
+    }
+}
+
+#[cfg(test)]
+impl Clock for ManualClock {
+    fn now_millis(&self) -> u64 {
+        self.0.get()
+    }
+}